@@ -1,5 +1,8 @@
 //! Asynchronous database.
+//!
+//! Available with the `async` feature, which is enabled by default.
 
+pub mod build;
 pub mod io;
 pub mod proto;
 pub mod stored;
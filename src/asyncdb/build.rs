@@ -0,0 +1,694 @@
+//! Asynchronous serialization of built databases.
+//!
+//! [`serialize_database`] writes a [`Database`] to an asynchronous file
+//! system; e.g., to S3 from a Tokio runtime without blocking threads. The
+//! written files are identical to those of
+//! [`serialize_database`](crate::db::build::proto::serialize_database).
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+
+use crate::db::build::{Database, Partition};
+use crate::db::build::proto::{
+    DatabaseSerialize,
+    DatabaseSerializer,
+    SerializeOptions,
+};
+use crate::error::Error;
+use crate::io::hash::HashAlgorithm;
+use crate::io::{
+    HashedFileOut as SyncHashedFileOut,
+    WriteFileSystem as SyncWriteFileSystem,
+};
+use crate::kmeans::Scalar;
+use crate::protos::Serialize;
+use crate::protos::database::{
+    Database as ProtosDatabase,
+    Partition as ProtosPartition,
+    VectorSet as ProtosVectorSet,
+};
+use crate::vector::{BlockVectorSet, VectorSet};
+
+use super::io::{HashedFileOut, WriteFileSystem};
+
+/// Serializes [`Database`] to an asynchronous file system.
+pub async fn serialize_database<'a, T, VS, FS>(
+    db: &'a Database<T, VS>,
+    fs: &FS,
+) -> Result<(), Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
+    Partition<T>: Serialize<ProtosPartition>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem + Sync,
+{
+    serialize_database_with_options(db, fs, &SerializeOptions::default())
+        .await
+}
+
+/// Serializes [`Database`] to an asynchronous file system with options.
+///
+/// Every file is encoded in memory and written to `fs` before the next file
+/// is encoded, so at most one file is held in memory at a time.
+pub async fn serialize_database_with_options<'a, T, VS, FS>(
+    db: &'a Database<T, VS>,
+    fs: &FS,
+    options: &SerializeOptions,
+) -> Result<(), Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
+    Partition<T>: Serialize<ProtosPartition>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem + Sync,
+{
+    let mut serializer = DatabaseSerializer::new(db, options)?;
+    let mut pending = PendingFileSystem::default();
+    loop {
+        let has_next = serializer.write_next(&mut pending, &mut |_| {})?;
+        pending.write_to(fs).await?;
+        if !has_next {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Synchronous file system that keeps persisted files in memory until they
+// are written to an asynchronous file system.
+#[derive(Default)]
+struct PendingFileSystem {
+    files: Arc<Mutex<Vec<PendingFile>>>,
+    // Pointers and their targets to write after the files.
+    pointers: Mutex<Vec<(String, String)>>,
+}
+
+// File persisted in memory.
+struct PendingFile {
+    dir: String,
+    algorithm: HashAlgorithm,
+    extension: String,
+    hash: String,
+    contents: Vec<u8>,
+}
+
+impl PendingFileSystem {
+    // Writes the pending files to a given file system.
+    //
+    // Fails if the hash of a written file differs from the one given when
+    // it was persisted in memory.
+    async fn write_to<FS>(&mut self, fs: &FS) -> Result<(), Error>
+    where
+        FS: WriteFileSystem + Sync,
+    {
+        let files = core::mem::take(&mut *self.files.lock().unwrap());
+        for file in files {
+            let mut f =
+                fs.create_hashed_file_in_with(file.dir, file.algorithm).await?;
+            f.write_all(&file.contents).await?;
+            let hash = f.persist(file.extension).await?;
+            if hash != file.hash {
+                return Err(Error::VerificationFailure(format!(
+                    "hash discrepancy: expected {} but got {}",
+                    file.hash,
+                    hash,
+                )));
+            }
+        }
+        let pointers = core::mem::take(&mut *self.pointers.lock().unwrap());
+        for (path, target) in pointers {
+            fs.write_pointer(path, target).await?;
+        }
+        Ok(())
+    }
+}
+
+impl SyncWriteFileSystem for PendingFileSystem {
+    type HashedFileOut = PendingHashedFileOut;
+
+    fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.create_hashed_file_in("")
+    }
+
+    fn create_hashed_file_in(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.create_hashed_file_in_with(path, HashAlgorithm::Sha256)
+    }
+
+    fn create_hashed_file_in_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        algorithm.check_available()?;
+        Ok(PendingHashedFileOut {
+            dir: path.as_ref().to_string(),
+            algorithm,
+            contents: Vec::new(),
+            files: self.files.clone(),
+        })
+    }
+
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        self.pointers.lock().unwrap().push((
+            path.as_ref().to_string(),
+            target.as_ref().to_string(),
+        ));
+        Ok(())
+    }
+}
+
+// File written to memory.
+struct PendingHashedFileOut {
+    dir: String,
+    algorithm: HashAlgorithm,
+    contents: Vec<u8>,
+    files: Arc<Mutex<Vec<PendingFile>>>,
+}
+
+impl Write for PendingHashedFileOut {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.contents.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SyncHashedFileOut for PendingHashedFileOut {
+    fn persist(self, extension: impl AsRef<str>) -> Result<String, Error> {
+        let hash = self.algorithm.hash(&self.contents)?;
+        self.files.lock().unwrap().push(PendingFile {
+            dir: self.dir,
+            algorithm: self.algorithm,
+            extension: extension.as_ref().to_string(),
+            hash: hash.clone(),
+            contents: self.contents,
+        });
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asyncdb::io::{LocalFileSystem, ReadFileSystem as _};
+    use crate::asyncdb::stored::{
+        Database,
+        LoadDatabase,
+        LoadResidues,
+        LoadVectors,
+    };
+    use crate::db::build::DatabaseBuilder;
+    use crate::db::build::proto::serialize_database_with_options as
+        serialize_database_sync;
+    use crate::db::manifest::CURRENT;
+    use crate::io::{LocalFileSystem as SyncLocalFileSystem, ReadFileSystem};
+    use crate::testing::SyntheticDatasetBuilder;
+
+    async fn serialize_same_files_as_sync(options: SerializeOptions) {
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let options = options.with_residues(true);
+        let sync_dir = tempfile::tempdir().unwrap();
+        let mut sync_fs = SyncLocalFileSystem::new(sync_dir.path());
+        serialize_database_sync(&db, &mut sync_fs, &options).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let mut expected = sync_fs.list_files("").unwrap();
+        expected.sort();
+        assert_eq!(expected.len(), 1 + 2 + 1 + 2 + 2 + 2);
+        let mut files = SyncLocalFileSystem::new(dir.path())
+            .list_files("")
+            .unwrap();
+        files.sort();
+        assert_eq!(files, expected);
+    }
+
+    #[tokio::test]
+    async fn serialize_database_should_write_same_files_as_sync() {
+        serialize_same_files_as_sync(SerializeOptions::new()).await;
+    }
+
+    #[cfg(feature = "blake3")]
+    #[tokio::test]
+    async fn serialize_database_should_write_same_blake3_files_as_sync() {
+        serialize_same_files_as_sync(
+            SerializeOptions::new()
+                .with_hash_algorithm(crate::io::hash::HashAlgorithm::Blake3),
+        ).await;
+    }
+
+    #[tokio::test]
+    async fn serialize_database_should_publish_same_header_as_sync() {
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let options = SerializeOptions::new().with_publishing(true);
+        let sync_dir = tempfile::tempdir().unwrap();
+        let mut sync_fs = SyncLocalFileSystem::new(sync_dir.path());
+        serialize_database_sync(&db, &mut sync_fs, &options).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        assert_eq!(
+            fs.read_pointer(CURRENT).await.unwrap(),
+            sync_fs.read_pointer(CURRENT).unwrap(),
+        );
+        let stored =
+            Database::<f32, _>::load_current_database(fs).await.unwrap();
+        assert_eq!(stored.num_partitions(), 2);
+    }
+
+    #[tokio::test]
+    async fn stored_database_should_load_vectors_like_sync() {
+        use crate::db::stored::{
+            Database as SyncDatabase,
+            LoadDatabase as _,
+            LoadResidues as _,
+            LoadVectors as _,
+        };
+
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let options = SerializeOptions::new()
+            .with_residues(true)
+            .with_original_vectors(true)
+            .with_publishing(true);
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let stored = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).await.unwrap();
+        let sync_stored = SyncDatabase::<f32, _>::load_current_database(
+            SyncLocalFileSystem::new(dir.path()),
+        ).unwrap();
+        assert!(stored.has_original_vectors());
+        for pi in 0..2 {
+            let vectors = stored.load_vectors(pi).await.unwrap();
+            let expected = sync_stored.load_vectors(pi).unwrap();
+            assert_eq!(vectors.len(), expected.len());
+            for i in 0..vectors.len() {
+                assert_eq!(vectors.get(i), expected.get(i));
+            }
+            let residues = stored.load_residues(pi).await.unwrap();
+            let expected = sync_stored.load_residues(pi).unwrap();
+            for i in 0..residues.len() {
+                assert_eq!(residues.get(i), expected.get(i));
+            }
+        }
+        assert!(matches!(
+            stored.load_vectors(2).await,
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn stored_database_should_rerank_like_sync() {
+        use crate::db::stored::{
+            Database as SyncDatabase,
+            LoadDatabase as _,
+        };
+
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let options = SerializeOptions::new()
+            .with_residues(true)
+            .with_publishing(true);
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let stored = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).await.unwrap();
+        let sync_stored = SyncDatabase::<f32, _>::load_current_database(
+            SyncLocalFileSystem::new(dir.path()),
+        ).unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let k = 5.try_into().unwrap();
+        let nprobe = 1.try_into().unwrap();
+        let oversample = 4.try_into().unwrap();
+        let results = stored
+            .query_with_reranking(&query[..], k, nprobe, oversample)
+            .await
+            .unwrap();
+        let expected = sync_stored
+            .query_with_reranking(&query[..], k, nprobe, oversample)
+            .unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert_eq!(result.squared_distance, expected.squared_distance);
+            assert_eq!(result.error_bound, Some(0.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_query_result_should_be_verified_at_attribute_lookup() {
+        use crate::db::AttributeValue;
+        use crate::db::stored::{
+            Database as SyncDatabase,
+            LoadDatabase as _,
+        };
+
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let mut db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        for i in 0..db.num_vectors() {
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let options = SerializeOptions::new().with_publishing(true);
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let stored = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).await.unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let k = 1.try_into().unwrap();
+        let nprobe = 2.try_into().unwrap();
+        let results = stored.query(&query[..], k, nprobe).await.unwrap();
+        let result = &results[0];
+        let old_value = result.get_attribute("index").await.unwrap();
+        assert!(old_value.is_some());
+
+        // updates the attribute through another instance
+        let sync_stored = SyncDatabase::<f32, _>::load_current_database(
+            SyncLocalFileSystem::new(dir.path()),
+        ).unwrap();
+        sync_stored
+            .set_attribute_if(
+                &result.vector_id,
+                "index",
+                result.generation,
+                1000u64.into(),
+            )
+            .unwrap()
+            .unwrap();
+        let reloaded = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).await.unwrap();
+
+        // a result of the old database is stale against the reloaded one
+        assert!(matches!(
+            reloaded.get_attribute_of(result, "index").await,
+            Err(Error::InvalidContext(_)),
+        ));
+        // but still consistent with the database it came from
+        assert_eq!(
+            stored.get_attribute_of(result, "index").await.unwrap(),
+            old_value,
+        );
+        // a fresh result sees the update
+        let results = reloaded.query(&query[..], k, nprobe).await.unwrap();
+        assert_eq!(results[0].vector_id, result.vector_id);
+        assert_eq!(
+            results[0].get_attribute("index").await.unwrap(),
+            Some(AttributeValue::from(1000u64)),
+        );
+        assert_eq!(
+            reloaded.get_attribute_of(&results[0], "index").await.unwrap(),
+            Some(AttributeValue::from(1000u64)),
+        );
+    }
+
+    #[tokio::test]
+    async fn database_handles_should_share_results_across_tasks() {
+        use std::collections::HashMap;
+        use crate::asyncdb::stored::DatabaseHandle;
+        use crate::db::AttributeValue;
+
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let mut db = DatabaseBuilder::new(dataset.vectors.clone())
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        for i in 0..db.num_vectors() {
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let indices: HashMap<_, _> = db
+            .vector_ids()
+            .enumerate()
+            .map(|(i, id)| (*id, AttributeValue::from(i as u64)))
+            .collect();
+        let options = SerializeOptions::new().with_publishing(true);
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let handle = DatabaseHandle::new(
+            Database::<f32, _>::load_current_database(
+                LocalFileSystem::new(dir.path()),
+            ).await.unwrap(),
+        );
+
+        // concurrent queries through cloned handles
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let handle = handle.clone();
+                let query = dataset.vectors.get(i * 10).to_vec();
+                tokio::spawn(async move {
+                    let results = handle
+                        .query(
+                            &query[..],
+                            3.try_into().unwrap(),
+                            2.try_into().unwrap(),
+                        )
+                        .await
+                        .unwrap();
+                    let mut attributes = Vec::with_capacity(results.len());
+                    for result in results.iter() {
+                        let value =
+                            result.get_attribute("index").await.unwrap();
+                        attributes.push((result.vector_id, value));
+                    }
+                    (results, attributes)
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for task in tasks {
+            let (task_results, attributes) = task.await.unwrap();
+            for (id, value) in attributes {
+                assert_eq!(value.as_ref(), indices.get(&id));
+            }
+            results.extend(task_results);
+        }
+
+        // results are valid for any clone of the handle
+        let clone = handle.clone();
+        for result in results.iter() {
+            assert_eq!(
+                clone.get_attribute_of(result, "index").await.unwrap().as_ref(),
+                indices.get(&result.vector_id),
+            );
+        }
+        // but not for a handle of another instance of the database
+        let other = DatabaseHandle::new(
+            Database::<f32, _>::load_current_database(
+                LocalFileSystem::new(dir.path()),
+            ).await.unwrap(),
+        );
+        assert!(matches!(
+            other.get_attribute_of(&results[0], "index").await,
+            Err(Error::InvalidContext(_)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn stored_database_should_query_uneven_divisions_like_sync() {
+        use crate::db::stored::{
+            Database as SyncDatabase,
+            LoadDatabase as _,
+        };
+
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            6.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(4.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let options = SerializeOptions::new().with_publishing(true);
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let stored = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).await.unwrap();
+        let sync_stored = SyncDatabase::<f32, _>::load_current_database(
+            SyncLocalFileSystem::new(dir.path()),
+        ).unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0, 0.25, -1.0];
+        let k = 10.try_into().unwrap();
+        let nprobe = 2.try_into().unwrap();
+        let results = stored.query(&query[..], k, nprobe).await.unwrap();
+        let expected = sync_stored.query(&query[..], k, nprobe).unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert_eq!(result.squared_distance, expected.squared_distance);
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_database_should_query_with_fast_scan_like_sync() {
+        use crate::db::OpenOptions;
+        use crate::db::stored::{
+            Database as SyncDatabase,
+            LoadDatabase as _,
+        };
+
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            8.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(4.try_into().unwrap())
+            .with_clusters(16.try_into().unwrap())
+            .build()
+            .unwrap();
+        let options = SerializeOptions::new().with_publishing(true);
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &LocalFileSystem::new(dir.path()),
+            &options,
+        ).await.unwrap();
+        let options = OpenOptions::new().with_fast_scan(true);
+        let stored = Database::<f32, _>::load_current_database_with_options(
+            LocalFileSystem::new(dir.path()),
+            options,
+        ).await.unwrap();
+        let sync_stored =
+            SyncDatabase::<f32, _>::load_current_database_with_options(
+                SyncLocalFileSystem::new(dir.path()),
+                options,
+            ).unwrap();
+        let plain = SyncDatabase::<f32, _>::load_current_database(
+            SyncLocalFileSystem::new(dir.path()),
+        ).unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0, 0.25, -1.0, 0.0, 2.0];
+        let k = 10.try_into().unwrap();
+        let nprobe = 2.try_into().unwrap();
+        let results = stored.query(&query[..], k, nprobe).await.unwrap();
+        let expected = sync_stored.query(&query[..], k, nprobe).unwrap();
+        // ranks every vector so that each result has a counterpart
+        let plain = plain
+            .query(&query[..], 100.try_into().unwrap(), nprobe)
+            .unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert_eq!(result.squared_distance, expected.squared_distance);
+            assert_eq!(result.error_bound, expected.error_bound);
+        }
+        // error bounds widen by the quantization of terms
+        for result in results.iter() {
+            let plain = plain
+                .iter()
+                .find(|plain| plain.vector_id == result.vector_id)
+                .unwrap();
+            assert!(result.error_bound > plain.error_bound);
+        }
+    }
+}
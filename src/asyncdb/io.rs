@@ -1,24 +1,57 @@
 //! Asynchronous file system.
 
 use async_trait::async_trait;
-use base64::engine::{
-    Engine,
-    general_purpose::URL_SAFE_NO_PAD as url_safe_base_64,
-};
-use core::mem::{MaybeUninit, transmute};
+use core::num::NonZeroUsize;
 use core::pin::Pin;
-use core::task::Poll;
+use core::task::{Poll, ready};
 use flate2::{Decompress, FlushDecompress};
 use pin_project_lite::pin_project;
+use rand::Rng;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use tokio::fs::File;
-use tokio::io::{AsyncRead, ReadBuf};
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncSeekExt,
+    AsyncWrite,
+    AsyncWriteExt,
+    BufReader,
+    ReadBuf,
+};
 
 use crate::error::Error;
+use crate::io::hash::{HashAlgorithm, Hasher};
+use crate::io::merkle::MerkleTree;
+use crate::io::parse_pointer;
+
+use self::codec::AsyncDecoder;
+
+pub use crate::io::DEFAULT_READAHEAD_SIZE;
+
+pub mod cache;
+mod codec;
+pub mod encrypt;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "opendal")]
+pub mod opendal;
+pub mod retry;
+pub mod throttle;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 
-/// Asynchronous file system.
+/// Asynchronous file system that can be read and written.
+///
+/// Implemented for every type that implements both [`ReadFileSystem`] and
+/// [`WriteFileSystem`].
+pub trait FileSystem: ReadFileSystem + WriteFileSystem {}
+
+impl<FS> FileSystem for FS where FS: ReadFileSystem + WriteFileSystem {}
+
+/// Asynchronous file system that can be read.
 #[async_trait]
-pub trait FileSystem {
+pub trait ReadFileSystem {
     /// File whose contents can be verified with the hash.
     type HashedFileIn: HashedFileIn;
 
@@ -28,6 +61,35 @@ pub trait FileSystem {
         path: impl Into<String> + Send,
     ) -> Result<Self::HashedFileIn, Error>;
 
+    /// Opens a file whose contents can be verified with a hash of a given
+    /// algorithm.
+    ///
+    /// The default implementation supports only [`HashAlgorithm::Sha256`],
+    /// and fails with `Error::InvalidContext` for the others.
+    async fn open_hashed_file_with(
+        &self,
+        path: impl Into<String> + Send,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        algorithm.check_sha256()?;
+        self.open_hashed_file(path).await
+    }
+
+    /// Opens a hashed file that the caller is not going to verify.
+    ///
+    /// An implementation may skip hashing the contents, in which case
+    /// [`HashedFileIn::verify`] of the file fails with
+    /// `Error::InvalidContext`.
+    ///
+    /// The default implementation calls
+    /// [`open_hashed_file`](Self::open_hashed_file).
+    async fn open_hashed_file_unverified(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        self.open_hashed_file(path).await
+    }
+
     /// Opens a compressed file whose contents can be verified with the hash.
     async fn open_compressed_hashed_file(
         &self,
@@ -36,6 +98,169 @@ pub trait FileSystem {
         let file = self.open_hashed_file(path).await?;
         Ok(CompressedHashedFileIn::new(file))
     }
+
+    /// Lists files under a given directory.
+    ///
+    /// `prefix` is the path of a directory relative to the root of the file
+    /// system, and an empty one lists every file. Returns paths relative to
+    /// the root, including those in subdirectories, in no particular order.
+    /// A missing directory has no files.
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot list files: {}",
+            prefix.into(),
+        )))
+    }
+
+    /// Reads a pointer; i.e., a small mutable file that names another file.
+    ///
+    /// See [`crate::io::ReadFileSystem::read_pointer`].
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot read pointers: {}",
+            path.into(),
+        )))
+    }
+
+    /// Reads a byte range of a file.
+    ///
+    /// Returns up to `len` bytes from `offset`; fewer if the file ends
+    /// before. Bytes in a range cannot be verified with the hash of the
+    /// entire file.
+    ///
+    /// The default implementation reads and discards bytes before `offset`.
+    /// Implementations should override it if they can fetch only the range.
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut file = self.open_hashed_file(path).await?;
+        tokio::io::copy(&mut (&mut file).take(offset), &mut tokio::io::sink())
+            .await?;
+        let mut buf: Vec<u8> = Vec::new();
+        file.take(len as u64).read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Reads a chunk of a file and verifies it with a Merkle tree of the
+    /// file.
+    ///
+    /// Fails with `Error::InvalidArgs` if `index` is out of bounds, or with
+    /// `Error::VerificationFailure` if the chunk cannot be verified.
+    async fn read_verified_chunk(
+        &self,
+        path: impl Into<String> + Send,
+        tree: &MerkleTree,
+        index: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let range = tree.chunk_range(index).ok_or(Error::InvalidArgs(
+            format!("chunk index out of bounds: {}", index),
+        ))?;
+        let chunk = self.read_range(
+            path,
+            range.start,
+            (range.end - range.start) as usize,
+        ).await?;
+        tree.verify_chunk(index, &chunk)?;
+        Ok(chunk)
+    }
+}
+
+/// Asynchronous file system that can be written.
+#[async_trait]
+pub trait WriteFileSystem {
+    /// File that calculates the hash of its contents.
+    type HashedFileOut: HashedFileOut;
+
+    /// Creates a file that calculates the hash of its contents.
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error>;
+
+    /// Creates a hashed file in a given directory.
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error>;
+
+    /// Creates a hashed file in a given directory, which hashes its contents
+    /// with a given algorithm.
+    ///
+    /// An empty `path` creates the file at the root of the file system.
+    ///
+    /// The default implementation supports only [`HashAlgorithm::Sha256`],
+    /// and fails with `Error::InvalidContext` for the others.
+    async fn create_hashed_file_in_with(
+        &self,
+        path: impl Into<String> + Send,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        algorithm.check_sha256()?;
+        let path = path.into();
+        if path.is_empty() {
+            self.create_hashed_file().await
+        } else {
+            self.create_hashed_file_in(path).await
+        }
+    }
+
+    /// Deletes a file.
+    ///
+    /// Deleting a missing file is not an error.
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot delete files: {}",
+            path.into(),
+        )))
+    }
+
+    /// Replaces a pointer with one naming a given target atomically.
+    ///
+    /// See [`crate::io::WriteFileSystem::write_pointer`].
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        _target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot write pointers: {}",
+            path.into(),
+        )))
+    }
+}
+
+/// File whose name will be the hash of its contents.
+#[async_trait]
+pub trait HashedFileOut: AsyncWrite + Send + Unpin {
+    /// Persists the file.
+    ///
+    /// Flushes the file, finishes the calculation of the hash, and persists
+    /// the file.
+    ///
+    /// Returns the encoded hash value that is supposed to be a URL-safe
+    /// Base64 encoded SHA256 digest, unless the file was created with
+    /// another [`HashAlgorithm`].
+    async fn persist(
+        self,
+        extension: impl Into<String> + Send,
+    ) -> Result<String, Error>;
 }
 
 /// File whose contents can be verified with the hash.
@@ -54,39 +279,47 @@ pub trait HashedFileIn: AsyncRead + Send + Unpin {
     async fn verify(self) -> Result<(), Error>;
 }
 
-pin_project! {
-    /// Compressed file whose contents can be verified with the hash.
-    pub struct CompressedHashedFileIn<R>
-    where
-        R: AsyncRead,
-    {
-        #[pin]
-        decoder: AsyncZlibDecoder<R>,
-    }
+/// Compressed file whose contents can be verified with the hash.
+///
+/// The codec is detected from the first bytes of the file.
+pub struct CompressedHashedFileIn<R>
+where
+    R: AsyncRead + Unpin,
+{
+    decoder: AsyncDecoder<R>,
 }
 
 impl<R> CompressedHashedFileIn<R>
 where
-    R: AsyncRead,
+    R: AsyncRead + Unpin,
 {
     /// Reads compressed data from a given [`AsyncRead`](https://docs.rs/tokio/1.32.0/tokio/io/trait.AsyncRead.html).
     pub fn new(r: R) -> Self {
+        Self::with_readahead_size(
+            r,
+            DEFAULT_READAHEAD_SIZE.try_into().unwrap(),
+        )
+    }
+
+    /// Reads compressed data from a given reader reading a given number of
+    /// bytes ahead.
+    pub fn with_readahead_size(r: R, readahead_size: NonZeroUsize) -> Self {
         Self {
-            decoder: AsyncZlibDecoder::new(r)
+            decoder: AsyncDecoder::new(r, readahead_size),
         }
     }
 }
 
 impl<R> AsyncRead for CompressedHashedFileIn<R>
 where
-    R: AsyncRead,
+    R: AsyncRead + Unpin,
 {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        self.project().decoder.poll_read(cx, buf)
+        Pin::new(&mut self.get_mut().decoder).poll_read(cx, buf)
     }
 }
 
@@ -100,29 +333,492 @@ where
     }
 }
 
+/// Hashed file verified chunk by chunk with a [`MerkleTree`].
+///
+/// Asynchronous counterpart of
+/// [`ChunkVerifiedFileIn`](crate::io::merkle::ChunkVerifiedFileIn).
+pub struct ChunkVerifiedFileIn<R> {
+    file: R,
+    tree: MerkleTree,
+    // Current chunk.
+    chunk: Vec<u8>,
+    // Number of bytes of the current chunk read from the file.
+    filled: usize,
+    // Position of the next byte to return in the current chunk.
+    pos: usize,
+    // Index of the next chunk to verify.
+    next_index: usize,
+    // Number of bytes in the verified chunks.
+    read_size: u64,
+}
+
+impl<R> ChunkVerifiedFileIn<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Wraps a given file with the Merkle tree of the contents.
+    ///
+    /// `file` does not have to hash the contents.
+    pub fn new(file: R, tree: MerkleTree) -> Self {
+        Self {
+            file,
+            tree,
+            chunk: Vec::new(),
+            filled: 0,
+            pos: 0,
+            next_index: 0,
+            read_size: 0,
+        }
+    }
+}
+
+impl<R> AsyncRead for ChunkVerifiedFileIn<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos == this.chunk.len() && this.filled == this.chunk.len() {
+            let remaining = this.tree.file_size() - this.read_size;
+            let len = remaining.min(this.tree.chunk_size() as u64) as usize;
+            this.chunk.resize(len, 0u8);
+            this.filled = 0;
+            this.pos = 0;
+        }
+        while this.filled < this.chunk.len() {
+            let mut chunk_buf = ReadBuf::new(&mut this.chunk[this.filled..]);
+            ready!(Pin::new(&mut this.file).poll_read(cx, &mut chunk_buf))?;
+            let n = chunk_buf.filled().len();
+            if n == 0 {
+                return Poll::Ready(
+                    Err(std::io::ErrorKind::UnexpectedEof.into()),
+                );
+            }
+            this.filled += n;
+            if this.filled == this.chunk.len() {
+                let index = this.next_index;
+                if let Err(e) = this.tree.verify_chunk(index, &this.chunk) {
+                    // never returns unverified bytes
+                    this.chunk.clear();
+                    this.filled = 0;
+                    return Poll::Ready(Err(std::io::Error::other(e)));
+                }
+                this.read_size += this.chunk.len() as u64;
+                this.next_index += 1;
+            }
+        }
+        let n = buf.remaining().min(this.chunk.len() - this.pos);
+        buf.put_slice(&this.chunk[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl<R> HashedFileIn for ChunkVerifiedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    /// Fails with `Error::VerificationFailure` if the file has not been
+    /// entirely read, or has extra bytes.
+    async fn verify(mut self) -> Result<(), Error> {
+        if self.tree.file_size() == 0 {
+            self.tree.verify_chunk(0, &[])?;
+        }
+        if self.read_size != self.tree.file_size() {
+            return Err(Error::VerificationFailure(format!(
+                "read only {} of {} bytes",
+                self.read_size,
+                self.tree.file_size(),
+            )));
+        }
+        if self.file.read(&mut [0u8; 1]).await? != 0 {
+            return Err(Error::VerificationFailure(format!(
+                "file is larger than {} bytes",
+                self.tree.file_size(),
+            )));
+        }
+        Ok(())
+    }
+}
+
 /// Asynchronous local file system.
 pub struct LocalFileSystem {
     base_path: PathBuf,
+    // Number of bytes read ahead from a file.
+    readahead_size: usize,
+    // Directory to create temporary files in. The directory of each file
+    // if `None`.
+    temp_dir: Option<PathBuf>,
+    // Whether persisted files are synced to the storage device.
+    sync_on_persist: bool,
 }
 
 impl LocalFileSystem {
     /// Creates a local file system working under a given base path.
+    ///
+    /// Reads [`DEFAULT_READAHEAD_SIZE`] bytes ahead from a file.
     pub fn new(base_path: impl AsRef<Path>) -> Self {
         Self {
             base_path: base_path.as_ref().to_path_buf(),
+            readahead_size: DEFAULT_READAHEAD_SIZE,
+            temp_dir: None,
+            sync_on_persist: false,
         }
     }
+
+    /// Sets the directory to create temporary files in.
+    ///
+    /// The directory must be on the same file system as the base path,
+    /// because a persisted file is renamed from its temporary file.
+    ///
+    /// The directory the file is persisted in by default.
+    pub fn with_temp_dir<P>(mut self, dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.temp_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets whether persisted files are synced to the storage device.
+    ///
+    /// See [`crate::io::LocalFileSystem::with_sync_on_persist`].
+    pub fn with_sync_on_persist(mut self, sync_on_persist: bool) -> Self {
+        self.sync_on_persist = sync_on_persist;
+        self
+    }
+
+    // Creates a file to be persisted under a given path.
+    async fn create_hashed_file_at(
+        &self,
+        base_path: PathBuf,
+        algorithm: HashAlgorithm,
+    ) -> Result<LocalHashedFileOut, Error> {
+        let hasher = Hasher::new(algorithm)?;
+        let mut file = LocalHashedFileOut::create(
+            base_path,
+            self.temp_dir.as_deref(),
+        ).await?;
+        file.hasher = hasher;
+        file.sync_on_persist = self.sync_on_persist;
+        Ok(file)
+    }
+
+    /// Sets the number of bytes read ahead from a file.
+    ///
+    /// Every read from a file goes to a blocking thread of Tokio, so larger
+    /// readahead saves round trips to the thread as well as system calls.
+    /// Zero disables readahead.
+    pub fn with_readahead_size(mut self, readahead_size: usize) -> Self {
+        self.readahead_size = readahead_size;
+        self
+    }
 }
 
 #[async_trait]
-impl FileSystem for LocalFileSystem {
+impl ReadFileSystem for LocalFileSystem {
     type HashedFileIn = LocalHashedFileIn;
 
     async fn open_hashed_file(
         &self,
         path: impl Into<String> + Send,
     ) -> Result<Self::HashedFileIn, Error> {
-        LocalHashedFileIn::open(self.base_path.join(path.into())).await
+        LocalHashedFileIn::open(
+            self.base_path.join(path.into()),
+            self.readahead_size,
+        ).await
+    }
+
+    async fn open_hashed_file_with(
+        &self,
+        path: impl Into<String> + Send,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let hasher = Hasher::new(algorithm)?;
+        let mut file = self.open_hashed_file(path).await?;
+        file.hasher = Some(hasher);
+        Ok(file)
+    }
+
+    /// Does not hash the contents.
+    async fn open_hashed_file_unverified(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let mut file = self.open_hashed_file(path).await?;
+        file.hasher = None;
+        Ok(file)
+    }
+
+    /// Reads ahead in the decompressor instead of the file.
+    async fn open_compressed_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<CompressedHashedFileIn<Self::HashedFileIn>, Error> {
+        let file =
+            LocalHashedFileIn::open(self.base_path.join(path.into()), 0).await?;
+        Ok(match NonZeroUsize::new(self.readahead_size) {
+            Some(readahead_size) => {
+                CompressedHashedFileIn::with_readahead_size(
+                    file,
+                    readahead_size,
+                )
+            },
+            None => CompressedHashedFileIn::new(file),
+        })
+    }
+
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut file = File::open(self.base_path.join(path.into())).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut buf: Vec<u8> = Vec::new();
+        file.take(len as u64).read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        let mut files: Vec<String> = Vec::new();
+        let mut dirs = vec![prefix.into().trim_matches('/').to_string()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries =
+                match tokio::fs::read_dir(self.base_path.join(&dir)).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        continue;
+                    },
+                    Err(e) => return Err(e.into()),
+                };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().into_string().map_err(|name| {
+                    Error::InvalidData(format!(
+                        "non UTF-8 file name: {:?}",
+                        name,
+                    ))
+                })?;
+                let path = if dir.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", dir, name)
+                };
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        match tokio::fs::read(self.base_path.join(path.into())).await {
+            Ok(contents) => parse_pointer(contents).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl WriteFileSystem for LocalFileSystem {
+    type HashedFileOut = LocalHashedFileOut;
+
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.create_hashed_file_at(
+            self.base_path.clone(),
+            HashAlgorithm::Sha256,
+        ).await
+    }
+
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.create_hashed_file_at(
+            self.base_path.join(path.into()),
+            HashAlgorithm::Sha256,
+        ).await
+    }
+
+    async fn create_hashed_file_in_with(
+        &self,
+        path: impl Into<String> + Send,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.create_hashed_file_at(self.base_path.join(path.into()), algorithm)
+            .await
+    }
+
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.base_path.join(path.into())).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the target to a temporary file next to the pointer, and
+    /// renames it over the pointer.
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        let path = self.base_path.join(path.into());
+        let dir = path.parent().unwrap_or(&self.base_path);
+        tokio::fs::create_dir_all(dir).await?;
+        let temp_path = dir.join(temp_file_name());
+        let mut file = File::create(&temp_path).await?;
+        let result = async {
+            file.write_all(target.into().as_bytes()).await?;
+            file.flush().await?;
+            if self.sync_on_persist {
+                file.sync_all().await?;
+            }
+            tokio::fs::rename(&temp_path, &path).await
+        }.await;
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+        if self.sync_on_persist {
+            #[cfg(unix)]
+            File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+// Returns a random name of a temporary file.
+fn temp_file_name() -> String {
+    let name: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    format!(".tmp{}", name)
+}
+
+/// Writable file in the local file system.
+///
+/// Written to a temporary file in the directory to persist it in, unless
+/// another directory is specified to the file system, and renamed to the
+/// hash of its contents. The temporary file is removed if the
+/// file is dropped without being persisted.
+pub struct LocalHashedFileOut {
+    file: File,
+    // Temporary path. `None` once persisted.
+    temp_path: Option<PathBuf>,
+    // Directory to persist the file in.
+    base_path: PathBuf,
+    // Hasher of the contents.
+    hasher: Hasher,
+    // Whether the file and its directory are synced when persisted.
+    sync_on_persist: bool,
+}
+
+impl LocalHashedFileOut {
+    // Creates a temporary file to be persisted under a given path.
+    //
+    // The temporary file is created in `temp_dir` if specified, otherwise in
+    // `base_path`.
+    async fn create(
+        base_path: PathBuf,
+        temp_dir: Option<&Path>,
+    ) -> Result<Self, Error> {
+        tokio::fs::create_dir_all(&base_path).await?;
+        let temp_path = temp_dir.unwrap_or(&base_path).join(temp_file_name());
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .await?;
+        Ok(Self {
+            file,
+            temp_path: Some(temp_path),
+            base_path,
+            hasher: Hasher::sha256(),
+            sync_on_persist: false,
+        })
+    }
+}
+
+impl AsyncWrite for LocalHashedFileOut {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.file).poll_write(cx, buf))?;
+        this.hasher.update(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl HashedFileOut for LocalHashedFileOut {
+    async fn persist(
+        mut self,
+        extension: impl Into<String> + Send,
+    ) -> Result<String, Error> {
+        self.file.flush().await?;
+        let hash = self.hasher.clone().finish();
+        let path = self.base_path
+            .join(&hash)
+            .with_extension(extension.into());
+        if self.sync_on_persist {
+            self.file.sync_all().await?;
+        }
+        if let Some(temp_path) = self.temp_path.take() {
+            tokio::fs::rename(temp_path, path).await?;
+        }
+        if self.sync_on_persist {
+            #[cfg(unix)]
+            File::open(&self.base_path).await?.sync_all().await?;
+        }
+        Ok(hash)
+    }
+}
+
+impl Drop for LocalHashedFileOut {
+    fn drop(&mut self) {
+        if let Some(temp_path) = self.temp_path.take() {
+            let _ = std::fs::remove_file(temp_path);
+        }
     }
 }
 
@@ -134,14 +830,16 @@ pin_project! {
     #[must_use = "futures do nothing unless you `.await` or poll them"]
     pub struct LocalHashedFileIn {
         #[pin]
-        file: File,
+        file: BufReader<File>,
         hash: String,
-        digest: ring::digest::Context,
+        // Hasher of the contents. `None` if the contents are not hashed.
+        hasher: Option<Hasher>,
     }
 }
 
 impl LocalHashedFileIn {
-    async fn open(path: PathBuf) -> Result<Self, Error> {
+    // Opens a file reading `readahead_size` bytes ahead.
+    async fn open(path: PathBuf, readahead_size: usize) -> Result<Self, Error> {
         let hash = path.file_stem()
             .ok_or(Error::InvalidArgs(format!(
                 "file name must be hash: {}",
@@ -151,9 +849,9 @@ impl LocalHashedFileIn {
             .to_string();
         let file = File::open(&path).await?;
         Ok(Self {
-            file,
+            file: BufReader::with_capacity(readahead_size, file),
             hash,
-            digest: ring::digest::Context::new(&ring::digest::SHA256),
+            hasher: Some(Hasher::sha256()),
         })
     }
 }
@@ -161,8 +859,11 @@ impl LocalHashedFileIn {
 #[async_trait]
 impl HashedFileIn for LocalHashedFileIn {
     async fn verify(self) -> Result<(), Error> {
-        let digest = self.digest.finish();
-        let hash = url_safe_base_64.encode(digest);
+        let hasher = self.hasher.ok_or(Error::InvalidContext(format!(
+            "file was opened without hashing: {}",
+            self.hash,
+        )))?;
+        let hash = hasher.finish();
         if self.hash == hash {
             Ok(())
         } else {
@@ -185,9 +886,8 @@ impl AsyncRead for LocalHashedFileIn {
         let last_len = buf.filled().len();
         match this.file.poll_read(cx, buf) {
             Poll::Ready(Ok(())) => {
-                if buf.filled().len() != last_len {
-                    let buf = &buf.filled()[last_len..];
-                    this.digest.update(buf);
+                if let Some(hasher) = this.hasher.as_mut() {
+                    hasher.update(&buf.filled()[last_len..]);
                 }
                 Poll::Ready(Ok(()))
             },
@@ -197,9 +897,6 @@ impl AsyncRead for LocalHashedFileIn {
     }
 }
 
-// Size of `input_buf` of `AsyncZlibDecoder`.
-const INPUT_BUFFER_SIZE: usize = 1024;
-
 pin_project! {
     /// Zlib decoder that reads bytes from [`AsyncRead`](https://docs.rs/tokio/1.32.0/tokio/io/trait.AsyncRead.html).
     pub struct AsyncZlibDecoder<R> {
@@ -208,21 +905,39 @@ pin_project! {
         reader_finished: bool,
         decoder: Decompress,
         decoder_finished: bool,
-        input_buf: [MaybeUninit<u8>; INPUT_BUFFER_SIZE],
+        // Bytes read ahead from `reader`.
+        // `input_buf[input_pos..input_len]` has not been decompressed yet.
+        input_buf: Box<[u8]>,
         input_pos: usize,
+        input_len: usize,
     }
 }
 
 impl<R> AsyncZlibDecoder<R> {
     /// Decompresses bytes from a given reader.
+    ///
+    /// Reads [`DEFAULT_READAHEAD_SIZE`] bytes ahead from the reader.
     pub fn new(reader: R) -> Self {
+        Self::with_readahead_size(
+            reader,
+            DEFAULT_READAHEAD_SIZE.try_into().unwrap(),
+        )
+    }
+
+    /// Decompresses bytes from a given reader reading a given number of
+    /// bytes ahead.
+    pub fn with_readahead_size(
+        reader: R,
+        readahead_size: NonZeroUsize,
+    ) -> Self {
         Self {
             reader,
             reader_finished: false,
             decoder: Decompress::new(true),
             decoder_finished: false,
-            input_buf: unsafe { MaybeUninit::uninit().assume_init() },
+            input_buf: vec![0u8; readahead_size.get()].into_boxed_slice(),
             input_pos: 0,
+            input_len: 0,
         }
     }
 
@@ -244,120 +959,307 @@ where
         cx: &mut core::task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        macro_rules! assume_advance {
-            ($buf:ident, $amount:expr) => {
-                unsafe { $buf.assume_init($buf.filled().len() + $amount); }
-                buf.advance($amount);
-            };
-        }
-
         let mut this = self.project();
         let initial_len = buf.filled().len();
-        let mut input_buf = ReadBuf::uninit(this.input_buf);
-        unsafe { input_buf.assume_init(*this.input_pos); }
-        input_buf.set_filled(*this.input_pos);
-        let mut had_buf_error = false;
         loop {
-            if *this.input_pos < input_buf.filled().len()
-                && buf.remaining() > 0
+            if buf.filled().len() > initial_len || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            if *this.decoder_finished {
+                if *this.input_pos < *this.input_len {
+                    return Poll::Ready(Err(std::io::Error::other(
+                        Error::InvalidData(
+                            "extra bytes after compressed block".to_string(),
+                        ),
+                    )));
+                }
+                if *this.reader_finished {
+                    return Poll::Ready(Ok(()));
+                }
+            } else if *this.input_pos < *this.input_len
+                || *this.reader_finished
             {
-                // decompresses the remaining input unless `buf` is full
+                // decompresses the input read ahead
                 let last_total_in = this.decoder.total_in();
                 let last_total_out = this.decoder.total_out();
-                let input = &input_buf.filled()[*this.input_pos..];
-                match this.decoder.decompress(
-                    input,
-                    unsafe { transmute(buf.unfilled_mut()) },
+                let status = this.decoder.decompress(
+                    &this.input_buf[*this.input_pos..*this.input_len],
+                    buf.initialize_unfilled(),
                     if *this.reader_finished {
                         FlushDecompress::Finish
                     } else {
                         FlushDecompress::None
                     },
-                ) {
-                    Ok(flate2::Status::Ok) => {
-                        let num_written =
-                            this.decoder.total_out() - last_total_out;
-                        assume_advance!(buf, num_written as usize);
-                        let num_read = this.decoder.total_in() - last_total_in;
-                        *this.input_pos += num_read as usize;
-                        if *this.input_pos == input_buf.filled().len() {
-                            input_buf.clear();
-                            *this.input_pos = 0;
-                        }
-                        had_buf_error = false;
-                    },
-                    Ok(flate2::Status::BufError) => {
-                        if had_buf_error {
-                            return Poll::Ready(Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                Error::InvalidContext(format!(
-                                    "got persisted decoder buffer error",
-                                )),
-                            )));
-                        }
-                        had_buf_error = true;
-                    },
-                    Ok(flate2::Status::StreamEnd) => {
-                        *this.decoder_finished = true;
-                        let num_written =
-                            this.decoder.total_out() - last_total_out;
-                        assume_advance!(buf, num_written as usize);
-                        let num_read = this.decoder.total_in() - last_total_in;
-                        *this.input_pos += num_read as usize;
-                        if *this.input_pos == input_buf.filled().len() {
-                            input_buf.clear();
-                            *this.input_pos = 0;
-                        } else {
-                            return Poll::Ready(Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                Error::InvalidData(format!(
-                                    "extra bytes after compressed block",
-                                )),
-                            )));
-                        }
-                        had_buf_error = false;
-                    },
-                    Err(err) => {
-                        return Poll::Ready(Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            err,
-                        )));
-                    },
-                };
-            }
-            if !*this.reader_finished && input_buf.remaining() > 0 {
-                // reads more bytes from the reader
-                // unless the reader has finished, or input_buf is full
-                let last_len = input_buf.filled().len();
-                match this.reader
-                    .as_mut()
-                    .poll_read(cx, &mut input_buf)
-                {
-                    Poll::Ready(Ok(_)) => {
-                        if input_buf.filled().len() == last_len {
-                            *this.reader_finished = true;
-                        } else if *this.decoder_finished {
-                            return Poll::Ready(Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                Error::InvalidData(format!(
-                                    "extra bytes after compressed block",
-                                )),
-                            )));
-                        }
-                    },
-                    Poll::Pending => {
-                        if buf.filled().len() > initial_len {
-                            return Poll::Ready(Ok(()));
-                        } else {
-                            return Poll::Pending;
-                        }
-                    },
-                    Poll::Ready(err) => return Poll::Ready(err),
+                ).map_err(std::io::Error::other)?;
+                let num_written =
+                    (this.decoder.total_out() - last_total_out) as usize;
+                let num_read =
+                    (this.decoder.total_in() - last_total_in) as usize;
+                buf.advance(num_written);
+                *this.input_pos += num_read;
+                if status == flate2::Status::StreamEnd {
+                    *this.decoder_finished = true;
+                }
+                if num_written > 0 || num_read > 0 || *this.decoder_finished {
+                    continue;
+                }
+                if *this.reader_finished {
+                    return Poll::Ready(Err(std::io::Error::other(
+                        Error::InvalidData(
+                            "unexpected end of compressed block".to_string(),
+                        ),
+                    )));
                 }
             }
-            if *this.decoder_finished && *this.reader_finished {
-                return Poll::Ready(Ok(()));
+            // reads more bytes ahead from the reader
+            this.input_buf.copy_within(*this.input_pos..*this.input_len, 0);
+            *this.input_len -= *this.input_pos;
+            *this.input_pos = 0;
+            if *this.input_len == this.input_buf.len() {
+                return Poll::Ready(Err(std::io::Error::other(
+                    Error::InvalidContext(
+                        "got persisted decoder buffer error".to_string(),
+                    ),
+                )));
+            }
+            let mut input_buf =
+                ReadBuf::new(&mut this.input_buf[*this.input_len..]);
+            ready!(this.reader.as_mut().poll_read(cx, &mut input_buf))?;
+            match input_buf.filled().len() {
+                0 => *this.reader_finished = true,
+                n => *this.input_len += n,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    // File system that only supports whole-file reads.
+    struct WholeFileSystem(LocalFileSystem);
+
+    #[async_trait]
+    impl ReadFileSystem for WholeFileSystem {
+        type HashedFileIn = LocalHashedFileIn;
+
+        async fn open_hashed_file(
+            &self,
+            path: impl Into<String> + Send,
+        ) -> Result<Self::HashedFileIn, Error> {
+            self.0.open_hashed_file(path).await
+        }
+    }
+
+    #[tokio::test]
+    async fn read_range_should_read_only_requested_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = std::fs::File::create(dir.path().join("data.bin"))
+            .unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        assert_eq!(fs.read_range("data.bin", 2, 3).await.unwrap(), b"234");
+        assert_eq!(fs.read_range("data.bin", 8, 5).await.unwrap(), b"89");
+        assert!(fs.read_range("data.bin", 20, 5).await.unwrap().is_empty());
+        let fs = WholeFileSystem(fs);
+        assert_eq!(fs.read_range("data.bin", 2, 3).await.unwrap(), b"234");
+        assert_eq!(fs.read_range("data.bin", 8, 5).await.unwrap(), b"89");
+        assert!(fs.read_range("data.bin", 20, 5).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn local_file_system_should_list_and_delete_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("data/nested")).unwrap();
+        std::fs::create_dir(dir.path().join("database")).unwrap();
+        for path in ["data/a.bin", "data/nested/b.bin", "database/c.bin"] {
+            std::fs::write(dir.path().join(path), b"0123").unwrap();
+        }
+        let fs = LocalFileSystem::new(dir.path());
+        let mut files = fs.list_files("data").await.unwrap();
+        files.sort();
+        assert_eq!(files, ["data/a.bin", "data/nested/b.bin"]);
+        assert_eq!(fs.list_files("").await.unwrap().len(), 3);
+        assert!(fs.list_files("missing").await.unwrap().is_empty());
+
+        fs.delete("data/a.bin").await.unwrap();
+        fs.delete("data/a.bin").await.unwrap();
+        assert_eq!(
+            fs.list_files("data/").await.unwrap(),
+            ["data/nested/b.bin"],
+        );
+        assert!(WholeFileSystem(fs).list_files("").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_hashed_file_out_should_be_persisted_under_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let mut f = fs.create_hashed_file_in("data").await.unwrap();
+        f.write_all(b"0123456789").await.unwrap();
+        let hash = f.persist("bin").await.unwrap();
+        assert_eq!(
+            hash,
+            HashAlgorithm::Sha256.hash(b"0123456789").unwrap(),
+        );
+        let path = format!("data/{}.bin", hash);
+        assert_eq!(fs.list_files("data").await.unwrap(), [path.as_str()]);
+        let mut f = fs.open_hashed_file(&path).await.unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"0123456789");
+        f.verify().await.unwrap();
+
+        let mut f = fs.create_hashed_file_in("data").await.unwrap();
+        f.write_all(b"discarded").await.unwrap();
+        drop(f);
+        let entries = std::fs::read_dir(dir.path().join("data")).unwrap();
+        assert_eq!(entries.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn unverified_local_hashed_file_in_should_not_be_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let mut f = fs.create_hashed_file().await.unwrap();
+        f.write_all(b"0123456789").await.unwrap();
+        let path = format!("{}.bin", f.persist("bin").await.unwrap());
+        let mut f = fs.open_hashed_file_unverified(path).await.unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"0123456789");
+        assert!(matches!(f.verify().await, Err(Error::InvalidContext(_))));
+    }
+
+    #[tokio::test]
+    async fn local_hashed_file_out_should_be_written_in_temp_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().join("temp");
+        std::fs::create_dir(&temp_dir).unwrap();
+        let fs = LocalFileSystem::new(dir.path().join("db"))
+            .with_temp_dir(&temp_dir);
+        let mut f = fs.create_hashed_file_in("data").await.unwrap();
+        f.write_all(b"0123").await.unwrap();
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 1);
+        let hash = f.persist("bin").await.unwrap();
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert_eq!(
+            fs.list_files("data").await.unwrap(),
+            [format!("data/{}.bin", hash)],
+        );
+    }
+
+    #[tokio::test]
+    async fn async_zlib_decoder_should_decompress_with_any_readahead_size() {
+        let contents: Vec<u8> = (0..100_000u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut encoder = flate2::write::ZlibEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&contents).unwrap();
+        let compressed = encoder.finish().unwrap();
+        for size in [1, 7, 1024, DEFAULT_READAHEAD_SIZE] {
+            let mut decoder = AsyncZlibDecoder::with_readahead_size(
+                &compressed[..],
+                size.try_into().unwrap(),
+            );
+            let mut buf: Vec<u8> = Vec::new();
+            decoder.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, contents);
+        }
+        let truncated = &compressed[..compressed.len() / 2];
+        let mut decoder = AsyncZlibDecoder::new(truncated);
+        assert!(decoder.read_to_end(&mut Vec::new()).await.is_err());
+        let mut extra = compressed.clone();
+        extra.push(0);
+        let mut decoder = AsyncZlibDecoder::new(&extra[..]);
+        assert!(decoder.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn compressed_hashed_file_in_should_detect_codec() {
+        use crate::io::{
+            CompressedHashedFileOut,
+            HashedFileOut as SyncHashedFileOut,
+            LocalFileSystem as SyncLocalFileSystem,
+            WriteFileSystem as SyncWriteFileSystem,
+        };
+        use crate::io::codec::Codec;
+
+        let contents: Vec<u8> = (0..100_000u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let sync_fs = SyncLocalFileSystem::new(dir.path());
+        let fs = LocalFileSystem::new(dir.path());
+        for codec in [Codec::Zlib, Codec::Zstd, Codec::Lz4] {
+            if !codec.is_available() {
+                continue;
             }
+            let mut f = CompressedHashedFileOut::with_codec(
+                sync_fs.create_hashed_file().unwrap(),
+                codec,
+                6,
+            ).unwrap();
+            std::io::Write::write_all(&mut f, &contents).unwrap();
+            let hash = f.persist("bin").unwrap();
+            let mut f = fs
+                .open_compressed_hashed_file(format!("{}.bin", hash))
+                .await
+                .unwrap();
+            let mut buf: Vec<u8> = Vec::new();
+            f.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, contents);
+            f.verify().await.unwrap();
         }
     }
+
+    #[tokio::test]
+    async fn read_verified_chunk_should_reject_modified_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let tree = MerkleTree::from_reader(
+            &b"0123456789"[..],
+            4.try_into().unwrap(),
+        ).unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let chunk = fs.read_verified_chunk("data.bin", &tree, 2).await;
+        assert_eq!(chunk.unwrap(), b"89");
+        std::fs::write(&path, b"0123X56789").unwrap();
+        assert!(fs.read_verified_chunk("data.bin", &tree, 0).await.is_ok());
+        assert!(matches!(
+            fs.read_verified_chunk("data.bin", &tree, 1).await,
+            Err(Error::VerificationFailure(_)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn chunk_verified_file_in_should_stop_at_corrupted_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let tree = MerkleTree::from_reader(
+            &b"0123456789"[..],
+            4.try_into().unwrap(),
+        ).unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let f = fs.open_hashed_file_unverified("data.bin").await.unwrap();
+        let mut f = ChunkVerifiedFileIn::new(f, tree.clone());
+        let mut contents = Vec::new();
+        f.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, b"0123456789");
+        f.verify().await.unwrap();
+        std::fs::write(&path, b"0123X56789").unwrap();
+        let f = fs.open_hashed_file_unverified("data.bin").await.unwrap();
+        let mut f = ChunkVerifiedFileIn::new(f, tree);
+        let mut contents = Vec::new();
+        assert!(f.read_to_end(&mut contents).await.is_err());
+        assert_eq!(contents, b"0123");
+    }
 }
@@ -0,0 +1,417 @@
+//! Read-through cache of hashed files for asynchronous file systems.
+//!
+//! [`CachingFileSystem`] works like
+//! [`io::cache::CachingFileSystem`](crate::io::cache::CachingFileSystem) on
+//! an asynchronous file system; e.g., one over HTTP, including the quota of
+//! cached copies.
+
+use async_trait::async_trait;
+use base64::engine::{
+    Engine,
+    general_purpose::URL_SAFE_NO_PAD as url_safe_base_64,
+};
+use core::pin::Pin;
+use core::task::Poll;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::error::Error;
+use crate::io::cache::CacheIndex;
+
+use super::{
+    DEFAULT_READAHEAD_SIZE,
+    HashedFileIn,
+    LocalHashedFileIn,
+    ReadFileSystem,
+    WriteFileSystem,
+};
+
+/// Asynchronous file system that caches files of another file system in a
+/// local directory.
+///
+/// A file is cached when it has been entirely read and its contents match
+/// the hash in its name; subsequent opens read the cached copy. A cached
+/// copy that fails verification is removed so that it is fetched again.
+///
+/// Copies are written to the cache directory with blocking writes, which
+/// are expected to be much faster than fetching the contents.
+pub struct CachingFileSystem<FS> {
+    inner: FS,
+    cache_dir: PathBuf,
+    // Tracks cached copies if the cache has a quota.
+    index: Option<Arc<Mutex<CacheIndex>>>,
+}
+
+impl<FS> CachingFileSystem<FS> {
+    /// Wraps a given file system caching files under a given directory.
+    pub fn new(inner: FS, cache_dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner,
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+            index: None,
+        }
+    }
+
+    /// Limits the total size of cached copies to a given number of bytes.
+    ///
+    /// Shares the index file with
+    /// [`io::cache::CachingFileSystem::with_quota`](
+    /// crate::io::cache::CachingFileSystem::with_quota), and loads it with
+    /// blocking reads.
+    pub fn with_quota(mut self, quota: u64) -> Self {
+        self.index = Some(Arc::new(Mutex::new(
+            CacheIndex::load(&self.cache_dir, quota),
+        )));
+        self
+    }
+
+    /// Returns the wrapped file system.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Returns the cache directory.
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Returns the total size of cached copies in bytes if the cache has a
+    /// quota.
+    pub fn cached_size(&self) -> Option<u64> {
+        self.index.as_ref().map(|index| index.lock().unwrap().total_size())
+    }
+}
+
+#[async_trait]
+impl<FS> ReadFileSystem for CachingFileSystem<FS>
+where
+    FS: ReadFileSystem + Sync,
+{
+    type HashedFileIn = CachedHashedFileIn<FS::HashedFileIn>;
+
+    async fn open_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let path = path.into();
+        let cache_path = self.cache_dir.join(&path);
+        if tokio::fs::metadata(&cache_path).await.is_ok_and(|m| m.is_file()) {
+            if let Some(index) = self.index.as_ref() {
+                index.lock().unwrap().touch(&path);
+            }
+            return Ok(CachedHashedFileIn {
+                source: Source::Cached {
+                    file: LocalHashedFileIn::open(
+                        cache_path.clone(),
+                        DEFAULT_READAHEAD_SIZE,
+                    ).await?,
+                    path: cache_path,
+                },
+                key: path,
+                index: self.index.clone(),
+            });
+        }
+        let file = self.inner.open_hashed_file(path.clone()).await?;
+        let tempfile = match cache_path.parent() {
+            Some(dir) => match tokio::fs::create_dir_all(dir).await {
+                Ok(_) => NamedTempFile::new_in(dir).ok(),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        Ok(CachedHashedFileIn {
+            source: Source::Fetching {
+                file,
+                tempfile,
+                size: 0,
+                cache_path,
+                context: Some(
+                    ring::digest::Context::new(&ring::digest::SHA256),
+                ),
+            },
+            key: path,
+            index: self.index.clone(),
+        })
+    }
+
+    /// Reads the range from the cached copy if any; otherwise, from the
+    /// wrapped file system without caching it.
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let path = path.into();
+        let cache_path = self.cache_dir.join(&path);
+        if tokio::fs::metadata(&cache_path).await.is_ok_and(|m| m.is_file()) {
+            if let Some(index) = self.index.as_ref() {
+                index.lock().unwrap().touch(&path);
+            }
+            super::LocalFileSystem::new(&self.cache_dir)
+                .read_range(path, offset, len)
+                .await
+        } else {
+            self.inner.read_range(path, offset, len).await
+        }
+    }
+
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        self.inner.list_files(prefix).await
+    }
+
+    /// Pointers are mutable, so they are never cached.
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        self.inner.read_pointer(path).await
+    }
+}
+
+#[async_trait]
+impl<FS> WriteFileSystem for CachingFileSystem<FS>
+where
+    FS: WriteFileSystem + Sync,
+{
+    type HashedFileOut = FS::HashedFileOut;
+
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file().await
+    }
+
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file_in(path).await
+    }
+
+    /// Deletes the file from the wrapped file system, and its cached copy.
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        let path = path.into();
+        self.inner.delete(path.clone()).await?;
+        match tokio::fs::remove_file(self.cache_dir.join(&path)).await {
+            Ok(()) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(index) = self.index.as_ref() {
+            index.lock().unwrap().remove(&path);
+        }
+        Ok(())
+    }
+
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.inner.write_pointer(path, target).await
+    }
+}
+
+/// File read from the cache, or fetched from the wrapped file system.
+pub struct CachedHashedFileIn<R> {
+    source: Source<R>,
+    // Path of the file relative to the cache directory.
+    key: String,
+    index: Option<Arc<Mutex<CacheIndex>>>,
+}
+
+// Where a file is read from.
+enum Source<R> {
+    // Cached copy.
+    Cached {
+        file: LocalHashedFileIn,
+        path: PathBuf,
+    },
+    // Wrapped file system. Contents are copied to the cache.
+    Fetching {
+        file: R,
+        // Copy of the contents. `None` once persisted or discarded.
+        tempfile: Option<NamedTempFile>,
+        // Number of bytes copied.
+        size: u64,
+        cache_path: PathBuf,
+        // Context to calculate an SHA-256 digest.
+        context: Option<ring::digest::Context>,
+    },
+}
+
+impl<R> CachedHashedFileIn<R> {
+    /// Returns if the file is read from the cache.
+    pub fn is_cached(&self) -> bool {
+        matches!(self.source, Source::Cached { .. })
+    }
+}
+
+impl<R> AsyncRead for CachedHashedFileIn<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match &mut this.source {
+            Source::Cached { file, .. } => Pin::new(file).poll_read(cx, buf),
+            Source::Fetching { file, tempfile, size, cache_path, context } => {
+                let last_len = buf.filled().len();
+                match Pin::new(file).poll_read(cx, buf) {
+                    Poll::Ready(Ok(())) => {},
+                    poll => return poll,
+                }
+                let read = &buf.filled()[last_len..];
+                if read.is_empty() {
+                    let finished = buf.remaining() > 0
+                        && finish_copy(tempfile, context, cache_path);
+                    if let (true, Some(index)) = (finished, &this.index) {
+                        index.lock().unwrap().insert(&this.key, *size);
+                    }
+                } else if let Some(f) = tempfile.as_mut() {
+                    if f.write_all(read).is_ok() {
+                        *size += read.len() as u64;
+                        if let Some(context) = context.as_mut() {
+                            context.update(read);
+                        }
+                    } else {
+                        *tempfile = None;
+                    }
+                }
+                Poll::Ready(Ok(()))
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl<R> HashedFileIn for CachedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    async fn verify(self) -> Result<(), Error> {
+        match self.source {
+            Source::Cached { file, path } => {
+                let result = file.verify().await;
+                if result.is_err() {
+                    // fetches the file again next time
+                    let _ = tokio::fs::remove_file(path).await;
+                    if let Some(index) = self.index.as_ref() {
+                        index.lock().unwrap().remove(&self.key);
+                    }
+                }
+                result
+            },
+            Source::Fetching { file, .. } => file.verify().await,
+        }
+    }
+}
+
+// Moves a copy into the cache if its hash matches the file name.
+//
+// Returns if the copy has been moved.
+fn finish_copy(
+    tempfile: &mut Option<NamedTempFile>,
+    context: &mut Option<ring::digest::Context>,
+    cache_path: &Path,
+) -> bool {
+    let (Some(tempfile), Some(context)) = (tempfile.take(), context.take())
+    else {
+        return false;
+    };
+    let hash = url_safe_base_64.encode(context.finish());
+    let stem = cache_path.file_stem().and_then(|stem| stem.to_str());
+    stem == Some(hash.as_str()) && tempfile.persist(cache_path).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+
+    use crate::asyncdb::io::LocalFileSystem;
+
+    #[tokio::test]
+    async fn caching_file_system_should_serve_fetched_files_from_cache() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let contents = b"0123456789";
+        let hash = url_safe_base_64.encode(
+            ring::digest::digest(&ring::digest::SHA256, contents),
+        );
+        let path = format!("data/{}.bin", hash);
+        std::fs::create_dir(remote_dir.path().join("data")).unwrap();
+        std::fs::write(remote_dir.path().join(&path), contents).unwrap();
+        let fs = CachingFileSystem::new(
+            LocalFileSystem::new(remote_dir.path()),
+            cache_dir.path(),
+        );
+
+        let mut f = fs.open_hashed_file(&path).await.unwrap();
+        assert!(!f.is_cached());
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, contents);
+        f.verify().await.unwrap();
+        // serves the copy even if the original is gone
+        std::fs::remove_file(remote_dir.path().join(&path)).unwrap();
+        let mut f = fs.open_hashed_file(&path).await.unwrap();
+        assert!(f.is_cached());
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, contents);
+        f.verify().await.unwrap();
+        assert_eq!(fs.read_range(&path, 2, 3).await.unwrap(), b"234");
+
+        // a corrupted copy is removed
+        std::fs::write(cache_dir.path().join(&path), b"012345678X").unwrap();
+        let mut f = fs.open_hashed_file(&path).await.unwrap();
+        f.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(f.verify().await.is_err());
+        assert!(fs.open_hashed_file(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn caching_file_system_should_keep_copies_within_quota() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = (0..3u8)
+            .map(|i| {
+                let contents = [i; 10];
+                let hash = url_safe_base_64.encode(
+                    ring::digest::digest(&ring::digest::SHA256, &contents),
+                );
+                std::fs::write(
+                    remote_dir.path().join(format!("{}.bin", hash)),
+                    contents,
+                ).unwrap();
+                format!("{}.bin", hash)
+            })
+            .collect();
+        let fs = CachingFileSystem::new(
+            LocalFileSystem::new(remote_dir.path()),
+            cache_dir.path(),
+        ).with_quota(25);
+        for path in [&paths[0], &paths[1], &paths[0], &paths[2]] {
+            let mut f = fs.open_hashed_file(path).await.unwrap();
+            f.read_to_end(&mut Vec::new()).await.unwrap();
+        }
+        assert_eq!(fs.cached_size(), Some(20));
+        assert!(cache_dir.path().join(&paths[0]).exists());
+        assert!(!cache_dir.path().join(&paths[1]).exists());
+        assert!(cache_dir.path().join(&paths[2]).exists());
+    }
+}
@@ -0,0 +1,185 @@
+// Asynchronous decoder of compressed files.
+//
+// The codec is detected in the same way as the synchronous decoder in
+// `crate::io::codec`.
+
+use core::num::NonZeroUsize;
+use core::pin::Pin;
+use core::task::{Context, Poll, ready};
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+use tokio::io::BufReader;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::io::codec::{Codec, MAGIC_SIZE};
+
+use super::AsyncZlibDecoder;
+
+// Decoder that detects the codec from the first bytes.
+pub(crate) enum AsyncDecoder<R> {
+    // Before the codec is detected. `reader` is `None` only while switching
+    // to a decoder.
+    Detecting {
+        reader: Option<R>,
+        head: [u8; MAGIC_SIZE],
+        len: usize,
+        readahead_size: NonZeroUsize,
+    },
+    Zlib(AsyncZlibDecoder<Prefixed<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(
+        async_compression::tokio::bufread::ZstdDecoder<
+            BufReader<Prefixed<R>>,
+        >,
+    ),
+    #[cfg(feature = "lz4")]
+    Lz4(
+        async_compression::tokio::bufread::Lz4Decoder<
+            BufReader<Prefixed<R>>,
+        >,
+    ),
+}
+
+impl<R> AsyncDecoder<R>
+where
+    R: AsyncRead + Unpin,
+{
+    // Creates a decoder reading a given number of bytes ahead.
+    pub(crate) fn new(reader: R, readahead_size: NonZeroUsize) -> Self {
+        AsyncDecoder::Detecting {
+            reader: Some(reader),
+            head: [0u8; MAGIC_SIZE],
+            len: 0,
+            readahead_size,
+        }
+    }
+
+    // Consumes the decoder and returns the underlying reader.
+    //
+    // Panics if a Zlib stream has not been decoded to the end.
+    pub(crate) fn into_inner(self) -> R {
+        match self {
+            AsyncDecoder::Detecting { reader, .. } => reader.unwrap(),
+            AsyncDecoder::Zlib(decoder) => decoder.into_inner().inner,
+            #[cfg(feature = "zstd")]
+            AsyncDecoder::Zstd(decoder) => {
+                decoder.into_inner().into_inner().inner
+            },
+            #[cfg(feature = "lz4")]
+            AsyncDecoder::Lz4(decoder) => {
+                decoder.into_inner().into_inner().inner
+            },
+        }
+    }
+
+    // Reads the magic number and switches to the decoder of the codec.
+    fn poll_detect(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let AsyncDecoder::Detecting {
+            reader,
+            head,
+            len,
+            readahead_size,
+        } = self else {
+            return Poll::Ready(Ok(()));
+        };
+        while *len < MAGIC_SIZE {
+            let mut buf = ReadBuf::new(&mut head[*len..]);
+            let r = reader.as_mut().unwrap();
+            ready!(Pin::new(r).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                break;
+            }
+            *len += buf.filled().len();
+        }
+        let codec = Codec::detect(&head[..*len]);
+        codec.check_available().map_err(std::io::Error::other)?;
+        let r = Prefixed {
+            head: *head,
+            pos: 0,
+            len: *len,
+            inner: reader.take().unwrap(),
+        };
+        let readahead_size = *readahead_size;
+        *self = match codec {
+            Codec::Zlib => AsyncDecoder::Zlib(
+                AsyncZlibDecoder::with_readahead_size(r, readahead_size),
+            ),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => AsyncDecoder::Zstd(
+                async_compression::tokio::bufread::ZstdDecoder::new(
+                    BufReader::with_capacity(readahead_size.get(), r),
+                ),
+            ),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => AsyncDecoder::Lz4(
+                async_compression::tokio::bufread::Lz4Decoder::new(
+                    BufReader::with_capacity(readahead_size.get(), r),
+                ),
+            ),
+            #[cfg(not(all(feature = "zstd", feature = "lz4")))]
+            _ => unreachable!("codec must be available"),
+        };
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R> AsyncRead for AsyncDecoder<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_detect(cx))?;
+        match this {
+            AsyncDecoder::Detecting { .. } => {
+                unreachable!("codec must be detected")
+            },
+            AsyncDecoder::Zlib(decoder) => {
+                Pin::new(decoder).poll_read(cx, buf)
+            },
+            #[cfg(feature = "zstd")]
+            AsyncDecoder::Zstd(decoder) => {
+                Pin::new(decoder).poll_read(cx, buf)
+            },
+            #[cfg(feature = "lz4")]
+            AsyncDecoder::Lz4(decoder) => {
+                Pin::new(decoder).poll_read(cx, buf)
+            },
+        }
+    }
+}
+
+// Reader that reads given first bytes before the rest of another reader.
+pub(crate) struct Prefixed<R> {
+    head: [u8; MAGIC_SIZE],
+    pos: usize,
+    len: usize,
+    inner: R,
+}
+
+impl<R> AsyncRead for Prefixed<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.len {
+            let n = buf.remaining().min(this.len - this.pos);
+            buf.put_slice(&this.head[this.pos..this.pos + n]);
+            this.pos += n;
+            Poll::Ready(Ok(()))
+        } else {
+            Pin::new(&mut this.inner).poll_read(cx, buf)
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! Encryption at rest on asynchronous file systems.
+//!
+//! [`EncryptedFileSystem`] works like
+//! [`io::encrypt::EncryptedFileSystem`](crate::io::encrypt::EncryptedFileSystem)
+//! on an asynchronous file system, and files written by either can be read
+//! by the other.
+
+use async_trait::async_trait;
+use core::pin::Pin;
+use core::task::{Poll, ready};
+use ring::aead::LessSafeKey;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::error::Error;
+use crate::io::encrypt::{
+    HEADER_SIZE,
+    Opener,
+    SEALED_SEGMENT_SIZE,
+    Sealer,
+    new_key,
+};
+pub use crate::io::encrypt::{KEY_LEN, SEGMENT_SIZE};
+use crate::io::hash::HashAlgorithm;
+
+use super::{HashedFileIn, HashedFileOut, ReadFileSystem, WriteFileSystem};
+
+/// Asynchronous file system that encrypts the contents of files in another
+/// file system.
+pub struct EncryptedFileSystem<FS> {
+    inner: FS,
+    key: Arc<LessSafeKey>,
+}
+
+impl<FS> EncryptedFileSystem<FS> {
+    /// Wraps a given file system encrypting files with a given key.
+    pub fn new(inner: FS, key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            inner,
+            key: new_key(key),
+        }
+    }
+
+    /// Returns the wrapped file system.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<FS> ReadFileSystem for EncryptedFileSystem<FS>
+where
+    FS: ReadFileSystem + Send + Sync,
+{
+    type HashedFileIn = EncryptedHashedFileIn<FS::HashedFileIn>;
+
+    async fn open_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let file = self.inner.open_hashed_file(path).await?;
+        EncryptedHashedFileIn::new(file, self.key.clone()).await
+    }
+
+    async fn open_hashed_file_with(
+        &self,
+        path: impl Into<String> + Send,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let file = self.inner.open_hashed_file_with(path, algorithm).await?;
+        EncryptedHashedFileIn::new(file, self.key.clone()).await
+    }
+
+    async fn open_hashed_file_unverified(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let file = self.inner.open_hashed_file_unverified(path).await?;
+        EncryptedHashedFileIn::new(file, self.key.clone()).await
+    }
+
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        self.inner.list_files(prefix).await
+    }
+
+    /// Pointers are not encrypted because they only name files.
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        self.inner.read_pointer(path).await
+    }
+}
+
+#[async_trait]
+impl<FS> WriteFileSystem for EncryptedFileSystem<FS>
+where
+    FS: WriteFileSystem + Send + Sync,
+{
+    type HashedFileOut = EncryptedHashedFileOut<FS::HashedFileOut>;
+
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        let file = self.inner.create_hashed_file().await?;
+        EncryptedHashedFileOut::new(file, self.key.clone())
+    }
+
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error> {
+        let file = self.inner.create_hashed_file_in(path).await?;
+        EncryptedHashedFileOut::new(file, self.key.clone())
+    }
+
+    async fn create_hashed_file_in_with(
+        &self,
+        path: impl Into<String> + Send,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        let file = self.inner
+            .create_hashed_file_in_with(path, algorithm)
+            .await?;
+        EncryptedHashedFileOut::new(file, self.key.clone())
+    }
+
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.inner.delete(path).await
+    }
+
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.inner.write_pointer(path, target).await
+    }
+}
+
+/// Hashed file that encrypts its contents.
+///
+/// Plaintext is buffered until a segment fills, so flushing does not write
+/// a partial segment; persisting the file does.
+pub struct EncryptedHashedFileOut<W> {
+    file: W,
+    sealer: Sealer,
+    // Plaintext of the current segment.
+    segment: Vec<u8>,
+    // Encrypted bytes waiting to be written.
+    pending: Vec<u8>,
+    // Number of bytes in `pending` already written.
+    pending_pos: usize,
+}
+
+impl<W> EncryptedHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    // Wraps a given file. The header is written with the first segment.
+    fn new(file: W, key: Arc<LessSafeKey>) -> Result<Self, Error> {
+        let sealer = Sealer::new(key)?;
+        let mut pending = Vec::with_capacity(SEALED_SEGMENT_SIZE);
+        pending.extend_from_slice(sealer.header());
+        Ok(Self {
+            file,
+            sealer,
+            segment: Vec::with_capacity(SEALED_SEGMENT_SIZE),
+            pending,
+            pending_pos: 0,
+        })
+    }
+
+    // Writes the pending encrypted bytes.
+    fn poll_write_pending(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.file).poll_write(
+                cx,
+                &self.pending[self.pending_pos..],
+            ))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += n;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> AsyncWrite for EncryptedHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_write_pending(cx))?;
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            if this.segment.len() < SEGMENT_SIZE {
+                let n = buf.len().min(SEGMENT_SIZE - this.segment.len());
+                this.segment.extend_from_slice(&buf[..n]);
+                return Poll::Ready(Ok(n));
+            }
+            // the segment is not the last because more bytes follow
+            this.sealer
+                .seal(&mut this.segment, false)
+                .map_err(std::io::Error::other)?;
+            std::mem::swap(&mut this.segment, &mut this.pending);
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.file).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl<W> HashedFileOut for EncryptedHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    async fn persist(
+        mut self,
+        extension: impl Into<String> + Send,
+    ) -> Result<String, Error> {
+        self.flush().await?;
+        self.sealer.seal(&mut self.segment, true)?;
+        self.file.write_all(&self.segment).await?;
+        self.file.persist(extension).await
+    }
+}
+
+/// Hashed file that decrypts its contents.
+///
+/// Bytes of a segment are not returned until the whole segment has been
+/// authenticated.
+pub struct EncryptedHashedFileIn<R> {
+    file: R,
+    opener: Opener,
+    // Current segment; encrypted while it is being read, and decrypted
+    // after it has been authenticated.
+    segment: Vec<u8>,
+    // Whether the current segment is being read.
+    reading: bool,
+    // Position of the next byte to return in the current segment.
+    pos: usize,
+    // First byte of the next segment, read to tell the last segment.
+    lookahead: Option<u8>,
+    // Whether the last segment has been decrypted.
+    finished: bool,
+}
+
+impl<R> EncryptedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    // Wraps a given file and reads the header.
+    async fn new(mut file: R, key: Arc<LessSafeKey>) -> Result<Self, Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header).await?;
+        Ok(Self {
+            file,
+            opener: Opener::new(key, header)?,
+            segment: Vec::with_capacity(SEALED_SEGMENT_SIZE + 1),
+            reading: false,
+            pos: 0,
+            lookahead: None,
+            finished: false,
+        })
+    }
+
+    // Reads and decrypts the next segment.
+    fn poll_next_segment(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.reading {
+            self.segment.clear();
+            self.segment.extend(self.lookahead.take());
+            self.pos = 0;
+            self.reading = true;
+        }
+        while self.segment.len() <= SEALED_SEGMENT_SIZE {
+            let len = self.segment.len();
+            self.segment.resize(SEALED_SEGMENT_SIZE + 1, 0u8);
+            let mut buf = ReadBuf::new(&mut self.segment[len..]);
+            let result = Pin::new(&mut self.file).poll_read(cx, &mut buf);
+            let n = buf.filled().len();
+            self.segment.truncate(len + n);
+            ready!(result)?;
+            if n == 0 {
+                break;
+            }
+        }
+        self.reading = false;
+        let last = self.segment.len() <= SEALED_SEGMENT_SIZE;
+        if !last {
+            self.lookahead = self.segment.pop();
+        }
+        if let Err(e) = self.opener.open(&mut self.segment, last) {
+            // never returns unauthenticated bytes
+            self.segment.clear();
+            return Poll::Ready(Err(std::io::Error::other(e)));
+        }
+        self.finished = last;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R> AsyncRead for EncryptedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        // a segment being read is still encrypted
+        while this.reading || (this.pos == this.segment.len() && !this.finished)
+        {
+            ready!(this.poll_next_segment(cx))?;
+        }
+        let n = buf.remaining().min(this.segment.len() - this.pos);
+        buf.put_slice(&this.segment[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl<R> HashedFileIn for EncryptedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    /// Fails with `Error::VerificationFailure` if the last segment has not
+    /// been read, or the encrypted contents do not match the hash.
+    async fn verify(self) -> Result<(), Error> {
+        if !self.finished {
+            return Err(Error::VerificationFailure(
+                "encrypted file has not been entirely read".to_string(),
+            ));
+        }
+        self.file.verify().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asyncdb::io::LocalFileSystem;
+    use crate::io::{
+        HashedFileIn as _,
+        HashedFileOut as _,
+        LocalFileSystem as SyncLocalFileSystem,
+        ReadFileSystem as _,
+        WriteFileSystem as _,
+    };
+    use crate::io::encrypt::EncryptedFileSystem as SyncEncryptedFileSystem;
+
+    fn data() -> Vec<u8> {
+        (0..(2 * SEGMENT_SIZE + 100)).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn encrypted_file_should_be_compatible_with_sync_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = EncryptedFileSystem::new(
+            LocalFileSystem::new(dir.path()),
+            &[1u8; KEY_LEN],
+        );
+        let sync_fs = SyncEncryptedFileSystem::new(
+            SyncLocalFileSystem::new(dir.path()),
+            &[1u8; KEY_LEN],
+        );
+        for data in [Vec::new(), vec![0u8; SEGMENT_SIZE], data()] {
+            let mut f = fs.create_hashed_file().await.unwrap();
+            f.write_all(&data).await.unwrap();
+            let path = format!("{}.bin", f.persist("bin").await.unwrap());
+            let mut f = sync_fs.open_hashed_file(&path).unwrap();
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut f, &mut contents).unwrap();
+            assert_eq!(contents, data);
+            f.verify().unwrap();
+            let mut f = sync_fs.create_hashed_file().unwrap();
+            std::io::Write::write_all(&mut f, &data).unwrap();
+            let path = format!("{}.bin", f.persist("bin").unwrap());
+            let mut f = fs.open_hashed_file(path).await.unwrap();
+            let mut contents = Vec::new();
+            f.read_to_end(&mut contents).await.unwrap();
+            assert_eq!(contents, data);
+            f.verify().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn encrypted_file_should_not_be_decrypted_with_another_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = EncryptedFileSystem::new(
+            LocalFileSystem::new(dir.path()),
+            &[1u8; KEY_LEN],
+        );
+        let mut f = fs.create_hashed_file().await.unwrap();
+        f.write_all(&data()).await.unwrap();
+        let path = format!("{}.bin", f.persist("bin").await.unwrap());
+        let another = EncryptedFileSystem::new(
+            LocalFileSystem::new(dir.path()),
+            &[2u8; KEY_LEN],
+        );
+        let mut f = another.open_hashed_file(path).await.unwrap();
+        let mut contents = Vec::new();
+        assert!(f.read_to_end(&mut contents).await.is_err());
+        assert!(contents.is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+//! Read-only file system over HTTP(S).
+//!
+//! [`HttpFileSystem`] fetches hashed files from a static web host or CDN
+//! with GET requests. Files are streamed from response bodies, and byte
+//! ranges are fetched with range requests; e.g., to read verified chunks of
+//! large partitions.
+
+use async_trait::async_trait;
+use base64::engine::{
+    Engine,
+    general_purpose::URL_SAFE_NO_PAD as url_safe_base_64,
+};
+use core::pin::Pin;
+use core::task::Poll;
+use futures::TryStreamExt;
+use reqwest::{Client, Response, StatusCode, header};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio_util::io::StreamReader;
+
+use crate::error::Error;
+use crate::io::parse_pointer;
+
+use super::{HashedFileIn, ReadFileSystem};
+
+/// Read-only file system on a web host.
+///
+/// The path of a file is resolved against the base URL.
+#[derive(Clone, Debug)]
+pub struct HttpFileSystem {
+    client: Client,
+    // Base URL without a trailing slash.
+    base_url: String,
+}
+
+impl HttpFileSystem {
+    /// Creates a file system that fetches files under a given base URL; e.g.,
+    /// `https://cdn.example.com/databases/mydb`.
+    pub fn new(base_url: impl AsRef<str>) -> Self {
+        Self {
+            client: Client::new(),
+            base_url: base_url.as_ref().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Sends requests with a given client; e.g., to set default headers or
+    /// timeouts.
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    // Returns the URL of a given path.
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path.trim_start_matches('/'))
+    }
+}
+
+#[async_trait]
+impl ReadFileSystem for HttpFileSystem {
+    type HashedFileIn = HttpHashedFileIn;
+
+    async fn open_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let path = path.into();
+        let hash = path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.split('.').next())
+            .filter(|hash| !hash.is_empty())
+            .ok_or(Error::InvalidArgs(format!(
+                "file name must be hash: {}",
+                path,
+            )))?
+            .to_string();
+        let response = self.client
+            .get(self.url(&path))
+            .send()
+            .await
+            .map_err(request_error)?;
+        let response = check_status(response)?;
+        Ok(HttpHashedFileIn {
+            body: Box::pin(body_reader(response)),
+            hash,
+            digest: ring::digest::Context::new(&ring::digest::SHA256),
+        })
+    }
+
+    /// Fetches only the range with a range request.
+    ///
+    /// Falls back to discarding bytes before `offset` if the server ignores
+    /// the range and responds with the entire file.
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let response = self.client
+            .get(self.url(&path.into()))
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", offset, offset + len as u64 - 1),
+            )
+            .send()
+            .await
+            .map_err(request_error)?;
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // the range starts after the end of the file
+            return Ok(Vec::new());
+        }
+        let response = check_status(response)?;
+        let skip = if response.status() == StatusCode::PARTIAL_CONTENT {
+            0
+        } else {
+            offset
+        };
+        let mut body = body_reader(response);
+        tokio::io::copy(&mut (&mut body).take(skip), &mut tokio::io::sink())
+            .await?;
+        let mut buf: Vec<u8> = Vec::with_capacity(len);
+        body.take(len as u64).read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Fetches the pointer with a GET request. A pointer is missing if the
+    /// server responds with 404 Not Found.
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        let response = self.client
+            .get(self.url(&path.into()))
+            .send()
+            .await
+            .map_err(request_error)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let contents = check_status(response)?
+            .bytes()
+            .await
+            .map_err(request_error)?;
+        parse_pointer(contents.to_vec()).map(Some)
+    }
+}
+
+/// File on a web host whose contents can be verified with the hash.
+///
+/// File name is supposed to be a Base64 encoded URL-safe SHA256 digest of the
+/// contents plus an extension.
+pub struct HttpHashedFileIn {
+    // Response body.
+    body: Pin<Box<dyn AsyncRead + Send>>,
+    hash: String,
+    digest: ring::digest::Context,
+}
+
+#[async_trait]
+impl HashedFileIn for HttpHashedFileIn {
+    async fn verify(self) -> Result<(), Error> {
+        let hash = url_safe_base_64.encode(self.digest.finish());
+        if self.hash == hash {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "hash discrepancy: expected {} but got {}",
+                self.hash,
+                hash,
+            )))
+        }
+    }
+}
+
+impl AsyncRead for HttpHashedFileIn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let last_len = buf.filled().len();
+        match this.body.as_mut().poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.digest.update(&buf.filled()[last_len..]);
+                Poll::Ready(Ok(()))
+            },
+            poll => poll,
+        }
+    }
+}
+
+// Reads the body of a response.
+fn body_reader(response: Response) -> impl AsyncRead + Send + Unpin {
+    StreamReader::new(
+        response.bytes_stream().map_err(std::io::Error::other),
+    )
+}
+
+// Fails unless a response is successful.
+fn check_status(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        let kind = if status == StatusCode::NOT_FOUND {
+            std::io::ErrorKind::NotFound
+        } else {
+            std::io::ErrorKind::Other
+        };
+        Err(Error::IOError(std::io::Error::new(
+            kind,
+            format!("{} responded with {}", response.url(), status),
+        )))
+    }
+}
+
+// Converts an error from a request.
+fn request_error(e: reqwest::Error) -> Error {
+    Error::IOError(std::io::Error::other(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // Serves files in memory.
+    //
+    // Ranges are ignored for paths under `/whole/`.
+    fn serve_files(listener: TcpListener, files: HashMap<String, Vec<u8>>) {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let path = line.split_whitespace().nth(1).unwrap().to_string();
+            let mut range: Option<(usize, usize)> = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                if name.eq_ignore_ascii_case("range") {
+                    let (start, end) = value
+                        .trim()
+                        .trim_start_matches("bytes=")
+                        .split_once('-')
+                        .unwrap();
+                    range = Some((
+                        start.parse().unwrap(),
+                        end.parse().unwrap(),
+                    ));
+                }
+            }
+            let (status, body) = match files.get(&path) {
+                None => ("404 Not Found", Vec::new()),
+                Some(file) => match range {
+                    Some(_) if path.starts_with("/whole/") => {
+                        ("200 OK", file.clone())
+                    },
+                    Some((start, _)) if start >= file.len() => {
+                        ("416 Range Not Satisfiable", Vec::new())
+                    },
+                    Some((start, end)) => {
+                        let end = (end + 1).min(file.len());
+                        ("206 Partial Content", file[start..end].to_vec())
+                    },
+                    None => ("200 OK", file.clone()),
+                },
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n",
+                status,
+                body.len(),
+            ).unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn http_file_system_should_stream_and_range_read_files() {
+        let contents = b"0123456789".to_vec();
+        let hash = url_safe_base_64.encode(
+            ring::digest::digest(&ring::digest::SHA256, &contents),
+        );
+        let path = format!("partitions/{}.binpb", hash);
+        let files = HashMap::from([
+            (format!("/db/{}", path), contents.clone()),
+            (format!("/whole/{}", path), contents.clone()),
+            ("/db/codebooks/AAAA.binpb".to_string(), contents.clone()),
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || serve_files(listener, files));
+
+        let fs = HttpFileSystem::new(format!("{}/db/", base_url));
+        let mut file = fs.open_hashed_file(&path).await.unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        file.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, contents);
+        file.verify().await.unwrap();
+        let mut file = fs.open_hashed_file("codebooks/AAAA.binpb")
+            .await
+            .unwrap();
+        file.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(matches!(
+            file.verify().await,
+            Err(Error::VerificationFailure(_)),
+        ));
+        assert!(matches!(
+            fs.open_hashed_file("codebooks/BBBB.binpb").await,
+            Err(Error::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound,
+        ));
+
+        assert_eq!(fs.read_range(&path, 2, 3).await.unwrap(), b"234");
+        assert_eq!(fs.read_range(&path, 8, 5).await.unwrap(), b"89");
+        assert!(fs.read_range(&path, 20, 5).await.unwrap().is_empty());
+        let fs = HttpFileSystem::new(format!("{}/whole", base_url));
+        assert_eq!(fs.read_range(&path, 2, 3).await.unwrap(), b"234");
+        assert_eq!(fs.read_range(&path, 8, 5).await.unwrap(), b"89");
+    }
+}
@@ -0,0 +1,334 @@
+//! Asynchronous file system on storage services supported by OpenDAL.
+//!
+//! [`OpendalFileSystem`] reads and writes hashed files through an
+//! [`Operator`](https://docs.rs/opendal/latest/opendal/struct.Operator.html)
+//! of [OpenDAL](https://opendal.apache.org). Services are enabled by
+//! features of the `opendal` crate in your own dependencies.
+
+use async_trait::async_trait;
+use base64::engine::{
+    Engine,
+    general_purpose::URL_SAFE_NO_PAD as url_safe_base_64,
+};
+use core::pin::Pin;
+use core::task::{Poll, ready};
+use std::io::SeekFrom;
+use tokio::fs::File;
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+    AsyncSeekExt,
+    AsyncWrite,
+    AsyncWriteExt,
+    ReadBuf,
+};
+use tokio_util::io::StreamReader;
+
+use crate::error::Error;
+use crate::io::opendal::{UPLOAD_CHUNK_SIZE, dir_path};
+use crate::io::parse_pointer;
+
+use super::{
+    HashedFileIn,
+    HashedFileOut,
+    ReadFileSystem,
+    WriteFileSystem,
+};
+
+/// Asynchronous file system on an OpenDAL operator.
+#[derive(Clone, Debug)]
+pub struct OpendalFileSystem {
+    operator: ::opendal::Operator,
+}
+
+impl OpendalFileSystem {
+    /// Creates a file system on a given operator.
+    pub fn new(operator: ::opendal::Operator) -> Self {
+        Self { operator }
+    }
+}
+
+#[async_trait]
+impl ReadFileSystem for OpendalFileSystem {
+    type HashedFileIn = OpendalHashedFileIn;
+
+    async fn open_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let path = path.into();
+        let hash = path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.split('.').next())
+            .filter(|hash| !hash.is_empty())
+            .ok_or(Error::InvalidArgs(format!(
+                "file name must be hash: {}",
+                path,
+            )))?
+            .to_string();
+        let size = self.operator.stat(&path).await?.content_length();
+        let stream = self.operator
+            .reader(&path)
+            .await?
+            .into_bytes_stream(0..size)
+            .await?;
+        Ok(OpendalHashedFileIn {
+            reader: Box::pin(StreamReader::new(stream)),
+            hash,
+            digest: ring::digest::Context::new(&ring::digest::SHA256),
+        })
+    }
+
+    /// Reads only the range from the operator.
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let path = path.into();
+        let size = self.operator.stat(&path).await?.content_length();
+        let end = size.min(offset.saturating_add(len as u64));
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let buf = self.operator.read_with(&path).range(offset..end).await?;
+        Ok(buf.to_vec())
+    }
+
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        let entries = self.operator
+            .list_with(&dir_path(&prefix.into()))
+            .recursive(true)
+            .await?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.metadata().is_file())
+            .map(|entry| entry.path().to_string())
+            .collect())
+    }
+
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        match self.operator.read(&path.into()).await {
+            Ok(contents) => parse_pointer(contents.to_vec()).map(Some),
+            Err(e) if e.kind() == ::opendal::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl WriteFileSystem for OpendalFileSystem {
+    type HashedFileOut = OpendalHashedFileOut;
+
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        OpendalHashedFileOut::create(self.operator.clone(), "")
+    }
+
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error> {
+        OpendalHashedFileOut::create(self.operator.clone(), &path.into())
+    }
+
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.operator.delete(&path.into()).await?;
+        Ok(())
+    }
+
+    /// Whether the pointer is replaced atomically depends on the service.
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.operator
+            .write(&path.into(), target.into().into_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Writable file on an OpenDAL operator.
+///
+/// Contents are buffered in a temporary file, and written to the operator
+/// under the hash of the contents when persisted.
+pub struct OpendalHashedFileOut {
+    tempfile: File,
+    operator: ::opendal::Operator,
+    // Directory to put the file in.
+    dir: String,
+    digest: ring::digest::Context,
+}
+
+impl OpendalHashedFileOut {
+    // Creates a temporary file to be written under a given directory.
+    fn create(
+        operator: ::opendal::Operator,
+        dir: &str,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            tempfile: File::from_std(tempfile::tempfile()?),
+            operator,
+            dir: dir.trim_matches('/').to_string(),
+            digest: ring::digest::Context::new(&ring::digest::SHA256),
+        })
+    }
+}
+
+impl AsyncWrite for OpendalHashedFileOut {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.tempfile).poll_write(cx, buf))?;
+        this.digest.update(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().tempfile).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().tempfile).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl HashedFileOut for OpendalHashedFileOut {
+    async fn persist(
+        mut self,
+        extension: impl Into<String> + Send,
+    ) -> Result<String, Error> {
+        self.tempfile.flush().await?;
+        let hash = url_safe_base_64.encode(self.digest.finish());
+        let name = format!("{}.{}", hash, extension.into());
+        let path = if self.dir.is_empty() {
+            name
+        } else {
+            format!("{}/{}", self.dir, name)
+        };
+        self.tempfile.seek(SeekFrom::Start(0)).await?;
+        let mut writer = self.operator.writer(&path).await?;
+        let mut chunk = vec![0u8; UPLOAD_CHUNK_SIZE];
+        loop {
+            let n = self.tempfile.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            writer.write(chunk[..n].to_vec()).await?;
+        }
+        writer.close().await?;
+        Ok(hash)
+    }
+}
+
+/// File on an OpenDAL operator whose contents can be verified with the hash.
+///
+/// File name is supposed to be a Base64 encoded URL-safe SHA256 digest of the
+/// contents plus an extension.
+pub struct OpendalHashedFileIn {
+    reader: Pin<Box<dyn AsyncRead + Send>>,
+    hash: String,
+    digest: ring::digest::Context,
+}
+
+#[async_trait]
+impl HashedFileIn for OpendalHashedFileIn {
+    async fn verify(self) -> Result<(), Error> {
+        let hash = url_safe_base_64.encode(self.digest.finish());
+        if self.hash == hash {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "hash discrepancy: expected {} but got {}",
+                self.hash,
+                hash,
+            )))
+        }
+    }
+}
+
+impl AsyncRead for OpendalHashedFileIn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let last_len = buf.filled().len();
+        match this.reader.as_mut().poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.digest.update(&buf.filled()[last_len..]);
+                Poll::Ready(Ok(()))
+            },
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn opendal_file_system_should_round_trip_hashed_files() {
+        let operator = ::opendal::Operator::new(
+            ::opendal::services::Memory::default(),
+        ).unwrap().finish();
+        let contents = b"0123456789";
+        let hash = url_safe_base_64.encode(
+            ring::digest::digest(&ring::digest::SHA256, contents),
+        );
+        let path = format!("data/{}.bin", hash);
+        operator.write("data/AAAA.bin", &contents[..]).await.unwrap();
+        let fs = OpendalFileSystem::new(operator);
+        let mut f = fs.create_hashed_file_in("data").await.unwrap();
+        f.write_all(contents).await.unwrap();
+        assert_eq!(f.persist("bin").await.unwrap(), hash);
+
+        let mut f = fs.open_hashed_file(&path).await.unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, contents);
+        f.verify().await.unwrap();
+        let mut f = fs.open_hashed_file("data/AAAA.bin").await.unwrap();
+        f.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(matches!(
+            f.verify().await,
+            Err(Error::VerificationFailure(_)),
+        ));
+
+        assert_eq!(fs.read_range(&path, 2, 3).await.unwrap(), b"234");
+        assert_eq!(fs.read_range(&path, 8, 5).await.unwrap(), b"89");
+        assert!(fs.read_range(&path, 20, 5).await.unwrap().is_empty());
+
+        let mut files = fs.list_files("data").await.unwrap();
+        files.sort();
+        let mut expected = vec!["data/AAAA.bin".to_string(), path.clone()];
+        expected.sort();
+        assert_eq!(files, expected);
+        fs.delete("data/AAAA.bin").await.unwrap();
+        assert_eq!(fs.list_files("").await.unwrap(), [path]);
+    }
+}
@@ -0,0 +1,421 @@
+//! Retries of failed reads on asynchronous file systems.
+//!
+//! [`RetryingFileSystem`] works like
+//! [`io::retry::RetryingFileSystem`](crate::io::retry::RetryingFileSystem)
+//! on an asynchronous file system, and also retries range reads.
+//! Listings and deletions, which are idempotent, are retried as well.
+
+use async_trait::async_trait;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Poll, ready};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::error::Error;
+pub use crate::io::retry::{ErrorClass, RetryPolicy};
+
+use super::{HashedFileIn, ReadFileSystem, WriteFileSystem};
+
+/// Asynchronous file system that retries failed reads of another file
+/// system.
+pub struct RetryingFileSystem<FS> {
+    inner: Arc<FS>,
+    policy: Arc<RetryPolicy>,
+}
+
+impl<FS> RetryingFileSystem<FS> {
+    /// Wraps a given file system retrying failed reads with the default
+    /// policy.
+    pub fn new(inner: FS) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            policy: Arc::new(RetryPolicy::new()),
+        }
+    }
+
+    /// Sets the policy of retries.
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Returns the wrapped file system.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<FS> ReadFileSystem for RetryingFileSystem<FS>
+where
+    FS: ReadFileSystem + Send + Sync + 'static,
+{
+    type HashedFileIn = RetryingHashedFileIn<FS>;
+
+    async fn open_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let path = path.into();
+        let file = retry(&self.policy, || {
+            self.inner.open_hashed_file(path.clone())
+        }).await?;
+        Ok(RetryingHashedFileIn {
+            inner: self.inner.clone(),
+            policy: self.policy.clone(),
+            path,
+            file,
+            pos: 0,
+            num_retries: 0,
+            state: ReadState::Reading,
+        })
+    }
+
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let path = path.into();
+        retry(&self.policy, || {
+            self.inner.read_range(path.clone(), offset, len)
+        }).await
+    }
+
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        let prefix = prefix.into();
+        retry(&self.policy, || self.inner.list_files(prefix.clone())).await
+    }
+
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        let path = path.into();
+        retry(&self.policy, || self.inner.read_pointer(path.clone())).await
+    }
+}
+
+#[async_trait]
+impl<FS> WriteFileSystem for RetryingFileSystem<FS>
+where
+    FS: WriteFileSystem + Send + Sync + 'static,
+{
+    type HashedFileOut = FS::HashedFileOut;
+
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file().await
+    }
+
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file_in(path).await
+    }
+
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        let path = path.into();
+        retry(&self.policy, || self.inner.delete(path.clone())).await
+    }
+
+    /// Retrying is safe because writing the same target again is harmless.
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        let path = path.into();
+        let target = target.into();
+        retry(&self.policy, || {
+            self.inner.write_pointer(path.clone(), target.clone())
+        }).await
+    }
+}
+
+/// File that is reopened when reading it fails.
+pub struct RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem,
+{
+    inner: Arc<FS>,
+    policy: Arc<RetryPolicy>,
+    path: String,
+    file: FS::HashedFileIn,
+    // Number of bytes read so far.
+    pos: u64,
+    // Number of retries since the last successful read.
+    num_retries: u32,
+    state: ReadState<FS::HashedFileIn>,
+}
+
+// State of reading a file.
+enum ReadState<R> {
+    // Reading the current file.
+    Reading,
+    // Waiting before reopening the file.
+    Waiting(Pin<Box<tokio::time::Sleep>>),
+    // Reopening the file.
+    Reopening(Pin<Box<dyn Future<Output = Result<R, Error>> + Send>>),
+}
+
+impl<FS> RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem + Send + Sync + 'static,
+{
+    // Schedules a retry after a given error.
+    //
+    // Returns the error back if the policy gives up.
+    fn retry_after(&mut self, e: Error) -> Result<(), std::io::Error> {
+        self.num_retries += 1;
+        match self.policy.backoff(&e, self.num_retries) {
+            Some(backoff) => {
+                self.state = ReadState::Waiting(
+                    Box::pin(tokio::time::sleep(backoff)),
+                );
+                Ok(())
+            },
+            None => {
+                self.num_retries = 0;
+                self.state = ReadState::Reading;
+                Err(match e {
+                    Error::IOError(e) => e,
+                    e => std::io::Error::other(e),
+                })
+            },
+        }
+    }
+}
+
+impl<FS> AsyncRead for RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem + Send + Sync + 'static,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                ReadState::Reading => {
+                    let last_len = buf.filled().len();
+                    match ready!(Pin::new(&mut this.file).poll_read(cx, buf)) {
+                        Ok(()) => {
+                            this.pos += (buf.filled().len() - last_len) as u64;
+                            this.num_retries = 0;
+                            return Poll::Ready(Ok(()));
+                        },
+                        Err(e) => this.retry_after(Error::IOError(e))?,
+                    }
+                },
+                ReadState::Waiting(sleep) => {
+                    ready!(sleep.as_mut().poll(cx));
+                    this.state = ReadState::Reopening(Box::pin(reopen(
+                        this.inner.clone(),
+                        this.path.clone(),
+                        this.pos,
+                    )));
+                },
+                ReadState::Reopening(reopening) => {
+                    match ready!(reopening.as_mut().poll(cx)) {
+                        Ok(file) => {
+                            this.file = file;
+                            this.state = ReadState::Reading;
+                        },
+                        Err(e) => this.retry_after(e)?,
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<FS> HashedFileIn for RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem + Send + Sync + 'static,
+{
+    async fn verify(self) -> Result<(), Error> {
+        self.file.verify().await
+    }
+}
+
+// Reopens a file and skips the bytes already read.
+//
+// Skipped bytes go through the new file so that it can verify the entire
+// contents.
+async fn reopen<FS>(
+    fs: Arc<FS>,
+    path: String,
+    pos: u64,
+) -> Result<FS::HashedFileIn, Error>
+where
+    FS: ReadFileSystem,
+{
+    let mut file = fs.open_hashed_file(path.clone()).await?;
+    let skipped = tokio::io::copy(
+        &mut (&mut file).take(pos),
+        &mut tokio::io::sink(),
+    ).await?;
+    if skipped != pos {
+        return Err(Error::IOError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("{} ended before {} bytes", path, pos),
+        )));
+    }
+    Ok(file)
+}
+
+// Runs an operation retrying it as a given policy says.
+async fn retry<T, F, Fut>(policy: &RetryPolicy, mut op: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut n = 0;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                n += 1;
+                match policy.backoff(&e, n) {
+                    Some(backoff) => tokio::time::sleep(backoff).await,
+                    None => return Err(e),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::asyncdb::io::{LocalFileSystem, LocalHashedFileIn};
+
+    // File system that fails to open files and read them as scheduled.
+    struct FlakyFileSystem {
+        local: LocalFileSystem,
+        // Number of opens to fail, and number of bytes a file can read
+        // before failing once.
+        schedule: Mutex<(usize, Option<usize>)>,
+        num_opens: Mutex<usize>,
+    }
+
+    struct FlakyHashedFileIn {
+        file: LocalHashedFileIn,
+        bytes_before_failure: Option<usize>,
+    }
+
+    impl AsyncRead for FlakyHashedFileIn {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            match this.bytes_before_failure.as_mut() {
+                Some(0) => Poll::Ready(Err(
+                    std::io::ErrorKind::ConnectionReset.into(),
+                )),
+                Some(remaining) => {
+                    let len = buf.remaining().min(*remaining);
+                    let mut limited = vec![0u8; len];
+                    let mut limited = ReadBuf::new(&mut limited);
+                    let file = Pin::new(&mut this.file);
+                    ready!(file.poll_read(cx, &mut limited))?;
+                    buf.put_slice(limited.filled());
+                    *remaining -= limited.filled().len();
+                    Poll::Ready(Ok(()))
+                },
+                None => Pin::new(&mut this.file).poll_read(cx, buf),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HashedFileIn for FlakyHashedFileIn {
+        async fn verify(self) -> Result<(), Error> {
+            self.file.verify().await
+        }
+    }
+
+    #[async_trait]
+    impl ReadFileSystem for FlakyFileSystem {
+        type HashedFileIn = FlakyHashedFileIn;
+
+        async fn open_hashed_file(
+            &self,
+            path: impl Into<String> + Send,
+        ) -> Result<Self::HashedFileIn, Error> {
+            *self.num_opens.lock().unwrap() += 1;
+            let bytes_before_failure = {
+                let mut schedule = self.schedule.lock().unwrap();
+                if schedule.0 > 0 {
+                    schedule.0 -= 1;
+                    return Err(Error::IOError(
+                        std::io::ErrorKind::TimedOut.into(),
+                    ));
+                }
+                schedule.1.take()
+            };
+            Ok(FlakyHashedFileIn {
+                file: self.local.open_hashed_file(path).await?,
+                bytes_before_failure,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn retrying_file_system_should_retry_transient_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = b"0123456789";
+        let hash = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            ring::digest::digest(&ring::digest::SHA256, contents),
+        );
+        let path = format!("{}.bin", hash);
+        std::fs::write(dir.path().join(&path), contents).unwrap();
+        let fs = RetryingFileSystem::new(FlakyFileSystem {
+            local: LocalFileSystem::new(dir.path()),
+            schedule: Mutex::new((2, Some(4))),
+            num_opens: Mutex::new(0),
+        })
+            .with_policy(RetryPolicy::new()
+                .with_initial_backoff(Duration::ZERO));
+
+        // fails twice to open, and once to read after 4 bytes
+        let mut f = fs.open_hashed_file(&path).await.unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, contents);
+        f.verify().await.unwrap();
+        assert_eq!(*fs.inner().num_opens.lock().unwrap(), 4);
+
+        // gives up after 3 retries
+        *fs.inner().num_opens.lock().unwrap() = 0;
+        *fs.inner().schedule.lock().unwrap() = (4, None);
+        assert!(fs.read_range(&path, 2, 3).await.is_err());
+        assert_eq!(*fs.inner().num_opens.lock().unwrap(), 4);
+        *fs.inner().schedule.lock().unwrap() = (3, None);
+        assert_eq!(fs.read_range(&path, 2, 3).await.unwrap(), b"234");
+
+        // does not retry missing files
+        *fs.inner().num_opens.lock().unwrap() = 0;
+        assert!(fs.open_hashed_file("missing.bin").await.is_err());
+        assert_eq!(*fs.inner().num_opens.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,322 @@
+//! Throttling of reads on asynchronous file systems.
+//!
+//! [`ThrottlingFileSystem`] limits the bytes per second read from another
+//! file system and the number of files open at once; e.g., so that warming
+//! up a large database in the background does not saturate the network of a
+//! serving host.
+
+use async_trait::async_trait;
+use core::future::Future;
+use core::num::{NonZeroU64, NonZeroUsize};
+use core::pin::Pin;
+use core::task::{Poll, ready};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::Error;
+
+use super::{HashedFileIn, ReadFileSystem, WriteFileSystem};
+
+/// Asynchronous file system that throttles reads of another file system.
+///
+/// Reads are limited to a given number of bytes per second in total over
+/// all files, bursting up to 100 milliseconds' worth of bytes. A read that
+/// exceeds the limit delays the next read until the excess is paid off.
+///
+/// An open file counts toward the limit of concurrent opens until it is
+/// dropped.
+pub struct ThrottlingFileSystem<FS> {
+    inner: FS,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    open_permits: Option<Arc<Semaphore>>,
+}
+
+impl<FS> ThrottlingFileSystem<FS> {
+    /// Wraps a given file system without any limits.
+    pub fn new(inner: FS) -> Self {
+        Self {
+            inner,
+            rate_limiter: None,
+            open_permits: None,
+        }
+    }
+
+    /// Limits the number of bytes read per second.
+    pub fn with_max_bytes_per_second(mut self, rate: NonZeroU64) -> Self {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(rate)));
+        self
+    }
+
+    /// Limits the number of files open at once.
+    pub fn with_max_concurrent_opens(mut self, n: NonZeroUsize) -> Self {
+        self.open_permits = Some(Arc::new(Semaphore::new(n.get())));
+        self
+    }
+
+    /// Returns the wrapped file system.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    // Waits for a permit to open a file if limited.
+    async fn acquire_permit(
+        &self,
+    ) -> Result<Option<OwnedSemaphorePermit>, Error> {
+        match self.open_permits.as_ref() {
+            Some(permits) => permits
+                .clone()
+                .acquire_owned()
+                .await
+                .map(Some)
+                .map_err(|e| Error::InvalidContext(format!(
+                    "failed to acquire permit to open file: {}",
+                    e,
+                ))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<FS> ReadFileSystem for ThrottlingFileSystem<FS>
+where
+    FS: ReadFileSystem + Sync,
+{
+    type HashedFileIn = ThrottledHashedFileIn<FS::HashedFileIn>;
+
+    async fn open_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let permit = self.acquire_permit().await?;
+        let file = self.inner.open_hashed_file(path).await?;
+        Ok(ThrottledHashedFileIn {
+            file,
+            rate_limiter: self.rate_limiter.clone(),
+            delay: None,
+            _permit: permit,
+        })
+    }
+
+    async fn open_hashed_file_unverified(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let permit = self.acquire_permit().await?;
+        let file = self.inner.open_hashed_file_unverified(path).await?;
+        Ok(ThrottledHashedFileIn {
+            file,
+            rate_limiter: self.rate_limiter.clone(),
+            delay: None,
+            _permit: permit,
+        })
+    }
+
+    /// Reads the range from the wrapped file system, and waits until the
+    /// read bytes are paid off before returning them.
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let _permit = self.acquire_permit().await?;
+        let bytes = self.inner.read_range(path, offset, len).await?;
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            let delay = rate_limiter.consume(bytes.len());
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Lists files on the wrapped file system without throttling.
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        self.inner.list_files(prefix).await
+    }
+
+    /// Reads the pointer on the wrapped file system without throttling.
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        self.inner.read_pointer(path).await
+    }
+}
+
+#[async_trait]
+impl<FS> WriteFileSystem for ThrottlingFileSystem<FS>
+where
+    FS: WriteFileSystem + Sync,
+{
+    type HashedFileOut = FS::HashedFileOut;
+
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file().await
+    }
+
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file_in(path).await
+    }
+
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.inner.delete(path).await
+    }
+
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.inner.write_pointer(path, target).await
+    }
+}
+
+/// File whose reads are throttled.
+pub struct ThrottledHashedFileIn<R> {
+    file: R,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Delay before the next read.
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    // Released when the file is dropped.
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl<R> AsyncRead for ThrottledHashedFileIn<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(delay) = this.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+        let last_len = buf.filled().len();
+        ready!(Pin::new(&mut this.file).poll_read(cx, buf))?;
+        if let Some(rate_limiter) = this.rate_limiter.as_ref() {
+            let delay = rate_limiter.consume(buf.filled().len() - last_len);
+            if !delay.is_zero() {
+                this.delay = Some(Box::pin(tokio::time::sleep(delay)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl<R> HashedFileIn for ThrottledHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    async fn verify(self) -> Result<(), Error> {
+        self.file.verify().await
+    }
+}
+
+// Token bucket shared by files.
+struct RateLimiter {
+    // Bytes per second.
+    rate: f64,
+    // Maximum number of tokens.
+    capacity: f64,
+    // Available tokens, which go negative while in debt, and the time they
+    // were last refilled.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    // Creates a limiter with a full bucket.
+    fn new(rate: NonZeroU64) -> Self {
+        let rate = rate.get() as f64;
+        let capacity = rate * 0.1;
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    // Consumes tokens for a given number of bytes, and returns how long to
+    // wait until the debt is paid off.
+    fn consume(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.capacity);
+        *tokens -= n as f64;
+        *last = now;
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+
+    use crate::asyncdb::io::LocalFileSystem;
+
+    #[tokio::test]
+    async fn throttling_file_system_should_limit_bytes_per_second() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("data.bin"), vec![0u8; 50_000])
+            .unwrap();
+        let fs = ThrottlingFileSystem::new(LocalFileSystem::new(dir.path()))
+            .with_max_bytes_per_second(100_000.try_into().unwrap());
+        let started = Instant::now();
+        let mut f = fs.open_hashed_file("data.bin").await.unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 50_000);
+        // 10,000 bytes in the bucket, and 40,000 bytes at 100,000 bytes/s
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        let started = Instant::now();
+        let bytes = fs.read_range("data.bin", 0, 20_000).await.unwrap();
+        assert_eq!(bytes.len(), 20_000);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn throttling_file_system_should_limit_concurrent_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.bin"), b"a").unwrap();
+        std::fs::write(dir.path().join("b.bin"), b"b").unwrap();
+        let fs = ThrottlingFileSystem::new(LocalFileSystem::new(dir.path()))
+            .with_max_concurrent_opens(1.try_into().unwrap());
+        let a = fs.open_hashed_file("a.bin").await.unwrap();
+        let b = tokio::time::timeout(
+            Duration::from_millis(50),
+            fs.open_hashed_file("b.bin"),
+        ).await;
+        assert!(b.is_err());
+        drop(a);
+        let b = tokio::time::timeout(
+            Duration::from_millis(50),
+            fs.open_hashed_file("b.bin"),
+        ).await;
+        assert!(b.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,426 @@
+//! Asynchronous local file system on io_uring.
+//!
+//! Available with the `io-uring` feature on Linux.
+//!
+//! [`UringFileSystem`] submits opens and reads to io_uring through
+//! [`tokio-uring`](https://docs.rs/tokio-uring), which saves system calls
+//! when a query opens many small partition and attributes log files at once.
+//!
+//! tokio-uring needs its own single-threaded runtime, and its files cannot
+//! move between threads. So a dedicated thread runs the runtime and serves
+//! requests from [`UringFileSystem`] and its files, which can be used on any
+//! Tokio runtime.
+
+use async_trait::async_trait;
+use base64::engine::{
+    Engine,
+    general_purpose::URL_SAFE_NO_PAD as url_safe_base_64,
+};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Poll, ready};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::Error;
+
+use super::{
+    HashedFileIn,
+    LocalFileSystem,
+    ReadFileSystem,
+    WriteFileSystem,
+};
+
+// Number of bytes requested by a single read.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Asynchronous local file system that reads files through io_uring.
+///
+/// Writes and listings go through [`LocalFileSystem`].
+pub struct UringFileSystem {
+    local: LocalFileSystem,
+    base_path: PathBuf,
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl UringFileSystem {
+    /// Creates a file system working under a given base path.
+    ///
+    /// Starts a thread that runs an io_uring runtime. The thread stops when
+    /// the file system and all the files opened from it are dropped.
+    ///
+    /// Fails with `Error::IOError` if io_uring is not available; e.g., on
+    /// kernels older than 5.10 or in sandboxes that prohibit io_uring.
+    pub fn new(base_path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            local: LocalFileSystem::new(base_path.as_ref()),
+            base_path: base_path.as_ref().to_path_buf(),
+            requests: start_worker()?,
+        })
+    }
+
+    // Opens a file and returns its ID on the worker.
+    async fn open(&self, path: PathBuf) -> Result<u64, Error> {
+        let (reply, response) = oneshot::channel();
+        send_request(&self.requests, Request::Open { path, reply })?;
+        receive_response(response).await
+    }
+}
+
+#[async_trait]
+impl ReadFileSystem for UringFileSystem {
+    type HashedFileIn = UringHashedFileIn;
+
+    async fn open_hashed_file(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let path = self.base_path.join(path.into());
+        let hash = path.file_stem()
+            .ok_or(Error::InvalidArgs(format!(
+                "file name must be hash: {}",
+                path.display(),
+            )))?
+            .to_string_lossy() // should not matter as Base64 is expected
+            .to_string();
+        let id = self.open(path).await?;
+        Ok(UringHashedFileIn {
+            requests: self.requests.clone(),
+            id,
+            offset: 0,
+            hash,
+            digest: ring::digest::Context::new(&ring::digest::SHA256),
+            pending: None,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+        })
+    }
+
+    /// Reads only the range with positioned reads.
+    async fn read_range(
+        &self,
+        path: impl Into<String> + Send,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let id = self.open(self.base_path.join(path.into())).await?;
+        let _close = CloseOnDrop { requests: &self.requests, id };
+        let mut buf: Vec<u8> = Vec::with_capacity(len);
+        while buf.len() < len {
+            let (reply, response) = oneshot::channel();
+            send_request(&self.requests, Request::ReadAt {
+                id,
+                offset: offset + buf.len() as u64,
+                len: len - buf.len(),
+                reply,
+            })?;
+            let chunk = receive_response(response).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(buf)
+    }
+
+    async fn list_files(
+        &self,
+        prefix: impl Into<String> + Send,
+    ) -> Result<Vec<String>, Error> {
+        self.local.list_files(prefix).await
+    }
+
+    async fn read_pointer(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Option<String>, Error> {
+        self.local.read_pointer(path).await
+    }
+}
+
+#[async_trait]
+impl WriteFileSystem for UringFileSystem {
+    type HashedFileOut = <LocalFileSystem as WriteFileSystem>::HashedFileOut;
+
+    async fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.local.create_hashed_file().await
+    }
+
+    async fn create_hashed_file_in(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.local.create_hashed_file_in(path).await
+    }
+
+    async fn delete(
+        &self,
+        path: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.local.delete(path).await
+    }
+
+    async fn write_pointer(
+        &self,
+        path: impl Into<String> + Send,
+        target: impl Into<String> + Send,
+    ) -> Result<(), Error> {
+        self.local.write_pointer(path, target).await
+    }
+}
+
+/// Local file read through io_uring whose contents can be verified with the
+/// hash.
+///
+/// File name is supposed to be a Base64 encoded URL-safe SHA256 digest of the
+/// contents plus an extension.
+pub struct UringHashedFileIn {
+    requests: mpsc::UnboundedSender<Request>,
+    // ID of the file on the worker.
+    id: u64,
+    // Offset of the next chunk to read.
+    offset: u64,
+    hash: String,
+    digest: ring::digest::Context,
+    // Response to the read of the next chunk.
+    pending: Option<oneshot::Receiver<std::io::Result<Vec<u8>>>>,
+    // Chunk being consumed.
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+}
+
+#[async_trait]
+impl HashedFileIn for UringHashedFileIn {
+    async fn verify(self) -> Result<(), Error> {
+        let hash = url_safe_base_64.encode(self.digest.clone().finish());
+        if self.hash == hash {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "hash discrepancy: expected {} but got {}",
+                self.hash,
+                hash,
+            )))
+        }
+    }
+}
+
+impl AsyncRead for UringHashedFileIn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.chunk_pos < this.chunk.len() {
+                let n = buf.remaining().min(this.chunk.len() - this.chunk_pos);
+                let bytes = &this.chunk[this.chunk_pos..this.chunk_pos + n];
+                this.digest.update(bytes);
+                buf.put_slice(bytes);
+                this.chunk_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.pending.is_none() {
+                let (reply, response) = oneshot::channel();
+                this.requests
+                    .send(Request::ReadAt {
+                        id: this.id,
+                        offset: this.offset,
+                        len: READ_CHUNK_SIZE,
+                        reply,
+                    })
+                    .map_err(|_| worker_stopped())?;
+                this.pending = Some(response);
+            }
+            let response = this.pending.as_mut().unwrap();
+            let result = ready!(Pin::new(response).poll(cx));
+            this.pending = None;
+            let chunk = result.map_err(|_| worker_stopped())??;
+            if chunk.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.offset += chunk.len() as u64;
+            this.chunk = chunk;
+            this.chunk_pos = 0;
+        }
+    }
+}
+
+impl Drop for UringHashedFileIn {
+    fn drop(&mut self) {
+        let _ = self.requests.send(Request::Close { id: self.id });
+    }
+}
+
+// Closes a file on the worker when dropped.
+struct CloseOnDrop<'a> {
+    requests: &'a mpsc::UnboundedSender<Request>,
+    id: u64,
+}
+
+impl Drop for CloseOnDrop<'_> {
+    fn drop(&mut self) {
+        let _ = self.requests.send(Request::Close { id: self.id });
+    }
+}
+
+// Request to the worker.
+enum Request {
+    // Opens a file, and replies its ID.
+    Open {
+        path: PathBuf,
+        reply: oneshot::Sender<std::io::Result<u64>>,
+    },
+    // Reads at most `len` bytes at `offset` of a file.
+    //
+    // Replies an empty chunk at the end of the file.
+    ReadAt {
+        id: u64,
+        offset: u64,
+        len: usize,
+        reply: oneshot::Sender<std::io::Result<Vec<u8>>>,
+    },
+    // Closes a file.
+    Close {
+        id: u64,
+    },
+}
+
+// Starts a thread that runs an io_uring runtime and serves requests.
+fn start_worker() -> Result<mpsc::UnboundedSender<Request>, Error> {
+    let (requests, receiver) = mpsc::unbounded_channel::<Request>();
+    let (started, starting) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("flechasdb-io-uring".to_string())
+        .spawn(move || {
+            let runtime =
+                match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ = started.send(Err(e));
+                        return;
+                    },
+                };
+            let _ = started.send(Ok(()));
+            runtime.block_on(serve(receiver));
+        })?;
+    match starting.recv() {
+        Ok(Ok(())) => Ok(requests),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(Error::InvalidContext(
+            "io_uring worker stopped while starting".to_string(),
+        )),
+    }
+}
+
+// Serves requests until all the senders are dropped.
+async fn serve(mut receiver: mpsc::UnboundedReceiver<Request>) {
+    let files: Rc<RefCell<HashMap<u64, Rc<tokio_uring::fs::File>>>> =
+        Rc::new(RefCell::new(HashMap::new()));
+    let mut next_id: u64 = 0;
+    while let Some(request) = receiver.recv().await {
+        match request {
+            Request::Open { path, reply } => {
+                let id = next_id;
+                next_id += 1;
+                let files = files.clone();
+                tokio_uring::spawn(async move {
+                    let result = tokio_uring::fs::File::open(path)
+                        .await
+                        .map(|file| {
+                            files.borrow_mut().insert(id, Rc::new(file));
+                            id
+                        });
+                    let _ = reply.send(result);
+                });
+            },
+            Request::ReadAt { id, offset, len, reply } => {
+                let file = files.borrow().get(&id).cloned();
+                tokio_uring::spawn(async move {
+                    let result = match file {
+                        Some(file) => {
+                            let buf = Vec::with_capacity(len);
+                            let (result, buf) = file.read_at(buf, offset).await;
+                            result.map(|_| buf)
+                        },
+                        None => Err(std::io::Error::other(
+                            format!("no such file: {}", id),
+                        )),
+                    };
+                    let _ = reply.send(result);
+                });
+            },
+            Request::Close { id } => {
+                // the file is closed when pending reads finish
+                files.borrow_mut().remove(&id);
+            },
+        }
+    }
+}
+
+// Sends a request to the worker.
+fn send_request(
+    requests: &mpsc::UnboundedSender<Request>,
+    request: Request,
+) -> Result<(), Error> {
+    requests.send(request).map_err(|_| worker_stopped().into())
+}
+
+// Receives a response from the worker.
+async fn receive_response<T>(
+    response: oneshot::Receiver<std::io::Result<T>>,
+) -> Result<T, Error> {
+    Ok(response.await.map_err(|_| worker_stopped())??)
+}
+
+// Error when the worker is no longer running.
+fn worker_stopped() -> std::io::Error {
+    std::io::Error::other("io_uring worker stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn uring_file_system_should_read_hashed_files() {
+        let contents: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let hash = url_safe_base_64.encode(
+            ring::digest::digest(&ring::digest::SHA256, &contents),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = format!("{}.bin", hash);
+        std::fs::write(dir.path().join(&path), &contents).unwrap();
+        std::fs::write(dir.path().join("AAAA.bin"), &contents).unwrap();
+        let fs = UringFileSystem::new(dir.path()).unwrap();
+
+        let mut f = fs.open_hashed_file(&path).await.unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, contents);
+        f.verify().await.unwrap();
+        let mut f = fs.open_hashed_file("AAAA.bin").await.unwrap();
+        f.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(matches!(
+            f.verify().await,
+            Err(Error::VerificationFailure(_)),
+        ));
+
+        let bytes = fs.read_range(&path, 100_000, 80_000).await.unwrap();
+        assert_eq!(bytes, contents[100_000..180_000]);
+        let bytes = fs.read_range(&path, 199_990, 20).await.unwrap();
+        assert_eq!(bytes, contents[199_990..]);
+        assert!(fs.read_range(&path, 300_000, 5).await.unwrap().is_empty());
+        assert!(matches!(
+            fs.open_hashed_file("missing.bin").await,
+            Err(Error::IOError(_)),
+        ));
+    }
+}
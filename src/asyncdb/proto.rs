@@ -1,19 +1,179 @@
 //! Asynchronous utilities for Protocol Buffers.
 
 use protobuf::Message;
+use std::io::Read;
 use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
 
 use crate::error::Error;
+use crate::protos::check_message_size;
+
+// Number of bytes read from an input stream at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+// Number of chunks read ahead of parsing.
+const NUM_PENDING_CHUNKS: usize = 4;
 
 /// Reads a message from a given
 /// [`AsyncRead`](https://docs.rs/tokio/1.32.0/tokio/io/trait.AsyncRead.html).
+///
+/// The message is parsed on a blocking thread of Tokio while the input
+/// stream is being read, so that the entire encoded message is never held
+/// in memory together with the parsed one. At most a few chunks are read
+/// ahead of parsing.
 pub async fn read_message<M, R>(r: &mut R) -> Result<M, Error>
 where
     M: Message,
     R: AsyncRead + Unpin + ?Sized,
 {
-    let mut buf: Vec<u8> = Vec::with_capacity(1024 * 1024);
-    r.read_to_end(&mut buf).await?;
-    let m = M::parse_from_bytes(&buf)?;
-    Ok(m)
+    read_message_up_to(r, None).await
+}
+
+/// Reads a message no larger than a given number of bytes from a given
+/// [`AsyncRead`](https://docs.rs/tokio/1.32.0/tokio/io/trait.AsyncRead.html).
+///
+/// See [`crate::protos::read_message_with_max_size`].
+pub async fn read_message_with_max_size<M, R>(
+    r: &mut R,
+    max_size: usize,
+) -> Result<M, Error>
+where
+    M: Message,
+    R: AsyncRead + Unpin + ?Sized,
+{
+    read_message_up_to(r, Some(max_size)).await
+}
+
+// Reads a message no larger than `max_size` bytes unless `None`.
+pub(crate) async fn read_message_up_to<M, R>(
+    r: &mut R,
+    max_size: Option<usize>,
+) -> Result<M, Error>
+where
+    M: Message,
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(NUM_PENDING_CHUNKS);
+    let parser = tokio::task::spawn_blocking(move || {
+        crate::protos::read_message::<M, _>(&mut ChunkReader::new(rx))
+    });
+    let feeder = async move {
+        let mut size: u64 = 0;
+        loop {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            let n = r.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            size += n as u64;
+            if let Some(max_size) = max_size {
+                check_message_size(size, max_size)?;
+            }
+            chunk.truncate(n);
+            if tx.send(chunk).await.is_err() {
+                // the parser has finished or failed
+                break;
+            }
+        }
+        Ok::<(), Error>(())
+    };
+    let fed = feeder.await;
+    let parsed = match parser.await {
+        Ok(parsed) => parsed,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => return Err(Error::Cancelled(format!(
+            "parsing a message has been cancelled: {}",
+            e,
+        ))),
+    };
+    // a read error is the cause of a parse error
+    fed?;
+    parsed
+}
+
+// Synchronous reader of chunks sent from an asynchronous task.
+//
+// The stream ends when the sender is dropped.
+struct ChunkReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    // Position in the current chunk.
+    pos: usize,
+}
+
+impl ChunkReader {
+    fn new(rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::protos::database::VectorSet as ProtosVectorSet;
+    use crate::protos::write_message;
+
+    #[tokio::test]
+    async fn read_message_should_parse_message_larger_than_chunks() {
+        let mut message = ProtosVectorSet::new();
+        message.vector_size = 4;
+        message.data = (0..100_000).map(|i| i as f32).collect();
+        let mut encoded: Vec<u8> = Vec::new();
+        write_message(&message, &mut encoded).unwrap();
+        assert!(encoded.len() > CHUNK_SIZE * (NUM_PENDING_CHUNKS + 1));
+
+        let parsed: ProtosVectorSet =
+            read_message(&mut &encoded[..]).await.unwrap();
+        assert_eq!(parsed, message);
+        let truncated = &encoded[..encoded.len() - 1];
+        assert!(matches!(
+            read_message::<ProtosVectorSet, _>(&mut &truncated[..]).await,
+            Err(Error::ProtobufError(_)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_message_with_max_size_should_reject_larger_message() {
+        let mut message = ProtosVectorSet::new();
+        message.vector_size = 4;
+        message.data = (0..100_000).map(|i| i as f32).collect();
+        let mut encoded: Vec<u8> = Vec::new();
+        write_message(&message, &mut encoded).unwrap();
+        let size = encoded.len();
+
+        let parsed: ProtosVectorSet =
+            read_message_with_max_size(&mut &encoded[..], size)
+                .await
+                .unwrap();
+        assert_eq!(parsed, message);
+        assert!(matches!(
+            read_message_with_max_size::<ProtosVectorSet, _>(
+                &mut &encoded[..],
+                size - 1,
+            ).await,
+            Err(Error::InvalidData(_)),
+        ));
+    }
 }
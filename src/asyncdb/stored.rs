@@ -6,12 +6,59 @@ use core::hash::Hash;
 use core::marker::{PhantomData, Send, Sync};
 use core::num::NonZeroUsize;
 use futures::future::try_join_all;
-use std::collections::hash_map::{Entry as HashMapEntry};
-use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, OnceCell};
+use protobuf::Message;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use tokio::sync::{
+    MappedMutexGuard,
+    Mutex,
+    MutexGuard,
+    OnceCell,
+    Semaphore,
+};
 use uuid::Uuid;
 
-use crate::db::{AttributeValue, AttributeTable, Attributes};
+use crate::db::{
+    AttributeValue,
+    AttributeTable,
+    Attributes,
+    CompressionPolicy,
+    FileCompression,
+    FileKind,
+    Generation,
+    OpenOptions,
+    PartitionAssignment,
+    PartitionStats,
+    Quantization,
+    QuantizationError,
+    ValidationMode,
+    assign_partition,
+    attribute_table_bytes,
+    exact_squared_distance,
+    num_rerank_candidates,
+    project_query,
+    strings_bytes,
+};
+use crate::db::encoder::PqEncoder;
+use crate::db::manifest::{CURRENT, check_published};
+use crate::db::proto::{
+    deserialize_chunk_trees,
+    deserialize_file_compression,
+    deserialize_hash_algorithm,
+    deserialize_partition_residues,
+    deserialize_partition_sizes,
+    deserialize_projection,
+    deserialize_quantization,
+    deserialize_quantization_errors,
+    deserialize_squared_error_norms,
+    replay_attributes_log,
+};
 use crate::error::Error;
+use crate::io::hash::HashAlgorithm;
+use crate::io::merkle::MerkleTree;
+use crate::kmeans::Scalar;
+use crate::linalg::simd::Kernels;
+use crate::projection::Projection;
 use crate::protos::Deserialize;
 use crate::protos::database::{
     AttributesLog as ProtosAttributesLog,
@@ -21,13 +68,22 @@ use crate::protos::database::{
 };
 use crate::slice::AsSlice;
 use crate::vector::BlockVectorSet;
+use crate::vector::proto::{LazyEncodedVectorSet, deserialize_lazy};
 
-use super::io::{FileSystem, HashedFileIn};
-use super::proto::read_message;
+use super::io::{
+    ChunkVerifiedFileIn,
+    CompressedHashedFileIn,
+    HashedFileIn,
+    ReadFileSystem,
+};
+use get_attribute::GetAttributeInPartition;
+use super::proto::read_message_up_to;
 
 pub mod get_attribute;
+pub mod handle;
 pub mod query;
-pub use query::{Query, QueryEvent, QueryResult};
+pub use handle::{DatabaseHandle, SharedQueryResult};
+pub use query::{PartitionQueryResult, Query, QueryEvent, QueryResult};
 
 /// Extension for Protocol Buffers files.
 pub const PROTOBUF_EXTENSION: &str = "binpb";
@@ -53,6 +109,29 @@ where
     attributes_log_load_flags: Vec<OnceCell<bool>>,
     attribute_names: Vec<String>,
     attribute_table: Mutex<AttributeTable>,
+    residues_ids: Vec<String>,
+    // Whether `residues_ids` refer to original vectors.
+    original_vectors: bool,
+    quantization_errors: Vec<QuantizationError<T>>,
+    // Projection applied to query vectors.
+    projection: Option<Projection<T>>,
+    quantization: Quantization,
+    compression: FileCompression,
+    hash_algorithm: HashAlgorithm,
+    // Merkle trees of files keyed by their paths.
+    chunk_trees: HashMap<String, MerkleTree>,
+    // Number of vectors in each partition. `None` if unknown.
+    partition_sizes: Option<Vec<usize>>,
+    verification: bool,
+    validation_mode: ValidationMode,
+    // Maximum number of bytes of a message. `None` if unlimited.
+    max_message_size: Option<usize>,
+    fast_scan: bool,
+    // Number of threads reductions over long vectors are split among.
+    // `None` if disabled.
+    parallel_reduction: Option<NonZeroUsize>,
+    // Permits to load files. `None` if unlimited.
+    load_permits: Option<Semaphore>,
 }
 
 impl<T, FS> Database<T, FS>
@@ -61,6 +140,8 @@ where
     FS: Send,
 {
     /// Returns the vector size.
+    ///
+    /// The output size of the projection if the database has one.
     pub const fn vector_size(&self) -> usize {
         self.vector_size
     }
@@ -80,6 +161,151 @@ where
         self.num_codes
     }
 
+    /// Returns the quantization errors of the codebooks.
+    ///
+    /// Empty if the database was serialized without them.
+    pub fn quantization_errors(&self) -> &[QuantizationError<T>] {
+        &self.quantization_errors
+    }
+
+    /// Returns the quantization of residues.
+    pub const fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    /// Returns the projection applied to query vectors.
+    ///
+    /// `None` if the database was built without PCA or a random rotation.
+    pub fn projection(&self) -> Option<&Projection<T>> {
+        self.projection.as_ref()
+    }
+
+    /// Returns the total number of vectors in the database.
+    ///
+    /// Answers without loading any partition. `None` if the database was
+    /// serialized without vector counts.
+    pub fn num_vectors(&self) -> Option<usize> {
+        self.partition_sizes.as_ref().map(|sizes| sizes.iter().sum())
+    }
+
+    /// Returns the number of vectors in each partition.
+    ///
+    /// `None` if the database was serialized without vector counts.
+    pub fn partition_sizes(&self) -> Option<&[usize]> {
+        self.partition_sizes.as_deref()
+    }
+
+    /// Returns the statistics of the numbers of vectors in partitions.
+    ///
+    /// Tells how imbalanced the partitions are without loading any of them.
+    /// `None` if the database was serialized without vector counts.
+    pub fn partition_stats(&self) -> Option<PartitionStats> {
+        self.partition_sizes
+            .as_deref()
+            .and_then(PartitionStats::from_sizes)
+    }
+
+    /// Returns which kinds of files of the database are compressed.
+    ///
+    /// Recorded in the database header, so loading a file never has to
+    /// guess whether it is compressed.
+    pub fn compression(&self) -> CompressionPolicy {
+        self.compression.policy()
+    }
+
+    /// Returns the algorithm of the content hashes of the files of the
+    /// database.
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    /// Returns if the database has residue vectors persisted.
+    ///
+    /// Also `true` if original vectors are persisted instead of residues.
+    pub fn has_residues(&self) -> bool {
+        !self.residues_ids.is_empty()
+    }
+
+    /// Returns if original vectors are persisted instead of residues.
+    pub fn has_original_vectors(&self) -> bool {
+        self.has_residues() && self.original_vectors
+    }
+
+    /// Returns the generation of the attributes log of a partition.
+    ///
+    /// `None` if `index` ≥ `num_partitions`.
+    pub fn attributes_generation(&self, index: usize) -> Option<Generation> {
+        self.attributes_log_ids
+            .get(index)
+            .map(|id| Generation::of_attributes_log(id))
+    }
+
+    /// Estimates the number of bytes of memory the database occupies.
+    ///
+    /// Counts only what has been loaded so far; i.e., partition centroids,
+    /// codebooks, partitions, and attributes, in addition to file IDs.
+    /// Overheads of allocators are not counted.
+    ///
+    /// Waits for the lock of the attribute table.
+    pub async fn estimated_memory_bytes(&self) -> usize {
+        let partitions: usize = self.partitions
+            .iter()
+            .filter_map(|p| p.get())
+            .map(|p| p.memory_bytes())
+            .sum();
+        let partition_centroids = self.partition_centroids
+            .get()
+            .map_or(0, |c| c.memory_bytes());
+        let codebooks = self.codebooks
+            .get()
+            .map_or(0, |cbs| cbs.iter().map(|c| c.memory_bytes()).sum());
+        let attribute_table =
+            attribute_table_bytes(&*self.attribute_table.lock().await);
+        let ids = self.partition_centroids_id.capacity()
+            + strings_bytes(&self.partition_ids)
+            + strings_bytes(&self.codebook_ids)
+            + strings_bytes(&self.attributes_log_ids)
+            + strings_bytes(&self.attribute_names)
+            + strings_bytes(&self.residues_ids);
+        core::mem::size_of_val(self)
+            + core::mem::size_of_val(&self.partitions[..])
+            + core::mem::size_of_val(&self.attributes_log_load_flags[..])
+            + partitions
+            + partition_centroids
+            + codebooks
+            + attribute_table
+            + ids
+    }
+
+    // Verifies the provenance of a given query result.
+    fn verify_query_result(
+        &self,
+        result: &PartitionQueryResult<T>,
+    ) -> Result<(), Error> {
+        let pi = result.partition_index;
+        if pi >= self.num_partitions() {
+            return Err(Error::InvalidContext(format!(
+                "stale query result: partition index {} out of bounds",
+                pi,
+            )));
+        }
+        if result.partition_id != self.partition_ids[pi] {
+            return Err(Error::InvalidContext(format!(
+                "stale query result: partition ID {} vs {}",
+                result.partition_id,
+                self.partition_ids[pi],
+            )));
+        }
+        if result.attributes_log_id != self.attributes_log_ids[pi] {
+            return Err(Error::InvalidContext(format!(
+                "stale query result: attributes log ID {} vs {}",
+                result.attributes_log_id,
+                self.attributes_log_ids[pi],
+            )));
+        }
+        Ok(())
+    }
+
     // Returns the attribute value.
     //
     // Supposes the attributes log of the partition where a given vector
@@ -103,7 +329,7 @@ where
         ).or(Err(Error::InvalidArgs(format!("no such vector: {}", uuid))))?;
         match MappedMutexGuard::try_map(
             attributes,
-            |attrs| attrs.get_mut(&key),
+            |attrs| attrs.get_mut(key),
         ) {
             Ok(value) => Ok(Some(value)),
             Err(_) => Ok(None),
@@ -121,6 +347,8 @@ where
     Self: 'db + LoadPartitionCentroids<'db, T>,
 {
     /// Queries k-nearest neighbors of a given vector.
+    ///
+    /// The query fails if `v` has an infinite or NaN element.
     pub fn query<'v, V>(
         &'db self,
         v: &'v V,
@@ -134,6 +362,8 @@ where
     }
 
     /// Queries k-nearest neighbors of a given vector.
+    ///
+    /// The query fails if `v` has an infinite or NaN element.
     pub fn query_with_events<'v, V, EV>(
         &'db self,
         v: &'v V,
@@ -149,29 +379,181 @@ where
     }
 }
 
+impl<'db, T, FS> Database<T, FS>
+where
+    T: Scalar + Send + Sync,
+    FS: Send + Sync,
+    Self: 'db
+        + LoadPartitionCentroids<'db, T>
+        + LoadCodebook<T>
+        + LoadPartition<'db, T>
+        + LoadVectors<T>,
+{
+    /// Queries k-nearest neighbors of a given vector, and re-ranks them by
+    /// exact distances.
+    ///
+    /// Retrieves `k` × `oversample` candidates with approximate distances,
+    /// recomputes their squared distances from the persisted vectors, and
+    /// returns the `k` nearest of them. See the [synchronous
+    /// counterpart][sync] for details.
+    ///
+    /// Fails if `v` has an infinite or NaN element, `k` × `oversample`
+    /// overflows, or the database has no residues.
+    ///
+    /// [sync]: crate::db::stored::Database::query_with_reranking
+    pub async fn query_with_reranking<V>(
+        &'db self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+        oversample: NonZeroUsize,
+    ) -> Result<Vec<QueryResult<'db, T, FS>>, Error>
+    where
+        V: AsSlice<T> + Send + ?Sized,
+    {
+        if self.quantization() == Quantization::Flat {
+            return self.query(v, k, nprobe).await;
+        }
+        let num_candidates = num_rerank_candidates(k, oversample)?;
+        let mut results = self.query(v, num_candidates, nprobe).await?;
+        let v = project_query(self.projection(), v.as_slice())?;
+        // loads the vectors of each partition only once
+        let mut vectors: HashMap<usize, BlockVectorSet<T>> = HashMap::new();
+        for result in results.iter() {
+            let pi = result.partition_index;
+            if let Entry::Vacant(entry) = vectors.entry(pi) {
+                entry.insert(self.load_vectors(pi).await?);
+            }
+        }
+        for result in results.iter_mut() {
+            let squared_distance = exact_squared_distance(
+                &v,
+                &vectors[&result.partition_index],
+                result.vector_index,
+                self.parallel_reduction,
+            )?;
+            result.set_exact_distance(squared_distance);
+        }
+        results.sort_by(|l, r| {
+            (l.squared_distance, l.vector_id)
+                .partial_cmp(&(r.squared_distance, r.vector_id))
+                .unwrap()
+        });
+        results.truncate(k.get());
+        Ok(results)
+    }
+}
+
+impl<'db, T, FS> Database<T, FS>
+where
+    T: Scalar + Send,
+    FS: Send,
+    Self: 'db + LoadPartitionCentroids<'db, T>,
+{
+    /// Assigns a given vector to the nearest partition.
+    ///
+    /// Lazily loads partition centroids. Projects `v` if the database has a
+    /// projection.
+    ///
+    /// Fails if the vector size does not match.
+    pub async fn assign_partition<V>(
+        &'db self,
+        v: &V,
+    ) -> Result<PartitionAssignment<T>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let v = project_query(self.projection(), v.as_slice())?;
+        let partition_centroids = self.load_partition_centroids().await?;
+        assign_partition(partition_centroids, &v)
+    }
+}
+
+impl<'db, T, FS> Database<T, FS>
+where
+    T: Scalar + Send,
+    FS: Send,
+    Self: 'db + LoadPartitionCentroids<'db, T> + LoadCodebook<T>,
+{
+    /// Returns an encoder that encodes vectors the same way as the vectors
+    /// in the database.
+    ///
+    /// Lazily loads partition centroids and codebooks.
+    pub async fn pq_encoder(&'db self) -> Result<PqEncoder<T>, Error> {
+        let partition_centroids = self.load_partition_centroids().await?;
+        let codebooks = self.load_codebooks().await?;
+        PqEncoder::new(partition_centroids.clone(), codebooks.clone())
+    }
+}
+
+impl<'db, T, FS> Database<T, FS>
+where
+    T: Send + Sync,
+    FS: Send + Sync,
+    Self: 'db + LoadAttributesLog<'db>,
+{
+    /// Returns an attribute value of the vector corresponding to a given
+    /// query result.
+    ///
+    /// Fails if `result` is stale against the database; i.e., the partition
+    /// or its attributes log has been changed since the query.
+    pub async fn get_attribute_of<K>(
+        &'db self,
+        result: &'db PartitionQueryResult<T>,
+        key: &'db K,
+    ) -> Result<Option<AttributeValue>, Error>
+    where
+        String: Borrow<K>,
+        K: Hash + Eq + Send + Sync + ?Sized,
+    {
+        GetAttributeInPartition::of_query_result(self, result, key).await
+    }
+}
+
 /// Partition.
 pub struct Partition<T> {
     _t: PhantomData<T>,
-    encoded_vectors: BlockVectorSet<u32>,
+    encoded_vectors: LazyEncodedVectorSet,
     vector_ids: Vec<Uuid>,
+    // Residues in full precision. `None` if the database is quantized.
+    residues: Option<BlockVectorSet<T>>,
+    // Squared norms of the quantization errors. `None` if unknown.
+    squared_error_norms: Option<Vec<T>>,
 }
 
 impl<T> Partition<T> {
+    // Returns the number of bytes allocated for the partition.
+    fn memory_bytes(&self) -> usize {
+        self.encoded_vectors.memory_bytes()
+            + self.vector_ids.capacity() * core::mem::size_of::<Uuid>()
+            + self.residues.as_ref().map_or(0, |residues| {
+                residues.len()
+                    * residues.vector_size()
+                    * core::mem::size_of::<T>()
+            })
+            + self.squared_error_norms.as_ref().map_or(0, |norms| {
+                norms.capacity() * core::mem::size_of::<T>()
+            })
+    }
+
     const fn num_divisions(&self) -> usize {
         self.encoded_vectors.vector_size()
     }
 
-    fn num_vectors(&self) -> usize {
-        self.encoded_vectors.len()
+    const fn encoded_vectors(&self) -> &LazyEncodedVectorSet {
+        &self.encoded_vectors
     }
 
-    // Panics if the index is out of bounds.
-    fn get_encoded_vector<'a>(&'a self, index: usize) -> &'a [u32] {
-        self.encoded_vectors.get(index)
+    fn residues(&self) -> Option<&BlockVectorSet<T>> {
+        self.residues.as_ref()
+    }
+
+    fn squared_error_norms(&self) -> Option<&[T]> {
+        self.squared_error_norms.as_deref()
     }
 
     // Panics if the index is out of bounds.
-    fn get_vector_id<'a>(&'a self, index: usize) -> &'a Uuid {
+    fn get_vector_id(&self, index: usize) -> &Uuid {
         &self.vector_ids[index]
     }
 }
@@ -181,12 +563,58 @@ impl<T> Partition<T> {
 /// Supposed to be specialized for a specific [`Database`].
 #[async_trait]
 pub trait LoadDatabase<T, FS> {
-    /// Loads a database.
+    /// Loads a database with the default [`OpenOptions`].
     async fn load_database<P>(fs: FS, path: P) -> Result<Database<T, FS>, Error>
+    where
+        T: Send,
+        FS: Send + 'async_trait,
+        P: Into<String> + Send + 'async_trait,
+    {
+        Self::load_database_with_options(fs, path, OpenOptions::default())
+            .await
+    }
+
+    /// Loads a database with given options.
+    ///
+    /// The cache budget in `options` is ignored.
+    async fn load_database_with_options<P>(
+        fs: FS,
+        path: P,
+        options: OpenOptions,
+    ) -> Result<Database<T, FS>, Error>
     where
         T: Send,
         FS: Send,
         P: Into<String> + Send;
+
+    /// Loads the current version of a database with the default
+    /// [`OpenOptions`].
+    ///
+    /// See [`crate::db::manifest`].
+    async fn load_current_database(fs: FS) -> Result<Database<T, FS>, Error>
+    where
+        T: Send,
+        FS: ReadFileSystem + Send + Sync + 'async_trait,
+    {
+        Self::load_current_database_with_options(fs, OpenOptions::default())
+            .await
+    }
+
+    /// Loads the current version of a database with given options.
+    ///
+    /// Loads the database header [`CURRENT`] names. Fails with
+    /// `Error::InvalidContext` if no version has been published.
+    async fn load_current_database_with_options(
+        fs: FS,
+        options: OpenOptions,
+    ) -> Result<Database<T, FS>, Error>
+    where
+        T: Send,
+        FS: ReadFileSystem + Send + Sync + 'async_trait,
+    {
+        let path = check_published(fs.read_pointer(CURRENT).await?)?;
+        Self::load_database_with_options(fs, path, options).await
+    }
 }
 
 /// Capability of loading a partition centroids.
@@ -228,6 +656,43 @@ pub trait LoadPartition<'db, T> {
     ) -> Result<&'db Partition<T>, Error>;
 }
 
+/// Capability of loading residue vectors.
+///
+/// Supposed to be specialized for a specific [`Database`].
+#[async_trait]
+pub trait LoadResidues<T> {
+    /// Loads the residue vectors of a partition at a given index.
+    ///
+    /// Residue vectors are in the same order as the vectors in the
+    /// partition. Residues are derived from original vectors if the database
+    /// persists original vectors instead.
+    ///
+    /// Fails if `index` is out of bounds, or the database has no residues.
+    async fn load_residues(
+        &self,
+        index: usize,
+    ) -> Result<BlockVectorSet<T>, Error>;
+}
+
+/// Capability of loading original vectors.
+///
+/// Supposed to be specialized for a specific [`Database`].
+#[async_trait]
+pub trait LoadVectors<T> {
+    /// Loads the original vectors of a partition at a given index.
+    ///
+    /// Vectors are in the same order as the vectors in the partition, and
+    /// in the space the database indexes; i.e., projected if the database
+    /// has a projection. Vectors are reconstructed from residues unless the
+    /// database persists original vectors.
+    ///
+    /// Fails if `index` is out of bounds, or the database has no residues.
+    async fn load_vectors(
+        &self,
+        index: usize,
+    ) -> Result<BlockVectorSet<T>, Error>;
+}
+
 /// Capability of loading the attributes log of a partition.
 ///
 /// Supposed to be specialized for a specific [`Database`].
@@ -239,6 +704,85 @@ pub trait LoadAttributesLog<'db> {
     async fn load_attributes_log(&'db self, index: usize) -> Result<(), Error>;
 }
 
+impl<T, FS> Database<T, FS>
+where
+    T: Send,
+    FS: ReadFileSystem + Send + Sync,
+{
+    // Reads a message in a file of a given kind verifying it unless disabled.
+    //
+    // Decompresses the file if the database says so. Verifies the file
+    // chunk by chunk if the database records the Merkle tree of the file.
+    // Waits for a load permit if the number of concurrent loads is limited.
+    async fn read_file<M>(
+        &self,
+        kind: FileKind,
+        path: String,
+    ) -> Result<M, Error>
+    where
+        M: Message,
+    {
+        let _permit = match self.load_permits.as_ref() {
+            Some(permits) => Some(
+                permits.acquire().await.expect("permits must not be closed"),
+            ),
+            None => None,
+        };
+        let compressed = self.compression.is_compressed(kind, &path);
+        if !self.verification {
+            let mut f = self.fs.open_hashed_file_unverified(path).await?;
+            return if compressed {
+                read_message_up_to(
+                    &mut CompressedHashedFileIn::new(f),
+                    self.max_message_size,
+                ).await
+            } else {
+                read_message_up_to(&mut f, self.max_message_size).await
+            };
+        }
+        if let Some(tree) = self.chunk_trees.get(&path) {
+            let f = self.fs.open_hashed_file_unverified(path).await?;
+            let mut f = ChunkVerifiedFileIn::new(f, tree.clone());
+            return if compressed {
+                let mut f = CompressedHashedFileIn::new(f);
+                let message =
+                    read_message_up_to(&mut f, self.max_message_size).await?;
+                f.verify().await?;
+                Ok(message)
+            } else {
+                let message =
+                    read_message_up_to(&mut f, self.max_message_size).await?;
+                f.verify().await?;
+                Ok(message)
+            };
+        }
+        if !compressed {
+            let mut f = self.fs
+                .open_hashed_file_with(path, self.hash_algorithm)
+                .await?;
+            let message =
+                read_message_up_to(&mut f, self.max_message_size).await?;
+            f.verify().await?;
+            Ok(message)
+        } else if self.hash_algorithm == HashAlgorithm::Sha256 {
+            let mut f = self.fs.open_compressed_hashed_file(path).await?;
+            let message =
+                read_message_up_to(&mut f, self.max_message_size).await?;
+            f.verify().await?;
+            Ok(message)
+        } else {
+            let f = self.fs
+                .open_hashed_file_with(path, self.hash_algorithm)
+                .await?;
+            let mut f = CompressedHashedFileIn::new(f);
+            let message =
+                read_message_up_to(&mut f, self.max_message_size).await?;
+            f.verify().await?;
+            Ok(message)
+        }
+    }
+}
+
 impl<'db, T, FS> Database<T, FS>
 where
     T: Send,
@@ -259,7 +803,7 @@ where
 impl<'db, T, FS> LoadAttributesLog<'db> for Database<T, FS>
 where
     T: Send + Sync,
-    FS: FileSystem + Send + Sync,
+    FS: ReadFileSystem + Send + Sync,
     Self: LoadPartition<'db, T> + Sync,
 {
     async fn load_attributes_log(&'db self, index: usize) -> Result<(), Error> {
@@ -273,14 +817,10 @@ where
         self.attributes_log_load_flags[index].get_or_try_init(|| async move {
             let partition = self.load_partition(index).await?;
             let id = &self.attributes_log_ids[index];
-            let mut f = self.fs.open_compressed_hashed_file(format!(
-                "attributes/{}.{}",
-                id,
-                PROTOBUF_EXTENSION,
-            )).await?;
-            let attributes_log: ProtosAttributesLog =
-                read_message(&mut f).await?;
-            f.verify().await?;
+            let attributes_log: ProtosAttributesLog = self.read_file(
+                FileKind::AttributesLog,
+                format!("attributes/{}.{}", id, PROTOBUF_EXTENSION),
+            ).await?;
             if attributes_log.partition_id != self.partition_ids[index] {
                 return Err(Error::InvalidData(format!(
                     "inconsistent partition IDs: {} vs {}",
@@ -289,52 +829,17 @@ where
                 )));
             }
             let mut attribute_table = self.attribute_table.lock().await;
-            for (i, entry) in attributes_log.entries.into_iter().enumerate() {
-                let vector_id = entry.vector_id
-                    .into_option()
-                    .ok_or(Error::InvalidData(format!(
-                        "attributes log[{}, {}]: missing vector ID",
-                        index,
-                        i,
-                    )))?
-                    .deserialize()?;
-                let attribute_name = self.attribute_names
-                    .get(entry.name_index as usize)
-                    .ok_or(Error::InvalidData(format!(
-                        "attribute name index out of bounds: {}",
-                        entry.name_index,
-                    )))?;
-                let value = entry.value
-                    .into_option()
-                    .ok_or(Error::InvalidData(format!(
-                        "attributes log[{}, {}]: missing value",
-                        index,
-                        i,
-                    )))?
-                    .deserialize()?;
-                match attribute_table.entry(vector_id) {
-                    HashMapEntry::Occupied(slot) => {
-                        match slot.into_mut().entry(attribute_name.clone()) {
-                            HashMapEntry::Occupied(slot) => {
-                                *slot.into_mut() = value;
-                            },
-                            HashMapEntry::Vacant(slot) => {
-                                slot.insert(value);
-                            },
-                        };
-                    },
-                    HashMapEntry::Vacant(slot) => {
-                        slot.insert(Attributes::from([
-                            (attribute_name.clone(), value),
-                        ]));
-                    },
-                };
-            }
+            replay_attributes_log(
+                attributes_log,
+                index,
+                &self.attribute_names,
+                &mut attribute_table,
+            )?;
             // defaults to empty attributes so that get_attribute won't fail
             // for an existing vector without attributes.
             for vector_id in partition.vector_ids.iter() {
                 attribute_table
-                    .entry(vector_id.clone())
+                    .entry(*vector_id)
                     .or_insert_with(Attributes::new);
             }
             Ok(true)
@@ -349,43 +854,47 @@ mod f32impl {
 #[async_trait]
     impl<FS> LoadDatabase<f32, FS> for Database<f32, FS>
     where
-        for<'a> FS: 'a + FileSystem + Send + Sync,
+        for<'a> FS: 'a + ReadFileSystem + Send + Sync,
     {
-        async fn load_database<P>(
+        async fn load_database_with_options<P>(
             fs: FS,
             path: P,
+            options: OpenOptions,
         ) -> Result<Database<f32, FS>, Error>
         where
             P: Into<String> + Send,
         {
             let mut f = fs.open_compressed_hashed_file(path).await?;
-            let db: ProtosDatabase = read_message(&mut f).await?;
+            let db: ProtosDatabase =
+                read_message_up_to(&mut f, options.max_message_size()).await?;
             f.verify().await?;
             let vector_size = db.vector_size as usize;
             let num_partitions = db.num_partitions as usize;
             let num_divisions = db.num_divisions as usize;
             let num_codes = db.num_codes as usize;
             if vector_size == 0 {
-                return Err(Error::InvalidData(format!("vector_size is zero")));
+                return Err(Error::InvalidData(
+                    "vector_size is zero".to_string(),
+                ));
             }
             if num_divisions == 0 {
                 return Err(Error::InvalidData(
-                    format!("num_divisions is zero"),
+                    "num_divisions is zero".to_string(),
                 ));
             }
             if num_partitions == 0 {
                 return Err(Error::InvalidData(
-                    format!("num_partitions is zero"),
+                    "num_partitions is zero".to_string(),
                 ));
             }
             if num_codes == 0 {
-                return Err(Error::InvalidData(format!("num_codes is zero")));
+                return Err(Error::InvalidData("num_codes is zero".to_string()));
             }
-            if vector_size % num_divisions != 0 {
+            if num_divisions > vector_size {
                 return Err(Error::InvalidData(format!(
-                    "vector_size {} is not multiple of num_divisions {}",
-                    vector_size,
+                    "num_divisions {} exceeds vector_size {}",
                     num_divisions,
+                    vector_size,
                 )));
             }
             if num_partitions != db.partition_ids.len() {
@@ -402,6 +911,15 @@ mod f32impl {
                     db.codebook_ids.len(),
                 )));
             }
+            if !db.residues_ids.is_empty()
+                && num_partitions != db.residues_ids.len()
+            {
+                return Err(Error::InvalidData(format!(
+                    "num_partitions {} and residues_ids.len() {} do not match",
+                    num_partitions,
+                    db.residues_ids.len(),
+                )));
+            }
             let mut partitions = Vec::with_capacity(num_partitions);
             partitions.resize_with(num_partitions, OnceCell::new);
             let mut attributes_log_load_flags =
@@ -410,46 +928,73 @@ mod f32impl {
                 num_partitions,
                 OnceCell::new,
             );
-            Ok(
-                Database {
-                    fs,
-                    vector_size,
-                    num_partitions,
-                    num_divisions,
-                    num_codes,
-                    partition_ids: db.partition_ids,
-                    partitions,
-                    partition_centroids_id: db.partition_centroids_id,
-                    partition_centroids: OnceCell::new(),
-                    codebook_ids: db.codebook_ids,
-                    codebooks: OnceCell::new(),
-                    attributes_log_ids: db.attributes_log_ids,
-                    attributes_log_load_flags,
-                    attribute_names: db.attribute_names,
-                    attribute_table: Mutex::new(AttributeTable::new()),
-                }
-            )
+            let quantization_errors = deserialize_quantization_errors(&db)?;
+            let projection = deserialize_projection(&db)?;
+            let quantization = deserialize_quantization(&db)?;
+            let compression = deserialize_file_compression(&db)?;
+            let hash_algorithm = deserialize_hash_algorithm(&db)?;
+            let chunk_trees = deserialize_chunk_trees(&db)?;
+            let partition_sizes = deserialize_partition_sizes(&db)?;
+            let db = Database {
+                fs,
+                vector_size,
+                num_partitions,
+                num_divisions,
+                num_codes,
+                partition_ids: db.partition_ids,
+                partitions,
+                partition_centroids_id: db.partition_centroids_id,
+                partition_centroids: OnceCell::new(),
+                codebook_ids: db.codebook_ids,
+                codebooks: OnceCell::new(),
+                attributes_log_ids: db.attributes_log_ids,
+                attributes_log_load_flags,
+                attribute_names: db.attribute_names,
+                attribute_table: Mutex::new(AttributeTable::new()),
+                residues_ids: db.residues_ids,
+                original_vectors: db.original_vectors,
+                quantization_errors,
+                projection,
+                quantization,
+                compression,
+                hash_algorithm,
+                chunk_trees,
+                partition_sizes,
+                verification: options.is_verification_enabled(),
+                validation_mode: options.validation_mode(),
+                max_message_size: options.max_message_size(),
+                fast_scan: options.is_fast_scan_enabled(),
+                parallel_reduction: options.parallel_reduction(),
+                load_permits: options
+                    .max_concurrent_loads()
+                    .map(|n| Semaphore::new(n.get())),
+            };
+            if options.is_eager() {
+                db.load_partition_centroids().await?;
+                db.load_codebooks().await?;
+            }
+            Ok(db)
         }
     }
 
     #[async_trait]
     impl<'db, FS> LoadPartitionCentroids<'db, f32> for Database<f32, FS>
     where
-        FS: FileSystem + Send + Sync,
+        FS: ReadFileSystem + Send + Sync,
         Self: 'db,
     {
         async fn load_partition_centroids(
             &'db self,
         ) -> Result<&'db BlockVectorSet<f32>, Error> {
             self.partition_centroids.get_or_try_init(|| async move {
-                let mut f = self.fs.open_hashed_file(format!(
-                    "partitions/{}.{}",
-                    self.partition_centroids_id,
-                    PROTOBUF_EXTENSION,
-                )).await?;
-                let partition_centroids: ProtosVectorSet =
-                    read_message(&mut f).await?;
-                f.verify().await?;
+                let partition_centroids: ProtosVectorSet = self.read_file(
+                    FileKind::PartitionCentroids,
+                    format!(
+                        "partitions/{}.{}",
+                        self.partition_centroids_id,
+                        PROTOBUF_EXTENSION,
+                    ),
+                ).await?;
                 let partition_centroids: BlockVectorSet<f32> =
                     partition_centroids.deserialize()?;
                 Ok(partition_centroids)
@@ -460,7 +1005,7 @@ mod f32impl {
     #[async_trait]
     impl<FS> LoadCodebook<f32> for Database<f32, FS>
     where
-        FS: FileSystem + Send + Sync,
+        FS: ReadFileSystem + Send + Sync,
     {
         async fn load_codebook(
             &self,
@@ -473,22 +1018,112 @@ mod f32impl {
                     self.num_divisions(),
                 )));
             }
-            let mut f = self.fs.open_hashed_file(format!(
-                "codebooks/{}.{}",
-                &self.codebook_ids[index],
-                PROTOBUF_EXTENSION,
-            )).await?;
-            let codebook: ProtosVectorSet = read_message(&mut f).await?;
-            f.verify().await?;
+            let codebook: ProtosVectorSet = self.read_file(
+                FileKind::Codebook,
+                format!(
+                    "codebooks/{}.{}",
+                    &self.codebook_ids[index],
+                    PROTOBUF_EXTENSION,
+                ),
+            ).await?;
             let codebook: BlockVectorSet<f32> = codebook.deserialize()?;
             Ok(codebook)
         }
     }
 
+    #[async_trait]
+    impl<FS> LoadResidues<f32> for Database<f32, FS>
+    where
+        FS: ReadFileSystem + Send + Sync,
+    {
+        async fn load_residues(
+            &self,
+            index: usize,
+        ) -> Result<BlockVectorSet<f32>, Error> {
+            let mut residues = self.load_persisted_vectors(index).await?;
+            if self.has_original_vectors() {
+                let centroids = self.load_partition_centroids().await?;
+                let centroid = centroids.get(index);
+                for i in 0..residues.len() {
+                    let v = residues.get_mut(i);
+                    for (x, c) in v.iter_mut().zip(centroid) {
+                        *x -= *c;
+                    }
+                }
+            }
+            Ok(residues)
+        }
+    }
+
+    #[async_trait]
+    impl<FS> LoadVectors<f32> for Database<f32, FS>
+    where
+        FS: ReadFileSystem + Send + Sync,
+    {
+        async fn load_vectors(
+            &self,
+            index: usize,
+        ) -> Result<BlockVectorSet<f32>, Error> {
+            let mut vectors = self.load_persisted_vectors(index).await?;
+            if !self.has_original_vectors() {
+                let centroids = self.load_partition_centroids().await?;
+                let centroid = centroids.get(index);
+                for i in 0..vectors.len() {
+                    f32::add_in(vectors.get_mut(i), centroid);
+                }
+            }
+            Ok(vectors)
+        }
+    }
+
+    impl<FS> Database<f32, FS>
+    where
+        FS: ReadFileSystem + Send + Sync,
+    {
+        // Loads the vectors persisted for a partition; i.e., residues or
+        // original vectors.
+        async fn load_persisted_vectors(
+            &self,
+            index: usize,
+        ) -> Result<BlockVectorSet<f32>, Error> {
+            if !self.has_residues() {
+                return Err(Error::InvalidContext(
+                    "database has no residues".to_string(),
+                ));
+            }
+            if index >= self.num_partitions() {
+                return Err(Error::InvalidArgs(format!(
+                    "partition index {} must be < {}",
+                    index,
+                    self.num_partitions(),
+                )));
+            }
+            let residues: ProtosVectorSet = self.read_file(
+                FileKind::Residues,
+                format!(
+                    "residues/{}.{}",
+                    &self.residues_ids[index],
+                    PROTOBUF_EXTENSION,
+                ),
+            ).await?;
+            let residues: BlockVectorSet<f32> = residues.deserialize()?;
+            if !residues.is_empty()
+                && residues.vector_size() != self.vector_size()
+            {
+                return Err(Error::InvalidData(format!(
+                    "vector_size is inconsistent: expected {} but got {}",
+                    self.vector_size(),
+                    residues.vector_size(),
+                )));
+            }
+            Ok(residues)
+        }
+    }
+
     #[async_trait]
     impl<'db, FS> LoadPartition<'db, f32> for Database<f32, FS>
     where
-        FS: FileSystem + Send + Sync,
+        FS: ReadFileSystem + Send + Sync,
         Self: 'db,
     {
         async fn load_partition(
@@ -504,22 +1139,27 @@ mod f32impl {
             }
             self.partitions[index].get_or_try_init(|| async move {
                 let id = &self.partition_ids[index];
-                let mut f = self.fs.open_compressed_hashed_file(format!(
-                    "partitions/{}.{}",
-                    id,
-                    PROTOBUF_EXTENSION,
-                )).await?;
-                let partition: ProtosPartition = read_message(&mut f).await?;
-                f.verify().await?;
+                let mut partition: ProtosPartition = self.read_file(
+                    FileKind::Partition,
+                    format!("partitions/{}.{}", id, PROTOBUF_EXTENSION),
+                ).await?;
+                let residues = deserialize_partition_residues(
+                    &mut partition,
+                    self.quantization(),
+                    self.vector_size(),
+                )?;
+                let squared_error_norms =
+                    deserialize_squared_error_norms(&mut partition)?;
                 let vector_size = partition.vector_size as usize;
                 let num_divisions = partition.num_divisions as usize;
-                let encoded_vectors: BlockVectorSet<u32> = partition.encoded_vectors
-                    .into_option()
-                    .ok_or(Error::InvalidData(format!(
-                        "missing encoded vectors for partition: {}",
-                        id,
-                    )))?
-                    .deserialize()?;
+                let encoded_vectors = deserialize_lazy(
+                    partition.encoded_vectors
+                        .into_option()
+                        .ok_or(Error::InvalidData(format!(
+                            "missing encoded vectors for partition: {}",
+                            id,
+                        )))?,
+                )?;
                 if vector_size != self.vector_size() {
                     return Err(Error::InvalidData(format!(
                         "inconsistent vector size: expected {} but got {}",
@@ -541,14 +1181,35 @@ mod f32impl {
                         partition.vector_ids.len(),
                     )));
                 }
-                let vector_ids: Vec<Uuid> = partition.vector_ids
+                if let Some(sizes) = self.partition_sizes() {
+                    if encoded_vectors.len() != sizes[index] {
+                        return Err(Error::InvalidData(format!(
+                            "inconsistent # of vectors in partition {}: \
+                             expected {} but got {}",
+                            index,
+                            sizes[index],
+                            encoded_vectors.len(),
+                        )));
+                    }
+                }
+                let vector_ids = partition.vector_ids
                     .into_iter()
-                    .map(|id| id.deserialize().unwrap())
-                    .collect();
+                    .enumerate()
+                    .map(|(i, id)| id.deserialize().map_err(|e| {
+                        Error::InvalidData(format!(
+                            "partition[{}, {}]: invalid vector ID: {}",
+                            index,
+                            i,
+                            e,
+                        ))
+                    }))
+                    .collect::<Result<Vec<Uuid>, Error>>()?;
                 Ok(Partition {
                     _t: std::marker::PhantomData,
                     encoded_vectors,
                     vector_ids,
+                    residues,
+                    squared_error_norms,
                 })
             }).await
         }
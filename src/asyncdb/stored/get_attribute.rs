@@ -8,9 +8,10 @@ use core::task::{Context, Poll};
 use pin_project_lite::pin_project;
 use uuid::Uuid;
 
-use crate::db::AttributeValue;
+use crate::db::{AttributeValue, ValidationMode};
 use crate::error::Error;
 
+use super::query::PartitionQueryResult;
 use super::{AttributeValueRef, Database, LoadAttributesLog};
 
 pin_project! {
@@ -26,13 +27,15 @@ pin_project! {
         partition_index: usize,
         vector_id: &'i Uuid,
         key: &'k K,
+        // Error found before the request; e.g., a stale query result.
+        error: Option<Error>,
         #[pin]
         load_attributes_log: Option<Pin<Box<
-            dyn 'db + Future<Output = Result<(), Error>>,
+            dyn 'db + Future<Output = Result<(), Error>> + Send,
         >>>,
         #[pin]
         get_attribute_internal: Option<Pin<Box<
-            dyn 'db + Future<Output = Result<Option<AttributeValueRef<'db>>, Error>>,
+            dyn 'db + Future<Output = Result<Option<AttributeValueRef<'db>>, Error>> + Send,
         >>>,
     }
 }
@@ -56,18 +59,39 @@ where
             partition_index,
             vector_id,
             key,
+            error: None,
             load_attributes_log: None,
             get_attribute_internal: None,
         }
     }
+
+    /// Creates a new asynchronous request for an attribute of the vector
+    /// corresponding to a given query result.
+    ///
+    /// The request fails with `Error::InvalidContext` if `result` is stale
+    /// against `db`.
+    pub(super) fn of_query_result(
+        db: &'db Database<T, FS>,
+        result: &'i PartitionQueryResult<T>,
+        key: &'k K,
+    ) -> Self {
+        let mut request = Self::new(
+            db,
+            result.partition_index,
+            &result.vector_id,
+            key,
+        );
+        request.error = db.verify_query_result(result).err();
+        request
+    }
 }
 
 impl<'db, 'i, 'k, T, FS, K> Future for GetAttributeInPartition<'db, 'i, 'k, T, FS, K>
 where
-    T: Send,
-    FS: Send,
+    T: Send + Sync,
+    FS: Send + Sync,
     String: Borrow<K>,
-    K: Hash + Eq + ?Sized,
+    K: Hash + Eq + Sync + ?Sized,
     Database<T, FS>: LoadAttributesLog<'db>,
     'i: 'db,
     'k: 'db,
@@ -76,6 +100,9 @@ where
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut this = self.project();
+        if let Some(err) = this.error.take() {
+            return Poll::Ready(Err(err));
+        }
         loop {
             if let Some(future) = this.get_attribute_internal
                 .as_mut()
@@ -105,6 +132,13 @@ where
                         ));
                     },
                     Poll::Pending => return Poll::Pending,
+                    // the vector has no attributes if the attributes log is
+                    // damaged and skipped
+                    Poll::Ready(Err(_))
+                        if this.db.validation_mode == ValidationMode::Lenient =>
+                    {
+                        return Poll::Ready(Ok(None));
+                    },
                     Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
                 };
             } else {
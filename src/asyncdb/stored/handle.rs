@@ -0,0 +1,206 @@
+//! Shared handle of a stored database.
+
+use core::borrow::Borrow;
+use core::hash::Hash;
+use core::num::NonZeroUsize;
+use core::ops::Deref;
+use std::sync::Arc;
+
+use crate::db::AttributeValue;
+use crate::error::Error;
+use crate::kmeans::Scalar;
+use crate::slice::AsSlice;
+
+use super::get_attribute::GetAttributeInPartition;
+use super::query::PartitionQueryResult;
+use super::{
+    Database,
+    LoadAttributesLog,
+    LoadCodebook,
+    LoadPartition,
+    LoadPartitionCentroids,
+};
+
+/// Cheaply cloneable handle of a [`Database`].
+///
+/// Clones share the same database, so partitions, codebooks, and attributes
+/// loaded through one of them are available to the others.
+/// Query results own a handle instead of borrowing the database, so they
+/// may outlive the borrow that produced them; e.g., be moved to another task.
+pub struct DatabaseHandle<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    db: Arc<Database<T, FS>>,
+}
+
+impl<T, FS> DatabaseHandle<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    /// Creates a handle of a given database.
+    pub fn new(db: Database<T, FS>) -> Self {
+        Self { db: Arc::new(db) }
+    }
+}
+
+impl<T, FS> Clone for DatabaseHandle<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    fn clone(&self) -> Self {
+        Self { db: self.db.clone() }
+    }
+}
+
+impl<T, FS> From<Database<T, FS>> for DatabaseHandle<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    fn from(db: Database<T, FS>) -> Self {
+        Self::new(db)
+    }
+}
+
+impl<T, FS> Deref for DatabaseHandle<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    type Target = Database<T, FS>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.db
+    }
+}
+
+impl<T, FS> DatabaseHandle<T, FS>
+where
+    T: Scalar + Send + Sync,
+    FS: Send + Sync,
+{
+    /// Queries k-nearest neighbors of a given vector.
+    ///
+    /// Unlike [`Database::query`], results do not borrow the database.
+    pub async fn query<'db, V>(
+        &'db self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+    ) -> Result<Vec<SharedQueryResult<T, FS>>, Error>
+    where
+        V: AsSlice<T> + Send + ?Sized,
+        Database<T, FS>:
+            LoadPartitionCentroids<'db, T>
+            + LoadCodebook<T>
+            + LoadPartition<'db, T>,
+    {
+        let results = self.db.query(v, k, nprobe).await?;
+        Ok(results
+            .into_iter()
+            .map(|result| SharedQueryResult {
+                db: self.clone(),
+                result: (*result).clone(),
+            })
+            .collect())
+    }
+}
+
+impl<T, FS> DatabaseHandle<T, FS>
+where
+    T: Send + Sync,
+    FS: Send + Sync,
+{
+    /// Returns an attribute value of the vector corresponding to a given
+    /// query result.
+    ///
+    /// Fails with `Error::InvalidContext` if `result` did not come from the
+    /// database of this handle or its clones, or if `result` is stale
+    /// against the database.
+    pub async fn get_attribute_of<'db, K>(
+        &'db self,
+        result: &'db SharedQueryResult<T, FS>,
+        key: &'db K,
+    ) -> Result<Option<AttributeValue>, Error>
+    where
+        String: Borrow<K>,
+        K: Hash + Eq + Send + Sync + ?Sized,
+        Database<T, FS>: LoadAttributesLog<'db>,
+    {
+        if !Arc::ptr_eq(&self.db, &result.db.db) {
+            return Err(Error::InvalidContext(
+                "query result from another database".to_string(),
+            ));
+        }
+        result.get_attribute(key).await
+    }
+}
+
+/// Query result that shares the database through a [`DatabaseHandle`].
+///
+/// Can be derefed as a [`PartitionQueryResult`].
+pub struct SharedQueryResult<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    db: DatabaseHandle<T, FS>,
+    result: PartitionQueryResult<T>,
+}
+
+impl<T, FS> SharedQueryResult<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    /// Returns an attribute value of the vector corresponding to the result.
+    ///
+    /// The first call of this function on a result belonging to a partition
+    /// will take longer because it will load the attributes of the partition.
+    ///
+    /// Fails with `Error::InvalidContext` if the result is stale against the
+    /// database of the handle.
+    pub async fn get_attribute<'db, K>(
+        &'db self,
+        key: &'db K,
+    ) -> Result<Option<AttributeValue>, Error>
+    where
+        T: Sync,
+        FS: Sync,
+        String: Borrow<K>,
+        K: Hash + Eq + Send + Sync + ?Sized,
+        Database<T, FS>: LoadAttributesLog<'db>,
+    {
+        GetAttributeInPartition::of_query_result(&self.db.db, &self.result, key)
+            .await
+    }
+}
+
+impl<T, FS> Clone for SharedQueryResult<T, FS>
+where
+    T: Clone + Send,
+    FS: Send,
+{
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            result: self.result.clone(),
+        }
+    }
+}
+
+impl<T, FS> Deref for SharedQueryResult<T, FS>
+where
+    T: Send,
+    FS: Send,
+{
+    type Target = PartitionQueryResult<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.result
+    }
+}
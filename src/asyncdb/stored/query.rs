@@ -7,17 +7,27 @@ use core::num::NonZeroUsize;
 use core::pin::Pin;
 use core::task::{Context, Poll};
 use pin_project_lite::pin_project;
+use std::borrow::Cow;
 use uuid::Uuid;
 
+use crate::db::{
+    AttributeValue,
+    Generation,
+    ValidationMode,
+    check_finite,
+    max_error2,
+    project_query,
+};
+use crate::db::metric::{QueryMetric, QueryScratch};
 use crate::error::Error;
 use crate::kmeans::Scalar;
-use crate::linalg::{dot, subtract};
-use crate::nbest::TakeNBestByKey;
+use crate::nbest::{NBestByKey, merge_sorted_by_key};
 use crate::slice::AsSlice;
-use crate::vector::BlockVectorSet;
+use crate::vector::{BlockVectorSet, division_range};
 
 use super::{
     Database,
+    LoadAttributesLog,
     LoadCodebook,
     LoadPartition,
     LoadPartitionCentroids,
@@ -37,20 +47,26 @@ pin_project! {
     {
         db: &'db Database<T, FS>,
         v: &'v V,
+        // Query vector projected by the database. `None` if the database
+        // has no projection or the query has not started.
+        projected: Option<Vec<T>>,
         k: usize,
         nprobe: usize,
+        metric: QueryMetric,
         event_handler: EV,
         partition_centroids: Option<&'db BlockVectorSet<T>>,
         #[pin]
         load_partition_centroids: Option<Pin<Box<
-            dyn 'db + Future<Output = Result<&'db BlockVectorSet<T>, Error>>,
+            dyn 'db + Future<Output = Result<&'db BlockVectorSet<T>, Error>> + Send,
         >>>,
         codebooks: Option<&'db Vec<BlockVectorSet<T>>>,
         #[pin]
         load_codebooks: Option<Pin<Box<
-            dyn 'db + Future<Output = Result<&'db Vec<BlockVectorSet<T>>, Error>>,
+            dyn 'db + Future<Output = Result<&'db Vec<BlockVectorSet<T>>, Error>> + Send,
         >>>,
         partition_queries: Vec<Pin<Box<PartitionQuery<'db, T>>>>,
+        // Buffers reused across partitions. Created on the first execution.
+        scratch: Option<QueryScratch<T>>,
     }
 }
 
@@ -88,6 +104,10 @@ where
     ///
     /// The first call of this function on a result belonging to a partition
     /// will take longer because it will load the attributes of the partition.
+    ///
+    /// Fails with `Error::InvalidContext` if the result is stale against the
+    /// database; i.e., the partition or its attributes log has been changed
+    /// since the query.
     pub fn get_attribute<'i, 'k, K>(
         &'i self,
         key: &'k K,
@@ -97,12 +117,47 @@ where
         K: Hash + Eq + Send + ?Sized,
         'i: 'db,
     {
-        GetAttributeInPartition::new(
-            self.db,
-            self.partition_index,
-            &self.vector_id,
-            key,
-        )
+        GetAttributeInPartition::of_query_result(self.db, &self.result, key)
+    }
+    /// Returns an attribute value of the vector corresponding to the result
+    /// and the generation of the attributes log it is read from.
+    ///
+    /// The attribute comes from a newer attributes log than the one the
+    /// query ran against if the generation differs from
+    /// [`PartitionQueryResult::generation`].
+    pub async fn get_attribute_with_generation<'i, 'k, K>(
+        &'i self,
+        key: &'k K,
+    ) -> Result<(Option<AttributeValue>, Generation), Error>
+    where
+        T: Sync,
+        FS: Sync,
+        String: Borrow<K>,
+        K: Hash + Eq + Send + Sync + ?Sized,
+        Database<T, FS>: LoadAttributesLog<'db>,
+        'i: 'db,
+        'k: 'db,
+    {
+        let value = self.get_attribute(key).await?;
+        let generation = self.db
+            .attributes_generation(self.partition_index)
+            .ok_or(Error::InvalidContext(format!(
+                "partition index out of bounds: {}",
+                self.partition_index,
+            )))?;
+        Ok((value, generation))
+    }
+}
+
+impl<'db, T, FS> QueryResult<'db, T, FS>
+where
+    T: Scalar + Send,
+    FS: Send,
+{
+    // Replaces the approximate distance with an exact one.
+    pub(crate) fn set_exact_distance(&mut self, squared_distance: T) {
+        self.result.squared_distance = squared_distance;
+        self.result.error_bound = Some(T::zero());
     }
 }
 
@@ -118,8 +173,8 @@ where
     }
 }
 
-// Partition index, localized vector, and squared distance.
-struct PartitionVector<T>(usize, Vec<T>, T);
+// Partition index and score of the partition centroid.
+struct PartitionVector<T>(usize, T);
 
 pin_project! {
     // State of a query in a partition.
@@ -127,9 +182,10 @@ pin_project! {
         vector: PartitionVector<T>,
         #[pin]
         load_partition: Pin<Box<
-            dyn 'db + Future<Output = Result<&'db Partition<T>, Error>>,
+            dyn 'db + Future<Output = Result<&'db Partition<T>, Error>> + Send,
         >>,
         partition: Option<&'db Partition<T>>,
+        generation: Generation,
         results: Option<Vec<PartitionQueryResult<T>>>,
     }
 }
@@ -144,7 +200,31 @@ pub struct PartitionQueryResult<T> {
     /// Unique ID of the vector.
     pub vector_id: Uuid,
     /// Approximate squared distance from the query vector.
+    ///
+    /// Corrected by the squared norm of the quantization error of the vector
+    /// if the partition records it.
     pub squared_distance: T,
+    /// Estimated upper bound of the error of `squared_distance`.
+    ///
+    /// Derived from the quantization error of the vector if the partition
+    /// records it, or the maximum quantization errors of the codebooks
+    /// otherwise; exact re-ranking with [`Database::query_with_reranking`]
+    /// may be worthwhile if the bound is comparable to the gaps between
+    /// results. `None` under [`QueryMetric::Cosine`], or if the database has
+    /// no quantization errors. Zero under
+    /// [`Quantization::Flat`](crate::db::Quantization::Flat), which scores
+    /// vectors exactly, or after re-ranking.
+    pub error_bound: Option<T>,
+    /// ID of the partition.
+    ///
+    /// Used to detect the result is stale against the database.
+    pub partition_id: String,
+    /// ID of the attributes log of the partition.
+    ///
+    /// Used to detect the result is stale against the database.
+    pub attributes_log_id: String,
+    /// Generation of the attributes log the query ran against.
+    pub generation: Generation,
 }
 
 /// Event notified while querying.
@@ -166,6 +246,11 @@ pub enum QueryEvent {
     StartingLoadingPartition(usize),
     /// Finished loading a single partition at a given index.
     FinishedLoadingPartition(usize),
+    /// Skipped a damaged partition at a given index because of a given
+    /// error.
+    ///
+    /// Notified only under [`ValidationMode::Lenient`].
+    SkippedPartition(usize, Error),
     /// Starting to run query on a single partition at a given index.
     StartingPartitionQueryExecution(usize),
     /// Finished running query on a single partition at a given index.
@@ -193,22 +278,34 @@ where
         Query {
             db,
             v,
+            projected: None,
             k: k.get(),
             nprobe: nprobe.get(),
+            metric: QueryMetric::SquaredL2,
             event_handler,
             partition_centroids: None,
             load_partition_centroids: None,
             codebooks: None,
             load_codebooks: None,
             partition_queries: Vec::with_capacity(nprobe.get()),
+            scratch: None,
         }
     }
+
+    /// Sets the metric to score vectors.
+    ///
+    /// [`PartitionQueryResult::squared_distance`] holds the score under
+    /// `metric`. [`QueryMetric::SquaredL2`] by default.
+    pub fn with_metric(mut self, metric: QueryMetric) -> Self {
+        self.metric = metric;
+        self
+    }
 }
 
 impl<'db, 'v, T, FS, V, EV> Future for Query<'db, 'v, T, FS, V, EV>
 where
-    T: Scalar + Send,
-    FS: Send,
+    T: Scalar + Send + Sync,
+    FS: Send + Sync,
     V: AsSlice<T> + Send + ?Sized,
     EV: FnMut(QueryEvent),
     Database<T, FS>:
@@ -227,6 +324,24 @@ where
             };
         }
 
+        // validates the query vector before loading anything
+        if this.partition_centroids.is_none()
+            && this.load_partition_centroids.is_none()
+        {
+            if let Err(e) = check_finite(this.v.as_slice()) {
+                return Poll::Ready(Err(e));
+            }
+            match project_query(this.db.projection(), this.v.as_slice()) {
+                Ok(Cow::Owned(v)) => *this.projected = Some(v),
+                Ok(Cow::Borrowed(_)) => {},
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        let v: &[T] = match this.projected {
+            Some(projected) => projected,
+            None => this.v.as_slice(),
+        };
+
         loop {
             let mut had_progress = false;
             // lazily loads partition centroids and codebooks
@@ -236,14 +351,16 @@ where
                     event!(QueryEvent::StartingPartitionSelection);
                     let selected_partitions = select_partitions(
                         partition_centroids,
-                        *this.v,
+                        v,
                         *this.nprobe,
+                        *this.metric,
+                        this.db.parallel_reduction,
                     );
                     event!(QueryEvent::FinishedPartitionSelection);
                     if selected_partitions.is_empty() {
-                        return Poll::Ready(Err(Error::InvalidContext(format!(
-                            "no partitions selected for query",
-                        ))));
+                        return Poll::Ready(Err(Error::InvalidContext(
+                            "no partitions selected for query".to_string(),
+                        )));
                     }
                     this.partition_queries.extend(
                         selected_partitions.into_iter().map(|p| {
@@ -300,8 +417,17 @@ where
             }
             // loads partitions and chooses k-NN
             if !this.partition_queries.is_empty() {
+                // sizes the buffers for the largest partition to query if
+                // known
+                let capacity = this.db.partition_sizes().map(|sizes| {
+                    this.partition_queries
+                        .iter()
+                        .map(|query| sizes[query.partition_index()])
+                        .max()
+                        .unwrap_or(0)
+                });
                 for query in this.partition_queries.iter_mut() {
-                    if query.partition.is_none() {
+                    if query.partition.is_none() && query.results.is_none() {
                         match query.as_mut().poll_loading(cx) {
                             Poll::Ready(Ok(_)) => {
                                 event!(QueryEvent::FinishedLoadingPartition(
@@ -310,6 +436,17 @@ where
                                 had_progress = true;
                             },
                             Poll::Pending => {},
+                            Poll::Ready(Err(err))
+                                if this.db.validation_mode
+                                    == ValidationMode::Lenient =>
+                            {
+                                // a damaged partition yields no results
+                                let pi = query.partition_index();
+                                *query.as_mut().project().results =
+                                    Some(Vec::new());
+                                event!(QueryEvent::SkippedPartition(pi, err));
+                                had_progress = true;
+                            },
                             Poll::Ready(Err(err)) =>
                                 return Poll::Ready(Err(err)),
                         }
@@ -318,10 +455,30 @@ where
                             event!(QueryEvent::StartingPartitionQueryExecution(
                                 query.partition_index(),
                             ));
-                            if let Err(err) = query
-                                .as_mut()
-                                .execute(codebooks)
-                            {
+                            let pi = query.partition_index();
+                            let centroid = this.partition_centroids
+                                .unwrap()
+                                .get(pi);
+                            let metric = *this.metric;
+                            let fast_scan = this.db.fast_scan;
+                            let scratch = this.scratch.get_or_insert_with(|| {
+                                match capacity {
+                                    Some(capacity) =>
+                                        QueryScratch::with_capacity(
+                                            metric,
+                                            capacity,
+                                        ),
+                                    None => QueryScratch::new(metric),
+                                }.with_fast_scan(fast_scan)
+                            });
+                            if let Err(err) = query.as_mut().execute(
+                                v,
+                                centroid,
+                                codebooks,
+                                scratch,
+                                max_error2(&this.db.quantization_errors),
+                                *this.k,
+                            ) {
                                 return Poll::Ready(Err(err));
                             }
                             event!(QueryEvent::FinishedPartitionQueryExecution(
@@ -339,10 +496,15 @@ where
                     let results = select_knn(this.partition_queries, *this.k);
                     let results: Vec<_> = results
                         .into_iter()
-                        .map(|result| QueryResult::new(
-                            *this.db,
-                            result.clone(),
-                        ))
+                        .map(|result| {
+                            let pi = result.partition_index;
+                            let mut result = result.clone();
+                            result.partition_id =
+                                this.db.partition_ids[pi].clone();
+                            result.attributes_log_id =
+                                this.db.attributes_log_ids[pi].clone();
+                            QueryResult::new(*this.db, result)
+                        })
                         .collect();
                     event!(QueryEvent::FinishedKNNSelection);
                     return Poll::Ready(Ok(results));
@@ -372,6 +534,7 @@ where
             vector,
             load_partition: db.load_partition(index),
             partition: None,
+            generation: db.attributes_generation(index).unwrap(),
             results: None,
         }
     }
@@ -380,10 +543,6 @@ where
         self.vector.0
     }
 
-    fn query_vector<'a>(&'a self) -> &'a [T] {
-        &self.vector.1
-    }
-
     fn poll_loading(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -406,106 +565,139 @@ where
 {
     // Executes the query in the partition.
     //
-    // Updates `results` field.
+    // Updates `results` field with the `k` nearest vectors in ascending
+    // order of scores under the metric of `scratch`.
     //
     // Panics if:
     // - partition is not ready
     fn execute(
         &mut self,
-        codebooks: &Vec<BlockVectorSet<T>>,
+        query_vector: &[T],
+        centroid: &[T],
+        codebooks: &[BlockVectorSet<T>],
+        scratch: &mut QueryScratch<T>,
+        max_error2: Option<T>,
+        k: usize,
     ) -> Result<(), Error> {
         let partition = self.partition.expect("partition must be loaded");
-        let distance_table = self.calculate_distance_table(codebooks)?;
-        let num_vectors = partition.num_vectors();
-        let num_divisions = partition.num_divisions();
-        let mut results: Vec<PartitionQueryResult<T>> =
-            Vec::with_capacity(num_vectors);
-        for vi in 0..num_vectors {
-            let encoded_vector = partition.get_encoded_vector(vi);
-            let mut distance = T::zero();
-            for di in 0..num_divisions {
-                let ci = encoded_vector[di] as usize;
-                distance += distance_table.get(di)[ci];
-            }
+        if partition.num_divisions() != codebooks.len() {
+            return Err(Error::InvalidData(format!(
+                "inconsistent number of divisions: {} and {}",
+                partition.num_divisions(),
+                codebooks.len(),
+            )));
+        }
+        check_score_table_inputs(query_vector, centroid, codebooks)?;
+        // scores residues exactly if the partition has them
+        let (table, scores) = match partition.residues() {
+            Some(residues) => (
+                None,
+                scratch.score_residues(query_vector, centroid, residues),
+            ),
+            None => {
+                let (table, scores) = scratch.score_partition(
+                    query_vector,
+                    centroid,
+                    codebooks,
+                    partition.encoded_vectors(),
+                    partition.squared_error_norms(),
+                );
+                (Some(table), scores)
+            },
+        };
+        let error_norms = partition.squared_error_norms();
+        // breaks ties by vector IDs so that results are deterministic
+        let mut results = NBestByKey::new(
+            k,
+            |r: &PartitionQueryResult<T>| (r.squared_distance, r.vector_id),
+        );
+        for (vi, &distance) in scores.iter().enumerate() {
             results.push(PartitionQueryResult {
                 partition_index: self.partition_index(),
                 vector_index: vi,
-                vector_id: partition.get_vector_id(vi).clone(),
+                vector_id: *partition.get_vector_id(vi),
                 squared_distance: distance,
+                error_bound: match table {
+                    Some(table) => table.vector_error_bound(
+                        distance,
+                        error_norms.map(|norms| norms[vi]),
+                        max_error2,
+                    ),
+                    None => Some(T::zero()),
+                },
+                // provenance is filled in for the selected results
+                partition_id: String::new(),
+                attributes_log_id: String::new(),
+                generation: self.generation,
             });
         }
-        self.results = Some(results);
+        self.results = Some(results.into_sorted_vec());
         Ok(())
     }
 
-    // Calculates the distance table for the partition.
-    //
-    // Fails if:
-    // - `codebooks` is empty
-    // - a codebook has no code
-    // - vector size is not (# of division) × (subvector size)
-    // - numbers of codes in codebooks are not the same
-    fn calculate_distance_table(
-        &self,
-        codebooks: &Vec<BlockVectorSet<T>>,
-    ) -> Result<BlockVectorSet<T>, Error> {
-        let num_divisions = codebooks.len();
-        if num_divisions == 0 {
-            return Err(Error::InvalidData(format!("no codebooks")));
-        }
-        let num_codes = codebooks[0].len();
-        if num_codes == 0 {
-            return Err(Error::InvalidData(format!("no code in codebook")));
-        }
-        let subvector_size = codebooks[0].vector_size();
-        let query_vector = self.query_vector();
-        if query_vector.len() != num_divisions * subvector_size {
+}
+
+// Checks if the score table for a partition can be calculated.
+//
+// Fails if:
+// - `codebooks` is empty
+// - a codebook has no code
+// - numbers of codes in codebooks are not the same
+// - a codebook does not match the size of its division given by
+//   `division_range`
+fn check_score_table_inputs<T>(
+    query_vector: &[T],
+    centroid: &[T],
+    codebooks: &[BlockVectorSet<T>],
+) -> Result<(), Error>
+where
+    T: Scalar,
+{
+    let num_divisions = codebooks.len();
+    if num_divisions == 0 {
+        return Err(Error::InvalidData("no codebooks".to_string()));
+    }
+    let num_codes = codebooks[0].len();
+    if num_codes == 0 {
+        return Err(Error::InvalidData("no code in codebook".to_string()));
+    }
+    if query_vector.len() < num_divisions {
+        return Err(Error::InvalidData(format!(
+            "vector size {} is less than the number of divisions {}",
+            query_vector.len(),
+            num_divisions,
+        )));
+    }
+    if centroid.len() != query_vector.len() {
+        return Err(Error::InvalidData(format!(
+            "inconsistent centroid size: {} and {}",
+            centroid.len(),
+            query_vector.len(),
+        )));
+    }
+    for (i, codebook) in codebooks.iter().enumerate() {
+        if codebook.len() != num_codes {
             return Err(Error::InvalidData(format!(
-                "inconsistent vector size: {} and {}",
-                query_vector.len(),
-                num_divisions * subvector_size,
+                "inconsistent number of codes: {} and {}",
+                codebook.len(),
+                num_codes,
             )));
         }
-        let mut distance_table: Vec<T> =
-            Vec::with_capacity(num_divisions * num_codes);
-        let mut vector_buf: Vec<T> = Vec::with_capacity(subvector_size);
-        unsafe {
-            vector_buf.set_len(subvector_size);
-        }
-        for di in 0..num_divisions {
-            let from = di * subvector_size;
-            let to = from + subvector_size;
-            let subv = &query_vector[from..to];
-            let codebook = &codebooks[di];
-            if codebook.len() != num_codes {
-                return Err(Error::InvalidData(format!(
-                    "inconsistent number of codes: {} and {}",
-                    codebook.len(),
-                    num_codes,
-                )));
-            }
-            if codebook.vector_size() != subvector_size {
-                return Err(Error::InvalidData(format!(
-                    "inconsistent subvector size: {} and {}",
-                    codebook.vector_size(),
-                    subvector_size,
-                )));
-            }
-            for ci in 0..num_codes {
-                let code_vector = codebook.get(ci);
-                let d = &mut vector_buf[..];
-                subtract(subv, code_vector, d);
-                distance_table.push(dot(d, d));
-            }
+        let subvector_size =
+            division_range(query_vector.len(), num_divisions, i).len();
+        if codebook.vector_size() != subvector_size {
+            return Err(Error::InvalidData(format!(
+                "inconsistent subvector size: {} and {}",
+                codebook.vector_size(),
+                subvector_size,
+            )));
         }
-        BlockVectorSet::chunk(
-            distance_table,
-            num_codes.try_into().unwrap(),
-        )
     }
+    Ok(())
 }
 
-// Selects `nprobe` partitions nearest to a given vector.
+// Selects `nprobe` partitions nearest to a given vector under a given
+// metric, splitting reductions among `num_threads` threads if given.
 //
 // Panics if:
 // - nprobe is zero.
@@ -514,6 +706,8 @@ fn select_partitions<T, V>(
     partition_centroids: &BlockVectorSet<T>,
     v: &V,
     nprobe: usize,
+    metric: QueryMetric,
+    num_threads: Option<NonZeroUsize>,
 ) -> Vec<PartitionVector<T>>
 where
     T: Scalar,
@@ -527,17 +721,15 @@ where
     let mut partition_vectors: Vec<PartitionVector<T>> =
         Vec::with_capacity(num_partitions);
     for pi in 0..num_partitions {
-        let mut localized: Vec<T> = Vec::with_capacity(vector_size);
-        unsafe {
-            localized.set_len(vector_size);
-        }
-        let centroid = partition_centroids.get(pi);
-        subtract(v, centroid, &mut localized[..]);
-        let distance = dot(&localized[..], &localized[..]);
-        partition_vectors.push(PartitionVector(pi, localized, distance));
+        let score = metric.score_with_threads(
+            v,
+            partition_centroids.get(pi),
+            num_threads,
+        );
+        partition_vectors.push(PartitionVector(pi, score));
     }
-    // chooses `nprobe` nearest vectors
-    partition_vectors.sort_by(|l, r| l.2.partial_cmp(&r.2).unwrap());
+    // chooses `nprobe` best scores
+    partition_vectors.sort_by(|l, r| l.1.partial_cmp(&r.1).unwrap());
     partition_vectors.truncate(nprobe);
     partition_vectors
 }
@@ -551,13 +743,8 @@ where
     T: PartialOrd,
 {
     assert!(k > 0);
-    let mut results: Vec<_> = queries
-        .iter()
-        .flat_map(|q| q.results.as_ref().unwrap().iter())
-        .n_best_by_key(k, |r| &r.squared_distance)
-        .into();
-    results.sort_by(
-        |l, r| l.squared_distance.partial_cmp(&r.squared_distance).unwrap(),
-    );
-    results
+    merge_sorted_by_key(
+        queries.iter().map(|q| q.results.as_ref().unwrap().iter()),
+        |r| (&r.squared_distance, &r.vector_id),
+    ).take(k).collect()
 }
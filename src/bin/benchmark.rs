@@ -17,6 +17,7 @@ use flechasdb::linalg::{
     sum,
     sum_naive,
 };
+use flechasdb::linalg::{parallel, simd};
 
 fn main() {
     benchmark_dot();
@@ -36,7 +37,18 @@ fn benchmark_dot() {
     let mut rng = rand::thread_rng();
     rng.fill(&mut xs[..]);
     rng.fill(&mut ys[..]);
+    println!("selected kernel: {:?}", simd::selected_kernel());
+    let num_threads = std::thread::available_parallelism()
+        .unwrap_or(std::num::NonZeroUsize::MIN);
     for _ in 0..R {
+        let time = std::time::Instant::now();
+        let ans = simd::dot(&xs, &ys);
+        let elapsed = time.elapsed();
+        println!("dispatched dot {} in {} μs", ans, elapsed.as_micros());
+        let time = std::time::Instant::now();
+        let ans = parallel::dot(&xs, &ys, num_threads);
+        let elapsed = time.elapsed();
+        println!("parallel dot {} in {} μs", ans, elapsed.as_micros());
         let time = std::time::Instant::now();
         let ans = dot(&xs, &ys);
         let elapsed = time.elapsed();
@@ -0,0 +1,175 @@
+//! Sweeps query parameters and reports recall versus latency.
+//!
+//! Usage:
+//!
+//! ```sh
+//! sweep <database> <queries.fvecs> <neighbors.ivecs> <k> <nprobes> [<reranks>]
+//! ```
+//!
+//! `<nprobes>` and `<reranks>` are comma-separated lists; e.g., `1,2,4,8`.
+//! A rerank factor is the `oversample` of
+//! [`query_with_reranking`](stored::Database::query_with_reranking), and `0`
+//! queries without re-ranking, which is the default. Re-ranking needs a
+//! database serialized with residues.
+//!
+//! Vectors in the database must have the `"datum_id"` attribute that holds
+//! the index of the vector in the dataset the ground truth refers to.
+//! Writes a CSV row for every pair of `nprobe` and rerank factor to the
+//! standard output.
+//!
+//! Exits with code 2 on a usage error, or if the queries or the ground
+//! truth are empty.
+
+use anyhow::{anyhow, Error};
+use core::num::NonZeroUsize;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use flechasdb::db::AttributeValue;
+use flechasdb::db::stored::{self, LoadDatabase};
+use flechasdb::io::LocalFileSystem;
+use flechasdb::testing::recall;
+use flechasdb::testing::vecs::{read_fvecs, read_ivecs};
+use flechasdb::vector::VectorSet;
+
+fn main() -> Result<(), Error> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 6 || args.len() > 7 {
+        eprintln!(
+            "usage: {} <database> <queries.fvecs> <neighbors.ivecs> <k> \
+             <nprobes> [<reranks>]",
+            args[0],
+        );
+        std::process::exit(2);
+    }
+    let queries = read_fvecs(&read_nonempty(&args[2], "query vectors")?[..])?;
+    let neighbors =
+        read_ivecs(&read_nonempty(&args[3], "neighbor lists")?[..])?;
+    let db = load_database(&args[1])?;
+    let k: NonZeroUsize = args[4].parse()?;
+    let nprobes: Vec<NonZeroUsize> = parse_list(&args[5])?;
+    let reranks: Vec<usize> = match args.get(6) {
+        Some(s) => parse_list(s)?,
+        None => vec![0],
+    };
+    if queries.len() != neighbors.len() {
+        return Err(anyhow!(
+            "{} queries but {} neighbor lists",
+            queries.len(),
+            neighbors.len(),
+        ));
+    }
+    if queries.vector_size() != db.vector_size() {
+        return Err(anyhow!(
+            "query size {} does not match vector size {}",
+            queries.vector_size(),
+            db.vector_size(),
+        ));
+    }
+    // warms up so that loading partitions and residues does not count as
+    // latency
+    let max_nprobe = *nprobes.iter().max().unwrap();
+    let max_rerank = *reranks.iter().max().unwrap();
+    for (_, qv) in queries.iter() {
+        match NonZeroUsize::new(max_rerank) {
+            Some(oversample) => {
+                db.query_with_reranking(qv, k, max_nprobe, oversample)?;
+            },
+            None => {
+                db.query(qv, k, max_nprobe)?;
+            },
+        }
+    }
+    println!("nprobe,rerank,k,recall,mean_us,p50_us,p99_us");
+    for &nprobe in nprobes.iter() {
+        for &rerank in reranks.iter() {
+            let mut latencies: Vec<u128> = Vec::with_capacity(queries.len());
+            let mut total_recall = 0.0f32;
+            for (i, qv) in queries.iter() {
+                let time = std::time::Instant::now();
+                let results = match NonZeroUsize::new(rerank) {
+                    Some(oversample) => {
+                        db.query_with_reranking(qv, k, nprobe, oversample)?
+                    },
+                    None => db.query(qv, k, nprobe)?,
+                };
+                latencies.push(time.elapsed().as_micros());
+                let found = results
+                    .iter()
+                    .map(|result| {
+                        datum_index(
+                            result.get_attribute("datum_id")?.as_deref(),
+                        )
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let truth = &neighbors[i];
+                total_recall +=
+                    recall(&found, &truth[..k.get().min(truth.len())]);
+            }
+            latencies.sort_unstable();
+            let n = latencies.len();
+            println!(
+                "{},{},{},{:.4},{},{},{}",
+                nprobe,
+                rerank,
+                k,
+                total_recall / n as f32,
+                latencies.iter().sum::<u128>() / n as u128,
+                latencies[n / 2],
+                latencies[(n * 99 / 100).min(n - 1)],
+            );
+        }
+    }
+    Ok(())
+}
+
+// Reads the contents of an input file.
+//
+// Exits with the same code as a usage error if the file is empty, because
+// neither recall nor latency is defined without queries.
+fn read_nonempty(path: &str, what: &str) -> Result<Vec<u8>, Error> {
+    let mut contents: Vec<u8> = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    if contents.is_empty() {
+        eprintln!("no {} in {}", what, path);
+        std::process::exit(2);
+    }
+    Ok(contents)
+}
+
+// Parses a comma-separated list of values.
+fn parse_list<V>(s: &str) -> Result<Vec<V>, Error>
+where
+    V: core::str::FromStr,
+    V::Err: std::error::Error + Send + Sync + 'static,
+{
+    let values = s
+        .split(',')
+        .map(|value| value.trim().parse::<V>())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(values)
+}
+
+// Interprets the "datum_id" attribute as an index in the dataset.
+fn datum_index(value: Option<&AttributeValue>) -> Result<usize, Error> {
+    match value {
+        Some(AttributeValue::Uint64(n)) => Ok(*n as usize),
+        Some(AttributeValue::String(s)) => Ok(s.parse()?),
+        None => Err(anyhow!("vector without datum_id")),
+    }
+}
+
+fn load_database<P>(
+    path: P,
+) -> Result<stored::Database<f32, LocalFileSystem>, Error>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let db = stored::Database::<f32, _>::load_database(
+        LocalFileSystem::new(path.parent().unwrap()),
+        path.file_name().unwrap().to_str().unwrap(),
+    )?;
+    Ok(db)
+}
@@ -4,7 +4,12 @@ use anyhow::Error;
 use rand::Rng;
 
 use flechasdb::asyncdb::io::LocalFileSystem;
-use flechasdb::asyncdb::stored::{Database, LoadDatabase, QueryEvent};
+use flechasdb::asyncdb::stored::{
+    Database,
+    DatabaseHandle,
+    LoadDatabase,
+    QueryEvent,
+};
 use flechasdb::linalg::{norm2, scale_in};
 
 #[tokio::main]
@@ -73,6 +78,13 @@ async fn main() -> Result<(), Error> {
                     i,
                     event_time.elapsed().as_micros(),
                 ),
+            QueryEvent::SkippedPartition(i, e) =>
+                println!(
+                    "skipped partition {} at {} μs: {}",
+                    i,
+                    event_time.elapsed().as_micros(),
+                    e,
+                ),
             QueryEvent::StartingPartitionQueryExecution(i) =>
                 println!(
                     "starting partition query execution {} at {} μs",
@@ -119,6 +131,38 @@ async fn main() -> Result<(), Error> {
     }
     println!("iterated results in {:?} μs", time.elapsed().as_micros());
 
+    // runs concurrent queries on tasks sharing the database
+    const NUM_TASKS: usize = 4;
+    let db = DatabaseHandle::from(db);
+    let time = std::time::Instant::now();
+    let tasks: Vec<_> = (0..NUM_TASKS)
+        .map(|_| {
+            let db = db.clone();
+            let qv = random_query_vector(&mut rng, db.vector_size());
+            tokio::spawn(async move {
+                let results = db.query(
+                    &qv[..],
+                    K.try_into().unwrap(),
+                    NP.try_into().unwrap(),
+                ).await?;
+                let datum_id = match results.first() {
+                    Some(result) =>
+                        db.get_attribute_of(result, "datum_id").await?,
+                    None => None,
+                };
+                Ok::<_, flechasdb::error::Error>(datum_id)
+            })
+        })
+        .collect();
+    for (i, task) in tasks.into_iter().enumerate() {
+        println!("[task {}]: nearest datum_id: {:?}", i, task.await??);
+    }
+    println!(
+        "queried database on {} tasks in {:?} μs",
+        NUM_TASKS,
+        time.elapsed().as_micros(),
+    );
+
     Ok(())
 }
 
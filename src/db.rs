@@ -4,10 +4,28 @@
 //!
 //! Use `stored` submodule to load a stored database.
 
+use core::num::NonZeroUsize;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::error::Error;
+use crate::io::codec::Codec;
+use crate::kmeans::Scalar;
+use crate::linalg::{parallel, subtract};
+use crate::projection::Projection;
+use crate::vector::{BlockVectorSet, VectorSet};
+
+pub mod backup;
+pub mod binary;
 pub mod build;
+pub mod encoder;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod gc;
+pub mod lsh;
+pub mod manifest;
+pub mod metric;
 pub mod proto;
 pub mod stored;
 
@@ -18,7 +36,7 @@ pub type Attributes = HashMap<String, AttributeValue>;
 pub type AttributeTable = HashMap<Uuid, Attributes>;
 
 /// Attribute value.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum AttributeValue {
     /// String value.
     String(String),
@@ -44,6 +62,635 @@ impl From<u64> for AttributeValue {
     }
 }
 
+/// Generation of an attributes log.
+///
+/// Changes whenever the contents of an attributes log change, so comparing
+/// the generation recorded in a query result with the generation of an
+/// attribute read tells whether the attribute comes from a newer log than
+/// the one the query ran against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Generation(u64);
+
+impl Generation {
+    /// Returns the generation of an attributes log with a given ID.
+    ///
+    /// An attributes log ID is a digest of the contents, so is the
+    /// generation.
+    pub fn of_attributes_log(id: &str) -> Self {
+        // FNV-1a
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for b in id.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        Self(hash)
+    }
+}
+
+/// Partition a vector is assigned to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartitionAssignment<T> {
+    /// Index of the nearest partition.
+    pub partition_index: usize,
+    /// Residual vector; i.e., the vector minus the partition centroid.
+    pub residual: Vec<T>,
+    /// Squared distance between the vector and the partition centroid.
+    pub squared_distance: T,
+}
+
+/// Kind of a file that makes up a database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    /// Database header.
+    Database,
+    /// Partition.
+    Partition,
+    /// Partition centroids.
+    PartitionCentroids,
+    /// Codebook.
+    Codebook,
+    /// Attributes log of a partition.
+    AttributesLog,
+    /// Residue vectors of a partition.
+    Residues,
+}
+
+/// Policy of which kinds of files are compressed.
+///
+/// The database header is always compressed. The header records the codec
+/// of every file as well as the policy so that loaders know which files to
+/// decompress. The codec of each compressed file is detected from its
+/// contents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    partitions: bool,
+    partition_centroids: bool,
+    codebooks: bool,
+    attributes_logs: bool,
+    residues: bool,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            partitions: true,
+            partition_centroids: false,
+            codebooks: false,
+            attributes_logs: true,
+            residues: true,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    /// Creates the default policy.
+    ///
+    /// Compresses partitions, attributes logs, and residues, but not
+    /// partition centroids and codebooks, which hardly shrink.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy that compresses every kind of files.
+    pub fn all() -> Self {
+        Self {
+            partitions: true,
+            partition_centroids: true,
+            codebooks: true,
+            attributes_logs: true,
+            residues: true,
+        }
+    }
+
+    /// Creates a policy that compresses no files but the header.
+    pub fn none() -> Self {
+        Self {
+            partitions: false,
+            partition_centroids: false,
+            codebooks: false,
+            attributes_logs: false,
+            residues: false,
+        }
+    }
+
+    /// Sets whether a given kind of files is compressed.
+    ///
+    /// Ignored for [`FileKind::Database`].
+    pub fn with_compressed(mut self, kind: FileKind, compressed: bool) -> Self {
+        match kind {
+            FileKind::Database => {},
+            FileKind::Partition => self.partitions = compressed,
+            FileKind::PartitionCentroids => {
+                self.partition_centroids = compressed;
+            },
+            FileKind::Codebook => self.codebooks = compressed,
+            FileKind::AttributesLog => self.attributes_logs = compressed,
+            FileKind::Residues => self.residues = compressed,
+        }
+        self
+    }
+
+    /// Returns if a given kind of files is compressed.
+    pub fn is_compressed(&self, kind: FileKind) -> bool {
+        match kind {
+            FileKind::Database => true,
+            FileKind::Partition => self.partitions,
+            FileKind::PartitionCentroids => self.partition_centroids,
+            FileKind::Codebook => self.codebooks,
+            FileKind::AttributesLog => self.attributes_logs,
+            FileKind::Residues => self.residues,
+        }
+    }
+}
+
+// Compression of the files a stored database refers to.
+//
+// Follows the codec recorded for each file in the database header, and the
+// policy for a file without one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FileCompression {
+    policy: CompressionPolicy,
+    // Codecs of files keyed by their paths. `None` if uncompressed.
+    codecs: HashMap<String, Option<Codec>>,
+}
+
+impl FileCompression {
+    pub(crate) fn new(
+        policy: CompressionPolicy,
+        codecs: HashMap<String, Option<Codec>>,
+    ) -> Self {
+        Self { policy, codecs }
+    }
+
+    // Returns the policy recorded in the database header.
+    pub(crate) fn policy(&self) -> CompressionPolicy {
+        self.policy
+    }
+
+    // Returns if a file of a given kind at a given path is compressed.
+    pub(crate) fn is_compressed(&self, kind: FileKind, path: &str) -> bool {
+        match self.codecs.get(path) {
+            Some(codec) => codec.is_some(),
+            None => self.policy.is_compressed(kind),
+        }
+    }
+}
+
+/// How a stored database deals with damaged files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ValidationMode {
+    /// Fails on any damaged file.
+    #[default]
+    Strict,
+    /// Skips damaged partitions and attributes logs.
+    ///
+    /// A query ignores a partition that fails to load, and vectors in a
+    /// partition whose attributes log fails to load have no attributes.
+    /// Files are skipped only while querying or reading attributes; e.g.,
+    /// [`stored::Database::get_partition`] and updates of attributes still
+    /// fail.
+    Lenient,
+}
+
+/// Options for opening a stored database.
+///
+/// Applies to both [`stored::Database`] and the asynchronous database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenOptions {
+    cache_budget: Option<usize>,
+    eager: bool,
+    verification: bool,
+    validation_mode: ValidationMode,
+    max_concurrent_loads: Option<NonZeroUsize>,
+    max_message_size: Option<usize>,
+    fast_scan: bool,
+    parallel_reduction: Option<NonZeroUsize>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            cache_budget: None,
+            eager: false,
+            verification: true,
+            validation_mode: ValidationMode::Strict,
+            max_concurrent_loads: None,
+            max_message_size: None,
+            fast_scan: false,
+            parallel_reduction: None,
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Creates the default options.
+    ///
+    /// Files are loaded lazily and verified, loaded partitions are kept
+    /// without limit, and files may be loaded concurrently without limit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of bytes of partitions kept in memory.
+    ///
+    /// The partitions loaded earliest are evicted to make room for a new
+    /// one, and loaded again when they are needed. The asynchronous database
+    /// ignores the budget because it lends partitions out for its lifetime.
+    pub fn with_cache_budget(mut self, bytes: usize) -> Self {
+        self.cache_budget = Some(bytes);
+        self
+    }
+
+    /// Sets whether the partition centroids and codebooks are loaded when
+    /// the database is opened rather than on the first query.
+    pub fn with_eager_loading(mut self, eager: bool) -> Self {
+        self.eager = eager;
+        self
+    }
+
+    /// Sets whether the hashes of files are verified while they are loaded.
+    ///
+    /// Disabling verification suits trusted storage; e.g., a private bucket.
+    /// Files are then opened with `open_hashed_file_unverified` of the file
+    /// system, which saves hashing their contents if the file system
+    /// supports it.
+    /// The database header is always verified.
+    pub fn with_verification(mut self, verification: bool) -> Self {
+        self.verification = verification;
+        self
+    }
+
+    /// Sets how damaged files are dealt with.
+    ///
+    /// [`ValidationMode::Lenient`] keeps the rest of the database queryable
+    /// if a partition or an attributes log is corrupted or missing. The
+    /// database header, partition centroids and codebooks are always
+    /// required.
+    pub fn with_validation_mode(mut self, mode: ValidationMode) -> Self {
+        self.validation_mode = mode;
+        self
+    }
+
+    /// Sets the maximum number of files loaded at the same time.
+    ///
+    /// Only the asynchronous database loads files concurrently.
+    pub fn with_max_concurrent_loads(mut self, n: NonZeroUsize) -> Self {
+        self.max_concurrent_loads = Some(n);
+        self
+    }
+
+    /// Sets the maximum number of bytes of a message in a file.
+    ///
+    /// Loading a file fails with `Error::InvalidData` as soon as more than
+    /// `bytes` bytes of its message, after decompression, are read. It
+    /// protects from corrupted or malicious files that would make a loader
+    /// allocate a huge amount of memory. Set it large enough for the largest
+    /// partition.
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = Some(bytes);
+        self
+    }
+
+    /// Sets whether partitions are scanned with the fast-scan kernel.
+    ///
+    /// Fast scan quantizes the lookup table of a partition into bytes and
+    /// scores 32 vectors at a time with vector shuffle instructions, which
+    /// makes scanning partitions several times faster. It applies to
+    /// partitions of codes that fit in 4 bits; i.e., databases with at most
+    /// 16 codes, under [`metric::QueryMetric::SquaredL2`] and
+    /// [`metric::QueryMetric::NegativeDot`]. Other partitions are scanned as
+    /// usual.
+    ///
+    /// Scores become less accurate by at most a quantization step per
+    /// division, which the error bounds of query results include.
+    /// Re-ranking recovers exact distances.
+    pub fn with_fast_scan(mut self, fast_scan: bool) -> Self {
+        self.fast_scan = fast_scan;
+        self
+    }
+
+    /// Splits reductions over long vectors among a given number of threads.
+    ///
+    /// Applies to scoring partition centroids and to the exact distances of
+    /// re-ranking, when vectors have at least
+    /// [`linalg::parallel::MIN_PARALLEL_LEN`](crate::linalg::parallel::MIN_PARALLEL_LEN)
+    /// elements; e.g., high-dimensional embeddings. Threads are spawned for
+    /// every reduction, so it pays off only if the vectors are long enough
+    /// and idle cores are available. Scores may differ from those without
+    /// the option in the last bits, but do not depend on `num_threads`.
+    ///
+    /// Disabled by default.
+    pub fn with_parallel_reduction(
+        mut self,
+        num_threads: NonZeroUsize,
+    ) -> Self {
+        self.parallel_reduction = Some(num_threads);
+        self
+    }
+
+    /// Returns the maximum number of bytes of partitions kept in memory.
+    ///
+    /// `None` if unlimited.
+    pub fn cache_budget(&self) -> Option<usize> {
+        self.cache_budget
+    }
+
+    /// Returns if the partition centroids and codebooks are loaded eagerly.
+    pub fn is_eager(&self) -> bool {
+        self.eager
+    }
+
+    /// Returns if the hashes of files are verified.
+    pub fn is_verification_enabled(&self) -> bool {
+        self.verification
+    }
+
+    /// Returns how damaged files are dealt with.
+    pub fn validation_mode(&self) -> ValidationMode {
+        self.validation_mode
+    }
+
+    /// Returns the maximum number of files loaded at the same time.
+    ///
+    /// `None` if unlimited.
+    pub fn max_concurrent_loads(&self) -> Option<NonZeroUsize> {
+        self.max_concurrent_loads
+    }
+
+    /// Returns the maximum number of bytes of a message in a file.
+    ///
+    /// `None` if unlimited.
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Returns if partitions are scanned with the fast-scan kernel.
+    pub fn is_fast_scan_enabled(&self) -> bool {
+        self.fast_scan
+    }
+
+    /// Returns the number of threads reductions over long vectors are split
+    /// among.
+    ///
+    /// `None` if disabled.
+    pub fn parallel_reduction(&self) -> Option<NonZeroUsize> {
+        self.parallel_reduction
+    }
+}
+
+/// Quantization of residues in a database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Quantization {
+    /// Product quantization (PQ).
+    ///
+    /// Subvectors are encoded into the nearest code vectors of codebooks
+    /// trained by k-means clustering.
+    #[default]
+    Product,
+    /// Scalar quantization into 8 bits (SQ8).
+    ///
+    /// Every element is a subvector division of its own, and its codebook
+    /// has 256 values evenly spaced between the minimum and maximum of the
+    /// element. More accurate than PQ for small vectors at the cost of a
+    /// byte per element.
+    Scalar8,
+    /// No quantization.
+    ///
+    /// Partitions hold residues in full precision, and queries score them
+    /// exactly; i.e., recall is 100% within the partitions queried. The
+    /// database has a single subvector division whose codebook has a single
+    /// zero vector.
+    ///
+    /// A single partition makes a flat index, which suits small collections
+    /// where exact search is cheap enough. More partitions make an IVF-Flat
+    /// index, which prunes partitions by `nprobe` and trades disk space for
+    /// exact distances within the partitions.
+    Flat,
+}
+
+/// Statistics of the quantization errors of a codebook.
+///
+/// Errors are squared Euclidean distances between subvectors and the code
+/// vectors they are encoded into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuantizationError<T> {
+    /// Mean squared error.
+    pub mean: T,
+    /// Maximum squared error.
+    pub max: T,
+}
+
+/// Statistics of the numbers of vectors in partitions.
+///
+/// Available without loading any partition if the database records vector
+/// counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PartitionStats {
+    /// Total number of vectors.
+    pub num_vectors: usize,
+    /// Number of vectors in the smallest partition.
+    pub min: usize,
+    /// Number of vectors in the largest partition.
+    pub max: usize,
+    /// Mean number of vectors in a partition.
+    pub mean: f64,
+    /// Imbalance factor of partitions.
+    ///
+    /// (# of partitions) × Σ (partition size)² / (# of vectors)², which is
+    /// 1 if all the partitions have the same size, and grows as vectors
+    /// concentrate in fewer partitions. Proportional to the expected number
+    /// of vectors scanned by a query. 1 if there is no vector.
+    pub imbalance: f64,
+}
+
+impl PartitionStats {
+    // Calculates the statistics of given partition sizes.
+    //
+    // `None` if `sizes` is empty.
+    pub(crate) fn from_sizes(sizes: &[usize]) -> Option<Self> {
+        let min = *sizes.iter().min()?;
+        let max = *sizes.iter().max()?;
+        let num_vectors: usize = sizes.iter().sum();
+        let num_partitions = sizes.len() as f64;
+        let imbalance = if num_vectors == 0 {
+            1.0
+        } else {
+            let squares: f64 = sizes.iter().map(|&n| (n as f64).powi(2)).sum();
+            num_partitions * squares / (num_vectors as f64).powi(2)
+        };
+        Some(Self {
+            num_vectors,
+            min,
+            max,
+            mean: num_vectors as f64 / num_partitions,
+            imbalance,
+        })
+    }
+}
+
+// Sums the maximum squared quantization errors of all the codebooks.
+//
+// `None` if `errors` is empty.
+pub(crate) fn max_error2<T>(errors: &[QuantizationError<T>]) -> Option<T>
+where
+    T: Scalar,
+{
+    if errors.is_empty() {
+        return None;
+    }
+    let mut sum = T::zero();
+    for error in errors {
+        sum += error.max;
+    }
+    Some(sum)
+}
+
+// Estimates the heap memory occupied by an attribute table.
+pub(crate) fn attribute_table_bytes(table: &AttributeTable) -> usize {
+    let entries = table.capacity()
+        * (core::mem::size_of::<(Uuid, Attributes)>() + 1);
+    let attributes: usize = table
+        .values()
+        .map(|attributes| {
+            let entries = attributes.capacity()
+                * (core::mem::size_of::<(String, AttributeValue)>() + 1);
+            let strings: usize = attributes
+                .iter()
+                .map(|(key, value)| key.capacity() + match value {
+                    AttributeValue::String(s) => s.capacity(),
+                    AttributeValue::Uint64(_) => 0,
+                })
+                .sum();
+            entries + strings
+        })
+        .sum();
+    entries + attributes
+}
+
+// Estimates the heap memory occupied by strings.
+pub(crate) fn strings_bytes(strings: &[String]) -> usize {
+    core::mem::size_of_val(strings)
+        + strings.iter().map(|s| s.capacity()).sum::<usize>()
+}
+
+// Fails if a given vector has an infinite or NaN element.
+//
+// Such elements poison centroids and break sorting by distance.
+pub(crate) fn check_finite<T>(v: &[T]) -> Result<(), Error>
+where
+    T: Scalar,
+{
+    match v.iter().position(|x| !x.is_finite()) {
+        Some(i) => Err(Error::InvalidArgs(format!(
+            "non-finite element at {}: {:?}",
+            i,
+            v[i],
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Projects a query vector if a database has a projection.
+//
+// Fails if the vector size does not match the input size of the projection.
+pub(crate) fn project_query<'a, T>(
+    projection: Option<&Projection<T>>,
+    v: &'a [T],
+) -> Result<Cow<'a, [T]>, Error>
+where
+    T: Scalar,
+{
+    match projection {
+        Some(projection) => Ok(Cow::Owned(projection.project(v)?)),
+        None => Ok(Cow::Borrowed(v)),
+    }
+}
+
+// Calculates the exact squared distance between a query vector and a vector
+// persisted for re-ranking.
+//
+// Splits the reduction among `num_threads` threads if given; see
+// `OpenOptions::with_parallel_reduction`.
+//
+// Fails if `vector_index` is out of the bounds of `vectors`; i.e., the
+// persisted vectors are inconsistent with the partition.
+pub(crate) fn exact_squared_distance<T>(
+    query: &[T],
+    vectors: &BlockVectorSet<T>,
+    vector_index: usize,
+    num_threads: Option<NonZeroUsize>,
+) -> Result<T, Error>
+where
+    T: Scalar,
+{
+    if vector_index >= vectors.len() {
+        return Err(Error::InvalidData(format!(
+            "vector index {} exceeds the number of persisted vectors {}",
+            vector_index,
+            vectors.len(),
+        )));
+    }
+    let v = vectors.get(vector_index);
+    Ok(match num_threads {
+        Some(num_threads) => parallel::squared_distance(query, v, num_threads),
+        None => T::squared_distance(query, v),
+    })
+}
+
+// Returns the number of candidates to re-rank.
+//
+// Fails if `k` × `oversample` overflows.
+pub(crate) fn num_rerank_candidates(
+    k: NonZeroUsize,
+    oversample: NonZeroUsize,
+) -> Result<NonZeroUsize, Error> {
+    k.checked_mul(oversample).ok_or(Error::InvalidArgs(format!(
+        "k {} times oversample {} overflows",
+        k,
+        oversample,
+    )))
+}
+
+// Assigns a given vector to the nearest partition centroid.
+//
+// Ties are broken by the smaller partition index.
+//
+// Fails if the vector size does not match, the vector has a non-finite
+// element, or there is no centroid.
+pub(crate) fn assign_partition<T>(
+    partition_centroids: &BlockVectorSet<T>,
+    v: &[T],
+) -> Result<PartitionAssignment<T>, Error>
+where
+    T: Scalar,
+{
+    if v.len() != partition_centroids.vector_size() {
+        return Err(Error::InvalidArgs(format!(
+            "vector size mismatch: expected {}, got {}",
+            partition_centroids.vector_size(),
+            v.len(),
+        )));
+    }
+    check_finite(v)?;
+    let mut nearest: Option<(usize, T)> = None;
+    for (pi, centroid) in partition_centroids.iter() {
+        let distance = T::squared_distance(v, centroid);
+        if nearest.is_none_or(|(_, d)| distance < d) {
+            nearest = Some((pi, distance));
+        }
+    }
+    let (partition_index, squared_distance) = nearest.ok_or(
+        Error::InvalidContext("no partition centroids".to_string()),
+    )?;
+    let mut residual: Vec<T> = vec![T::zero(); v.len()];
+    subtract(v, partition_centroids.get(partition_index), &mut residual[..]);
+    Ok(PartitionAssignment {
+        partition_index,
+        residual,
+        squared_distance,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,6 +707,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn partition_stats_should_measure_imbalance() {
+        let stats = PartitionStats::from_sizes(&[2, 2, 2, 2]).unwrap();
+        assert_eq!(stats.num_vectors, 8);
+        assert_eq!((stats.min, stats.max), (2, 2));
+        assert_eq!(stats.mean, 2.0);
+        assert_eq!(stats.imbalance, 1.0);
+        let stats = PartitionStats::from_sizes(&[0, 0, 0, 8]).unwrap();
+        assert_eq!((stats.min, stats.max), (0, 8));
+        assert_eq!(stats.imbalance, 4.0);
+        assert_eq!(PartitionStats::from_sizes(&[0, 0]).unwrap().imbalance, 1.0);
+        assert!(PartitionStats::from_sizes(&[]).is_none());
+    }
+
     #[test]
     fn attribute_value_can_be_made_from_str_ref() {
         assert_eq!(AttributeValue::String("attr".to_string()), "attr".into());
@@ -74,4 +735,60 @@ mod tests {
             0xFFFF_FFFF_FFFF_FFFFu64.into(),
         );
     }
+
+    #[test]
+    fn generation_should_differ_for_different_attributes_logs() {
+        assert_eq!(
+            Generation::of_attributes_log("abc"),
+            Generation::of_attributes_log("abc"),
+        );
+        assert_ne!(
+            Generation::of_attributes_log("abc"),
+            Generation::of_attributes_log("abd"),
+        );
+    }
+
+    #[test]
+    fn assign_partition_should_choose_nearest_centroid() {
+        let centroids = BlockVectorSet::chunk(
+            vec![0.0f32, 0.0, 10.0, 0.0, 0.0, 10.0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let assignment = assign_partition(&centroids, &[9.0, 1.0]).unwrap();
+        assert_eq!(assignment.partition_index, 1);
+        assert_eq!(assignment.residual, vec![-1.0, 1.0]);
+        assert_eq!(assignment.squared_distance, 2.0);
+    }
+
+    #[test]
+    fn assign_partition_should_prefer_smaller_index_on_tie() {
+        let centroids = BlockVectorSet::chunk(
+            vec![1.0f32, 0.0, -1.0, 0.0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let assignment = assign_partition(&centroids, &[0.0, 1.0]).unwrap();
+        assert_eq!(assignment.partition_index, 0);
+    }
+
+    #[test]
+    fn assign_partition_should_fail_for_vector_size_mismatch() {
+        let centroids = BlockVectorSet::chunk(
+            vec![1.0f32, 0.0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        assert!(assign_partition(&centroids, &[0.0, 1.0, 2.0]).is_err());
+    }
+
+    #[test]
+    fn check_finite_should_reject_nan_and_infinity() {
+        assert!(check_finite(&[0.0f32, 1.0, -1.0]).is_ok());
+        assert!(matches!(
+            check_finite(&[0.0f32, f32::NAN]),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            check_finite(&[f64::NEG_INFINITY]),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
 }
@@ -0,0 +1,317 @@
+//! Copying of databases between file systems.
+//!
+//! Files of a database are named after their contents, so a file that
+//! already exists at a destination never has to be copied again.
+//! [`copy_database`] therefore makes an incremental backup of a database;
+//! e.g., from a local directory to an object store. [`sync_database`]
+//! mirrors every database in a file system; e.g., to read replicas in other
+//! regions.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+
+use crate::error::Error;
+use crate::io::hash::HashAlgorithm;
+use crate::io::{
+    FileSystem,
+    HashedFileIn,
+    HashedFileOut,
+    ReadFileSystem,
+    WriteFileSystem,
+};
+use crate::protos::database::Database as ProtosDatabase;
+use crate::protos::read_message;
+
+use super::manifest::CURRENT;
+use super::proto::{deserialize_hash_algorithm, referenced_file_paths};
+use super::stored::PROTOBUF_EXTENSION;
+
+/// Report of [`copy_database`] and [`sync_database`].
+#[derive(Debug, Default)]
+pub struct CopyReport {
+    /// Number of files copied including the database header.
+    pub num_copied: usize,
+    /// Number of files that already existed at the destination.
+    pub num_skipped: usize,
+    /// Number of bytes copied.
+    pub num_bytes_copied: u64,
+}
+
+/// Copies a database from a file system to another.
+///
+/// Copies the database header at `path` and every file it refers to,
+/// skipping files that already exist in `dst_fs`. Every copied file is
+/// verified with its hash. The database header is copied last, so `dst_fs`
+/// never has a header that refers to a missing file. Publish the copy with
+/// [`publish_database`](crate::db::manifest::publish_database) if needed.
+///
+/// `dst_fs` has to support [`ReadFileSystem::list_files`] to tell existing
+/// files.
+pub fn copy_database<SrcFS, DstFS, P>(
+    src_fs: &SrcFS,
+    dst_fs: &DstFS,
+    path: P,
+) -> Result<CopyReport, Error>
+where
+    SrcFS: ReadFileSystem,
+    DstFS: FileSystem,
+    P: AsRef<str>,
+{
+    let existing: HashSet<String> =
+        dst_fs.list_files("")?.into_iter().collect();
+    let path = path.as_ref();
+    let files = read_referenced_files(src_fs, path)?;
+    let mut report = CopyReport::default();
+    copy_missing_files(src_fs, dst_fs, files, &existing, &mut report)?;
+    // the database header is always hashed with SHA-256
+    copy_missing_files(
+        src_fs,
+        dst_fs,
+        [(path.to_string(), HashAlgorithm::Sha256)],
+        &existing,
+        &mut report,
+    )?;
+    Ok(report)
+}
+
+/// Mirrors every database in a file system to another.
+///
+/// Lists files in both file systems, and copies the database headers at
+/// the root of `src_fs` and the files they refer to unless they exist in
+/// `dst_fs`. Headers are copied after all the other files, and
+/// [`CURRENT`] is updated at last if it differs, so readers of `dst_fs`
+/// never see a partially copied database. Files no header refers to are
+/// not copied.
+///
+/// Both file systems have to support [`ReadFileSystem::list_files`], and
+/// pointers if `src_fs` has [`CURRENT`].
+pub fn sync_database<SrcFS, DstFS>(
+    src_fs: &SrcFS,
+    dst_fs: &DstFS,
+) -> Result<CopyReport, Error>
+where
+    SrcFS: ReadFileSystem,
+    DstFS: FileSystem,
+{
+    let existing: HashSet<String> =
+        dst_fs.list_files("")?.into_iter().collect();
+    let header_extension = format!(".{}", PROTOBUF_EXTENSION);
+    let mut headers: Vec<String> = src_fs
+        .list_files("")?
+        .into_iter()
+        .filter(|path| {
+            !path.contains('/')
+                && !path.starts_with('.')
+                && path.ends_with(&header_extension)
+        })
+        .collect();
+    headers.sort();
+    let mut files: BTreeMap<String, HashAlgorithm> = BTreeMap::new();
+    for header in headers.iter() {
+        files.extend(read_referenced_files(src_fs, header)?);
+    }
+    let mut report = CopyReport::default();
+    copy_missing_files(src_fs, dst_fs, files, &existing, &mut report)?;
+    copy_missing_files(
+        src_fs,
+        dst_fs,
+        headers.into_iter().map(|path| (path, HashAlgorithm::Sha256)),
+        &existing,
+        &mut report,
+    )?;
+    if let Some(current) = src_fs.read_pointer(CURRENT)? {
+        if dst_fs.read_pointer(CURRENT)?.as_ref() != Some(&current) {
+            dst_fs.write_pointer(CURRENT, current)?;
+        }
+    }
+    Ok(report)
+}
+
+// Reads a database header, and returns the paths of the files it refers to
+// paired with their hash algorithm.
+fn read_referenced_files<FS>(
+    fs: &FS,
+    path: &str,
+) -> Result<Vec<(String, HashAlgorithm)>, Error>
+where
+    FS: ReadFileSystem,
+{
+    let mut f = fs.open_compressed_hashed_file(path)?;
+    let db: ProtosDatabase = read_message(&mut f)?;
+    f.verify()?;
+    let hash_algorithm = deserialize_hash_algorithm(&db)?;
+    Ok(referenced_file_paths(&db)
+        .into_iter()
+        .map(|path| (path, hash_algorithm))
+        .collect())
+}
+
+// Copies files unless they are in `existing`, and records them in a report.
+fn copy_missing_files<SrcFS, DstFS, I>(
+    src_fs: &SrcFS,
+    dst_fs: &DstFS,
+    files: I,
+    existing: &HashSet<String>,
+    report: &mut CopyReport,
+) -> Result<(), Error>
+where
+    SrcFS: ReadFileSystem,
+    DstFS: WriteFileSystem,
+    I: IntoIterator<Item = (String, HashAlgorithm)>,
+{
+    for (path, algorithm) in files {
+        if existing.contains(&path) {
+            report.num_skipped += 1;
+        } else {
+            report.num_bytes_copied +=
+                copy_file(src_fs, dst_fs, &path, algorithm)?;
+            report.num_copied += 1;
+        }
+    }
+    Ok(())
+}
+
+// Copies a hashed file from a file system to another.
+//
+// The file is persisted only after its contents have been verified, and
+// keeps the name; i.e., the hash with `algorithm`.
+//
+// Returns the number of bytes copied.
+fn copy_file<SrcFS, DstFS>(
+    src_fs: &SrcFS,
+    dst_fs: &DstFS,
+    path: &str,
+    algorithm: HashAlgorithm,
+) -> Result<u64, Error>
+where
+    SrcFS: ReadFileSystem,
+    DstFS: WriteFileSystem,
+{
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let (stem, extension) = name.split_once('.').ok_or(
+        Error::InvalidData(format!("file without extension: {}", path)),
+    )?;
+    let mut f = src_fs.open_hashed_file_with(path, algorithm)?;
+    let mut out = dst_fs.create_hashed_file_in_with(dir, algorithm)?;
+    let size = std::io::copy(&mut f, &mut out)?;
+    f.verify()?;
+    out.flush()?;
+    let hash = out.persist(extension)?;
+    if hash != stem {
+        return Err(Error::VerificationFailure(format!(
+            "Expected hash {}, but got {}",
+            stem,
+            hash,
+        )));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::DatabaseBuilder;
+    use crate::db::build::proto::{
+        SerializeOptions,
+        serialize_database,
+        serialize_database_with_options,
+    };
+    use crate::db::stored::{Database, LoadDatabase};
+    use crate::io::LocalFileSystem;
+    use crate::testing::SyntheticDatasetBuilder;
+
+    #[test]
+    fn copy_database_should_copy_only_missing_files() {
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let src = tempfile::tempdir().unwrap();
+        let src_fs = LocalFileSystem::new(src.path());
+        serialize_database(&db, &mut LocalFileSystem::new(src.path()))
+            .unwrap();
+        let header = src_fs.list_files("")
+            .unwrap()
+            .into_iter()
+            .find(|path| !path.contains('/'))
+            .unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let dst_fs = LocalFileSystem::new(dst.path());
+
+        let report = copy_database(&src_fs, &dst_fs, &header).unwrap();
+        assert_eq!(report.num_copied, 8);
+        assert_eq!(report.num_skipped, 0);
+        assert!(report.num_bytes_copied > 0);
+        let mut src_files = src_fs.list_files("").unwrap();
+        src_files.sort();
+        let mut dst_files = dst_fs.list_files("").unwrap();
+        dst_files.sort();
+        assert_eq!(dst_files, src_files);
+
+        let report = copy_database(&src_fs, &dst_fs, &header).unwrap();
+        assert_eq!(report.num_copied, 0);
+        assert_eq!(report.num_skipped, 8);
+        let stored = Database::<f32, _>::load_database(dst_fs, &header)
+            .unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn sync_database_should_mirror_databases_and_current_version() {
+        let src = tempfile::tempdir().unwrap();
+        let mut src_fs = LocalFileSystem::new(src.path());
+        for (seed, publishing) in [(1, false), (2, true)] {
+            let dataset = SyntheticDatasetBuilder::new(
+                100.try_into().unwrap(),
+                4.try_into().unwrap(),
+            )
+                .with_seed(seed)
+                .build()
+                .unwrap();
+            let db = DatabaseBuilder::new(dataset.vectors)
+                .with_partitions(2.try_into().unwrap())
+                .with_divisions(2.try_into().unwrap())
+                .with_clusters(4.try_into().unwrap())
+                .build()
+                .unwrap();
+            serialize_database_with_options(
+                &db,
+                &mut src_fs,
+                &SerializeOptions::new().with_publishing(publishing),
+            ).unwrap();
+        }
+        // garbage is not mirrored
+        std::fs::write(src.path().join("partitions/garbage.binpb"), b"")
+            .unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let dst_fs = LocalFileSystem::new(dst.path());
+
+        let report = sync_database(&src_fs, &dst_fs).unwrap();
+        assert_eq!(report.num_copied, 16);
+        assert_eq!(report.num_skipped, 0);
+        assert_eq!(
+            dst_fs.read_pointer(CURRENT).unwrap(),
+            src_fs.read_pointer(CURRENT).unwrap(),
+        );
+        assert!(!dst.path().join("partitions/garbage.binpb").exists());
+        Database::<f32, _>::load_current_database(dst_fs).unwrap();
+
+        let report =
+            sync_database(&src_fs, &LocalFileSystem::new(dst.path()))
+                .unwrap();
+        assert_eq!(report.num_copied, 0);
+        assert_eq!(report.num_skipped, 16);
+    }
+}
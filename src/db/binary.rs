@@ -0,0 +1,428 @@
+//! Database of binary vectors.
+//!
+//! [`BinaryDatabaseBuilder`] partitions binary vectors; e.g., sign-quantized
+//! embeddings, by k-majority clustering, which is k-means under the Hamming
+//! distance with centroids at the bitwise majorities of their members.
+//! [`BinaryDatabase`] scans the binary codes in the partitions nearest to a
+//! query exactly. Binary codes are compact enough not to be quantized
+//! further.
+//!
+//! Binary databases live in memory; they cannot be serialized yet.
+
+use core::num::NonZeroUsize;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::linalg::hamming_distance;
+use crate::nbest::NBestByKey;
+use crate::vector::bits::{BitVectorSet, WORD_BITS, pack_bits};
+
+// Maximum number of iterations of k-majority clustering.
+const MAX_ITERATIONS: usize = 100;
+
+/// Builder of a [`BinaryDatabase`].
+pub struct BinaryDatabaseBuilder {
+    vs: BitVectorSet,
+    num_partitions: usize,
+    vector_ids: Option<Vec<Uuid>>,
+}
+
+impl BinaryDatabaseBuilder {
+    /// Initializes a builder for a given binary vector set.
+    pub fn new(vs: BitVectorSet) -> Self {
+        Self {
+            vs,
+            num_partitions: 10,
+            vector_ids: None,
+        }
+    }
+
+    /// Sets the number of partitions.
+    pub fn with_partitions(mut self, num_partitions: NonZeroUsize) -> Self {
+        self.num_partitions = num_partitions.get();
+        self
+    }
+
+    /// Sets the IDs of the vectors.
+    ///
+    /// The i-th ID is assigned to the i-th vector in the input vector set.
+    /// Random IDs are assigned by default.
+    pub fn with_vector_ids(mut self, vector_ids: Vec<Uuid>) -> Self {
+        self.vector_ids = Some(vector_ids);
+        self
+    }
+
+    /// Builds the database.
+    ///
+    /// Fails if:
+    /// - the input vector set has fewer vectors than partitions
+    /// - the number of vector IDs does not match the number of vectors
+    /// - vector IDs are not unique
+    pub fn build(self) -> Result<BinaryDatabase, Error> {
+        let n = self.vs.len();
+        let k = self.num_partitions;
+        if n < k {
+            return Err(Error::InvalidArgs(format!(
+                "vs has fewer vectors than partitions: {} < {}",
+                n,
+                k,
+            )));
+        }
+        let vector_ids = match self.vector_ids {
+            Some(vector_ids) => {
+                if vector_ids.len() != n {
+                    return Err(Error::InvalidArgs(format!(
+                        "number of vector IDs {} does not match {}",
+                        vector_ids.len(),
+                        n,
+                    )));
+                }
+                let mut unique = HashSet::with_capacity(n);
+                let duplicate =
+                    vector_ids.iter().find(|&id| !unique.insert(id));
+                if let Some(id) = duplicate {
+                    return Err(Error::InvalidArgs(
+                        format!("duplicate vector ID: {}", id),
+                    ));
+                }
+                vector_ids
+            },
+            None => (0..n).map(|_| Uuid::new_v4()).collect(),
+        };
+        let num_bits = self.vs.num_bits().try_into().unwrap();
+        let (centroids, indices) = cluster_bits(&self.vs, k);
+        let mut partitions: Vec<BinaryPartition> = (0..k)
+            .map(|_| BinaryPartition {
+                vector_ids: Vec::new(),
+                vectors: BitVectorSet::new(num_bits),
+            })
+            .collect();
+        for (i, &pi) in indices.iter().enumerate() {
+            let partition = &mut partitions[pi];
+            partition.vector_ids.push(vector_ids[i]);
+            partition.vectors.push(self.vs.get(i))?;
+        }
+        Ok(BinaryDatabase {
+            centroids,
+            partitions,
+        })
+    }
+}
+
+/// Database of binary vectors in memory.
+pub struct BinaryDatabase {
+    centroids: BitVectorSet,
+    partitions: Vec<BinaryPartition>,
+}
+
+impl BinaryDatabase {
+    /// Returns the number of bits in a vector.
+    pub fn num_bits(&self) -> usize {
+        self.centroids.num_bits()
+    }
+
+    /// Returns the number of partitions.
+    pub fn num_partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Returns the number of vectors.
+    pub fn num_vectors(&self) -> usize {
+        self.partitions.iter().map(|p| p.num_vectors()).sum()
+    }
+
+    /// Returns the centroids of the partitions.
+    pub fn partition_centroids(&self) -> &BitVectorSet {
+        &self.centroids
+    }
+
+    /// Returns a partition.
+    ///
+    /// `None` if `index` ≥ `num_partitions`.
+    pub fn get_partition(&self, index: usize) -> Option<&BinaryPartition> {
+        self.partitions.get(index)
+    }
+
+    /// Returns an iterator over all the vector IDs.
+    pub fn vector_ids(&self) -> impl Iterator<Item = &Uuid> {
+        self.partitions.iter().flat_map(|p| p.vector_ids.iter())
+    }
+
+    /// Returns the number of bytes allocated for the vectors and IDs.
+    pub fn memory_bytes(&self) -> usize {
+        core::mem::size_of_val(self)
+            + self.centroids.memory_bytes()
+            + self.partitions.iter().map(|p| p.memory_bytes()).sum::<usize>()
+    }
+
+    /// Queries k-nearest neighbors (k-NN) of a given vector of packed words
+    /// under the Hamming distance.
+    ///
+    /// Scans every vector in the `nprobe` partitions whose centroids are
+    /// nearest to `v`. Ties are broken by the smaller vector ID.
+    ///
+    /// Fails if `v` does not fit in the vector set (see
+    /// [`BitVectorSet::check_vector`]), or `nprobe` exceeds the number of
+    /// partitions.
+    pub fn query(
+        &self,
+        v: &[u64],
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+    ) -> Result<Vec<BinaryQueryResult>, Error> {
+        self.centroids.check_vector(v)?;
+        let nprobe = nprobe.get();
+        if nprobe > self.num_partitions() {
+            return Err(Error::InvalidArgs(format!(
+                "nprobe {} exceeds the number of partitions {}",
+                nprobe,
+                self.num_partitions(),
+            )));
+        }
+        let mut probed: Vec<(usize, u32)> = (0..self.num_partitions())
+            .map(|pi| (pi, hamming_distance(v, self.centroids.get(pi))))
+            .collect();
+        probed.sort_by_key(|&(_, distance)| distance);
+        probed.truncate(nprobe);
+        let mut results = NBestByKey::new(
+            k.get(),
+            |result: &BinaryQueryResult| (result.distance, result.vector_id),
+        );
+        for (pi, _) in probed {
+            let partition = &self.partitions[pi];
+            for (vi, id) in partition.vector_ids.iter().enumerate() {
+                results.push(BinaryQueryResult {
+                    partition_index: pi,
+                    vector_id: *id,
+                    vector_index: vi,
+                    distance: hamming_distance(v, partition.vectors.get(vi)),
+                });
+            }
+        }
+        Ok(results.into_sorted_vec())
+    }
+}
+
+/// Partition of a [`BinaryDatabase`].
+pub struct BinaryPartition {
+    vector_ids: Vec<Uuid>,
+    vectors: BitVectorSet,
+}
+
+impl BinaryPartition {
+    /// Returns the number of vectors.
+    pub fn num_vectors(&self) -> usize {
+        self.vector_ids.len()
+    }
+
+    /// Returns the vector IDs.
+    pub fn vector_ids(&self) -> &[Uuid] {
+        &self.vector_ids
+    }
+
+    /// Returns the binary vectors.
+    ///
+    /// The i-th vector has the i-th ID in [`BinaryPartition::vector_ids`].
+    pub fn vectors(&self) -> &BitVectorSet {
+        &self.vectors
+    }
+
+    // Returns the number of bytes allocated for the vectors and IDs.
+    fn memory_bytes(&self) -> usize {
+        core::mem::size_of_val(self)
+            + self.vector_ids.capacity() * core::mem::size_of::<Uuid>()
+            + self.vectors.memory_bytes()
+    }
+}
+
+/// Result of [`BinaryDatabase::query`].
+#[derive(Clone, Debug)]
+pub struct BinaryQueryResult {
+    /// Partition index.
+    pub partition_index: usize,
+    /// Vector ID.
+    pub vector_id: Uuid,
+    /// Vector index. Local index in the partition.
+    pub vector_index: usize,
+    /// Hamming distance; i.e., the number of different bits.
+    pub distance: u32,
+}
+
+// Clusters binary vectors by k-majority.
+//
+// Centroids are initialized with `k` vectors chosen at random. Ties are
+// broken by the smaller centroid index, and a bit of a centroid is set only
+// if more than half of its members have it. Empty clusters keep their
+// centroids.
+//
+// Returns the centroids and the cluster index of every vector.
+fn cluster_bits(vs: &BitVectorSet, k: usize) -> (BitVectorSet, Vec<usize>) {
+    let n = vs.len();
+    let num_bits = vs.num_bits();
+    let mut centroids = BitVectorSet::new(num_bits.try_into().unwrap());
+    let mut chosen =
+        rand::seq::index::sample(&mut rand::thread_rng(), n, k).into_vec();
+    chosen.sort_unstable();
+    for i in chosen {
+        centroids.push(vs.get(i)).unwrap();
+    }
+    let mut indices: Vec<usize> = vec![usize::MAX; n];
+    let mut counts: Vec<usize> = vec![0; k * num_bits];
+    let mut occupancy: Vec<usize> = vec![0; k];
+    for _ in 0..MAX_ITERATIONS {
+        // assigns vectors and counts the set bits of the members
+        let mut changed = false;
+        counts.fill(0);
+        occupancy.fill(0);
+        for (i, index) in indices.iter_mut().enumerate() {
+            let v = vs.get(i);
+            let mut nearest: Option<(usize, u32)> = None;
+            for ci in 0..k {
+                let distance = hamming_distance(v, centroids.get(ci));
+                if nearest.is_none_or(|(_, d)| distance < d) {
+                    nearest = Some((ci, distance));
+                }
+            }
+            let (ci, _) = nearest.unwrap();
+            if *index != ci {
+                *index = ci;
+                changed = true;
+            }
+            occupancy[ci] += 1;
+            let counts = &mut counts[ci * num_bits..(ci + 1) * num_bits];
+            for (wi, &word) in v.iter().enumerate() {
+                let mut word = word;
+                while word != 0 {
+                    let j = wi * WORD_BITS + word.trailing_zeros() as usize;
+                    counts[j] += 1;
+                    word &= word - 1;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+        // updates centroids to the majorities
+        let mut words: Vec<u64> = Vec::with_capacity(k * centroids.num_words());
+        for ci in 0..k {
+            if occupancy[ci] == 0 {
+                words.extend_from_slice(centroids.get(ci));
+            } else {
+                let counts = &counts[ci * num_bits..(ci + 1) * num_bits];
+                let bits: Vec<bool> = counts
+                    .iter()
+                    .map(|&count| 2 * count > occupancy[ci])
+                    .collect();
+                words.extend(pack_bits(&bits));
+            }
+        }
+        centroids = BitVectorSet::from_words(
+            words,
+            num_bits.try_into().unwrap(),
+        ).unwrap();
+    }
+    (centroids, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Makes `num_vectors` vectors of 100 bits around `num_clusters` random
+    // centers by flipping a few bits of them.
+    fn clustered_bits(num_clusters: usize, num_vectors: usize) -> BitVectorSet {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let centers: Vec<Vec<bool>> = (0..num_clusters)
+            .map(|_| (0..100).map(|_| rng.gen_bool(0.5)).collect())
+            .collect();
+        let mut vs = BitVectorSet::new(100.try_into().unwrap());
+        for i in 0..num_vectors {
+            let mut bits = centers[i % num_clusters].clone();
+            for _ in 0..3 {
+                let j = rng.gen_range(0..100);
+                bits[j] = !bits[j];
+            }
+            vs.push(&pack_bits(&bits)).unwrap();
+        }
+        vs
+    }
+
+    #[test]
+    fn binary_database_should_find_exact_nearest_neighbors() {
+        let vs = clustered_bits(4, 200);
+        let vector_ids: Vec<Uuid> = (0..200).map(|_| Uuid::new_v4()).collect();
+        let db = BinaryDatabaseBuilder::new(vs.clone())
+            .with_partitions(4.try_into().unwrap())
+            .with_vector_ids(vector_ids.clone())
+            .build()
+            .unwrap();
+        assert_eq!(db.num_bits(), 100);
+        assert_eq!(db.num_partitions(), 4);
+        assert_eq!(db.num_vectors(), 200);
+        let query = vs.get(7);
+        // probing all the partitions scans every vector
+        let results = db
+            .query(query, 10.try_into().unwrap(), 4.try_into().unwrap())
+            .unwrap();
+        let mut expected: Vec<(u32, Uuid)> = (0..vs.len())
+            .map(|i| (hamming_distance(query, vs.get(i)), vector_ids[i]))
+            .collect();
+        expected.sort();
+        expected.truncate(10);
+        let actual: Vec<(u32, Uuid)> = results
+            .iter()
+            .map(|result| (result.distance, result.vector_id))
+            .collect();
+        assert_eq!(actual, expected);
+        for result in results.iter() {
+            let partition = db.get_partition(result.partition_index).unwrap();
+            assert_eq!(
+                partition.vector_ids()[result.vector_index],
+                result.vector_id,
+            );
+        }
+        // the nearest partition holds the cluster of the query
+        let results = db
+            .query(query, 10.try_into().unwrap(), 1.try_into().unwrap())
+            .unwrap();
+        assert_eq!(results[0].vector_id, vector_ids[7]);
+        assert_eq!(results[0].distance, 0);
+    }
+
+    #[test]
+    fn binary_database_should_reject_invalid_inputs() {
+        let vs = clustered_bits(2, 10);
+        assert!(matches!(
+            BinaryDatabaseBuilder::new(vs.clone())
+                .with_partitions(11.try_into().unwrap())
+                .build(),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            BinaryDatabaseBuilder::new(vs.clone())
+                .with_partitions(2.try_into().unwrap())
+                .with_vector_ids(vec![Uuid::new_v4(); 10])
+                .build(),
+            Err(Error::InvalidArgs(_)),
+        ));
+        let db = BinaryDatabaseBuilder::new(vs)
+            .with_partitions(2.try_into().unwrap())
+            .build()
+            .unwrap();
+        let k = 1.try_into().unwrap();
+        assert!(matches!(
+            db.query(&[0], k, 1.try_into().unwrap()),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            db.query(&[0, 0], k, 3.try_into().unwrap()),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            db.query(&[0, 1 << 36], k, 1.try_into().unwrap()),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+}
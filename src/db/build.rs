@@ -4,19 +4,60 @@ use core::borrow::Borrow;
 use core::hash::Hash;
 use core::iter::{IntoIterator, Iterator};
 use core::num::NonZeroUsize;
-use std::collections::HashMap;
+use core::ops::ControlFlow;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::{Entry as HashMapEntry};
+use std::sync::mpsc;
 use uuid::Uuid;
 
+use crate::distance::{Distance, SquaredL2};
 use crate::error::Error;
-use crate::kmeans::{ClusterEvent, Codebook, Scalar, cluster_with_events};
-use crate::linalg::{dot, subtract_in};
+use crate::kmeans::{
+    ClusterEvent,
+    ClusterMetrics,
+    Codebook,
+    EventControl,
+    OperationEvent,
+    Scalar,
+    cluster_anisotropic_with_events,
+    cluster_sequential_with_distance_and_events,
+    cluster_with_events,
+    event,
+    quantize_scalars,
+};
+use crate::linalg::norm2;
 use crate::partitions::{Partitioning, Partitions};
+use crate::projection::{Projection, random_rotation, train_pca};
 use crate::slice::AsSlice;
-use crate::vector::{BlockVectorSet, VectorSet, divide_vector_set};
+use crate::vector::{
+    BlockVectorSet,
+    EncodedVectorSet,
+    VectorSet,
+    VectorSetRemove,
+    divide_vector_set,
+    division_range,
+    find_duplicates,
+};
 
-use super::{Attributes, AttributeValue};
+use super::{
+    Attributes,
+    AttributeValue,
+    PartitionAssignment,
+    Quantization,
+    assign_partition,
+    attribute_table_bytes,
+    QuantizationError,
+    check_finite,
+    max_error2,
+    project_query,
+};
+use super::encoder::PqEncoder;
+use super::metric::{QueryMetric, ScoreTable, score_residue};
 
+#[cfg(feature = "import")]
+pub mod import;
+pub mod ingest;
 pub mod proto;
 
 /// Vector database builder.
@@ -29,25 +70,71 @@ where
     vs: VS,
     // Number of partitions.
     num_partitions: usize,
+    // Distance to partition the vectors under.
+    distance: Box<dyn Distance<T> + Send + Sync>,
     // Number of subvector divisions.
     num_divisions: usize,
     // Number of clusters for product quantization (PQ).
     num_clusters: usize,
+    // Whether to reject vectors that have non-finite elements.
+    validates_input: bool,
+    // Tolerance for duplicate detection. No detection if `None`.
+    duplicate_tolerance: Option<T>,
+    // Sample size for k-means in sequential passes. In-memory k-means if
+    // `None`.
+    sequential_sample_size: Option<NonZeroUsize>,
+    // Weight of the parallel error for anisotropic quantization. Isotropic
+    // if `None`.
+    anisotropic_eta: Option<T>,
+    // Maximum number of threads to train codebooks.
+    num_threads: usize,
+    // IDs of the vectors. Random IDs are assigned if `None`.
+    vector_ids: Option<Vec<Uuid>>,
+    // Output size of principal component analysis. No PCA if `None`.
+    pca_output_size: Option<NonZeroUsize>,
+    // Seed of a random rotation. No rotation if `None`.
+    rotation_seed: Option<u64>,
+    // Function to project the input vectors. Set along with PCA or a random
+    // rotation.
+    project_vectors: Option<ProjectVectors<T, VS>>,
+    quantization: Quantization,
 }
 
+// Number of levels of scalar quantization into 8 bits.
+const NUM_SCALAR8_LEVELS: usize = 256;
+
+// Function that projects all the vectors in a vector set.
+type ProjectVectors<T, VS> = fn(&Projection<T>, &VS) -> Result<VS, Error>;
+
 impl<T, VS> DatabaseBuilder<T, VS>
 where
     T: Scalar,
     VS: VectorSet<T> + Partitioning<T, VS>,
 {
     /// Initializes a builder for a given vector set.
+    ///
+    /// The builder partitions `vs` in place. To build a database from more
+    /// vectors than fit in memory, pass a
+    /// [`SpillVectorSet`](crate::vector::spill::SpillVectorSet).
     pub fn new(vs: VS) -> Self {
         Self {
             _t: core::marker::PhantomData,
             vs,
             num_partitions: 10,
+            distance: Box::new(SquaredL2),
             num_divisions: 8,
             num_clusters: 16,
+            validates_input: true,
+            duplicate_tolerance: None,
+            sequential_sample_size: None,
+            anisotropic_eta: None,
+            num_threads: std::thread::available_parallelism()
+                .map_or(1, |n| n.get()),
+            vector_ids: None,
+            pca_output_size: None,
+            rotation_seed: None,
+            project_vectors: None,
+            quantization: Quantization::Product,
         }
     }
 
@@ -57,65 +144,342 @@ where
         self
     }
 
+    /// Sets the distance to partition the vectors under.
+    ///
+    /// Partition centroids are trained by k-means clustering under
+    /// `distance`; e.g., [`Cosine`](crate::distance::Cosine) makes them unit
+    /// vectors, and [`L1`](crate::distance::L1) makes them coordinate-wise
+    /// medians. Codebooks of residues are still trained under the squared
+    /// Euclidean distance. Query with a [`QueryMetric`] that agrees with
+    /// `distance`, so that queries select partitions as they were built.
+    /// Vectors encoded later; e.g., by [`PqEncoder`], still go to the
+    /// nearest partition in the Euclidean distance.
+    ///
+    /// Building fails if
+    /// [`DatabaseBuilder::with_sequential_clustering`] is also set and
+    /// `distance` needs median centroids, because sequential passes
+    /// calculate centroids only from sums.
+    ///
+    /// [`SquaredL2`] by default.
+    pub fn with_distance<D>(mut self, distance: D) -> Self
+    where
+        D: Distance<T> + Send + Sync + 'static,
+    {
+        self.distance = Box::new(distance);
+        self
+    }
+
     /// Sets the number of subvector divisions.
+    ///
+    /// The vector size does not have to be a multiple of `num_divisions`;
+    /// the first `vector_size % num_divisions` divisions have one more
+    /// element than the others. See [`division_range`]. Building fails if
+    /// `num_divisions` exceeds the vector size.
     pub fn with_divisions(mut self, num_divisions: NonZeroUsize) -> Self {
         self.num_divisions = num_divisions.get();
         self
     }
 
     /// Sets the number of clusters for product quantization (PQ).
+    ///
+    /// Codes of at most 16 clusters are packed two per byte when the
+    /// database is serialized, which halves the size of partitions.
     pub fn with_clusters(mut self, num_clusters: NonZeroUsize) -> Self {
         self.num_clusters = num_clusters.get();
         self
     }
 
+    /// Sets whether to reject input vectors that have infinite or NaN
+    /// elements.
+    ///
+    /// Enabled by default. Disabling it saves a scan of the input vector
+    /// set, but non-finite elements will corrupt the database.
+    pub fn with_input_validation(mut self, validates: bool) -> Self {
+        self.validates_input = validates;
+        self
+    }
+
+    /// Enables detection of duplicate vectors.
+    ///
+    /// Vectors within the Euclidean distance `tolerance` of each other are
+    /// reported in [`Database::duplicate_groups`]; zero `tolerance` detects
+    /// exact duplicates only. Use [`Database::collapse_duplicates`] to keep
+    /// only one vector of each group.
+    ///
+    /// Disabled by default.
+    pub fn with_duplicate_detection(mut self, tolerance: T) -> Self {
+        self.duplicate_tolerance = Some(tolerance);
+        self
+    }
+
+    /// Makes k-means clustering read the vectors in sequential passes.
+    ///
+    /// Use this when the vector set does not fit in memory; e.g., a
+    /// [`SpillVectorSet`](crate::vector::spill::SpillVectorSet). Both
+    /// partitioning and quantization initialize centroids from a random
+    /// sample of `sample_size` vectors. See
+    /// [`cluster_sequential_with_distance_and_events`] for details.
+    ///
+    /// Disabled by default.
+    pub fn with_sequential_clustering(
+        mut self,
+        sample_size: NonZeroUsize,
+    ) -> Self {
+        self.sequential_sample_size = Some(sample_size);
+        self
+    }
+
+    /// Trains codebooks under the score-aware anisotropic loss.
+    ///
+    /// Errors of a quantized residue parallel to the input vector are
+    /// weighted by `eta` relative to orthogonal errors. `eta` greater than
+    /// one improves the recall of inner-product queries on normalized
+    /// vectors; e.g., [`QueryMetric::NegativeDot`](super::metric::QueryMetric).
+    /// See [`cluster_anisotropic_with_events`] for details.
+    ///
+    /// Takes precedence over
+    /// [`DatabaseBuilder::with_sequential_clustering`] in quantization.
+    /// Disabled by default.
+    pub fn with_anisotropic_quantization(mut self, eta: T) -> Self {
+        self.anisotropic_eta = Some(eta);
+        self
+    }
+
+    /// Sets the maximum number of threads to train codebooks.
+    ///
+    /// Subvector divisions are independent of each other, so their
+    /// codebooks are trained concurrently. Events of a division are still
+    /// notified after those of the preceding divisions.
+    ///
+    /// The available parallelism by default.
+    pub fn with_threads(mut self, num_threads: NonZeroUsize) -> Self {
+        self.num_threads = num_threads.get();
+        self
+    }
+
+    /// Sets the quantization of residues.
+    ///
+    /// [`Quantization::Scalar8`] ignores the numbers of subvector divisions
+    /// and clusters, and makes every element a division of 256 levels.
+    /// [`Quantization::Flat`] also ignores them, and stores residues as
+    /// they are in every partition; build a single partition for exhaustive
+    /// search. Neither sequential clustering nor anisotropic quantization
+    /// applies to them.
+    /// [`Quantization::Product`] by default.
+    pub fn with_quantization(mut self, quantization: Quantization) -> Self {
+        self.quantization = quantization;
+        self
+    }
+
+    /// Sets the IDs of the vectors.
+    ///
+    /// The i-th ID is assigned to the i-th vector in the input vector set.
+    /// Random IDs are assigned by default.
+    pub fn with_vector_ids(mut self, vector_ids: Vec<Uuid>) -> Self {
+        self.vector_ids = Some(vector_ids);
+        self
+    }
+
     /// Builds the vector database.
-    pub fn build(self) -> Result<Database<T, VS>, Error> {
+    ///
+    /// Fails with [`Error::InvalidArgs`] if:
+    /// - input validation is enabled and a vector has an infinite or NaN
+    ///   element
+    /// - vector IDs are given but their number does not match the number of
+    ///   vectors, or they have duplicates
+    pub fn build(self) -> Result<Database<T, VS>, Error>
+    where
+        T: Send + Sync,
+        VS: Sync,
+    {
         self.build_with_events(|_| {})
     }
 
     /// Builds the vector database with an event handler.
-    pub fn build_with_events<EventHandler>(
+    ///
+    /// `event` may cancel the build by returning
+    /// [`ControlFlow::Break`](core::ops::ControlFlow::Break).
+    pub fn build_with_events<EventHandler, C>(
         self,
+        event: EventHandler,
+    ) -> Result<Database<T, VS>, Error>
+    where
+        T: Send + Sync,
+        VS: Sync,
+        EventHandler: FnMut(BuildEvent<'_, T>) -> C,
+        C: EventControl,
+    {
+        self.build_with_stages(event, |_| Ok(()))
+    }
+
+    // Builds the vector database notifying `stage` of intermediate
+    // products as soon as they are ready.
+    //
+    // `stage` runs on the calling thread while codebooks are being trained
+    // on other threads. The build fails if `stage` fails.
+    pub(crate) fn build_with_stages<EventHandler, C, Stage>(
+        mut self,
         mut event: EventHandler,
+        mut stage: Stage,
     ) -> Result<Database<T, VS>, Error>
     where
-        EventHandler: FnMut(BuildEvent<'_, T>) -> (),
+        T: Send + Sync,
+        VS: Sync,
+        EventHandler: FnMut(BuildEvent<'_, T>) -> C,
+        C: EventControl,
+        Stage: FnMut(BuildStage<'_, T, VS>) -> Result<(), Error>,
     {
+        if self.validates_input {
+            for (i, v) in self.vs.iter() {
+                check_finite(v.as_slice()).map_err(|e| Error::InvalidArgs(
+                    format!("vector {}: {}", i, e),
+                ))?;
+            }
+        }
         // assigns IDs to vectors
-        event(BuildEvent::StartingIdAssignment);
-        let mut vector_ids: Vec<Uuid> = Vec::with_capacity(self.vs.len());
-        for _ in 0..self.vs.len() {
-            vector_ids.push(Uuid::new_v4());
+        event!(event, BuildEvent::StartingIdAssignment);
+        let vector_ids: Vec<Uuid> = match self.vector_ids.take() {
+            Some(vector_ids) => {
+                if vector_ids.len() != self.vs.len() {
+                    return Err(Error::InvalidArgs(format!(
+                        "{} vector IDs for {} vectors",
+                        vector_ids.len(),
+                        self.vs.len(),
+                    )));
+                }
+                let mut unique = HashSet::with_capacity(vector_ids.len());
+                let duplicate =
+                    vector_ids.iter().find(|&id| !unique.insert(id));
+                if let Some(id) = duplicate {
+                    return Err(Error::InvalidArgs(
+                        format!("duplicate vector ID: {}", id),
+                    ));
+                }
+                vector_ids
+            },
+            None => (0..self.vs.len()).map(|_| Uuid::new_v4()).collect(),
+        };
+        event!(event, BuildEvent::FinishedIdAssignment);
+        // detects duplicates
+        let mut duplicate_groups: Vec<Vec<Uuid>> = Vec::new();
+        if let Some(tolerance) = self.duplicate_tolerance {
+            event!(event, BuildEvent::StartingDuplicateDetection);
+            duplicate_groups = find_duplicates(&self.vs, tolerance)
+                .into_iter()
+                .map(|g| g.into_iter().map(|i| vector_ids[i]).collect())
+                .collect();
+            event!(event, BuildEvent::FinishedDuplicateDetection);
+        }
+        // projects vectors
+        let mut projection: Option<Projection<T>> = None;
+        if let Some(project_vectors) = self.project_vectors {
+            event!(event, BuildEvent::StartingProjection);
+            if let Some(output_size) = self.pca_output_size {
+                projection = Some(train_pca(&self.vs, output_size)?);
+            }
+            if let Some(seed) = self.rotation_seed {
+                let size = projection
+                    .as_ref()
+                    .map_or(self.vs.vector_size(), |p| p.output_size());
+                let rotation = random_rotation(size.try_into().unwrap(), seed);
+                projection = Some(match projection {
+                    Some(pca) => pca.then(&rotation)?,
+                    None => rotation,
+                });
+            }
+            if let Some(projection) = projection.as_ref() {
+                self.vs = project_vectors(projection, &self.vs)?;
+            }
+            event!(event, BuildEvent::FinishedProjection);
         }
-        event(BuildEvent::FinishedIdAssignment);
         // partitions all the data
-        event(BuildEvent::StartingPartitioning);
-        let partitions = self.vs.partition_with_events(
-            self.num_partitions.try_into().unwrap(),
-            |e| event(BuildEvent::ClusterEvent(e)),
-        )?;
-        event(BuildEvent::FinishedPartitioning);
+        event!(event, BuildEvent::StartingPartitioning);
+        let num_partitions = self.num_partitions.try_into().unwrap();
+        let partitions = match self.sequential_sample_size {
+            Some(sample_size) => {
+                self.vs.partition_sequential_with_distance_and_events(
+                    num_partitions,
+                    sample_size,
+                    &*self.distance,
+                    |e| event(BuildEvent::ClusterEvent(e)),
+                )?
+            },
+            None => self.vs.partition_with_distance_and_events(
+                num_partitions,
+                &*self.distance,
+                |e| event(BuildEvent::ClusterEvent(e)),
+            )?,
+        };
+        event!(event, BuildEvent::FinishedPartitioning);
+        match self.quantization {
+            Quantization::Product => {},
+            Quantization::Scalar8 => {
+                self.num_divisions = partitions.residues.vector_size();
+                self.num_clusters = NUM_SCALAR8_LEVELS;
+            },
+            Quantization::Flat => {
+                self.num_divisions = 1;
+                self.num_clusters = 1;
+            },
+        }
         // divides residual vectors
-        event(BuildEvent::StartingSubvectorDivision);
+        event!(event, BuildEvent::StartingSubvectorDivision);
         let divided = divide_vector_set(
             &partitions.residues,
             self.num_divisions.try_into().unwrap(),
         )?;
-        event(BuildEvent::FinishedSubvectorDivision);
+        event!(event, BuildEvent::FinishedSubvectorDivision);
         // builds codebooks for residues
-        let mut codebooks: Vec<Codebook<T>> = Vec::with_capacity(
-            self.num_divisions.try_into().unwrap(),
-        );
-        for (i, subvs) in divided.iter().enumerate() {
-            event(BuildEvent::StartingQuantization(i));
-            codebooks.push(cluster_with_events(
-                subvs,
-                self.num_clusters.try_into().unwrap(),
-                |e| event(BuildEvent::ClusterEvent(e)),
-            )?);
-            event(BuildEvent::FinishedQuantization(i));
-        }
+        let quantizer = Quantizer {
+            num_divisions: self.num_divisions,
+            num_clusters: self.num_clusters.try_into().unwrap(),
+            sequential_sample_size: self.sequential_sample_size,
+            anisotropic_eta: self.anisotropic_eta,
+            quantization: self.quantization,
+        };
+        let num_threads = self.num_threads.min(self.num_divisions);
+        let codebooks: Vec<Codebook<T>> = if num_threads > 1 {
+            quantizer.train_in_parallel(
+                &partitions,
+                &divided,
+                num_threads,
+                &mut event,
+                &mut stage,
+            )?
+        } else {
+            stage(BuildStage::Partitioned(&partitions))?;
+            let mut codebooks = Vec::with_capacity(self.num_divisions);
+            for (i, subvs) in divided.iter().enumerate() {
+                event!(event, BuildEvent::StartingQuantization(i));
+                let codebook = quantizer.train(
+                    &partitions,
+                    subvs,
+                    i,
+                    |e| event(BuildEvent::ClusterEvent(e)),
+                )?;
+                event!(event, BuildEvent::FinishedQuantization(i));
+                stage(BuildStage::Quantized(i, &codebook))?;
+                codebooks.push(codebook);
+            }
+            codebooks
+        };
+        let (quantization_errors, squared_error_norms) =
+            if self.quantization == Quantization::Flat {
+                // residues are stored as they are
+                (
+                    vec![QuantizationError { mean: T::zero(), max: T::zero() }],
+                    Vec::new(),
+                )
+            } else {
+                (
+                    divided
+                        .iter()
+                        .zip(codebooks.iter())
+                        .map(|(vs, cb)| measure_quantization_error(vs, cb))
+                        .collect(),
+                    measure_squared_error_norms(&divided, &codebooks),
+                )
+            };
         Ok(Database {
             vector_size: partitions.residues.vector_size(),
             num_partitions: self.num_partitions,
@@ -125,10 +489,421 @@ where
             partitions,
             codebooks,
             attribute_table: HashMap::new(),
+            duplicate_groups,
+            quantization_errors,
+            squared_error_norms,
+            projection,
+            quantization: self.quantization,
+        })
+    }
+}
+
+impl<T> DatabaseBuilder<T, BlockVectorSet<T>>
+where
+    T: Scalar,
+{
+    /// Reduces the dimensions of the input vectors by principal component
+    /// analysis (PCA).
+    ///
+    /// Trains a projection onto the `output_size` principal components of
+    /// the input vectors, and indexes the projected vectors instead; e.g.,
+    /// 1536-dimensional embeddings can be indexed in 256 dimensions with
+    /// much smaller codebooks. The projection is saved in the database and
+    /// applied to query vectors, which keep the original size. See
+    /// [`train_pca`] for details.
+    ///
+    /// `output_size` must not be less than the number of subvector
+    /// divisions. Disabled by default.
+    pub fn with_pca(mut self, output_size: NonZeroUsize) -> Self {
+        self.pca_output_size = Some(output_size);
+        self.project_vectors = Some(project_block_vector_set);
+        self
+    }
+
+    /// Rotates the input vectors randomly before they are indexed.
+    ///
+    /// The rotation generated by [`random_rotation`] with `seed` spreads
+    /// variances across subvector divisions, which may improve recall for
+    /// embeddings whose variances concentrate in a few elements. Distances
+    /// are preserved. The rotation is saved in the database and applied to
+    /// query vectors. Follows PCA if [`with_pca`](Self::with_pca) is also
+    /// specified. Disabled by default.
+    pub fn with_random_rotation(mut self, seed: u64) -> Self {
+        self.rotation_seed = Some(seed);
+        self.project_vectors = Some(project_block_vector_set);
+        self
+    }
+}
+
+// Projects all the vectors in a block vector set.
+fn project_block_vector_set<T>(
+    projection: &Projection<T>,
+    vs: &BlockVectorSet<T>,
+) -> Result<BlockVectorSet<T>, Error>
+where
+    T: Scalar,
+{
+    projection.project_vector_set(vs)
+}
+
+// Configuration to train the codebooks of subvector divisions.
+struct Quantizer<T> {
+    num_divisions: usize,
+    num_clusters: NonZeroUsize,
+    sequential_sample_size: Option<NonZeroUsize>,
+    anisotropic_eta: Option<T>,
+    quantization: Quantization,
+}
+
+impl<T> Quantizer<T>
+where
+    T: Scalar,
+{
+    // Trains the codebook of a division.
+    fn train<VS, SVS, EV, C>(
+        &self,
+        partitions: &Partitions<T, VS>,
+        subvs: &SVS,
+        division: usize,
+        event_handler: EV,
+    ) -> Result<Codebook<T>, Error>
+    where
+        VS: VectorSet<T>,
+        SVS: VectorSet<T>,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl,
+    {
+        match self.quantization {
+            Quantization::Product => {},
+            Quantization::Scalar8 => {
+                return quantize_scalars(subvs, self.num_clusters);
+            },
+            Quantization::Flat => return Ok(zero_codebook(subvs)),
+        }
+        match (self.anisotropic_eta, self.sequential_sample_size) {
+            (Some(eta), _) => {
+                let directions = anisotropic_directions(
+                    partitions,
+                    division,
+                    self.num_divisions,
+                )?;
+                cluster_anisotropic_with_events(
+                    subvs,
+                    &directions,
+                    self.num_clusters,
+                    eta,
+                    event_handler,
+                )
+            },
+            (None, Some(sample_size)) => {
+                cluster_sequential_with_distance_and_events(
+                    subvs,
+                    self.num_clusters,
+                    sample_size,
+                    &SquaredL2,
+                    event_handler,
+                )
+            },
+            (None, None) => {
+                cluster_with_events(subvs, self.num_clusters, event_handler)
+            },
+        }
+    }
+
+    // Trains the codebooks of all the divisions on `num_threads` threads.
+    //
+    // Worker threads take divisions in order and send their events to the
+    // calling thread, which holds back the events of a division until the
+    // preceding divisions finish. So `event` observes the same sequence of
+    // events as in sequential training. The calling thread also runs
+    // `stage` meanwhile.
+    //
+    // Cancelling the build or a failure of any division or `stage` stops
+    // the workers at their next clustering event.
+    fn train_in_parallel<VS, SVS, EventHandler, C, Stage>(
+        &self,
+        partitions: &Partitions<T, VS>,
+        divided: &[SVS],
+        num_threads: usize,
+        event: &mut EventHandler,
+        stage: &mut Stage,
+    ) -> Result<Vec<Codebook<T>>, Error>
+    where
+        T: Send + Sync,
+        VS: VectorSet<T> + Sync,
+        SVS: VectorSet<T> + Sync,
+        EventHandler: FnMut(BuildEvent<'_, T>) -> C,
+        C: EventControl,
+        Stage: FnMut(BuildStage<'_, T, VS>) -> Result<(), Error>,
+    {
+        let num_divisions = divided.len();
+        let next_division = AtomicUsize::new(0);
+        let stopped = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::channel::<DivisionMessage<T>>();
+        std::thread::scope(|scope| {
+            for _ in 0..num_threads {
+                let sender = sender.clone();
+                let next_division = &next_division;
+                let stopped = &stopped;
+                scope.spawn(move || loop {
+                    let i = next_division.fetch_add(1, Ordering::Relaxed);
+                    if i >= num_divisions || stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let result = self.train(partitions, &divided[i], i, |e| {
+                        let e = OwnedClusterEvent::from_event(e);
+                        // the receiver is gone if the build has stopped
+                        let _ = sender.send(DivisionMessage::Event(i, e));
+                        if stopped.load(Ordering::Relaxed) {
+                            ControlFlow::Break(())
+                        } else {
+                            ControlFlow::Continue(())
+                        }
+                    });
+                    let _ = sender.send(DivisionMessage::Finished(i, result));
+                });
+            }
+            drop(sender);
+            let result = stage(BuildStage::Partitioned(partitions))
+                .and_then(|_| forward_division_messages(
+                    &receiver,
+                    num_divisions,
+                    event,
+                    |i, codebook| stage(BuildStage::Quantized(i, codebook)),
+                ));
+            if result.is_err() {
+                stopped.store(true, Ordering::Relaxed);
+            }
+            result
         })
     }
 }
 
+// Notifies events and collects codebooks sent from workers training
+// divisions, in the order of divisions.
+//
+// `quantized` is called with every codebook after its `FinishedQuantization`
+// event.
+fn forward_division_messages<T, EventHandler, C, Quantized>(
+    receiver: &mpsc::Receiver<DivisionMessage<T>>,
+    num_divisions: usize,
+    mut event: &mut EventHandler,
+    mut quantized: Quantized,
+) -> Result<Vec<Codebook<T>>, Error>
+where
+    T: Clone,
+    EventHandler: FnMut(BuildEvent<'_, T>) -> C,
+    C: EventControl,
+    Quantized: FnMut(usize, &Codebook<T>) -> Result<(), Error>,
+{
+    let mut pending_events: Vec<Vec<OwnedClusterEvent<T>>> =
+        (0..num_divisions).map(|_| Vec::new()).collect();
+    let mut finished: Vec<Option<Codebook<T>>> =
+        (0..num_divisions).map(|_| None).collect();
+    let mut codebooks: Vec<Codebook<T>> = Vec::with_capacity(num_divisions);
+    // whether the current division has been started
+    let mut started = false;
+    while codebooks.len() < num_divisions {
+        let message = receiver.recv().map_err(|_| Error::InvalidContext(
+            "codebook training has stopped unexpectedly".to_string(),
+        ))?;
+        match message {
+            DivisionMessage::Event(i, e) => pending_events[i].push(e),
+            DivisionMessage::Finished(i, result) => finished[i] = Some(result?),
+        };
+        // notifies the events of the current and finished divisions
+        while codebooks.len() < num_divisions {
+            let i = codebooks.len();
+            if !started {
+                event!(event, BuildEvent::StartingQuantization(i));
+                started = true;
+            }
+            for e in pending_events[i].drain(..) {
+                event!(event, BuildEvent::ClusterEvent(e.as_event()));
+            }
+            match finished[i].take() {
+                Some(codebook) => {
+                    event!(event, BuildEvent::FinishedQuantization(i));
+                    quantized(i, &codebook)?;
+                    codebooks.push(codebook);
+                    started = false;
+                },
+                None => break,
+            };
+        }
+    }
+    Ok(codebooks)
+}
+
+// Message from a worker training a division.
+enum DivisionMessage<T> {
+    // Clustering event of a division.
+    Event(usize, OwnedClusterEvent<T>),
+    // Codebook of a division.
+    Finished(usize, Result<Codebook<T>, Error>),
+}
+
+// Clustering event that owns its arguments to be sent across threads.
+enum OwnedClusterEvent<T> {
+    StartingCentroidInitialization,
+    FinishedCentroidInitialization,
+    StartingCentroidUpdate(usize),
+    FinishedCentroidUpdate(usize, T),
+    StartingCentroidReassignment(usize),
+    FinishedCentroidReassignment(usize, ClusterMetrics<T>),
+}
+
+impl<T> OwnedClusterEvent<T>
+where
+    T: Clone,
+{
+    fn from_event(e: ClusterEvent<'_, T>) -> Self {
+        match e {
+            ClusterEvent::StartingCentroidInitialization => {
+                Self::StartingCentroidInitialization
+            },
+            ClusterEvent::FinishedCentroidInitialization => {
+                Self::FinishedCentroidInitialization
+            },
+            ClusterEvent::StartingCentroidUpdate(n) => {
+                Self::StartingCentroidUpdate(n)
+            },
+            ClusterEvent::FinishedCentroidUpdate(n, change) => {
+                Self::FinishedCentroidUpdate(n, change.clone())
+            },
+            ClusterEvent::StartingCentroidReassignment(n) => {
+                Self::StartingCentroidReassignment(n)
+            },
+            ClusterEvent::FinishedCentroidReassignment(n, metrics) => {
+                Self::FinishedCentroidReassignment(n, metrics.clone())
+            },
+        }
+    }
+
+    fn as_event(&self) -> ClusterEvent<'_, T> {
+        match self {
+            Self::StartingCentroidInitialization => {
+                ClusterEvent::StartingCentroidInitialization
+            },
+            Self::FinishedCentroidInitialization => {
+                ClusterEvent::FinishedCentroidInitialization
+            },
+            Self::StartingCentroidUpdate(n) => {
+                ClusterEvent::StartingCentroidUpdate(*n)
+            },
+            Self::FinishedCentroidUpdate(n, change) => {
+                ClusterEvent::FinishedCentroidUpdate(*n, change)
+            },
+            Self::StartingCentroidReassignment(n) => {
+                ClusterEvent::StartingCentroidReassignment(*n)
+            },
+            Self::FinishedCentroidReassignment(n, metrics) => {
+                ClusterEvent::FinishedCentroidReassignment(*n, metrics)
+            },
+        }
+    }
+}
+
+// Creates a codebook that encodes all the subvectors into a single zero
+// vector.
+fn zero_codebook<T, VS>(subvs: &VS) -> Codebook<T>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    let size = subvs.vector_size();
+    Codebook {
+        centroids: BlockVectorSet::chunk(
+            vec![T::zero(); size],
+            size.try_into().unwrap(),
+        ).unwrap(),
+        indices: vec![0; subvs.len()],
+    }
+}
+
+// Measures the quantization error of a codebook over encoded subvectors.
+fn measure_quantization_error<T, VS>(
+    vs: &VS,
+    codebook: &Codebook<T>,
+) -> QuantizationError<T>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    let mut sum = T::zero();
+    let mut max = T::zero();
+    for (i, v) in vs.iter() {
+        let centroid = codebook.centroids.get(codebook.indices[i]);
+        let error = T::squared_distance(v.as_slice(), centroid);
+        sum += error;
+        if max < error {
+            max = error;
+        }
+    }
+    let mean = if vs.is_empty() {
+        T::zero()
+    } else {
+        sum / T::from_as(vs.len())
+    };
+    QuantizationError { mean, max }
+}
+
+// Measures the squared norm of the quantization error of every vector over
+// all the divisions.
+fn measure_squared_error_norms<T, VS>(
+    divided: &[VS],
+    codebooks: &[Codebook<T>],
+) -> Vec<T>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    let num_vectors = divided.first().map_or(0, |subvs| subvs.len());
+    let mut norms = vec![T::zero(); num_vectors];
+    for (subvs, codebook) in divided.iter().zip(codebooks.iter()) {
+        for (i, v) in subvs.iter() {
+            let centroid = codebook.centroids.get(codebook.indices[i]);
+            norms[i] += T::squared_distance(v.as_slice(), centroid);
+        }
+    }
+    norms
+}
+
+// Returns the directions of input vectors in a given division.
+//
+// The direction of a vector is the division of the input vector, which is
+// reconstructed from the residue and the partition centroid, divided by the
+// norm of the whole input vector. Zero vectors have zero directions.
+fn anisotropic_directions<T, VS>(
+    partitions: &Partitions<T, VS>,
+    division: usize,
+    num_divisions: usize,
+) -> Result<BlockVectorSet<T>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    let m = partitions.residues.vector_size();
+    let range = division_range(m, num_divisions, division);
+    let md = range.len();
+    let mut directions: Vec<T> =
+        Vec::with_capacity(partitions.residues.len() * md);
+    let mut x: Vec<T> = vec![T::zero(); m];
+    for (i, residue) in partitions.residues.iter() {
+        x.copy_from_slice(residue.as_slice());
+        let pi = partitions.codebook.indices[i];
+        T::add_in(&mut x, partitions.codebook.centroids.get(pi));
+        let norm = norm2(&x);
+        let scale = if norm != T::zero() {
+            T::one() / norm
+        } else {
+            T::zero()
+        };
+        directions.extend(x[range.clone()].iter().map(|&e| e * scale));
+    }
+    BlockVectorSet::chunk(directions, md.try_into().unwrap())
+}
+
 /// Events from [`DatabaseBuilder::build_with_events`].
 #[derive(Debug)]
 pub enum BuildEvent<'a, T> {
@@ -136,6 +911,14 @@ pub enum BuildEvent<'a, T> {
     StartingIdAssignment,
     /// Finished assigning unique IDs to individual vectors.
     FinishedIdAssignment,
+    /// Starting to detect duplicate vectors.
+    StartingDuplicateDetection,
+    /// Finished detecting duplicate vectors.
+    FinishedDuplicateDetection,
+    /// Starting to project vectors; e.g., by PCA.
+    StartingProjection,
+    /// Finished projecting vectors.
+    FinishedProjection,
     /// Starting to partition vectors.
     StartingPartitioning,
     /// Finished partitioning vectors.
@@ -152,6 +935,18 @@ pub enum BuildEvent<'a, T> {
     ClusterEvent(ClusterEvent<'a, T>),
 }
 
+impl<T> OperationEvent for BuildEvent<'_, T> {
+    const OPERATION: &'static str = "build";
+}
+
+// Intermediate product of a build.
+pub(crate) enum BuildStage<'a, T, VS> {
+    // Vectors have been partitioned.
+    Partitioned(&'a Partitions<T, VS>),
+    // Codebook of a division has been trained.
+    Quantized(usize, &'a Codebook<T>),
+}
+
 /// Database.
 pub struct Database<T, VS>
 where
@@ -173,6 +968,16 @@ where
     codebooks: Vec<Codebook<T>>,
     // Attributes associated with vectors.
     attribute_table: HashMap<Uuid, Attributes>,
+    // Groups of duplicate vectors detected at build.
+    duplicate_groups: Vec<Vec<Uuid>>,
+    // Quantization errors of the codebooks measured at build.
+    quantization_errors: Vec<QuantizationError<T>>,
+    // Squared norm of the quantization error of each vector. Empty if the
+    // database is not quantized.
+    squared_error_norms: Vec<T>,
+    // Projection applied to vectors before they are indexed.
+    projection: Option<Projection<T>>,
+    quantization: Quantization,
 }
 
 impl<T, VS> Database<T, VS>
@@ -185,6 +990,8 @@ where
     }
 
     /// Returns the vector size.
+    ///
+    /// The output size of the projection if the database has one.
     pub const fn vector_size(&self) -> usize {
         self.vector_size
     }
@@ -199,9 +1006,71 @@ where
         self.num_divisions
     }
 
-    /// Returns the size of a subvector.
+    /// Returns the quantization of residues.
+    pub const fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    /// Returns the projection applied to vectors before they are indexed.
+    ///
+    /// `None` if vectors are indexed as they are.
+    pub fn projection(&self) -> Option<&Projection<T>> {
+        self.projection.as_ref()
+    }
+
+    /// Returns groups of duplicate vectors detected at build.
+    ///
+    /// Each group lists the IDs of duplicate vectors; the first one is the
+    /// earliest in the input vector set.
+    /// Empty unless [`DatabaseBuilder::with_duplicate_detection`] is enabled.
+    /// Removed vectors remain in the groups until
+    /// [`Database::collapse_duplicates`].
+    pub fn duplicate_groups(&self) -> &[Vec<Uuid>] {
+        &self.duplicate_groups
+    }
+
+    /// Returns the quantization errors of the codebooks.
+    ///
+    /// Measured over the input vectors at build.
+    pub fn quantization_errors(&self) -> &[QuantizationError<T>] {
+        &self.quantization_errors
+    }
+
+    /// Estimates the number of bytes of memory the database occupies.
+    ///
+    /// Counts residue vectors, quantization errors, centroids, codebooks,
+    /// vector IDs, and the attribute table. Overheads of allocators are not
+    /// counted.
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let residues = self.partitions.residues.len()
+            * self.partitions.residues.vector_size()
+            * core::mem::size_of::<T>();
+        let codebooks: usize = core::iter::once(&self.partitions.codebook)
+            .chain(self.codebooks.iter())
+            .map(|codebook| {
+                codebook.centroids.memory_bytes()
+                    + codebook.indices.capacity()
+                        * core::mem::size_of::<usize>()
+            })
+            .sum();
+        let vector_ids =
+            self.vector_ids.capacity() * core::mem::size_of::<Uuid>();
+        let squared_error_norms =
+            self.squared_error_norms.capacity() * core::mem::size_of::<T>();
+        core::mem::size_of_val(self)
+            + residues
+            + squared_error_norms
+            + codebooks
+            + vector_ids
+            + attribute_table_bytes(&self.attribute_table)
+    }
+
+    /// Returns the size of the longest subvector; i.e., the first division.
+    ///
+    /// Trailing divisions are one element shorter if `vector_size` is not a
+    /// multiple of `num_divisions`. See [`division_range`].
     pub fn subvector_size(&self) -> usize {
-        self.vector_size / self.num_divisions
+        self.vector_size.div_ceil(self.num_divisions)
     }
 
     /// Returns the number of clusters.
@@ -267,17 +1136,17 @@ where
         let key = key.into();
         let value = value.into();
         if let Some(attributes) = self.attribute_table.get_mut(id) {
-            match attributes.entry(key.into()) {
+            match attributes.entry(key) {
                 HashMapEntry::Occupied(entry) => {
-                    *entry.into_mut() = value.into();
+                    *entry.into_mut() = value;
                 },
                 HashMapEntry::Vacant(entry) => {
-                    entry.insert(value.into());
+                    entry.insert(value);
                 },
             };
         } else {
             self.attribute_table.insert(
-                id.clone(),
+                *id,
                 Attributes::from([(key, value)]),
             );
         }
@@ -285,12 +1154,103 @@ where
     }
 }
 
+impl<T, VS> Database<T, VS>
+where
+    VS: VectorSetRemove<T>,
+{
+    /// Removes a given vector from the database.
+    ///
+    /// Drops the vector from the partitions, codebook indices, and attribute
+    /// table. Centroids and codebooks are not retrained.
+    ///
+    /// Fails if no vector is associated with `id`.
+    pub fn remove_vector(&mut self, id: &Uuid) -> Result<(), Error> {
+        self.remove_vectors([id])
+    }
+
+    /// Removes given vectors from the database.
+    ///
+    /// Fails if any of `ids` is not associated with a vector, in which case
+    /// no vector is removed.
+    pub fn remove_vectors<'a, I>(&mut self, ids: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'a Uuid>,
+    {
+        let ids: HashSet<&Uuid> = ids.into_iter().collect();
+        let keep: Vec<bool> = self.vector_ids
+            .iter()
+            .map(|id| !ids.contains(id))
+            .collect();
+        let num_removed = keep.iter().filter(|&&k| !k).count();
+        if num_removed != ids.len() {
+            let missing = ids
+                .iter()
+                .find(|&&id| !self.vector_ids.contains(id))
+                .unwrap();
+            return Err(Error::InvalidArgs(
+                format!("no such vector ID: {}", missing),
+            ));
+        }
+        for id in &ids {
+            self.attribute_table.remove(*id);
+        }
+        retain_by_flags(&mut self.vector_ids, &keep);
+        retain_by_flags(&mut self.partitions.codebook.indices, &keep);
+        self.partitions.residues.retain(|i| keep[i]);
+        for codebook in self.codebooks.iter_mut() {
+            retain_by_flags(&mut codebook.indices, &keep);
+        }
+        Ok(())
+    }
+
+    /// Keeps only the first vector of every duplicate group.
+    ///
+    /// Attributes of the other vectors in a group are merged into the first
+    /// one, which keeps its own value if both have the same attribute.
+    /// Set attributes before collapsing because indices of vectors shift.
+    /// Clears [`Database::duplicate_groups`].
+    ///
+    /// Returns the number of removed vectors.
+    pub fn collapse_duplicates(&mut self) -> Result<usize, Error> {
+        let existing: HashSet<Uuid> = self.vector_ids.iter().cloned().collect();
+        let mut removed: Vec<Uuid> = Vec::new();
+        for group in core::mem::take(&mut self.duplicate_groups) {
+            let mut group = group
+                .into_iter()
+                .filter(|id| existing.contains(id));
+            let Some(first) = group.next() else {
+                continue;
+            };
+            for id in group {
+                if let Some(attributes) = self.attribute_table.remove(&id) {
+                    let merged = self.attribute_table.entry(first).or_default();
+                    for (key, value) in attributes {
+                        merged.entry(key).or_insert(value);
+                    }
+                }
+                removed.push(id);
+            }
+        }
+        self.remove_vectors(&removed)?;
+        Ok(removed.len())
+    }
+}
+
+// Retains only the elements whose corresponding flags are `true`.
+fn retain_by_flags<E>(elements: &mut Vec<E>, keep: &[bool]) {
+    assert_eq!(elements.len(), keep.len());
+    let mut keep = keep.iter();
+    elements.retain(|_| *keep.next().unwrap());
+}
+
 impl<T, VS> Database<T, VS>
 where
     T: Scalar,
     VS: VectorSet<T>,
 {
     /// Queries k-nearest neighbors (k-NN) of a given vector.
+    ///
+    /// Fails if `v` has an infinite or NaN element.
     pub fn query<V>(
         &self,
         v: &V,
@@ -303,49 +1263,120 @@ where
         self.query_with_events(v, k, nprobe, |_| {})
     }
 
+    /// Assigns a given vector to the nearest partition.
+    ///
+    /// Projects `v` if the database has a projection.
+    ///
+    /// Fails if the vector size does not match.
+    pub fn assign_partition<V>(
+        &self,
+        v: &V,
+    ) -> Result<PartitionAssignment<T>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let v = project_query(self.projection(), v.as_slice())?;
+        assign_partition(&self.partitions.codebook.centroids, &v)
+    }
+
+    /// Returns an encoder that encodes vectors the same way as the vectors
+    /// in the database.
+    pub fn pq_encoder(&self) -> Result<PqEncoder<T>, Error> {
+        PqEncoder::new(
+            self.partitions.codebook.centroids.clone(),
+            self.codebooks.iter().map(|cb| cb.centroids.clone()).collect(),
+        )
+    }
+
     /// Queries k-nearest neighbors (k-NN) of a given vector.
+    ///
+    /// Fails if `v` has an infinite or NaN element.
     pub fn query_with_events<V, EventHandler>(
         &self,
         v: &V,
         k: NonZeroUsize,
         nprobe: NonZeroUsize,
+        event: EventHandler,
+    ) -> Result<Vec<QueryResult<T>>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+        EventHandler: FnMut(QueryEvent),
+    {
+        self.query_internal(v, k, nprobe, QueryMetric::SquaredL2, event)
+    }
+
+    /// Queries k-nearest neighbors (k-NN) of a given vector under a given
+    /// metric.
+    ///
+    /// [`QueryResult::squared_distance`] holds the score under `metric`.
+    ///
+    /// Fails if `v` has an infinite or NaN element.
+    pub fn query_with_metric<V>(
+        &self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+        metric: QueryMetric,
+    ) -> Result<Vec<QueryResult<T>>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        self.query_internal(v, k, nprobe, metric, |_| {})
+    }
+
+    // Queries k-nearest neighbors of a given vector under a given metric.
+    fn query_internal<V, EventHandler>(
+        &self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+        metric: QueryMetric,
         mut event: EventHandler,
     ) -> Result<Vec<QueryResult<T>>, Error>
     where
         V: AsSlice<T> + ?Sized,
-        EventHandler: FnMut(QueryEvent) -> (),
+        EventHandler: FnMut(QueryEvent),
     {
-        event(QueryEvent::StartingPartitionSelection);
         let v = v.as_slice();
-        let queries = self.query_partitions(v, nprobe)?;
+        check_finite(v)?;
+        let v = project_query(self.projection(), v)?;
+        event(QueryEvent::StartingPartitionSelection);
+        let queries = self.query_partitions(&v, nprobe, metric)?;
         event(QueryEvent::FinishedPartitionSelection);
         let mut all_results: Vec<QueryResult<T>> = Vec::new();
+        // reuses the buffers of the score table across partitions
+        let mut table = ScoreTable::empty(metric);
         for query in &queries {
             event(QueryEvent::StartingPartitionQuery(
                 query.partition_index,
             ));
-            let results = query.execute()?;
+            let results = query.execute_in(&mut table, &mut event)?;
             all_results.extend(results);
             event(QueryEvent::FinishedPartitionQuery(
                 query.partition_index,
             ));
         }
         event(QueryEvent::StartingResultSelection);
+        // breaks ties by vector IDs so that the order does not depend on
+        // the order of partitions
         all_results.sort_by(|lhs, rhs| {
-            lhs.squared_distance.partial_cmp(&rhs.squared_distance).unwrap()
+            lhs.squared_distance.partial_cmp(&rhs.squared_distance)
+                .unwrap()
+                .then_with(|| lhs.vector_id.cmp(&rhs.vector_id))
         });
         all_results.truncate(k.get());
         event(QueryEvent::FinishedResultSelection);
         Ok(all_results)
     }
 
-    // Queries partitions.
+    // Queries partitions closest to a given vector under a given metric.
     //
     // Fails if `nprobe` exceeds the number of partitions.
     fn query_partitions<'a>(
         &'a self,
         v: &[T],
         nprobe: NonZeroUsize,
+        metric: QueryMetric,
     ) -> Result<Vec<PartitionQuery<'a, T, VS>>, Error> {
         let nprobe = nprobe.get();
         if nprobe > self.num_partitions {
@@ -355,27 +1386,23 @@ where
                 self.num_partitions,
             )));
         }
-        // localizes vectors and calculates distances
-        let mut local_vectors: Vec<(usize, Vec<T>, T)> =
+        // scores partition centroids
+        let mut scores: Vec<(usize, T)> =
             Vec::with_capacity(self.num_partitions);
-        for pi in 0..self.num_partitions {
-            let mut localized: Vec<T> = Vec::new();
-            localized.extend_from_slice(v);
-            let centroid = self.partitions.codebook.centroids.get(pi);
-            subtract_in(&mut localized[..], centroid.as_slice());
-            let distance = dot(&localized[..], &localized[..]);
-            local_vectors.push((pi, localized, distance));
-        }
-        // chooses `nprobe` shortest distances
-        local_vectors.sort_by(|lhs, rhs| lhs.2.partial_cmp(&rhs.2).unwrap());
-        local_vectors.truncate(nprobe);
+        for (pi, centroid) in self.partitions.codebook.centroids.iter() {
+            scores.push((pi, metric.score(v, centroid)));
+        }
+        // chooses `nprobe` best scores
+        scores.sort_by(|lhs, rhs| lhs.1.partial_cmp(&rhs.1).unwrap());
+        scores.truncate(nprobe);
         // queries
-        let queries = local_vectors
+        let queries = scores
             .into_iter()
-            .map(|(partition_index, localized, _)| PartitionQuery {
+            .map(|(partition_index, _)| PartitionQuery {
                 db: self,
                 partition_index,
-                localized,
+                query: v.to_vec(),
+                metric,
             })
             .collect();
         Ok(queries)
@@ -416,9 +1443,16 @@ pub struct Partition<T> {
     // Centroid of the partition.
     centroid: Vec<T>,
     // Encoded vectors.
-    encoded_vectors: BlockVectorSet<u32>,
+    encoded_vectors: EncodedVectorSet,
+    // Number of codes in each codebook.
+    num_codes: usize,
     // Vector IDs.
     vector_ids: Vec<Uuid>,
+    // Residues in full precision. `None` if the database is quantized.
+    residues: Option<BlockVectorSet<T>>,
+    // Squared norms of the quantization errors. `None` if the database is
+    // not quantized.
+    squared_error_norms: Option<Vec<T>>,
 }
 
 impl<T> Partition<T> {
@@ -436,6 +1470,25 @@ impl<T> Partition<T> {
     pub fn num_vectors(&self) -> usize {
         self.encoded_vectors.len()
     }
+
+    /// Returns the number of codes in each codebook.
+    pub fn num_codes(&self) -> usize {
+        self.num_codes
+    }
+
+    /// Returns the residues of the vectors in full precision.
+    ///
+    /// `None` unless the database is built with [`Quantization::Flat`].
+    pub fn residues(&self) -> Option<&BlockVectorSet<T>> {
+        self.residues.as_ref()
+    }
+
+    /// Returns the squared norms of the quantization errors of the vectors.
+    ///
+    /// `None` if the database is built with [`Quantization::Flat`].
+    pub fn squared_error_norms(&self) -> Option<&[T]> {
+        self.squared_error_norms.as_deref()
+    }
 }
 
 impl<T> Partition<T>
@@ -459,6 +1512,18 @@ where
         let mut encoded_vectors: Vec<u32> =
             Vec::with_capacity(num_vectors * num_divisions);
         let mut vector_ids: Vec<Uuid> = Vec::with_capacity(num_vectors);
+        let mut residues: Option<Vec<T>> =
+            if db.quantization() == Quantization::Flat {
+                Some(Vec::with_capacity(num_vectors * db.vector_size()))
+            } else {
+                None
+            };
+        let mut squared_error_norms: Option<Vec<T>> =
+            if db.squared_error_norms.is_empty() {
+                None
+            } else {
+                Some(Vec::with_capacity(num_vectors))
+            };
         for (vi, _) in db.partitions.codebook.indices
             .iter()
             .enumerate()
@@ -470,14 +1535,34 @@ where
                 );
             }
             vector_ids.push(db.vector_ids[vi]);
+            if let Some(residues) = residues.as_mut() {
+                residues.extend_from_slice(
+                    db.partitions.residues.get(vi).as_slice(),
+                );
+            }
+            if let Some(norms) = squared_error_norms.as_mut() {
+                norms.push(db.squared_error_norms[vi].clone());
+            }
         }
+        let encoded_vectors = BlockVectorSet::chunk(
+            encoded_vectors,
+            num_divisions.try_into().unwrap(),
+        ).unwrap();
         Partition {
             centroid,
-            encoded_vectors: BlockVectorSet::chunk(
+            encoded_vectors: EncodedVectorSet::with_num_codes(
                 encoded_vectors,
-                num_divisions.try_into().unwrap(),
+                db.num_clusters(),
             ).unwrap(),
+            num_codes: db.num_clusters(),
             vector_ids,
+            residues: residues.map(|residues| {
+                BlockVectorSet::chunk(
+                    residues,
+                    db.vector_size().try_into().unwrap(),
+                ).unwrap()
+            }),
+            squared_error_norms,
         }
     }
 }
@@ -493,6 +1578,14 @@ pub enum QueryEvent {
     StartingPartitionQuery(usize),
     /// Finished to run a query on a specific partition.
     FinishedPartitionQuery(usize),
+    /// Starting to calculate the distance table for a specific partition.
+    StartingDistanceTableCalculation(usize),
+    /// Finished calculating the distance table for a specific partition.
+    FinishedDistanceTableCalculation(usize),
+    /// Scanned candidates in a specific partition.
+    ///
+    /// Carries the partition index and the number of candidates.
+    ScannedCandidates(usize, usize),
     /// Starting to select k-nearest neighbors.
     StartingResultSelection,
     /// Finished selecting k-nearest neighbors.
@@ -508,8 +1601,10 @@ where
     db: &'a Database<T, VS>,
     // Partition index.
     partition_index: usize,
-    // Localized query vector.
-    localized: Vec<T>,
+    // Query vector.
+    query: Vec<T>,
+    // Metric to score vectors.
+    metric: QueryMetric,
 }
 
 impl<'a, T, VS> PartitionQuery<'a, T, VS>
@@ -519,27 +1614,49 @@ where
 {
     /// Executes the query.
     pub fn execute(&self) -> Result<Vec<QueryResult<T>>, Error> {
-        let num_divisions = self.db.num_divisions();
-        let num_clusters = self.db.num_clusters();
-        let md = self.db.subvector_size();
+        self.execute_with_events(|_| {})
+    }
+
+    /// Executes the query.
+    pub fn execute_with_events<EventHandler>(
+        &self,
+        event: EventHandler,
+    ) -> Result<Vec<QueryResult<T>>, Error>
+    where
+        EventHandler: FnMut(QueryEvent),
+    {
+        self.execute_in(&mut ScoreTable::empty(self.metric), event)
+    }
+
+    // Executes the query recalculating a given score table.
+    //
+    // `table` must have been created for the metric of the query.
+    fn execute_in<EventHandler>(
+        &self,
+        table: &mut ScoreTable<T>,
+        mut event: EventHandler,
+    ) -> Result<Vec<QueryResult<T>>, Error>
+    where
+        EventHandler: FnMut(QueryEvent),
+    {
         // calculates the distance table
-        let mut distance_table: Vec<T> = Vec::with_capacity(
-            num_divisions * num_clusters,
+        event(QueryEvent::StartingDistanceTableCalculation(
+            self.partition_index,
+        ));
+        table.recalculate(
+            &self.query,
+            self.db.partitions.codebook.centroids.get(self.partition_index),
+            self.db.codebooks.iter().map(|cb| &cb.centroids),
         );
-        let mut vector_buf = vec![T::zero(); md];
-        for di in 0..num_divisions {
-            let from = di * md;
-            let to = from + md;
-            let subv = &self.localized[from..to];
-            for ci in 0..num_clusters {
-                let centroid = self.db.codebooks[di].centroids.get(ci);
-                let d = &mut vector_buf[..];
-                d.copy_from_slice(subv);
-                subtract_in(d, centroid.as_slice());
-                distance_table.push(dot(d, d));
-            }
-        }
-        // approximates the squared distances to individual vectors
+        event(QueryEvent::FinishedDistanceTableCalculation(
+            self.partition_index,
+        ));
+        let max_error2 = max_error2(&self.db.quantization_errors);
+        let flat = self.db.quantization() == Quantization::Flat;
+        let centroid =
+            self.db.partitions.codebook.centroids.get(self.partition_index);
+        let mut reconstructed: Vec<T> = Vec::new();
+        // approximates the scores of individual vectors unless they are flat
         let mut results: Vec<QueryResult<T>> = Vec::with_capacity(
             self.partition_size(),
         );
@@ -549,18 +1666,39 @@ where
             .filter(|(_, &pi)| pi == self.partition_index)
             .enumerate()
         {
-            let mut distance = T::zero();
-            for di in 0..num_divisions {
-                let ci = self.db.codebooks[di].indices[vi];
-                distance += distance_table[di * num_clusters + ci];
-            }
+            let (distance, error_bound) = if flat {
+                let distance = score_residue(
+                    self.metric,
+                    &self.query,
+                    centroid,
+                    self.db.partitions.residues.get(vi).as_slice(),
+                    &mut reconstructed,
+                );
+                (distance, Some(T::zero()))
+            } else {
+                let error2 = self.db.squared_error_norms.get(vi).copied();
+                let mut distance = table.score(
+                    self.db.codebooks.iter().map(|cb| cb.indices[vi]),
+                );
+                if let Some(error2) = error2 {
+                    distance = table.correct(distance, error2);
+                }
+                let error_bound =
+                    table.vector_error_bound(distance, error2, max_error2);
+                (distance, error_bound)
+            };
             results.push(QueryResult {
                 partition_index: self.partition_index,
-                vector_id: self.db.vector_ids[vi].clone(),
+                vector_id: self.db.vector_ids[vi],
                 vector_index: pvi,
                 squared_distance: distance,
+                error_bound,
             });
         }
+        event(QueryEvent::ScannedCandidates(
+            self.partition_index,
+            results.len(),
+        ));
         Ok(results)
     }
 
@@ -583,5 +1721,417 @@ pub struct QueryResult<T> {
     /// Vector index. Local index in the partition.
     pub vector_index: usize,
     /// Approximate squared distance.
+    ///
+    /// Corrected by the squared norm of the quantization error of the
+    /// vector. Holds the score under the metric if queried with
+    /// [`Database::query_with_metric`].
     pub squared_distance: T,
+    /// Estimated upper bound of the error of `squared_distance`.
+    ///
+    /// Derived from the quantization error of the vector; exact re-ranking
+    /// may be worthwhile if the bound is comparable to the gaps between
+    /// results. `None` under [`QueryMetric::Cosine`]. Zero under
+    /// [`Quantization::Flat`], which scores vectors exactly.
+    pub error_bound: Option<T>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::proto::SerializeOptions;
+    use crate::db::fixtures::{
+        build_database,
+        load_database,
+        store_database,
+        synthetic_vectors,
+    };
+    use crate::linalg::squared_distance;
+    use crate::testing::SyntheticDatasetBuilder;
+    use crate::vector::normalize_vectors;
+
+    #[test]
+    fn database_builder_should_reject_non_finite_vectors() {
+        let vs = BlockVectorSet::chunk(
+            vec![0.0f32, 0.0, 1.0, f32::NAN, 2.0, 2.0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let result = DatabaseBuilder::new(vs)
+            .with_partitions(1.try_into().unwrap())
+            .with_divisions(1.try_into().unwrap())
+            .with_clusters(1.try_into().unwrap())
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArgs(_))));
+    }
+
+    #[test]
+    fn database_builder_should_partition_under_given_distance() {
+        use crate::distance::{Cosine, L1};
+        use crate::db::fixtures::build_database_with;
+
+        let db = build_database_with(100, 4, |builder| {
+            builder.with_distance(Cosine)
+        });
+        for (_, centroid) in db.partitions.codebook.centroids.iter() {
+            assert!((norm2(centroid) - 1.0).abs() < 1e-5);
+        }
+
+        // every element of a centroid is the median of the members
+        let vectors = synthetic_vectors(100, 4);
+        let db = build_database_with(100, 4, |builder| {
+            builder.with_distance(L1)
+        });
+        let codebook = &db.partitions.codebook;
+        for (pi, centroid) in codebook.centroids.iter() {
+            for (d, &x) in centroid.iter().enumerate() {
+                let mut column: Vec<f32> = vectors
+                    .iter()
+                    .filter(|(i, _)| codebook.indices[*i] == pi)
+                    .map(|(_, v)| v[d])
+                    .collect();
+                column.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let n = column.len();
+                let median = if n % 2 == 1 {
+                    column[n / 2]
+                } else {
+                    (column[n / 2 - 1] + column[n / 2]) / 2.0
+                };
+                assert_eq!(x, median);
+            }
+        }
+
+        let result = DatabaseBuilder::new(vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .with_distance(L1)
+            .with_sequential_clustering(10.try_into().unwrap())
+            .build();
+        assert!(matches!(result, Err(Error::InvalidArgs(_))));
+    }
+
+    #[test]
+    fn parallel_quantization_should_notify_events_in_division_order() {
+        let mut quantization_events: Vec<(bool, usize)> = Vec::new();
+        let mut current: Option<usize> = None;
+        let db = DatabaseBuilder::new(synthetic_vectors(200, 8))
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(4.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .with_threads(4.try_into().unwrap())
+            .build_with_events(|event| match event {
+                BuildEvent::StartingQuantization(i) => {
+                    assert_eq!(current, None);
+                    current = Some(i);
+                    quantization_events.push((true, i));
+                },
+                BuildEvent::FinishedQuantization(i) => {
+                    assert_eq!(current, Some(i));
+                    current = None;
+                    quantization_events.push((false, i));
+                },
+                // partitioning also notifies cluster events
+                BuildEvent::ClusterEvent(_)
+                    if !quantization_events.is_empty() =>
+                {
+                    assert!(current.is_some());
+                },
+                _ => {},
+            })
+            .unwrap();
+        assert_eq!(db.num_vectors(), 200);
+        let expected: Vec<(bool, usize)> = (0..4)
+            .flat_map(|i| [(true, i), (false, i)])
+            .collect();
+        assert_eq!(quantization_events, expected);
+    }
+
+    #[test]
+    fn build_should_stop_at_event_that_breaks() {
+        let mut events: Vec<String> = Vec::new();
+        let result = DatabaseBuilder::new(synthetic_vectors(200, 8))
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(4.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build_with_events(|event| {
+                events.push(format!("{:?}", event));
+                match event {
+                    BuildEvent::FinishedPartitioning => ControlFlow::Break(()),
+                    _ => ControlFlow::Continue(()),
+                }
+            });
+        match result {
+            Err(Error::Cancelled(message)) => {
+                assert_eq!(message, "build has been cancelled");
+            },
+            _ => panic!("build must have been cancelled"),
+        }
+        // no events after the one that breaks
+        assert_eq!(events.last().unwrap(), "FinishedPartitioning");
+        assert!(events.iter().any(|e| e.starts_with("ClusterEvent(")));
+    }
+
+    #[test]
+    fn parallel_quantization_can_be_cancelled() {
+        let result = DatabaseBuilder::new(synthetic_vectors(200, 8))
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(4.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .with_threads(2.try_into().unwrap())
+            .build_with_events(|event| match event {
+                BuildEvent::StartingQuantization(1) => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            });
+        assert!(matches!(result, Err(Error::Cancelled(_))));
+    }
+
+    #[test]
+    fn query_should_break_ties_by_vector_id() {
+        let vs = BlockVectorSet::chunk(
+            [1.0f32, 2.0].repeat(8),
+            2.try_into().unwrap(),
+        ).unwrap();
+        let db = DatabaseBuilder::new(vs)
+            .with_partitions(1.try_into().unwrap())
+            .with_divisions(1.try_into().unwrap())
+            .with_clusters(1.try_into().unwrap())
+            .build()
+            .unwrap();
+        let results = db.query(
+            &[0.0f32, 0.0][..],
+            5.try_into().unwrap(),
+            1.try_into().unwrap(),
+        ).unwrap();
+        let mut expected: Vec<Uuid> = db.vector_ids().cloned().collect();
+        expected.sort();
+        let ids: Vec<Uuid> = results.iter().map(|r| r.vector_id).collect();
+        assert_eq!(ids, expected[..5]);
+    }
+
+    #[test]
+    fn anisotropic_quantization_should_preserve_inner_products() {
+        let mut dataset = SyntheticDatasetBuilder::new(
+            200.try_into().unwrap(),
+            8.try_into().unwrap(),
+        )
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        normalize_vectors(&mut dataset.vectors);
+        let query = dataset.vectors.get(0).to_vec();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(8.try_into().unwrap())
+            .with_anisotropic_quantization(4.0)
+            .build()
+            .unwrap();
+        let results = db.query_with_metric(
+            &query[..],
+            1.try_into().unwrap(),
+            2.try_into().unwrap(),
+            QueryMetric::NegativeDot,
+        ).unwrap();
+        // the inner product with the query itself is one
+        assert!((results[0].squared_distance + 1.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn error_bound_should_cover_exact_distance() {
+        let vectors = synthetic_vectors(100, 4);
+        let query = [0.5f32, -0.5, 0.25, 0.0];
+        let db = build_database(100, 4);
+        assert_eq!(db.quantization_errors().len(), 2);
+        assert!(db.quantization_errors().iter().all(|e| e.mean <= e.max));
+        let ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        let results = db.query(
+            &query[..],
+            10.try_into().unwrap(),
+            2.try_into().unwrap(),
+        ).unwrap();
+        for result in results {
+            let i = ids.iter().position(|id| *id == result.vector_id).unwrap();
+            let exact = squared_distance(&query, vectors.get(i));
+            let error = (exact - result.squared_distance).abs();
+            assert!(error <= result.error_bound.unwrap() + 1e-5);
+        }
+    }
+
+    #[test]
+    fn duplicates_can_be_detected_and_collapsed() {
+        let vs = BlockVectorSet::chunk(
+            vec![1.0f32, 2.0, 3.0, 4.0, 1.0, 2.0, 5.0, 6.0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let mut db = DatabaseBuilder::new(vs)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(1.try_into().unwrap())
+            .with_clusters(2.try_into().unwrap())
+            .with_duplicate_detection(0.0)
+            .build()
+            .unwrap();
+        let ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        assert_eq!(db.duplicate_groups(), &[vec![ids[0], ids[2]]]);
+        db.set_attribute_at(0, ("name", "first")).unwrap();
+        db.set_attribute_at(2, ("name", "second")).unwrap();
+        db.set_attribute_at(2, ("extra", 1u64)).unwrap();
+        assert_eq!(db.collapse_duplicates().unwrap(), 1);
+        assert_eq!(db.num_vectors(), 3);
+        assert!(db.duplicate_groups().is_empty());
+        assert_eq!(
+            db.get_attribute(&ids[0], "name").unwrap(),
+            Some(&AttributeValue::from("first")),
+        );
+        assert_eq!(
+            db.get_attribute(&ids[0], "extra").unwrap(),
+            Some(&AttributeValue::from(1u64)),
+        );
+    }
+
+    #[test]
+    fn remove_vectors_should_drop_vectors_from_every_index() {
+        let mut db = build_database(100, 4);
+        let ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        for i in 0..100 {
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let partition_indices = db.partitions.codebook.indices.clone();
+        let codebook_indices: Vec<Vec<usize>> = db.codebooks
+            .iter()
+            .map(|codebook| codebook.indices.clone())
+            .collect();
+        let residues: Vec<Vec<f32>> = db.partitions.residues
+            .iter()
+            .map(|(_, v)| v.to_vec())
+            .collect();
+
+        db.remove_vectors(&[ids[3], ids[50]]).unwrap();
+        db.remove_vector(&ids[99]).unwrap();
+        let kept: Vec<usize> = (0..100)
+            .filter(|i| ![3, 50, 99].contains(i))
+            .collect();
+        assert_eq!(db.num_vectors(), 97);
+        assert_eq!(
+            db.vector_ids().cloned().collect::<Vec<_>>(),
+            kept.iter().map(|&i| ids[i]).collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            db.partitions.codebook.indices,
+            kept.iter().map(|&i| partition_indices[i]).collect::<Vec<_>>(),
+        );
+        for (codebook, indices) in db.codebooks.iter().zip(&codebook_indices) {
+            assert_eq!(
+                codebook.indices,
+                kept.iter().map(|&i| indices[i]).collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(
+            db.partitions.residues
+                .iter()
+                .map(|(_, v)| v.to_vec())
+                .collect::<Vec<_>>(),
+            kept.iter().map(|&i| residues[i].clone()).collect::<Vec<_>>(),
+        );
+        assert_eq!(db.attribute_table.len(), 97);
+        for i in [3, 50, 99] {
+            assert!(matches!(
+                db.get_attribute(&ids[i], "index"),
+                Err(Error::InvalidArgs(_)),
+            ));
+        }
+        assert_eq!(
+            db.get_attribute(&ids[51], "index").unwrap(),
+            Some(&AttributeValue::from(51u64)),
+        );
+    }
+
+    #[test]
+    fn remove_vectors_should_remove_nothing_if_any_id_is_unknown() {
+        let mut db = build_database(100, 4);
+        let ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        db.set_attribute_at(0, ("index", 0u64)).unwrap();
+        let partition_indices = db.partitions.codebook.indices.clone();
+        assert!(matches!(
+            db.remove_vectors(&[ids[0], Uuid::new_v4(), ids[1]]),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert_eq!(db.num_vectors(), 100);
+        assert_eq!(db.vector_ids().cloned().collect::<Vec<_>>(), ids);
+        assert_eq!(db.partitions.codebook.indices, partition_indices);
+        assert!(db.codebooks.iter().all(|c| c.indices.len() == 100));
+        assert_eq!(db.partitions.residues.len(), 100);
+        assert_eq!(
+            db.get_attribute(&ids[0], "index").unwrap(),
+            Some(&AttributeValue::from(0u64)),
+        );
+
+        db.remove_vector(&ids[0]).unwrap();
+        assert!(matches!(
+            db.remove_vector(&ids[0]),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert_eq!(db.num_vectors(), 99);
+    }
+
+    #[test]
+    fn database_should_be_stored_and_loaded_after_removing_vectors() {
+        let vectors = synthetic_vectors(100, 4);
+        let mut db = build_database(100, 4);
+        let ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        for i in 0..100 {
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let removed = [ids[3], ids[50], ids[99]];
+        db.remove_vectors(&removed).unwrap();
+
+        let options = SerializeOptions::new().with_residues(true);
+        let (dir, header) = store_database(&db, &options);
+        let stored = load_database(&dir, &header);
+        assert_eq!(stored.num_vectors(), Some(97));
+        let results = stored
+            .query(
+                vectors.get(3),
+                100.try_into().unwrap(),
+                2.try_into().unwrap(),
+            )
+            .unwrap();
+        let built_results = db
+            .query(
+                vectors.get(3),
+                100.try_into().unwrap(),
+                2.try_into().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(results.len(), 97);
+        for (result, built) in results.iter().zip(built_results.iter()) {
+            assert_eq!(result.vector_id, built.vector_id);
+            assert!(!removed.contains(&result.vector_id));
+        }
+        for id in removed.iter() {
+            assert!(matches!(
+                stored.get_attribute(id, "index"),
+                Err(Error::InvalidArgs(_)),
+            ));
+        }
+        let value = stored.get_attribute(&ids[51], "index").unwrap().unwrap();
+        assert_eq!(*value, AttributeValue::from(51u64));
+    }
+
+    #[test]
+    fn estimated_memory_bytes_should_count_vectors_and_attributes() {
+        let vs = BlockVectorSet::chunk(
+            (0..64).map(|x| x as f32).collect(),
+            4.try_into().unwrap(),
+        ).unwrap();
+        let mut db = DatabaseBuilder::new(vs)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(2.try_into().unwrap())
+            .build()
+            .unwrap();
+        let before = db.estimated_memory_bytes();
+        assert!(before >= 64 * core::mem::size_of::<f32>());
+        db.set_attribute_at(0, ("name", "x".repeat(1000))).unwrap();
+        assert!(db.estimated_memory_bytes() >= before + 1000);
+    }
 }
@@ -0,0 +1,423 @@
+//! Imports rows of CSV or JSON Lines into a database.
+//!
+//! Every row has an embedding column and optional attribute columns.
+//! An embedding in CSV is a list of numbers separated by commas, semicolons,
+//! or whitespace, optionally enclosed in square brackets; e.g.,
+//! `"[0.1, 0.2, 0.3]"`. An embedding in JSON Lines is an array of numbers.
+
+use std::io::{BufRead, BufReader, Read};
+
+use crate::error::Error;
+use crate::io::WriteFileSystem;
+use crate::vector::BlockVectorSet;
+
+use super::proto::serialize_database;
+use super::{Attributes, AttributeValue, Database, DatabaseBuilder};
+
+/// How to coerce a column value into an [`AttributeValue`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coercion {
+    /// Into [`AttributeValue::String`].
+    ///
+    /// JSON numbers and booleans are converted into their textual forms.
+    String,
+    /// Into [`AttributeValue::Uint64`].
+    ///
+    /// Strings are parsed as decimal numbers.
+    Uint64,
+}
+
+/// Importer of rows.
+///
+/// Empty CSV fields, JSON nulls, and missing JSON fields leave the
+/// attribute unset.
+pub struct Importer {
+    // Name of the embedding column.
+    embedding_column: String,
+    // Attribute columns.
+    attribute_columns: Vec<AttributeColumn>,
+}
+
+// Mapping from a column to an attribute.
+struct AttributeColumn {
+    // Column name.
+    column: String,
+    // Attribute key.
+    key: String,
+    // Coercion of values.
+    coercion: Coercion,
+}
+
+impl Importer {
+    /// Creates an importer that reads embeddings from a given column.
+    pub fn new<S>(embedding_column: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            embedding_column: embedding_column.into(),
+            attribute_columns: Vec::new(),
+        }
+    }
+
+    /// Maps a column to an attribute.
+    pub fn with_attribute<C, K>(
+        mut self,
+        column: C,
+        key: K,
+        coercion: Coercion,
+    ) -> Self
+    where
+        C: Into<String>,
+        K: Into<String>,
+    {
+        self.attribute_columns.push(AttributeColumn {
+            column: column.into(),
+            key: key.into(),
+            coercion,
+        });
+        self
+    }
+
+    /// Reads rows in CSV with a header row.
+    ///
+    /// Fails if a mapped column is not in the header, or if a row has an
+    /// embedding of a different size or a value that cannot be coerced.
+    pub fn read_csv<R>(&self, reader: R) -> Result<ImportedRows, Error>
+    where
+        R: Read,
+    {
+        let mut reader = csv::Reader::from_reader(reader);
+        let headers = reader.headers().map_err(csv_error)?.clone();
+        let column_index = |column: &str| {
+            headers.iter().position(|h| h == column).ok_or_else(|| {
+                Error::InvalidArgs(format!("no such column: {}", column))
+            })
+        };
+        let embedding_index = column_index(&self.embedding_column)?;
+        let attribute_indices = self.attribute_columns
+            .iter()
+            .map(|c| column_index(&c.column))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut rows = RowCollector::default();
+        for (row, record) in reader.records().enumerate() {
+            let record = record.map_err(csv_error)?;
+            let embedding = parse_embedding(&record[embedding_index])
+                .map_err(|e| row_error(row, &self.embedding_column, e))?;
+            let mut attributes = Attributes::new();
+            let columns = self.attribute_columns.iter().zip(&attribute_indices);
+            for (c, &i) in columns {
+                let field = &record[i];
+                if field.is_empty() {
+                    continue;
+                }
+                let value = coerce_str(field, c.coercion)
+                    .map_err(|e| row_error(row, &c.column, e))?;
+                attributes.insert(c.key.clone(), value);
+            }
+            rows.push(row, embedding, attributes)?;
+        }
+        rows.finish()
+    }
+
+    /// Reads rows in JSON Lines.
+    ///
+    /// Every line must be a JSON object. Blank lines are skipped.
+    ///
+    /// Fails if a row has no embedding, an embedding of a different size,
+    /// or a value that cannot be coerced.
+    pub fn read_jsonl<R>(&self, reader: R) -> Result<ImportedRows, Error>
+    where
+        R: Read,
+    {
+        let mut rows = RowCollector::default();
+        for (row, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let object: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&line).map_err(|e| Error::InvalidData(
+                    format!("row {}: {}", row, e),
+                ))?;
+            let embedding = object.get(&self.embedding_column)
+                .ok_or_else(|| "missing embedding".to_string())
+                .and_then(json_embedding)
+                .map_err(|e| row_error(row, &self.embedding_column, e))?;
+            let mut attributes = Attributes::new();
+            for c in self.attribute_columns.iter() {
+                let value = match object.get(&c.column) {
+                    None | Some(serde_json::Value::Null) => continue,
+                    Some(value) => value,
+                };
+                let value = coerce_json(value, c.coercion)
+                    .map_err(|e| row_error(row, &c.column, e))?;
+                attributes.insert(c.key.clone(), value);
+            }
+            rows.push(row, embedding, attributes)?;
+        }
+        rows.finish()
+    }
+}
+
+/// Rows read by an [`Importer`].
+pub struct ImportedRows {
+    /// Embeddings.
+    pub vectors: BlockVectorSet<f32>,
+    /// Attributes of the rows.
+    pub attributes: Vec<Attributes>,
+}
+
+impl ImportedRows {
+    /// Builds a database of the rows.
+    ///
+    /// `configure` configures the builder; e.g., the number of partitions.
+    pub fn build<F>(
+        self,
+        configure: F,
+    ) -> Result<Database<f32, BlockVectorSet<f32>>, Error>
+    where
+        F: FnOnce(
+            DatabaseBuilder<f32, BlockVectorSet<f32>>,
+        ) -> DatabaseBuilder<f32, BlockVectorSet<f32>>,
+    {
+        let mut db = configure(DatabaseBuilder::new(self.vectors)).build()?;
+        for (i, attributes) in self.attributes.into_iter().enumerate() {
+            for attribute in attributes {
+                db.set_attribute_at(i, attribute)?;
+            }
+        }
+        Ok(db)
+    }
+
+    /// Builds a database of the rows and serializes it into a given file
+    /// system.
+    pub fn build_and_serialize<F, FS>(
+        self,
+        configure: F,
+        fs: &mut FS,
+    ) -> Result<Database<f32, BlockVectorSet<f32>>, Error>
+    where
+        F: FnOnce(
+            DatabaseBuilder<f32, BlockVectorSet<f32>>,
+        ) -> DatabaseBuilder<f32, BlockVectorSet<f32>>,
+        FS: WriteFileSystem,
+    {
+        let db = self.build(configure)?;
+        serialize_database(&db, fs)?;
+        Ok(db)
+    }
+}
+
+// Accumulates rows.
+#[derive(Default)]
+struct RowCollector {
+    // Concatenated embeddings.
+    data: Vec<f32>,
+    // Size of the first embedding.
+    vector_size: Option<usize>,
+    // Attributes of the rows.
+    attributes: Vec<Attributes>,
+}
+
+impl RowCollector {
+    fn push(
+        &mut self,
+        row: usize,
+        embedding: Vec<f32>,
+        attributes: Attributes,
+    ) -> Result<(), Error> {
+        match self.vector_size {
+            None => {
+                if embedding.is_empty() {
+                    return Err(Error::InvalidData(
+                        format!("row {}: empty embedding", row),
+                    ));
+                }
+                self.vector_size = Some(embedding.len());
+            },
+            Some(size) if size != embedding.len() => {
+                return Err(Error::InvalidData(format!(
+                    "row {}: embedding size {} does not match {}",
+                    row,
+                    embedding.len(),
+                    size,
+                )));
+            },
+            _ => {},
+        }
+        self.data.extend(embedding);
+        self.attributes.push(attributes);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<ImportedRows, Error> {
+        let vector_size = self.vector_size
+            .ok_or(Error::InvalidData("no rows".to_string()))?;
+        Ok(ImportedRows {
+            vectors: BlockVectorSet::chunk(
+                self.data,
+                vector_size.try_into().unwrap(),
+            )?,
+            attributes: self.attributes,
+        })
+    }
+}
+
+// Parses an embedding in CSV.
+fn parse_embedding(field: &str) -> Result<Vec<f32>, String> {
+    let field = field.trim();
+    let field = field.strip_prefix('[')
+        .and_then(|f| f.strip_suffix(']'))
+        .unwrap_or(field);
+    field.split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<f32>().map_err(|e| format!("{}: {}", s, e)))
+        .collect()
+}
+
+// Converts an embedding in JSON.
+fn json_embedding(value: &serde_json::Value) -> Result<Vec<f32>, String> {
+    value.as_array()
+        .ok_or_else(|| "embedding is not an array".to_string())?
+        .iter()
+        .map(|x| x.as_f64()
+            .map(|x| x as f32)
+            .ok_or_else(|| format!("not a number: {}", x)))
+        .collect()
+}
+
+fn coerce_str(
+    field: &str,
+    coercion: Coercion,
+) -> Result<AttributeValue, String> {
+    match coercion {
+        Coercion::String => Ok(AttributeValue::String(field.to_string())),
+        Coercion::Uint64 => field.trim()
+            .parse::<u64>()
+            .map(AttributeValue::Uint64)
+            .map_err(|e| format!("{}: {}", field, e)),
+    }
+}
+
+fn coerce_json(
+    value: &serde_json::Value,
+    coercion: Coercion,
+) -> Result<AttributeValue, String> {
+    match (value, coercion) {
+        (serde_json::Value::String(s), _) => coerce_str(s, coercion),
+        (serde_json::Value::Number(n), Coercion::Uint64) => n.as_u64()
+            .map(AttributeValue::Uint64)
+            .ok_or_else(|| format!("not an unsigned integer: {}", n)),
+        (serde_json::Value::Number(_), Coercion::String) |
+        (serde_json::Value::Bool(_), Coercion::String) => {
+            Ok(AttributeValue::String(value.to_string()))
+        },
+        _ => Err(format!("cannot coerce into {:?}: {}", coercion, value)),
+    }
+}
+
+fn row_error(row: usize, column: &str, message: String) -> Error {
+    Error::InvalidData(format!("row {}, column {}: {}", row, column, message))
+}
+
+fn csv_error(e: csv::Error) -> Error {
+    if e.is_io_error() {
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => Error::IOError(e),
+            _ => unreachable!(),
+        }
+    } else {
+        Error::InvalidData(format!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn importer() -> Importer {
+        Importer::new("embedding")
+            .with_attribute("id", "datum_id", Coercion::String)
+            .with_attribute("count", "count", Coercion::Uint64)
+    }
+
+    #[test]
+    fn importer_should_read_csv() {
+        let input = "\
+id,embedding,count
+a,\"[1.0, 2.0]\",3
+b,3.0 4.0,
+";
+        let rows = importer().read_csv(input.as_bytes()).unwrap();
+        assert_eq!(rows.vectors.get(0), &[1.0, 2.0]);
+        assert_eq!(rows.vectors.get(1), &[3.0, 4.0]);
+        assert_eq!(rows.attributes[0]["datum_id"], AttributeValue::from("a"));
+        assert_eq!(rows.attributes[0]["count"], AttributeValue::from(3u64));
+        assert_eq!(rows.attributes[1].get("count"), None);
+    }
+
+    #[test]
+    fn importer_should_read_jsonl() {
+        let input = r#"
+{"id": 1, "embedding": [1.0, 2.0], "count": "3"}
+{"id": "b", "embedding": [3, 4], "count": null}
+"#;
+        let rows = importer().read_jsonl(input.as_bytes()).unwrap();
+        assert_eq!(rows.vectors.get(1), &[3.0, 4.0]);
+        assert_eq!(rows.attributes[0]["datum_id"], AttributeValue::from("1"));
+        assert_eq!(rows.attributes[0]["count"], AttributeValue::from(3u64));
+        assert_eq!(rows.attributes[1].get("count"), None);
+    }
+
+    #[test]
+    fn importer_should_reject_inconsistent_embedding_sizes() {
+        let input = "id,embedding\na,1 2\nb,1 2 3\n";
+        assert!(matches!(
+            Importer::new("embedding").read_csv(input.as_bytes()),
+            Err(Error::InvalidData(_)),
+        ));
+    }
+
+    #[test]
+    fn importer_should_reject_value_that_cannot_be_coerced() {
+        let input = r#"{"embedding": [1.0], "count": -1}"#;
+        assert!(matches!(
+            importer().read_jsonl(input.as_bytes()),
+            Err(Error::InvalidData(_)),
+        ));
+    }
+
+    #[test]
+    fn importer_should_reject_missing_column() {
+        let input = "embedding\n1 2\n";
+        assert!(matches!(
+            importer().read_csv(input.as_bytes()),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn imported_rows_should_build_database_with_attributes() {
+        let input = (0..20)
+            .map(|i| format!(
+                "{{\"id\": {}, \"embedding\": [{}, {}]}}\n",
+                i,
+                i,
+                20 - i,
+            ))
+            .collect::<String>();
+        let rows = importer().read_jsonl(input.as_bytes()).unwrap();
+        let db = rows
+            .build(|b| b
+                .with_partitions(2.try_into().unwrap())
+                .with_divisions(1.try_into().unwrap())
+                .with_clusters(4.try_into().unwrap()))
+            .unwrap();
+        let id = db.vector_ids().nth(5).unwrap();
+        assert_eq!(
+            db.get_attribute(id, "datum_id").unwrap(),
+            Some(&AttributeValue::from("5")),
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! Ingests embeddings from a source into a database.
+//!
+//! An [`EmbeddingSource`] yields batches of items, each of which has an ID,
+//! an embedding, and attributes; e.g., a source may wrap a client of an
+//! embedding API. [`Ingestion`] pulls batches from a source while it collects
+//! the items pulled so far, but never lets more than a given number of
+//! batches wait for collection, so a fast source cannot run away with memory.
+
+#[cfg(feature = "async")]
+use async_trait::async_trait;
+use core::num::NonZeroUsize;
+use std::collections::HashSet;
+use std::sync::mpsc;
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::io::WriteFileSystem;
+use crate::vector::BlockVectorSet;
+
+use super::proto::serialize_database;
+use super::{Attributes, Database, DatabaseBuilder};
+
+/// Item yielded by an [`EmbeddingSource`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmbeddingItem {
+    /// ID of the vector.
+    pub id: Uuid,
+    /// Embedding.
+    pub vector: Vec<f32>,
+    /// Attributes of the vector.
+    pub attributes: Attributes,
+}
+
+/// Source of embeddings.
+pub trait EmbeddingSource {
+    /// Returns the next batch of at most `max_len` items.
+    ///
+    /// `None` if the source is exhausted.
+    fn next_batch(
+        &mut self,
+        max_len: usize,
+    ) -> Result<Option<Vec<EmbeddingItem>>, Error>;
+}
+
+/// Asynchronous source of embeddings.
+#[cfg(feature = "async")]
+#[async_trait]
+pub trait AsyncEmbeddingSource {
+    /// Returns the next batch of at most `max_len` items.
+    ///
+    /// `None` if the source is exhausted.
+    async fn next_batch(
+        &mut self,
+        max_len: usize,
+    ) -> Result<Option<Vec<EmbeddingItem>>, Error>;
+}
+
+/// Pipeline that pulls items from a source.
+#[derive(Clone, Debug)]
+pub struct Ingestion {
+    // Maximum number of items in a batch.
+    batch_size: usize,
+    // Maximum number of batches waiting for collection.
+    max_pending_batches: usize,
+}
+
+impl Default for Ingestion {
+    fn default() -> Self {
+        Self {
+            batch_size: 64,
+            max_pending_batches: 4,
+        }
+    }
+}
+
+impl Ingestion {
+    /// Creates a pipeline that pulls batches of 64 items with at most 4
+    /// pending batches.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of items in a batch.
+    pub fn with_batch_size(mut self, batch_size: NonZeroUsize) -> Self {
+        self.batch_size = batch_size.get();
+        self
+    }
+
+    /// Sets the maximum number of batches waiting for collection.
+    ///
+    /// The source is not asked for another batch until a pending batch is
+    /// collected.
+    pub fn with_max_pending_batches(mut self, n: NonZeroUsize) -> Self {
+        self.max_pending_batches = n.get();
+        self
+    }
+
+    /// Pulls all the items from a given source.
+    ///
+    /// The source runs on another thread.
+    ///
+    /// Fails if the source fails, the source yields no items, an embedding
+    /// is empty or its size differs from the first one, or an ID is
+    /// duplicate. The source is not asked for more batches once the
+    /// ingestion fails.
+    pub fn run<S>(&self, source: S) -> Result<IngestedItems, Error>
+    where
+        S: EmbeddingSource + Send,
+    {
+        let batch_size = self.batch_size;
+        let (tx, rx) = mpsc::sync_channel(self.max_pending_batches);
+        std::thread::scope(|scope| {
+            let producer = scope.spawn(move || {
+                let mut source = source;
+                while let Some(batch) = source.next_batch(batch_size)? {
+                    if tx.send(batch).is_err() {
+                        // collection has failed
+                        break;
+                    }
+                }
+                Ok::<(), Error>(())
+            });
+            let mut items = ItemCollector::default();
+            let collected = rx
+                .iter()
+                .flatten()
+                .try_for_each(|item| items.push(item));
+            // unblocks the producer if collection has failed
+            drop(rx);
+            let produced = producer.join().expect("source must not panic");
+            produced?;
+            collected?;
+            items.finish()
+        })
+    }
+
+    /// Pulls all the items from a given asynchronous source.
+    ///
+    /// The source runs concurrently on the calling task.
+    ///
+    /// Fails in the same cases as [`Ingestion::run`].
+    #[cfg(feature = "async")]
+    pub async fn run_async<S>(
+        &self,
+        mut source: S,
+    ) -> Result<IngestedItems, Error>
+    where
+        S: AsyncEmbeddingSource,
+    {
+        let batch_size = self.batch_size;
+        let (tx, mut rx) =
+            tokio::sync::mpsc::channel(self.max_pending_batches);
+        let producer = async move {
+            while let Some(batch) = source.next_batch(batch_size).await? {
+                if tx.send(batch).await.is_err() {
+                    // collection has failed
+                    break;
+                }
+            }
+            Ok::<(), Error>(())
+        };
+        let consumer = async move {
+            let mut items = ItemCollector::default();
+            while let Some(batch) = rx.recv().await {
+                for item in batch {
+                    items.push(item)?;
+                }
+            }
+            Ok::<_, Error>(items)
+        };
+        let (produced, collected) = tokio::join!(producer, consumer);
+        produced?;
+        collected?.finish()
+    }
+}
+
+/// Items pulled by an [`Ingestion`].
+pub struct IngestedItems {
+    /// Embeddings.
+    pub vectors: BlockVectorSet<f32>,
+    /// IDs of the vectors.
+    pub vector_ids: Vec<Uuid>,
+    /// Attributes of the vectors.
+    pub attributes: Vec<Attributes>,
+}
+
+impl IngestedItems {
+    /// Builds a database of the items.
+    ///
+    /// `configure` configures the builder; e.g., the number of partitions.
+    /// The vectors keep the IDs of the items.
+    pub fn build<F>(
+        self,
+        configure: F,
+    ) -> Result<Database<f32, BlockVectorSet<f32>>, Error>
+    where
+        F: FnOnce(
+            DatabaseBuilder<f32, BlockVectorSet<f32>>,
+        ) -> DatabaseBuilder<f32, BlockVectorSet<f32>>,
+    {
+        let builder = DatabaseBuilder::new(self.vectors);
+        let mut db = configure(builder)
+            .with_vector_ids(self.vector_ids)
+            .build()?;
+        for (i, attributes) in self.attributes.into_iter().enumerate() {
+            for attribute in attributes {
+                db.set_attribute_at(i, attribute)?;
+            }
+        }
+        Ok(db)
+    }
+
+    /// Builds a database of the items and serializes it into a given file
+    /// system.
+    pub fn build_and_serialize<F, FS>(
+        self,
+        configure: F,
+        fs: &mut FS,
+    ) -> Result<Database<f32, BlockVectorSet<f32>>, Error>
+    where
+        F: FnOnce(
+            DatabaseBuilder<f32, BlockVectorSet<f32>>,
+        ) -> DatabaseBuilder<f32, BlockVectorSet<f32>>,
+        FS: WriteFileSystem,
+    {
+        let db = self.build(configure)?;
+        serialize_database(&db, fs)?;
+        Ok(db)
+    }
+}
+
+// Accumulates items.
+#[derive(Default)]
+struct ItemCollector {
+    // Concatenated embeddings.
+    data: Vec<f32>,
+    // Size of the first embedding.
+    vector_size: Option<usize>,
+    // IDs of the items.
+    vector_ids: Vec<Uuid>,
+    // Set of `vector_ids` to find duplicates.
+    unique_ids: HashSet<Uuid>,
+    // Attributes of the items.
+    attributes: Vec<Attributes>,
+}
+
+impl ItemCollector {
+    fn push(&mut self, item: EmbeddingItem) -> Result<(), Error> {
+        let i = self.vector_ids.len();
+        match self.vector_size {
+            None => {
+                if item.vector.is_empty() {
+                    return Err(Error::InvalidData(
+                        format!("item {}: empty embedding", i),
+                    ));
+                }
+                self.vector_size = Some(item.vector.len());
+            },
+            Some(size) if size != item.vector.len() => {
+                return Err(Error::InvalidData(format!(
+                    "item {}: embedding size {} does not match {}",
+                    i,
+                    item.vector.len(),
+                    size,
+                )));
+            },
+            _ => {},
+        }
+        if !self.unique_ids.insert(item.id) {
+            return Err(Error::InvalidData(
+                format!("item {}: duplicate ID {}", i, item.id),
+            ));
+        }
+        self.data.extend(item.vector);
+        self.vector_ids.push(item.id);
+        self.attributes.push(item.attributes);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<IngestedItems, Error> {
+        let vector_size = self.vector_size
+            .ok_or(Error::InvalidData("no items".to_string()))?;
+        Ok(IngestedItems {
+            vectors: BlockVectorSet::chunk(
+                self.data,
+                vector_size.try_into().unwrap(),
+            )?,
+            vector_ids: self.vector_ids,
+            attributes: self.attributes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::db::AttributeValue;
+
+    // Source of `len` items that records the size of every batch it yields.
+    struct CountingSource {
+        len: usize,
+        next: usize,
+        sent: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl CountingSource {
+        fn new(len: usize) -> Self {
+            Self {
+                len,
+                next: 0,
+                sent: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+
+        fn next_items(&mut self, max_len: usize) -> Option<Vec<EmbeddingItem>> {
+            if self.next == self.len {
+                return None;
+            }
+            let end = (self.next + max_len).min(self.len);
+            let items = (self.next..end)
+                .map(|i| EmbeddingItem {
+                    id: Uuid::from_u128(i as u128 + 1),
+                    vector: vec![
+                        i as f32,
+                        (i % 7) as f32,
+                        (i % 3) as f32,
+                        (i % 5) as f32,
+                    ],
+                    attributes: Attributes::from([
+                        ("index".to_string(), AttributeValue::from(i as u64)),
+                    ]),
+                })
+                .collect();
+            self.sent.lock().unwrap().push(end - self.next);
+            self.next = end;
+            Some(items)
+        }
+    }
+
+    impl EmbeddingSource for CountingSource {
+        fn next_batch(
+            &mut self,
+            max_len: usize,
+        ) -> Result<Option<Vec<EmbeddingItem>>, Error> {
+            Ok(self.next_items(max_len))
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[async_trait]
+    impl AsyncEmbeddingSource for CountingSource {
+        async fn next_batch(
+            &mut self,
+            max_len: usize,
+        ) -> Result<Option<Vec<EmbeddingItem>>, Error> {
+            tokio::task::yield_now().await;
+            Ok(self.next_items(max_len))
+        }
+    }
+
+    #[test]
+    fn ingestion_should_pull_all_items_in_batches() {
+        let source = CountingSource::new(50);
+        let sent = source.sent.clone();
+        let items = Ingestion::new()
+            .with_batch_size(8.try_into().unwrap())
+            .with_max_pending_batches(1.try_into().unwrap())
+            .run(source)
+            .unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![8, 8, 8, 8, 8, 8, 2]);
+        assert_eq!(items.vectors.len(), 50);
+        assert_eq!(items.vector_ids[49], Uuid::from_u128(50));
+        let db = items
+            .build(|builder| builder
+                .with_partitions(2.try_into().unwrap())
+                .with_divisions(2.try_into().unwrap())
+                .with_clusters(4.try_into().unwrap()))
+            .unwrap();
+        assert_eq!(
+            db.get_attribute(&Uuid::from_u128(50), "index").unwrap(),
+            Some(&AttributeValue::from(49u64)),
+        );
+    }
+
+    #[test]
+    fn ingestion_should_stop_pulling_items_once_it_fails() {
+        // inconsistent embedding sizes
+        struct BadSource(usize);
+        impl EmbeddingSource for BadSource {
+            fn next_batch(
+                &mut self,
+                _max_len: usize,
+            ) -> Result<Option<Vec<EmbeddingItem>>, Error> {
+                self.0 += 1;
+                Ok(Some(vec![EmbeddingItem {
+                    id: Uuid::new_v4(),
+                    vector: vec![0.0; self.0],
+                    attributes: Attributes::new(),
+                }]))
+            }
+        }
+        assert!(matches!(
+            Ingestion::new().run(BadSource(0)),
+            Err(Error::InvalidData(_)),
+        ));
+        assert!(Ingestion::new().run(CountingSource::new(0)).is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_ingestion_should_pull_all_items_in_batches() {
+        let source = CountingSource::new(20);
+        let sent = source.sent.clone();
+        let items = Ingestion::new()
+            .with_batch_size(8.try_into().unwrap())
+            .run_async(source)
+            .await
+            .unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![8, 8, 4]);
+        assert_eq!(items.vector_ids.len(), 20);
+        assert_eq!(items.attributes[19]["index"], AttributeValue::from(19u64));
+    }
+}
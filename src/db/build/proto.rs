@@ -1,11 +1,32 @@
 //! [`Database`] into Protocol Buffers data.
 
+use core::cell::RefCell;
 use core::iter::IntoIterator;
-use std::collections::BTreeSet;
+use core::num::NonZeroUsize;
+use protobuf::Message;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use uuid::Uuid;
 
+use crate::db::{AttributeValue, Attributes, CompressionPolicy, FileKind};
+use crate::db::manifest::CURRENT;
+use crate::db::proto::{
+    record_file_codecs,
+    serialize_hash_algorithm,
+    serialize_quantization,
+};
 use crate::error::Error;
-use crate::io::{FileSystem, HashedFileOut};
-use crate::kmeans::Codebook;
+use crate::io::codec::{Codec, DEFAULT_COMPRESSION_LEVEL};
+use crate::io::hash::HashAlgorithm;
+use crate::io::merkle::{MerkleHasher, MerkleTree};
+use crate::io::{
+    CompressedHashedFileOut,
+    HashedFileOut,
+    IoProgress,
+    ProgressHashedFileOut,
+    WriteFileSystem,
+};
+use crate::kmeans::{Codebook, EventControl, Scalar};
 use crate::protos::database::{
     AttributesLog as ProtosAttributesLog,
     Database as ProtosDatabase,
@@ -13,136 +34,532 @@ use crate::protos::database::{
     Partition as ProtosPartition,
     VectorSet as ProtosVectorSet,
 };
-use crate::partitions::Partitions;
+use crate::partitions::{Partitioning, Partitions};
 use crate::protos::{Serialize, write_message};
-use crate::vector::{BlockVectorSet, VectorSet};
-use super::{Database, Partition};
+use crate::slice::AsSlice;
+use crate::vector::{BlockVectorSet, EncodedVectorSet, VectorSet};
+use crate::vector::proto::serialize_packed;
+use super::{
+    BuildEvent,
+    BuildStage,
+    Database,
+    DatabaseBuilder,
+    Partition,
+    PartitionIter,
+};
 
 /// Extension of a Protocol Buffers file.
 pub const PROTOBUF_EXTENSION: &str = "binpb";
 
+/// Options for serializing a [`Database`].
+#[derive(Clone, Debug)]
+pub struct SerializeOptions {
+    include_residues: bool,
+    original_vectors: bool,
+    compression: CompressionPolicy,
+    compression_level: u32,
+    codec: Codec,
+    hash_algorithm: HashAlgorithm,
+    chunk_size: Option<NonZeroUsize>,
+    publishing: bool,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> Self {
+        Self {
+            include_residues: false,
+            original_vectors: false,
+            compression: CompressionPolicy::default(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            codec: Codec::default(),
+            hash_algorithm: HashAlgorithm::default(),
+            chunk_size: None,
+            publishing: false,
+        }
+    }
+}
+
+impl SerializeOptions {
+    /// Creates default options.
+    ///
+    /// Residues are not persisted by default. Files are compressed according
+    /// to the default [`CompressionPolicy`] with [`Codec::Zlib`] at level 6.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets which kinds of files are compressed.
+    pub fn with_compression(mut self, compression: CompressionPolicy) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the compression level.
+    ///
+    /// Ranges from 0 to [`Codec::max_level`] of the codec; i.e., 9 for
+    /// Zlib. Lower levels build faster, and higher levels produce smaller
+    /// files. 0 means no compression for [`Codec::Zlib`], and the default
+    /// level of the other codecs. Serialization fails if `level` exceeds
+    /// the maximum level.
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Sets the codec of compressed files.
+    ///
+    /// Loaders detect the codec of each file, so the codec is not recorded
+    /// in the database. Serialization fails if `codec` is not available in
+    /// this build.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets the algorithm of the content hashes of files.
+    ///
+    /// The algorithm is recorded in the database header, which is always
+    /// hashed with SHA-256. Serialization fails if `algorithm` is not
+    /// available in this build, or the file system does not support it.
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Sets the chunk size of the Merkle trees recorded for files.
+    ///
+    /// If `chunk_size` is given, the database header records a
+    /// [`MerkleTree`] over chunks of every file the database refers to, and
+    /// loaders verify those files chunk by chunk, failing at the first
+    /// corrupted chunk.
+    /// [`DEFAULT_CHUNK_SIZE`](crate::io::merkle::DEFAULT_CHUNK_SIZE) is a
+    /// reasonable choice. No trees are recorded by default. Serialization
+    /// fails if `chunk_size` exceeds `u32::MAX`.
+    pub fn with_chunk_size(mut self, chunk_size: Option<NonZeroUsize>) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Sets whether residue vectors are persisted.
+    ///
+    /// Residues allow exact distance verification and retraining without
+    /// the original dataset, at the cost of storing full-precision vectors.
+    pub fn with_residues(mut self, include_residues: bool) -> Self {
+        self.include_residues = include_residues;
+        self
+    }
+
+    /// Sets whether original vectors are persisted instead of residues.
+    ///
+    /// Takes effect only if residues are persisted; see
+    /// [`with_residues`](Self::with_residues). Original vectors save adding
+    /// partition centroids when vectors are reconstructed; e.g., to re-rank
+    /// query results exactly. They are in the space the database indexes;
+    /// i.e., projected if the database has a projection. Residues are
+    /// persisted by default.
+    pub fn with_original_vectors(mut self, original_vectors: bool) -> Self {
+        self.original_vectors = original_vectors;
+        self
+    }
+
+    /// Sets whether the database is published as the current version.
+    ///
+    /// If `publishing` is `true`, [`CURRENT`] is replaced with the database
+    /// header after every file has been written, so that readers of the
+    /// current version switch to the database at once. The file system has
+    /// to support [`WriteFileSystem::write_pointer`]. Not published by
+    /// default.
+    pub fn with_publishing(mut self, publishing: bool) -> Self {
+        self.publishing = publishing;
+        self
+    }
+}
+
 /// Serializes [`Database`].
 pub fn serialize_database<'a, T, VS, FS>(
     db: &'a Database<T, VS>,
     fs: &mut FS,
 ) -> Result<(), Error>
 where
-    T: Clone,
+    T: Scalar,
+    VS: VectorSet<T>,
+    DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
+    Partition<T>: Serialize<ProtosPartition>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem,
+{
+    serialize_database_with_options(db, fs, &SerializeOptions::default())
+}
+
+/// Serializes [`Database`] with options.
+pub fn serialize_database_with_options<'a, T, VS, FS>(
+    db: &'a Database<T, VS>,
+    fs: &mut FS,
+    options: &SerializeOptions,
+) -> Result<(), Error>
+where
+    T: Scalar,
     VS: VectorSet<T>,
     DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
     Partition<T>: Serialize<ProtosPartition>,
     BlockVectorSet<T>: Serialize<ProtosVectorSet>,
-    FS: FileSystem,
+    FS: WriteFileSystem,
 {
-    // serializes partitions
-    let partition_ids = serialize_partitions(db.partitions(), fs)?;
-    // serializes partition centroids
-    let partition_centroids_id =
-        serialize_partition_centroids(&db.partitions, fs)?;
-    // serializes codebooks
-    let codebook_ids = serialize_codebooks(&db.codebooks, fs)?;
-    // sorts attribute names
+    serialize_database_with_events(db, fs, options, |_| {})
+}
+
+/// Events from [`serialize_database_with_events`].
+#[derive(Debug)]
+pub enum SerializeEvent {
+    /// Starting to write a file of a given kind.
+    StartingFile(FileKind),
+    /// Wrote bytes of a file of a given kind.
+    ///
+    /// Bytes are counted before compression, and the total is the size of
+    /// the serialized message.
+    WritingFile(FileKind, IoProgress),
+    /// Finished writing a file of a given kind.
+    FinishedFile(FileKind),
+}
+
+/// Serializes [`Database`] with options and events.
+///
+/// `event` is notified of the progress of writing every file, so that an
+/// application can show the progress of serializing a large database.
+pub fn serialize_database_with_events<'a, T, VS, FS, EventHandler>(
+    db: &'a Database<T, VS>,
+    fs: &mut FS,
+    options: &SerializeOptions,
+    mut event: EventHandler,
+) -> Result<(), Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
+    Partition<T>: Serialize<ProtosPartition>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
+{
+    let mut serializer = DatabaseSerializer::new(db, options)?;
+    while serializer.write_next(fs, &mut event)? {}
+    Ok(())
+}
+
+/// Builds a [`Database`] and serializes it at the same time.
+///
+/// Partition centroids and residues are written while codebooks are being
+/// trained, and every codebook is written as soon as its training finishes.
+/// Partitions and the database itself are written after the build, because
+/// every partition holds codes from all the codebooks. So the total time is
+/// shorter than [`DatabaseBuilder::build`] followed by
+/// [`serialize_database_with_options`].
+///
+/// Returns the built database. Attributes set to the returned database are
+/// not serialized; serialize the database again to persist them.
+pub fn build_and_serialize_database<T, VS, FS>(
+    builder: DatabaseBuilder<T, VS>,
+    fs: &mut FS,
+    options: &SerializeOptions,
+) -> Result<Database<T, VS>, Error>
+where
+    T: Scalar + Send + Sync,
+    VS: VectorSet<T> + Partitioning<T, VS> + Sync,
+    for<'a> DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
+    Partition<T>: Serialize<ProtosPartition>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem,
+{
+    build_and_serialize_database_with_events(
+        builder,
+        fs,
+        options,
+        |_| {},
+        |_| {},
+    )
+}
+
+/// Builds a [`Database`] and serializes it at the same time with events.
+///
+/// `build_event` is notified as in [`DatabaseBuilder::build_with_events`]
+/// and may cancel the build. `serialize_event` is notified as in
+/// [`serialize_database_with_events`]. Both are called on the calling
+/// thread.
+///
+/// See [`build_and_serialize_database`] for details.
+pub fn build_and_serialize_database_with_events<
+    T,
+    VS,
+    FS,
+    BuildEventHandler,
+    C,
+    SerializeEventHandler,
+>(
+    builder: DatabaseBuilder<T, VS>,
+    fs: &mut FS,
+    options: &SerializeOptions,
+    build_event: BuildEventHandler,
+    mut serialize_event: SerializeEventHandler,
+) -> Result<Database<T, VS>, Error>
+where
+    T: Scalar + Send + Sync,
+    VS: VectorSet<T> + Partitioning<T, VS> + Sync,
+    for<'a> DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
+    Partition<T>: Serialize<ProtosPartition>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem,
+    BuildEventHandler: FnMut(BuildEvent<'_, T>) -> C,
+    C: EventControl,
+    SerializeEventHandler: FnMut(SerializeEvent),
+{
+    check_options(options)?;
+    let event = &mut serialize_event;
+    let chunk_trees = RefCell::new(BTreeMap::new());
+    let mut chunk_fs =
+        ChunkDigestFileSystem::new(&*fs, options.chunk_size, &chunk_trees);
+    let mut partition_centroids_id = String::new();
+    let mut residues_ids: Vec<String> = Vec::new();
+    let mut codebook_ids: Vec<String> = Vec::new();
+    let db = builder.build_with_stages(build_event, |stage| {
+        match stage {
+            BuildStage::Partitioned(partitions) => {
+                partition_centroids_id = serialize_partition_centroids(
+                    partitions,
+                    &chunk_fs,
+                    options,
+                    event,
+                )?;
+                if options.include_residues {
+                    residues_ids = serialize_residues(
+                        partitions,
+                        &mut chunk_fs,
+                        options,
+                        event,
+                    )?;
+                }
+            },
+            BuildStage::Quantized(i, codebook) => {
+                // codebooks come in the order of divisions
+                debug_assert_eq!(i, codebook_ids.len());
+                codebook_ids.push(serialize_codebook(
+                    codebook,
+                    &mut chunk_fs,
+                    options,
+                    event,
+                )?);
+            },
+        };
+        Ok(())
+    })?;
+    let partition_ids = serialize_partitions(
+        db.partitions(),
+        &mut chunk_fs,
+        options,
+        event,
+    )?;
     let attribute_names = get_sorted_attribute_names(&db);
-    // serializes attributes
-    let attributes_log_ids =
-        serialize_attribute_table(&db, &partition_ids, &attribute_names, fs)?;
-    // serializes the database
-    let db = DatabaseSerialize {
-        database: db,
+    let attributes_log_ids = serialize_attribute_table(
+        &db,
+        &partition_ids,
+        &attribute_names,
+        &mut chunk_fs,
+        options,
+        event,
+    )?;
+    let header = DatabaseSerialize {
+        database: &db,
         partition_ids,
         partition_centroids_id,
         codebook_ids,
         attributes_log_ids,
         attribute_names,
+        residues_ids,
+        original_vectors: options.original_vectors,
+        compression: options.compression,
+        codec: options.codec,
+        hash_algorithm: options.hash_algorithm,
+        chunk_trees: chunk_trees.take(),
     };
-    let db = db.serialize()?;
-    let mut f = fs.create_compressed_hashed_file()?;
-    write_message(&db, &mut f)?;
-    f.persist(PROTOBUF_EXTENSION)?;
+    let header = header.serialize()?;
+    let f = fs.create_hashed_file()?;
+    let id =
+        write_message_file(&header, f, FileKind::Database, options, event)?;
+    publish_header(fs, &id, options)?;
+    Ok(db)
+}
+
+// Checks if options are valid.
+fn check_options(options: &SerializeOptions) -> Result<(), Error> {
+    options.codec.check_available()?;
+    options.hash_algorithm.check_available()?;
+    if let Some(chunk_size) = options.chunk_size {
+        if chunk_size.get() > u32::MAX as usize {
+            return Err(Error::InvalidArgs(format!(
+                "chunk size must be ≤ {} but got {}",
+                u32::MAX,
+                chunk_size,
+            )));
+        }
+    }
+    options.codec.check_level(options.compression_level)
+}
+
+// Replaces the pointer to the current version with a database header of a
+// given ID if `options` specifies so.
+fn publish_header<FS>(
+    fs: &FS,
+    id: &str,
+    options: &SerializeOptions,
+) -> Result<(), Error>
+where
+    FS: WriteFileSystem,
+{
+    if options.publishing {
+        fs.write_pointer(CURRENT, format!("{}.{}", id, PROTOBUF_EXTENSION))?;
+    }
     Ok(())
 }
 
+// Writes a message to a file notifying the progress.
+//
+// Compresses the file if `options` specifies so for `kind`.
+//
+// Returns the ID of the file.
+pub(crate) fn write_message_file<M, W, EventHandler>(
+    message: &M,
+    f: W,
+    kind: FileKind,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
+) -> Result<String, Error>
+where
+    M: Message,
+    W: HashedFileOut,
+    EventHandler: FnMut(SerializeEvent),
+{
+    if options.compression.is_compressed(kind) {
+        let f = CompressedHashedFileOut::with_codec(
+            f,
+            options.codec,
+            options.compression_level,
+        )?;
+        write_message_file_as_is(message, f, kind, event)
+    } else {
+        write_message_file_as_is(message, f, kind, event)
+    }
+}
+
+// Writes a message to a given file notifying the progress.
+fn write_message_file_as_is<M, W, EventHandler>(
+    message: &M,
+    f: W,
+    kind: FileKind,
+    event: &mut EventHandler,
+) -> Result<String, Error>
+where
+    M: Message,
+    W: HashedFileOut,
+    EventHandler: FnMut(SerializeEvent),
+{
+    event(SerializeEvent::StartingFile(kind));
+    let total = message.compute_size();
+    let mut f = ProgressHashedFileOut::new(f, Some(total), |progress| {
+        event(SerializeEvent::WritingFile(kind, progress));
+    });
+    write_message(message, &mut f)?;
+    let id = f.persist(PROTOBUF_EXTENSION)?;
+    event(SerializeEvent::FinishedFile(kind));
+    Ok(id)
+}
+
 // Serializes partitions.
-fn serialize_partitions<I, T, FS>(
+fn serialize_partitions<I, T, FS, EventHandler>(
     partitions: I,
     fs: &mut FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
 ) -> Result<Vec<String>, Error>
 where
     I: IntoIterator<Item = Partition<T>>,
-    T: Clone,
+    T: Scalar,
     Partition<T>: Serialize<ProtosPartition>,
-    FS: FileSystem,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
 {
     let mut partition_ids: Vec<String> = Vec::new();
     for partition in partitions {
-        let partition_id = serialize_partition(&partition, fs)?;
+        let partition_id = serialize_partition(&partition, fs, options, event)?;
         partition_ids.push(partition_id);
     }
     Ok(partition_ids)
 }
 
 // Serializes a partition.
-fn serialize_partition<T, FS>(
+fn serialize_partition<T, FS, EventHandler>(
     partition: &Partition<T>,
     fs: &mut FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
 ) -> Result<String, Error>
 where
-    T: Clone,
+    T: Scalar,
     Partition<T>: Serialize<ProtosPartition>,
-    FS: FileSystem,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
 {
     let partition = partition.serialize()?;
-    let mut f = fs.create_compressed_hashed_file_in("partitions")?;
-    write_message(&partition, &mut f)?;
-    f.persist(PROTOBUF_EXTENSION)
+    let f = fs.create_hashed_file_in_with(
+        "partitions",
+        options.hash_algorithm,
+    )?;
+    write_message_file(&partition, f, FileKind::Partition, options, event)
 }
 
 // Serializes the partition centroids.
-fn serialize_partition_centroids<T, VS, FS>(
+fn serialize_partition_centroids<T, VS, FS, EventHandler>(
     partitions: &Partitions<T, VS>,
     fs: &FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
 ) -> Result<String, Error>
 where
     BlockVectorSet<T>: Serialize<ProtosVectorSet>,
-    FS: FileSystem,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
 {
     let partition_centroids: ProtosVectorSet =
         partitions.codebook.centroids.serialize()?;
-    let mut f = fs.create_hashed_file_in("partitions")?;
-    write_message(&partition_centroids, &mut f)?;
-    f.persist(PROTOBUF_EXTENSION)
-}
-
-// Serializes codebooks.
-fn serialize_codebooks<T, FS>(
-    codebooks: &Vec<Codebook<T>>,
-    fs: &mut FS,
-) -> Result<Vec<String>, Error>
-where
-    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
-    FS: FileSystem,
-{
-    let mut codebook_ids = Vec::with_capacity(codebooks.len());
-    for codebook in codebooks {
-        let codebook_id = serialize_codebook(codebook, fs)?;
-        codebook_ids.push(codebook_id);
-    }
-    Ok(codebook_ids)
+    let f = fs.create_hashed_file_in_with(
+        "partitions",
+        options.hash_algorithm,
+    )?;
+    write_message_file(
+        &partition_centroids,
+        f,
+        FileKind::PartitionCentroids,
+        options,
+        event,
+    )
 }
 
 // Serializes a codebook.
-fn serialize_codebook<T, FS>(
+fn serialize_codebook<T, FS, EventHandler>(
     codebook: &Codebook<T>,
     fs: &mut FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
 ) -> Result<String, Error>
 where
     BlockVectorSet<T>: Serialize<ProtosVectorSet>,
-    FS: FileSystem,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
 {
     let codebook = codebook.centroids.serialize()?;
-    let mut f = fs.create_hashed_file_in("codebooks")?;
-    write_message(&codebook, &mut f)?;
-    f.persist(PROTOBUF_EXTENSION)
+    let f = fs.create_hashed_file_in_with(
+        "codebooks",
+        options.hash_algorithm,
+    )?;
+    write_message_file(&codebook, f, FileKind::Codebook, options, event)
 }
 
 // Obtains the sorted attribute names from a database.
@@ -160,48 +577,473 @@ where
 // Serializes an attribute table.
 //
 // `attribute_names` must be sorted.
-fn serialize_attribute_table<T, VS, FS>(
+fn serialize_attribute_table<T, VS, FS, EventHandler>(
     db: &Database<T, VS>,
-    partition_ids: &Vec<String>,
-    attribute_names: &Vec<String>,
+    partition_ids: &[String],
+    attribute_names: &[String],
     fs: &mut FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
 ) -> Result<Vec<String>, Error>
 where
     VS: VectorSet<T>,
-    FS: FileSystem,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
 {
     assert_eq!(db.num_partitions(), partition_ids.len());
     let mut attributes_log_ids: Vec<String> =
         Vec::with_capacity(db.num_partitions());
     for (pi, partition_id) in partition_ids.iter().enumerate() {
-        let mut attributes_log = ProtosAttributesLog::new();
-        attributes_log.partition_id = partition_id.clone();
-        attributes_log.entries.reserve(db.vector_ids.len());
-        for (_, id) in db.vector_ids
-            .iter()
-            .enumerate()
-            .filter(|(vi, _)| db.partitions.codebook.indices[*vi] == pi)
-        {
-            if let Some(attributes) = db.attribute_table.get(id) {
-                for (name, value) in attributes.iter() {
-                    let mut set_attribute = ProtosOperationSetAttribute::new();
-                    set_attribute.vector_id = Some(id.serialize()?).into();
-                    set_attribute.name_index = attribute_names
-                        .binary_search(name)
-                        .or(Err(Error::InvalidContext(format!(
-                            "attribute name must be encoded: {}",
-                            name,
-                        ))))? as u32;
-                    set_attribute.value = Some(value.serialize()?).into();
-                    attributes_log.entries.push(set_attribute);
-                }
+        attributes_log_ids.push(serialize_attributes_log(
+            db,
+            pi,
+            partition_id,
+            attribute_names,
+            fs,
+            options,
+            event,
+        )?);
+    }
+    Ok(attributes_log_ids)
+}
+
+// Serializes the attributes log of a partition.
+fn serialize_attributes_log<T, VS, FS, EventHandler>(
+    db: &Database<T, VS>,
+    partition_index: usize,
+    partition_id: &str,
+    attribute_names: &[String],
+    fs: &FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
+) -> Result<String, Error>
+where
+    VS: VectorSet<T>,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
+{
+    let attributes: Vec<_> = db.vector_ids
+        .iter()
+        .enumerate()
+        .filter(|(vi, _)| {
+            db.partitions.codebook.indices[*vi] == partition_index
+        })
+        .filter_map(|(_, id)| {
+            db.attribute_table.get(id).map(|attributes| (id, attributes))
+        })
+        .collect();
+    let attributes_log = encode_attributes_log(
+        partition_id,
+        &attributes,
+        attribute_names,
+    )?;
+    let f = fs.create_hashed_file_in_with(
+        "attributes",
+        options.hash_algorithm,
+    )?;
+    write_message_file(
+        &attributes_log,
+        f,
+        FileKind::AttributesLog,
+        options,
+        event,
+    )
+}
+
+// Encodes attributes of vectors in a partition into an attributes log.
+//
+// `attribute_names` must be sorted.
+pub(crate) fn encode_attributes_log(
+    partition_id: &str,
+    attributes: &[(&Uuid, &Attributes)],
+    attribute_names: &[String],
+) -> Result<ProtosAttributesLog, Error> {
+    let mut attributes_log = ProtosAttributesLog::new();
+    attributes_log.partition_id = partition_id.to_string();
+    attributes_log.entries.reserve(
+        attributes.iter().map(|(_, attributes)| attributes.len()).sum(),
+    );
+    // values appearing more than once go to the value table
+    let mut value_counts: HashMap<&AttributeValue, usize> = HashMap::new();
+    for (_, attributes) in attributes.iter() {
+        for value in attributes.values() {
+            *value_counts.entry(value).or_insert(0) += 1;
+        }
+    }
+    let mut value_indices: HashMap<&AttributeValue, u32> = HashMap::new();
+    for (_, attributes) in attributes.iter() {
+        for value in attributes.values() {
+            if value_counts[value] > 1 && !value_indices.contains_key(value) {
+                value_indices.insert(
+                    value,
+                    attributes_log.value_table.len() as u32,
+                );
+                attributes_log.value_table.push(value.serialize()?);
             }
         }
-        let mut f = fs.create_compressed_hashed_file_in("attributes")?;
-        write_message(&attributes_log, &mut f)?;
-        attributes_log_ids.push(f.persist(PROTOBUF_EXTENSION)?);
     }
-    Ok(attributes_log_ids)
+    for (id, attributes) in attributes {
+        for (name, value) in attributes.iter() {
+            let mut set_attribute = ProtosOperationSetAttribute::new();
+            set_attribute.vector_id = Some(id.serialize()?).into();
+            set_attribute.name_index =
+                encode_attribute_name(attribute_names, name)?;
+            match value_indices.get(value) {
+                Some(&index) => set_attribute.value_index = index,
+                None => {
+                    set_attribute.value = Some(value.serialize()?).into();
+                },
+            };
+            attributes_log.entries.push(set_attribute);
+        }
+    }
+    Ok(attributes_log)
+}
+
+// Encodes an attribute name as the index in sorted `attribute_names`.
+pub(crate) fn encode_attribute_name(
+    attribute_names: &[String],
+    name: &str,
+) -> Result<u32, Error> {
+    attribute_names
+        .binary_search_by(|n| n.as_str().cmp(name))
+        .map(|i| i as u32)
+        .or(Err(Error::InvalidContext(format!(
+            "attribute name must be encoded: {}",
+            name,
+        ))))
+}
+
+// Serializes residue vectors of every partition.
+//
+// Residue vectors in a partition are arranged in the same order as the
+// vectors in the partition. The partition centroid is added to every residue
+// vector if original vectors are persisted.
+fn serialize_residues<T, VS, FS, EventHandler>(
+    partitions: &Partitions<T, VS>,
+    fs: &mut FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
+) -> Result<Vec<String>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
+{
+    let num_partitions = partitions.codebook.centroids.len();
+    let mut residues_ids: Vec<String> = Vec::with_capacity(num_partitions);
+    for pi in 0..num_partitions {
+        residues_ids.push(
+            serialize_partition_residues(partitions, pi, fs, options, event)?,
+        );
+    }
+    Ok(residues_ids)
+}
+
+// Serializes residue vectors of a partition.
+fn serialize_partition_residues<T, VS, FS, EventHandler>(
+    partitions: &Partitions<T, VS>,
+    partition_index: usize,
+    fs: &FS,
+    options: &SerializeOptions,
+    event: &mut EventHandler,
+) -> Result<String, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+    FS: WriteFileSystem,
+    EventHandler: FnMut(SerializeEvent),
+{
+    let residues = &partitions.residues;
+    let m = residues.vector_size();
+    let centroid = partitions.codebook.centroids.get(partition_index);
+    let mut data: Vec<T> = Vec::new();
+    for (_, v) in residues
+        .iter()
+        .filter(|(vi, _)| partitions.codebook.indices[*vi] == partition_index)
+    {
+        data.extend_from_slice(v.as_slice());
+        if options.original_vectors {
+            let start = data.len() - m;
+            T::add_in(&mut data[start..], centroid);
+        }
+    }
+    let residues = BlockVectorSet::chunk(data, m.try_into().unwrap())?;
+    let residues = residues.serialize()?;
+    let f = fs.create_hashed_file_in_with(
+        "residues",
+        options.hash_algorithm,
+    )?;
+    write_message_file(&residues, f, FileKind::Residues, options, event)
+}
+
+// Serializes a database one file at a time.
+//
+// Files are written in the order of partitions, partition centroids,
+// codebooks, attributes logs, residues, and the database itself.
+pub(crate) struct DatabaseSerializer<'a, T, VS>
+where
+    VS: VectorSet<T>,
+{
+    header: DatabaseSerialize<'a, T, VS>,
+    partitions: PartitionIter<'a, T, VS>,
+    options: SerializeOptions,
+    step: SerializeStep,
+    // Merkle trees of the files written so far.
+    chunk_trees: RefCell<BTreeMap<String, MerkleTree>>,
+}
+
+// File to write next.
+#[derive(Clone, Copy)]
+enum SerializeStep {
+    Partition,
+    PartitionCentroids,
+    Codebook(usize),
+    AttributesLog(usize),
+    Residues(usize),
+    Database,
+    Done,
+}
+
+impl<'a, T, VS> DatabaseSerializer<'a, T, VS>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    DatabaseSerialize<'a, T, VS>: Serialize<ProtosDatabase>,
+    Partition<T>: Serialize<ProtosPartition>,
+    BlockVectorSet<T>: Serialize<ProtosVectorSet>,
+{
+    // Starts serializing a given database.
+    pub(crate) fn new(
+        db: &'a Database<T, VS>,
+        options: &SerializeOptions,
+    ) -> Result<Self, Error> {
+        check_options(options)?;
+        Ok(Self {
+            header: DatabaseSerialize {
+                database: db,
+                partition_ids: Vec::with_capacity(db.num_partitions()),
+                partition_centroids_id: String::new(),
+                codebook_ids: Vec::with_capacity(db.codebooks.len()),
+                attributes_log_ids: Vec::with_capacity(db.num_partitions()),
+                attribute_names: get_sorted_attribute_names(db),
+                residues_ids: Vec::new(),
+                original_vectors: options.original_vectors,
+                compression: options.compression,
+                codec: options.codec,
+                hash_algorithm: options.hash_algorithm,
+                chunk_trees: BTreeMap::new(),
+            },
+            partitions: db.partitions(),
+            options: options.clone(),
+            step: SerializeStep::Partition,
+            chunk_trees: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    // Writes the next file.
+    //
+    // Returns `false` after the database itself has been written.
+    pub(crate) fn write_next<FS, EventHandler>(
+        &mut self,
+        fs: &mut FS,
+        event: &mut EventHandler,
+    ) -> Result<bool, Error>
+    where
+        FS: WriteFileSystem,
+        EventHandler: FnMut(SerializeEvent),
+    {
+        let db = self.header.database;
+        let options = &self.options;
+        let num_partitions = db.num_partitions();
+        let mut chunk_fs = ChunkDigestFileSystem::new(
+            &*fs,
+            options.chunk_size,
+            &self.chunk_trees,
+        );
+        let fs = &mut chunk_fs;
+        self.step = match self.step {
+            SerializeStep::Partition => match self.partitions.next() {
+                Some(partition) => {
+                    self.header.partition_ids.push(
+                        serialize_partition(&partition, fs, options, event)?,
+                    );
+                    SerializeStep::Partition
+                },
+                None => SerializeStep::PartitionCentroids,
+            },
+            SerializeStep::PartitionCentroids => {
+                self.header.partition_centroids_id =
+                    serialize_partition_centroids(
+                        &db.partitions,
+                        fs,
+                        options,
+                        event,
+                    )?;
+                SerializeStep::Codebook(0)
+            },
+            SerializeStep::Codebook(i) => match db.codebooks.get(i) {
+                Some(codebook) => {
+                    self.header.codebook_ids.push(
+                        serialize_codebook(codebook, fs, options, event)?,
+                    );
+                    SerializeStep::Codebook(i + 1)
+                },
+                None => SerializeStep::AttributesLog(0),
+            },
+            SerializeStep::AttributesLog(i) if i < num_partitions => {
+                self.header.attributes_log_ids.push(serialize_attributes_log(
+                    db,
+                    i,
+                    &self.header.partition_ids[i],
+                    &self.header.attribute_names,
+                    fs,
+                    options,
+                    event,
+                )?);
+                SerializeStep::AttributesLog(i + 1)
+            },
+            SerializeStep::AttributesLog(_) => if options.include_residues {
+                SerializeStep::Residues(0)
+            } else {
+                SerializeStep::Database
+            },
+            SerializeStep::Residues(i) if i < num_partitions => {
+                self.header.residues_ids.push(serialize_partition_residues(
+                    &db.partitions,
+                    i,
+                    fs,
+                    options,
+                    event,
+                )?);
+                SerializeStep::Residues(i + 1)
+            },
+            SerializeStep::Residues(_) => SerializeStep::Database,
+            SerializeStep::Database => {
+                self.header.chunk_trees = self.chunk_trees.take();
+                let header = self.header.serialize()?;
+                // the header cannot record its own tree
+                let f = fs.fs.create_hashed_file()?;
+                let id = write_message_file(
+                    &header,
+                    f,
+                    FileKind::Database,
+                    options,
+                    event,
+                )?;
+                publish_header(fs.fs, &id, options)?;
+                SerializeStep::Done
+            },
+            SerializeStep::Done => SerializeStep::Done,
+        };
+        Ok(!matches!(self.step, SerializeStep::Done))
+    }
+}
+
+// Write file system that records the Merkle trees of persisted files if a
+// chunk size is given.
+struct ChunkDigestFileSystem<'a, FS> {
+    fs: &'a FS,
+    chunk_size: Option<NonZeroUsize>,
+    // Trees keyed by the paths of files.
+    trees: &'a RefCell<BTreeMap<String, MerkleTree>>,
+}
+
+impl<'a, FS> ChunkDigestFileSystem<'a, FS> {
+    fn new(
+        fs: &'a FS,
+        chunk_size: Option<NonZeroUsize>,
+        trees: &'a RefCell<BTreeMap<String, MerkleTree>>,
+    ) -> Self {
+        Self { fs, chunk_size, trees }
+    }
+
+    // Wraps a file created in a given directory.
+    fn wrap<W>(&self, file: W, dir: String) -> ChunkDigestFileOut<'a, W> {
+        ChunkDigestFileOut {
+            file,
+            dir,
+            hasher: self.chunk_size.map(MerkleHasher::new),
+            trees: self.trees,
+        }
+    }
+}
+
+impl<'a, FS> WriteFileSystem for ChunkDigestFileSystem<'a, FS>
+where
+    FS: WriteFileSystem,
+{
+    type HashedFileOut = ChunkDigestFileOut<'a, FS::HashedFileOut>;
+
+    fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        Ok(self.wrap(self.fs.create_hashed_file()?, String::new()))
+    }
+
+    fn create_hashed_file_in(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileOut, Error> {
+        let file = self.fs.create_hashed_file_in(path.as_ref())?;
+        Ok(self.wrap(file, path.as_ref().to_string()))
+    }
+
+    fn create_hashed_file_in_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        let file =
+            self.fs.create_hashed_file_in_with(path.as_ref(), algorithm)?;
+        Ok(self.wrap(file, path.as_ref().to_string()))
+    }
+}
+
+// Hashed file that records its Merkle tree when it is persisted.
+struct ChunkDigestFileOut<'a, W> {
+    file: W,
+    dir: String,
+    hasher: Option<MerkleHasher>,
+    trees: &'a RefCell<BTreeMap<String, MerkleTree>>,
+}
+
+impl<'a, W> Write for ChunkDigestFileOut<'a, W>
+where
+    W: HashedFileOut,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<'a, W> HashedFileOut for ChunkDigestFileOut<'a, W>
+where
+    W: HashedFileOut,
+{
+    fn persist(self, extension: impl AsRef<str>) -> Result<String, Error> {
+        let extension = extension.as_ref();
+        let id = self.file.persist(extension)?;
+        if let Some(hasher) = self.hasher {
+            let name = format!("{}.{}", id, extension);
+            let path = if self.dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", self.dir, name)
+            };
+            self.trees.borrow_mut().insert(path, hasher.finish());
+        }
+        Ok(id)
+    }
 }
 
 /// Serializable form of [`Database`].
@@ -215,6 +1057,14 @@ where
     codebook_ids: Vec<String>,
     attributes_log_ids: Vec<String>,
     attribute_names: Vec<String>,
+    residues_ids: Vec<String>,
+    // Whether `residues_ids` refer to original vectors.
+    original_vectors: bool,
+    compression: CompressionPolicy,
+    // Codec of the compressed files.
+    codec: Codec,
+    hash_algorithm: HashAlgorithm,
+    chunk_trees: BTreeMap<String, MerkleTree>,
 }
 
 impl<'a, T, VS> core::ops::Deref for DatabaseSerialize<'a, T, VS>
@@ -243,6 +1093,34 @@ where
         db.codebook_ids = self.codebook_ids.clone();
         db.attributes_log_ids = self.attributes_log_ids.clone();
         db.attribute_names = self.attribute_names.clone();
+        db.residues_ids = self.residues_ids.clone();
+        db.original_vectors =
+            self.original_vectors && !self.residues_ids.is_empty();
+        db.num_vectors = self.num_vectors() as u64;
+        db.partition_sizes = vec![0; self.num_partitions()];
+        for &pi in self.partitions.codebook.indices.iter() {
+            db.partition_sizes[pi] += 1;
+        }
+        db.compression = Some(self.compression.serialize()?).into();
+        record_file_codecs(&mut db, self.compression, self.codec);
+        db.hash_algorithm =
+            serialize_hash_algorithm(self.hash_algorithm).into();
+        db.chunk_trees = self.chunk_trees
+            .iter()
+            .map(|(path, tree)| Ok((path.clone(), tree.serialize()?)))
+            .collect::<Result<_, Error>>()?;
+        db.mean_quantization_errors = self.quantization_errors()
+            .iter()
+            .map(|e| e.mean)
+            .collect();
+        db.max_quantization_errors = self.quantization_errors()
+            .iter()
+            .map(|e| e.max)
+            .collect();
+        db.quantization = serialize_quantization(self.quantization()).into();
+        if let Some(projection) = self.projection() {
+            db.projection = Some(projection.serialize()?).into();
+        }
         Ok(db)
     }
 }
@@ -250,6 +1128,9 @@ where
 impl Serialize<ProtosPartition> for Partition<f32> {
     fn serialize(&self) -> Result<ProtosPartition, Error> {
         let mut partition = ProtosPartition::new();
+        if let Some(norms) = self.squared_error_norms() {
+            partition.squared_error_norms = norms.to_vec();
+        }
         let m = self.vector_size();
         let d = self.num_divisions();
         partition.vector_size = m as u32;
@@ -260,8 +1141,187 @@ impl Serialize<ProtosPartition> for Partition<f32> {
             .iter()
             .map(|id| id.serialize())
             .collect::<Result<_, _>>()?;
-        partition.encoded_vectors =
-            Some(self.encoded_vectors.serialize()?).into();
+        let encoded_vectors = match &self.encoded_vectors {
+            // packs two codes in a byte if codes fit in 4 bits
+            EncodedVectorSet::U8(vs) if self.num_codes() <= 16 => {
+                serialize_packed(vs)?
+            },
+            EncodedVectorSet::U8(vs) => vs.serialize()?,
+            EncodedVectorSet::U32(vs) => vs.serialize()?,
+        };
+        partition.encoded_vectors = Some(encoded_vectors).into();
+        if let Some(residues) = self.residues() {
+            partition.residues = Some(residues.serialize()?).into();
+        }
         Ok(partition)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::db::fixtures::{
+        build_database,
+        header_path,
+        synthetic_vectors,
+    };
+    use crate::db::stored::{
+        Database as StoredDatabase,
+        LoadDatabase,
+        LoadEvent,
+    };
+    use crate::io::LocalFileSystem;
+
+    #[test]
+    fn build_and_serialize_database_should_write_same_files_as_serialize() {
+        fn list_files(dir: &std::path::Path) -> Vec<String> {
+            let mut files: Vec<String> = Vec::new();
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    files.extend(list_files(&path));
+                } else {
+                    files.push(
+                        path.file_name().unwrap().to_str().unwrap().to_string(),
+                    );
+                }
+            }
+            files.sort();
+            files
+        }
+
+        let builder = DatabaseBuilder::new(synthetic_vectors(100, 4))
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .with_threads(2.try_into().unwrap());
+        let options = SerializeOptions::new().with_residues(true);
+        let pipelined_dir = tempfile::tempdir().unwrap();
+        let db = build_and_serialize_database(
+            builder,
+            &mut LocalFileSystem::new(pipelined_dir.path()),
+            &options,
+        ).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database_with_options(
+            &db,
+            &mut LocalFileSystem::new(dir.path()),
+            &options,
+        ).unwrap();
+        let files = list_files(dir.path());
+        assert_eq!(files.len(), 1 + 2 + 1 + 2 + 2 + 2);
+        assert_eq!(list_files(pipelined_dir.path()), files);
+    }
+
+    #[test]
+    fn io_progress_should_be_reported_while_serializing_and_loading() {
+
+        let db = build_database(100, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = LocalFileSystem::new(dir.path());
+        let mut num_partitions = 0;
+        let mut last_progress = None;
+        serialize_database_with_events(
+            &db,
+            &mut fs,
+            &SerializeOptions::new(),
+            |event| match event {
+                SerializeEvent::StartingFile(_) => last_progress = None,
+                SerializeEvent::WritingFile(_, progress) => {
+                    last_progress = Some(progress);
+                },
+                SerializeEvent::FinishedFile(kind) => {
+                    let progress = last_progress.unwrap();
+                    assert_eq!(Some(progress.processed), progress.total);
+                    if kind == FileKind::Partition {
+                        num_partitions += 1;
+                    }
+                },
+            },
+        ).unwrap();
+        assert_eq!(num_partitions, 2);
+
+        let path = header_path(dir.path());
+        let events: Arc<Mutex<Vec<LoadEvent>>> = Arc::new(Mutex::new(vec![]));
+        let db = StoredDatabase::<f32, _>::load_database(
+            LocalFileSystem::new(dir.path()),
+            &path,
+        )
+            .unwrap()
+            .with_load_event_handler({
+                let events = events.clone();
+                move |event| events.lock().unwrap().push(event)
+            });
+        db.query(
+            &[0.0f32; 4][..],
+            1.try_into().unwrap(),
+            2.try_into().unwrap(),
+        ).unwrap();
+        let events = events.lock().unwrap();
+        let loaded_partitions = events
+            .iter()
+            .filter(|event| matches!(
+                event,
+                LoadEvent::FinishedFile(FileKind::Partition),
+            ))
+            .count();
+        assert_eq!(loaded_partitions, 2);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            LoadEvent::ReadingFile(FileKind::Codebook, progress)
+                if Some(progress.processed) == progress.total,
+        )));
+    }
+
+    #[test]
+    fn serialization_should_fail_with_invalid_compression_level() {
+        let vs = crate::vector::BlockVectorSet::chunk(
+            vec![0.0f32, 1.0, 2.0, 3.0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let db = DatabaseBuilder::new(vs)
+            .with_partitions(1.try_into().unwrap())
+            .with_divisions(1.try_into().unwrap())
+            .with_clusters(1.try_into().unwrap())
+            .build()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = LocalFileSystem::new(dir.path());
+        let options = SerializeOptions::new().with_compression_level(10);
+        assert!(matches!(
+            serialize_database_with_options(&db, &mut fs, &options),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn serialization_should_accept_zstd_compression_level_up_to_22() {
+        use crate::io::codec::Codec;
+
+        let vs = crate::vector::BlockVectorSet::chunk(
+            vec![0.0f32, 1.0, 2.0, 3.0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let db = DatabaseBuilder::new(vs)
+            .with_partitions(1.try_into().unwrap())
+            .with_divisions(1.try_into().unwrap())
+            .with_clusters(1.try_into().unwrap())
+            .build()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = LocalFileSystem::new(dir.path());
+        let options = SerializeOptions::new()
+            .with_codec(Codec::Zstd)
+            .with_compression_level(22);
+        serialize_database_with_options(&db, &mut fs, &options).unwrap();
+        let options = options.with_compression_level(23);
+        assert!(matches!(
+            serialize_database_with_options(&db, &mut fs, &options),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+}
@@ -0,0 +1,371 @@
+//! Product quantization (PQ) encoder.
+//!
+//! [`PqEncoder`] encodes vectors with the partition centroids and codebooks
+//! of an existing database, so that new vectors can be encoded the same way
+//! as the vectors in the database without building one.
+
+use crate::error::Error;
+use crate::kmeans::Scalar;
+use crate::slice::AsSlice;
+use crate::vector::{BlockVectorSet, VectorSet, division_range};
+
+use super::assign_partition;
+
+/// Encoder that quantizes vectors with given partition centroids and
+/// codebooks.
+///
+/// A vector is assigned to the nearest partition, and every subvector
+/// division of the residual vector is replaced with the index of the
+/// nearest code in the codebook of the division.
+#[derive(Clone, Debug)]
+pub struct PqEncoder<T> {
+    partition_centroids: BlockVectorSet<T>,
+    codebooks: Vec<BlockVectorSet<T>>,
+}
+
+/// Vector encoded by a [`PqEncoder`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedVector<T> {
+    /// Index of the partition the vector is assigned to.
+    pub partition_index: usize,
+    /// Code per subvector division.
+    pub codes: Vec<u32>,
+    /// Squared distance between the vector and its approximation.
+    pub squared_error: T,
+}
+
+impl<T> PqEncoder<T>
+where
+    T: Scalar,
+{
+    /// Creates an encoder.
+    ///
+    /// `codebooks` has a codebook per subvector division.
+    ///
+    /// Fails if:
+    /// - `partition_centroids` or `codebooks` is empty
+    /// - a codebook has no code
+    /// - codebooks have different numbers of codes
+    /// - a codebook does not match the size of its division given by
+    ///   [`division_range`]
+    pub fn new(
+        partition_centroids: BlockVectorSet<T>,
+        codebooks: Vec<BlockVectorSet<T>>,
+    ) -> Result<Self, Error> {
+        if partition_centroids.is_empty() {
+            return Err(Error::InvalidArgs(
+                "no partition centroids".to_string(),
+            ));
+        }
+        let first = codebooks.first().ok_or(Error::InvalidArgs(
+            "no codebooks".to_string(),
+        ))?;
+        let num_codes = first.len();
+        if num_codes == 0 {
+            return Err(Error::InvalidArgs("no code in codebook".to_string()));
+        }
+        for (i, codebook) in codebooks.iter().enumerate() {
+            if codebook.len() != num_codes {
+                return Err(Error::InvalidArgs(format!(
+                    "codebook[{}]: expected {} codes but got {}",
+                    i,
+                    num_codes,
+                    codebook.len(),
+                )));
+            }
+            let subvector_size = division_range(
+                partition_centroids.vector_size(),
+                codebooks.len(),
+                i,
+            ).len();
+            if codebook.vector_size() != subvector_size {
+                return Err(Error::InvalidArgs(format!(
+                    "codebook[{}]: expected subvector size {} but got {}",
+                    i,
+                    subvector_size,
+                    codebook.vector_size(),
+                )));
+            }
+        }
+        Ok(Self {
+            partition_centroids,
+            codebooks,
+        })
+    }
+
+    /// Returns the vector size.
+    pub fn vector_size(&self) -> usize {
+        self.partition_centroids.vector_size()
+    }
+
+    /// Returns the number of partitions.
+    pub fn num_partitions(&self) -> usize {
+        self.partition_centroids.len()
+    }
+
+    /// Returns the number of subvector divisions.
+    pub fn num_divisions(&self) -> usize {
+        self.codebooks.len()
+    }
+
+    /// Returns the number of codes in each codebook.
+    pub fn num_codes(&self) -> usize {
+        self.codebooks[0].len()
+    }
+
+    /// Encodes a given vector.
+    ///
+    /// Ties are broken by the smaller partition or code index.
+    ///
+    /// Fails if the vector size does not match, or the vector has a
+    /// non-finite element.
+    pub fn encode<V>(&self, v: &V) -> Result<EncodedVector<T>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let assignment =
+            assign_partition(&self.partition_centroids, v.as_slice())?;
+        let mut squared_error = T::zero();
+        let codes = self.encode_residual_internal(
+            &assignment.residual,
+            &mut squared_error,
+        );
+        Ok(EncodedVector {
+            partition_index: assignment.partition_index,
+            codes,
+            squared_error,
+        })
+    }
+
+    /// Encodes a given residual vector; i.e., a vector minus the centroid of
+    /// the partition it belongs to.
+    ///
+    /// Fails if the vector size does not match.
+    pub fn encode_residual<V>(&self, residual: &V) -> Result<Vec<u32>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let residual = residual.as_slice();
+        self.check_vector_size(residual.len())?;
+        Ok(self.encode_residual_internal(residual, &mut T::zero()))
+    }
+
+    /// Reconstructs the approximate vector from a partition index and codes.
+    ///
+    /// Fails if the partition index or a code is out of bounds, or the
+    /// number of codes does not match the number of divisions.
+    pub fn decode(
+        &self,
+        partition_index: usize,
+        codes: &[u32],
+    ) -> Result<Vec<T>, Error> {
+        if partition_index >= self.num_partitions() {
+            return Err(Error::InvalidArgs(format!(
+                "partition index {} exceeds the number of partitions {}",
+                partition_index,
+                self.num_partitions(),
+            )));
+        }
+        if codes.len() != self.num_divisions() {
+            return Err(Error::InvalidArgs(format!(
+                "expected {} codes but got {}",
+                self.num_divisions(),
+                codes.len(),
+            )));
+        }
+        let mut v = self.partition_centroids.get(partition_index).to_vec();
+        let mut from = 0;
+        for (&code, codebook) in codes.iter().zip(self.codebooks.iter()) {
+            if code as usize >= codebook.len() {
+                return Err(Error::InvalidArgs(format!(
+                    "code {} exceeds the number of codes {}",
+                    code,
+                    codebook.len(),
+                )));
+            }
+            let to = from + codebook.vector_size();
+            T::add_in(&mut v[from..to], codebook.get(code as usize));
+            from = to;
+        }
+        Ok(v)
+    }
+
+    // Encodes a residual vector of the right size accumulating the squared
+    // quantization error to `squared_error`.
+    fn encode_residual_internal(
+        &self,
+        residual: &[T],
+        squared_error: &mut T,
+    ) -> Vec<u32> {
+        let mut from = 0;
+        self.codebooks
+            .iter()
+            .map(|codebook| {
+                let subv = &residual[from..from + codebook.vector_size()];
+                from += codebook.vector_size();
+                let mut nearest: Option<(usize, T)> = None;
+                for (ci, code_vector) in codebook.iter() {
+                    let distance = T::squared_distance(subv, code_vector);
+                    if nearest.is_none_or(|(_, d)| distance < d) {
+                        nearest = Some((ci, distance));
+                    }
+                }
+                let (ci, distance) = nearest.expect("codebook must have codes");
+                *squared_error += distance;
+                ci as u32
+            })
+            .collect()
+    }
+
+    // Checks if a given vector size matches.
+    fn check_vector_size(&self, vector_size: usize) -> Result<(), Error> {
+        if vector_size != self.vector_size() {
+            return Err(Error::InvalidArgs(format!(
+                "vector size mismatch: expected {}, got {}",
+                self.vector_size(),
+                vector_size,
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::proto::SerializeOptions;
+    use crate::db::fixtures::{
+        build_database,
+        load_database,
+        store_database,
+        synthetic_vectors,
+    };
+
+    fn encoder() -> PqEncoder<f32> {
+        let partition_centroids = BlockVectorSet::chunk(
+            vec![0.0f32, 0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 10.0],
+            4.try_into().unwrap(),
+        ).unwrap();
+        let codebooks = vec![
+            BlockVectorSet::chunk(
+                vec![0.0f32, 0.0, 1.0, 1.0, -1.0, -1.0],
+                2.try_into().unwrap(),
+            ).unwrap(),
+            BlockVectorSet::chunk(
+                vec![0.0f32, 0.0, 2.0, 0.0, 0.0, 2.0],
+                2.try_into().unwrap(),
+            ).unwrap(),
+        ];
+        PqEncoder::new(partition_centroids, codebooks).unwrap()
+    }
+
+    #[test]
+    fn pq_encoder_should_encode_vector_into_nearest_codes() {
+        let encoder = encoder();
+        let encoded = encoder.encode(&[9.0f32, 9.0, 12.0, 10.5][..]).unwrap();
+        assert_eq!(encoded.partition_index, 1);
+        assert_eq!(encoded.codes, vec![2, 1]);
+        assert_eq!(encoded.squared_error, 0.25);
+        assert_eq!(
+            encoder.decode(encoded.partition_index, &encoded.codes).unwrap(),
+            vec![9.0, 9.0, 12.0, 10.0],
+        );
+        assert_eq!(
+            encoder.encode_residual(&[1.0f32, 0.8, 0.1, 1.9][..]).unwrap(),
+            vec![1, 2],
+        );
+    }
+
+    #[test]
+    fn pq_encoder_should_reject_inconsistent_codebooks() {
+        let partition_centroids = BlockVectorSet::chunk(
+            vec![0.0f32; 4],
+            4.try_into().unwrap(),
+        ).unwrap();
+        let codebooks = vec![
+            BlockVectorSet::chunk(vec![0.0f32; 4], 2.try_into().unwrap())
+                .unwrap(),
+            BlockVectorSet::chunk(vec![0.0f32; 6], 2.try_into().unwrap())
+                .unwrap(),
+        ];
+        assert!(matches!(
+            PqEncoder::new(partition_centroids.clone(), codebooks),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            PqEncoder::new(partition_centroids, Vec::new()),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn pq_encoder_should_encode_divisions_of_different_sizes() {
+        let partition_centroids = BlockVectorSet::chunk(
+            vec![0.0f32; 3],
+            3.try_into().unwrap(),
+        ).unwrap();
+        let codebooks = vec![
+            BlockVectorSet::chunk(
+                vec![0.0f32, 0.0, 1.0, 2.0],
+                2.try_into().unwrap(),
+            ).unwrap(),
+            BlockVectorSet::chunk(vec![0.0f32, 3.0], 1.try_into().unwrap())
+                .unwrap(),
+        ];
+        let encoder =
+            PqEncoder::new(partition_centroids.clone(), codebooks.clone())
+                .unwrap();
+        let encoded = encoder.encode(&[1.0f32, 2.0, 3.0][..]).unwrap();
+        assert_eq!(encoded.codes, vec![1, 1]);
+        assert_eq!(encoded.squared_error, 0.0);
+        assert_eq!(
+            encoder.decode(0, &encoded.codes).unwrap(),
+            vec![1.0, 2.0, 3.0],
+        );
+        // the shorter division must come last
+        let reversed = codebooks.into_iter().rev().collect();
+        assert!(matches!(
+            PqEncoder::new(partition_centroids, reversed),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn pq_encoder_should_reject_vector_of_wrong_size() {
+        let encoder = encoder();
+        assert!(encoder.encode(&[0.0f32; 3][..]).is_err());
+        assert!(encoder.encode_residual(&[0.0f32; 5][..]).is_err());
+        assert!(encoder.decode(2, &[0, 0]).is_err());
+        assert!(encoder.decode(0, &[0, 3]).is_err());
+    }
+
+    #[test]
+    fn pq_encoder_of_stored_database_should_match_built_database() {
+        let vectors = synthetic_vectors(100, 4);
+        let db = build_database(100, 4);
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        let encoder = db.pq_encoder().unwrap();
+        let stored_encoder = stored.pq_encoder().unwrap();
+        assert_eq!(stored_encoder.num_divisions(), 2);
+        assert_eq!(stored_encoder.num_codes(), 4);
+        for (_, v) in vectors.iter().take(10) {
+            let encoded = encoder.encode(v).unwrap();
+            assert_eq!(stored_encoder.encode(v).unwrap(), encoded);
+            assert_eq!(
+                encoded.partition_index,
+                db.assign_partition(v).unwrap().partition_index,
+            );
+            let decoded = encoder
+                .decode(encoded.partition_index, &encoded.codes)
+                .unwrap();
+            let error: f32 = v
+                .iter()
+                .zip(decoded.iter())
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum();
+            assert!((error - encoded.squared_error).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Fixtures shared by tests of databases.
+//!
+//! Builds databases of synthetic vectors and stores them in temporary
+//! directories on the local file system.
+
+use std::path::Path;
+use tempfile::TempDir;
+
+use crate::io::LocalFileSystem;
+use crate::testing::SyntheticDatasetBuilder;
+use crate::vector::BlockVectorSet;
+
+use super::build::{Database as BuiltDatabase, DatabaseBuilder};
+use super::build::proto::{SerializeOptions, serialize_database_with_options};
+use super::manifest::CURRENT;
+use super::stored::{Database as StoredDatabase, LoadDatabase};
+
+/// Database built from synthetic vectors.
+pub(crate) type SyntheticDatabase = BuiltDatabase<f32, BlockVectorSet<f32>>;
+
+/// Database stored on the local file system.
+pub(crate) type LocalDatabase = StoredDatabase<f32, LocalFileSystem>;
+
+/// Builder of a [`SyntheticDatabase`].
+pub(crate) type SyntheticDatabaseBuilder =
+    DatabaseBuilder<f32, BlockVectorSet<f32>>;
+
+/// Generates synthetic vectors with the default seed.
+///
+/// Returns the same vectors for the same arguments.
+pub(crate) fn synthetic_vectors(
+    num_vectors: usize,
+    vector_size: usize,
+) -> BlockVectorSet<f32> {
+    SyntheticDatasetBuilder::new(
+        num_vectors.try_into().unwrap(),
+        vector_size.try_into().unwrap(),
+    )
+        .build()
+        .unwrap()
+        .vectors
+}
+
+/// Builds a database of synthetic vectors with 2 partitions, 2 divisions,
+/// and 4 clusters.
+pub(crate) fn build_database(
+    num_vectors: usize,
+    vector_size: usize,
+) -> SyntheticDatabase {
+    build_database_with(num_vectors, vector_size, |builder| builder)
+}
+
+/// Builds a database of synthetic vectors with 2 partitions, 2 divisions,
+/// and 4 clusters, unless `configure` overrides them.
+pub(crate) fn build_database_with<F>(
+    num_vectors: usize,
+    vector_size: usize,
+    configure: F,
+) -> SyntheticDatabase
+where
+    F: FnOnce(SyntheticDatabaseBuilder) -> SyntheticDatabaseBuilder,
+{
+    build_database_of(synthetic_vectors(num_vectors, vector_size), configure)
+}
+
+/// Builds a database of 100 synthetic vectors of size 4 generated with a
+/// given seed.
+///
+/// Databases of different seeds share no files.
+pub(crate) fn build_seeded_database(seed: u64) -> SyntheticDatabase {
+    let vectors = SyntheticDatasetBuilder::new(
+        100.try_into().unwrap(),
+        4.try_into().unwrap(),
+    )
+        .with_seed(seed)
+        .build()
+        .unwrap()
+        .vectors;
+    build_database_of(vectors, |builder| builder)
+}
+
+fn build_database_of<F>(
+    vectors: BlockVectorSet<f32>,
+    configure: F,
+) -> SyntheticDatabase
+where
+    F: FnOnce(SyntheticDatabaseBuilder) -> SyntheticDatabaseBuilder,
+{
+    let builder = DatabaseBuilder::new(vectors)
+        .with_partitions(2.try_into().unwrap())
+        .with_divisions(2.try_into().unwrap())
+        .with_clusters(4.try_into().unwrap());
+    configure(builder).build().unwrap()
+}
+
+/// Returns the path of the database header file in a given directory.
+///
+/// Panics unless the directory has exactly one header file besides
+/// [`CURRENT`].
+pub(crate) fn header_path(dir: impl AsRef<Path>) -> String {
+    let headers: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+        .filter(|name| name != CURRENT)
+        .collect();
+    assert_eq!(headers.len(), 1, "header files: {:?}", headers);
+    headers.into_iter().next().unwrap()
+}
+
+/// Serializes a database into a new temporary directory.
+///
+/// Returns the directory and the path of the database header file.
+pub(crate) fn store_database(
+    db: &SyntheticDatabase,
+    options: &SerializeOptions,
+) -> (TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    serialize_database_with_options(
+        db,
+        &mut LocalFileSystem::new(dir.path()),
+        options,
+    ).unwrap();
+    let header = header_path(dir.path());
+    (dir, header)
+}
+
+/// Loads a database stored in a given directory with the default options.
+pub(crate) fn load_database(dir: &TempDir, header: &str) -> LocalDatabase {
+    StoredDatabase::load_database(LocalFileSystem::new(dir.path()), header)
+        .unwrap()
+}
+
+/// Builds a database of synthetic vectors as [`build_database`] does and
+/// stores it in a new temporary directory.
+///
+/// Returns the directory and the path of the database header file.
+pub(crate) fn build_and_store(
+    num_vectors: usize,
+    vector_size: usize,
+) -> (TempDir, String) {
+    store_database(
+        &build_database(num_vectors, vector_size),
+        &SerializeOptions::new(),
+    )
+}
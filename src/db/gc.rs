@@ -0,0 +1,182 @@
+//! Garbage collection of files no database refers to.
+//!
+//! Aborted builds and updates of attributes leave files in `partitions`,
+//! `codebooks`, `attributes`, and `residues` directories that no database
+//! header refers to. [`collect_garbage`] deletes them.
+//!
+//! Garbage collection must not run while a database is being built or
+//! updated in the same file system, because the files being written are not
+//! referred to until their database header is written.
+//!
+//! LSH indexes are out of scope. Their headers in `lsh` and buckets in
+//! `buckets` are not referred to by database headers, so garbage collection
+//! never lists nor deletes them.
+
+use std::collections::HashSet;
+
+use crate::error::Error;
+use crate::io::{FileSystem, HashedFileIn, ReadFileSystem};
+use crate::protos::database::Database as ProtosDatabase;
+use crate::protos::read_message;
+
+use super::manifest::CURRENT;
+use super::proto::referenced_file_paths;
+
+/// Directories that contain files referred to by database headers.
+pub const DATA_DIRECTORIES: [&str; 4] =
+    ["partitions", "codebooks", "attributes", "residues"];
+
+/// Finds files that none of given database headers refers to.
+///
+/// Lists files in [`DATA_DIRECTORIES`] and returns the paths of those that
+/// neither the database headers at `header_paths` nor the one [`CURRENT`]
+/// names refers to. Hidden files; e.g., temporary files being written, are
+/// never garbage.
+///
+/// Fails with `Error::InvalidArgs` if `header_paths` is empty, because every
+/// file would be garbage. Fails if a database header cannot be loaded, or
+/// the file system cannot read pointers or list files.
+pub fn find_garbage<FS, P>(
+    fs: &FS,
+    header_paths: &[P],
+) -> Result<Vec<String>, Error>
+where
+    FS: ReadFileSystem,
+    P: AsRef<str>,
+{
+    if header_paths.is_empty() {
+        return Err(Error::InvalidArgs(
+            "no database header to keep".to_string(),
+        ));
+    }
+    let current = fs.read_pointer(CURRENT)?;
+    let header_paths = header_paths
+        .iter()
+        .map(|path| path.as_ref())
+        .chain(current.as_deref());
+    let mut referenced: HashSet<String> = HashSet::new();
+    for path in header_paths {
+        let mut f = fs.open_compressed_hashed_file(path)?;
+        let db: ProtosDatabase = read_message(&mut f)?;
+        f.verify()?;
+        referenced.extend(referenced_file_paths(&db));
+    }
+    let mut garbage: Vec<String> = Vec::new();
+    for dir in DATA_DIRECTORIES {
+        for path in fs.list_files(dir)? {
+            let name = path.rsplit('/').next().unwrap();
+            if !name.starts_with('.') && !referenced.contains(&path) {
+                garbage.push(path);
+            }
+        }
+    }
+    garbage.sort();
+    Ok(garbage)
+}
+
+/// Deletes files that none of given database headers refers to.
+///
+/// Deletes the files [`find_garbage`] finds, and returns their paths.
+/// Database headers themselves are never deleted; delete superseded ones
+/// before garbage collection to reclaim the files only they refer to.
+///
+/// The file system has to support [`ReadFileSystem::list_files`] and
+/// [`WriteFileSystem::delete`](crate::io::WriteFileSystem::delete).
+pub fn collect_garbage<FS, P>(
+    fs: &FS,
+    header_paths: &[P],
+) -> Result<Vec<String>, Error>
+where
+    FS: FileSystem,
+    P: AsRef<str>,
+{
+    let garbage = find_garbage(fs, header_paths)?;
+    for path in garbage.iter() {
+        fs.delete(path)?;
+    }
+    Ok(garbage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::proto::serialize_database;
+    use crate::db::fixtures::{build_seeded_database, load_database};
+    use crate::db::manifest::publish_database;
+    use crate::io::LocalFileSystem;
+
+    // Serializes databases of given seeds and returns their header paths.
+    fn store_databases(fs: &mut LocalFileSystem, seeds: &[u64]) -> Vec<String> {
+        let mut headers: Vec<String> = Vec::new();
+        for &seed in seeds {
+            serialize_database(&build_seeded_database(seed), fs).unwrap();
+            let header = fs.list_files("")
+                .unwrap()
+                .into_iter()
+                .find(|path| !path.contains('/') && !headers.contains(path))
+                .unwrap();
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn collect_garbage_should_delete_files_of_other_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = LocalFileSystem::new(dir.path());
+        let headers = store_databases(&mut fs, &[1, 2]);
+        // a file being written is not garbage
+        std::fs::write(dir.path().join("partitions/.tmp0"), b"").unwrap();
+
+        assert!(find_garbage(&fs, &headers).unwrap().is_empty());
+        let garbage = collect_garbage(&fs, &headers[..1]).unwrap();
+        assert_eq!(garbage.len(), 7);
+        assert!(find_garbage(&fs, &headers[..1]).unwrap().is_empty());
+        assert!(dir.path().join("partitions/.tmp0").exists());
+        let db = load_database(&dir, &headers[0]);
+        db.query(
+            &[0.0f32; 4][..],
+            1.try_into().unwrap(),
+            2.try_into().unwrap(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn collect_garbage_should_keep_files_of_current_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = LocalFileSystem::new(dir.path());
+        let headers = store_databases(&mut fs, &[1, 2, 3]);
+        publish_database(&fs, &headers[1]).unwrap();
+
+        let garbage = collect_garbage(&fs, &headers[..1]).unwrap();
+        // every file of the third database but its header
+        assert_eq!(garbage.len(), 7);
+        assert!(find_garbage(&fs, &headers[..1]).unwrap().is_empty());
+        let db = load_database(&dir, &headers[1]);
+        db.query(
+            &[0.0f32; 4][..],
+            1.try_into().unwrap(),
+            2.try_into().unwrap(),
+        ).unwrap();
+    }
+
+    #[test]
+    fn find_garbage_should_reject_empty_header_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = LocalFileSystem::new(dir.path());
+        let headers = store_databases(&mut fs, &[1]);
+        publish_database(&fs, &headers[0]).unwrap();
+        let no_headers: [&str; 0] = [];
+        assert!(matches!(
+            find_garbage(&fs, &no_headers),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            collect_garbage(&fs, &no_headers),
+            Err(Error::InvalidArgs(_)),
+        ));
+        // partition centroids and 2 partitions
+        assert_eq!(fs.list_files("partitions").unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,739 @@
+//! Locality-sensitive hashing (LSH) index.
+//!
+//! An [`LshIndex`] hashes every vector to the signature of
+//! [`random_hyperplanes`]; i.e., which side of each hyperplane the vector
+//! is on, and groups vectors of the same signature into a bucket. Unlike
+//! partitions of a database, buckets need no training, so vectors can be
+//! inserted and removed at any time without rebuilding the index; e.g., for
+//! datasets with so much churn that k-means clustering is too expensive.
+//!
+//! A query probes the buckets whose signatures are nearest to that of the
+//! query vector in Hamming distance (multi-probe LSH), and scores the
+//! vectors in them exactly.
+//!
+//! [`serialize_lsh_index`] writes every bucket to a file named after its
+//! contents in the `buckets` directory, and the header to the `lsh`
+//! directory, so only the buckets that changed are written to new files.
+//! [`StoredLshIndex`] loads the buckets a query probes on demand. Files
+//! of superseded LSH indexes are left to the caller to delete;
+//! [`collect_garbage`](crate::db::gc::collect_garbage) does not know them.
+
+use core::num::NonZeroUsize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::io::{HashedFileIn, HashedFileOut, ReadFileSystem, WriteFileSystem};
+use crate::kmeans::Scalar;
+use crate::nbest::NBestByKey;
+use crate::projection::{Projection, random_hyperplanes};
+use crate::protos::database::{
+    LshBucket as ProtosLshBucket,
+    LshIndex as ProtosLshIndex,
+};
+use crate::protos::{Deserialize, Serialize, read_message, write_message};
+use crate::slice::AsSlice;
+use crate::vector::bits::pack_signs;
+use crate::vector::{BlockVectorSet, VectorSet, VectorSetRemove};
+
+use super::check_finite;
+use super::stored::PROTOBUF_EXTENSION;
+
+pub mod proto;
+
+/// Maximum number of bits in a signature.
+pub const MAX_SIGNATURE_BITS: usize = 64;
+
+/// LSH index in memory.
+#[derive(Clone, Debug)]
+pub struct LshIndex<T> {
+    hyperplanes: Projection<T>,
+    // Buckets in ascending order of their signatures.
+    buckets: BTreeMap<u64, LshBucket<T>>,
+    // Signature of the bucket each vector is in.
+    signatures: HashMap<Uuid, u64>,
+}
+
+impl<T> LshIndex<T>
+where
+    T: Scalar,
+{
+    /// Creates an empty index of vectors of `vector_size` elements.
+    ///
+    /// Vectors are hashed to signatures of `num_bits` hyperplanes drawn from
+    /// the random generator seeded with `seed`. More bits make smaller
+    /// buckets, which makes queries faster but needs more probes for the
+    /// same recall.
+    ///
+    /// Fails if `num_bits` exceeds [`MAX_SIGNATURE_BITS`].
+    pub fn new(
+        vector_size: NonZeroUsize,
+        num_bits: NonZeroUsize,
+        seed: u64,
+    ) -> Result<Self, Error> {
+        Self::with_hyperplanes(random_hyperplanes(vector_size, num_bits, seed))
+    }
+
+    /// Creates an empty index that hashes vectors with given hyperplanes.
+    ///
+    /// Fails if there are more hyperplanes than [`MAX_SIGNATURE_BITS`].
+    pub fn with_hyperplanes(
+        hyperplanes: Projection<T>,
+    ) -> Result<Self, Error> {
+        if hyperplanes.output_size() > MAX_SIGNATURE_BITS {
+            return Err(Error::InvalidArgs(format!(
+                "number of hyperplanes {} exceeds {}",
+                hyperplanes.output_size(),
+                MAX_SIGNATURE_BITS,
+            )));
+        }
+        Ok(Self {
+            hyperplanes,
+            buckets: BTreeMap::new(),
+            signatures: HashMap::new(),
+        })
+    }
+
+    /// Returns the vector size.
+    pub const fn vector_size(&self) -> usize {
+        self.hyperplanes.input_size()
+    }
+
+    /// Returns the number of bits in a signature.
+    pub fn num_bits(&self) -> usize {
+        self.hyperplanes.output_size()
+    }
+
+    /// Returns the number of vectors.
+    pub fn num_vectors(&self) -> usize {
+        self.signatures.len()
+    }
+
+    /// Returns the number of non-empty buckets.
+    pub fn num_buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Returns the hyperplanes.
+    pub fn hyperplanes(&self) -> &Projection<T> {
+        &self.hyperplanes
+    }
+
+    /// Returns the bucket of a given signature.
+    pub fn get_bucket(&self, signature: u64) -> Option<&LshBucket<T>> {
+        self.buckets.get(&signature)
+    }
+
+    /// Returns an iterator over the buckets in ascending order of their
+    /// signatures.
+    pub fn buckets(&self) -> impl Iterator<Item = &LshBucket<T>> {
+        self.buckets.values()
+    }
+
+    /// Calculates the signature of a given vector.
+    ///
+    /// The i-th bit is set if the vector is on the positive side of the i-th
+    /// hyperplane.
+    ///
+    /// Fails if the vector size does not match.
+    pub fn signature<V>(&self, v: &V) -> Result<u64, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        signature(&self.hyperplanes, v.as_slice())
+    }
+
+    /// Inserts a vector.
+    ///
+    /// Fails if the vector size does not match, `v` has an infinite or NaN
+    /// element, or the index already has `vector_id`.
+    pub fn insert<V>(&mut self, vector_id: Uuid, v: &V) -> Result<(), Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let v = v.as_slice();
+        check_finite(v)?;
+        let signature = self.signature(v)?;
+        match self.signatures.entry(vector_id) {
+            Entry::Occupied(_) => {
+                return Err(Error::InvalidArgs(format!(
+                    "duplicate vector ID: {}",
+                    vector_id,
+                )));
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(signature);
+            },
+        }
+        let m = self.vector_size().try_into().unwrap();
+        let bucket = self.buckets
+            .entry(signature)
+            .or_insert_with(|| LshBucket::new(signature, m));
+        bucket.vector_ids.push(vector_id);
+        bucket.vectors.push(v)
+    }
+
+    /// Removes a vector.
+    ///
+    /// Returns whether the index had `vector_id`. A bucket is removed when
+    /// it becomes empty.
+    pub fn remove(&mut self, vector_id: &Uuid) -> bool {
+        let Some(signature) = self.signatures.remove(vector_id) else {
+            return false;
+        };
+        let bucket = self.buckets
+            .get_mut(&signature)
+            .expect("bucket of a vector must exist");
+        let index = bucket.vector_ids
+            .iter()
+            .position(|id| id == vector_id)
+            .expect("bucket must have a vector of its signature");
+        bucket.vector_ids.remove(index);
+        bucket.vectors.retain(|i| i != index);
+        if bucket.vector_ids.is_empty() {
+            self.buckets.remove(&signature);
+        }
+        true
+    }
+
+    /// Queries k-nearest neighbors (k-NN) of a given vector.
+    ///
+    /// Scores every vector in the `nprobe` buckets whose signatures are
+    /// nearest to that of `v` in Hamming distance, by the exact squared
+    /// distance. Ties are broken by the smaller signature, and the smaller
+    /// vector ID. All the buckets are probed if there are fewer than
+    /// `nprobe`.
+    ///
+    /// Fails if the vector size does not match, or `v` has an infinite or
+    /// NaN element.
+    pub fn query<V>(
+        &self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+    ) -> Result<Vec<LshQueryResult<T>>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let v = v.as_slice();
+        check_finite(v)?;
+        let signature = self.signature(v)?;
+        let probed =
+            probe_buckets(signature, self.buckets.keys().copied(), nprobe);
+        let mut results = new_nbest(k);
+        for signature in probed {
+            score_bucket(v, &self.buckets[&signature], &mut results);
+        }
+        Ok(results.into_sorted_vec())
+    }
+}
+
+/// Bucket of vectors of the same signature.
+#[derive(Clone, Debug)]
+pub struct LshBucket<T> {
+    signature: u64,
+    vector_ids: Vec<Uuid>,
+    vectors: BlockVectorSet<T>,
+}
+
+impl<T> LshBucket<T> {
+    // Creates an empty bucket.
+    fn new(signature: u64, vector_size: NonZeroUsize) -> Self {
+        Self {
+            signature,
+            vector_ids: Vec::new(),
+            vectors: BlockVectorSet::chunk(Vec::new(), vector_size).unwrap(),
+        }
+    }
+
+    /// Returns the signature.
+    pub const fn signature(&self) -> u64 {
+        self.signature
+    }
+
+    /// Returns the number of vectors.
+    pub fn num_vectors(&self) -> usize {
+        self.vector_ids.len()
+    }
+
+    /// Returns the vector IDs.
+    pub fn vector_ids(&self) -> &[Uuid] {
+        &self.vector_ids
+    }
+
+    /// Returns the vectors.
+    ///
+    /// The i-th vector has the i-th ID in [`LshBucket::vector_ids`].
+    pub fn vectors(&self) -> &BlockVectorSet<T> {
+        &self.vectors
+    }
+}
+
+/// Result of a query to an LSH index.
+#[derive(Clone, Debug)]
+pub struct LshQueryResult<T> {
+    /// Signature of the bucket.
+    pub signature: u64,
+    /// Vector ID.
+    pub vector_id: Uuid,
+    /// Vector index. Local index in the bucket.
+    pub vector_index: usize,
+    /// Exact squared distance.
+    pub squared_distance: T,
+}
+
+/// Serializes an LSH index into a given file system.
+///
+/// Writes every bucket to the `buckets` directory, and then the header to
+/// the `lsh` directory. Files are named after their contents, so a bucket
+/// that has not changed since the index was last serialized is written to
+/// the same file.
+///
+/// Returns the path of the header.
+pub fn serialize_lsh_index<FS>(
+    index: &LshIndex<f32>,
+    fs: &FS,
+) -> Result<String, Error>
+where
+    FS: WriteFileSystem,
+{
+    let mut header = ProtosLshIndex::new();
+    header.vector_size = index.vector_size() as u32;
+    header.hyperplanes = Some(index.hyperplanes.serialize()?).into();
+    for bucket in index.buckets() {
+        let mut f = fs.create_compressed_hashed_file_in("buckets")?;
+        write_message(&bucket.serialize()?, &mut f)?;
+        header.bucket_signatures.push(bucket.signature);
+        header.bucket_ids.push(f.persist(PROTOBUF_EXTENSION)?);
+        header.bucket_sizes.push(bucket.num_vectors() as u32);
+    }
+    let mut f = fs.create_compressed_hashed_file_in("lsh")?;
+    write_message(&header, &mut f)?;
+    let id = f.persist(PROTOBUF_EXTENSION)?;
+    Ok(format!("lsh/{}.{}", id, PROTOBUF_EXTENSION))
+}
+
+/// Loads an entire LSH index from a given file system.
+///
+/// Loads every bucket, so that vectors can be inserted and removed before
+/// the index is serialized again.
+///
+/// Fails if the header at `path` or a bucket is invalid.
+pub fn load_lsh_index<FS, P>(
+    fs: FS,
+    path: P,
+) -> Result<LshIndex<f32>, Error>
+where
+    FS: ReadFileSystem,
+    P: AsRef<str>,
+{
+    let stored = StoredLshIndex::load(fs, path)?;
+    let mut index = LshIndex::with_hyperplanes(stored.hyperplanes.clone())?;
+    for i in 0..stored.num_buckets() {
+        let bucket = stored.load_bucket(i)?;
+        for id in bucket.vector_ids.iter() {
+            if index.signatures.insert(*id, bucket.signature).is_some() {
+                return Err(Error::InvalidData(format!(
+                    "duplicate vector ID: {}",
+                    id,
+                )));
+            }
+        }
+        index.buckets.insert(bucket.signature, bucket);
+    }
+    Ok(index)
+}
+
+/// LSH index stored in a file system.
+///
+/// Keeps only the header in memory, and loads the buckets a query probes.
+pub struct StoredLshIndex<FS> {
+    fs: FS,
+    hyperplanes: Projection<f32>,
+    // Signatures of the buckets in ascending order.
+    bucket_signatures: Vec<u64>,
+    bucket_ids: Vec<String>,
+    bucket_sizes: Vec<usize>,
+}
+
+impl<FS> StoredLshIndex<FS>
+where
+    FS: ReadFileSystem,
+{
+    /// Loads the header of an LSH index at a given path.
+    ///
+    /// Fails if:
+    /// - `vector_size` is zero
+    /// - the hyperplanes are missing or invalid
+    /// - the input size of the hyperplanes and `vector_size` do not match
+    /// - there are more hyperplanes than [`MAX_SIGNATURE_BITS`]
+    /// - `bucket_signatures`, `bucket_ids`, and `bucket_sizes` do not have
+    ///   the same number of elements
+    /// - `bucket_signatures` are not in strictly ascending order
+    pub fn load<P>(fs: FS, path: P) -> Result<Self, Error>
+    where
+        P: AsRef<str>,
+    {
+        let mut f = fs.open_compressed_hashed_file(path)?;
+        let header: ProtosLshIndex = read_message(&mut f)?;
+        f.verify()?;
+        let vector_size = header.vector_size as usize;
+        if vector_size == 0 {
+            return Err(Error::InvalidData("vector_size is zero".to_string()));
+        }
+        let hyperplanes: Projection<f32> = header.hyperplanes
+            .into_option()
+            .ok_or(Error::InvalidData("missing hyperplanes".to_string()))?
+            .deserialize()?;
+        if hyperplanes.input_size() != vector_size {
+            return Err(Error::InvalidData(format!(
+                "vector_size {} and hyperplanes input size {} do not match",
+                vector_size,
+                hyperplanes.input_size(),
+            )));
+        }
+        if hyperplanes.output_size() > MAX_SIGNATURE_BITS {
+            return Err(Error::InvalidData(format!(
+                "number of hyperplanes {} exceeds {}",
+                hyperplanes.output_size(),
+                MAX_SIGNATURE_BITS,
+            )));
+        }
+        let num_buckets = header.bucket_signatures.len();
+        if header.bucket_ids.len() != num_buckets
+            || header.bucket_sizes.len() != num_buckets
+        {
+            return Err(Error::InvalidData(format!(
+                "numbers of bucket signatures {}, IDs {}, and sizes {} differ",
+                num_buckets,
+                header.bucket_ids.len(),
+                header.bucket_sizes.len(),
+            )));
+        }
+        if header.bucket_signatures.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(Error::InvalidData(
+                "bucket signatures are not in ascending order".to_string(),
+            ));
+        }
+        Ok(Self {
+            fs,
+            hyperplanes,
+            bucket_signatures: header.bucket_signatures,
+            bucket_ids: header.bucket_ids,
+            bucket_sizes: header.bucket_sizes
+                .into_iter()
+                .map(|size| size as usize)
+                .collect(),
+        })
+    }
+
+    /// Returns the vector size.
+    pub const fn vector_size(&self) -> usize {
+        self.hyperplanes.input_size()
+    }
+
+    /// Returns the number of bits in a signature.
+    pub fn num_bits(&self) -> usize {
+        self.hyperplanes.output_size()
+    }
+
+    /// Returns the number of buckets.
+    pub fn num_buckets(&self) -> usize {
+        self.bucket_signatures.len()
+    }
+
+    /// Returns the number of vectors.
+    pub fn num_vectors(&self) -> usize {
+        self.bucket_sizes.iter().sum()
+    }
+
+    /// Returns the signatures of the buckets in ascending order.
+    pub fn bucket_signatures(&self) -> &[u64] {
+        &self.bucket_signatures
+    }
+
+    /// Loads the i-th bucket.
+    ///
+    /// Fails if `index` exceeds the number of buckets, or the bucket does
+    /// not match the header.
+    pub fn load_bucket(&self, index: usize) -> Result<LshBucket<f32>, Error> {
+        if index >= self.num_buckets() {
+            return Err(Error::InvalidArgs(format!(
+                "bucket index {} exceeds the number of buckets {}",
+                index,
+                self.num_buckets(),
+            )));
+        }
+        let mut f = self.fs.open_compressed_hashed_file(format!(
+            "buckets/{}.{}",
+            self.bucket_ids[index],
+            PROTOBUF_EXTENSION,
+        ))?;
+        let bucket: ProtosLshBucket = read_message(&mut f)?;
+        f.verify()?;
+        let bucket: LshBucket<f32> = bucket.deserialize()?;
+        if bucket.signature != self.bucket_signatures[index] {
+            return Err(Error::InvalidData(format!(
+                "bucket[{}]: expected signature {:#x} but got {:#x}",
+                index,
+                self.bucket_signatures[index],
+                bucket.signature,
+            )));
+        }
+        if bucket.num_vectors() != self.bucket_sizes[index] {
+            return Err(Error::InvalidData(format!(
+                "bucket[{}]: expected {} vectors but got {}",
+                index,
+                self.bucket_sizes[index],
+                bucket.num_vectors(),
+            )));
+        }
+        if bucket.vectors.vector_size() != self.vector_size() {
+            return Err(Error::InvalidData(format!(
+                "bucket[{}]: expected vector size {} but got {}",
+                index,
+                self.vector_size(),
+                bucket.vectors.vector_size(),
+            )));
+        }
+        Ok(bucket)
+    }
+
+    /// Queries k-nearest neighbors (k-NN) of a given vector.
+    ///
+    /// Loads only the probed buckets. See [`LshIndex::query`].
+    pub fn query<V>(
+        &self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+    ) -> Result<Vec<LshQueryResult<f32>>, Error>
+    where
+        V: AsSlice<f32> + ?Sized,
+    {
+        let v = v.as_slice();
+        check_finite(v)?;
+        let signature = signature(&self.hyperplanes, v)?;
+        let probed = probe_buckets(
+            signature,
+            self.bucket_signatures.iter().copied(),
+            nprobe,
+        );
+        let mut results = new_nbest(k);
+        for signature in probed {
+            // signatures are sorted
+            let index = self.bucket_signatures
+                .binary_search(&signature)
+                .unwrap();
+            score_bucket(v, &self.load_bucket(index)?, &mut results);
+        }
+        Ok(results.into_sorted_vec())
+    }
+}
+
+// Calculates the signature of a vector with hyperplanes.
+fn signature<T>(hyperplanes: &Projection<T>, v: &[T]) -> Result<u64, Error>
+where
+    T: Scalar,
+{
+    let projected = hyperplanes.project(v)?;
+    Ok(pack_signs(&projected)[0])
+}
+
+// Chooses `nprobe` signatures nearest to a given signature in Hamming
+// distance. Ties are broken by the smaller signature.
+fn probe_buckets<I>(
+    signature: u64,
+    signatures: I,
+    nprobe: NonZeroUsize,
+) -> Vec<u64>
+where
+    I: IntoIterator<Item = u64>,
+{
+    let mut probed: Vec<u64> = signatures.into_iter().collect();
+    probed.sort_by_key(|&s| ((s ^ signature).count_ones(), s));
+    probed.truncate(nprobe.get());
+    probed
+}
+
+// N-best query results keyed by the squared distance and vector ID.
+type QueryNBest<T> = NBestByKey<
+    LshQueryResult<T>,
+    (T, Uuid),
+    fn(&LshQueryResult<T>) -> (T, Uuid),
+>;
+
+// Creates an empty n-best of query results.
+fn new_nbest<T>(k: NonZeroUsize) -> QueryNBest<T>
+where
+    T: Scalar,
+{
+    NBestByKey::new(k.get(), |result| {
+        (result.squared_distance, result.vector_id)
+    })
+}
+
+// Scores every vector in a bucket.
+fn score_bucket<T>(
+    v: &[T],
+    bucket: &LshBucket<T>,
+    results: &mut QueryNBest<T>,
+)
+where
+    T: Scalar,
+{
+    for (i, vector) in bucket.vectors.iter() {
+        results.push(LshQueryResult {
+            signature: bucket.signature,
+            vector_id: bucket.vector_ids[i],
+            vector_index: i,
+            squared_distance: T::squared_distance(v, vector),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::io::LocalFileSystem;
+    use crate::linalg::simd::Kernels;
+    use crate::testing::SyntheticDatasetBuilder;
+
+    // Inserts a synthetic dataset into an index, and returns the vectors and
+    // their IDs as well.
+    fn synthetic_index() -> (LshIndex<f32>, BlockVectorSet<f32>, Vec<Uuid>) {
+        let dataset = SyntheticDatasetBuilder::new(
+            200.try_into().unwrap(),
+            8.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let mut index = LshIndex::new(
+            8.try_into().unwrap(),
+            4.try_into().unwrap(),
+            1,
+        ).unwrap();
+        let vector_ids: Vec<Uuid> = (0..200).map(|_| Uuid::new_v4()).collect();
+        for (i, v) in dataset.vectors.iter() {
+            index.insert(vector_ids[i], v).unwrap();
+        }
+        (index, dataset.vectors, vector_ids)
+    }
+
+    #[test]
+    fn lsh_index_should_find_nearest_neighbors_in_probed_buckets() {
+        let (index, vectors, vector_ids) = synthetic_index();
+        assert_eq!(index.num_vectors(), 200);
+        assert!(index.num_buckets() > 1);
+        let query = vectors.get(3);
+        // probing all the buckets scores every vector
+        let k = 10.try_into().unwrap();
+        let nprobe = index.num_buckets().try_into().unwrap();
+        let results = index.query(query, k, nprobe).unwrap();
+        let mut expected: Vec<(f32, Uuid)> = vectors
+            .iter()
+            .map(|(i, v)| (f32::squared_distance(query, v), vector_ids[i]))
+            .collect();
+        expected.sort_by(|lhs, rhs| lhs.partial_cmp(rhs).unwrap());
+        expected.truncate(10);
+        let actual: Vec<(f32, Uuid)> = results
+            .iter()
+            .map(|result| (result.squared_distance, result.vector_id))
+            .collect();
+        assert_eq!(actual, expected);
+        // the nearest bucket has the query vector itself
+        let results = index.query(query, k, 1.try_into().unwrap()).unwrap();
+        assert_eq!(results[0].vector_id, vector_ids[3]);
+        assert_eq!(results[0].squared_distance, 0.0);
+        assert_eq!(results[0].signature, index.signature(query).unwrap());
+        assert!(results.iter().all(|r| r.signature == results[0].signature));
+    }
+
+    #[test]
+    fn lsh_index_should_insert_and_remove_vectors() {
+        let (mut index, vectors, vector_ids) = synthetic_index();
+        assert!(matches!(
+            index.insert(vector_ids[0], vectors.get(0)),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            index.insert(Uuid::new_v4(), &[0.0f32; 7][..]),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            index.insert(Uuid::new_v4(), &[f32::NAN; 8][..]),
+            Err(Error::InvalidArgs(_)),
+        ));
+        let signature = index.signature(vectors.get(0)).unwrap();
+        let bucket_size = index.get_bucket(signature).unwrap().num_vectors();
+        assert!(index.remove(&vector_ids[0]));
+        assert!(!index.remove(&vector_ids[0]));
+        assert_eq!(index.num_vectors(), 199);
+        match index.get_bucket(signature) {
+            Some(bucket) => {
+                assert_eq!(bucket.num_vectors(), bucket_size - 1);
+                assert!(!bucket.vector_ids().contains(&vector_ids[0]));
+                assert_eq!(bucket.vectors().len(), bucket_size - 1);
+            },
+            None => assert_eq!(bucket_size, 1),
+        }
+        // emptied buckets are removed
+        for id in vector_ids.iter() {
+            index.remove(id);
+        }
+        assert_eq!(index.num_buckets(), 0);
+        assert!(matches!(
+            LshIndex::<f32>::new(
+                8.try_into().unwrap(),
+                65.try_into().unwrap(),
+                1,
+            ),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn stored_lsh_index_should_query_like_index_in_memory() {
+        let (mut index, vectors, vector_ids) = synthetic_index();
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let path = serialize_lsh_index(&index, &fs).unwrap();
+        let stored = StoredLshIndex::load(
+            LocalFileSystem::new(dir.path()),
+            &path,
+        ).unwrap();
+        assert_eq!(stored.vector_size(), 8);
+        assert_eq!(stored.num_bits(), 4);
+        assert_eq!(stored.num_buckets(), index.num_buckets());
+        assert_eq!(stored.num_vectors(), 200);
+        let k = 5.try_into().unwrap();
+        let nprobe = 2.try_into().unwrap();
+        for i in [0, 50, 100] {
+            let expected = index.query(vectors.get(i), k, nprobe).unwrap();
+            let results = stored.query(vectors.get(i), k, nprobe).unwrap();
+            assert_eq!(results.len(), expected.len());
+            for (result, expected) in results.iter().zip(expected.iter()) {
+                assert_eq!(result.vector_id, expected.vector_id);
+                assert_eq!(result.squared_distance, expected.squared_distance);
+            }
+        }
+
+        // only the bucket of a removed vector is written to a new file
+        let mut loaded =
+            load_lsh_index(LocalFileSystem::new(dir.path()), &path).unwrap();
+        assert_eq!(loaded.num_vectors(), 200);
+        assert!(loaded.remove(&vector_ids[0]));
+        assert!(index.remove(&vector_ids[0]));
+        let new_path = serialize_lsh_index(&loaded, &fs).unwrap();
+        assert_ne!(new_path, path);
+        let num_buckets = index.num_buckets();
+        // the old file of the bucket is left behind
+        assert_eq!(fs.list_files("buckets").unwrap().len(), num_buckets + 1);
+        let stored = StoredLshIndex::load(fs, &new_path).unwrap();
+        assert_eq!(stored.num_vectors(), 199);
+        let results = stored.query(vectors.get(0), k, nprobe).unwrap();
+        assert!(results.iter().all(|r| r.vector_id != vector_ids[0]));
+    }
+}
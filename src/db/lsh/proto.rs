@@ -0,0 +1,88 @@
+//! Protocol Buffers utilities for [`lsh`][`crate::db::lsh`].
+
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::protos::database::LshBucket as ProtosLshBucket;
+use crate::protos::{Deserialize, Serialize};
+use crate::vector::BlockVectorSet;
+
+use super::LshBucket;
+
+impl Serialize<ProtosLshBucket> for LshBucket<f32> {
+    fn serialize(&self) -> Result<ProtosLshBucket, Error> {
+        let mut bucket = ProtosLshBucket::new();
+        bucket.signature = self.signature;
+        bucket.vector_ids = self.vector_ids
+            .iter()
+            .map(|id| id.serialize())
+            .collect::<Result<_, _>>()?;
+        bucket.vectors = Some(self.vectors.serialize()?).into();
+        Ok(bucket)
+    }
+}
+
+impl Deserialize<LshBucket<f32>> for ProtosLshBucket {
+    fn deserialize(self) -> Result<LshBucket<f32>, Error> {
+        let vectors: BlockVectorSet<f32> = self.vectors
+            .into_option()
+            .ok_or(Error::InvalidData("missing vectors".to_string()))?
+            .deserialize()?;
+        if vectors.len() != self.vector_ids.len() {
+            return Err(Error::InvalidData(format!(
+                "number of vector IDs is inconsistent: expected {} but got {}",
+                vectors.len(),
+                self.vector_ids.len(),
+            )));
+        }
+        let vector_ids = self.vector_ids
+            .into_iter()
+            .map(|id| id.deserialize())
+            .collect::<Result<Vec<Uuid>, Error>>()?;
+        Ok(LshBucket {
+            signature: self.signature,
+            vector_ids,
+            vectors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsh_bucket_can_be_serialized_and_deserialized() {
+        let input = LshBucket {
+            signature: 0b1010,
+            vector_ids: vec![Uuid::new_v4(), Uuid::new_v4()],
+            vectors: BlockVectorSet::chunk(
+                vec![1.0f32, 2.0, 3.0, 4.0],
+                2.try_into().unwrap(),
+            ).unwrap(),
+        };
+        let serialized = input.serialize().unwrap();
+        assert_eq!(serialized.signature, 0b1010);
+        let output: LshBucket<f32> = serialized.deserialize().unwrap();
+        assert_eq!(output.signature, input.signature);
+        assert_eq!(output.vector_ids, input.vector_ids);
+        assert_eq!(output.vectors.get(1), &[3.0, 4.0]);
+    }
+
+    #[test]
+    fn lsh_bucket_cannot_be_deserialized_with_inconsistent_vector_ids() {
+        let mut input = LshBucket {
+            signature: 1,
+            vector_ids: vec![Uuid::new_v4()],
+            vectors: BlockVectorSet::chunk(
+                vec![1.0f32, 2.0, 3.0, 4.0],
+                2.try_into().unwrap(),
+            ).unwrap(),
+        }.serialize().unwrap();
+        let output: Result<LshBucket<f32>, Error> = input.clone().deserialize();
+        assert!(matches!(output, Err(Error::InvalidData(_))));
+        input.vectors = None.into();
+        let output: Result<LshBucket<f32>, Error> = input.deserialize();
+        assert!(matches!(output, Err(Error::InvalidData(_))));
+    }
+}
@@ -0,0 +1,113 @@
+//! Versioning of databases with a mutable pointer.
+//!
+//! Every file of a database is named after its contents, so a new version of
+//! a database is written next to the previous one without touching it. The
+//! pointer [`CURRENT`] names the database header of the active version, and
+//! publishing a new version replaces the pointer atomically. Readers that
+//! have loaded the previous version keep using its files until they load
+//! [`current_database`] again.
+
+use crate::error::Error;
+use crate::io::{FileSystem, HashedFileIn, ReadFileSystem};
+use crate::protos::database::Database as ProtosDatabase;
+use crate::protos::read_message;
+
+/// Path to the pointer that names the database header of the current
+/// version.
+pub const CURRENT: &str = "CURRENT";
+
+/// Publishes the database header at a given path as the current version.
+///
+/// Loads and verifies the header before replacing [`CURRENT`], so that the
+/// pointer never names a missing or damaged header. The files the header
+/// refers to must have been written before.
+///
+/// The file system has to support
+/// [`WriteFileSystem::write_pointer`](crate::io::WriteFileSystem::write_pointer).
+pub fn publish_database<FS, P>(fs: &FS, path: P) -> Result<(), Error>
+where
+    FS: FileSystem,
+    P: AsRef<str>,
+{
+    let mut f = fs.open_compressed_hashed_file(path.as_ref())?;
+    let _: ProtosDatabase = read_message(&mut f)?;
+    f.verify()?;
+    fs.write_pointer(CURRENT, path)
+}
+
+/// Returns the path to the database header of the current version.
+///
+/// Fails with `Error::InvalidContext` if no version has been published.
+pub fn current_database<FS>(fs: &FS) -> Result<String, Error>
+where
+    FS: ReadFileSystem,
+{
+    check_published(fs.read_pointer(CURRENT)?)
+}
+
+// Fails unless the pointer to the current version exists.
+pub(crate) fn check_published(
+    current: Option<String>,
+) -> Result<String, Error> {
+    current.ok_or(Error::InvalidContext(format!(
+        "no database has been published: {} is missing",
+        CURRENT,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::proto::{
+        SerializeOptions,
+        serialize_database,
+        serialize_database_with_options,
+    };
+    use crate::db::fixtures::build_seeded_database;
+    use crate::db::stored::{Database, LoadDatabase};
+    use crate::io::LocalFileSystem;
+
+    #[test]
+    fn current_database_should_follow_published_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = LocalFileSystem::new(dir.path());
+        assert!(matches!(
+            current_database(&fs),
+            Err(Error::InvalidContext(_)),
+        ));
+
+        let options = SerializeOptions::new().with_publishing(true);
+        serialize_database_with_options(
+            &build_seeded_database(1),
+            &mut fs,
+            &options,
+        ).unwrap();
+        let first = current_database(&fs).unwrap();
+        let old = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).unwrap();
+
+        // an unpublished version does not change the pointer
+        serialize_database(&build_seeded_database(2), &mut fs).unwrap();
+        assert_eq!(current_database(&fs).unwrap(), first);
+        let second = fs.list_files("")
+            .unwrap()
+            .into_iter()
+            .find(|path| !path.contains('/') && path != CURRENT && *path != first)
+            .unwrap();
+        publish_database(&fs, &second).unwrap();
+        assert_eq!(current_database(&fs).unwrap(), second);
+        let new = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).unwrap();
+        assert_ne!(old.get_partition_id(0), new.get_partition_id(0));
+
+        // the previous version stays usable
+        let query = [0.0f32; 4];
+        old.query(&query[..], 1.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        assert!(publish_database(&fs, "missing.binpb").is_err());
+        assert_eq!(current_database(&fs).unwrap(), second);
+    }
+}
@@ -0,0 +1,1043 @@
+//! Metrics to score vectors in a query.
+
+use core::num::NonZeroUsize;
+
+use crate::kmeans::Scalar;
+use crate::linalg::parallel;
+use crate::linalg::simd::accumulate_fast_scan;
+use crate::vector::{BlockVectorSet, Code, EncodedVectorSet, VectorSet};
+use crate::vector::fastscan::{BLOCK_SIZE, FastScanCodes, NUM_CODES};
+use crate::vector::proto::LazyEncodedVectorSet;
+
+/// Metric to score vectors in a query.
+///
+/// Scores are calculated against the approximate vectors reconstructed
+/// from a partition centroid and PQ codes, so every metric works with any
+/// database. Since databases are quantized, and by default partitioned,
+/// under the squared Euclidean distance, metrics other than
+/// [`QueryMetric::SquaredL2`] give the best recall on normalized vectors,
+/// for which all the metrics rank vectors in the same order. See
+/// [`DatabaseBuilder::with_distance`](crate::db::build::DatabaseBuilder::with_distance)
+/// to partition under another distance.
+///
+/// A smaller score means a closer vector under every metric.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryMetric {
+    /// Squared Euclidean (L2) distance.
+    #[default]
+    SquaredL2,
+    /// Negated inner product.
+    NegativeDot,
+    /// Cosine distance; i.e., one minus the cosine similarity.
+    ///
+    /// A zero vector is regarded as orthogonal to any vector.
+    Cosine,
+}
+
+impl QueryMetric {
+    /// Scores a partition centroid against a query vector.
+    ///
+    /// Panics if the vector sizes do not match.
+    pub fn score<T>(&self, query: &[T], centroid: &[T]) -> T
+    where
+        T: Scalar,
+    {
+        match self {
+            Self::SquaredL2 => T::squared_distance(query, centroid),
+            Self::NegativeDot => T::zero() - T::dot(query, centroid),
+            Self::Cosine => cosine_distance(
+                T::dot(query, centroid),
+                T::dot(query, query),
+                T::dot(centroid, centroid),
+            ),
+        }
+    }
+
+    // Scores a partition centroid against a query vector, splitting the
+    // reductions among `num_threads` threads if given.
+    //
+    // See `OpenOptions::with_parallel_reduction`.
+    //
+    // Panics if the vector sizes do not match.
+    pub(crate) fn score_with_threads<T>(
+        &self,
+        query: &[T],
+        centroid: &[T],
+        num_threads: Option<NonZeroUsize>,
+    ) -> T
+    where
+        T: Scalar,
+    {
+        let Some(num_threads) = num_threads else {
+            return self.score(query, centroid);
+        };
+        let dot = |xs: &[T], ys: &[T]| parallel::dot(xs, ys, num_threads);
+        match self {
+            Self::SquaredL2 => {
+                parallel::squared_distance(query, centroid, num_threads)
+            },
+            Self::NegativeDot => T::zero() - dot(query, centroid),
+            Self::Cosine => cosine_distance(
+                dot(query, centroid),
+                dot(query, query),
+                dot(centroid, centroid),
+            ),
+        }
+    }
+}
+
+// Lookup table that scores encoded vectors in a partition.
+//
+// For every division and code, holds a term of the score between the query
+// subvector and the reconstructed subvector; i.e., the sum of the partition
+// centroid and the code vector:
+// - squared distance for `SquaredL2`
+// - inner product for `NegativeDot` and `Cosine`
+// `Cosine` additionally holds the squared norms of reconstructed subvectors.
+//
+// `quantize` additionally turns the terms into bytes for fast scan.
+//
+// A table can be recalculated for another partition without reallocating
+// its buffers.
+pub(crate) struct ScoreTable<T> {
+    metric: QueryMetric,
+    num_codes: usize,
+    terms: Vec<T>,
+    norms: Vec<T>,
+    // Squared norm of the query vector.
+    query_norm2: T,
+    // Buffer for a reconstructed subvector.
+    reconstructed: Vec<T>,
+    // Terms quantized into `NUM_CODES` bytes per division.
+    lut: Vec<u8>,
+    // Score of a vector whose quantized terms are all zero.
+    lut_bias: T,
+    // Score of a unit of quantized terms.
+    lut_scale: T,
+    // Upper bound of the difference between a score made of quantized
+    // terms and that made of the terms. Zero unless quantized.
+    lut_error: T,
+}
+
+impl<T> ScoreTable<T>
+where
+    T: Scalar,
+{
+    // Creates an empty table.
+    //
+    // `recalculate` calculates the table for a partition.
+    pub(crate) fn empty(metric: QueryMetric) -> Self {
+        Self {
+            metric,
+            num_codes: 0,
+            terms: Vec::new(),
+            norms: Vec::new(),
+            query_norm2: T::zero(),
+            reconstructed: Vec::new(),
+            lut: Vec::new(),
+            lut_bias: T::zero(),
+            lut_scale: T::zero(),
+            lut_error: T::zero(),
+        }
+    }
+
+    // Recalculates the table for a partition reusing the buffers.
+    //
+    // Panics if the sizes of `query`, `centroid`, and code vectors are not
+    // consistent.
+    pub(crate) fn recalculate<'a, I>(
+        &mut self,
+        query: &[T],
+        centroid: &[T],
+        codebooks: I,
+    )
+    where
+        T: 'a,
+        I: IntoIterator<Item = &'a BlockVectorSet<T>>,
+    {
+        self.num_codes = 0;
+        self.terms.clear();
+        self.norms.clear();
+        self.lut_error = T::zero();
+        let mut from = 0;
+        for codebook in codebooks {
+            self.num_codes = codebook.len();
+            let to = from + codebook.vector_size();
+            let subq = &query[from..to];
+            let subc = &centroid[from..to];
+            for (_, code_vector) in codebook.iter() {
+                let reconstructed = &mut self.reconstructed;
+                reconstructed.clear();
+                reconstructed.extend_from_slice(subc);
+                T::add_in(reconstructed, code_vector);
+                match self.metric {
+                    QueryMetric::SquaredL2 => {
+                        self.terms.push(T::squared_distance(
+                            subq,
+                            reconstructed,
+                        ));
+                    },
+                    QueryMetric::NegativeDot => {
+                        self.terms.push(T::dot(subq, reconstructed));
+                    },
+                    QueryMetric::Cosine => {
+                        self.terms.push(T::dot(subq, reconstructed));
+                        self.norms.push(T::dot(reconstructed, reconstructed));
+                    },
+                }
+            }
+            from = to;
+        }
+        self.query_norm2 = T::dot(query, query);
+    }
+
+    // Scores a vector encoded into a code per division.
+    //
+    // Panics if a code is out of bounds.
+    pub(crate) fn score<I>(&self, codes: I) -> T
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let mut term = T::zero();
+        let mut norm2 = T::zero();
+        for (di, ci) in codes.into_iter().enumerate() {
+            let i = di * self.num_codes + ci;
+            term += self.terms[i];
+            if self.metric == QueryMetric::Cosine {
+                norm2 += self.norms[i];
+            }
+        }
+        self.finish(term, norm2)
+    }
+
+    // Scores all the encoded vectors in a partition.
+    //
+    // Looks up codes division by division so that only a single row of the
+    // table is hot at a time; a row of 4-bit codes has as few as 16 entries
+    // and fits in vector registers.
+    //
+    // Replaces the contents of `scores` with the scores. `norms` is a buffer
+    // for the squared norms of the reconstructed vectors.
+    //
+    // Panics if a code is out of bounds.
+    pub(crate) fn score_all_into<C>(
+        &self,
+        encoded_vectors: &BlockVectorSet<C>,
+        scores: &mut Vec<T>,
+        norms: &mut Vec<T>,
+    )
+    where
+        C: Code,
+    {
+        let n = encoded_vectors.len();
+        scores.clear();
+        scores.resize(n, T::zero());
+        norms.clear();
+        if self.metric == QueryMetric::Cosine {
+            norms.resize(n, T::zero());
+        }
+        for di in 0..encoded_vectors.vector_size() {
+            let from = di * self.num_codes;
+            let to = from + self.num_codes;
+            let row = &self.terms[from..to];
+            for (vi, term) in scores.iter_mut().enumerate() {
+                *term += row[encoded_vectors.get(vi)[di].index()];
+            }
+            if !norms.is_empty() {
+                let row = &self.norms[from..to];
+                for (vi, norm2) in norms.iter_mut().enumerate() {
+                    *norm2 += row[encoded_vectors.get(vi)[di].index()];
+                }
+            }
+        }
+        for (vi, score) in scores.iter_mut().enumerate() {
+            *score = self.finish(
+                *score,
+                norms.get(vi).copied().unwrap_or(T::zero()),
+            );
+        }
+    }
+
+    // Quantizes the terms into bytes for `score_fast_scan_into`.
+    //
+    // Maps the terms of each division to the levels above the smallest term
+    // with a step shared among the divisions, so that the sum of levels of
+    // all the divisions fits in `u16`. Then a score made of quantized terms
+    // differs by at most a step per division.
+    //
+    // Returns `false` and leaves the table as it is if the table cannot be
+    // quantized; i.e., the metric is `Cosine`, which is not a sum of terms,
+    // a codebook has more than `NUM_CODES` codes, or there are too many
+    // divisions.
+    pub(crate) fn quantize(&mut self) -> bool {
+        if self.metric == QueryMetric::Cosine
+            || self.num_codes == 0
+            || self.num_codes > NUM_CODES
+        {
+            return false;
+        }
+        let num_divisions = self.terms.len() / self.num_codes;
+        let levels = (u8::MAX as usize).min(u16::MAX as usize / num_divisions);
+        if levels == 0 {
+            return false;
+        }
+        let mut bias = T::zero();
+        let mut max_range = T::zero();
+        for row in self.terms.chunks_exact(self.num_codes) {
+            let (min, max) = self.signed_range(row);
+            bias += min;
+            if max - min > max_range {
+                max_range = max - min;
+            }
+        }
+        let mut scale = max_range / T::from_as(levels);
+        if scale == T::zero() {
+            scale = T::one();
+        }
+        self.lut.clear();
+        for row in self.terms.chunks_exact(self.num_codes) {
+            let (min, _) = self.signed_range(row);
+            for &term in row {
+                let level = (self.signed_term(term) - min) / scale;
+                self.lut.push(round_level(level, levels));
+            }
+            self.lut.resize(self.lut.len() + NUM_CODES - row.len(), 0);
+        }
+        self.lut_bias = bias;
+        self.lut_scale = scale;
+        // rounding to the nearest level errs by half a step per division,
+        // and the other half leaves room for rounding errors of `T`
+        self.lut_error = scale * T::from_as(num_divisions);
+        true
+    }
+
+    // Scores all the encoded vectors in a partition with the quantized
+    // terms.
+    //
+    // Replaces the contents of `scores` with the scores.
+    //
+    // Panics if the table is not quantized for `codes`.
+    pub(crate) fn score_fast_scan_into(
+        &self,
+        codes: &FastScanCodes,
+        scores: &mut Vec<T>,
+    ) {
+        assert_eq!(self.lut.len(), codes.num_divisions() * NUM_CODES);
+        scores.clear();
+        for bi in 0..codes.num_blocks() {
+            let mut sums = [0u16; BLOCK_SIZE];
+            accumulate_fast_scan(codes.block(bi), &self.lut, &mut sums);
+            let n = (codes.len() - bi * BLOCK_SIZE).min(BLOCK_SIZE);
+            scores.extend(sums[..n].iter().map(|&sum| {
+                let mut score = self.lut_scale * T::from_as(sum as usize);
+                score += self.lut_bias;
+                score
+            }));
+        }
+    }
+
+    // Returns a term with the sign of the score.
+    fn signed_term(&self, term: T) -> T {
+        match self.metric {
+            QueryMetric::NegativeDot => T::zero() - term,
+            _ => term,
+        }
+    }
+
+    // Returns the smallest and largest signed terms in a row.
+    fn signed_range(&self, row: &[T]) -> (T, T) {
+        let mut terms = row.iter().map(|&term| self.signed_term(term));
+        let first = terms.next().expect("row must not be empty");
+        terms.fold((first, first), |(min, max), term| {
+            (
+                if term < min { term } else { min },
+                if term > max { term } else { max },
+            )
+        })
+    }
+
+    // Turns the sum of terms into a score.
+    fn finish(&self, term: T, norm2: T) -> T {
+        match self.metric {
+            QueryMetric::SquaredL2 => term,
+            QueryMetric::NegativeDot => T::zero() - term,
+            QueryMetric::Cosine => {
+                cosine_distance(term, self.query_norm2, norm2)
+            },
+        }
+    }
+
+    // Corrects the score of a vector by the squared norm of its
+    // quantization error.
+    //
+    // Adds `error2` under `SquaredL2`, because the error is uncorrelated with
+    // the vector from the query to the reconstructed vector on average, and
+    // ‖q - x‖² = ‖q - x̂‖² - 2(q - x̂) · e + ‖e‖². Leaves the other scores as
+    // they are, whose expected errors are zero.
+    pub(crate) fn correct(&self, score: T, error2: T) -> T {
+        match self.metric {
+            QueryMetric::SquaredL2 => {
+                let mut score = score;
+                score += error2;
+                score
+            },
+            QueryMetric::NegativeDot | QueryMetric::Cosine => score,
+        }
+    }
+
+    // Estimates the upper bound of the difference between the score of a
+    // vector and the exact score.
+    //
+    // `error2` is the squared norm of the quantization error of the vector if
+    // known, in which case `score` is supposed to be corrected by it.
+    // Otherwise, falls back to `max_error2`; see `error_bound`.
+    pub(crate) fn vector_error_bound(
+        &self,
+        score: T,
+        error2: Option<T>,
+        max_error2: Option<T>,
+    ) -> Option<T> {
+        if self.lut_error > T::zero() {
+            // the score made of the terms may be larger by `lut_error`
+            let mut upper = score;
+            upper += self.lut_error;
+            let mut bound = self.exact_error_bound(upper, error2, max_error2)?;
+            bound += self.lut_error;
+            Some(bound)
+        } else {
+            self.exact_error_bound(score, error2, max_error2)
+        }
+    }
+
+    // Estimates the upper bound of the difference between the score of a
+    // vector made of the terms and the exact score.
+    fn exact_error_bound(
+        &self,
+        score: T,
+        error2: Option<T>,
+        max_error2: Option<T>,
+    ) -> Option<T> {
+        match (error2, self.metric) {
+            // |‖q - x‖² - (‖q - x̂‖² + ‖e‖²)| ≤ 2‖q - x̂‖‖e‖
+            (Some(error2), QueryMetric::SquaredL2) => {
+                let distance2 = score - error2;
+                let distance = if distance2 > T::zero() {
+                    distance2.sqrt()
+                } else {
+                    T::zero()
+                };
+                let mut bound = distance * error2.sqrt();
+                bound += bound;
+                Some(bound)
+            },
+            (Some(error2), _) => self.error_bound(score, error2),
+            (None, _) => max_error2.and_then(|e| self.error_bound(score, e)),
+        }
+    }
+
+    // Estimates the upper bound of the difference between an approximate
+    // score and the exact score.
+    //
+    // `max_error2` is the sum of the maximum squared quantization errors of
+    // all the codebooks; i.e., the squared norm of the largest error vector.
+    //
+    // `None` for `Cosine`, whose error depends on the norm of the vector.
+    pub(crate) fn error_bound(&self, score: T, max_error2: T) -> Option<T> {
+        let error = max_error2.sqrt();
+        match self.metric {
+            // |‖q - x‖² - ‖q - x̂‖²| ≤ 2‖q - x̂‖‖e‖ + ‖e‖²
+            QueryMetric::SquaredL2 => {
+                let distance = if score > T::zero() {
+                    score.sqrt()
+                } else {
+                    T::zero()
+                };
+                let mut bound = distance;
+                bound += distance;
+                bound += error;
+                bound *= error;
+                Some(bound)
+            },
+            // |q · x - q · x̂| ≤ ‖q‖‖e‖
+            QueryMetric::NegativeDot => Some(self.query_norm2.sqrt() * error),
+            QueryMetric::Cosine => None,
+        }
+    }
+}
+
+// Scores the vector reconstructed from a partition centroid and a residue
+// exactly.
+//
+// `reconstructed` is a buffer for the reconstructed vector.
+//
+// Panics if the vector sizes do not match.
+pub(crate) fn score_residue<T>(
+    metric: QueryMetric,
+    query: &[T],
+    centroid: &[T],
+    residue: &[T],
+    reconstructed: &mut Vec<T>,
+) -> T
+where
+    T: Scalar,
+{
+    reconstructed.clear();
+    reconstructed.extend_from_slice(centroid);
+    T::add_in(reconstructed, residue);
+    metric.score(query, reconstructed)
+}
+
+// Reusable buffers to score vectors in partitions during a single query.
+//
+// Keeps the score table and scores of the last scored partition so that
+// probing another partition allocates nothing once the buffers have grown.
+pub(crate) struct QueryScratch<T> {
+    table: ScoreTable<T>,
+    scores: Vec<T>,
+    norms: Vec<T>,
+    // Buffer for a vector reconstructed from a residue.
+    reconstructed: Vec<T>,
+    // Whether `score_partition` tries fast scan.
+    fast_scan: bool,
+}
+
+impl<T> QueryScratch<T>
+where
+    T: Scalar,
+{
+    // Creates empty buffers for a query under a given metric.
+    pub(crate) fn new(metric: QueryMetric) -> Self {
+        Self {
+            table: ScoreTable::empty(metric),
+            scores: Vec::new(),
+            norms: Vec::new(),
+            reconstructed: Vec::new(),
+            fast_scan: false,
+        }
+    }
+
+    // Creates buffers for a query under a given metric, which can score a
+    // partition of up to a given number of vectors without reallocation.
+    pub(crate) fn with_capacity(metric: QueryMetric, capacity: usize) -> Self {
+        let mut scratch = Self::new(metric);
+        scratch.scores.reserve_exact(capacity);
+        if metric == QueryMetric::Cosine {
+            scratch.norms.reserve_exact(capacity);
+        }
+        scratch
+    }
+
+    // Makes `score_partition` try fast scan.
+    pub(crate) fn with_fast_scan(mut self, fast_scan: bool) -> Self {
+        self.fast_scan = fast_scan;
+        self
+    }
+
+    // Scores all the encoded vectors in a partition.
+    //
+    // Corrects the scores by `squared_error_norms` unless `None`; see
+    // `ScoreTable::correct`.
+    //
+    // With fast scan enabled, scores vectors with the terms quantized into
+    // bytes if the codes fit in 4 bits and the table can be quantized; see
+    // `ScoreTable::quantize`. The error bounds given by the returned table
+    // then account for the quantization of the terms.
+    //
+    // Returns the score table of the partition and the scores of the
+    // vectors, which remain valid until the next call.
+    //
+    // Panics if the sizes of `query`, `centroid`, and code vectors are not
+    // consistent, if a code is out of bounds, or if the number of
+    // `squared_error_norms` does not match that of the encoded vectors.
+    pub(crate) fn score_partition<'a, I>(
+        &mut self,
+        query: &[T],
+        centroid: &[T],
+        codebooks: I,
+        encoded_vectors: &LazyEncodedVectorSet,
+        squared_error_norms: Option<&[T]>,
+    ) -> (&ScoreTable<T>, &[T])
+    where
+        T: 'a,
+        I: IntoIterator<Item = &'a BlockVectorSet<T>>,
+    {
+        self.table.recalculate(query, centroid, codebooks);
+        let codes = self.fast_scan
+            .then(|| encoded_vectors.fast_scan_codes())
+            .flatten();
+        match codes {
+            Some(codes) if self.table.quantize() => {
+                self.table.score_fast_scan_into(codes, &mut self.scores);
+            },
+            _ => self.score_encoded_vectors(encoded_vectors.get()),
+        }
+        self.correct_scores(squared_error_norms);
+        (&self.table, &self.scores)
+    }
+
+    // Scores encoded vectors with the current table.
+    fn score_encoded_vectors(&mut self, encoded_vectors: &EncodedVectorSet) {
+        match encoded_vectors {
+            EncodedVectorSet::U8(vs) => {
+                self.table.score_all_into(vs, &mut self.scores, &mut self.norms)
+            },
+            EncodedVectorSet::U32(vs) => {
+                self.table.score_all_into(vs, &mut self.scores, &mut self.norms)
+            },
+        }
+    }
+
+    // Corrects the current scores unless `squared_error_norms` is `None`.
+    fn correct_scores(&mut self, squared_error_norms: Option<&[T]>) {
+        if let Some(norms) = squared_error_norms {
+            assert_eq!(norms.len(), self.scores.len());
+            for (score, &error2) in self.scores.iter_mut().zip(norms) {
+                *score = self.table.correct(*score, error2);
+            }
+        }
+    }
+
+    // Scores all the residues in a partition exactly.
+    //
+    // Returns the scores of the vectors, which remain valid until the next
+    // call.
+    //
+    // Panics if the sizes of `query`, `centroid`, and residues are not
+    // consistent.
+    pub(crate) fn score_residues(
+        &mut self,
+        query: &[T],
+        centroid: &[T],
+        residues: &BlockVectorSet<T>,
+    ) -> &[T] {
+        let metric = self.table.metric;
+        self.scores.clear();
+        for (_, residue) in residues.iter() {
+            self.scores.push(score_residue(
+                metric,
+                query,
+                centroid,
+                residue,
+                &mut self.reconstructed,
+            ));
+        }
+        &self.scores
+    }
+}
+
+// Rounds a non-negative number to the nearest level up to `max_level`.
+//
+// Compares `level` with integers since `T` cannot be cast into one.
+fn round_level<T>(level: T, max_level: usize) -> u8
+where
+    T: Scalar,
+{
+    // finds the largest `l` such that `l - 0.5 ≤ level`
+    let mut doubled = level;
+    doubled += level;
+    doubled += T::one();
+    let (mut lo, mut hi) = (0, max_level);
+    while lo < hi {
+        let mid = (lo + hi).div_ceil(2);
+        if T::from_as(mid * 2) <= doubled {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    lo as u8
+}
+
+// Calculates the cosine distance from an inner product and squared norms.
+fn cosine_distance<T>(dot: T, norm2_x: T, norm2_y: T) -> T
+where
+    T: Scalar,
+{
+    let norms = (norm2_x * norm2_y).sqrt();
+    if norms == T::zero() {
+        return T::one();
+    }
+    let distance = T::one() - dot / norms;
+    // cancels out a rounding error
+    if distance < T::zero() {
+        T::zero()
+    } else {
+        distance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    use crate::db::OpenOptions;
+    use crate::db::build::DatabaseBuilder;
+    use crate::db::build::proto::SerializeOptions;
+    use crate::db::fixtures::{
+        build_database_with,
+        store_database,
+        synthetic_vectors,
+    };
+    use crate::db::stored::{Database as StoredDatabase, LoadDatabase};
+    use crate::io::LocalFileSystem;
+
+    fn codebooks() -> Vec<BlockVectorSet<f32>> {
+        vec![
+            BlockVectorSet::chunk(
+                vec![0.0, 0.0, 1.0, 0.0],
+                2.try_into().unwrap(),
+            ).unwrap(),
+            BlockVectorSet::chunk(
+                vec![0.0, 0.0, 0.0, 1.0],
+                2.try_into().unwrap(),
+            ).unwrap(),
+        ]
+    }
+
+    fn score_table(
+        metric: QueryMetric,
+        query: &[f32],
+        centroid: &[f32],
+    ) -> ScoreTable<f32> {
+        let mut table = ScoreTable::empty(metric);
+        table.recalculate(query, centroid, &codebooks());
+        table
+    }
+
+    #[test]
+    fn score_table_should_score_reconstructed_vectors() {
+        let query = [1.0f32, 2.0, 3.0, 4.0];
+        let centroid = [1.0f32, 1.0, 1.0, 1.0];
+        // reconstructed: [2.0, 1.0, 1.0, 2.0]
+        let encoded = [1usize, 1];
+        let table = score_table(QueryMetric::SquaredL2, &query, &centroid);
+        assert_eq!(table.score(encoded), 1.0 + 1.0 + 4.0 + 4.0);
+        let table = score_table(QueryMetric::NegativeDot, &query, &centroid);
+        assert_eq!(table.score(encoded), -(2.0 + 2.0 + 3.0 + 8.0));
+        let table = score_table(QueryMetric::Cosine, &query, &centroid);
+        let expected = 1.0 - 15.0 / (30.0f32 * 10.0).sqrt();
+        assert!((table.score(encoded) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn score_all_should_match_score_of_each_vector() {
+        let query = [1.0f32, 2.0, 3.0, 4.0];
+        let centroid = [0.5f32, -1.0, 0.0, 2.0];
+        let encoded_vectors = BlockVectorSet::chunk(
+            vec![0u32, 0, 0, 1, 1, 0, 1, 1],
+            2.try_into().unwrap(),
+        ).unwrap();
+        for metric in [
+            QueryMetric::SquaredL2,
+            QueryMetric::NegativeDot,
+            QueryMetric::Cosine,
+        ] {
+            let table = score_table(metric, &query, &centroid);
+            let mut scores = Vec::new();
+            let mut norms = Vec::new();
+            table.score_all_into(&encoded_vectors, &mut scores, &mut norms);
+            assert_eq!(scores.len(), 4);
+            for (vi, codes) in encoded_vectors.iter() {
+                let expected = table.score(codes.iter().map(|&c| c as usize));
+                assert_eq!(scores[vi], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn query_scratch_should_score_partitions_like_fresh_tables() {
+        let query = [1.0f32, 2.0, 3.0, 4.0];
+        let centroids = [[0.5f32, -1.0, 0.0, 2.0], [1.0f32, 1.0, 1.0, 1.0]];
+        let encoded_vectors = BlockVectorSet::chunk(
+            vec![0u32, 0, 0, 1, 1, 0, 1, 1],
+            2.try_into().unwrap(),
+        ).unwrap();
+        for metric in [
+            QueryMetric::SquaredL2,
+            QueryMetric::NegativeDot,
+            QueryMetric::Cosine,
+        ] {
+            let mut scratch = QueryScratch::new(metric);
+            for centroid in centroids.iter() {
+                let table = score_table(metric, &query, centroid);
+                let (_, scores) = scratch.score_partition(
+                    &query,
+                    centroid,
+                    &codebooks(),
+                    &EncodedVectorSet::with_num_codes(
+                        encoded_vectors.clone(),
+                        2,
+                    ).unwrap().into(),
+                    None,
+                );
+                for (vi, codes) in encoded_vectors.iter() {
+                    assert_eq!(
+                        scores[vi],
+                        table.score(codes.iter().map(|&c| c as usize)),
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_table_should_score_within_its_error() {
+        // 3 divisions of 2 elements with 16 codes each
+        let codebooks: Vec<BlockVectorSet<f32>> = (0..3)
+            .map(|di| BlockVectorSet::chunk(
+                (0..32).map(|i| ((i * 7 + di * 5) as f32 * 0.61).sin())
+                    .collect(),
+                2.try_into().unwrap(),
+            ).unwrap())
+            .collect();
+        let encoded_vectors = BlockVectorSet::chunk(
+            (0..70 * 3).map(|i| ((i * 11) % 16) as u8).collect(),
+            3.try_into().unwrap(),
+        ).unwrap();
+        let codes = FastScanCodes::from_codes(&encoded_vectors).unwrap();
+        let query = [1.0f32, -2.0, 0.5, 0.25, 3.0, -1.0];
+        let centroid = [0.5f32, 0.0, -0.5, 1.0, 0.0, 2.0];
+        for metric in [QueryMetric::SquaredL2, QueryMetric::NegativeDot] {
+            let mut table = ScoreTable::empty(metric);
+            table.recalculate(&query, &centroid, &codebooks);
+            let mut expected = Vec::new();
+            table.score_all_into(&encoded_vectors, &mut expected, &mut vec![]);
+            assert!(table.quantize());
+            assert!(table.lut_error > 0.0);
+            let mut scores = Vec::new();
+            table.score_fast_scan_into(&codes, &mut scores);
+            assert_eq!(scores.len(), 70);
+            for (score, expected) in scores.iter().zip(&expected) {
+                assert!((score - expected).abs() <= table.lut_error);
+            }
+            // error bounds include the error of quantized terms
+            let bound = table.vector_error_bound(scores[0], None, Some(0.0));
+            assert!(bound.unwrap() >= table.lut_error);
+        }
+        let mut table = ScoreTable::empty(QueryMetric::Cosine);
+        table.recalculate(&query, &centroid, &codebooks);
+        assert!(!table.quantize());
+        assert_eq!(round_level(2.49f32, 255), 2);
+        assert_eq!(round_level(2.5f32, 255), 3);
+        assert_eq!(round_level(300.0f32, 255), 255);
+    }
+
+    #[test]
+    fn query_scratch_should_fall_back_if_codes_exceed_4_bits() {
+        let query = [1.0f32, 2.0, 3.0, 4.0];
+        let centroid = [0.5f32, -1.0, 0.0, 2.0];
+        let encoded_vectors: LazyEncodedVectorSet =
+            EncodedVectorSet::with_num_codes(
+                BlockVectorSet::chunk(
+                    vec![0u32, 0, 0, 1, 1, 0, 1, 1],
+                    2.try_into().unwrap(),
+                ).unwrap(),
+                2,
+            ).unwrap().into();
+        let mut scratch =
+            QueryScratch::new(QueryMetric::SquaredL2).with_fast_scan(true);
+        let (table, _) = scratch.score_partition(
+            &query,
+            &centroid,
+            &codebooks(),
+            &encoded_vectors,
+            None,
+        );
+        // 2 codes fit in 4 bits
+        assert!(table.lut_error > 0.0);
+        let mut codebooks = codebooks();
+        for codebook in codebooks.iter_mut() {
+            for _ in 0..15 {
+                codebook.push(&[0.0, 0.0]).unwrap();
+            }
+        }
+        let (table, scores) = scratch.score_partition(
+            &query,
+            &centroid,
+            &codebooks,
+            &encoded_vectors,
+            None,
+        );
+        // 17 codes do not
+        assert_eq!(table.lut_error, 0.0);
+        let expected = score_table(QueryMetric::SquaredL2, &query, &centroid)
+            .score([1, 1]);
+        assert_eq!(scores[3], expected);
+    }
+
+    #[test]
+    fn query_scratch_should_score_residues_exactly() {
+        let query = [1.0f32, 2.0, 3.0, 4.0];
+        let centroid = [1.0f32, 1.0, 1.0, 1.0];
+        let residues = BlockVectorSet::chunk(
+            vec![0.0f32, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 2.0],
+            4.try_into().unwrap(),
+        ).unwrap();
+        let mut scratch = QueryScratch::new(QueryMetric::SquaredL2);
+        let scores = scratch.score_residues(&query, &centroid, &residues);
+        assert_eq!(scores, &[0.0 + 1.0 + 4.0 + 9.0, 1.0 + 1.0 + 4.0 + 1.0]);
+        let mut scratch = QueryScratch::new(QueryMetric::NegativeDot);
+        let scores = scratch.score_residues(&query, &centroid, &residues);
+        assert_eq!(scores, &[-10.0, -(2.0 + 2.0 + 3.0 + 12.0)]);
+    }
+
+    #[test]
+    fn error_bound_should_cover_quantization_error() {
+        let query = [1.0f32, 2.0, 3.0, 4.0];
+        let centroid = [0.0f32; 4];
+        let table = score_table(QueryMetric::SquaredL2, &query, &centroid);
+        // exact vector [1.0, 0.5, 0.0, 1.0] is encoded as [1.0, 0.0, 0.0, 1.0]
+        let score = table.score([1, 1]);
+        let exact = 0.0 + 1.5 * 1.5 + 9.0 + 9.0;
+        let bound = table.error_bound(score, 0.25).unwrap();
+        assert!((score - exact).abs() <= bound);
+        let table = score_table(QueryMetric::Cosine, &query, &centroid);
+        assert_eq!(table.error_bound(score, 0.25), None);
+    }
+
+    #[test]
+    fn corrected_score_should_be_bounded_by_vector_error() {
+        let centroid = [0.0f32; 4];
+        // exact vector [1.0, 0.5, 0.0, 1.0] is encoded as [1.0, 0.0, 0.0, 1.0]
+        let (codes, error2) = ([1, 1], 0.25);
+        // the error is orthogonal to the query minus the encoded vector
+        let query = [1.0f32, 0.0, 3.0, 4.0];
+        let table = score_table(QueryMetric::SquaredL2, &query, &centroid);
+        let score = table.correct(table.score(codes), error2);
+        assert_eq!(score, 0.25 + 9.0 + 9.0);
+        // the error is correlated with the query minus the encoded vector
+        let query = [1.0f32, 2.0, 3.0, 4.0];
+        let table = score_table(QueryMetric::SquaredL2, &query, &centroid);
+        let score = table.correct(table.score(codes), error2);
+        let exact = 0.0 + 1.5 * 1.5 + 9.0 + 9.0;
+        let bound = table
+            .vector_error_bound(score, Some(error2), None)
+            .unwrap();
+        assert!((score - exact).abs() <= bound);
+        assert!(bound < table.error_bound(table.score(codes), error2).unwrap());
+        let table = score_table(QueryMetric::NegativeDot, &query, &centroid);
+        assert_eq!(table.correct(-1.0, error2), -1.0);
+    }
+
+    #[test]
+    fn cosine_score_should_be_one_for_zero_vector() {
+        let zero = [0.0f32, 0.0];
+        assert_eq!(QueryMetric::Cosine.score(&zero, &[1.0, 2.0]), 1.0);
+    }
+
+    #[test]
+    fn scores_with_threads_should_be_close_to_scores() {
+        use crate::linalg::parallel::MIN_PARALLEL_LEN;
+
+        let n = MIN_PARALLEL_LEN + 5;
+        let query: Vec<f32> = (0..n).map(|i| (i as f32 * 0.37).sin()).collect();
+        let centroid: Vec<f32> =
+            (0..n).map(|i| (i as f32 * 0.11).cos()).collect();
+        for metric in [
+            QueryMetric::SquaredL2,
+            QueryMetric::NegativeDot,
+            QueryMetric::Cosine,
+        ] {
+            let expected = metric.score(&query, &centroid);
+            assert_eq!(
+                metric.score_with_threads(&query, &centroid, None),
+                expected,
+            );
+            let threads = 3.try_into().unwrap();
+            let actual =
+                metric.score_with_threads(&query, &centroid, Some(threads));
+            let tolerance = 1e-4 * expected.abs().max(1.0);
+            assert!((actual - expected).abs() <= tolerance);
+        }
+    }
+
+    #[test]
+    fn query_with_metric_should_rank_by_metric() {
+        let vs = BlockVectorSet::chunk(
+            vec![1.0f32, 0.0, 3.0, 0.0, 0.0, 1.0, 0.0, 0.5],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let db = DatabaseBuilder::new(vs)
+            .with_partitions(1.try_into().unwrap())
+            .with_divisions(1.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        let query = |metric| -> Vec<Uuid> {
+            db.query_with_metric(
+                &[1.0f32, 0.0][..],
+                2.try_into().unwrap(),
+                1.try_into().unwrap(),
+                metric,
+            )
+                .unwrap()
+                .iter()
+                .map(|r| r.vector_id)
+                .collect()
+        };
+        assert_eq!(query(QueryMetric::SquaredL2)[0], ids[0]);
+        assert_eq!(query(QueryMetric::NegativeDot)[0], ids[1]);
+        let mut cosine = query(QueryMetric::Cosine);
+        cosine.sort();
+        let mut expected = vec![ids[0], ids[1]];
+        expected.sort();
+        assert_eq!(cosine, expected);
+    }
+
+    #[test]
+    fn stored_database_with_fast_scan_should_bound_approximate_distances() {
+        let vectors = synthetic_vectors(200, 16);
+        let query = vectors.get(3).to_vec();
+        let db = build_database_with(200, 16, |builder| {
+            builder
+                .with_divisions(4.try_into().unwrap())
+                .with_clusters(16.try_into().unwrap())
+        });
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let load = |options| {
+            StoredDatabase::<f32, _>::load_database_with_options(
+                LocalFileSystem::new(dir.path()),
+                &header,
+                options,
+            ).unwrap()
+        };
+        let plain = load(OpenOptions::new());
+        let fast = load(OpenOptions::new().with_fast_scan(true));
+        for metric in [QueryMetric::SquaredL2, QueryMetric::NegativeDot] {
+            let query = |db: &StoredDatabase<f32, _>| {
+                let results: HashMap<Uuid, (f32, Option<f32>)> = db
+                    .query_with_metric(
+                        &query[..],
+                        200.try_into().unwrap(),
+                        2.try_into().unwrap(),
+                        metric,
+                    )
+                    .unwrap()
+                    .into_iter()
+                    .map(|r| {
+                        (r.vector_id, (r.squared_distance, r.error_bound))
+                    })
+                    .collect();
+                results
+            };
+            let expected = query(&plain);
+            let results = query(&fast);
+            assert_eq!(results.len(), 200);
+            let mut num_differences = 0;
+            for (id, (distance, bound)) in results.iter() {
+                let (expected, expected_bound) = expected[id];
+                assert!((distance - expected).abs() <= bound.unwrap());
+                assert!(bound.unwrap() > expected_bound.unwrap());
+                if *distance != expected {
+                    num_differences += 1;
+                }
+            }
+            // terms are actually quantized
+            assert!(num_differences > 0);
+        }
+    }
+}
@@ -1,16 +1,43 @@
 //! Protocol Buffers utilities for [`db`][`crate::db`] module.
 
+use protobuf::MessageField;
+use std::collections::HashMap;
+use uuid::Uuid;
+
 use crate::error::Error;
+use crate::io::codec::Codec;
+use crate::io::hash::HashAlgorithm;
+use crate::io::merkle::MerkleTree;
+use crate::projection::Projection;
 use crate::protos::{Deserialize, Serialize};
 use crate::protos::database::{
+    AttributeOperationType as ProtosAttributeOperationType,
     AttributeValue as ProtosAttributeValue,
+    AttributesLog as ProtosAttributesLog,
+    Codec as ProtosCodec,
+    Compression as ProtosCompression,
+    Database as ProtosDatabase,
+    FileCodec as ProtosFileCodec,
+    HashAlgorithm as ProtosHashAlgorithm,
+    Partition as ProtosPartition,
+    Quantization as ProtosQuantization,
     attribute_value::Value::{
         StringValue as ProtosStringValue,
         Uint64Value as ProtosUint64Value,
     },
 };
+use crate::vector::BlockVectorSet;
 
-use super::AttributeValue;
+use super::{
+    AttributeTable,
+    AttributeValue,
+    CompressionPolicy,
+    FileCompression,
+    FileKind,
+    Quantization,
+    QuantizationError,
+};
+use super::stored::PROTOBUF_EXTENSION;
 
 impl Serialize<ProtosAttributeValue> for AttributeValue {
     fn serialize(&self) -> Result<ProtosAttributeValue, Error> {
@@ -31,15 +58,745 @@ impl Deserialize<AttributeValue> for ProtosAttributeValue {
                 ProtosUint64Value(n) => Ok(AttributeValue::Uint64(n)),
             }
         } else {
-            Err(Error::InvalidData(format!("missing attribute value")))
+            Err(Error::InvalidData("missing attribute value".to_string()))
         }
     }
 }
 
+impl Serialize<ProtosCompression> for CompressionPolicy {
+    fn serialize(&self) -> Result<ProtosCompression, Error> {
+        let mut compression = ProtosCompression::new();
+        compression.partitions = self.is_compressed(FileKind::Partition);
+        compression.partition_centroids =
+            self.is_compressed(FileKind::PartitionCentroids);
+        compression.codebooks = self.is_compressed(FileKind::Codebook);
+        compression.attributes_logs =
+            self.is_compressed(FileKind::AttributesLog);
+        compression.residues = self.is_compressed(FileKind::Residues);
+        Ok(compression)
+    }
+}
+
+impl Deserialize<CompressionPolicy> for ProtosCompression {
+    fn deserialize(self) -> Result<CompressionPolicy, Error> {
+        Ok(CompressionPolicy::none()
+            .with_compressed(FileKind::Partition, self.partitions)
+            .with_compressed(
+                FileKind::PartitionCentroids,
+                self.partition_centroids,
+            )
+            .with_compressed(FileKind::Codebook, self.codebooks)
+            .with_compressed(FileKind::AttributesLog, self.attributes_logs)
+            .with_compressed(FileKind::Residues, self.residues))
+    }
+}
+
+// Resolves the value of an operation to set an attribute.
+//
+// Looks up `value_table` by `value_index` unless `value` is present.
+fn resolve_attribute_value(
+    value: MessageField<ProtosAttributeValue>,
+    value_index: u32,
+    value_table: &[AttributeValue],
+) -> Result<AttributeValue, Error> {
+    match value.into_option() {
+        Some(value) => value.deserialize(),
+        None => value_table
+            .get(value_index as usize)
+            .cloned()
+            .ok_or(Error::InvalidData(format!(
+                "missing value: value index {} out of bounds",
+                value_index,
+            ))),
+    }
+}
+
+// Operation decoded from an attributes log entry.
+enum AttributeOperation {
+    Set(Uuid, String, AttributeValue),
+    Remove(Uuid, String),
+}
+
+// Replays the entries of an attributes log on an attribute table.
+//
+// Decodes and validates all the entries before applying any of them, so
+// `attribute_table` is left untouched if the log is invalid. A log is
+// invalid if non-zero timestamps decrease, or entries of a batch are not
+// consecutive or batch numbers decrease.
+pub(crate) fn replay_attributes_log(
+    attributes_log: ProtosAttributesLog,
+    partition_index: usize,
+    attribute_names: &[String],
+    attribute_table: &mut AttributeTable,
+) -> Result<(), Error> {
+    let value_table = attributes_log.value_table
+        .into_iter()
+        .map(|value| value.deserialize())
+        .collect::<Result<Vec<AttributeValue>, _>>()?;
+    let mut last_timestamp = 0u64;
+    let mut last_batch = 0u64;
+    let mut max_batch = 0u64;
+    let mut operations = Vec::with_capacity(attributes_log.entries.len());
+    for (i, entry) in attributes_log.entries.into_iter().enumerate() {
+        let invalid = |e: String| Error::InvalidData(format!(
+            "attributes log[{}, {}]: {}",
+            partition_index,
+            i,
+            e,
+        ));
+        if entry.timestamp != 0 {
+            if entry.timestamp < last_timestamp {
+                return Err(invalid(format!(
+                    "timestamp {} precedes {}",
+                    entry.timestamp,
+                    last_timestamp,
+                )));
+            }
+            last_timestamp = entry.timestamp;
+        }
+        if entry.batch != 0 && entry.batch != last_batch {
+            if entry.batch <= max_batch {
+                return Err(invalid(format!(
+                    "batch {} is not consecutive or out of order",
+                    entry.batch,
+                )));
+            }
+            max_batch = entry.batch;
+        }
+        last_batch = entry.batch;
+        let attribute_name = attribute_names
+            .get(entry.name_index as usize)
+            .ok_or(Error::InvalidData(format!(
+                "attribute name index out of bounds: {}",
+                entry.name_index,
+            )))?
+            .clone();
+        let vector_id = entry.vector_id
+            .into_option()
+            .ok_or(invalid("missing vector ID".to_string()))?
+            .deserialize()
+            .map_err(|e| invalid(e.to_string()))?;
+        let operation = entry.operation
+            .enum_value()
+            .map_err(|n| invalid(format!("unknown operation: {}", n)))?;
+        operations.push(match operation {
+            ProtosAttributeOperationType::SET_ATTRIBUTE => {
+                let value = resolve_attribute_value(
+                    entry.value,
+                    entry.value_index,
+                    &value_table,
+                ).map_err(|e| invalid(e.to_string()))?;
+                AttributeOperation::Set(vector_id, attribute_name, value)
+            },
+            ProtosAttributeOperationType::REMOVE_ATTRIBUTE => {
+                AttributeOperation::Remove(vector_id, attribute_name)
+            },
+        });
+    }
+    for operation in operations {
+        match operation {
+            AttributeOperation::Set(vector_id, name, value) => {
+                attribute_table
+                    .entry(vector_id)
+                    .or_default()
+                    .insert(name, value);
+            },
+            AttributeOperation::Remove(vector_id, name) => {
+                if let Some(attributes) = attribute_table.get_mut(&vector_id) {
+                    attributes.remove(&name);
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+// Extracts the compression policy from a database message.
+//
+// The default policy if the database does not record it.
+pub(crate) fn deserialize_compression_policy(
+    db: &ProtosDatabase,
+) -> Result<CompressionPolicy, Error> {
+    match db.compression.as_ref() {
+        Some(compression) => compression.clone().deserialize(),
+        None => Ok(CompressionPolicy::default()),
+    }
+}
+
+// Extracts the compression of files from a database message.
+//
+// Fails if the codec of any file is unknown.
+pub(crate) fn deserialize_file_compression(
+    db: &ProtosDatabase,
+) -> Result<FileCompression, Error> {
+    let policy = deserialize_compression_policy(db)?;
+    let codecs = db.file_codecs
+        .iter()
+        .map(|file| {
+            let codec = file.codec
+                .enum_value()
+                .map_err(|n| Error::InvalidData(format!(
+                    "unknown codec of {}: {}",
+                    file.path,
+                    n,
+                )))?;
+            let codec = match codec {
+                ProtosCodec::UNCOMPRESSED => None,
+                ProtosCodec::ZLIB => Some(Codec::Zlib),
+                ProtosCodec::ZSTD => Some(Codec::Zstd),
+                ProtosCodec::LZ4 => Some(Codec::Lz4),
+            };
+            Ok((file.path.clone(), codec))
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(FileCompression::new(policy, codecs))
+}
+
+// Records the codec of every file a database message refers to.
+//
+// A file of a kind `policy` compresses has `codec`, and the others are
+// uncompressed.
+pub(crate) fn record_file_codecs(
+    db: &mut ProtosDatabase,
+    policy: CompressionPolicy,
+    codec: Codec,
+) {
+    db.file_codecs = referenced_files(db)
+        .into_iter()
+        .map(|(kind, path)| {
+            let codec = if policy.is_compressed(kind) {
+                Some(codec)
+            } else {
+                None
+            };
+            serialize_file_codec(path, codec)
+        })
+        .collect();
+}
+
+// Replaces the attributes log of a partition in a database message.
+//
+// Records `codec` for the new log, and drops the codec and Merkle tree of
+// the previous log. `codec` is `None` if the new log is uncompressed.
+pub(crate) fn replace_attributes_log(
+    db: &mut ProtosDatabase,
+    partition_index: usize,
+    id: String,
+    codec: Option<Codec>,
+) -> Result<(), Error> {
+    let log_path = |id: &str| {
+        format!("attributes/{}.{}", id, PROTOBUF_EXTENSION)
+    };
+    let old_id = db.attributes_log_ids
+        .get_mut(partition_index)
+        .ok_or(Error::InvalidData(format!(
+            "no attributes log of partition: {}",
+            partition_index,
+        )))?;
+    let old_path = log_path(old_id);
+    let new_path = log_path(&id);
+    *old_id = id;
+    db.chunk_trees.remove(&old_path);
+    db.file_codecs.retain(|file| file.path != old_path);
+    db.file_codecs.push(serialize_file_codec(new_path, codec));
+    Ok(())
+}
+
+// Converts the codec of a file at a given path into its message.
+fn serialize_file_codec(path: String, codec: Option<Codec>) -> ProtosFileCodec {
+    let codec = match codec {
+        None => ProtosCodec::UNCOMPRESSED,
+        Some(Codec::Zlib) => ProtosCodec::ZLIB,
+        Some(Codec::Zstd) => ProtosCodec::ZSTD,
+        Some(Codec::Lz4) => ProtosCodec::LZ4,
+    };
+    let mut file = ProtosFileCodec::new();
+    file.path = path;
+    file.codec = codec.into();
+    file
+}
+
+// Extracts the hash algorithm of files from a database message.
+//
+// Fails if the algorithm is unknown.
+pub(crate) fn deserialize_hash_algorithm(
+    db: &ProtosDatabase,
+) -> Result<HashAlgorithm, Error> {
+    let algorithm = db.hash_algorithm
+        .enum_value()
+        .map_err(|n| Error::InvalidData(format!(
+            "unknown hash algorithm: {}",
+            n,
+        )))?;
+    Ok(match algorithm {
+        ProtosHashAlgorithm::SHA256 => HashAlgorithm::Sha256,
+        ProtosHashAlgorithm::BLAKE3 => HashAlgorithm::Blake3,
+    })
+}
+
+// Converts a hash algorithm into its message.
+pub(crate) fn serialize_hash_algorithm(
+    algorithm: HashAlgorithm,
+) -> ProtosHashAlgorithm {
+    match algorithm {
+        HashAlgorithm::Sha256 => ProtosHashAlgorithm::SHA256,
+        HashAlgorithm::Blake3 => ProtosHashAlgorithm::BLAKE3,
+    }
+}
+
+// Extracts the quantization from a database message.
+//
+// Fails if the quantization is unknown, or the shape of the database does
+// not fit it.
+pub(crate) fn deserialize_quantization(
+    db: &ProtosDatabase,
+) -> Result<Quantization, Error> {
+    let quantization = db.quantization
+        .enum_value()
+        .map_err(|n| Error::InvalidData(format!(
+            "unknown quantization: {}",
+            n,
+        )))?;
+    match quantization {
+        ProtosQuantization::PRODUCT => Ok(Quantization::Product),
+        ProtosQuantization::SCALAR8 => {
+            if db.num_divisions != db.vector_size {
+                return Err(Error::InvalidData(format!(
+                    "num_divisions {} and vector_size {} do not match for \
+                     scalar quantization",
+                    db.num_divisions,
+                    db.vector_size,
+                )));
+            }
+            if db.num_codes > 256 {
+                return Err(Error::InvalidData(format!(
+                    "num_codes {} exceeds 256 for scalar quantization",
+                    db.num_codes,
+                )));
+            }
+            Ok(Quantization::Scalar8)
+        },
+        ProtosQuantization::FLAT => {
+            if db.num_divisions != 1 || db.num_codes != 1 {
+                return Err(Error::InvalidData(format!(
+                    "num_divisions {} and num_codes {} must be 1 without \
+                     quantization",
+                    db.num_divisions,
+                    db.num_codes,
+                )));
+            }
+            Ok(Quantization::Flat)
+        },
+    }
+}
+
+// Converts a quantization into its message.
+pub(crate) fn serialize_quantization(
+    quantization: Quantization,
+) -> ProtosQuantization {
+    match quantization {
+        Quantization::Product => ProtosQuantization::PRODUCT,
+        Quantization::Scalar8 => ProtosQuantization::SCALAR8,
+        Quantization::Flat => ProtosQuantization::FLAT,
+    }
+}
+
+// Extracts the residues from a partition message.
+//
+// `None` unless the database has no quantization. Fails if the presence of
+// the residues does not fit `quantization`, or their shape does not match
+// the partition.
+pub(crate) fn deserialize_partition_residues(
+    partition: &mut ProtosPartition,
+    quantization: Quantization,
+    vector_size: usize,
+) -> Result<Option<BlockVectorSet<f32>>, Error> {
+    let residues = partition.residues.take();
+    if quantization != Quantization::Flat {
+        if residues.is_some() {
+            return Err(Error::InvalidData(
+                "residues in a partition of a quantized database".to_string(),
+            ));
+        }
+        return Ok(None);
+    }
+    let residues: BlockVectorSet<f32> = residues
+        .ok_or(Error::InvalidData("missing residues".to_string()))?
+        .deserialize()?;
+    if !residues.is_empty() && residues.vector_size() != vector_size {
+        return Err(Error::InvalidData(format!(
+            "vector_size {} and residue size {} do not match",
+            vector_size,
+            residues.vector_size(),
+        )));
+    }
+    if residues.len() != partition.vector_ids.len() {
+        return Err(Error::InvalidData(format!(
+            "number of residues is inconsistent: expected {} but got {}",
+            partition.vector_ids.len(),
+            residues.len(),
+        )));
+    }
+    Ok(Some(residues))
+}
+
+// Extracts the squared norms of the quantization errors from a partition
+// message.
+//
+// `None` if the partition has none. Fails if their number does not match
+// that of the vectors in the partition.
+pub(crate) fn deserialize_squared_error_norms(
+    partition: &mut ProtosPartition,
+) -> Result<Option<Vec<f32>>, Error> {
+    let norms = core::mem::take(&mut partition.squared_error_norms);
+    if norms.is_empty() {
+        return Ok(None);
+    }
+    if norms.len() != partition.vector_ids.len() {
+        return Err(Error::InvalidData(format!(
+            "number of squared error norms is inconsistent: expected {} but \
+             got {}",
+            partition.vector_ids.len(),
+            norms.len(),
+        )));
+    }
+    Ok(Some(norms))
+}
+
+// Extracts the quantization error statistics from a database message.
+//
+// Fails if the statistics are neither empty nor match the number of
+// divisions.
+pub(crate) fn deserialize_quantization_errors(
+    db: &ProtosDatabase,
+) -> Result<Vec<QuantizationError<f32>>, Error> {
+    let means = &db.mean_quantization_errors;
+    let maxes = &db.max_quantization_errors;
+    if means.len() != maxes.len() {
+        return Err(Error::InvalidData(format!(
+            "mean_quantization_errors.len() {} and \
+             max_quantization_errors.len() {} do not match",
+            means.len(),
+            maxes.len(),
+        )));
+    }
+    if !means.is_empty() && means.len() != db.num_divisions as usize {
+        return Err(Error::InvalidData(format!(
+            "num_divisions {} and mean_quantization_errors.len() {} do not \
+             match",
+            db.num_divisions,
+            means.len(),
+        )));
+    }
+    Ok(means
+        .iter()
+        .zip(maxes)
+        .map(|(&mean, &max)| QuantizationError { mean, max })
+        .collect())
+}
+
+// Extracts the projection from a database message.
+//
+// `None` if the database has no projection. Fails if the output size of the
+// projection does not match the vector size.
+pub(crate) fn deserialize_projection(
+    db: &ProtosDatabase,
+) -> Result<Option<Projection<f32>>, Error> {
+    let projection: Projection<f32> = match db.projection.as_ref() {
+        Some(projection) => projection.clone().deserialize()?,
+        None => return Ok(None),
+    };
+    if projection.output_size() != db.vector_size as usize {
+        return Err(Error::InvalidData(format!(
+            "vector_size {} and projection output size {} do not match",
+            db.vector_size,
+            projection.output_size(),
+        )));
+    }
+    Ok(Some(projection))
+}
+
+// Extracts the number of vectors in each partition from a database message.
+//
+// `None` if the database was serialized without the counts.
+pub(crate) fn deserialize_partition_sizes(
+    db: &ProtosDatabase,
+) -> Result<Option<Vec<usize>>, Error> {
+    if db.partition_sizes.is_empty() {
+        return Ok(None);
+    }
+    if db.partition_sizes.len() != db.num_partitions as usize {
+        return Err(Error::InvalidData(format!(
+            "num_partitions {} and partition_sizes.len() {} do not match",
+            db.num_partitions,
+            db.partition_sizes.len(),
+        )));
+    }
+    let total: u64 = db.partition_sizes.iter().sum();
+    if total != db.num_vectors {
+        return Err(Error::InvalidData(format!(
+            "num_vectors {} and sum of partition_sizes {} do not match",
+            db.num_vectors,
+            total,
+        )));
+    }
+    Ok(Some(db.partition_sizes.iter().map(|&n| n as usize).collect()))
+}
+
+// Extracts the Merkle trees of files from a database message.
+//
+// Fails if any of the trees is invalid.
+pub(crate) fn deserialize_chunk_trees(
+    db: &ProtosDatabase,
+) -> Result<HashMap<String, MerkleTree>, Error> {
+    db.chunk_trees
+        .iter()
+        .map(|(path, tree)| Ok((path.clone(), tree.clone().deserialize()?)))
+        .collect()
+}
+
+// Returns the paths of the files a database message refers to.
+pub(crate) fn referenced_file_paths(db: &ProtosDatabase) -> Vec<String> {
+    referenced_files(db).into_iter().map(|(_, path)| path).collect()
+}
+
+// Returns the kinds and paths of the files a database message refers to.
+fn referenced_files(db: &ProtosDatabase) -> Vec<(FileKind, String)> {
+    let file = |kind: FileKind, dir: &str, id: &String| {
+        (kind, format!("{}/{}.{}", dir, id, PROTOBUF_EXTENSION))
+    };
+    let mut files: Vec<(FileKind, String)> = Vec::new();
+    files.extend(
+        db.partition_ids
+            .iter()
+            .map(|id| file(FileKind::Partition, "partitions", id)),
+    );
+    files.push(file(
+        FileKind::PartitionCentroids,
+        "partitions",
+        &db.partition_centroids_id,
+    ));
+    files.extend(
+        db.codebook_ids
+            .iter()
+            .map(|id| file(FileKind::Codebook, "codebooks", id)),
+    );
+    files.extend(
+        db.attributes_log_ids
+            .iter()
+            .map(|id| file(FileKind::AttributesLog, "attributes", id)),
+    );
+    files.extend(
+        db.residues_ids
+            .iter()
+            .map(|id| file(FileKind::Residues, "residues", id)),
+    );
+    files
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::protos::database::{
+        OperationSetAttribute as ProtosOperationSetAttribute,
+    };
+
+    #[test]
+    fn partition_sizes_should_be_consistent_with_num_vectors() {
+        let mut db = ProtosDatabase::new();
+        db.num_partitions = 2;
+        assert_eq!(deserialize_partition_sizes(&db).unwrap(), None);
+        db.num_vectors = 5;
+        db.partition_sizes = vec![2, 3];
+        assert_eq!(deserialize_partition_sizes(&db).unwrap(), Some(vec![2, 3]));
+        db.num_vectors = 4;
+        assert!(deserialize_partition_sizes(&db).is_err());
+        db.partition_sizes = vec![4];
+        assert!(deserialize_partition_sizes(&db).is_err());
+    }
+
+    #[test]
+    fn attribute_value_can_be_resolved_inline_or_from_value_table() {
+        let table = vec![
+            AttributeValue::String("shared".to_string()),
+            AttributeValue::Uint64(7),
+        ];
+        let inline = AttributeValue::Uint64(1).serialize().unwrap();
+        assert_eq!(
+            resolve_attribute_value(Some(inline).into(), 1, &table).unwrap(),
+            AttributeValue::Uint64(1),
+        );
+        assert_eq!(
+            resolve_attribute_value(MessageField::none(), 1, &table).unwrap(),
+            AttributeValue::Uint64(7),
+        );
+        assert!(
+            resolve_attribute_value(MessageField::none(), 2, &table).is_err(),
+        );
+    }
+
+    // Creates an attributes log entry on the attribute at `name_index`.
+    fn log_entry(
+        vector_id: Uuid,
+        name_index: u32,
+        value: Option<AttributeValue>,
+        timestamp: u64,
+        batch: u64,
+    ) -> ProtosOperationSetAttribute {
+        let mut entry = ProtosOperationSetAttribute::new();
+        entry.vector_id = Some(vector_id.serialize().unwrap()).into();
+        entry.name_index = name_index;
+        match value {
+            Some(value) => {
+                entry.value = Some(value.serialize().unwrap()).into();
+            },
+            None => entry.operation =
+                ProtosAttributeOperationType::REMOVE_ATTRIBUTE.into(),
+        };
+        entry.timestamp = timestamp;
+        entry.batch = batch;
+        entry
+    }
+
+    #[test]
+    fn attributes_log_should_be_replayed_in_order() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let id = Uuid::from_u128(1);
+        let mut log = ProtosAttributesLog::new();
+        log.entries = vec![
+            log_entry(id, 0, Some(AttributeValue::Uint64(1)), 0, 0),
+            log_entry(id, 1, Some(AttributeValue::Uint64(2)), 10, 1),
+            log_entry(id, 0, None, 10, 1),
+            log_entry(id, 1, Some(AttributeValue::Uint64(3)), 0, 0),
+            log_entry(id, 0, None, 20, 2),
+        ];
+        let mut table = AttributeTable::new();
+        replay_attributes_log(log, 0, &names, &mut table).unwrap();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[&id].len(), 1);
+        assert_eq!(table[&id]["b"], AttributeValue::Uint64(3));
+    }
+
+    #[test]
+    fn attributes_log_with_out_of_order_entries_should_not_be_replayed() {
+        let names = vec!["a".to_string()];
+        let id = Uuid::from_u128(1);
+        let value = || Some(AttributeValue::Uint64(1));
+        let mut log = ProtosAttributesLog::new();
+        log.entries = vec![
+            log_entry(id, 0, value(), 20, 0),
+            log_entry(id, 0, value(), 10, 0),
+        ];
+        let mut table = AttributeTable::new();
+        assert!(replay_attributes_log(log, 0, &names, &mut table).is_err());
+        assert!(table.is_empty());
+        let mut log = ProtosAttributesLog::new();
+        log.entries = vec![
+            log_entry(id, 0, value(), 0, 1),
+            log_entry(id, 0, value(), 0, 0),
+            log_entry(id, 0, value(), 0, 1),
+        ];
+        assert!(replay_attributes_log(log, 0, &names, &mut table).is_err());
+        assert!(table.is_empty());
+        let mut log = ProtosAttributesLog::new();
+        log.entries = vec![
+            log_entry(id, 0, value(), 0, 2),
+            log_entry(id, 0, value(), 0, 1),
+        ];
+        assert!(replay_attributes_log(log, 0, &names, &mut table).is_err());
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn compression_policy_can_be_serialized_and_deserialized() {
+        let input = CompressionPolicy::none()
+            .with_compressed(FileKind::Codebook, true)
+            .with_compressed(FileKind::Residues, true);
+        let output = input.serialize().unwrap();
+        assert!(!output.partitions);
+        assert!(output.codebooks);
+        assert!(output.residues);
+        assert_eq!(output.deserialize().unwrap(), input);
+    }
+
+    #[test]
+    fn compression_policy_should_default_if_database_does_not_record_it() {
+        let db = ProtosDatabase::new();
+        assert_eq!(
+            deserialize_compression_policy(&db).unwrap(),
+            CompressionPolicy::default(),
+        );
+    }
+
+    #[test]
+    fn file_codecs_should_be_recorded_for_every_referenced_file() {
+        let mut db = ProtosDatabase::new();
+        db.partition_ids = vec!["p0".to_string(), "p1".to_string()];
+        db.partition_centroids_id = "c".to_string();
+        db.codebook_ids = vec!["b0".to_string()];
+        db.attributes_log_ids = vec!["a0".to_string(), "a1".to_string()];
+        let policy = CompressionPolicy::none()
+            .with_compressed(FileKind::Codebook, true);
+        record_file_codecs(&mut db, policy, Codec::Zlib);
+        let codecs: Vec<(&str, ProtosCodec)> = db.file_codecs
+            .iter()
+            .map(|file| (file.path.as_str(), file.codec.enum_value().unwrap()))
+            .collect();
+        assert_eq!(codecs, vec![
+            ("partitions/p0.binpb", ProtosCodec::UNCOMPRESSED),
+            ("partitions/p1.binpb", ProtosCodec::UNCOMPRESSED),
+            ("partitions/c.binpb", ProtosCodec::UNCOMPRESSED),
+            ("codebooks/b0.binpb", ProtosCodec::ZLIB),
+            ("attributes/a0.binpb", ProtosCodec::UNCOMPRESSED),
+            ("attributes/a1.binpb", ProtosCodec::UNCOMPRESSED),
+        ]);
+        db.compression = Some(policy.serialize().unwrap()).into();
+        let compression = deserialize_file_compression(&db).unwrap();
+        assert_eq!(compression.policy(), policy);
+        assert!(compression
+            .is_compressed(FileKind::Codebook, "codebooks/b0.binpb"));
+        assert!(!compression
+            .is_compressed(FileKind::Partition, "partitions/c.binpb"));
+    }
+
+    #[test]
+    fn file_codecs_should_override_compression_policy() {
+        let file_codec = |path: &str, codec| {
+            let mut file = ProtosFileCodec::new();
+            file.path = path.to_string();
+            file.codec = codec;
+            file
+        };
+        let mut db = ProtosDatabase::new();
+        db.compression =
+            Some(CompressionPolicy::all().serialize().unwrap()).into();
+        db.file_codecs = vec![
+            file_codec(
+                "partitions/p0.binpb",
+                ProtosCodec::UNCOMPRESSED.into(),
+            ),
+            file_codec("partitions/p1.binpb", ProtosCodec::ZSTD.into()),
+        ];
+        let compression = deserialize_file_compression(&db).unwrap();
+        assert!(!compression
+            .is_compressed(FileKind::Partition, "partitions/p0.binpb"));
+        assert!(compression
+            .is_compressed(FileKind::Partition, "partitions/p1.binpb"));
+        // a file without a codec follows the policy
+        assert!(compression
+            .is_compressed(FileKind::Partition, "partitions/p2.binpb"));
+
+        db.file_codecs.push(file_codec(
+            "partitions/p3.binpb",
+            protobuf::EnumOrUnknown::from_i32(9),
+        ));
+        assert!(matches!(
+            deserialize_file_compression(&db),
+            Err(Error::InvalidData(_)),
+        ));
+    }
+
     #[test]
     fn attribute_value_string_can_be_serialized_as_attribute_value_message() {
         let input = AttributeValue::String("string".to_string());
@@ -4,25 +4,94 @@ use core::borrow::Borrow;
 use core::cell::{OnceCell, Ref, RefCell, RefMut};
 use core::hash::Hash;
 use core::num::NonZeroUsize;
-use std::collections::hash_map::{Entry as HashMapEntry};
+use protobuf::Message;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 use crate::error::Error;
-use crate::io::{FileSystem, HashedFileIn};
+use crate::io::{
+    CompressedHashedFileIn,
+    FileSystem,
+    HashedFileIn,
+    IoProgress,
+    ProgressHashedFileIn,
+    ReadFileSystem,
+};
+use crate::io::codec::Codec;
+use crate::io::hash::HashAlgorithm;
+use crate::io::merkle::{ChunkVerifiedFileIn, MerkleTree};
 use crate::kmeans::Scalar;
-use crate::linalg::{dot, subtract};
-use crate::nbest::{NBestByKey, TakeNBestByKey};
+use crate::linalg::simd::Kernels;
+use crate::nbest::{NBestByKey, merge_sorted_by_key};
+use crate::projection::Projection;
 use crate::protos::database::{
     AttributesLog as ProtosAttributesLog,
     Database as ProtosDatabase,
+    OperationSetAttribute as ProtosOperationSetAttribute,
     Partition as ProtosPartition,
     VectorSet as ProtosVectorSet,
 };
-use crate::protos::{Deserialize, read_message};
+use crate::protos::{
+    Deserialize,
+    Serialize,
+    check_message_size,
+    read_message_up_to,
+};
 use crate::slice::AsSlice;
-use crate::vector::BlockVectorSet;
+use crate::vector::{BlockVectorSet, EncodedVectorSet, division_range};
+use crate::vector::proto::{LazyEncodedVectorSet, deserialize_lazy};
+
+use super::{
+    AttributeTable,
+    AttributeValue,
+    Attributes,
+    CompressionPolicy,
+    FileCompression,
+    FileKind,
+    Generation,
+    OpenOptions,
+    PartitionAssignment,
+    PartitionStats,
+    assign_partition,
+    attribute_table_bytes,
+    Quantization,
+    QuantizationError,
+    ValidationMode,
+    check_finite,
+    exact_squared_distance,
+    max_error2,
+    num_rerank_candidates,
+    project_query,
+    strings_bytes,
+};
+use super::build::proto::{
+    SerializeOptions,
+    encode_attribute_name,
+    encode_attributes_log,
+    write_message_file,
+};
+use super::encoder::PqEncoder;
+use super::manifest::{CURRENT, current_database, publish_database};
+use super::metric::{QueryMetric, QueryScratch};
+use super::proto::{
+    deserialize_chunk_trees,
+    deserialize_file_compression,
+    deserialize_hash_algorithm,
+    deserialize_partition_residues,
+    deserialize_partition_sizes,
+    deserialize_projection,
+    deserialize_quantization,
+    deserialize_quantization_errors,
+    deserialize_squared_error_norms,
+    replace_attributes_log,
+    replay_attributes_log,
+};
 
-use super::{AttributeTable, AttributeValue, Attributes};
+pub mod verify;
+pub use verify::{Problem, VerificationReport, verify_database};
 
 /// Extension of a Protocol Buffers file.
 pub const PROTOBUF_EXTENSION: &str = "binpb";
@@ -31,36 +100,223 @@ pub const PROTOBUF_EXTENSION: &str = "binpb";
 ///
 /// Supposed to be specifalized for a specific [`Database`].
 pub trait LoadDatabase<T, FS> {
-    /// Loads a database.
+    /// Loads a database with the default [`OpenOptions`].
     fn load_database<P>(fs: FS, path: P) -> Result<Database<T, FS>, Error>
+    where
+        P: AsRef<str>,
+    {
+        Self::load_database_with_options(fs, path, OpenOptions::default())
+    }
+
+    /// Loads a database with given options.
+    fn load_database_with_options<P>(
+        fs: FS,
+        path: P,
+        options: OpenOptions,
+    ) -> Result<Database<T, FS>, Error>
     where
         P: AsRef<str>;
+
+    /// Loads the current version of a database with the default
+    /// [`OpenOptions`].
+    ///
+    /// See [`manifest`](crate::db::manifest).
+    fn load_current_database(fs: FS) -> Result<Database<T, FS>, Error>
+    where
+        FS: ReadFileSystem,
+    {
+        Self::load_current_database_with_options(fs, OpenOptions::default())
+    }
+
+    /// Loads the current version of a database with given options.
+    ///
+    /// Loads the database header [`CURRENT`](crate::db::manifest::CURRENT)
+    /// names. Fails with `Error::InvalidContext` if no version has been
+    /// published.
+    fn load_current_database_with_options(
+        fs: FS,
+        options: OpenOptions,
+    ) -> Result<Database<T, FS>, Error>
+    where
+        FS: ReadFileSystem,
+    {
+        let path = current_database(&fs)?;
+        Self::load_database_with_options(fs, path, options)
+    }
 }
 
 /// Stored database.
 pub struct Database<T, FS> {
     fs: FS,
+    // Path to the database header. Updated when an attribute is set.
+    header_path: RefCell<String>,
     vector_size: usize,
     num_partitions: usize,
     num_divisions: usize,
     num_codes: usize,
     partition_ids: Vec<String>,
-    partitions: RefCell<Vec<Option<Partition<T>>>>,
+    partitions: RefCell<Vec<Option<Arc<Partition<T>>>>>,
     partition_centroids_id: String,
     partition_centroids: OnceCell<BlockVectorSet<T>>,
     codebook_ids: Vec<String>,
     codebooks: RefCell<Option<Vec<BlockVectorSet<T>>>>,
-    attributes_log_ids: Vec<String>,
+    // Updated when an attribute is set.
+    attributes_log_ids: RefCell<Vec<String>>,
     attributes_log_load_flags: RefCell<Vec<bool>>,
     attribute_names: Vec<String>,
     attribute_table: RefCell<Option<AttributeTable>>,
+    residues_ids: Vec<String>,
+    // Whether `residues_ids` refer to original vectors.
+    original_vectors: bool,
+    quantization_errors: Vec<QuantizationError<T>>,
+    // Projection applied to query vectors.
+    projection: Option<Projection<T>>,
+    quantization: Quantization,
+    compression: FileCompression,
+    hash_algorithm: HashAlgorithm,
+    // Merkle trees of files keyed by their paths.
+    chunk_trees: HashMap<String, MerkleTree>,
+    // Number of vectors in each partition. `None` if unknown.
+    partition_sizes: Option<Vec<usize>>,
+    verification: bool,
+    validation_mode: ValidationMode,
+    // Maximum number of bytes of a message. `None` if unlimited.
+    max_message_size: Option<usize>,
+    fast_scan: bool,
+    // Number of threads reductions over long vectors are split among.
+    // `None` if disabled.
+    parallel_reduction: Option<NonZeroUsize>,
+    // Maximum number of bytes of loaded partitions. `None` if unlimited.
+    cache_budget: Option<usize>,
+    // Indices of loaded partitions in the order they were loaded.
+    partition_load_order: RefCell<VecDeque<usize>>,
+    load_event_handler: RefCell<Option<Box<LoadEventHandler>>>,
+}
+
+/// Event notified while loading files of a [`Database`].
+#[derive(Debug)]
+pub enum LoadEvent {
+    /// Starting to read a file of a given kind.
+    StartingFile(FileKind),
+    /// Read bytes of a file of a given kind.
+    ///
+    /// Bytes are counted as stored; i.e., before decompression, and the
+    /// total is the size of the file if the file system knows it.
+    ReadingFile(FileKind, IoProgress),
+    /// Finished reading a file of a given kind.
+    FinishedFile(FileKind),
+    /// Skipped a damaged file of a given kind because of a given error.
+    ///
+    /// Notified only under [`ValidationMode::Lenient`].
+    SkippedFile(FileKind, Error),
 }
 
+// Function notified of load events.
+type LoadEventHandler = dyn FnMut(LoadEvent) + Send;
+
 impl<T, FS> Database<T, FS>
 where
-    FS: FileSystem,
+    FS: ReadFileSystem,
 {
+    /// Sets a function notified of the progress of loading files.
+    ///
+    /// Files are loaded lazily; e.g., a partition is loaded when it is
+    /// queried for the first time. The function must not call the database.
+    pub fn with_load_event_handler<F>(self, handler: F) -> Self
+    where
+        F: FnMut(LoadEvent) + Send + 'static,
+    {
+        self.load_event_handler.replace(Some(Box::new(handler)));
+        self
+    }
+
+    // Notifies the load event handler of an event.
+    fn notify_load_event(&self, event: LoadEvent) {
+        if let Some(handler) = self.load_event_handler.borrow_mut().as_mut() {
+            handler(event);
+        }
+    }
+
+    // Deals with an error on loading a file of a given kind according to the
+    // validation mode.
+    //
+    // Returns the error in strict mode. Otherwise, notifies the load event
+    // handler of the skipped file and succeeds.
+    fn skip_damaged_file(
+        &self,
+        kind: FileKind,
+        err: Error,
+    ) -> Result<(), Error> {
+        match self.validation_mode {
+            ValidationMode::Strict => Err(err),
+            ValidationMode::Lenient => {
+                self.notify_load_event(LoadEvent::SkippedFile(kind, err));
+                Ok(())
+            },
+        }
+    }
+
+    // Reads a message in a file of a given kind verifying it unless disabled.
+    //
+    // Verifies the file chunk by chunk if the database records the Merkle
+    // tree of the file.
+    fn read_file<M>(&self, kind: FileKind, path: String) -> Result<M, Error>
+    where
+        M: Message,
+    {
+        let compressed = self.compression.is_compressed(kind, &path);
+        if !self.verification {
+            let f = self.fs.open_hashed_file_unverified(path)?;
+            return self.read_opened_file(kind, compressed, f);
+        }
+        match self.chunk_trees.get(&path) {
+            Some(tree) => {
+                let f = self.fs.open_hashed_file_unverified(path)?;
+                let f = ChunkVerifiedFileIn::new(f, tree.clone());
+                self.read_opened_file(kind, compressed, f)
+            },
+            None => {
+                let f = self.fs
+                    .open_hashed_file_with(path, self.hash_algorithm)?;
+                self.read_opened_file(kind, compressed, f)
+            },
+        }
+    }
+
+    // Reads a message in an opened file of a given kind.
+    //
+    // Decompresses the file if `compressed`, and notifies the load event
+    // handler of the progress.
+    fn read_opened_file<M, F>(
+        &self,
+        kind: FileKind,
+        compressed: bool,
+        f: F,
+    ) -> Result<M, Error>
+    where
+        M: Message,
+        F: HashedFileIn,
+    {
+        self.notify_load_event(LoadEvent::StartingFile(kind));
+        let f = ProgressHashedFileIn::new(f, |progress| {
+            self.notify_load_event(LoadEvent::ReadingFile(kind, progress));
+        });
+        let message = if compressed {
+            read_file_message(
+                CompressedHashedFileIn::new(f),
+                self.verification,
+                self.max_message_size,
+            )?
+        } else {
+            read_file_message(f, self.verification, self.max_message_size)?
+        };
+        self.notify_load_event(LoadEvent::FinishedFile(kind));
+        Ok(message)
+    }
+
     /// Returns the vector size.
+    ///
+    /// The output size of the projection if the database has one.
     pub fn vector_size(&self) -> usize {
         self.vector_size
     }
@@ -80,9 +336,12 @@ where
         self.num_codes
     }
 
-    /// Returns the subvector size.
+    /// Returns the size of the longest subvector; i.e., the first division.
+    ///
+    /// Trailing divisions are one element shorter if `vector_size` is not a
+    /// multiple of `num_divisions`. See [`division_range`].
     pub fn subvector_size(&self) -> usize {
-        self.vector_size / self.num_divisions
+        self.vector_size.div_ceil(self.num_divisions)
     }
 
     /// Returns the ID of a partition.
@@ -98,11 +357,137 @@ where
     pub fn get_codebook_id(&self, index: usize) -> Option<&String> {
         self.codebook_ids.get(index)
     }
+
+    /// Returns the generation of the attributes log of a partition.
+    ///
+    /// `None` if `index` ≥ `num_partitions`.
+    pub fn attributes_generation(&self, index: usize) -> Option<Generation> {
+        self.attributes_log_ids
+            .borrow()
+            .get(index)
+            .map(|id| Generation::of_attributes_log(id))
+    }
+
+    /// Returns the quantization errors of the codebooks.
+    ///
+    /// Empty if the database was serialized without them.
+    pub fn quantization_errors(&self) -> &[QuantizationError<T>] {
+        &self.quantization_errors
+    }
+
+    /// Returns the quantization of residues.
+    pub fn quantization(&self) -> Quantization {
+        self.quantization
+    }
+
+    /// Returns the projection applied to query vectors.
+    ///
+    /// `None` if the database was built without PCA or a random rotation.
+    pub fn projection(&self) -> Option<&Projection<T>> {
+        self.projection.as_ref()
+    }
+
+    /// Returns the total number of vectors in the database.
+    ///
+    /// Answers without loading any partition. `None` if the database was
+    /// serialized without vector counts.
+    pub fn num_vectors(&self) -> Option<usize> {
+        self.partition_sizes.as_ref().map(|sizes| sizes.iter().sum())
+    }
+
+    /// Returns the number of vectors in each partition.
+    ///
+    /// `None` if the database was serialized without vector counts.
+    pub fn partition_sizes(&self) -> Option<&[usize]> {
+        self.partition_sizes.as_deref()
+    }
+
+    /// Returns the statistics of the numbers of vectors in partitions.
+    ///
+    /// Tells how imbalanced the partitions are without loading any of them.
+    /// `None` if the database was serialized without vector counts.
+    pub fn partition_stats(&self) -> Option<PartitionStats> {
+        self.partition_sizes
+            .as_deref()
+            .and_then(PartitionStats::from_sizes)
+    }
+
+    /// Returns which kinds of files of the database are compressed.
+    ///
+    /// Recorded in the database header, so loading a file never has to
+    /// guess whether it is compressed.
+    pub fn compression(&self) -> CompressionPolicy {
+        self.compression.policy()
+    }
+
+    /// Returns the algorithm of the content hashes of the files of the
+    /// database.
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    /// Returns if the database has residue vectors persisted.
+    ///
+    /// Also `true` if original vectors are persisted instead of residues.
+    pub fn has_residues(&self) -> bool {
+        !self.residues_ids.is_empty()
+    }
+
+    /// Returns if original vectors are persisted instead of residues.
+    pub fn has_original_vectors(&self) -> bool {
+        self.has_residues() && self.original_vectors
+    }
+
+    /// Returns the ID of the residue vector set of a partition.
+    ///
+    /// `None` if `index` ≥ `num_partitions`, or the database has no
+    /// residues.
+    pub fn get_residues_id(&self, index: usize) -> Option<&String> {
+        self.residues_ids.get(index)
+    }
+
+    /// Estimates the number of bytes of memory the database occupies.
+    ///
+    /// Counts only what has been loaded so far; i.e., partition centroids,
+    /// codebooks, partitions, and attributes, in addition to file IDs.
+    /// Overheads of allocators are not counted.
+    pub fn estimated_memory_bytes(&self) -> usize {
+        let partitions = self.partitions.borrow();
+        let partitions = core::mem::size_of_val(&partitions[..])
+            + partitions
+                .iter()
+                .flatten()
+                .map(|p| p.memory_bytes())
+                .sum::<usize>();
+        let partition_centroids = self.partition_centroids
+            .get()
+            .map_or(0, |c| c.memory_bytes());
+        let codebooks = self.codebooks
+            .borrow()
+            .as_ref()
+            .map_or(0, |cbs| cbs.iter().map(|c| c.memory_bytes()).sum());
+        let attribute_table = self.attribute_table
+            .borrow()
+            .as_ref()
+            .map_or(0, attribute_table_bytes);
+        let ids = self.partition_centroids_id.capacity()
+            + strings_bytes(&self.partition_ids)
+            + strings_bytes(&self.codebook_ids)
+            + strings_bytes(&self.attributes_log_ids.borrow())
+            + strings_bytes(&self.attribute_names)
+            + strings_bytes(&self.residues_ids);
+        core::mem::size_of_val(self)
+            + partitions
+            + partition_centroids
+            + codebooks
+            + attribute_table
+            + ids
+    }
 }
 
 impl<T, FS> Database<T, FS>
 where
-    FS: FileSystem,
+    FS: ReadFileSystem,
     Self: LoadPartition<T>,
 {
     /// Returns an attribute value of a given vector.
@@ -119,13 +504,13 @@ where
         &self,
         vector_id: &Uuid,
         key: &K,
-    ) -> Result<Option<AttributeValueRef>, Error>
+    ) -> Result<Option<AttributeValueRef<'_>>, Error>
     where
         String: Borrow<K>,
         K: Hash + Eq + ?Sized,
     {
         if self.attribute_table.borrow().is_none() {
-            self.load_attribute_table()?;
+            self.load_attribute_table_or_skip()?;
         }
         self.get_attribute_internal(vector_id, key)
     }
@@ -136,12 +521,12 @@ where
         partition_index: usize,
         vector_id: &Uuid,
         key: &K,
-    ) -> Result<Option<AttributeValueRef>, Error>
+    ) -> Result<Option<AttributeValueRef<'_>>, Error>
     where
         String: Borrow<K>,
         K: Hash + Eq + ?Sized,
     {
-        self.load_attributes_log(partition_index)?;
+        self.load_attributes_log_or_skip(partition_index)?;
         self.get_attribute_internal(vector_id, key)
     }
 
@@ -149,7 +534,7 @@ where
         &self,
         vector_id: &Uuid,
         key: &K,
-    ) -> Result<Option<AttributeValueRef>, Error>
+    ) -> Result<Option<AttributeValueRef<'_>>, Error>
     where
         String: Borrow<K>,
         K: Hash + Eq + ?Sized,
@@ -177,6 +562,54 @@ where
         Ok(())
     }
 
+    // Loads the attributes logs of all the partitions skipping damaged
+    // partitions and attributes logs if the validation mode is lenient.
+    fn load_attribute_table_or_skip(&self) -> Result<(), Error> {
+        for pi in 0..self.num_partitions() {
+            if let Err(err) = self.get_partition(pi) {
+                self.skip_damaged_file(FileKind::Partition, err)?;
+                continue;
+            }
+            self.load_attributes_log_or_skip(pi)?;
+        }
+        // the table must exist even if all the partitions are skipped
+        self.attribute_table_mut();
+        Ok(())
+    }
+
+    // Loads the attributes log of a specified partition skipping it if it
+    // is damaged and the validation mode is lenient.
+    //
+    // Vectors in the partition have no attributes if the attributes log is
+    // skipped. A skipped attributes log is not marked as loaded, so it may
+    // be read again later.
+    fn load_attributes_log_or_skip(
+        &self,
+        partition_index: usize,
+    ) -> Result<(), Error> {
+        self.get_partition(partition_index)?;
+        if let Err(err) = self.load_attributes_log(partition_index) {
+            self.skip_damaged_file(FileKind::AttributesLog, err)?;
+            let partition = self.get_partition(partition_index)?;
+            let mut attribute_table = self.attribute_table_mut();
+            for vector_id in partition.vector_ids.iter() {
+                attribute_table
+                    .entry(*vector_id)
+                    .or_default();
+            }
+        }
+        Ok(())
+    }
+
+    // Borrows the attribute table creating an empty one if it does not
+    // exist.
+    fn attribute_table_mut(&self) -> RefMut<'_, AttributeTable> {
+        RefMut::map(
+            self.attribute_table.borrow_mut(),
+            |tbl| tbl.get_or_insert_with(AttributeTable::new),
+        )
+    }
+
     // Loads the attributes log of a specified partition if it is not loaded
     // yet.
     //
@@ -187,12 +620,14 @@ where
             return Ok(());
         }
         let partition = self.get_partition(partition_index)?;
-        let mut f = self.fs.open_compressed_hashed_file(format!(
-            "attributes/{}.{}",
-            self.attributes_log_ids[partition_index],
-            PROTOBUF_EXTENSION,
-        ))?;
-        let attributes_log: ProtosAttributesLog = read_message(&mut f)?;
+        let attributes_log: ProtosAttributesLog = self.read_file(
+            FileKind::AttributesLog,
+            format!(
+                "attributes/{}.{}",
+                self.attributes_log_ids.borrow()[partition_index],
+                PROTOBUF_EXTENSION,
+            ),
+        )?;
         if attributes_log.partition_id != self.partition_ids[partition_index] {
             return Err(Error::InvalidData(format!(
                 "inconsistent partition IDs: {} vs {}",
@@ -200,101 +635,241 @@ where
                 self.partition_ids[partition_index],
             )));
         }
-        if self.attribute_table.borrow().is_none() {
-            self.attribute_table.replace(Some(AttributeTable::new()));
-        }
-        let mut attribute_table = RefMut::filter_map(
-            self.attribute_table.borrow_mut(),
-            |tbl| tbl.as_mut(),
-        ).expect("attribute table must exist");
-        for (i, entry) in attributes_log.entries.into_iter().enumerate() {
-            let attribute_name = self.attribute_names
-                .get(entry.name_index as usize)
-                .ok_or(Error::InvalidData(format!(
-                    "attribute name index out of bounds: {}",
-                    entry.name_index,
-                )))?;
-            let vector_id = entry.vector_id
-                .into_option()
-                .ok_or(Error::InvalidData(format!(
-                    "attributes log[{}, {}]: missing vector ID",
-                    partition_index,
-                    i,
-                )))?
-                .deserialize()?;
-            let value = entry.value
-                .into_option()
-                .ok_or(Error::InvalidData(format!(
-                    "attributes log[{}, {}]: missing value",
-                    partition_index,
-                    i,
-                )))?
-                .deserialize()?;
-            match attribute_table.entry(vector_id) {
-                HashMapEntry::Occupied(slot) => {
-                    match slot.into_mut().entry(attribute_name.clone()) {
-                        HashMapEntry::Occupied(slot) => {
-                            *slot.into_mut() = value;
-                        },
-                        HashMapEntry::Vacant(slot) => {
-                            slot.insert(value);
-                        },
-                    };
-                },
-                HashMapEntry::Vacant(slot) => {
-                    slot.insert(Attributes::from([
-                        (attribute_name.clone(), value),
-                    ]));
-                },
-            };
-        }
+        let mut attribute_table = self.attribute_table_mut();
+        replay_attributes_log(
+            attributes_log,
+            partition_index,
+            &self.attribute_names,
+            &mut attribute_table,
+        )?;
         // defaults to empty attributes so that
         // get_attribute won't fail for an existing vector without attributes.
         for vector_id in partition.vector_ids.iter() {
             attribute_table
-                .entry(vector_id.clone())
-                .or_insert_with(Attributes::new);
+                .entry(*vector_id)
+                .or_default();
         }
         self.attributes_log_load_flags.borrow_mut()[partition_index] = true;
         Ok(())
     }
 
-    // Obtains a specified partition.
-    //
-    // Lazily loads the partition if it is not loaded yet.
-    //
-    // Fails if:
-    // - `index` exceeds the number of partitions
-    // - there is any problem on the partition data
-    fn get_partition(
+    /// Obtains a specified partition.
+    ///
+    /// Lazily loads the partition if it is not loaded yet. Returns a shared
+    /// snapshot of the partition, so any number of partitions may be held at
+    /// the same time. A held partition stays in memory even after it is
+    /// evicted from the cache.
+    ///
+    /// Fails if:
+    /// - `index` exceeds the number of partitions
+    /// - there is any problem on the partition data
+    pub fn get_partition(
         &self,
         index: usize,
-    ) -> Result<PartitionRef<'_, T>, Error> {
+    ) -> Result<PartitionRef<T>, Error> {
         if index >= self.num_partitions() {
             return Err(Error::InvalidArgs(format!(
                 "partition index out of bounds: {}",
                 index,
             )));
         }
-        if self.partitions.borrow()[index].is_none() {
-            self.partitions.borrow_mut()[index] =
-                Some(self.load_partition(index)?);
+        if let Some(partition) = &self.partitions.borrow()[index] {
+            return Ok(partition.clone());
         }
-        let partition =
-            Ref:: filter_map(
-                self.partitions.borrow(),
-                |partitions| partitions[index].as_ref(),
-            )
-            .or(Err(Error::InvalidData(
-                "partition must be loaded".to_string(),
-            )))
-            .unwrap();
+        let partition = Arc::new(self.load_partition(index)?);
+        self.evict_partitions(partition.memory_bytes());
+        self.partitions.borrow_mut()[index] = Some(partition.clone());
+        self.partition_load_order.borrow_mut().push_back(index);
         Ok(partition)
     }
 }
 
-// Reference type of a partition.
-type PartitionRef<'a, T> = Ref<'a, Partition<T>>;
+impl<T, FS> Database<T, FS>
+where
+    FS: FileSystem,
+    Self: LoadPartition<T>,
+{
+    /// Sets an attribute value of a given vector if the attributes log of
+    /// the partition containing the vector is still at a given generation.
+    ///
+    /// `expected` is usually the generation obtained together with the
+    /// current value; e.g., from
+    /// [`QueryResult::get_attribute_with_generation`]. An update made in
+    /// the meantime changes the generation, so this function never
+    /// overwrites a value it has not seen.
+    ///
+    /// The update is based on the current version of the database; i.e.,
+    /// the database header [`CURRENT`] names, or the header this database
+    /// was loaded from if no version has been published. On success, writes
+    /// a new attributes log of the partition, which records the update after
+    /// the previous attributes, and a new database header that refers to
+    /// the log. Then publishes the header with [`publish_database`], and
+    /// returns the new generation.
+    ///
+    /// `None` if the generation, either of this database or of the current
+    /// version, differs from `expected`. Nothing is written in that case.
+    /// The check and the publication are not atomic, so concurrent writers
+    /// of the same database have to be serialized by the caller.
+    ///
+    /// The file system has to support pointers; see
+    /// [`WriteFileSystem::write_pointer`](crate::io::WriteFileSystem::write_pointer).
+    ///
+    /// Fails if no vector is associated with `vector_id`, or `key` is not
+    /// an attribute name in the database. Also fails with
+    /// `Error::InvalidContext` if the current version has partitions other
+    /// than this database.
+    pub fn set_attribute_if(
+        &self,
+        vector_id: &Uuid,
+        key: &str,
+        expected: Generation,
+        new: AttributeValue,
+    ) -> Result<Option<Generation>, Error> {
+        let name_index = encode_attribute_name(&self.attribute_names, key)
+            .or(Err(Error::InvalidArgs(format!(
+                "unknown attribute name: {}",
+                key,
+            ))))?;
+        self.load_attribute_table()?;
+        let mut partition_index = None;
+        for pi in 0..self.num_partitions() {
+            if self.get_partition(pi)?.vector_ids.contains(vector_id) {
+                partition_index = Some(pi);
+                break;
+            }
+        }
+        let partition_index = partition_index.ok_or(Error::InvalidArgs(
+            format!("no such vector ID: {}", vector_id),
+        ))?;
+        if self.attributes_generation(partition_index) != Some(expected) {
+            return Ok(None);
+        }
+        let mut header = self.read_current_header()?;
+        if header.partition_ids != self.partition_ids {
+            return Err(Error::InvalidContext(
+                "current version has different partitions".to_string(),
+            ));
+        }
+        let current_generation = header.attributes_log_ids
+            .get(partition_index)
+            .map(|id| Generation::of_attributes_log(id));
+        if current_generation != Some(expected) {
+            return Ok(None);
+        }
+        let mut attributes_log = {
+            let partition = self.get_partition(partition_index)?;
+            let attribute_table = Ref::filter_map(
+                self.attribute_table.borrow(),
+                |tbl| tbl.as_ref(),
+            ).expect("attribute table must be loaded");
+            let attributes: Vec<_> = partition.vector_ids
+                .iter()
+                .filter_map(|id| {
+                    attribute_table.get(id).map(|attributes| (id, attributes))
+                })
+                .collect();
+            encode_attributes_log(
+                &self.partition_ids[partition_index],
+                &attributes,
+                &self.attribute_names,
+            )?
+        };
+        let mut entry = ProtosOperationSetAttribute::new();
+        entry.vector_id = Some(vector_id.serialize()?).into();
+        entry.name_index = name_index;
+        entry.value = Some(new.serialize()?).into();
+        entry.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_micros() as u64);
+        attributes_log.entries.push(entry);
+        let codec = Codec::default();
+        let options = SerializeOptions::new()
+            .with_compression(self.compression.policy())
+            .with_codec(codec)
+            .with_hash_algorithm(self.hash_algorithm);
+        let id = write_message_file(
+            &attributes_log,
+            self.fs.create_hashed_file_in_with(
+                "attributes",
+                self.hash_algorithm,
+            )?,
+            FileKind::AttributesLog,
+            &options,
+            &mut |_| {},
+        )?;
+        let codec = self.compression
+            .policy()
+            .is_compressed(FileKind::AttributesLog)
+            .then_some(codec);
+        replace_attributes_log(
+            &mut header,
+            partition_index,
+            id.clone(),
+            codec,
+        )?;
+        let header_id = write_message_file(
+            &header,
+            self.fs.create_hashed_file()?,
+            FileKind::Database,
+            &options,
+            &mut |_| {},
+        )?;
+        let header_path = format!("{}.{}", header_id, PROTOBUF_EXTENSION);
+        publish_database(&self.fs, &header_path)?;
+        self.header_path.replace(header_path);
+        self.attributes_log_ids.borrow_mut()[partition_index] = id;
+        self.attribute_table
+            .borrow_mut()
+            .as_mut()
+            .expect("attribute table must be loaded")
+            .entry(*vector_id)
+            .or_default()
+            .insert(key.to_string(), new);
+        Ok(self.attributes_generation(partition_index))
+    }
+
+    // Reads the database header of the current version.
+    //
+    // Falls back to the header this database was loaded from if no version
+    // has been published.
+    fn read_current_header(&self) -> Result<ProtosDatabase, Error> {
+        let path = self.fs
+            .read_pointer(CURRENT)?
+            .unwrap_or_else(|| self.header_path.borrow().clone());
+        let mut f = self.fs.open_compressed_hashed_file(path)?;
+        let header = read_message_up_to(&mut f, self.max_message_size)?;
+        f.verify()?;
+        Ok(header)
+    }
+}
+
+impl<T, FS> Database<T, FS> {
+    // Evicts the partitions loaded earliest until a partition of a given
+    // size fits in the cache budget.
+    fn evict_partitions(&self, incoming_bytes: usize) {
+        let Some(budget) = self.cache_budget else {
+            return;
+        };
+        let mut partitions = self.partitions.borrow_mut();
+        let mut load_order = self.partition_load_order.borrow_mut();
+        let mut loaded_bytes: usize = partitions
+            .iter()
+            .flatten()
+            .map(|p| p.memory_bytes())
+            .sum();
+        while loaded_bytes + incoming_bytes > budget {
+            let Some(index) = load_order.pop_front() else {
+                break;
+            };
+            if let Some(partition) = partitions[index].take() {
+                loaded_bytes -= partition.memory_bytes();
+            }
+        }
+    }
+}
+
+/// Shared snapshot of a loaded partition.
+pub type PartitionRef<T> = Arc<Partition<T>>;
 
 /// Reference type of an attribute value.
 ///
@@ -305,13 +880,15 @@ pub type AttributeValueRef<'a> = Ref<'a, AttributeValue>;
 impl<T, FS> Database<T, FS>
 where
     T: Scalar,
-    FS: FileSystem,
+    FS: ReadFileSystem,
     Self: LoadPartition<T> + LoadCodebook<T> + LoadPartitionCentroids<T>,
 {
     /// Queries k-nearest neighbors (k-NN) of a given vector.
     ///
     /// The first call to this function will take longer because it lazily
     /// loads partition centroids, and codebooks.
+    ///
+    /// Fails if `v` has an infinite or NaN element.
     pub fn query<'a, V>(
         &'a self,
         v: &V,
@@ -324,49 +901,175 @@ where
         self.query_with_events(v, k, nprobe, |_| {})
     }
 
+    /// Assigns a given vector to the nearest partition.
+    ///
+    /// Lazily loads partition centroids. Projects `v` if the database has a
+    /// projection.
+    ///
+    /// Fails if the vector size does not match.
+    pub fn assign_partition<V>(
+        &self,
+        v: &V,
+    ) -> Result<PartitionAssignment<T>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let v = project_query(self.projection(), v.as_slice())?;
+        assign_partition(self.get_partition_centroids()?, &v)
+    }
+
+    /// Returns an encoder that encodes vectors the same way as the vectors
+    /// in the database.
+    ///
+    /// Lazily loads partition centroids and codebooks.
+    pub fn pq_encoder(&self) -> Result<PqEncoder<T>, Error> {
+        let partition_centroids = self.get_partition_centroids()?.clone();
+        self.load_codebooks_if_needed()?;
+        let codebooks = self.codebooks.borrow().clone().unwrap();
+        PqEncoder::new(partition_centroids, codebooks)
+    }
+
+    // Loads codebooks if not loaded yet.
+    fn load_codebooks_if_needed(&self) -> Result<(), Error> {
+        if self.codebooks.borrow().is_none() {
+            let mut codebooks: Vec<BlockVectorSet<T>> =
+                Vec::with_capacity(self.num_divisions());
+            for di in 0..self.num_divisions() {
+                codebooks.push(self.load_codebook(di)?);
+            }
+            self.codebooks.replace(Some(codebooks));
+        }
+        Ok(())
+    }
+
+    // Returns the partition centroids loading them if necessary.
+    fn get_partition_centroids(&self) -> Result<&BlockVectorSet<T>, Error> {
+        if self.partition_centroids.get().is_none() {
+            // lazily loads partition centroids
+            self.partition_centroids
+                .set(self.load_partition_centroids()?)
+                .unwrap();
+        }
+        Ok(self.partition_centroids.get().unwrap())
+    }
+
     /// Queries k-nearest neighbors (k-NN) of a given vector.
     ///
     /// The first call to this function will take longer because it lazily
     /// loads partition centroids, and codebooks.
+    ///
+    /// Fails if `v` has an infinite or NaN element.
     pub fn query_with_events<'a, V, EventHandler>(
         &'a self,
         v: &V,
         k: NonZeroUsize,
         nprobe: NonZeroUsize,
+        event: EventHandler,
+    ) -> Result<Vec<QueryResult<'a, T, FS>>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+        EventHandler: FnMut(QueryEvent),
+    {
+        self.query_internal(v, k, nprobe, QueryMetric::SquaredL2, event, None)
+    }
+
+    /// Queries k-nearest neighbors (k-NN) of a given vector under a given
+    /// metric.
+    ///
+    /// [`QueryResult::squared_distance`] holds the score under `metric`.
+    ///
+    /// Fails if `v` has an infinite or NaN element.
+    pub fn query_with_metric<'a, V>(
+        &'a self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+        metric: QueryMetric,
+    ) -> Result<Vec<QueryResult<'a, T, FS>>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        self.query_internal(v, k, nprobe, metric, |_| {}, None)
+    }
+
+    /// Queries k-nearest neighbors (k-NN) of a given vector that satisfy a
+    /// given predicate.
+    ///
+    /// `filter` receives the ID and attributes of a candidate vector, and
+    /// returns whether the candidate may be included in the results.
+    /// Attributes are `None` if the vector has no attribute.
+    /// `filter` is evaluated only for candidates close enough to enter the
+    /// k-nearest neighbors, and attributes of queried partitions are lazily
+    /// loaded.
+    ///
+    /// Fewer than `k` results may be returned if not enough candidates in
+    /// the `nprobe` partitions satisfy `filter`.
+    pub fn query_with_filter<'a, V, F>(
+        &'a self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+        mut filter: F,
+    ) -> Result<Vec<QueryResult<'a, T, FS>>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+        F: FnMut(&Uuid, Option<&Attributes>) -> bool,
+    {
+        self.query_internal(
+            v,
+            k,
+            nprobe,
+            QueryMetric::SquaredL2,
+            |_| {},
+            Some(&mut filter),
+        )
+    }
+
+    // Queries k-nearest neighbors of a given vector optionally filtered by
+    // a predicate.
+    fn query_internal<'a, V, EventHandler>(
+        &'a self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+        metric: QueryMetric,
         mut event: EventHandler,
+        mut filter: Option<&mut QueryFilter<'_>>,
     ) -> Result<Vec<QueryResult<'a, T, FS>>, Error>
     where
         V: AsSlice<T> + ?Sized,
-        EventHandler: FnMut(QueryEvent) -> (),
+        EventHandler: FnMut(QueryEvent),
     {
+        check_finite(v.as_slice())?;
+        let v = project_query(self.projection(), v.as_slice())?;
+        let v: &[T] = &v;
         event(QueryEvent::StartingQueryInitialization);
-        if self.partition_centroids.get().is_none() {
-            // lazily loads partition centroids
-            self.partition_centroids
-                .set(self.load_partition_centroids()?)
-                .unwrap();
-        }
-        if self.codebooks.borrow().is_none() {
-            // loads codebooks if not loaded yet.
-            let mut codebooks: Vec<BlockVectorSet<T>> =
-                Vec::with_capacity(self.num_divisions());
-            for di in 0..self.num_divisions() {
-                codebooks.push(self.load_codebook(di)?);
-            }
-            self.codebooks.replace(Some(codebooks));
-        }
+        self.get_partition_centroids()?;
+        self.load_codebooks_if_needed()?;
         event(QueryEvent::FinishedQueryInitialization);
         event(QueryEvent::StartingPartitionSelection);
-        let v = v.as_slice();
-        let queries = self.query_partitions(v, k, nprobe)?;
+        let queries = self.query_partitions(v, k, nprobe, metric)?;
         event(QueryEvent::FinishedPartitionSelection);
+        // sizes the buffers for the largest partition to query if known
+        let mut scratch = match self.partition_sizes() {
+            Some(sizes) => QueryScratch::with_capacity(
+                metric,
+                queries
+                    .iter()
+                    .map(|query| sizes[query.partition_index])
+                    .max()
+                    .unwrap_or(0),
+            ),
+            None => QueryScratch::new(metric),
+        }.with_fast_scan(self.fast_scan);
         let all_results: Vec<Vec<QueryResult<'a, T, FS>>> = queries
             .into_iter()
             .map(|query| {
                 event(QueryEvent::StartingPartitionQuery(
                     query.partition_index,
                 ));
-                let results = query.execute();
+                let results =
+                    query.execute(v, &mut scratch, filter.as_deref_mut());
                 if results.is_ok() {
                     event(QueryEvent::FinishedPartitionQuery(
                         query.partition_index,
@@ -376,19 +1079,18 @@ where
             })
             .collect::<Result<Vec<_>, Error>>()?;
         event(QueryEvent::StartingResultSelection);
-        let mut all_results: Vec<QueryResult<'a, T, FS>> = all_results
-            .into_iter()
-            .flatten()
-            .n_best_by_key(k.get(), |r| r.squared_distance)
-            .into();
-        all_results.sort_by(|lhs, rhs| {
-            lhs.squared_distance.partial_cmp(&rhs.squared_distance).unwrap()
-        });
+        let all_results: Vec<QueryResult<'a, T, FS>> =
+            merge_sorted_by_key(
+                all_results,
+                |r| (r.squared_distance, r.vector_id),
+            )
+                .take(k.get())
+                .collect();
         event(QueryEvent::FinishedResultSelection);
         Ok(all_results)
     }
 
-    // Queries partitions closest to a given vector.
+    // Queries partitions closest to a given vector under a given metric.
     //
     // Panics if the partition centroids are not loaded.
     fn query_partitions<'a>(
@@ -396,6 +1098,7 @@ where
         v: &[T],
         k: NonZeroUsize,
         nprobe: NonZeroUsize,
+        metric: QueryMetric,
     ) -> Result<Vec<PartitionQuery<'a, T, FS>>, Error> {
         let nprobe = nprobe.get();
         let k = k.get();
@@ -409,39 +1112,102 @@ where
         }
         let partition_centroids = self.partition_centroids.get()
             .expect("partition centroids must be loaded");
-        // localizes vectors and calculates distances
-        let mut distances: NBestByKey<(usize, Vec<T>, T), T, _> =
-            NBestByKey::new(nprobe, |(_, _, distance)| *distance);
+        // scores partition centroids
+        let mut scores: NBestByKey<(usize, T), T, _> =
+            NBestByKey::new(nprobe, |(_, score)| *score);
         for pi in 0..num_partitions {
-            let mut localized: Vec<T> = Vec::with_capacity(self.vector_size());
-            unsafe {
-                localized.set_len(self.vector_size());
-            }
             let centroid = partition_centroids.get(pi);
-            subtract(v, &centroid, &mut localized[..]);
-            let distance = dot(&localized[..], &localized[..]);
-            distances.push((pi, localized, distance));
-        }
-        // chooses `nprobes` shortest distances.
-        distances.sort_by(|lhs, rhs| lhs.2.partial_cmp(&rhs.2).unwrap());
-        // makes queries.
-        let queries = distances
+            let score = metric.score_with_threads(
+                v,
+                centroid,
+                self.parallel_reduction,
+            );
+            scores.push((pi, score));
+        }
+        // makes queries in ascending order of scores.
+        let queries = scores
+            .into_sorted_vec()
             .into_iter()
-            .map(|(pi, localized, _)| PartitionQuery {
+            .map(|(pi, _)| PartitionQuery {
                 db: self,
-                codebooks: Ref::map(
-                    self.codebooks.borrow(),
-                    |cb| cb.as_ref().unwrap(),
-                ),
                 partition_index: pi,
-                localized,
-                k,
+                // a partition cannot yield more results than its vectors
+                k: self.partition_sizes()
+                    .map_or(k, |sizes| k.min(sizes[pi])),
             })
             .collect();
         Ok(queries)
     }
 }
 
+impl<T, FS> Database<T, FS>
+where
+    T: Scalar,
+    FS: ReadFileSystem,
+    Self: LoadPartition<T>
+        + LoadCodebook<T>
+        + LoadPartitionCentroids<T>
+        + LoadVectors<T>,
+{
+    /// Queries k-nearest neighbors (k-NN) of a given vector, and re-ranks
+    /// them by exact distances.
+    ///
+    /// Retrieves `k` × `oversample` candidates with approximate distances,
+    /// recomputes their squared distances from the persisted vectors, and
+    /// returns the `k` nearest of them. Every result has the error bound of
+    /// zero. Loads the persisted vectors of every partition that has a
+    /// candidate, so the database has to be serialized with
+    /// [`SerializeOptions::with_residues`]. A database with
+    /// [`Quantization::Flat`] is queried without re-ranking, because its
+    /// distances are already exact.
+    ///
+    /// Fails if:
+    /// - `v` has an infinite or NaN element.
+    /// - `k` × `oversample` overflows.
+    /// - the database has no residues.
+    pub fn query_with_reranking<'a, V>(
+        &'a self,
+        v: &V,
+        k: NonZeroUsize,
+        nprobe: NonZeroUsize,
+        oversample: NonZeroUsize,
+    ) -> Result<Vec<QueryResult<'a, T, FS>>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        if self.quantization() == Quantization::Flat {
+            return self.query(v, k, nprobe);
+        }
+        let num_candidates = num_rerank_candidates(k, oversample)?;
+        let mut results = self.query(v, num_candidates, nprobe)?;
+        let v = project_query(self.projection(), v.as_slice())?;
+        // loads the vectors of each partition only once
+        let mut vectors: HashMap<usize, BlockVectorSet<T>> = HashMap::new();
+        for result in results.iter_mut() {
+            let vectors = match vectors.entry(result.partition_index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(self.load_vectors(result.partition_index)?)
+                },
+            };
+            result.squared_distance = exact_squared_distance(
+                &v,
+                vectors,
+                result.vector_index,
+                self.parallel_reduction,
+            )?;
+            result.error_bound = Some(T::zero());
+        }
+        results.sort_by(|l, r| {
+            (l.squared_distance, l.vector_id)
+                .partial_cmp(&(r.squared_distance, r.vector_id))
+                .unwrap()
+        });
+        results.truncate(k.get());
+        Ok(results)
+    }
+}
+
 /// Partition.
 ///
 /// Bears the centroid element type `T`, but the centroid is not retained
@@ -449,11 +1215,29 @@ where
 #[derive(Clone)]
 pub struct Partition<T> {
     _t: std::marker::PhantomData<T>,
-    encoded_vectors: BlockVectorSet<u32>,
+    encoded_vectors: LazyEncodedVectorSet,
     vector_ids: Vec<Uuid>,
+    // Residues in full precision. `None` if the database is quantized.
+    residues: Option<BlockVectorSet<T>>,
+    // Squared norms of the quantization errors. `None` if unknown.
+    squared_error_norms: Option<Vec<T>>,
 }
 
 impl<T> Partition<T> {
+    // Returns the number of bytes allocated for the partition.
+    fn memory_bytes(&self) -> usize {
+        self.encoded_vectors.memory_bytes()
+            + self.vector_ids.capacity() * core::mem::size_of::<Uuid>()
+            + self.residues.as_ref().map_or(0, |residues| {
+                residues.len()
+                    * residues.vector_size()
+                    * core::mem::size_of::<T>()
+            })
+            + self.squared_error_norms.as_ref().map_or(0, |norms| {
+                norms.capacity() * core::mem::size_of::<T>()
+            })
+    }
+
     /// Returns the number of vectors in the partition.
     pub fn num_vectors(&self) -> usize {
         self.encoded_vectors.len()
@@ -462,9 +1246,9 @@ impl<T> Partition<T> {
     /// Returns a specified encoded vector.
     ///
     /// `None` if `idnex` ≥ `num_vectors`.
-    pub fn get_encoded_vector(&self, index: usize) -> Option<&[u32]> {
+    pub fn get_encoded_vector(&self, index: usize) -> Option<Vec<u32>> {
         if index < self.encoded_vectors.len() {
-            Some(self.encoded_vectors.get(index))
+            Some(self.encoded_vectors.get().get(index))
         } else {
             None
         }
@@ -476,6 +1260,37 @@ impl<T> Partition<T> {
     pub fn get_vector_id(&self, index: usize) -> Option<&Uuid> {
         self.vector_ids.get(index)
     }
+
+    /// Returns all the encoded vectors in the partition.
+    ///
+    /// Each encoded vector consists of a code per subvector division.
+    /// Packed encoded vectors are unpacked on the first call.
+    pub fn encoded_vectors(&self) -> &EncodedVectorSet {
+        self.encoded_vectors.get()
+    }
+
+    /// Returns the IDs of all the vectors in the partition.
+    ///
+    /// IDs are in the same order as the encoded vectors.
+    pub fn vector_ids(&self) -> &[Uuid] {
+        &self.vector_ids
+    }
+
+    /// Returns the residues of the vectors in full precision.
+    ///
+    /// `None` unless the database has [`Quantization::Flat`].
+    pub fn residues(&self) -> Option<&BlockVectorSet<T>> {
+        self.residues.as_ref()
+    }
+
+    /// Returns the squared norms of the quantization errors of the vectors.
+    ///
+    /// Norms are in the same order as the encoded vectors, and correct the
+    /// approximate squared distances. `None` if the partition was serialized
+    /// without them.
+    pub fn squared_error_norms(&self) -> Option<&[T]> {
+        self.squared_error_norms.as_deref()
+    }
 }
 
 /// Capability of loading a partition.
@@ -498,6 +1313,40 @@ pub trait LoadCodebook<T> {
     fn load_codebook(&self, index: usize) -> Result<BlockVectorSet<T>, Error>;
 }
 
+/// Capability of loading residue vectors.
+///
+/// Supposed to be specialized for a specific [`Database`].
+pub trait LoadResidues<T> {
+    /// Loads the residue vectors of a partition at a given index.
+    ///
+    /// Residue vectors are in the same order as the vectors in the
+    /// partition. Adding the partition centroid to a residue vector
+    /// reconstructs the original vector.
+    ///
+    /// Residues are derived from original vectors if the database persists
+    /// original vectors instead.
+    ///
+    /// Fails if `index` is out of the bounds, or the database has no
+    /// residues.
+    fn load_residues(&self, index: usize) -> Result<BlockVectorSet<T>, Error>;
+}
+
+/// Capability of loading original vectors.
+///
+/// Supposed to be specialized for a specific [`Database`].
+pub trait LoadVectors<T> {
+    /// Loads the original vectors of a partition at a given index.
+    ///
+    /// Vectors are in the same order as the vectors in the partition, and
+    /// in the space the database indexes; i.e., projected if the database
+    /// has a projection. Vectors are reconstructed from residues unless the
+    /// database persists original vectors.
+    ///
+    /// Fails if `index` is out of the bounds, or the database has no
+    /// residues.
+    fn load_vectors(&self, index: usize) -> Result<BlockVectorSet<T>, Error>;
+}
+
 /// Capability of loading partition centroids.
 ///
 /// Supposed to be specialized for a specific [`Database`].
@@ -531,69 +1380,107 @@ pub enum QueryEvent {
     FinishedResultSelection,
 }
 
+// Predicate on the ID and attributes of a vector.
+type QueryFilter<'f> = dyn FnMut(&Uuid, Option<&Attributes>) -> bool + 'f;
+
 /// Query in a specific partition.
 struct PartitionQuery<'a, T, FS> {
     db: &'a Database<T, FS>,
-    codebooks: Ref<'a, Vec<BlockVectorSet<T>>>,
     partition_index: usize,
-    localized: Vec<T>, // query vector - partition centroid
     k: usize,
 }
 
 impl<'a, T, FS> PartitionQuery<'a, T, FS>
 where
     T: Scalar,
-    FS: FileSystem,
+    FS: ReadFileSystem,
     Database<T, FS>: LoadPartition<T> + LoadCodebook<T>,
 {
-    fn execute(&self) -> Result<Vec<QueryResult<'a, T, FS>>, Error> {
-        let num_divisions = self.db.num_divisions();
-        let num_codes = self.db.num_codes();
-        let subvector_size = self.db.subvector_size();
-        // loads the partition
+    // Panics if the partition centroids or codebooks are not loaded.
+    fn execute(
+        &self,
+        v: &[T],
+        scratch: &mut QueryScratch<T>,
+        mut filter: Option<&mut QueryFilter<'_>>,
+    ) -> Result<Vec<QueryResult<'a, T, FS>>, Error> {
+        // loads the partition, which may be skipped if it is damaged
+        if let Err(err) = self.db.get_partition(self.partition_index) {
+            self.db.skip_damaged_file(FileKind::Partition, err)?;
+            return Ok(Vec::new());
+        }
+        if filter.is_some() {
+            self.db.load_attributes_log_or_skip(self.partition_index)?;
+        }
         let partition = self.db.get_partition(self.partition_index)?;
-        // calculates the distance table
-        let mut distance_table: Vec<T> =
-            Vec::with_capacity(num_divisions * num_codes);
-        let mut vector_buf: Vec<T> = Vec::with_capacity(subvector_size);
-        unsafe {
-            vector_buf.set_len(subvector_size);
-        }
-        for di in 0..num_divisions {
-            let from = di * subvector_size;
-            let to = from + subvector_size;
-            let subv = &self.localized[from..to];
-            let codebook = &self.codebooks[di];
-            for ci in 0..num_codes {
-                let code_vector = codebook.get(ci);
-                let d = &mut vector_buf[..];
-                subtract(subv, code_vector, d);
-                distance_table.push(dot(d, d));
-            }
-        }
-        // approximates the squared distances to vectors in the partition
-        let num_vectors = partition.num_vectors();
-        let mut results: NBestByKey<QueryResult<'a, T, FS>, T, _> =
+        let attribute_table = self.db.attribute_table.borrow();
+        let generation = self.db
+            .attributes_generation(self.partition_index)
+            .unwrap();
+        let max_error2 = max_error2(&self.db.quantization_errors);
+        // approximates the scores of vectors in the partition
+        // breaks ties by vector IDs so that results are deterministic
+        let mut results: NBestByKey<QueryResult<'a, T, FS>, (T, Uuid), _> =
             NBestByKey::new(
                 self.k,
-                |i: &QueryResult<'a, T, FS>| i.squared_distance,
+                |i: &QueryResult<'a, T, FS>| (i.squared_distance, i.vector_id),
             );
-        for vi in 0..num_vectors {
-            let encoded_vector = partition.get_encoded_vector(vi).unwrap();
-            let mut distance = T::zero();
-            for di in 0..num_divisions {
-                let ci = encoded_vector[di] as usize;
-                distance += distance_table[di * num_codes + ci];
+        let centroid = self.db.partition_centroids.get()
+            .expect("partition centroids must be loaded")
+            .get(self.partition_index);
+        let codebooks = self.db.codebooks.borrow();
+        let codebooks = codebooks.as_ref().expect("codebooks must be loaded");
+        // scores residues exactly if the partition has them
+        let (table, scores) = match partition.residues() {
+            Some(residues) => {
+                (None, scratch.score_residues(v, centroid, residues))
+            },
+            None => {
+                let (table, scores) = scratch.score_partition(
+                    v,
+                    centroid,
+                    codebooks,
+                    &partition.encoded_vectors,
+                    partition.squared_error_norms(),
+                );
+                (Some(table), scores)
+            },
+        };
+        let error_norms = partition.squared_error_norms();
+        for (vi, &distance) in scores.iter().enumerate() {
+            let vector_id = partition.get_vector_id(vi).unwrap();
+            if let Some(filter) = filter.as_deref_mut() {
+                // evaluates the filter only if the candidate can enter
+                if results.len() == self.k && (distance, *vector_id) >= {
+                    let last = results.last().unwrap();
+                    (last.squared_distance, last.vector_id)
+                } {
+                    continue;
+                }
+                let attributes = attribute_table
+                    .as_ref()
+                    .and_then(|tbl| tbl.get(vector_id));
+                if !filter(vector_id, attributes) {
+                    continue;
+                }
             }
             results.push(QueryResult {
                 db: self.db,
                 partition_index: self.partition_index,
-                vector_id: partition.get_vector_id(vi).unwrap().clone(),
+                vector_id: *vector_id,
                 vector_index: vi,
                 squared_distance: distance,
+                error_bound: match table {
+                    Some(table) => table.vector_error_bound(
+                        distance,
+                        error_norms.map(|norms| norms[vi]),
+                        max_error2,
+                    ),
+                    None => Some(T::zero()),
+                },
+                generation,
             });
         }
-        Ok(results.into())
+        Ok(results.into_sorted_vec())
     }
 }
 
@@ -608,13 +1495,30 @@ pub struct QueryResult<'a, T, FS> {
     /// Vector index. Local index in the partition.
     pub vector_index: usize,
     /// Approximate squared distance.
+    ///
+    /// Corrected by the squared norm of the quantization error of the vector
+    /// if the partition records it; see [`Partition::squared_error_norms`].
+    /// Holds the score under the metric if queried with
+    /// [`Database::query_with_metric`].
     pub squared_distance: T,
+    /// Estimated upper bound of the error of `squared_distance`.
+    ///
+    /// Derived from the quantization error of the vector if the partition
+    /// records it, or the maximum quantization errors of the codebooks
+    /// otherwise; exact re-ranking with [`Database::query_with_reranking`]
+    /// may be worthwhile if the bound is comparable to the gaps between
+    /// results. `None` under [`QueryMetric::Cosine`], or if the database has
+    /// no quantization errors. Zero under [`Quantization::Flat`], which
+    /// scores vectors exactly, or after re-ranking.
+    pub error_bound: Option<T>,
+    /// Generation of the attributes log the query ran against.
+    pub generation: Generation,
 }
 
 impl<'a, T, FS> QueryResult<'a, T, FS>
 where
     T: Scalar,
-    FS: FileSystem,
+    FS: ReadFileSystem,
     Database<T, FS>:
         LoadPartition<T> + LoadCodebook<T> + LoadPartitionCentroids<T>,
 {
@@ -625,7 +1529,7 @@ where
     pub fn get_attribute<K>(
         &self,
         key: &K,
-    ) -> Result<Option<AttributeValueRef>, Error>
+    ) -> Result<Option<AttributeValueRef<'_>>, Error>
     where
         String: Borrow<K>,
         K: Hash + Eq + ?Sized,
@@ -636,6 +1540,56 @@ where
             key,
         )
     }
+
+    /// Returns an attribute value of the vector corresponding to the result
+    /// and the generation of the attributes log it is read from.
+    ///
+    /// The attribute comes from a newer attributes log than the one the
+    /// query ran against if the generation differs from
+    /// [`QueryResult::generation`].
+    pub fn get_attribute_with_generation<K>(
+        &self,
+        key: &K,
+    ) -> Result<(Option<AttributeValueRef<'_>>, Generation), Error>
+    where
+        String: Borrow<K>,
+        K: Hash + Eq + ?Sized,
+    {
+        let value = self.get_attribute(key)?;
+        let generation = self.db
+            .attributes_generation(self.partition_index)
+            .ok_or(Error::InvalidContext(format!(
+                "partition index out of bounds: {}",
+                self.partition_index,
+            )))?;
+        Ok((value, generation))
+    }
+}
+
+// Reads a message from a file, in place if possible, and verifies the file
+// unless disabled.
+fn read_file_message<M, R>(
+    mut f: R,
+    verification: bool,
+    max_message_size: Option<usize>,
+) -> Result<M, Error>
+where
+    M: Message,
+    R: HashedFileIn,
+{
+    let message = match f.read_in_place() {
+        Some(contents) => {
+            if let Some(max_size) = max_message_size {
+                check_message_size(contents.len() as u64, max_size)?;
+            }
+            M::parse_from_bytes(contents)?
+        },
+        None => read_message_up_to(&mut f, max_message_size)?,
+    };
+    if verification {
+        f.verify()?;
+    }
+    Ok(message)
 }
 
 mod f32impl {
@@ -643,7 +1597,7 @@ mod f32impl {
 
     impl<FS> LoadDatabase<f32, FS> for Database<f32, FS>
     where
-        FS: FileSystem,
+        FS: ReadFileSystem,
     {
         /// Loads a database.
         ///
@@ -652,38 +1606,56 @@ mod f32impl {
         /// - `num_divisions` is zero
         /// - `num_partitions` is zero
         /// - `num_codes` is zero
-        /// - `vector_size` is not a multiple of `num_divisions`
+        /// - `num_divisions` exceeds `vector_size`
         /// - `num_partitions` and `partitions_refs.len()` do not match
         /// - `vector_size` and centroid size do not match
         /// - `num_divisions` and `codebook_refs.len()` do not match
-        fn load_database<P>(fs: FS, path: P) -> Result<Database<f32, FS>, Error>
+        /// - `residues_ids` is neither empty nor matches `num_partitions`
+        /// - the output size of the projection and `vector_size` do not
+        ///   match
+        ///
+        /// Also fails if the partition centroids or codebooks are invalid
+        /// when they are loaded eagerly.
+        fn load_database_with_options<P>(
+            fs: FS,
+            path: P,
+            options: OpenOptions,
+        ) -> Result<Database<f32, FS>, Error>
         where
             P: AsRef<str>,
         {
+            let header_path = path.as_ref().to_string();
             let mut f = fs.open_compressed_hashed_file(path)?;
-            let db: ProtosDatabase = read_message(&mut f)?;
+            let db: ProtosDatabase =
+                read_message_up_to(&mut f, options.max_message_size())?;
             f.verify()?;
             let vector_size = db.vector_size as usize;
             let num_partitions = db.num_partitions as usize;
             let num_divisions = db.num_divisions as usize;
             let num_codes = db.num_codes as usize;
             if vector_size == 0 {
-                return Err(Error::InvalidData(format!("vector_size is zero")));
+                return Err(Error::InvalidData(
+                    "vector_size is zero".to_string(),
+                ));
             }
             if num_divisions == 0 {
-                return Err(Error::InvalidData(format!("num_divisions is zero")));
+                return Err(Error::InvalidData(
+                    "num_divisions is zero".to_string(),
+                ));
             }
             if num_partitions == 0 {
-                return Err(Error::InvalidData(format!("num_partitions is zero")));
+                return Err(Error::InvalidData(
+                    "num_partitions is zero".to_string(),
+                ));
             }
             if num_codes == 0 {
-                return Err(Error::InvalidData(format!("num_codes is zero")));
+                return Err(Error::InvalidData("num_codes is zero".to_string()));
             }
-            if vector_size % num_divisions != 0 {
+            if num_divisions > vector_size {
                 return Err(Error::InvalidData(format!(
-                    "vector_size {} is not multiple of num_divisions {}",
-                    vector_size,
+                    "num_divisions {} exceeds vector_size {}",
                     num_divisions,
+                    vector_size,
                 )));
             }
             if num_partitions != db.partition_ids.len() {
@@ -700,8 +1672,25 @@ mod f32impl {
                     db.codebook_ids.len(),
                 )));
             }
+            if !db.residues_ids.is_empty()
+                && num_partitions != db.residues_ids.len()
+            {
+                return Err(Error::InvalidData(format!(
+                    "num_partitions {} and residues_ids.len() {} do not match",
+                    db.num_partitions,
+                    db.residues_ids.len(),
+                )));
+            }
+            let quantization_errors = deserialize_quantization_errors(&db)?;
+            let projection = deserialize_projection(&db)?;
+            let quantization = deserialize_quantization(&db)?;
+            let compression = deserialize_file_compression(&db)?;
+            let hash_algorithm = deserialize_hash_algorithm(&db)?;
+            let chunk_trees = deserialize_chunk_trees(&db)?;
+            let partition_sizes = deserialize_partition_sizes(&db)?;
             let db = Database {
                 fs,
+                header_path: RefCell::new(header_path),
                 vector_size,
                 num_partitions,
                 num_divisions,
@@ -712,29 +1701,52 @@ mod f32impl {
                 partition_centroids: OnceCell::new(),
                 codebook_ids: db.codebook_ids,
                 codebooks: RefCell::new(None),
-                attributes_log_ids: db.attributes_log_ids,
+                attributes_log_ids: RefCell::new(db.attributes_log_ids),
                 attributes_log_load_flags:
                     RefCell::new(vec![false; num_partitions]),
                 attribute_names: db.attribute_names,
                 attribute_table: RefCell::new(None),
+                residues_ids: db.residues_ids,
+                original_vectors: db.original_vectors,
+                quantization_errors,
+                projection,
+                quantization,
+                compression,
+                hash_algorithm,
+                chunk_trees,
+                partition_sizes,
+                verification: options.is_verification_enabled(),
+                validation_mode: options.validation_mode(),
+                max_message_size: options.max_message_size(),
+                fast_scan: options.is_fast_scan_enabled(),
+                parallel_reduction: options.parallel_reduction(),
+                cache_budget: options.cache_budget(),
+                partition_load_order: RefCell::new(VecDeque::new()),
+                load_event_handler: RefCell::new(None),
             };
+            if options.is_eager() {
+                db.get_partition_centroids()?;
+                db.load_codebooks_if_needed()?;
+            }
             Ok(db)
         }
     }
 
     impl<FS> LoadPartitionCentroids<f32> for Database<f32, FS>
     where
-        FS: FileSystem,
+        FS: ReadFileSystem,
     {
         fn load_partition_centroids(
             &self,
         ) -> Result<BlockVectorSet<f32>, Error> {
-            let mut f = self.fs.open_hashed_file(format!(
-                "partitions/{}.{}",
-                self.partition_centroids_id,
-                PROTOBUF_EXTENSION,
-            ))?;
-            let partition_centroids: ProtosVectorSet = read_message(&mut f)?;
+            let partition_centroids: ProtosVectorSet = self.read_file(
+                FileKind::PartitionCentroids,
+                format!(
+                    "partitions/{}.{}",
+                    self.partition_centroids_id,
+                    PROTOBUF_EXTENSION,
+                ),
+            )?;
             let partition_centroids: BlockVectorSet<f32> =
                 partition_centroids.deserialize()?;
             if partition_centroids.vector_size() != self.vector_size() {
@@ -757,7 +1769,7 @@ mod f32impl {
 
     impl<FS> LoadCodebook<f32> for Database<f32, FS>
     where
-        FS: FileSystem,
+        FS: ReadFileSystem,
     {
         /// Loads a codebook.
         ///
@@ -771,7 +1783,7 @@ mod f32impl {
             index: usize,
         ) -> Result<BlockVectorSet<f32>, Error>
         where
-            FS: FileSystem,
+            FS: ReadFileSystem,
         {
             if index >= self.num_divisions() {
                 return Err(Error::InvalidArgs(format!(
@@ -780,18 +1792,22 @@ mod f32impl {
                     self.num_divisions(),
                 )));
             }
-            let mut f = self.fs.open_hashed_file(format!(
-                "codebooks/{}.{}",
-                self.get_codebook_id(index).unwrap(),
-                PROTOBUF_EXTENSION,
-            ))?;
-            let codebook: ProtosVectorSet = read_message(&mut f)?;
-            f.verify()?;
+            let codebook: ProtosVectorSet = self.read_file(
+                FileKind::Codebook,
+                format!(
+                    "codebooks/{}.{}",
+                    self.get_codebook_id(index).unwrap(),
+                    PROTOBUF_EXTENSION,
+                ),
+            )?;
             let codebook: BlockVectorSet<f32> = codebook.deserialize()?;
-            if codebook.vector_size() != self.subvector_size() {
+            let subvector_size =
+                division_range(self.vector_size(), self.num_divisions(), index)
+                    .len();
+            if codebook.vector_size() != subvector_size {
                 return Err(Error::InvalidData(format!(
                     "vector_size is inconsistent: expected {} but got {}",
-                    self.subvector_size(),
+                    subvector_size,
                     codebook.vector_size(),
                 )));
             }
@@ -806,9 +1822,103 @@ mod f32impl {
         }
     }
 
+    impl<FS> LoadResidues<f32> for Database<f32, FS>
+    where
+        FS: ReadFileSystem,
+    {
+        /// Loads the residue vectors of a partition.
+        ///
+        /// Fails if:
+        /// - the database has no residues.
+        /// - `index` exceeds the number of partitions.
+        /// - residues file cannot be loaded.
+        /// - vector size does not match that of the database.
+        fn load_residues(
+            &self,
+            index: usize,
+        ) -> Result<BlockVectorSet<f32>, Error> {
+            let mut residues = self.load_persisted_vectors(index)?;
+            if self.has_original_vectors() {
+                let centroid = self.get_partition_centroids()?.get(index);
+                for i in 0..residues.len() {
+                    let v = residues.get_mut(i);
+                    for (x, c) in v.iter_mut().zip(centroid) {
+                        *x -= *c;
+                    }
+                }
+            }
+            Ok(residues)
+        }
+    }
+
+    impl<FS> LoadVectors<f32> for Database<f32, FS>
+    where
+        FS: ReadFileSystem,
+    {
+        /// Loads the original vectors of a partition.
+        ///
+        /// Fails if:
+        /// - the database has no residues.
+        /// - `index` exceeds the number of partitions.
+        /// - residues file cannot be loaded.
+        /// - vector size does not match that of the database.
+        fn load_vectors(
+            &self,
+            index: usize,
+        ) -> Result<BlockVectorSet<f32>, Error> {
+            let mut vectors = self.load_persisted_vectors(index)?;
+            if !self.has_original_vectors() {
+                let centroid = self.get_partition_centroids()?.get(index);
+                for i in 0..vectors.len() {
+                    f32::add_in(vectors.get_mut(i), centroid);
+                }
+            }
+            Ok(vectors)
+        }
+    }
+
+    impl<FS> Database<f32, FS>
+    where
+        FS: ReadFileSystem,
+    {
+        // Loads the vectors persisted for a partition; i.e., residues or
+        // original vectors.
+        fn load_persisted_vectors(
+            &self,
+            index: usize,
+        ) -> Result<BlockVectorSet<f32>, Error> {
+            if !self.has_residues() {
+                return Err(Error::InvalidContext(
+                    "database has no residues".to_string(),
+                ));
+            }
+            let id = self.get_residues_id(index)
+                .ok_or(Error::InvalidArgs(format!(
+                    "index {} exceeds the number of partitions {}",
+                    index,
+                    self.num_partitions(),
+                )))?;
+            let residues: ProtosVectorSet = self.read_file(
+                FileKind::Residues,
+                format!("residues/{}.{}", id, PROTOBUF_EXTENSION),
+            )?;
+            let residues: BlockVectorSet<f32> = residues.deserialize()?;
+            if !residues.is_empty()
+                && residues.vector_size() != self.vector_size()
+            {
+                return Err(Error::InvalidData(format!(
+                    "vector_size is inconsistent: expected {} but got {}",
+                    self.vector_size(),
+                    residues.vector_size(),
+                )));
+            }
+            Ok(residues)
+        }
+    }
+
     impl<FS> LoadPartition<f32> for Database<f32, FS>
     where
-        FS: FileSystem,
+        FS: ReadFileSystem,
     {
         /// Loads a partition.
         ///
@@ -821,6 +1931,8 @@ mod f32impl {
         /// - `p.num_vectors` and `p.encoded_vectors.len()` do not match
         /// - `p.num_vectors` and `p.vector_ids.len()` do not match
         /// - `p.num_divisions` and encoded vector length do not match
+        /// - `p.residues` is missing without quantization, present with
+        ///   quantization, or does not match the vectors in shape
         fn load_partition(
             &self,
             index: usize,
@@ -832,21 +1944,30 @@ mod f32impl {
                     self.num_partitions,
                 )));
             }
-            let mut f = self.fs.open_compressed_hashed_file(format!(
-                "partitions/{}.{}",
-                self.get_partition_id(index).unwrap(),
-                PROTOBUF_EXTENSION,
-            ))?;
-            let partition: ProtosPartition = read_message(&mut f)?;
-            f.verify()?;
+            let mut partition: ProtosPartition = self.read_file(
+                FileKind::Partition,
+                format!(
+                    "partitions/{}.{}",
+                    self.get_partition_id(index).unwrap(),
+                    PROTOBUF_EXTENSION,
+                ),
+            )?;
+            let residues = deserialize_partition_residues(
+                &mut partition,
+                self.quantization(),
+                self.vector_size(),
+            )?;
+            let squared_error_norms =
+                deserialize_squared_error_norms(&mut partition)?;
             let vector_size = partition.vector_size as usize;
             let num_divisions = partition.num_divisions as usize;
-            let encoded_vectors: BlockVectorSet<u32> = partition.encoded_vectors
-                .into_option()
-                .ok_or(Error::InvalidData(
-                    "missing encoded vectors".to_string(),
-                ))?
-                .deserialize()?;
+            let encoded_vectors = deserialize_lazy(
+                partition.encoded_vectors
+                    .into_option()
+                    .ok_or(Error::InvalidData(
+                        "missing encoded vectors".to_string(),
+                    ))?,
+            )?;
             if vector_size != self.vector_size() {
                 return Err(Error::InvalidData(format!(
                     "vector_size {} and partition.vector_size {} do not match",
@@ -868,15 +1989,1020 @@ mod f32impl {
                     partition.vector_ids.len(),
                 )));
             }
-            let vector_ids: Vec<Uuid> = partition.vector_ids
+            if let Some(sizes) = self.partition_sizes() {
+                if encoded_vectors.len() != sizes[index] {
+                    return Err(Error::InvalidData(format!(
+                        "partition[{}]: expected {} vectors but got {}",
+                        index,
+                        sizes[index],
+                        encoded_vectors.len(),
+                    )));
+                }
+            }
+            let vector_ids = partition.vector_ids
                 .into_iter()
-                .map(|id| id.deserialize().unwrap())
-                .collect();
+                .enumerate()
+                .map(|(i, id)| id.deserialize().map_err(|e| {
+                    Error::InvalidData(format!(
+                        "partition[{}, {}]: invalid vector ID: {}",
+                        index,
+                        i,
+                        e,
+                    ))
+                }))
+                .collect::<Result<Vec<Uuid>, Error>>()?;
             Ok(Partition {
                 _t: std::marker::PhantomData,
                 encoded_vectors,
                 vector_ids,
+                residues,
+                squared_error_norms,
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use crate::db::build::DatabaseBuilder;
+    use crate::db::build::proto::{
+        SerializeOptions,
+        serialize_database_with_options,
+    };
+    use crate::db::fixtures::{
+        build_and_store,
+        build_database,
+        build_database_with,
+        header_path,
+        load_database,
+        store_database,
+        synthetic_vectors,
+    };
+    use crate::io::{
+        HashedFileOut,
+        LocalFileSystem,
+        LocalHashedFileIn,
+        ReadFileSystem,
+        WriteFileSystem,
+    };
+    use crate::protos::{read_message, write_message};
+    use crate::protos::database::{
+        Codec as ProtosCodec,
+        FileCodec as ProtosFileCodec,
+    };
+    use crate::vector::VectorSet;
+
+    #[test]
+    fn stored_database_should_query_byte_codes_like_built_database() {
+        let vectors = synthetic_vectors(200, 4);
+        let query = vectors.get(0).to_vec();
+        // more than 16 codes are stored a byte each
+        let db = build_database_with(200, 4, |builder| {
+            builder.with_clusters(32.try_into().unwrap())
+        });
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        let partition = stored.get_partition(0).unwrap();
+        assert!(matches!(
+            partition.encoded_vectors(),
+            EncodedVectorSet::U8(_),
+        ));
+        drop(partition);
+
+        let expected = db
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        let results = stored
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert!(
+                (result.squared_distance - expected.squared_distance).abs()
+                    < 1e-5,
+            );
+        }
+    }
+
+    #[test]
+    fn stored_database_should_query_uneven_divisions_like_built_database() {
+        let vectors = synthetic_vectors(100, 6);
+        let query = vectors.get(0).to_vec();
+        // 6 elements are divided into 2, 2, 1, and 1
+        let db = build_database_with(100, 6, |builder| {
+            builder.with_divisions(4.try_into().unwrap())
+        });
+        assert_eq!(db.subvector_size(), 2);
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        let sizes: Vec<usize> = (0..stored.num_divisions())
+            .map(|di| stored.load_codebook(di).unwrap().vector_size())
+            .collect();
+        assert_eq!(sizes, vec![2, 2, 1, 1]);
+
+        let expected = db
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        let results = stored
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert!(
+                (result.squared_distance - expected.squared_distance).abs()
+                    < 1e-5,
+            );
+        }
+        // a division cannot be empty
+        assert!(
+            DatabaseBuilder::new(synthetic_vectors(10, 3))
+                .with_partitions(1.try_into().unwrap())
+                .with_divisions(4.try_into().unwrap())
+                .with_clusters(2.try_into().unwrap())
+                .build()
+                .is_err(),
+        );
+    }
+
+    #[test]
+    fn stored_database_should_project_queries_like_built_database() {
+        let vectors = synthetic_vectors(200, 8);
+        let query = vectors.get(0).to_vec();
+        let db = build_database_with(200, 8, |builder| {
+            builder
+                .with_clusters(8.try_into().unwrap())
+                .with_pca(4.try_into().unwrap())
+        });
+        assert_eq!(db.vector_size(), 4);
+        assert_eq!(db.projection().unwrap().input_size(), 8);
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        assert_eq!(stored.vector_size(), 4);
+        assert_eq!(stored.projection().unwrap().input_size(), 8);
+
+        let expected = db
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        let results = stored
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert!(
+                (result.squared_distance - expected.squared_distance).abs()
+                    < 1e-5,
+            );
+        }
+        assert_eq!(
+            stored.assign_partition(&query[..]).unwrap().partition_index,
+            db.assign_partition(&query[..]).unwrap().partition_index,
+        );
+        // a query vector must have the size before projection
+        assert!(matches!(
+            stored.query(
+                &[0.0f32; 4][..],
+                1.try_into().unwrap(),
+                1.try_into().unwrap(),
+            ),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn stored_database_should_rotate_queries_like_built_database() {
+        let vectors = synthetic_vectors(200, 8);
+        let query = vectors.get(0).to_vec();
+        let db = build_database_with(200, 8, |builder| {
+            builder
+                .with_clusters(8.try_into().unwrap())
+                .with_pca(4.try_into().unwrap())
+                .with_random_rotation(1)
+        });
+        assert_eq!(db.vector_size(), 4);
+        let projection = db.projection().unwrap();
+        assert_eq!(projection.input_size(), 8);
+        assert_eq!(projection.output_size(), 4);
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+
+        let expected = db
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        let results = stored
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        let expected: Vec<_> = expected.iter().map(|r| r.vector_id).collect();
+        let results: Vec<_> = results.iter().map(|r| r.vector_id).collect();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn stored_database_should_query_scalar_quantized_vectors() {
+        let vectors = synthetic_vectors(200, 8);
+        let query = vectors.get(0).to_vec();
+        let db = build_database_with(200, 8, |builder| {
+            builder.with_quantization(Quantization::Scalar8)
+        });
+        assert_eq!(db.quantization(), Quantization::Scalar8);
+        assert_eq!(db.num_divisions(), 8);
+        assert_eq!(db.num_clusters(), 256);
+        let first_id = *db.vector_ids().next().unwrap();
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        assert_eq!(stored.quantization(), Quantization::Scalar8);
+
+        let expected = db
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        let results = stored
+            .query(&query[..], 10.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert!(
+                (result.squared_distance - expected.squared_distance).abs()
+                    < 1e-5,
+            );
+        }
+        // 8-bit elements locate the query vector itself
+        assert_eq!(results[0].vector_id, first_id);
+        assert!(results[0].squared_distance < 1e-2);
+    }
+
+    #[test]
+    fn flat_database_should_find_exact_nearest_neighbors() {
+        let vectors = synthetic_vectors(100, 4);
+        let query = [0.5f32, -0.25, 0.0, 1.0];
+        let mut expected: Vec<(f32, usize)> = vectors
+            .iter()
+            .map(|(i, v)| (f32::squared_distance(&query, v), i))
+            .collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let db = build_database_with(100, 4, |builder| {
+            builder
+                .with_partitions(1.try_into().unwrap())
+                .with_quantization(Quantization::Flat)
+        });
+        assert_eq!(db.num_partitions(), 1);
+        let vector_ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        let results = db
+            .query(&query[..], 10.try_into().unwrap(), 1.try_into().unwrap())
+            .unwrap();
+        assert_eq!(results.len(), 10);
+        for (result, &(distance, i)) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, vector_ids[i]);
+            assert!((result.squared_distance - distance).abs() < 1e-5);
+            assert_eq!(result.error_bound, Some(0.0));
+        }
+
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        assert_eq!(stored.quantization(), Quantization::Flat);
+        assert_eq!(
+            stored.get_partition(0).unwrap().residues().unwrap().len(),
+            100,
+        );
+        let stored_results = stored
+            .query(&query[..], 10.try_into().unwrap(), 1.try_into().unwrap())
+            .unwrap();
+        assert_eq!(stored_results.len(), 10);
+        for (result, expected) in stored_results.iter().zip(results.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+            assert!(
+                (result.squared_distance - expected.squared_distance).abs()
+                    < 1e-5,
+            );
+            assert_eq!(result.error_bound, Some(0.0));
+        }
+    }
+
+    #[test]
+    fn ivf_flat_database_should_score_probed_partitions_exactly() {
+        let vectors = synthetic_vectors(200, 4);
+        let query = vectors.get(0).to_vec();
+        let exact: HashMap<usize, f32> = vectors
+            .iter()
+            .map(|(i, v)| (i, f32::squared_distance(&query, v)))
+            .collect();
+        let db = build_database_with(200, 4, |builder| {
+            builder
+                .with_partitions(4.try_into().unwrap())
+                .with_quantization(Quantization::Flat)
+        });
+        assert_eq!(db.num_partitions(), 4);
+        let vector_ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+
+        // probing a single partition prunes the others
+        let results = stored
+            .query(&query[..], 10.try_into().unwrap(), 1.try_into().unwrap())
+            .unwrap();
+        let partition_index = results[0].partition_index;
+        assert_eq!(results[0].vector_id, vector_ids[0]);
+        for result in results.iter() {
+            assert_eq!(result.partition_index, partition_index);
+            let i = vector_ids
+                .iter()
+                .position(|id| *id == result.vector_id)
+                .unwrap();
+            assert!((result.squared_distance - exact[&i]).abs() < 1e-5);
+        }
+        // probing all the partitions finds the exact nearest neighbors
+        let mut expected: Vec<(f32, usize)> =
+            exact.iter().map(|(&i, &d)| (d, i)).collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let results = stored
+            .query(&query[..], 10.try_into().unwrap(), 4.try_into().unwrap())
+            .unwrap();
+        for (result, &(distance, i)) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, vector_ids[i]);
+            assert!((result.squared_distance - distance).abs() < 1e-5);
+        }
+        let built_results = db
+            .query(&query[..], 10.try_into().unwrap(), 4.try_into().unwrap())
+            .unwrap();
+        for (result, expected) in results.iter().zip(built_results.iter()) {
+            assert_eq!(result.vector_id, expected.vector_id);
+        }
+    }
+
+    #[test]
+    fn database_should_load_with_any_compression_policy() {
+        let db = build_database(100, 4);
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let expected: Vec<(Uuid, f32)> = db
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.vector_id, r.squared_distance))
+            .collect();
+        for compression in [
+            CompressionPolicy::all(),
+            CompressionPolicy::none(),
+            CompressionPolicy::new()
+                .with_compressed(FileKind::Partition, false)
+                .with_compressed(FileKind::Codebook, true),
+        ] {
+            let options = SerializeOptions::new()
+                .with_residues(true)
+                .with_compression(compression)
+                .with_compression_level(9);
+            let (dir, header) = store_database(&db, &options);
+            let stored = load_database(&dir, &header);
+            let results: Vec<(Uuid, f32)> = stored
+                .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.vector_id, r.squared_distance))
+                .collect();
+            assert_eq!(results, expected);
+            assert_eq!(stored.compression(), compression);
+            stored.get_attribute(&results[0].0, "datum_id").unwrap();
+            stored.load_residues(0).unwrap();
+        }
+    }
+
+    #[test]
+    fn database_should_load_files_compressed_unlike_policy() {
+        let db = build_database(100, 4);
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let expected: Vec<(Uuid, f32)> = db
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.vector_id, r.squared_distance))
+            .collect();
+        let (dir, header) =
+            store_database(&db, &SerializeOptions::new());
+        let (uncompressed_dir, uncompressed_header) = store_database(
+            &db,
+            &SerializeOptions::new()
+                .with_compression(CompressionPolicy::none()),
+        );
+        let read_header = |dir: &tempfile::TempDir, header: &str| {
+            let mut f = LocalFileSystem::new(dir.path())
+                .open_compressed_hashed_file(header)
+                .unwrap();
+            let db: ProtosDatabase = read_message(&mut f).unwrap();
+            db
+        };
+        // replaces the first partition with its uncompressed copy
+        let mut message = read_header(&dir, &header);
+        let old_path =
+            format!("partitions/{}.binpb", message.partition_ids[0]);
+        let id = read_header(&uncompressed_dir, &uncompressed_header)
+            .partition_ids[0]
+            .clone();
+        let new_path = format!("partitions/{}.binpb", id);
+        std::fs::copy(
+            uncompressed_dir.path().join(&new_path),
+            dir.path().join(&new_path),
+        ).unwrap();
+        message.partition_ids[0] = id;
+        message.file_codecs.retain(|file| file.path != old_path);
+        let write_header = |message: &ProtosDatabase| {
+            let fs = LocalFileSystem::new(dir.path());
+            let mut f = fs.create_compressed_hashed_file().unwrap();
+            write_message(message, &mut f).unwrap();
+            let id = f.persist(PROTOBUF_EXTENSION).unwrap();
+            format!("{}.{}", id, PROTOBUF_EXTENSION)
+        };
+        let query_header = |header: &str| {
+            load_database(&dir, header)
+                .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+                .map(|results| results
+                    .into_iter()
+                    .map(|r| (r.vector_id, r.squared_distance))
+                    .collect::<Vec<(Uuid, f32)>>())
+        };
+        // the policy alone misleads the loader
+        let misleading_header = write_header(&message);
+        assert!(query_header(&misleading_header).is_err());
+        let mut file = ProtosFileCodec::new();
+        file.path = new_path;
+        file.codec = ProtosCodec::UNCOMPRESSED.into();
+        message.file_codecs.push(file);
+        let header = write_header(&message);
+        assert_eq!(query_header(&header).unwrap(), expected);
+    }
+
+    #[test]
+    fn stored_database_should_load_original_vectors_and_residues() {
+        let vectors = synthetic_vectors(100, 4);
+        let db = build_database(100, 4);
+        let vector_ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        let mut loaded = Vec::new();
+        for original_vectors in [false, true] {
+            let options = SerializeOptions::new()
+                .with_residues(true)
+                .with_original_vectors(original_vectors);
+            let (dir, header) = store_database(&db, &options);
+            let stored = load_database(&dir, &header);
+            assert!(stored.has_residues());
+            assert_eq!(stored.has_original_vectors(), original_vectors);
+            for pi in 0..stored.num_partitions() {
+                let partition = stored.load_partition(pi).unwrap();
+                let residues = stored.load_residues(pi).unwrap();
+                let originals = stored.load_vectors(pi).unwrap();
+                assert_eq!(residues.len(), partition.vector_ids().len());
+                for (i, id) in partition.vector_ids().iter().enumerate() {
+                    let vi = vector_ids.iter().position(|v| v == id).unwrap();
+                    for (x, y) in originals.get(i).iter().zip(vectors.get(vi)) {
+                        assert!((x - y).abs() < 1e-5);
+                    }
+                }
+                loaded.push(residues);
+            }
+        }
+        // residues are the same whichever vectors are persisted
+        for pi in 0..2 {
+            let (residues, derived) = (&loaded[pi], &loaded[pi + 2]);
+            for i in 0..residues.len() {
+                for (x, y) in residues.get(i).iter().zip(derived.get(i)) {
+                    assert!((x - y).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stored_database_should_rerank_candidates_exactly() {
+        let vectors = synthetic_vectors(200, 4);
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let mut expected: Vec<(f32, usize)> = vectors
+            .iter()
+            .map(|(i, v)| (f32::squared_distance(&query, v), i))
+            .collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let db = build_database(200, 4);
+        let vector_ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        let load = |options: SerializeOptions| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut fs = LocalFileSystem::new(dir.path());
+            serialize_database_with_options(&db, &mut fs, &options).unwrap();
+            let path = header_path(dir.path());
+            let stored = load_database(&dir, &path);
+            (dir, stored)
+        };
+
+        let (_dir, stored) =
+            load(SerializeOptions::new().with_residues(true));
+        // oversampling every vector finds the exact nearest neighbors
+        let results = stored.query_with_reranking(
+            &query[..],
+            5.try_into().unwrap(),
+            2.try_into().unwrap(),
+            40.try_into().unwrap(),
+        ).unwrap();
+        assert_eq!(results.len(), 5);
+        for (result, &(distance, i)) in results.iter().zip(expected.iter()) {
+            assert_eq!(result.vector_id, vector_ids[i]);
+            assert!((result.squared_distance - distance).abs() < 1e-5);
+            assert_eq!(result.error_bound, Some(0.0));
+        }
+        assert!(matches!(
+            stored.query_with_reranking(
+                &query[..],
+                2.try_into().unwrap(),
+                2.try_into().unwrap(),
+                usize::MAX.try_into().unwrap(),
+            ),
+            Err(Error::InvalidArgs(_)),
+        ));
+
+        let (_dir, stored) = load(SerializeOptions::new());
+        assert!(matches!(
+            stored.query_with_reranking(
+                &query[..],
+                5.try_into().unwrap(),
+                2.try_into().unwrap(),
+                4.try_into().unwrap(),
+            ),
+            Err(Error::InvalidContext(_)),
+        ));
+    }
+
+    #[test]
+    fn stored_database_should_query_long_vectors_with_parallel_reduction() {
+        use crate::linalg::parallel::MIN_PARALLEL_LEN;
+
+        let vector_size = MIN_PARALLEL_LEN;
+        let db = build_database_with(40, vector_size, |builder| {
+            builder.with_divisions(4.try_into().unwrap())
+        });
+        let options = SerializeOptions::new().with_residues(true);
+        let (dir, header) = store_database(&db, &options);
+        let plain = load_database(&dir, &header);
+        let parallel = Database::<f32, _>::load_database_with_options(
+            LocalFileSystem::new(dir.path()),
+            &header,
+            OpenOptions::new()
+                .with_parallel_reduction(4.try_into().unwrap()),
+        ).unwrap();
+        let query: Vec<f32> = (0..vector_size)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect();
+        let k = 5.try_into().unwrap();
+        let oversample = 8.try_into().unwrap();
+        for nprobe in [1, 2] {
+            let nprobe = nprobe.try_into().unwrap();
+            let expected = plain.query(&query[..], k, nprobe).unwrap();
+            let results = parallel.query(&query[..], k, nprobe).unwrap();
+            assert_eq!(results.len(), expected.len());
+            for (result, expected) in results.iter().zip(expected.iter()) {
+                assert_eq!(result.vector_id, expected.vector_id);
+            }
+            let expected = plain
+                .query_with_reranking(&query[..], k, nprobe, oversample)
+                .unwrap();
+            let results = parallel
+                .query_with_reranking(&query[..], k, nprobe, oversample)
+                .unwrap();
+            assert_eq!(results.len(), expected.len());
+            for (result, expected) in results.iter().zip(expected.iter()) {
+                assert_eq!(result.vector_id, expected.vector_id);
+                let error =
+                    (result.squared_distance - expected.squared_distance).abs();
+                assert!(error <= 1e-4 * expected.squared_distance.max(1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn error_norms_should_correct_bias_of_approximate_distances() {
+        let vectors = synthetic_vectors(200, 8);
+        let db = build_database_with(200, 8, |builder| {
+            builder.with_divisions(4.try_into().unwrap())
+        });
+        let vector_ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+
+        let partitions: Vec<_> = (0..2)
+            .map(|pi| stored.load_partition(pi).unwrap())
+            .collect();
+        let k = 200.try_into().unwrap();
+        let nprobe = 2.try_into().unwrap();
+        for qi in (0..200).step_by(20) {
+            let query = vectors.get(qi);
+            let results = stored.query(query, k, nprobe).unwrap();
+            let built_results = db.query(query, k, nprobe).unwrap();
+            assert_eq!(results.len(), 200);
+            for (result, built) in results.iter().zip(built_results.iter()) {
+                assert_eq!(result.vector_id, built.vector_id);
+                assert!(
+                    (result.squared_distance - built.squared_distance).abs()
+                        < 1e-4,
+                );
+                let i = vector_ids
+                    .iter()
+                    .position(|id| *id == result.vector_id)
+                    .unwrap();
+                let exact = f32::squared_distance(query, vectors.get(i));
+                let error2 = partitions[result.partition_index]
+                    .squared_error_norms()
+                    .unwrap()[result.vector_index];
+                let error = result.squared_distance - exact;
+                assert!(error.abs() <= result.error_bound.unwrap() + 1e-4);
+                // the uncorrected distance from a vector to its own
+                // reconstruction is exactly the squared error norm, which
+                // the correction adds once more
+                if i == qi {
+                    assert!((result.squared_distance - 2.0 * error2).abs()
+                        < 1e-3 * (1.0 + error2));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn vector_counts_should_be_available_without_loading_partitions() {
+        let db = build_database_with(100, 4, |builder| {
+            builder.with_partitions(3.try_into().unwrap())
+        });
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        assert_eq!(stored.num_vectors(), Some(100));
+        let sizes = stored.partition_sizes().unwrap().to_vec();
+        assert_eq!(sizes.len(), 3);
+        let stats = stored.partition_stats().unwrap();
+        assert_eq!(stats.num_vectors, 100);
+        assert_eq!(stats.max, *sizes.iter().max().unwrap());
+        for (pi, partition) in db.partitions().enumerate() {
+            assert_eq!(sizes[pi], partition.num_vectors());
+            assert_eq!(
+                stored.load_partition(pi).unwrap().num_vectors(),
+                sizes[pi],
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_attribute_values_should_survive_serialization() {
+        let mut db = build_database(100, 4);
+        for i in 0..db.num_vectors() {
+            let category = if i % 3 == 0 { "fizz" } else { "other" };
+            db.set_attribute_at(i, ("category", category)).unwrap();
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        let ids: Vec<Uuid> = db.vector_ids().cloned().collect();
+        for (i, id) in ids.iter().enumerate() {
+            let category = stored.get_attribute(id, "category").unwrap();
+            assert_eq!(
+                category.as_deref(),
+                db.get_attribute(id, "category").unwrap(),
+            );
+            let index = stored.get_attribute(id, "index").unwrap();
+            assert_eq!(index.as_deref(), Some(&AttributeValue::from(i as u64)));
+        }
+    }
+
+    #[test]
+    fn stored_attribute_can_be_set_only_at_expected_generation() {
+        let mut db = build_database(100, 4);
+        for i in 0..db.num_vectors() {
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        let num_logs = || std::fs::read_dir(dir.path().join("attributes"))
+            .unwrap()
+            .count();
+        let id = *db.vector_ids().next().unwrap();
+        let partition_index = (0..stored.num_partitions())
+            .find(|&i| {
+                stored.get_partition(i).unwrap().vector_ids().contains(&id)
+            })
+            .unwrap();
+        let generation = stored.attributes_generation(partition_index).unwrap();
+        let new_generation = stored
+            .set_attribute_if(&id, "index", generation, 100u64.into())
+            .unwrap()
+            .unwrap();
+        assert_ne!(new_generation, generation);
+        assert_eq!(
+            stored.attributes_generation(partition_index),
+            Some(new_generation),
+        );
+        assert_eq!(
+            stored.get_attribute(&id, "index").unwrap().as_deref(),
+            Some(&AttributeValue::from(100u64)),
+        );
+        assert_eq!(num_logs(), 3);
+        // stale generation
+        assert_eq!(
+            stored
+                .set_attribute_if(&id, "index", generation, 200u64.into())
+                .unwrap(),
+            None,
+        );
+        assert_eq!(
+            stored.get_attribute(&id, "index").unwrap().as_deref(),
+            Some(&AttributeValue::from(100u64)),
+        );
+        assert_eq!(num_logs(), 3);
+        assert!(matches!(
+            stored.set_attribute_if(
+                &id,
+                "unknown",
+                new_generation,
+                1u64.into(),
+            ),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            stored.set_attribute_if(
+                &Uuid::nil(),
+                "index",
+                new_generation,
+                1u64.into(),
+            ),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn stored_attribute_update_should_survive_reloading() {
+        let mut db = build_database(100, 4);
+        for i in 0..db.num_vectors() {
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        let stale = load_database(&dir, &header);
+        let id = *db.vector_ids().next().unwrap();
+        let partition_index = (0..stored.num_partitions())
+            .find(|&i| {
+                stored.get_partition(i).unwrap().vector_ids().contains(&id)
+            })
+            .unwrap();
+        let generation = stored.attributes_generation(partition_index).unwrap();
+        let new_generation = stored
+            .set_attribute_if(&id, "index", generation, 100u64.into())
+            .unwrap()
+            .unwrap();
+
+        let fs = LocalFileSystem::new(dir.path());
+        assert_ne!(current_database(&fs).unwrap(), header);
+        let reloaded = Database::<f32, _>::load_current_database(fs).unwrap();
+        assert_eq!(
+            reloaded.attributes_generation(partition_index),
+            Some(new_generation),
+        );
+        assert_eq!(
+            reloaded.get_attribute(&id, "index").unwrap().as_deref(),
+            Some(&AttributeValue::from(100u64)),
+        );
+
+        // a database loaded before the update does not overwrite it
+        assert_eq!(
+            stale
+                .set_attribute_if(&id, "index", generation, 200u64.into())
+                .unwrap(),
+            None,
+        );
+        let reloaded = Database::<f32, _>::load_current_database(
+            LocalFileSystem::new(dir.path()),
+        ).unwrap();
+        assert_eq!(
+            reloaded.get_attribute(&id, "index").unwrap().as_deref(),
+            Some(&AttributeValue::from(100u64)),
+        );
+    }
+
+    #[test]
+    fn stored_attribute_update_should_not_leave_new_log_as_garbage() {
+        use crate::db::gc::find_garbage;
+
+        let mut db = build_database(100, 4);
+        for i in 0..db.num_vectors() {
+            db.set_attribute_at(i, ("index", i as u64)).unwrap();
+        }
+        let (dir, header) = store_database(&db, &SerializeOptions::new());
+        let stored = load_database(&dir, &header);
+        let fs = LocalFileSystem::new(dir.path());
+        let old_logs = fs.list_files("attributes").unwrap();
+        let id = *db.vector_ids().next().unwrap();
+        let partition_index = (0..stored.num_partitions())
+            .find(|&i| {
+                stored.get_partition(i).unwrap().vector_ids().contains(&id)
+            })
+            .unwrap();
+        let generation = stored.attributes_generation(partition_index).unwrap();
+        stored
+            .set_attribute_if(&id, "index", generation, 100u64.into())
+            .unwrap()
+            .unwrap();
+        let new_logs: Vec<String> = fs.list_files("attributes")
+            .unwrap()
+            .into_iter()
+            .filter(|path| !old_logs.contains(path))
+            .collect();
+        assert_eq!(new_logs.len(), 1);
+
+        // the previous header still refers to the previous log
+        assert!(find_garbage(&fs, &[&header]).unwrap().is_empty());
+        // only the replaced log is garbage once the previous header is gone
+        let current = current_database(&fs).unwrap();
+        let garbage = find_garbage(&fs, &[&current]).unwrap();
+        assert_eq!(garbage.len(), 1);
+        assert!(old_logs.contains(&garbage[0]));
+        assert!(!garbage.contains(&new_logs[0]));
+    }
+
+    #[test]
+    fn stored_partitions_can_be_held_at_the_same_time() {
+        let (dir, header) = build_and_store(100, 4);
+        let stored = load_database(&dir, &header);
+        let first = stored.get_partition(0).unwrap();
+        let second = stored.get_partition(1).unwrap();
+        assert!(first.vector_ids().iter().all(|id| {
+            !second.vector_ids().contains(id)
+        }));
+        assert!(Arc::ptr_eq(&first, &stored.get_partition(0).unwrap()));
+
+        // a held partition survives eviction
+        let stored = Database::<f32, _>::load_database_with_options(
+            LocalFileSystem::new(dir.path()),
+            &header,
+            OpenOptions::new().with_cache_budget(0),
+        ).unwrap();
+        let first = stored.get_partition(0).unwrap();
+        let vector_ids = first.vector_ids().to_vec();
+        let second = stored.get_partition(1).unwrap();
+        assert_eq!(first.vector_ids(), &vector_ids[..]);
+        assert!(!Arc::ptr_eq(&first, &stored.get_partition(0).unwrap()));
+        assert!(!second.vector_ids().is_empty());
+    }
+
+    #[test]
+    fn stored_database_should_follow_open_options() {
+        let (dir, header) = build_and_store(100, 4);
+        let load = |options: OpenOptions| {
+            Database::<f32, _>::load_database_with_options(
+                LocalFileSystem::new(dir.path()),
+                &header,
+                options,
+            )
+        };
+
+        // partitions are evicted to fit in the cache budget
+        let count_partition_loads = |options: OpenOptions| {
+            let num_loads = Arc::new(Mutex::new(0));
+            let db = load(options).unwrap().with_load_event_handler({
+                let num_loads = num_loads.clone();
+                move |event| {
+                    if matches!(
+                        event,
+                        LoadEvent::FinishedFile(FileKind::Partition),
+                    ) {
+                        *num_loads.lock().unwrap() += 1;
+                    }
+                }
+            });
+            for _ in 0..2 {
+                db.query(
+                    &[0.0f32; 4][..],
+                    1.try_into().unwrap(),
+                    2.try_into().unwrap(),
+                ).unwrap();
+            }
+            let num_loads = *num_loads.lock().unwrap();
+            num_loads
+        };
+        assert_eq!(count_partition_loads(OpenOptions::new()), 2);
+        assert_eq!(
+            count_partition_loads(OpenOptions::new().with_cache_budget(0)),
+            4,
+        );
+
+        // centroids and codebooks are loaded on open
+        let lazy = load(OpenOptions::new()).unwrap();
+        let eager = load(OpenOptions::new().with_eager_loading(true)).unwrap();
+        assert!(eager.estimated_memory_bytes() > lazy.estimated_memory_bytes());
+
+        // appends an unknown field to a codebook to break its hash
+        let codebook = std::fs::read_dir(dir.path().join("codebooks"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(codebook)
+            .unwrap()
+            .write_all(&[0xC0, 0x3E, 0x01])
+            .unwrap();
+        let eager = OpenOptions::new().with_eager_loading(true);
+        assert!(load(eager).is_err());
+        assert!(load(eager.with_verification(false)).is_ok());
+    }
+
+    #[test]
+    fn stored_database_should_skip_damaged_files_in_lenient_mode() {
+        let (dir, header) = build_and_store(100, 4);
+        let load = |options: OpenOptions| {
+            Database::<f32, _>::load_database_with_options(
+                LocalFileSystem::new(dir.path()),
+                &header,
+                options,
+            ).unwrap()
+        };
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+
+        // corrupts a partition and removes all the attributes logs
+        let strict = load(OpenOptions::new());
+        let partition = dir.path().join(format!(
+            "partitions/{}.binpb",
+            strict.get_partition_id(0).unwrap(),
+        ));
+        let mut contents = std::fs::read(&partition).unwrap();
+        *contents.last_mut().unwrap() ^= 1;
+        std::fs::write(&partition, contents).unwrap();
+        std::fs::remove_dir_all(dir.path().join("attributes")).unwrap();
+        assert!(strict
+            .query(&query[..], 100.try_into().unwrap(), 2.try_into().unwrap())
+            .is_err());
+
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let lenient = load(
+            OpenOptions::new().with_validation_mode(ValidationMode::Lenient),
+        ).with_load_event_handler({
+            let skipped = skipped.clone();
+            move |event| {
+                if let LoadEvent::SkippedFile(kind, _) = event {
+                    skipped.lock().unwrap().push(kind);
+                }
+            }
+        });
+        let results = lenient
+            .query(&query[..], 100.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        let pi = results[0].partition_index;
+        assert!(results.iter().all(|r| r.partition_index == pi));
+        assert_eq!(results.len(), lenient.partition_sizes().unwrap()[pi]);
+        assert!(results[0].get_attribute("datum_id").unwrap().is_none());
+        assert_eq!(
+            *skipped.lock().unwrap(),
+            vec![FileKind::Partition, FileKind::AttributesLog],
+        );
+    }
+
+    #[test]
+    fn stored_database_should_reject_message_larger_than_maximum() {
+        let (dir, header) = build_and_store(100, 4);
+        let load = |max_message_size: usize| {
+            Database::<f32, _>::load_database_with_options(
+                LocalFileSystem::new(dir.path()),
+                &header,
+                OpenOptions::new().with_max_message_size(max_message_size),
+            )
+        };
+        assert!(matches!(load(16), Err(Error::InvalidData(_))));
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        load(1024 * 1024)
+            .unwrap()
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn stored_database_should_be_loaded_from_read_only_file_system() {
+        // File system that can only be read.
+        struct ReadOnlyFileSystem(LocalFileSystem);
+
+        impl ReadFileSystem for ReadOnlyFileSystem {
+            type HashedFileIn = LocalHashedFileIn;
+
+            fn open_hashed_file(
+                &self,
+                path: impl AsRef<str>,
+            ) -> Result<Self::HashedFileIn, Error> {
+                self.0.open_hashed_file(path)
+            }
+        }
+
+        let (dir, header) = build_and_store(100, 4);
+        let fs = ReadOnlyFileSystem(LocalFileSystem::new(dir.path()));
+        let db = Database::<f32, _>::load_database(fs, &header).unwrap();
+        let v = [1.0f32, -1.0, 0.5, 0.0];
+        let results = db
+            .query(&v[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        assert_eq!(results.len(), 5);
+        assert!(results[0].get_attribute("missing").unwrap().is_none());
+    }
+}
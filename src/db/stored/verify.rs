@@ -0,0 +1,285 @@
+//! Verification of stored databases.
+
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::db::{AttributeTable, FileKind, OpenOptions};
+use crate::db::proto::replay_attributes_log;
+use crate::error::Error;
+use crate::io::ReadFileSystem;
+use crate::protos::database::AttributesLog as ProtosAttributesLog;
+use crate::vector::EncodedVectorSet;
+
+use super::{
+    Database,
+    LoadCodebook,
+    LoadDatabase,
+    LoadPartition,
+    LoadPartitionCentroids,
+    LoadResidues,
+    PROTOBUF_EXTENSION,
+};
+
+/// Report of [`verify_database`].
+#[derive(Debug, Default)]
+pub struct VerificationReport {
+    /// Number of files checked including the database header.
+    pub num_files: usize,
+    /// Number of vectors in the partitions that have been loaded.
+    pub num_vectors: usize,
+    /// Problems found in the files.
+    pub problems: Vec<Problem>,
+}
+
+impl VerificationReport {
+    /// Returns if no problem has been found.
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    // Records a problem with a file unless a given result is successful.
+    fn check<R>(
+        &mut self,
+        kind: FileKind,
+        path: &str,
+        result: Result<R, Error>,
+    ) -> Option<R> {
+        self.num_files += 1;
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.problems.push(Problem {
+                    kind,
+                    path: path.to_string(),
+                    error,
+                });
+                None
+            },
+        }
+    }
+}
+
+/// Problem found in a file of a stored database.
+#[derive(Debug)]
+pub struct Problem {
+    /// Kind of the file.
+    pub kind: FileKind,
+    /// Path to the file.
+    pub path: String,
+    /// What is wrong with the file.
+    pub error: Error,
+}
+
+/// Verifies every file of a stored database.
+///
+/// Opens every file the database header at `path` refers to and checks its
+/// hash. Also cross-checks the files against the header and each other:
+/// - the numbers and sizes of the partition centroids and codebooks
+/// - the numbers of vectors in the partitions
+/// - codes of encoded vectors are in the codebooks
+/// - vector IDs are unique across the partitions
+/// - attributes logs belong to their partitions and refer to no other
+///   vectors
+/// - residues match their partitions
+///
+/// Problems are collected in the report instead of failing at the first
+/// one, so that a single run tells every damaged file.
+///
+/// Fails if the database header cannot be loaded.
+pub fn verify_database<FS, P>(
+    fs: FS,
+    path: P,
+) -> Result<VerificationReport, Error>
+where
+    FS: ReadFileSystem,
+    P: AsRef<str>,
+{
+    let db = Database::<f32, FS>::load_database_with_options(
+        fs,
+        path,
+        OpenOptions::new(),
+    )?;
+    let mut report = VerificationReport {
+        num_files: 1,
+        ..VerificationReport::default()
+    };
+    report.check(
+        FileKind::PartitionCentroids,
+        &format!(
+            "partitions/{}.{}",
+            db.partition_centroids_id,
+            PROTOBUF_EXTENSION,
+        ),
+        db.load_partition_centroids(),
+    );
+    for (i, id) in db.codebook_ids.iter().enumerate() {
+        report.check(
+            FileKind::Codebook,
+            &format!("codebooks/{}.{}", id, PROTOBUF_EXTENSION),
+            db.load_codebook(i),
+        );
+    }
+    let mut all_vector_ids: HashSet<Uuid> = HashSet::new();
+    let mut partitions = Vec::with_capacity(db.num_partitions());
+    for (pi, id) in db.partition_ids.iter().enumerate() {
+        let path = format!("partitions/{}.{}", id, PROTOBUF_EXTENSION);
+        let result = db.load_partition(pi).and_then(|partition| {
+            check_codes(partition.encoded_vectors(), db.num_codes())?;
+            for vector_id in partition.vector_ids.iter() {
+                if !all_vector_ids.insert(*vector_id) {
+                    return Err(Error::InvalidData(format!(
+                        "duplicate vector ID: {}",
+                        vector_id,
+                    )));
+                }
+            }
+            Ok(partition)
+        });
+        let partition = report.check(FileKind::Partition, &path, result);
+        if let Some(partition) = partition.as_ref() {
+            report.num_vectors += partition.num_vectors();
+        }
+        partitions.push(partition);
+    }
+    let attributes_log_ids = db.attributes_log_ids.borrow().clone();
+    for (pi, id) in attributes_log_ids.iter().enumerate() {
+        let path = format!("attributes/{}.{}", id, PROTOBUF_EXTENSION);
+        let result = db
+            .read_file(FileKind::AttributesLog, path.clone())
+            .and_then(|attributes_log: ProtosAttributesLog| {
+                if attributes_log.partition_id != db.partition_ids[pi] {
+                    return Err(Error::InvalidData(format!(
+                        "inconsistent partition IDs: {} vs {}",
+                        attributes_log.partition_id,
+                        db.partition_ids[pi],
+                    )));
+                }
+                let mut attribute_table = AttributeTable::new();
+                replay_attributes_log(
+                    attributes_log,
+                    pi,
+                    &db.attribute_names,
+                    &mut attribute_table,
+                )?;
+                // vector IDs are unknown if the partition is damaged
+                if let Some(partition) = partitions[pi].as_ref() {
+                    let vector_ids: HashSet<&Uuid> =
+                        partition.vector_ids.iter().collect();
+                    for vector_id in attribute_table.keys() {
+                        if !vector_ids.contains(vector_id) {
+                            return Err(Error::InvalidData(format!(
+                                "no such vector in partition: {}",
+                                vector_id,
+                            )));
+                        }
+                    }
+                }
+                Ok(())
+            });
+        report.check(FileKind::AttributesLog, &path, result);
+    }
+    for (pi, id) in db.residues_ids.iter().enumerate() {
+        let path = format!("residues/{}.{}", id, PROTOBUF_EXTENSION);
+        let result = db.load_residues(pi).and_then(|residues| {
+            if let Some(partition) = partitions[pi].as_ref() {
+                if residues.len() != partition.num_vectors() {
+                    return Err(Error::InvalidData(format!(
+                        "partition has {} vectors but {} residues",
+                        partition.num_vectors(),
+                        residues.len(),
+                    )));
+                }
+            }
+            Ok(())
+        });
+        report.check(FileKind::Residues, &path, result);
+    }
+    Ok(report)
+}
+
+// Checks if all the codes of encoded vectors are less than a given number
+// of codes.
+fn check_codes(
+    encoded_vectors: &EncodedVectorSet,
+    num_codes: usize,
+) -> Result<(), Error> {
+    for i in 0..encoded_vectors.len() {
+        if let Some(code) = (0..encoded_vectors.vector_size())
+            .map(|di| encoded_vectors.code(i, di))
+            .find(|&code| code >= num_codes)
+        {
+            return Err(Error::InvalidData(format!(
+                "code {} of vector {} exceeds the number of codes {}",
+                code,
+                i,
+                num_codes,
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::DatabaseBuilder;
+    use crate::db::build::proto::serialize_database;
+    use crate::io::LocalFileSystem;
+    use crate::testing::SyntheticDatasetBuilder;
+
+    #[test]
+    fn verify_database_should_report_every_damaged_file() {
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        serialize_database(&db, &mut LocalFileSystem::new(dir.path()))
+            .unwrap();
+        let path = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.is_file())
+            .unwrap();
+        let path = path.file_name().unwrap().to_str().unwrap();
+        let report =
+            verify_database(LocalFileSystem::new(dir.path()), path).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(report.num_files, 1 + 1 + 2 + 2 + 2);
+        assert_eq!(report.num_vectors, 100);
+
+        // corrupts a codebook and removes an attributes log
+        let codebook = std::fs::read_dir(dir.path().join("codebooks"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let mut contents = std::fs::read(&codebook).unwrap();
+        *contents.last_mut().unwrap() ^= 1;
+        std::fs::write(&codebook, contents).unwrap();
+        let attributes_log = std::fs::read_dir(dir.path().join("attributes"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        std::fs::remove_file(attributes_log).unwrap();
+        let report =
+            verify_database(LocalFileSystem::new(dir.path()), path).unwrap();
+        assert!(!report.is_healthy());
+        let kinds: Vec<FileKind> =
+            report.problems.iter().map(|p| p.kind).collect();
+        assert_eq!(kinds, vec![FileKind::Codebook, FileKind::AttributesLog]);
+        assert_eq!(report.num_vectors, 100);
+    }
+}
@@ -0,0 +1,159 @@
+//! Distance functions.
+
+use crate::kmeans::Scalar;
+use crate::linalg::norm2;
+
+/// Distance between two vectors.
+///
+/// A smaller value means closer vectors.
+/// Must not be negative.
+pub trait Distance<T> {
+    /// Calculates the distance between given two vectors.
+    ///
+    /// Panics if `xs` and `ys` have different lengths.
+    fn distance(&self, xs: &[T], ys: &[T]) -> T;
+
+    /// Returns how a centroid is calculated from cluster members.
+    ///
+    /// [`CentroidKind::Mean`] by default.
+    fn centroid_kind(&self) -> CentroidKind {
+        CentroidKind::Mean
+    }
+
+    /// Adjusts a centroid calculated from cluster members.
+    ///
+    /// Does nothing by default.
+    fn normalize_centroid(&self, _centroid: &mut [T]) {}
+}
+
+/// How a centroid is calculated from cluster members.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CentroidKind {
+    /// Mean of the members, which minimizes the sum of squared Euclidean
+    /// distances.
+    #[default]
+    Mean,
+    /// Coordinate-wise median of the members, which minimizes the sum of
+    /// Manhattan distances (k-medians).
+    ///
+    /// The mean of the middle two values for an even number of members.
+    Median,
+}
+
+/// Squared Euclidean (L2) distance.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SquaredL2;
+
+impl<T> Distance<T> for SquaredL2
+where
+    T: Scalar,
+{
+    fn distance(&self, xs: &[T], ys: &[T]) -> T {
+        T::squared_distance(xs, ys)
+    }
+}
+
+/// Cosine distance; i.e., one minus the cosine similarity.
+///
+/// Centroids are normalized to unit length (spherical k-means).
+/// A zero vector is regarded as orthogonal to any vector.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cosine;
+
+impl<T> Distance<T> for Cosine
+where
+    T: Scalar,
+{
+    fn distance(&self, xs: &[T], ys: &[T]) -> T {
+        let norms = norm2(xs) * norm2(ys);
+        if norms == T::zero() {
+            return T::one();
+        }
+        let distance = T::one() - T::dot(xs, ys) / norms;
+        // cancels out a rounding error
+        if distance < T::zero() {
+            T::zero()
+        } else {
+            distance
+        }
+    }
+
+    fn normalize_centroid(&self, centroid: &mut [T]) {
+        let norm = norm2(centroid);
+        if norm != T::zero() {
+            let scale = T::one() / norm;
+            centroid.iter_mut().for_each(|x| *x *= scale);
+        }
+    }
+}
+
+/// Manhattan (L1) distance.
+///
+/// Centroids are the coordinate-wise medians of cluster members.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct L1;
+
+impl<T> Distance<T> for L1
+where
+    T: Scalar,
+{
+    fn distance(&self, xs: &[T], ys: &[T]) -> T {
+        assert_eq!(xs.len(), ys.len());
+        let mut acc = T::zero();
+        for (x, y) in xs.iter().zip(ys) {
+            acc += (*x - *y).abs();
+        }
+        acc
+    }
+
+    fn centroid_kind(&self) -> CentroidKind {
+        CentroidKind::Median
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn squared_l2_should_calculate_squared_euclidean_distance() {
+        let xs: &[f32] = &[1.0, 2.0, 3.0];
+        let ys: &[f32] = &[2.0, 4.0, 0.0];
+        assert_eq!(SquaredL2.distance(xs, ys), 14.0);
+    }
+
+    #[test]
+    fn cosine_of_parallel_vectors_should_be_zero() {
+        let xs: &[f32] = &[1.0, 2.0, 3.0];
+        let ys: &[f32] = &[2.0, 4.0, 6.0];
+        assert!(Cosine.distance(xs, ys) < 1e-6);
+    }
+
+    #[test]
+    fn cosine_of_orthogonal_vectors_should_be_one() {
+        let xs: &[f32] = &[1.0, 0.0];
+        let ys: &[f32] = &[0.0, 3.0];
+        assert_eq!(Cosine.distance(xs, ys), 1.0);
+    }
+
+    #[test]
+    fn cosine_of_zero_vector_should_be_one() {
+        let xs: &[f32] = &[0.0, 0.0];
+        let ys: &[f32] = &[1.0, 3.0];
+        assert_eq!(Cosine.distance(xs, ys), 1.0);
+    }
+
+    #[test]
+    fn cosine_should_normalize_centroid() {
+        let mut centroid: Vec<f32> = vec![3.0, 4.0];
+        Distance::<f32>::normalize_centroid(&Cosine, &mut centroid);
+        assert_eq!(centroid, vec![0.6, 0.8]);
+    }
+
+    #[test]
+    fn l1_should_calculate_manhattan_distance() {
+        let xs: &[f32] = &[1.0, 2.0, 3.0];
+        let ys: &[f32] = &[2.0, 4.0, 0.0];
+        assert_eq!(L1.distance(xs, ys), 6.0);
+    }
+}
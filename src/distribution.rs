@@ -13,12 +13,17 @@ use crate::numbers::Zero;
 /// Straightforward weighted distribution.
 ///
 /// Unlike [`rand::distributions::weighted::WeightedIndex`], this distribution
-/// won't precalculate the cumulative weights, but will do it on the fly.
+/// can update individual weights.
+/// Cumulative weights are maintained in a Fenwick (binary indexed) tree, so
+/// both sampling and updating a weight take O(log n) time.
 pub struct WeightedIndex<X>
 where
     X: SampleUniform + PartialOrd,
 {
     weights: Vec<X>,
+    // Fenwick tree of the weights; `tree[i]` is the sum of
+    // `weights[(i - lowbit(i))..i]`, and `tree[0]` is unused.
+    tree: Vec<X>,
     total_weight: X,
     weight_distribution: X::Sampler,
 }
@@ -34,20 +39,33 @@ where
     /// or if the sum of all weights is zero.
     pub fn new(weights: Vec<X>) -> Result<Self, Error> {
         if weights.is_empty() {
-            return Err(Error::InvalidArgs(format!("weights is empty")));
+            return Err(Error::InvalidArgs("weights is empty".to_string()));
         }
         let mn = min(&weights[..]).unwrap();
         if mn < X::zero() {
             return Err(Error::InvalidArgs(
-                format!("weights contains negagive"),
+                "weights contains negagive".to_string(),
             ));
         }
         let total_weight = sum(&weights[..]);
         if total_weight <= X::zero() {
-            return Err(Error::InvalidArgs(format!("total weight is zero")));
+            return Err(Error::InvalidArgs("total weight is zero".to_string()));
+        }
+        // builds the Fenwick tree in O(n)
+        let n = weights.len();
+        let mut tree: Vec<X> = Vec::with_capacity(n + 1);
+        tree.push(X::zero());
+        tree.extend_from_slice(&weights[..]);
+        for i in 1..=n {
+            let parent = i + lowbit(i);
+            if parent <= n {
+                let w = tree[i];
+                tree[parent] += w;
+            }
         }
         Ok(Self {
             weights,
+            tree,
             total_weight,
             weight_distribution: X::Sampler::new(X::zero(), total_weight),
         })
@@ -65,11 +83,13 @@ where
         let mut new_total_weight = self.total_weight;
         for &(i, &weight) in new_weights {
             if i >= self.weights.len() {
-                return Err(Error::InvalidArgs(format!("index out of range")));
+                return Err(Error::InvalidArgs(
+                    "index out of range".to_string(),
+                ));
             }
             if weight < X::zero() {
                 return Err(Error::InvalidArgs(
-                    format!("new weights contains negative"),
+                    "new weights contains negative".to_string(),
                 ));
             }
             new_total_weight -= self.weights[i];
@@ -77,11 +97,20 @@ where
         }
         if new_total_weight <= X::zero() {
             return Err(Error::InvalidArgs(
-                format!("total weight becomes zero"),
+                "total weight becomes zero".to_string(),
             ));
         }
         // safely updates the weights
-        new_weights.iter().for_each(|(i, weight)| self.weights[*i] = **weight);
+        for &(i, &weight) in new_weights {
+            let mut delta = weight;
+            delta -= self.weights[i];
+            self.weights[i] = weight;
+            let mut j = i + 1;
+            while j < self.tree.len() {
+                self.tree[j] += delta;
+                j += lowbit(j);
+            }
+        }
         self.total_weight = new_total_weight;
         self.weight_distribution = X::Sampler::new(
             X::zero(),
@@ -98,26 +127,53 @@ where
 
 impl<X> Distribution<usize> for WeightedIndex<X>
 where
-    X: SampleUniform + Zero + AddAssign + PartialOrd + Copy,
+    X: SampleUniform + Zero + AddAssign + SubAssign + PartialOrd + Copy,
 {
     /// Samples a value from the distribution.
     fn sample<R>(&self, rng: &mut R) -> usize
     where
         R: Rng + ?Sized,
     {
-        let sample = self.weight_distribution.sample(rng);
-        let mut cum_weight = X::zero();
-        let mut last_non_zero_index: Option<usize> = None;
-        for i in 0..self.weights.len() {
-            if self.weights[i] > X::zero() {
-                last_non_zero_index = Some(i);
-                cum_weight += self.weights[i];
-                if cum_weight > sample {
-                    break;
-                }
+        let mut remaining = self.weight_distribution.sample(rng);
+        // descends the Fenwick tree to locate the first index whose
+        // cumulative weight exceeds the sample
+        let n = self.weights.len();
+        let mut pos = 0;
+        let mut step = highest_power_of_two(n);
+        while step > 0 {
+            let next = pos + step;
+            if next <= n && self.tree[next] <= remaining {
+                pos = next;
+                remaining -= self.tree[next];
             }
+            step >>= 1;
+        }
+        // rounding errors in the cumulative weights may end up with a zero
+        // weight or the end
+        if pos < n && self.weights[pos] > X::zero() {
+            return pos;
         }
-        last_non_zero_index.unwrap()
+        (0..pos.min(n))
+            .rev()
+            .chain(pos.min(n)..n)
+            .find(|&i| self.weights[i] > X::zero())
+            .unwrap()
+    }
+}
+
+// Returns the lowest set bit of a given number.
+const fn lowbit(i: usize) -> usize {
+    i & i.wrapping_neg()
+}
+
+// Returns the highest power of two less than or equal to a given number.
+//
+// Returns zero if `n` is zero.
+const fn highest_power_of_two(n: usize) -> usize {
+    if n == 0 {
+        0
+    } else {
+        1 << (usize::BITS - 1 - n.leading_zeros())
     }
 }
 
@@ -350,6 +406,24 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn weighted_index_should_sample_many_indices_according_to_weights() {
+        let weights: Vec<Number> = (0..37)
+            .map(|i| Number(if i % 3 == 0 { 0.0 } else { 1.0 }))
+            .collect();
+        let weighted_index = WeightedIndex::new(weights).unwrap();
+        let rng = rand::thread_rng();
+        let indices = rng
+            .sample_iter(&weighted_index)
+            .take(48)
+            .collect::<Vec<usize>>();
+        let expected: Vec<usize> = (0..37)
+            .filter(|i| i % 3 != 0)
+            .flat_map(|i| [i, i])
+            .collect();
+        assert_eq!(indices, expected);
+    }
+
     #[test]
     fn weighted_index_should_keep_total_weight_at_failed_update() {
         let weights = vec![Number(1.0), Number(2.0), Number(3.0)];
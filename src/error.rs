@@ -11,6 +11,8 @@ pub enum Error {
     InvalidContext(String),
     /// Verification has failed.
     VerificationFailure(String),
+    /// Operation has been cancelled by an event handler.
+    Cancelled(String),
     /// I/O error.
     IOError(std::io::Error),
     /// Error on `protobuf`.
@@ -25,7 +27,8 @@ impl std::fmt::Display for Error {
             Self::InvalidArgs(s) |
             Self::InvalidData(s) |
             Self::InvalidContext(s) |
-            Self::VerificationFailure(s) => write!(f, "{}", s),
+            Self::VerificationFailure(s) |
+            Self::Cancelled(s) => write!(f, "{}", s),
             Self::IOError(e) => write!(f, "I/O error: {}", e),
             Self::ProtobufError(e) => write!(f, "Protobuf error: {}", e),
         }
@@ -52,6 +55,23 @@ impl From<tempfile::PersistError> for Error {
 
 impl From<tempfile::PathPersistError> for Error {
     fn from(e: tempfile::PathPersistError) -> Self {
-        Self::IOError(std::io::Error::new(std::io::ErrorKind::Other, e))
+        Self::IOError(std::io::Error::other(e))
+    }
+}
+
+#[cfg(feature = "opendal")]
+impl From<opendal::Error> for Error {
+    fn from(e: opendal::Error) -> Self {
+        Self::IOError(e.into())
+    }
+}
+
+#[cfg(feature = "zip")]
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        match e {
+            zip::result::ZipError::Io(e) => Self::IOError(e),
+            e => Self::InvalidData(format!("invalid zip archive: {}", e)),
+        }
     }
 }
@@ -1,26 +1,141 @@
 //! IO utilities.
 
-use base64::{
-    Engine,
-    engine::general_purpose::{URL_SAFE_NO_PAD as base64_engine},
-};
-use flate2::Compression;
-use flate2::read::ZlibDecoder;
-use flate2::write::ZlibEncoder;
 use std::ffi::OsStr;
-use std::io::{Read, Write};
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use tempfile::NamedTempFile;
 
 use crate::error::Error;
 
-/// Abstracts a file system.
-pub trait FileSystem {
-    /// File that calculates the hash of its contents.
-    type HashedFileOut: HashedFileOut;
+use self::codec::{
+    Codec,
+    DEFAULT_COMPRESSION_LEVEL,
+    Decoder,
+    Encoder,
+};
+use self::hash::{HashAlgorithm, Hasher};
+
+#[cfg(any(feature = "tar", feature = "zip"))]
+pub mod archive;
+pub mod cache;
+pub mod codec;
+#[cfg(feature = "mmap")]
+pub mod container;
+pub mod encrypt;
+pub mod hash;
+pub mod merkle;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "opendal")]
+pub mod opendal;
+pub mod retry;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+/// Default number of bytes read ahead from a local file.
+pub const DEFAULT_READAHEAD_SIZE: usize = 64 * 1024;
+
+/// Abstracts a file system that can be read and written.
+///
+/// Implemented for every type that implements both [`ReadFileSystem`] and
+/// [`WriteFileSystem`].
+pub trait FileSystem: ReadFileSystem + WriteFileSystem {}
+
+impl<FS> FileSystem for FS where FS: ReadFileSystem + WriteFileSystem {}
+
+/// Abstracts a file system that can be read.
+///
+/// A read-only backend; e.g., one over HTTP, implements only this trait, and
+/// can still load a [`Database`](crate::db::stored::Database).
+pub trait ReadFileSystem {
     /// File whose contents can be verified with the hash.
     type HashedFileIn: HashedFileIn;
 
+    /// Opens a file whose contents can be verified with a hash.
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error>;
+
+    /// Opens a file whose contents can be verified with a hash of a given
+    /// algorithm.
+    ///
+    /// The default implementation supports only [`HashAlgorithm::Sha256`],
+    /// and fails with `Error::InvalidContext` for the others.
+    fn open_hashed_file_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        algorithm.check_sha256()?;
+        self.open_hashed_file(path)
+    }
+
+    /// Opens a hashed file that the caller is not going to verify.
+    ///
+    /// An implementation may skip hashing the contents, in which case
+    /// [`HashedFileIn::verify`] of the file fails with
+    /// `Error::InvalidContext`.
+    ///
+    /// The default implementation calls
+    /// [`open_hashed_file`](Self::open_hashed_file).
+    fn open_hashed_file_unverified(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        self.open_hashed_file(path)
+    }
+
+    /// Lists files under a given directory.
+    ///
+    /// `prefix` is the path of a directory relative to the root of the file
+    /// system, and an empty one lists every file. Returns paths relative to
+    /// the root, including those in subdirectories, in no particular order.
+    /// A missing directory has no files.
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot list files: {}",
+            prefix.as_ref(),
+        )))
+    }
+
+    /// Reads a pointer; i.e., a small mutable file that names another file.
+    ///
+    /// Unlike hashed files, a pointer is updated in place; e.g.,
+    /// [`CURRENT`](crate::db::manifest::CURRENT) names the database header
+    /// to load. `None` if the pointer does not exist.
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot read pointers: {}",
+            path.as_ref(),
+        )))
+    }
+
+    /// Opens a compressed file whose contents can be verified with a hash.
+    fn open_compressed_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<CompressedHashedFileIn<Self::HashedFileIn>, Error> {
+        let file = self.open_hashed_file(path)?;
+        Ok(CompressedHashedFileIn::new(file))
+    }
+}
+
+/// Abstracts a file system that can be written.
+pub trait WriteFileSystem {
+    /// File that calculates the hash of its contents.
+    type HashedFileOut: HashedFileOut;
+
     /// Creates a file that calculates the hash of its contents.
     fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error>;
 
@@ -30,11 +145,54 @@ pub trait FileSystem {
         path: impl AsRef<str>,
     ) -> Result<Self::HashedFileOut, Error>;
 
-    /// Opens a file whose contents can be verified with a hash.
-    fn open_hashed_file(
+    /// Creates a hashed file in a given directory, which hashes its contents
+    /// with a given algorithm.
+    ///
+    /// An empty `path` creates the file at the root of the file system.
+    ///
+    /// The default implementation supports only [`HashAlgorithm::Sha256`],
+    /// and fails with `Error::InvalidContext` for the others.
+    fn create_hashed_file_in_with(
         &self,
         path: impl AsRef<str>,
-    ) -> Result<Self::HashedFileIn, Error>;
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        algorithm.check_sha256()?;
+        if path.as_ref().is_empty() {
+            self.create_hashed_file()
+        } else {
+            self.create_hashed_file_in(path)
+        }
+    }
+
+    /// Deletes a file.
+    ///
+    /// Deleting a missing file is not an error.
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    fn delete(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot delete files: {}",
+            path.as_ref(),
+        )))
+    }
+
+    /// Replaces a pointer with one naming a given target atomically.
+    ///
+    /// A reader of the pointer sees either the previous or the new target,
+    /// never a partial one.
+    ///
+    /// The default implementation fails with `Error::InvalidContext`.
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        _target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        Err(Error::InvalidContext(format!(
+            "file system cannot write pointers: {}",
+            path.as_ref(),
+        )))
+    }
 
     /// Creates a compressed file that calculates the hash of its contents.
     fn create_compressed_hashed_file(
@@ -52,15 +210,6 @@ pub trait FileSystem {
         let file = self.create_hashed_file_in(path)?;
         Ok(CompressedHashedFileOut::new(file))
     }
-
-    /// Opens a compressed file whose contents can be verified with a hash.
-    fn open_compressed_hashed_file(
-        &self,
-        path: impl AsRef<str>,
-    ) -> Result<CompressedHashedFileIn<Self::HashedFileIn>, Error> {
-        let file = self.open_hashed_file(path)?;
-        Ok(CompressedHashedFileIn::new(file))
-    }
 }
 
 /// File whose name will be the hash of its contents.
@@ -71,7 +220,8 @@ pub trait HashedFileOut: Write {
     /// You should flush the stream before calling this function.
     ///
     /// Returns the encoded hash value that is supposed to be a URS-safe Base64
-    /// encoded SHA256 digest.
+    /// encoded SHA256 digest, unless the file was created with another
+    /// [`HashAlgorithm`].
     fn persist(self, extension: impl AsRef<str>) -> Result<String, Error>;
 }
 
@@ -84,6 +234,154 @@ pub trait HashedFileIn: Read {
     ///
     /// File name is supposed to be a Base64 encoded URL-safe SHA256 digest.
     fn verify(self) -> Result<(), Error>;
+
+    /// Returns the size of the file in bytes.
+    ///
+    /// `None` if unknown, which is the default.
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the remaining contents of the file if they are accessible in
+    /// place; e.g., memory-mapped.
+    ///
+    /// Returned contents are regarded as read, so that a caller can parse
+    /// them without copying them into a buffer.
+    ///
+    /// `None` if the contents have to be read, which is the default.
+    fn read_in_place(&mut self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Progress of reading or writing a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoProgress {
+    /// Number of bytes processed so far.
+    pub processed: u64,
+    /// Total number of bytes. `None` if unknown.
+    pub total: Option<u64>,
+}
+
+/// Hashed file that reports the progress of writing.
+///
+/// Calls a given function with the progress every time bytes are written.
+pub struct ProgressHashedFileOut<W, F> {
+    // Wrapped file.
+    file: W,
+    progress: IoProgress,
+    // Function called with the progress.
+    on_progress: F,
+}
+
+impl<W, F> ProgressHashedFileOut<W, F>
+where
+    W: HashedFileOut,
+    F: FnMut(IoProgress),
+{
+    /// Wraps a given file.
+    ///
+    /// `total` is the number of bytes supposed to be written if known.
+    pub fn new(file: W, total: Option<u64>, on_progress: F) -> Self {
+        Self {
+            file,
+            progress: IoProgress { processed: 0, total },
+            on_progress,
+        }
+    }
+}
+
+impl<W, F> Write for ProgressHashedFileOut<W, F>
+where
+    W: HashedFileOut,
+    F: FnMut(IoProgress),
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.progress.processed += n as u64;
+        (self.on_progress)(self.progress);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<W, F> HashedFileOut for ProgressHashedFileOut<W, F>
+where
+    W: HashedFileOut,
+    F: FnMut(IoProgress),
+{
+    fn persist(self, extension: impl AsRef<str>) -> Result<String, Error> {
+        self.file.persist(extension)
+    }
+}
+
+/// Hashed file that reports the progress of reading.
+///
+/// Calls a given function with the progress every time bytes are read.
+/// The total is the size of the file if known.
+pub struct ProgressHashedFileIn<R, F> {
+    // Wrapped file.
+    file: R,
+    progress: IoProgress,
+    // Function called with the progress.
+    on_progress: F,
+}
+
+impl<R, F> ProgressHashedFileIn<R, F>
+where
+    R: HashedFileIn,
+    F: FnMut(IoProgress),
+{
+    /// Wraps a given file.
+    pub fn new(file: R, on_progress: F) -> Self {
+        let total = file.size();
+        Self {
+            file,
+            progress: IoProgress { processed: 0, total },
+            on_progress,
+        }
+    }
+}
+
+impl<R, F> Read for ProgressHashedFileIn<R, F>
+where
+    R: HashedFileIn,
+    F: FnMut(IoProgress),
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read(buf)?;
+        if n > 0 {
+            self.progress.processed += n as u64;
+            (self.on_progress)(self.progress);
+        }
+        Ok(n)
+    }
+}
+
+impl<R, F> HashedFileIn for ProgressHashedFileIn<R, F>
+where
+    R: HashedFileIn,
+    F: FnMut(IoProgress),
+{
+    fn verify(self) -> Result<(), Error> {
+        self.file.verify()
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.progress.total
+    }
+
+    fn read_in_place(&mut self) -> Option<&[u8]> {
+        let contents = self.file.read_in_place()?;
+        if !contents.is_empty() {
+            self.progress.processed += contents.len() as u64;
+            (self.on_progress)(self.progress);
+        }
+        Some(contents)
+    }
 }
 
 /// Compressed file that calculates the hash of its contents.
@@ -91,7 +389,7 @@ pub struct CompressedHashedFileOut<W>
 where
     W: std::io::Write,
 {
-    encoder: ZlibEncoder<W>,
+    encoder: Encoder<W>,
 }
 
 impl<W> CompressedHashedFileOut<W>
@@ -101,9 +399,32 @@ where
     /// Writes compressed data to a given [`Write`].
     pub fn new(w: W) -> Self {
         Self {
-            encoder: ZlibEncoder::new(w, Compression::default()),
+            encoder: Encoder::zlib(w, DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// Writes data compressed at a given level to a given [`Write`].
+    ///
+    /// `level` ranges from 0 (no compression) to 9 (best compression).
+    pub fn with_level(w: W, level: u32) -> Self {
+        Self {
+            encoder: Encoder::zlib(w, level),
         }
     }
+
+    /// Writes data compressed with a given codec to a given [`Write`].
+    ///
+    /// `level` ranges from 0 to [`Codec::max_level`]. 0 means no
+    /// compression for [`Codec::Zlib`], and the default level of the other
+    /// codecs.
+    ///
+    /// Fails with `Error::InvalidContext` if `codec` is not available in
+    /// this build, or with `Error::InvalidArgs` if `level` is out of range.
+    pub fn with_codec(w: W, codec: Codec, level: u32) -> Result<Self, Error> {
+        Ok(Self {
+            encoder: Encoder::new(w, codec, level)?,
+        })
+    }
 }
 
 impl<W> Write for CompressedHashedFileOut<W>
@@ -133,7 +454,7 @@ pub struct CompressedHashedFileIn<R>
 where
     R: std::io::Read,
 {
-    decoder: ZlibDecoder<R>,
+    decoder: Decoder<R>,
 }
 
 impl<R> CompressedHashedFileIn<R>
@@ -141,9 +462,11 @@ where
     R: std::io::Read,
 {
     /// Reads compressed data from a given [`Read`].
+    ///
+    /// The codec is detected from the first bytes of the data.
     pub fn new(r: R) -> Self {
         Self {
-            decoder: ZlibDecoder::new(r),
+            decoder: Decoder::new(r),
         }
     }
 }
@@ -164,74 +487,370 @@ where
     fn verify(self) -> Result<(), Error> {
         self.decoder.into_inner().verify()
     }
+
+    fn size(&self) -> Option<u64> {
+        self.decoder.get_ref().size()
+    }
 }
 
 /// File system uses the local file system.
 pub struct LocalFileSystem {
     // Base path.
     base_path: PathBuf,
+    // Number of bytes read ahead from a file.
+    readahead_size: usize,
+    // Directory to create temporary files in. The directory of each file
+    // if `None`.
+    temp_dir: Option<PathBuf>,
+    // Whether persisted files are synced to the storage device.
+    sync_on_persist: bool,
 }
 
 impl LocalFileSystem {
     /// Creates a local file system working under a given base path.
+    ///
+    /// Reads [`DEFAULT_READAHEAD_SIZE`] bytes ahead from a file.
     pub fn new(base_path: impl AsRef<Path>) -> Self {
         Self {
             base_path: base_path.as_ref().to_path_buf(),
+            readahead_size: DEFAULT_READAHEAD_SIZE,
+            temp_dir: None,
+            sync_on_persist: false,
         }
     }
+
+    /// Sets the directory to create temporary files in.
+    ///
+    /// A written file stays in a temporary file until it is persisted, and
+    /// then is renamed into its directory. So the directory must be on the
+    /// same file system as the base path, otherwise persisting fails.
+    ///
+    /// The directory the file is persisted in by default.
+    pub fn with_temp_dir<P>(mut self, dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.temp_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the number of bytes read ahead from a file.
+    ///
+    /// Larger readahead issues fewer system calls while a large file is
+    /// parsed. Zero disables readahead.
+    pub fn with_readahead_size(mut self, readahead_size: usize) -> Self {
+        self.readahead_size = readahead_size;
+        self
+    }
+
+    /// Sets whether persisted files are synced to the storage device.
+    ///
+    /// If `true`, persisting a file syncs its contents before renaming it,
+    /// and then syncs the directory it is renamed into on Unix. Directories
+    /// created for files are synced as well as their parents. So a crash
+    /// right after serializing a database does not leave truncated or
+    /// missing files that fail verification later.
+    ///
+    /// `false` by default, which leaves flushing to the operating system.
+    pub fn with_sync_on_persist(mut self, sync_on_persist: bool) -> Self {
+        self.sync_on_persist = sync_on_persist;
+        self
+    }
+
+    // Creates a file to be persisted under a given path.
+    fn create_hashed_file_at(
+        &self,
+        base_path: PathBuf,
+        algorithm: HashAlgorithm,
+    ) -> Result<LocalHashedFileOut, Error> {
+        let mut file = LocalHashedFileOut::create(
+            base_path,
+            self.temp_dir.as_deref(),
+            self.sync_on_persist,
+        )?;
+        file.hasher = Hasher::new(algorithm)?;
+        Ok(file)
+    }
 }
 
-impl FileSystem for LocalFileSystem {
-    type HashedFileOut = LocalHashedFileOut;
+impl ReadFileSystem for LocalFileSystem {
     type HashedFileIn = LocalHashedFileIn;
 
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        LocalHashedFileIn::open(
+            self.base_path.join(path.as_ref()),
+            self.readahead_size,
+        )
+    }
+
+    fn open_hashed_file_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let mut file = self.open_hashed_file(path)?;
+        file.hasher = Some(Hasher::new(algorithm)?);
+        Ok(file)
+    }
+
+    /// Does not hash the contents.
+    fn open_hashed_file_unverified(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let mut file = self.open_hashed_file(path)?;
+        file.hasher = None;
+        Ok(file)
+    }
+
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        list_local_files(&self.base_path, prefix.as_ref())
+    }
+
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        read_local_pointer(&self.base_path.join(path.as_ref()))
+    }
+}
+
+impl WriteFileSystem for LocalFileSystem {
+    type HashedFileOut = LocalHashedFileOut;
+
     fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
-        LocalHashedFileOut::create(self.base_path.clone())
+        self.create_hashed_file_at(
+            self.base_path.clone(),
+            HashAlgorithm::Sha256,
+        )
     }
 
     fn create_hashed_file_in(
         &self,
         path: impl AsRef<str>,
     ) -> Result<Self::HashedFileOut, Error> {
-        LocalHashedFileOut::create(self.base_path.join(path.as_ref()))
+        self.create_hashed_file_at(
+            self.base_path.join(path.as_ref()),
+            HashAlgorithm::Sha256,
+        )
     }
 
-    fn open_hashed_file(
+    fn create_hashed_file_in_with(
         &self,
         path: impl AsRef<str>,
-    ) -> Result<Self::HashedFileIn, Error> {
-        LocalHashedFileIn::open(self.base_path.join(path.as_ref()))
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        let base_path = self.base_path.join(path.as_ref());
+        self.create_hashed_file_at(base_path, algorithm)
+    }
+
+    fn delete(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        delete_local_file(&self.base_path.join(path.as_ref()))
     }
+
+    /// Writes the target to a temporary file next to the pointer, and
+    /// renames it over the pointer. Syncs them if
+    /// [`with_sync_on_persist`](Self::with_sync_on_persist) is enabled.
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        write_local_pointer(
+            &self.base_path.join(path.as_ref()),
+            target.as_ref(),
+            self.sync_on_persist,
+        )
+    }
+}
+
+// Lists files under a directory relative to a given base path.
+pub(crate) fn list_local_files(
+    base_path: &Path,
+    prefix: &str,
+) -> Result<Vec<String>, Error> {
+    let mut files: Vec<String> = Vec::new();
+    let mut dirs = vec![prefix.trim_matches('/').to_string()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(base_path.join(&dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                Error::InvalidData(format!("non UTF-8 file name: {:?}", name))
+            })?;
+            let path = if dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+// Syncs a directory so that renames in it survive a crash.
+//
+// Directories cannot be opened as files on Windows, where renames are
+// durable once the renamed file is synced.
+pub(crate) fn sync_dir(path: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    std::fs::File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+// Creates a directory and its missing ancestors.
+//
+// If `sync` is `true`, also syncs every newly created directory and the
+// existing ancestor it is created in, so that the new directories survive a
+// crash as well as the files renamed into them.
+pub(crate) fn create_local_dir_all(
+    path: &Path,
+    sync: bool,
+) -> Result<(), Error> {
+    if !sync {
+        std::fs::create_dir_all(path)?;
+        return Ok(());
+    }
+    let mut new_dirs: Vec<&Path> = Vec::new();
+    let mut existing = Some(path);
+    while let Some(dir) = existing.filter(|dir| !dir.as_os_str().is_empty()) {
+        if dir.exists() {
+            break;
+        }
+        new_dirs.push(dir);
+        existing = dir.parent();
+    }
+    if new_dirs.is_empty() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(path)?;
+    for dir in new_dirs.iter() {
+        sync_dir(dir)?;
+    }
+    let top = new_dirs.last().unwrap();
+    match top.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => sync_dir(dir)?,
+        None => sync_dir(Path::new("."))?,
+    }
+    Ok(())
+}
+
+// Deletes a local file unless it is missing.
+pub(crate) fn delete_local_file(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Reads a pointer in a local file. `None` if the file is missing.
+pub(crate) fn read_local_pointer(
+    path: &Path,
+) -> Result<Option<String>, Error> {
+    match std::fs::read(path) {
+        Ok(contents) => parse_pointer(contents).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Parses the contents of a pointer into its target.
+//
+// Surrounding whitespace is ignored so that a pointer may be edited by hand.
+pub(crate) fn parse_pointer(contents: Vec<u8>) -> Result<String, Error> {
+    let target = String::from_utf8(contents).map_err(|_| {
+        Error::InvalidData("pointer must be UTF-8".to_string())
+    })?;
+    Ok(target.trim().to_string())
+}
+
+// Replaces a pointer in a local file by renaming a temporary file in the
+// same directory over it.
+pub(crate) fn write_local_pointer(
+    path: &Path,
+    target: &str,
+    sync: bool,
+) -> Result<(), Error> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    create_local_dir_all(dir, sync)?;
+    let mut tempfile = NamedTempFile::new_in(dir)?;
+    tempfile.write_all(target.as_bytes())?;
+    if sync {
+        tempfile.as_file().sync_all()?;
+    }
+    tempfile.persist(path)?;
+    if sync {
+        sync_dir(dir)?;
+    }
+    Ok(())
 }
 
 /// Writable file in the local file system.
 ///
-/// Created as a temporary file and renamed to the hash of its contents.
+/// Created as a temporary file in the directory to persist it in, unless
+/// another directory is specified to the file system, and renamed to the
+/// hash of its contents.
 pub struct LocalHashedFileOut {
     // Temporary file.
     tempfile: NamedTempFile,
     // Persisted path.
     base_path: PathBuf,
-    // Context to calculate an SHA-256 digest.
-    context: ring::digest::Context,
+    // Hasher of the contents.
+    hasher: Hasher,
+    // Whether the file and its directory are synced when persisted.
+    sync_on_persist: bool,
 }
 
 impl LocalHashedFileOut {
     /// Creates a temporary file to be persisted under a given path.
-    fn create(base_path: PathBuf) -> Result<Self, Error> {
-        let tempfile = NamedTempFile::new()?;
+    ///
+    /// The temporary file is created in `temp_dir` if specified, otherwise
+    /// in `base_path`. Directories created for the file are synced if
+    /// `sync_on_persist` is `true`.
+    fn create(
+        base_path: PathBuf,
+        temp_dir: Option<&Path>,
+        sync_on_persist: bool,
+    ) -> Result<Self, Error> {
+        let tempfile = match temp_dir {
+            Some(temp_dir) => NamedTempFile::new_in(temp_dir)?,
+            None => {
+                create_local_dir_all(&base_path, sync_on_persist)?;
+                NamedTempFile::new_in(&base_path)?
+            },
+        };
         Ok(LocalHashedFileOut {
             tempfile,
             base_path,
-            context: ring::digest::Context::new(&ring::digest::SHA256),
+            hasher: Hasher::sha256(),
+            sync_on_persist,
         })
     }
 }
 
 impl Write for LocalHashedFileOut {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.context.update(buf);
-        self.tempfile.write(buf)
+        let n = self.tempfile.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -242,35 +861,40 @@ impl Write for LocalHashedFileOut {
 impl HashedFileOut for LocalHashedFileOut {
     fn persist(mut self, extension: impl AsRef<str>) -> Result<String, Error> {
         self.flush()?;
-        if !self.base_path.exists() {
-            std::fs::create_dir_all(&self.base_path)?;
-        }
-        let hash = self.context.finish();
-        let hash = base64_engine.encode(&hash);
+        create_local_dir_all(&self.base_path, self.sync_on_persist)?;
+        let hash = self.hasher.finish();
         let path = self.base_path
             .join(&hash)
             .with_extension(extension.as_ref());
+        if self.sync_on_persist {
+            self.tempfile.as_file().sync_all()?;
+        }
         self.tempfile.persist(path)?;
+        if self.sync_on_persist {
+            sync_dir(&self.base_path)?;
+        }
         Ok(hash)
     }
 }
 
 /// Readable file in the local file system.
 pub struct LocalHashedFileIn {
-    file: std::fs::File,
+    file: BufReader<std::fs::File>,
     path: PathBuf,
-    // Context to calculate an SHA-256 digest.
-    context: ring::digest::Context,
+    // Hasher of the contents. `None` if the contents are not hashed.
+    hasher: Option<Hasher>,
 }
 
 impl LocalHashedFileIn {
     /// Opens a file whose name is the hash of its contents.
-    fn open(path: PathBuf) -> Result<Self, Error> {
+    ///
+    /// Reads `readahead_size` bytes ahead from the file.
+    fn open(path: PathBuf, readahead_size: usize) -> Result<Self, Error> {
         let file = std::fs::File::open(&path)?;
         Ok(LocalHashedFileIn {
-            file,
+            file: BufReader::with_capacity(readahead_size, file),
             path,
-            context: ring::digest::Context::new(&ring::digest::SHA256),
+            hasher: Some(Hasher::sha256()),
         })
     }
 }
@@ -278,15 +902,20 @@ impl LocalHashedFileIn {
 impl Read for LocalHashedFileIn {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let n = self.file.read(buf)?;
-        self.context.update(&buf[..n]);
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
         Ok(n)
     }
 }
 
 impl HashedFileIn for LocalHashedFileIn {
     fn verify(self) -> Result<(), Error> {
-        let hash = self.context.finish();
-        let hash = base64_engine.encode(&hash);
+        let hasher = self.hasher.ok_or(Error::InvalidContext(format!(
+            "file was opened without hashing: {}",
+            self.path.display(),
+        )))?;
+        let hash = hasher.finish();
         if hash.as_str() == self.path.file_stem().unwrap_or(OsStr::new("")) {
             Ok(())
         } else {
@@ -297,4 +926,93 @@ impl HashedFileIn for LocalHashedFileIn {
             )))
         }
     }
+
+    fn size(&self) -> Option<u64> {
+        self.file.get_ref().metadata().ok().map(|metadata| metadata.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unverified_local_hashed_file_in_should_not_be_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let mut f = fs.create_hashed_file().unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let path = format!("{}.bin", f.persist("bin").unwrap());
+        let mut f = fs.open_hashed_file_unverified(path).unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"0123456789");
+        assert!(matches!(f.verify(), Err(Error::InvalidContext(_))));
+    }
+
+    #[test]
+    fn local_hashed_file_out_should_be_written_in_temp_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().join("temp");
+        std::fs::create_dir(&temp_dir).unwrap();
+        let count_files = |path: &Path| std::fs::read_dir(path)
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+
+        let fs = LocalFileSystem::new(dir.path().join("db"));
+        let mut f = fs.create_hashed_file_in("data").unwrap();
+        f.write_all(b"0123").unwrap();
+        assert_eq!(count_files(&dir.path().join("db/data")), 1);
+        let hash = f.persist("bin").unwrap();
+        assert_eq!(
+            fs.list_files("data").unwrap(),
+            [format!("data/{}.bin", hash)],
+        );
+
+        let fs = LocalFileSystem::new(dir.path().join("db"))
+            .with_temp_dir(&temp_dir);
+        let mut f = fs.create_hashed_file_in("other").unwrap();
+        f.write_all(b"4567").unwrap();
+        assert_eq!(count_files(&temp_dir), 1);
+        assert_eq!(count_files(&dir.path().join("db/other")), 0);
+        let hash = f.persist("bin").unwrap();
+        assert_eq!(count_files(&temp_dir), 0);
+        assert_eq!(
+            fs.list_files("other").unwrap(),
+            [format!("other/{}.bin", hash)],
+        );
+    }
+
+    #[test]
+    fn local_file_system_should_create_nested_directories_with_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path().join("db"))
+            .with_sync_on_persist(true);
+        let mut f = fs.create_hashed_file_in("a/b/c").unwrap();
+        f.write_all(b"0123").unwrap();
+        let hash = f.persist("bin").unwrap();
+        assert_eq!(
+            fs.list_files("a").unwrap(),
+            [format!("a/b/c/{}.bin", hash)],
+        );
+        fs.write_pointer("x/y/CURRENT", "a").unwrap();
+        assert_eq!(
+            fs.read_pointer("x/y/CURRENT").unwrap().as_deref(),
+            Some("a"),
+        );
+    }
+
+    #[test]
+    fn local_hashed_file_out_should_be_persisted_with_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path()).with_sync_on_persist(true);
+        let mut f = fs.create_hashed_file_in("data").unwrap();
+        f.write_all(b"0123").unwrap();
+        let hash = f.persist("bin").unwrap();
+        let mut f = fs.open_hashed_file(format!("data/{}.bin", hash)).unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"0123");
+        f.verify().unwrap();
+    }
 }
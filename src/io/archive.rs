@@ -0,0 +1,352 @@
+//! Export and import of databases as tar or zip archives.
+//!
+//! [`export_archive`] bundles every file of a database into a tar archive,
+//! and [`import_archive`] unpacks an archive into a file system. Files keep
+//! their names, i.e., hashes, so the unpacked database is verified as usual.
+//! [`export_zip_archive`] and [`import_zip_archive`] do the same with a zip
+//! archive, whose files are stored without compression.
+//!
+//! The tar functions are available with the `tar` feature, and the zip
+//! functions with the `zip` feature.
+
+use std::io::{Read, Write};
+#[cfg(feature = "zip")]
+use std::io::Seek;
+use std::path::{Component, Path};
+
+use crate::error::Error;
+
+use super::hash::HashAlgorithm;
+use super::{HashedFileOut, ReadFileSystem, WriteFileSystem};
+
+/// Writes every file in a given file system into a tar archive.
+///
+/// Give a [`LocalFileSystem`](super::LocalFileSystem) over the directory of
+/// a database to export the database. Files are copied without verification
+/// or decompression, and hidden files, e.g., temporary files of unfinished
+/// writes, are skipped. The file system has to support
+/// [`ReadFileSystem::list_files`].
+///
+/// Returns the writer after the archive is finished.
+#[cfg(feature = "tar")]
+pub fn export_archive<FS, W>(fs: &FS, w: W) -> Result<W, Error>
+where
+    FS: ReadFileSystem,
+    W: Write,
+{
+    let mut builder = tar::Builder::new(w);
+    for path in archived_paths(fs)? {
+        let contents = read_archived_file(fs, &path)?;
+        let mut header = tar::Header::new_ustar();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        builder.append_data(&mut header, &path, &contents[..])?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Unpacks a tar archive into a given file system.
+///
+/// Every file in the archive must be named after the hash of its contents
+/// with one of the available [`HashAlgorithm`]s, and is written with the
+/// algorithm so that it keeps the name. Directories are created as needed,
+/// and entries other than regular files are ignored.
+///
+/// Fails with `Error::VerificationFailure` if the contents of a file do not
+/// match its name, or with `Error::InvalidData` if a path in the archive is
+/// not relative or has no extension.
+///
+/// Returns the number of files unpacked.
+#[cfg(feature = "tar")]
+pub fn import_archive<R, FS>(r: R, fs: &FS) -> Result<usize, Error>
+where
+    R: Read,
+    FS: WriteFileSystem,
+{
+    let mut archive = tar::Archive::new(r);
+    let mut num_files = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let mut contents: Vec<u8> = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        import_file(fs, &path, &contents)?;
+        num_files += 1;
+    }
+    Ok(num_files)
+}
+
+/// Writes every file in a given file system into a zip archive.
+///
+/// Same as [`export_archive`] except for the format. Files are stored
+/// without compression, because compressed files of a database would not
+/// shrink any further.
+///
+/// Returns the writer after the archive is finished.
+#[cfg(feature = "zip")]
+pub fn export_zip_archive<FS, W>(fs: &FS, w: W) -> Result<W, Error>
+where
+    FS: ReadFileSystem,
+    W: Write + Seek,
+{
+    let mut writer = zip::ZipWriter::new(w);
+    let options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored)
+        .last_modified_time(zip::DateTime::default())
+        .unix_permissions(0o644);
+    for path in archived_paths(fs)? {
+        let contents = read_archived_file(fs, &path)?;
+        writer.start_file(path, options)?;
+        writer.write_all(&contents)?;
+    }
+    Ok(writer.finish()?)
+}
+
+/// Unpacks a zip archive into a given file system.
+///
+/// Same as [`import_archive`] except for the format. Entries other than
+/// regular files are ignored, and files may be stored or compressed with
+/// any method the `zip` crate reads without optional features.
+///
+/// Returns the number of files unpacked.
+#[cfg(feature = "zip")]
+pub fn import_zip_archive<R, FS>(r: R, fs: &FS) -> Result<usize, Error>
+where
+    R: Read + Seek,
+    FS: WriteFileSystem,
+{
+    let mut archive = zip::ZipArchive::new(r)?;
+    let mut num_files = 0;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if !file.is_file() {
+            continue;
+        }
+        let path = Path::new(file.name()).to_path_buf();
+        let mut contents: Vec<u8> = Vec::new();
+        file.read_to_end(&mut contents)?;
+        import_file(fs, &path, &contents)?;
+        num_files += 1;
+    }
+    Ok(num_files)
+}
+
+// Lists the files to archive in a given file system in the order of paths.
+//
+// Hidden files are excluded.
+fn archived_paths<FS>(fs: &FS) -> Result<Vec<String>, Error>
+where
+    FS: ReadFileSystem,
+{
+    let mut paths: Vec<String> = fs
+        .list_files("")?
+        .into_iter()
+        .filter(|path| !path.rsplit('/').next().unwrap().starts_with('.'))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+// Reads the raw contents of a file to archive.
+fn read_archived_file<FS>(fs: &FS, path: &str) -> Result<Vec<u8>, Error>
+where
+    FS: ReadFileSystem,
+{
+    let mut f = fs.open_hashed_file_unverified(path)?;
+    let mut contents: Vec<u8> = Vec::new();
+    f.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+// Writes a file in an archive to a given file system.
+//
+// Verifies the contents against the name of the file.
+fn import_file<FS>(fs: &FS, path: &Path, contents: &[u8]) -> Result<(), Error>
+where
+    FS: WriteFileSystem,
+{
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(Error::InvalidData(format!(
+            "path in archive must be relative: {}",
+            path.display(),
+        )));
+    }
+    let path = path.to_str().ok_or(Error::InvalidData(format!(
+        "non UTF-8 path in archive: {}",
+        path.display(),
+    )))?;
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let (stem, extension) = name.split_once('.').ok_or(
+        Error::InvalidData(format!("file without extension: {}", path)),
+    )?;
+    let algorithm = detect_hash_algorithm(contents, stem)?.ok_or(
+        Error::VerificationFailure(format!(
+            "hash discrepancy in archive: {}",
+            path,
+        )),
+    )?;
+    let mut f = fs.create_hashed_file_in_with(dir, algorithm)?;
+    f.write_all(contents)?;
+    f.flush()?;
+    let hash = f.persist(extension)?;
+    if hash != stem {
+        return Err(Error::VerificationFailure(format!(
+            "Expected hash {}, but got {}",
+            stem,
+            hash,
+        )));
+    }
+    Ok(())
+}
+
+// Returns the available hash algorithm whose hash of given contents is a
+// given one.
+fn detect_hash_algorithm(
+    contents: &[u8],
+    hash: &str,
+) -> Result<Option<HashAlgorithm>, Error> {
+    for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        if algorithm.is_available() && algorithm.hash(contents)? == hash {
+            return Ok(Some(algorithm));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::DatabaseBuilder;
+    use crate::db::build::proto::serialize_database;
+    use crate::db::stored::{Database, LoadDatabase};
+    use crate::io::LocalFileSystem;
+    use crate::testing::SyntheticDatasetBuilder;
+
+    // Serializes a database in a temporary directory.
+    fn serialize_database_in_temp_dir() -> tempfile::TempDir {
+        let dataset = SyntheticDatasetBuilder::new(
+            100.try_into().unwrap(),
+            4.try_into().unwrap(),
+        )
+            .build()
+            .unwrap();
+        let db = DatabaseBuilder::new(dataset.vectors)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        let src = tempfile::tempdir().unwrap();
+        serialize_database(&db, &mut LocalFileSystem::new(src.path()))
+            .unwrap();
+        src
+    }
+
+    // Queries the database unpacked in `dst` that is exported from `src`.
+    fn query_unpacked_database(src: &Path, dst: &Path) {
+        let header = LocalFileSystem::new(src)
+            .list_files("")
+            .unwrap()
+            .into_iter()
+            .find(|path| !path.contains('/'))
+            .unwrap();
+        let stored = Database::<f32, _>::load_database(
+            LocalFileSystem::new(dst),
+            header,
+        ).unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let results = stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        stored.get_attribute(&results[0].vector_id, "datum_id").unwrap();
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn database_should_be_loaded_after_export_and_import() {
+        let src = serialize_database_in_temp_dir();
+        let fs = LocalFileSystem::new(src.path());
+        let archive = export_archive(&fs, Vec::new()).unwrap();
+
+        let dst = tempfile::tempdir().unwrap();
+        let num_files =
+            import_archive(&archive[..], &LocalFileSystem::new(dst.path()))
+                .unwrap();
+        assert_eq!(num_files, 8);
+        query_unpacked_database(src.path(), dst.path());
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn import_archive_should_reject_file_not_matching_its_name() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_size(10);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "data/AAAA.bin", &b"0123456789"[..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            import_archive(&archive[..], &LocalFileSystem::new(dir.path())),
+            Err(Error::VerificationFailure(_)),
+        ));
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn database_should_be_loaded_after_zip_export_and_import() {
+        use std::io::Cursor;
+
+        let src = serialize_database_in_temp_dir();
+        let fs = LocalFileSystem::new(src.path());
+        let archive = export_zip_archive(&fs, Cursor::new(Vec::new()))
+            .unwrap()
+            .into_inner();
+
+        let dst = tempfile::tempdir().unwrap();
+        let num_files = import_zip_archive(
+            Cursor::new(&archive[..]),
+            &LocalFileSystem::new(dst.path()),
+        ).unwrap();
+        assert_eq!(num_files, 8);
+        query_unpacked_database(src.path(), dst.path());
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn import_zip_archive_should_reject_invalid_paths_and_contents() {
+        use std::io::Cursor;
+
+        let archive_of = |path: &str| {
+            let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            writer
+                .start_file(path, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(b"0123456789").unwrap();
+            writer.finish().unwrap().into_inner()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let archive = archive_of("data/AAAA.bin");
+        assert!(matches!(
+            import_zip_archive(Cursor::new(&archive[..]), &fs),
+            Err(Error::VerificationFailure(_)),
+        ));
+        let archive = archive_of("../AAAA.bin");
+        assert!(matches!(
+            import_zip_archive(Cursor::new(&archive[..]), &fs),
+            Err(Error::InvalidData(_)),
+        ));
+        assert!(matches!(
+            import_zip_archive(Cursor::new(&b"not a zip"[..]), &fs),
+            Err(Error::InvalidData(_)),
+        ));
+    }
+}
@@ -0,0 +1,646 @@
+//! Read-through cache of hashed files.
+//!
+//! [`CachingFileSystem`] wraps a file system, typically a remote one, and
+//! keeps copies of fetched files in a local directory. As a file is named
+//! after the hash of its contents, a cached copy never goes stale.
+//!
+//! The cache may be limited to a quota of bytes, in which case the least
+//! recently used copies are evicted to make room for new ones. Accesses are
+//! recorded in an index file in the cache directory, so that they survive
+//! restarts.
+
+use base64::{
+    Engine,
+    engine::general_purpose::{URL_SAFE_NO_PAD as base64_engine},
+};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+
+use crate::error::Error;
+
+use super::{
+    DEFAULT_READAHEAD_SIZE,
+    HashedFileIn,
+    LocalHashedFileIn,
+    ReadFileSystem,
+    WriteFileSystem,
+};
+
+/// Name of the index file in a cache directory.
+pub const CACHE_INDEX_FILE_NAME: &str = ".cache-index.json";
+
+/// File system that caches files of another file system in a local
+/// directory.
+///
+/// A file is cached when it has been entirely read and its contents match
+/// the hash in its name; subsequent opens read the cached copy. A cached
+/// copy that fails verification is removed so that it is fetched again.
+///
+/// Files are written to, listed in, and deleted from the wrapped file system.
+/// Deleting a file also removes its cached copy.
+pub struct CachingFileSystem<FS> {
+    inner: FS,
+    cache_dir: PathBuf,
+    // Tracks cached copies if the cache has a quota.
+    index: Option<Arc<Mutex<CacheIndex>>>,
+}
+
+impl<FS> CachingFileSystem<FS> {
+    /// Wraps a given file system caching files under a given directory.
+    pub fn new(inner: FS, cache_dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner,
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+            index: None,
+        }
+    }
+
+    /// Limits the total size of cached copies to a given number of bytes.
+    ///
+    /// Loads the index of the cache directory; copies missing in the index
+    /// are regarded as the least recently used. A single file larger than
+    /// the quota is not cached.
+    pub fn with_quota(mut self, quota: u64) -> Self {
+        self.index = Some(Arc::new(Mutex::new(
+            CacheIndex::load(&self.cache_dir, quota),
+        )));
+        self
+    }
+
+    /// Returns the wrapped file system.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+
+    /// Returns the cache directory.
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Returns the total size of cached copies in bytes if the cache has a
+    /// quota.
+    pub fn cached_size(&self) -> Option<u64> {
+        self.index.as_ref().map(|index| index.lock().unwrap().total_size())
+    }
+}
+
+impl<FS> ReadFileSystem for CachingFileSystem<FS>
+where
+    FS: ReadFileSystem,
+{
+    type HashedFileIn = CachedHashedFileIn<FS::HashedFileIn>;
+
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let key = path.as_ref().to_string();
+        let cache_path = self.cache_dir.join(&key);
+        let source = if cache_path.is_file() {
+            if let Some(index) = self.index.as_ref() {
+                index.lock().unwrap().touch(&key);
+            }
+            Source::Cached(LocalHashedFileIn::open(
+                cache_path,
+                DEFAULT_READAHEAD_SIZE,
+            )?)
+        } else {
+            let file = self.inner.open_hashed_file(&key)?;
+            Source::Fetching(CacheWriter::new(file, cache_path))
+        };
+        Ok(CachedHashedFileIn {
+            source,
+            key,
+            index: self.index.clone(),
+        })
+    }
+
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        self.inner.list_files(prefix)
+    }
+
+    /// Pointers are mutable, so they are never cached.
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        self.inner.read_pointer(path)
+    }
+}
+
+impl<FS> WriteFileSystem for CachingFileSystem<FS>
+where
+    FS: WriteFileSystem,
+{
+    type HashedFileOut = FS::HashedFileOut;
+
+    fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file()
+    }
+
+    fn create_hashed_file_in(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file_in(path)
+    }
+
+    fn delete(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        self.inner.delete(path.as_ref())?;
+        super::delete_local_file(&self.cache_dir.join(path.as_ref()))?;
+        if let Some(index) = self.index.as_ref() {
+            index.lock().unwrap().remove(path.as_ref());
+        }
+        Ok(())
+    }
+
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        self.inner.write_pointer(path, target)
+    }
+}
+
+/// File read from the cache, or fetched from the wrapped file system.
+pub struct CachedHashedFileIn<R> {
+    source: Source<R>,
+    // Path of the file relative to the cache directory.
+    key: String,
+    index: Option<Arc<Mutex<CacheIndex>>>,
+}
+
+// Where a file is read from.
+enum Source<R> {
+    // Cached copy.
+    Cached(LocalHashedFileIn),
+    // Wrapped file system.
+    Fetching(CacheWriter<R>),
+}
+
+impl<R> CachedHashedFileIn<R> {
+    /// Returns if the file is read from the cache.
+    pub fn is_cached(&self) -> bool {
+        matches!(self.source, Source::Cached(_))
+    }
+}
+
+impl<R> Read for CachedHashedFileIn<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.source {
+            Source::Cached(file) => file.read(buf),
+            Source::Fetching(writer) => {
+                let n = writer.read(buf)?;
+                if n == 0 && !buf.is_empty() {
+                    if let Some(size) = writer.finish() {
+                        if let Some(index) = self.index.as_ref() {
+                            index.lock().unwrap().insert(&self.key, size);
+                        }
+                    }
+                }
+                Ok(n)
+            },
+        }
+    }
+}
+
+impl<R> HashedFileIn for CachedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    fn verify(self) -> Result<(), Error> {
+        match self.source {
+            Source::Cached(file) => {
+                let path = file.path.clone();
+                let result = file.verify();
+                if result.is_err() {
+                    // fetches the file again next time
+                    let _ = std::fs::remove_file(path);
+                    if let Some(index) = self.index.as_ref() {
+                        index.lock().unwrap().remove(&self.key);
+                    }
+                }
+                result
+            },
+            Source::Fetching(writer) => writer.file.verify(),
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        match &self.source {
+            Source::Cached(file) => file.size(),
+            Source::Fetching(writer) => writer.file.size(),
+        }
+    }
+}
+
+// Copies contents of a file to the cache while they are read.
+struct CacheWriter<R> {
+    file: R,
+    // Copy of the contents. `None` once persisted or discarded.
+    tempfile: Option<NamedTempFile>,
+    // Number of bytes copied.
+    size: u64,
+    cache_path: PathBuf,
+    // Context to calculate an SHA-256 digest.
+    context: Option<ring::digest::Context>,
+}
+
+impl<R> CacheWriter<R> {
+    // Copies a given file to a given path in the cache.
+    fn new(file: R, cache_path: PathBuf) -> Self {
+        // the cache is an optimization; fetches the file anyway
+        let tempfile = cache_path
+            .parent()
+            .and_then(|dir| std::fs::create_dir_all(dir).ok().map(|_| dir))
+            .and_then(|dir| NamedTempFile::new_in(dir).ok());
+        Self {
+            file,
+            tempfile,
+            size: 0,
+            cache_path,
+            context: Some(ring::digest::Context::new(&ring::digest::SHA256)),
+        }
+    }
+
+    // Moves the copy into the cache if its hash matches the file name.
+    //
+    // Returns the size of the copy if it has been moved.
+    fn finish(&mut self) -> Option<u64> {
+        let tempfile = self.tempfile.take()?;
+        let context = self.context.take()?;
+        let hash = base64_engine.encode(context.finish());
+        let stem = self.cache_path.file_stem().and_then(|stem| stem.to_str());
+        if stem == Some(hash.as_str()) {
+            tempfile.persist(&self.cache_path).ok().map(|_| self.size)
+        } else {
+            None
+        }
+    }
+}
+
+impl<R> Read for CacheWriter<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read(buf)?;
+        if let Some(tempfile) = self.tempfile.as_mut() {
+            if tempfile.write_all(&buf[..n]).is_ok() {
+                self.size += n as u64;
+                if let Some(context) = self.context.as_mut() {
+                    context.update(&buf[..n]);
+                }
+            } else {
+                self.tempfile = None;
+            }
+        }
+        Ok(n)
+    }
+}
+
+// Index of cached copies for least-recently-used eviction.
+//
+// Keys are paths relative to the cache directory.
+pub(crate) struct CacheIndex {
+    cache_dir: PathBuf,
+    quota: u64,
+    // Size and last access of each copy.
+    entries: HashMap<String, CacheEntry>,
+    total_size: u64,
+    // Logical clock of accesses.
+    clock: u64,
+}
+
+// Entry in a cache index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct CacheEntry {
+    size: u64,
+    last_access: u64,
+}
+
+impl CacheIndex {
+    // Loads the index of a given cache directory.
+    //
+    // Entries whose copies are missing are dropped, and copies missing in
+    // the index are added as the least recently used. Evicts copies if they
+    // exceed the quota.
+    pub(crate) fn load(cache_dir: &Path, quota: u64) -> Self {
+        let mut recorded: HashMap<String, u64> = HashMap::new();
+        if let Ok(index) = std::fs::read_to_string(
+            cache_dir.join(CACHE_INDEX_FILE_NAME),
+        ) {
+            if let Ok(serde_json::Value::Array(entries)) =
+                serde_json::from_str(&index)
+            {
+                for entry in entries {
+                    let path = entry.get("path").and_then(|p| p.as_str());
+                    let last_access = entry
+                        .get("last_access")
+                        .and_then(|t| t.as_u64());
+                    if let (Some(path), Some(last_access)) = (path, last_access)
+                    {
+                        recorded.insert(path.to_string(), last_access);
+                    }
+                }
+            }
+        }
+        let mut entries: HashMap<String, CacheEntry> = HashMap::new();
+        let mut dirs = vec![(cache_dir.to_path_buf(), String::new())];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let Ok(read_dir) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in read_dir.flatten() {
+                let Some(name) = entry.file_name().to_str().map(String::from)
+                else {
+                    continue;
+                };
+                let key = format!("{}{}", prefix, name);
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    dirs.push((entry.path(), format!("{}/", key)));
+                } else if !name.starts_with('.') {
+                    // skips the index and temporary files
+                    entries.insert(key.clone(), CacheEntry {
+                        size: metadata.len(),
+                        last_access: recorded.get(&key).copied().unwrap_or(0),
+                    });
+                }
+            }
+        }
+        let clock = entries.values().map(|e| e.last_access).max().unwrap_or(0);
+        let total_size = entries.values().map(|e| e.size).sum();
+        let mut index = Self {
+            cache_dir: cache_dir.to_path_buf(),
+            quota,
+            entries,
+            total_size,
+            clock,
+        };
+        index.evict(None);
+        index.save();
+        index
+    }
+
+    // Records an access to a copy.
+    pub(crate) fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.last_access = self.clock;
+            self.save();
+        }
+    }
+
+    // Adds a new copy evicting the least recently used ones to fit in the
+    // quota.
+    pub(crate) fn insert(&mut self, key: &str, size: u64) {
+        self.clock += 1;
+        let entry = CacheEntry { size, last_access: self.clock };
+        if let Some(old) = self.entries.insert(key.to_string(), entry) {
+            self.total_size -= old.size;
+        }
+        self.total_size += size;
+        self.evict(Some(key));
+        self.save();
+    }
+
+    // Forgets a removed copy.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.total_size -= entry.size;
+            self.save();
+        }
+    }
+
+    // Returns the total size of copies.
+    pub(crate) fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    // Removes the least recently used copies until they fit in the quota.
+    //
+    // `keep` is evicted only if it does not fit alone.
+    fn evict(&mut self, keep: Option<&str>) {
+        if self.total_size <= self.quota {
+            return;
+        }
+        let mut lru: Vec<(u64, String)> = self.entries
+            .iter()
+            .filter(|(key, _)| Some(key.as_str()) != keep)
+            .map(|(key, entry)| (entry.last_access, key.clone()))
+            .collect();
+        lru.sort();
+        if let Some(keep) = keep {
+            lru.push((u64::MAX, keep.to_string()));
+        }
+        for (_, key) in lru {
+            if self.total_size <= self.quota {
+                break;
+            }
+            let _ = std::fs::remove_file(self.cache_dir.join(&key));
+            if let Some(entry) = self.entries.remove(&key) {
+                self.total_size -= entry.size;
+            }
+        }
+    }
+
+    // Writes the index to the cache directory.
+    //
+    // Failures are ignored as the index is rebuilt from the cache directory.
+    fn save(&self) {
+        let entries: Vec<serde_json::Value> = self.entries
+            .iter()
+            .map(|(key, entry)| serde_json::json!({
+                "path": key,
+                "last_access": entry.last_access,
+            }))
+            .collect();
+        let _ = std::fs::create_dir_all(&self.cache_dir)
+            .and_then(|_| NamedTempFile::new_in(&self.cache_dir))
+            .and_then(|mut f| {
+                f.write_all(serde_json::Value::Array(entries)
+                    .to_string()
+                    .as_bytes())?;
+                f.persist(self.cache_dir.join(CACHE_INDEX_FILE_NAME))
+                    .map_err(|e| e.error)
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    use crate::io::{HashedFileOut, LocalFileSystem};
+
+    // File system that counts opened files.
+    struct CountingFileSystem {
+        local: LocalFileSystem,
+        num_opens: Cell<usize>,
+    }
+
+    impl ReadFileSystem for CountingFileSystem {
+        type HashedFileIn = <LocalFileSystem as ReadFileSystem>::HashedFileIn;
+
+        fn open_hashed_file(
+            &self,
+            path: impl AsRef<str>,
+        ) -> Result<Self::HashedFileIn, Error> {
+            self.num_opens.set(self.num_opens.get() + 1);
+            self.local.open_hashed_file(path)
+        }
+    }
+
+    impl WriteFileSystem for CountingFileSystem {
+        type HashedFileOut =
+            <LocalFileSystem as WriteFileSystem>::HashedFileOut;
+
+        fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+            self.local.create_hashed_file()
+        }
+
+        fn create_hashed_file_in(
+            &self,
+            path: impl AsRef<str>,
+        ) -> Result<Self::HashedFileOut, Error> {
+            self.local.create_hashed_file_in(path)
+        }
+    }
+
+    #[test]
+    fn caching_file_system_should_serve_fetched_files_from_cache() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let fs = CachingFileSystem::new(
+            CountingFileSystem {
+                local: LocalFileSystem::new(remote_dir.path()),
+                num_opens: Cell::new(0),
+            },
+            cache_dir.path(),
+        );
+        let mut f = fs.create_hashed_file_in("data").unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let path = format!("data/{}.bin", f.persist("bin").unwrap());
+
+        // a partially read file is not cached
+        let mut f = fs.open_hashed_file(&path).unwrap();
+        assert!(!f.is_cached());
+        f.read_exact(&mut [0u8; 4]).unwrap();
+        drop(f);
+        for _ in 0..2 {
+            let mut f = fs.open_hashed_file(&path).unwrap();
+            let mut buf: Vec<u8> = Vec::new();
+            f.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, b"0123456789");
+            f.verify().unwrap();
+        }
+        assert_eq!(fs.inner().num_opens.get(), 2);
+        assert!(fs.open_hashed_file(&path).unwrap().is_cached());
+
+        // a corrupted copy is removed
+        std::fs::write(cache_dir.path().join(&path), b"012345678X").unwrap();
+        let mut f = fs.open_hashed_file(&path).unwrap();
+        f.read_to_end(&mut Vec::new()).unwrap();
+        assert!(f.verify().is_err());
+        assert!(!fs.open_hashed_file(&path).unwrap().is_cached());
+
+        // a file that does not match its name is not cached
+        std::fs::write(remote_dir.path().join("data/AAAA.bin"), b"0123")
+            .unwrap();
+        let mut f = fs.open_hashed_file("data/AAAA.bin").unwrap();
+        f.read_to_end(&mut Vec::new()).unwrap();
+        assert!(!fs.open_hashed_file("data/AAAA.bin").unwrap().is_cached());
+    }
+
+    #[test]
+    fn caching_file_system_should_evict_least_recently_used_copies() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let remote = LocalFileSystem::new(remote_dir.path());
+        let paths: Vec<String> = (0..4u8)
+            .map(|i| {
+                let mut f = remote.create_hashed_file_in("data").unwrap();
+                f.write_all(&[i; 10]).unwrap();
+                format!("data/{}.bin", f.persist("bin").unwrap())
+            })
+            .collect();
+        let read = |fs: &CachingFileSystem<LocalFileSystem>, path: &str| {
+            let mut f = fs.open_hashed_file(path).unwrap();
+            let cached = f.is_cached();
+            f.read_to_end(&mut Vec::new()).unwrap();
+            cached
+        };
+
+        let fs = CachingFileSystem::new(
+            LocalFileSystem::new(remote_dir.path()),
+            cache_dir.path(),
+        ).with_quota(25);
+        assert!(!read(&fs, &paths[0]));
+        assert!(!read(&fs, &paths[1]));
+        assert!(read(&fs, &paths[0]));
+        assert!(!read(&fs, &paths[2]));
+        // the least recently used copy (1) is evicted
+        assert_eq!(fs.cached_size(), Some(20));
+        assert!(!cache_dir.path().join(&paths[1]).exists());
+
+        // accesses survive restarts
+        drop(fs);
+        let fs = CachingFileSystem::new(
+            LocalFileSystem::new(remote_dir.path()),
+            cache_dir.path(),
+        ).with_quota(25);
+        assert_eq!(fs.cached_size(), Some(20));
+        assert!(read(&fs, &paths[2]));
+        assert!(!read(&fs, &paths[3]));
+        assert!(!cache_dir.path().join(&paths[0]).exists());
+        assert!(read(&fs, &paths[2]));
+        assert!(read(&fs, &paths[3]));
+
+        // a smaller quota evicts copies on load
+        drop(fs);
+        let fs = CachingFileSystem::new(
+            LocalFileSystem::new(remote_dir.path()),
+            cache_dir.path(),
+        ).with_quota(10);
+        assert_eq!(fs.cached_size(), Some(10));
+        assert!(read(&fs, &paths[3]));
+    }
+
+    #[test]
+    fn caching_file_system_should_delete_cached_copies() {
+        let remote_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let fs = CachingFileSystem::new(
+            LocalFileSystem::new(remote_dir.path()),
+            cache_dir.path(),
+        ).with_quota(100);
+        let mut f = fs.create_hashed_file_in("data/nested").unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let path = format!("data/nested/{}.bin", f.persist("bin").unwrap());
+        let mut f = fs.open_hashed_file(&path).unwrap();
+        f.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(fs.list_files("data").unwrap(), [path.as_str()]);
+        assert_eq!(fs.cached_size(), Some(10));
+
+        fs.delete(&path).unwrap();
+        fs.delete(&path).unwrap();
+        assert!(fs.list_files("").unwrap().is_empty());
+        assert!(!cache_dir.path().join(&path).exists());
+        assert_eq!(fs.cached_size(), Some(0));
+    }
+}
@@ -0,0 +1,451 @@
+//! Compression codecs of files.
+//!
+//! Compressed files start with the magic number of their [`Codec`], so a
+//! loader detects the codec of every file without being told.
+//!
+//! Zlib is always available. Zstandard and LZ4 are available with the
+//! `zstd` and `lz4` features respectively.
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+#[cfg(feature = "zstd")]
+use std::io::BufReader;
+use std::io::{Read, Write};
+
+use crate::error::Error;
+
+/// Default compression level.
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// Number of bytes to detect a codec.
+pub const MAGIC_SIZE: usize = 4;
+
+// Magic number of a Zstandard frame.
+const ZSTD_MAGIC: [u8; MAGIC_SIZE] = [0x28, 0xB5, 0x2F, 0xFD];
+
+// Magic number of an LZ4 frame.
+const LZ4_MAGIC: [u8; MAGIC_SIZE] = [0x04, 0x22, 0x4D, 0x18];
+
+/// Compression codec.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Codec {
+    /// Zlib, which is compatible with every version of flechasdb.
+    #[default]
+    Zlib,
+    /// Zstandard, which decompresses faster than Zlib at a similar ratio.
+    ///
+    /// Available with the `zstd` feature.
+    Zstd,
+    /// LZ4 frame format, which decompresses fastest at a lower ratio.
+    ///
+    /// Available with the `lz4` feature.
+    Lz4,
+}
+
+impl Codec {
+    /// Detects the codec from the first bytes of a compressed file.
+    ///
+    /// Zlib if no other codec matches, since a Zlib stream has no magic
+    /// number.
+    pub fn detect(head: &[u8]) -> Self {
+        if head.starts_with(&ZSTD_MAGIC) {
+            Codec::Zstd
+        } else if head.starts_with(&LZ4_MAGIC) {
+            Codec::Lz4
+        } else {
+            Codec::Zlib
+        }
+    }
+
+    /// Returns if the codec is available in this build.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Codec::Zlib => true,
+            Codec::Zstd => cfg!(feature = "zstd"),
+            Codec::Lz4 => cfg!(feature = "lz4"),
+        }
+    }
+
+    /// Returns the maximum compression level of the codec.
+    ///
+    /// 9 for Zlib, 22 for Zstandard, and 12 for LZ4. Higher levels compress
+    /// better but slower.
+    pub const fn max_level(&self) -> u32 {
+        match self {
+            Codec::Zlib => 9,
+            Codec::Zstd => 22,
+            Codec::Lz4 => 12,
+        }
+    }
+
+    // Fails with `Error::InvalidArgs` if `level` exceeds the maximum level.
+    pub(crate) fn check_level(&self, level: u32) -> Result<(), Error> {
+        if level > self.max_level() {
+            return Err(Error::InvalidArgs(format!(
+                "{:?} compression level must be ≤ {} but got {}",
+                self,
+                self.max_level(),
+                level,
+            )));
+        }
+        Ok(())
+    }
+
+    // Fails with `Error::InvalidContext` unless the codec is available.
+    pub(crate) fn check_available(&self) -> Result<(), Error> {
+        if self.is_available() {
+            Ok(())
+        } else {
+            Err(Error::InvalidContext(format!(
+                "{:?} codec requires the {} feature",
+                self,
+                self.feature(),
+            )))
+        }
+    }
+
+    // Name of the feature that enables the codec.
+    fn feature(&self) -> &'static str {
+        match self {
+            Codec::Zlib => "default",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        }
+    }
+}
+
+// Encoder of any codec.
+pub(crate) enum Encoder<W>
+where
+    W: Write,
+{
+    Zlib(ZlibEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4::Encoder<W>),
+}
+
+impl<W> Encoder<W>
+where
+    W: Write,
+{
+    // Creates a Zlib encoder that compresses at a given level.
+    pub(crate) fn zlib(w: W, level: u32) -> Self {
+        Encoder::Zlib(ZlibEncoder::new(w, Compression::new(level)))
+    }
+
+    // Creates an encoder that compresses at a given level.
+    //
+    // `level` ranges from 0 to `codec.max_level()`. 0 means no compression
+    // for Zlib, and the default level for Zstandard and LZ4.
+    pub(crate) fn new(w: W, codec: Codec, level: u32) -> Result<Self, Error> {
+        codec.check_available()?;
+        codec.check_level(level)?;
+        match codec {
+            Codec::Zlib => Ok(Encoder::zlib(w, level)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                let mut encoder =
+                    zstd::stream::write::Encoder::new(w, level as i32)?;
+                encoder.include_checksum(false)?;
+                Ok(Encoder::Zstd(encoder))
+            },
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => {
+                let encoder = lz4::EncoderBuilder::new().level(level).build(w)?;
+                Ok(Encoder::Lz4(encoder))
+            },
+            #[cfg(not(all(feature = "zstd", feature = "lz4")))]
+            _ => unreachable!("codec must be available"),
+        }
+    }
+
+    // Finishes the compressed stream and returns the underlying writer.
+    pub(crate) fn finish(self) -> Result<W, Error> {
+        match self {
+            Encoder::Zlib(encoder) => Ok(encoder.finish()?),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => Ok(encoder.finish()?),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => {
+                let (w, result) = encoder.finish();
+                result?;
+                Ok(w)
+            },
+        }
+    }
+}
+
+impl<W> Write for Encoder<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Zlib(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Zlib(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Decoder that detects the codec from the first bytes.
+pub(crate) enum Decoder<R>
+where
+    R: Read,
+{
+    // Before the first read. `None` only while switching to a decoder.
+    Detecting(Option<R>),
+    Zlib(ZlibDecoder<Prefixed<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<Prefixed<R>>>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4::Decoder<Prefixed<R>>),
+}
+
+impl<R> Decoder<R>
+where
+    R: Read,
+{
+    // Creates a decoder reading a given reader.
+    pub(crate) fn new(r: R) -> Self {
+        Decoder::Detecting(Some(r))
+    }
+
+    // Returns the underlying reader.
+    pub(crate) fn get_ref(&self) -> &R {
+        match self {
+            Decoder::Detecting(r) => r.as_ref().unwrap(),
+            Decoder::Zlib(decoder) => decoder.get_ref().get_ref(),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.get_ref().get_ref().get_ref(),
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(decoder) => decoder.reader().get_ref(),
+        }
+    }
+
+    // Consumes the decoder and returns the underlying reader.
+    pub(crate) fn into_inner(self) -> R {
+        match self {
+            Decoder::Detecting(r) => r.unwrap(),
+            Decoder::Zlib(decoder) => decoder.into_inner().into_inner(),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => {
+                decoder.finish().into_inner().into_inner()
+            },
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(decoder) => decoder.finish().0.into_inner(),
+        }
+    }
+
+    // Reads the magic number and switches to the decoder of the codec.
+    fn detect(&mut self) -> std::io::Result<()> {
+        let Decoder::Detecting(r) = self else {
+            return Ok(());
+        };
+        let mut r = r.take().unwrap();
+        let mut head = [0u8; MAGIC_SIZE];
+        let mut len = 0;
+        while len < MAGIC_SIZE {
+            match r.read(&mut head[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    *self = Decoder::Detecting(Some(r));
+                    return Err(e);
+                },
+            }
+        }
+        let codec = Codec::detect(&head[..len]);
+        if let Err(e) = codec.check_available() {
+            *self = Decoder::Detecting(Some(r));
+            return Err(std::io::Error::other(e));
+        }
+        let r = Prefixed { head, pos: 0, len, inner: r };
+        *self = match codec {
+            Codec::Zlib => Decoder::Zlib(ZlibDecoder::new(r)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Decoder::Zstd(
+                zstd::stream::read::Decoder::new(r)?.single_frame(),
+            ),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Decoder::Lz4(lz4::Decoder::new(r)?),
+            #[cfg(not(all(feature = "zstd", feature = "lz4")))]
+            _ => unreachable!("codec must be available"),
+        };
+        Ok(())
+    }
+}
+
+impl<R> Read for Decoder<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.detect()?;
+        match self {
+            Decoder::Detecting(_) => unreachable!("codec must be detected"),
+            Decoder::Zlib(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            Decoder::Zstd(decoder) => decoder.read(buf),
+            #[cfg(feature = "lz4")]
+            Decoder::Lz4(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+// Reader that reads given first bytes before the rest of another reader.
+pub(crate) struct Prefixed<R> {
+    head: [u8; MAGIC_SIZE],
+    pos: usize,
+    len: usize,
+    inner: R,
+}
+
+impl<R> Prefixed<R> {
+    fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> Read for Prefixed<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos < self.len {
+            let n = buf.len().min(self.len - self.pos);
+            buf[..n].copy_from_slice(&self.head[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        } else {
+            self.inner.read(buf)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    use crate::db::CompressionPolicy;
+    use crate::db::build::proto::{
+        SerializeOptions,
+        serialize_database_with_options,
+    };
+    use crate::db::fixtures::{build_database, header_path, load_database};
+    use crate::io::LocalFileSystem;
+
+    fn round_trip(codec: Codec) {
+        let contents: Vec<u8> = (0..100_000u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut encoder = Encoder::new(Vec::new(), codec, 6).unwrap();
+        encoder.write_all(&contents).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() < contents.len());
+        assert_eq!(Codec::detect(&compressed), codec);
+        let mut decoder = Decoder::new(&compressed[..]);
+        let mut buf: Vec<u8> = Vec::new();
+        decoder.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, contents);
+        assert!(decoder.into_inner().is_empty());
+    }
+
+    #[test]
+    fn zlib_should_round_trip() {
+        round_trip(Codec::Zlib);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_should_round_trip() {
+        round_trip(Codec::Zstd);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_should_round_trip() {
+        round_trip(Codec::Lz4);
+    }
+
+    #[test]
+    fn level_beyond_max_should_be_rejected() {
+        for codec in [Codec::Zlib, Codec::Zstd, Codec::Lz4] {
+            if codec.is_available() {
+                assert!(matches!(
+                    Encoder::new(Vec::new(), codec, codec.max_level() + 1),
+                    Err(Error::InvalidArgs(_)),
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn unavailable_codec_should_be_rejected() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            if !codec.is_available() {
+                assert!(matches!(
+                    Encoder::new(Vec::new(), codec, 6),
+                    Err(Error::InvalidContext(_)),
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn database_should_load_with_any_available_codec() {
+        let db = build_database(100, 4);
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let expected: Vec<(Uuid, f32)> = db
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.vector_id, r.squared_distance))
+            .collect();
+        for codec in [Codec::Zlib, Codec::Zstd, Codec::Lz4] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut fs = LocalFileSystem::new(dir.path());
+            let options = SerializeOptions::new()
+                .with_compression(CompressionPolicy::all())
+                .with_codec(codec);
+            let result =
+                serialize_database_with_options(&db, &mut fs, &options);
+            if !codec.is_available() {
+                assert!(matches!(result, Err(Error::InvalidContext(_))));
+                continue;
+            }
+            result.unwrap();
+            let path = header_path(dir.path());
+            let stored = load_database(&dir, &path);
+            let results: Vec<(Uuid, f32)> = stored
+                .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.vector_id, r.squared_distance))
+                .collect();
+            assert_eq!(results, expected);
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! Single-file container of a database.
+//!
+//! A serialized database consists of many hashed files, which are tedious
+//! to distribute, and costly to store in an object storage that charges
+//! every request. [`write_container`] packs every file in a file system into
+//! a single container, and [`ContainerFileSystem`] reads the files from the
+//! container, so that a database can be loaded from it as it is.
+//!
+//! A container starts with a magic number and a version, followed by the
+//! contents of the files as they are, an index of the offsets of the files,
+//! and a footer locating the index. Files keep their names, i.e., hashes,
+//! so they are verified as usual. Pointers, e.g.,
+//! [`CURRENT`](crate::db::manifest::CURRENT), are packed like other files,
+//! so the current version of a database can be loaded from a container.
+
+use memmap2::Mmap;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::protos::database::{
+    ContainerEntry as ProtosContainerEntry,
+    ContainerIndex as ProtosContainerIndex,
+};
+
+use super::hash::HashAlgorithm;
+use super::{HashedFileIn, ReadFileSystem, parse_pointer};
+
+// Magic number of a container.
+const MAGIC: &[u8; 4] = b"FDBC";
+// Version of the format.
+const VERSION: u32 = 1;
+// Size of the header; i.e., the magic number and the version.
+const HEADER_SIZE: usize = 8;
+// Size of the footer; i.e., the offset and the size of the index, and the
+// magic number.
+const FOOTER_SIZE: usize = 20;
+
+/// Writes every file in a given file system into a container.
+///
+/// Files are copied without verification or decompression. Hidden files,
+/// e.g., temporary files of unfinished writes, are skipped. The file system
+/// has to support [`ReadFileSystem::list_files`].
+///
+/// Returns the number of files written.
+pub fn write_container<FS, W>(fs: &FS, mut w: W) -> Result<usize, Error>
+where
+    FS: ReadFileSystem,
+    W: Write,
+{
+    let mut paths: Vec<String> = fs
+        .list_files("")?
+        .into_iter()
+        .filter(|path| !path.rsplit('/').next().unwrap().starts_with('.'))
+        .collect();
+    paths.sort();
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    let mut offset = HEADER_SIZE as u64;
+    let mut index = ProtosContainerIndex::new();
+    for path in paths {
+        let mut f = fs.open_hashed_file_unverified(&path)?;
+        let size = std::io::copy(&mut f, &mut w)?;
+        let mut entry = ProtosContainerEntry::new();
+        entry.path = path;
+        entry.offset = offset;
+        entry.size = size;
+        index.entries.push(entry);
+        offset += size;
+    }
+    let num_files = index.entries.len();
+    let index = index.write_to_bytes()?;
+    w.write_all(&index)?;
+    w.write_all(&offset.to_le_bytes())?;
+    w.write_all(&(index.len() as u64).to_le_bytes())?;
+    w.write_all(MAGIC)?;
+    w.flush()?;
+    Ok(num_files)
+}
+
+/// Read-only file system over a container in the local file system.
+///
+/// The container is mapped into memory, so messages are parsed in place as
+/// in [`MmapFileSystem`](super::mmap::MmapFileSystem).
+pub struct ContainerFileSystem {
+    map: Arc<Mmap>,
+    // Byte ranges of the files in the container.
+    entries: HashMap<String, Range<usize>>,
+}
+
+impl ContainerFileSystem {
+    /// Opens a container at a given path.
+    ///
+    /// Fails with `Error::InvalidData` if the file is not a container of a
+    /// known version, or the index is broken.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = std::fs::File::open(path.as_ref())?;
+        // SAFETY: a container is not supposed to be modified, and modified
+        // files fail verification.
+        let map = unsafe { Mmap::map(&file)? };
+        let entries = read_index(&map)?;
+        Ok(Self {
+            map: Arc::new(map),
+            entries,
+        })
+    }
+
+    // Opens a file that is verified with a given algorithm if any.
+    fn open_file(
+        &self,
+        path: &str,
+        algorithm: Option<HashAlgorithm>,
+    ) -> Result<ContainerHashedFileIn, Error> {
+        let range = self.entries.get(path).ok_or(Error::IOError(
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no such file in container: {}", path),
+            ),
+        ))?;
+        Ok(ContainerHashedFileIn {
+            map: self.map.clone(),
+            range: range.clone(),
+            pos: range.start,
+            path: path.to_string(),
+            algorithm,
+        })
+    }
+}
+
+impl ReadFileSystem for ContainerFileSystem {
+    type HashedFileIn = ContainerHashedFileIn;
+
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        self.open_file(path.as_ref(), Some(HashAlgorithm::Sha256))
+    }
+
+    fn open_hashed_file_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        algorithm.check_available()?;
+        self.open_file(path.as_ref(), Some(algorithm))
+    }
+
+    fn open_hashed_file_unverified(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        self.open_file(path.as_ref(), None)
+    }
+
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        let prefix = prefix.as_ref().trim_matches('/');
+        Ok(self.entries
+            .keys()
+            .filter(|path| {
+                prefix.is_empty() || path
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        self.entries
+            .get(path.as_ref())
+            .map(|range| parse_pointer(self.map[range.clone()].to_vec()))
+            .transpose()
+    }
+}
+
+/// File in a container.
+///
+/// The contents are hashed all at once when verified.
+pub struct ContainerHashedFileIn {
+    map: Arc<Mmap>,
+    range: Range<usize>,
+    // Position of the next read in the container.
+    pos: usize,
+    path: String,
+    // `None` if the file is not verified.
+    algorithm: Option<HashAlgorithm>,
+}
+
+impl Read for ContainerHashedFileIn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (&self.map[self.pos..self.range.end]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl HashedFileIn for ContainerHashedFileIn {
+    fn verify(self) -> Result<(), Error> {
+        let algorithm = self.algorithm.ok_or(Error::InvalidContext(format!(
+            "file was opened without hashing: {}",
+            self.path,
+        )))?;
+        let hash = algorithm.hash(&self.map[self.range.clone()])?;
+        let name = self.path.rsplit('/').next().unwrap();
+        let stem = name.split('.').next().unwrap();
+        if hash == stem {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "Expected hash {}, but got {}",
+                stem,
+                hash,
+            )))
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.range.len() as u64)
+    }
+
+    fn read_in_place(&mut self) -> Option<&[u8]> {
+        let pos = self.pos;
+        self.pos = self.range.end;
+        Some(&self.map[pos..self.range.end])
+    }
+}
+
+// Reads the index of a container.
+fn read_index(data: &[u8]) -> Result<HashMap<String, Range<usize>>, Error> {
+    if data.len() < HEADER_SIZE + FOOTER_SIZE
+        || &data[..MAGIC.len()] != MAGIC
+        || &data[data.len() - MAGIC.len()..] != MAGIC
+    {
+        return Err(Error::InvalidData("file is not a container".to_string()));
+    }
+    let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(Error::InvalidData(format!(
+            "unknown version of container: {}",
+            version,
+        )));
+    }
+    let footer = &data[data.len() - FOOTER_SIZE..];
+    let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    let index_size = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+    let body_end = (data.len() - FOOTER_SIZE) as u64;
+    let index_end = index_offset.checked_add(index_size);
+    if index_offset < HEADER_SIZE as u64 || index_end != Some(body_end) {
+        return Err(Error::InvalidData(format!(
+            "index out of bounds: offset {}, size {}",
+            index_offset,
+            index_size,
+        )));
+    }
+    let index = ProtosContainerIndex::parse_from_bytes(
+        &data[index_offset as usize..body_end as usize],
+    )?;
+    index.entries
+        .into_iter()
+        .map(|entry| {
+            let end = entry.offset.checked_add(entry.size);
+            if entry.offset < HEADER_SIZE as u64
+                || end.is_none_or(|end| end > index_offset)
+            {
+                return Err(Error::InvalidData(format!(
+                    "file out of bounds: {}",
+                    entry.path,
+                )));
+            }
+            let start = entry.offset as usize;
+            Ok((entry.path, start..start + entry.size as usize))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::proto::{
+        SerializeOptions,
+        serialize_database_with_options,
+    };
+    use crate::db::fixtures::{build_and_store, build_database};
+    use crate::db::manifest::{CURRENT, current_database};
+    use crate::db::stored::{Database, LoadDatabase};
+    use crate::io::LocalFileSystem;
+
+    #[test]
+    fn database_should_be_loaded_from_container() {
+        let (db_dir, header) = build_and_store(100, 4);
+        let fs = LocalFileSystem::new(db_dir.path());
+        let dir = tempfile::tempdir().unwrap();
+        let container_path = dir.path().join("db.fdbc");
+        let num_files = write_container(
+            &fs,
+            std::fs::File::create(&container_path).unwrap(),
+        ).unwrap();
+        // database, partition centroids, 2 partitions, 2 codebooks, and 2
+        // attributes logs
+        assert_eq!(num_files, 8);
+
+        let container = ContainerFileSystem::open(&container_path).unwrap();
+        assert_eq!(container.list_files("").unwrap().len(), 8);
+        assert_eq!(container.list_files("codebooks").unwrap().len(), 2);
+        assert!(container.list_files("codebook").unwrap().is_empty());
+        let stored = Database::<f32, _>::load_database(container, &header)
+            .unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let results = stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        stored.get_attribute(&results[0].vector_id, "datum_id").unwrap();
+    }
+
+    #[test]
+    fn current_database_should_be_loaded_from_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_dir = dir.path().join("db");
+        let mut fs = LocalFileSystem::new(&db_dir);
+        serialize_database_with_options(
+            &build_database(100, 4),
+            &mut fs,
+            &SerializeOptions::new().with_publishing(true),
+        ).unwrap();
+        let container_path = dir.path().join("db.fdbc");
+        write_container(
+            &fs,
+            std::fs::File::create(&container_path).unwrap(),
+        ).unwrap();
+
+        let container = ContainerFileSystem::open(&container_path).unwrap();
+        assert_eq!(
+            current_database(&container).unwrap(),
+            current_database(&fs).unwrap(),
+        );
+        assert!(container.read_pointer("MISSING").unwrap().is_none());
+        assert!(container.list_files("").unwrap().contains(&CURRENT.into()));
+        let stored = Database::<f32, _>::load_current_database(container)
+            .unwrap();
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn container_file_should_be_verified_with_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path().join("files"));
+        std::fs::create_dir(dir.path().join("files")).unwrap();
+        let hash = HashAlgorithm::Sha256.hash(b"0123456789").unwrap();
+        std::fs::write(
+            dir.path().join("files").join(format!("{}.bin", hash)),
+            b"0123456789",
+        ).unwrap();
+        std::fs::write(dir.path().join("files").join("AAAA.bin"), b"012")
+            .unwrap();
+        let container_path = dir.path().join("files.fdbc");
+        write_container(
+            &fs,
+            std::fs::File::create(&container_path).unwrap(),
+        ).unwrap();
+        let container = ContainerFileSystem::open(&container_path).unwrap();
+        let mut f = container
+            .open_hashed_file(format!("{}.bin", hash))
+            .unwrap();
+        assert_eq!(f.size(), Some(10));
+        let mut buf = [0u8; 4];
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"0123");
+        assert_eq!(f.read_in_place().unwrap(), b"456789");
+        f.verify().unwrap();
+        let f = container.open_hashed_file("AAAA.bin").unwrap();
+        assert!(matches!(f.verify(), Err(Error::VerificationFailure(_))));
+        assert!(matches!(
+            container.open_hashed_file("BBBB.bin"),
+            Err(Error::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound,
+        ));
+        assert!(ContainerFileSystem::open(
+            dir.path().join("files").join("AAAA.bin"),
+        ).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+//! Encryption at rest.
+//!
+//! [`EncryptedFileSystem`] wraps a file system and encrypts the contents of
+//! hashed files with AES-256-GCM under a key that the application supplies,
+//! so that a database can be stored on shared storage without exposing the
+//! vectors or attributes.
+//!
+//! An encrypted file starts with a header of a magic number, a version, and
+//! a random nonce prefix, followed by segments of up to
+//! [`SEGMENT_SIZE`] bytes of plaintext, each of which is sealed with its own
+//! authentication tag. The nonce of a segment consists of the prefix, the
+//! index of the segment, and a flag of the last segment, so segments cannot
+//! be reordered, dropped, or truncated without being detected.
+//!
+//! Files are named after the hash of the encrypted contents, which the
+//! wrapped file system verifies as usual.
+
+use ring::aead::{
+    AES_256_GCM,
+    Aad,
+    LessSafeKey,
+    MAX_TAG_LEN,
+    NONCE_LEN,
+    Nonce,
+    UnboundKey,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use crate::error::Error;
+
+use super::hash::HashAlgorithm;
+use super::{HashedFileIn, HashedFileOut, ReadFileSystem, WriteFileSystem};
+
+/// Size of a key in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// Maximum number of bytes of plaintext in a segment.
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+
+// Magic number of an encrypted file.
+const MAGIC: &[u8; 4] = b"FENC";
+// Version of the format.
+const VERSION: u8 = 1;
+// Size of the random part of nonces.
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 5;
+// Size of the header.
+pub(crate) const HEADER_SIZE: usize = MAGIC.len() + 1 + NONCE_PREFIX_LEN;
+// Size of an authentication tag.
+const TAG_LEN: usize = MAX_TAG_LEN;
+// Maximum size of a sealed segment.
+pub(crate) const SEALED_SEGMENT_SIZE: usize = SEGMENT_SIZE + TAG_LEN;
+
+/// File system that encrypts the contents of files in another file system.
+///
+/// Listings and deletions are passed to the wrapped file system as they
+/// are. Reading a file fails with `Error::VerificationFailure` if it was
+/// encrypted with another key or has been tampered with.
+pub struct EncryptedFileSystem<FS> {
+    inner: FS,
+    key: Arc<LessSafeKey>,
+}
+
+impl<FS> EncryptedFileSystem<FS> {
+    /// Wraps a given file system encrypting files with a given key.
+    pub fn new(inner: FS, key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            inner,
+            key: new_key(key),
+        }
+    }
+
+    /// Returns the wrapped file system.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+}
+
+impl<FS> ReadFileSystem for EncryptedFileSystem<FS>
+where
+    FS: ReadFileSystem,
+{
+    type HashedFileIn = EncryptedHashedFileIn<FS::HashedFileIn>;
+
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let file = self.inner.open_hashed_file(path)?;
+        EncryptedHashedFileIn::new(file, self.key.clone())
+    }
+
+    fn open_hashed_file_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let file = self.inner.open_hashed_file_with(path, algorithm)?;
+        EncryptedHashedFileIn::new(file, self.key.clone())
+    }
+
+    fn open_hashed_file_unverified(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let file = self.inner.open_hashed_file_unverified(path)?;
+        EncryptedHashedFileIn::new(file, self.key.clone())
+    }
+
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        self.inner.list_files(prefix)
+    }
+
+    /// Pointers are not encrypted because they only name files.
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        self.inner.read_pointer(path)
+    }
+}
+
+impl<FS> WriteFileSystem for EncryptedFileSystem<FS>
+where
+    FS: WriteFileSystem,
+{
+    type HashedFileOut = EncryptedHashedFileOut<FS::HashedFileOut>;
+
+    fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        let file = self.inner.create_hashed_file()?;
+        EncryptedHashedFileOut::new(file, self.key.clone())
+    }
+
+    fn create_hashed_file_in(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileOut, Error> {
+        let file = self.inner.create_hashed_file_in(path)?;
+        EncryptedHashedFileOut::new(file, self.key.clone())
+    }
+
+    fn create_hashed_file_in_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        let file = self.inner.create_hashed_file_in_with(path, algorithm)?;
+        EncryptedHashedFileOut::new(file, self.key.clone())
+    }
+
+    fn delete(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        self.inner.delete(path)
+    }
+
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        self.inner.write_pointer(path, target)
+    }
+}
+
+/// Hashed file that encrypts its contents.
+///
+/// Plaintext is buffered until a segment fills, so flushing does not write
+/// a partial segment; persisting the file does.
+pub struct EncryptedHashedFileOut<W> {
+    file: W,
+    sealer: Sealer,
+    // Plaintext of the current segment.
+    segment: Vec<u8>,
+}
+
+impl<W> EncryptedHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    // Wraps a given file and writes the header.
+    fn new(mut file: W, key: Arc<LessSafeKey>) -> Result<Self, Error> {
+        let sealer = Sealer::new(key)?;
+        file.write_all(sealer.header())?;
+        Ok(Self {
+            file,
+            sealer,
+            segment: Vec::with_capacity(SEALED_SEGMENT_SIZE),
+        })
+    }
+}
+
+impl<W> Write for EncryptedHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.segment.len() == SEGMENT_SIZE {
+            // the segment is not the last because more bytes follow
+            self.sealer
+                .seal(&mut self.segment, false)
+                .map_err(std::io::Error::other)?;
+            self.file.write_all(&self.segment)?;
+            self.segment.clear();
+        }
+        let n = buf.len().min(SEGMENT_SIZE - self.segment.len());
+        self.segment.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<W> HashedFileOut for EncryptedHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    fn persist(mut self, extension: impl AsRef<str>) -> Result<String, Error> {
+        self.sealer.seal(&mut self.segment, true)?;
+        self.file.write_all(&self.segment)?;
+        self.file.flush()?;
+        self.file.persist(extension)
+    }
+}
+
+/// Hashed file that decrypts its contents.
+///
+/// Bytes of a segment are not returned until the whole segment has been
+/// authenticated.
+pub struct EncryptedHashedFileIn<R> {
+    file: R,
+    opener: Opener,
+    // Plaintext of the current segment.
+    segment: Vec<u8>,
+    // Position of the next byte to return in the current segment.
+    pos: usize,
+    // First byte of the next segment, read to tell the last segment.
+    lookahead: Option<u8>,
+    // Whether the last segment has been decrypted.
+    finished: bool,
+}
+
+impl<R> EncryptedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    // Wraps a given file and reads the header.
+    fn new(mut file: R, key: Arc<LessSafeKey>) -> Result<Self, Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        Ok(Self {
+            file,
+            opener: Opener::new(key, header)?,
+            segment: Vec::with_capacity(SEALED_SEGMENT_SIZE + 1),
+            pos: 0,
+            lookahead: None,
+            finished: false,
+        })
+    }
+
+    // Reads and decrypts the next segment.
+    fn read_next_segment(&mut self) -> Result<(), Error> {
+        self.segment.clear();
+        self.pos = 0;
+        self.segment.extend(self.lookahead.take());
+        let limit = SEALED_SEGMENT_SIZE + 1 - self.segment.len();
+        (&mut self.file).take(limit as u64).read_to_end(&mut self.segment)?;
+        let last = self.segment.len() <= SEALED_SEGMENT_SIZE;
+        if !last {
+            self.lookahead = self.segment.pop();
+        }
+        if let Err(e) = self.opener.open(&mut self.segment, last) {
+            // never returns unauthenticated bytes
+            self.segment.clear();
+            return Err(e);
+        }
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R> Read for EncryptedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.segment.len() && !self.finished {
+            self.read_next_segment().map_err(std::io::Error::other)?;
+        }
+        let n = buf.len().min(self.segment.len() - self.pos);
+        buf[..n].copy_from_slice(&self.segment[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R> HashedFileIn for EncryptedHashedFileIn<R>
+where
+    R: HashedFileIn,
+{
+    /// Fails with `Error::VerificationFailure` if the last segment has not
+    /// been read, or the encrypted contents do not match the hash.
+    fn verify(self) -> Result<(), Error> {
+        if !self.finished {
+            return Err(Error::VerificationFailure(
+                "encrypted file has not been entirely read".to_string(),
+            ));
+        }
+        self.file.verify()
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.file.size().and_then(plaintext_size)
+    }
+}
+
+// Creates a key from given bytes.
+pub(crate) fn new_key(key: &[u8; KEY_LEN]) -> Arc<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, key)
+        .expect("key must have the length of AES-256");
+    Arc::new(LessSafeKey::new(key))
+}
+
+// Returns the size of the plaintext of an encrypted file of a given size.
+//
+// `None` if the size is impossible.
+pub(crate) fn plaintext_size(size: u64) -> Option<u64> {
+    let sealed = size.checked_sub(HEADER_SIZE as u64)?;
+    let num_segments = sealed.div_ceil(SEALED_SEGMENT_SIZE as u64).max(1);
+    sealed.checked_sub(num_segments * TAG_LEN as u64)
+}
+
+// Seals segments of a file.
+pub(crate) struct Sealer {
+    key: Arc<LessSafeKey>,
+    header: [u8; HEADER_SIZE],
+    // Index of the next segment.
+    index: u32,
+}
+
+impl Sealer {
+    // Starts a file with a random nonce prefix.
+    pub(crate) fn new(key: Arc<LessSafeKey>) -> Result<Self, Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = VERSION;
+        SystemRandom::new()
+            .fill(&mut header[MAGIC.len() + 1..])
+            .map_err(|_| Error::InvalidContext(
+                "failed to generate a nonce".to_string(),
+            ))?;
+        Ok(Self { key, header, index: 0 })
+    }
+
+    // Returns the header of the file.
+    pub(crate) fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    // Encrypts a segment in place and appends the tag.
+    pub(crate) fn seal(
+        &mut self,
+        segment: &mut Vec<u8>,
+        last: bool,
+    ) -> Result<(), Error> {
+        let nonce = segment_nonce(&self.header, self.index, last);
+        self.index = self.index.checked_add(1).ok_or(Error::InvalidContext(
+            "too many segments in an encrypted file".to_string(),
+        ))?;
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(&self.header), segment)
+            .map_err(|_| Error::InvalidContext(
+                "failed to encrypt a segment".to_string(),
+            ))
+    }
+}
+
+// Opens segments of a file.
+pub(crate) struct Opener {
+    key: Arc<LessSafeKey>,
+    header: [u8; HEADER_SIZE],
+    // Index of the next segment.
+    index: u32,
+}
+
+impl Opener {
+    // Starts reading a file with a given header.
+    //
+    // Fails with `Error::InvalidData` if the header is not of an encrypted
+    // file of a known version.
+    pub(crate) fn new(
+        key: Arc<LessSafeKey>,
+        header: [u8; HEADER_SIZE],
+    ) -> Result<Self, Error> {
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidData(
+                "file is not encrypted".to_string(),
+            ));
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(Error::InvalidData(format!(
+                "unknown version of encrypted file: {}",
+                header[MAGIC.len()],
+            )));
+        }
+        Ok(Self { key, header, index: 0 })
+    }
+
+    // Decrypts a sealed segment in place and removes the tag.
+    //
+    // Fails with `Error::VerificationFailure` if the segment cannot be
+    // authenticated.
+    pub(crate) fn open(
+        &mut self,
+        segment: &mut Vec<u8>,
+        last: bool,
+    ) -> Result<(), Error> {
+        let index = self.index;
+        let nonce = segment_nonce(&self.header, index, last);
+        let len = self.key
+            .open_in_place(nonce, Aad::from(&self.header), segment)
+            .map_err(|_| Error::VerificationFailure(format!(
+                "failed to decrypt segment {}; wrong key or corrupted file",
+                index,
+            )))?
+            .len();
+        segment.truncate(len);
+        self.index = index.checked_add(1).ok_or(Error::InvalidData(
+            "too many segments in an encrypted file".to_string(),
+        ))?;
+        Ok(())
+    }
+}
+
+// Derives the nonce of a segment.
+fn segment_nonce(header: &[u8; HEADER_SIZE], index: u32, last: bool) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(&header[MAGIC.len() + 1..]);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1]
+        .copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::proto::serialize_database;
+    use crate::db::fixtures::{build_database, header_path};
+    use crate::db::stored::{Database, LoadDatabase};
+    use crate::io::LocalFileSystem;
+
+    fn data() -> Vec<u8> {
+        (0..(2 * SEGMENT_SIZE + 100)).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn encrypted_file_should_be_decrypted_with_same_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = EncryptedFileSystem::new(
+            LocalFileSystem::new(dir.path()),
+            &[1u8; KEY_LEN],
+        );
+        for data in [Vec::new(), vec![0u8; SEGMENT_SIZE], data()] {
+            let mut f = fs.create_hashed_file().unwrap();
+            f.write_all(&data).unwrap();
+            let id = f.persist("bin").unwrap();
+            let path = format!("{}.bin", id);
+            let stored = std::fs::read(dir.path().join(&path)).unwrap();
+            assert_eq!(
+                plaintext_size(stored.len() as u64),
+                Some(data.len() as u64),
+            );
+            let mut f = fs.open_hashed_file(&path).unwrap();
+            let mut contents = Vec::new();
+            f.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, data);
+            f.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn encrypted_file_should_not_be_decrypted_with_another_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = EncryptedFileSystem::new(
+            LocalFileSystem::new(dir.path()),
+            &[1u8; KEY_LEN],
+        );
+        let mut f = fs.create_hashed_file().unwrap();
+        f.write_all(&data()).unwrap();
+        let path = format!("{}.bin", f.persist("bin").unwrap());
+        let another = EncryptedFileSystem::new(
+            LocalFileSystem::new(dir.path()),
+            &[2u8; KEY_LEN],
+        );
+        let mut f = another.open_hashed_file(&path).unwrap();
+        let mut contents = Vec::new();
+        assert!(f.read_to_end(&mut contents).is_err());
+        assert!(contents.is_empty());
+        // drops the last segment
+        let stored = std::fs::read(dir.path().join(&path)).unwrap();
+        std::fs::write(
+            dir.path().join(&path),
+            &stored[..HEADER_SIZE + 2 * SEALED_SEGMENT_SIZE],
+        ).unwrap();
+        let mut f = fs.open_hashed_file_unverified(&path).unwrap();
+        let mut contents = Vec::new();
+        assert!(f.read_to_end(&mut contents).is_err());
+        assert_eq!(contents.len(), SEGMENT_SIZE);
+    }
+
+    #[test]
+    fn database_should_load_from_encrypted_file_system() {
+        let db = build_database(100, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let key = [7u8; KEY_LEN];
+        let mut fs =
+            EncryptedFileSystem::new(LocalFileSystem::new(dir.path()), &key);
+        serialize_database(&db, &mut fs).unwrap();
+        let path = header_path(dir.path());
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let stored =
+            Database::<f32, _>::load_database(fs, &path).unwrap();
+        let results = stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        stored.get_attribute(&results[0].vector_id, "datum_id").unwrap();
+        assert!(Database::<f32, _>::load_database(
+            LocalFileSystem::new(dir.path()),
+            &path,
+        ).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Content hashes of files.
+//!
+//! A hashed file is named after the URL-safe Base64 encoded hash of its
+//! contents. SHA-256 is always available. BLAKE3, which is several times
+//! faster to verify, is available with the `blake3` feature.
+
+use base64::{
+    Engine,
+    engine::general_purpose::{URL_SAFE_NO_PAD as base64_engine},
+};
+
+use crate::error::Error;
+
+/// Algorithm of the content hash of files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    /// SHA-256, which every file system supports.
+    #[default]
+    Sha256,
+    /// BLAKE3.
+    ///
+    /// Available with the `blake3` feature.
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Returns if the algorithm is available in this build.
+    pub fn is_available(&self) -> bool {
+        match self {
+            HashAlgorithm::Sha256 => true,
+            HashAlgorithm::Blake3 => cfg!(feature = "blake3"),
+        }
+    }
+
+    /// Hashes given bytes and encodes the hash in URL-safe Base64.
+    ///
+    /// Fails with `Error::InvalidContext` if the algorithm is not available.
+    pub fn hash(&self, data: &[u8]) -> Result<String, Error> {
+        let mut hasher = Hasher::new(*self)?;
+        hasher.update(data);
+        Ok(hasher.finish())
+    }
+
+    // Fails with `Error::InvalidContext` unless the algorithm is available.
+    pub(crate) fn check_available(&self) -> Result<(), Error> {
+        if self.is_available() {
+            Ok(())
+        } else {
+            Err(Error::InvalidContext(format!(
+                "{:?} hash requires the blake3 feature",
+                self,
+            )))
+        }
+    }
+
+    // Fails with `Error::InvalidContext` unless the algorithm is SHA-256.
+    //
+    // Used by file systems that support only SHA-256.
+    pub(crate) fn check_sha256(&self) -> Result<(), Error> {
+        if *self == HashAlgorithm::Sha256 {
+            Ok(())
+        } else {
+            Err(Error::InvalidContext(format!(
+                "file system does not support {:?} hash",
+                self,
+            )))
+        }
+    }
+}
+
+// Incremental hasher of any algorithm.
+#[derive(Clone)]
+pub(crate) enum Hasher {
+    Sha256(Box<ring::digest::Context>),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    // Creates a hasher of a given algorithm.
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Result<Self, Error> {
+        algorithm.check_available()?;
+        match algorithm {
+            HashAlgorithm::Sha256 => Ok(Hasher::sha256()),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => {
+                Ok(Hasher::Blake3(Box::new(blake3::Hasher::new())))
+            },
+            #[cfg(not(feature = "blake3"))]
+            HashAlgorithm::Blake3 => {
+                unreachable!("algorithm must be available")
+            },
+        }
+    }
+
+    // Creates an SHA-256 hasher.
+    pub(crate) fn sha256() -> Self {
+        Hasher::Sha256(Box::new(
+            ring::digest::Context::new(&ring::digest::SHA256),
+        ))
+    }
+
+    // Feeds bytes.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(context) => context.update(data),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            },
+        }
+    }
+
+    // Finishes the hash and encodes it in URL-safe Base64.
+    pub(crate) fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(context) => base64_engine.encode(context.finish()),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => {
+                base64_engine.encode(hasher.finalize().as_bytes())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_hash_should_match_ring() {
+        let expected = base64_engine.encode(
+            ring::digest::digest(&ring::digest::SHA256, b"0123456789"),
+        );
+        assert_eq!(
+            HashAlgorithm::Sha256.hash(b"0123456789").unwrap(),
+            expected,
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_hash_should_differ_from_sha256() {
+        let blake3 = HashAlgorithm::Blake3.hash(b"0123456789").unwrap();
+        assert_eq!(
+            blake3,
+            base64_engine.encode(blake3::hash(b"0123456789").as_bytes()),
+        );
+        assert_ne!(blake3, HashAlgorithm::Sha256.hash(b"0123456789").unwrap());
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn database_should_load_with_blake3_hashes() {
+        use crate::db::build::proto::SerializeOptions;
+        use crate::db::fixtures::{build_database, store_database};
+        use crate::db::stored::{Database, LoadDatabase, LoadResidues};
+        use crate::io::LocalFileSystem;
+
+        let db = build_database(100, 4);
+        let options = SerializeOptions::new()
+            .with_residues(true)
+            .with_hash_algorithm(HashAlgorithm::Blake3);
+        let (dir, header) = store_database(&db, &options);
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let stored = Database::<f32, _>::load_database(
+            LocalFileSystem::new(dir.path()),
+            &header,
+        ).unwrap();
+        assert_eq!(stored.hash_algorithm(), HashAlgorithm::Blake3);
+        let results = stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        stored.get_attribute(&results[0].vector_id, "datum_id").unwrap();
+        stored.load_residues(0).unwrap();
+        #[cfg(feature = "mmap")]
+        {
+            use crate::io::mmap::MmapFileSystem;
+
+            let stored = Database::<f32, _>::load_database(
+                MmapFileSystem::new(dir.path()),
+                &header,
+            ).unwrap();
+            stored
+                .query(
+                    &query[..],
+                    5.try_into().unwrap(),
+                    2.try_into().unwrap(),
+                )
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,710 @@
+//! Merkle trees over chunks of files.
+//!
+//! A hashed file can be verified only after it has been entirely read.
+//! [`MerkleTree`] hashes fixed-size chunks of a file instead, so that a
+//! chunk fetched by a ranged read can be verified on its own; either
+//! against the leaf hashes of the tree stored alongside the file, or against
+//! the root hash with a proof of `O(log n)` sibling hashes. A database
+//! serialized with chunk digests records the trees of its files in the
+//! header, and [`ChunkVerifiedFileIn`] verifies a file chunk by chunk.
+//!
+//! Leaves and internal nodes are hashed with distinct prefixes so that an
+//! internal node cannot be passed off as a chunk. A node without a sibling
+//! is promoted to the next level as it is.
+
+use base64::{
+    Engine,
+    engine::general_purpose::{URL_SAFE_NO_PAD as base64_engine},
+};
+use core::ops::Range;
+use ring::digest::{Context, SHA256, SHA256_OUTPUT_LEN};
+use std::io::{Read, Write};
+use std::num::NonZeroUsize;
+
+use crate::error::Error;
+use crate::protos::{Deserialize, Serialize};
+use crate::protos::database::MerkleTree as ProtosMerkleTree;
+
+use super::{HashedFileIn, HashedFileOut};
+
+/// Digest of a node in a Merkle tree.
+pub type Digest = [u8; SHA256_OUTPUT_LEN];
+
+/// Default chunk size in bytes; i.e., 1 MiB.
+pub const DEFAULT_CHUNK_SIZE: NonZeroUsize =
+    NonZeroUsize::new(1024 * 1024).unwrap();
+
+// Prefix of a leaf hash.
+const LEAF_PREFIX: u8 = 0x00;
+// Prefix of an internal node hash.
+const NODE_PREFIX: u8 = 0x01;
+
+/// Merkle tree over fixed-size chunks of a file.
+///
+/// A file has at least one chunk; an empty file consists of an empty chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTree {
+    // Chunk size in bytes.
+    chunk_size: usize,
+    // File size in bytes.
+    file_size: u64,
+    // Hashes of the chunks.
+    leaves: Vec<Digest>,
+}
+
+impl MerkleTree {
+    /// Builds a tree over the contents of a given reader.
+    pub fn from_reader<R>(
+        mut read: R,
+        chunk_size: NonZeroUsize,
+    ) -> Result<Self, Error>
+    where
+        R: Read,
+    {
+        let mut hasher = MerkleHasher::new(chunk_size);
+        std::io::copy(&mut read, &mut hasher)?;
+        Ok(hasher.finish())
+    }
+
+    /// Returns the chunk size in bytes.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Returns the file size in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Returns the number of chunks.
+    pub fn num_chunks(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Returns the byte range of a given chunk in the file.
+    ///
+    /// `None` if `index` is out of bounds.
+    pub fn chunk_range(&self, index: usize) -> Option<Range<u64>> {
+        if index < self.leaves.len() {
+            let start = index as u64 * self.chunk_size as u64;
+            let end = (start + self.chunk_size as u64).min(self.file_size);
+            Some(start..end)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the root hash.
+    pub fn root(&self) -> Digest {
+        let mut level = self.leaves.clone();
+        while level.len() > 1 {
+            level = next_level(&level);
+        }
+        level[0]
+    }
+
+    /// Returns the root hash as a URL-safe Base64 encoded string.
+    ///
+    /// Suitable for the name of the file.
+    pub fn root_hash(&self) -> String {
+        base64_engine.encode(self.root())
+    }
+
+    /// Returns the sibling hashes from a given leaf to the root.
+    ///
+    /// Levels where the node has no sibling are skipped.
+    ///
+    /// `None` if `index` is out of bounds.
+    pub fn proof(&self, index: usize) -> Option<Vec<Digest>> {
+        if index >= self.leaves.len() {
+            return None;
+        }
+        let mut proof: Vec<Digest> = Vec::new();
+        let mut level = self.leaves.clone();
+        let mut i = index;
+        while level.len() > 1 {
+            let sibling = i ^ 1;
+            if sibling < level.len() {
+                proof.push(level[sibling]);
+            }
+            level = next_level(&level);
+            i /= 2;
+        }
+        Some(proof)
+    }
+
+    /// Verifies a chunk against the leaf hash.
+    ///
+    /// Fails with `Error::InvalidArgs` if `index` is out of bounds, or with
+    /// `Error::VerificationFailure` if the chunk does not match.
+    pub fn verify_chunk(
+        &self,
+        index: usize,
+        chunk: &[u8],
+    ) -> Result<(), Error> {
+        let leaf = self.leaves.get(index).ok_or(Error::InvalidArgs(format!(
+            "chunk index out of bounds: {} ≥ {}",
+            index,
+            self.leaves.len(),
+        )))?;
+        if hash_leaf(chunk) == *leaf {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "hash discrepancy in chunk {}",
+                index,
+            )))
+        }
+    }
+
+    /// Verifies that the tree has a given root hash.
+    ///
+    /// Leaf hashes of a tree stored alongside a file can be trusted once the
+    /// root hash, e.g., the file name, is verified.
+    ///
+    /// Fails with `Error::VerificationFailure` if the root hash does not
+    /// match.
+    pub fn verify_root_hash(&self, root_hash: &str) -> Result<(), Error> {
+        let hash = self.root_hash();
+        if hash == root_hash {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "root hash discrepancy: expected {} but got {}",
+                root_hash,
+                hash,
+            )))
+        }
+    }
+}
+
+/// Verifies a chunk against a root hash with a proof.
+///
+/// `proof` is supposed to be given by [`MerkleTree::proof`].
+///
+/// Fails with `Error::InvalidArgs` if `index` is out of bounds, or with
+/// `Error::VerificationFailure` if the chunk or the proof does not match.
+pub fn verify_chunk_with_proof(
+    root: &Digest,
+    num_chunks: usize,
+    index: usize,
+    chunk: &[u8],
+    proof: &[Digest],
+) -> Result<(), Error> {
+    if index >= num_chunks {
+        return Err(Error::InvalidArgs(format!(
+            "chunk index out of bounds: {} ≥ {}",
+            index,
+            num_chunks,
+        )));
+    }
+    let mut hash = hash_leaf(chunk);
+    let mut siblings = proof.iter();
+    let mut i = index;
+    let mut width = num_chunks;
+    while width > 1 {
+        let sibling = i ^ 1;
+        if sibling < width {
+            let sibling_hash = siblings.next().ok_or(
+                Error::VerificationFailure("proof is too short".to_string()),
+            )?;
+            hash = if i.is_multiple_of(2) {
+                hash_node(&hash, sibling_hash)
+            } else {
+                hash_node(sibling_hash, &hash)
+            };
+        }
+        i /= 2;
+        width = width.div_ceil(2);
+    }
+    if siblings.next().is_some() {
+        return Err(Error::VerificationFailure(
+            "proof is too long".to_string(),
+        ));
+    }
+    if hash == *root {
+        Ok(())
+    } else {
+        Err(Error::VerificationFailure(format!(
+            "hash discrepancy in chunk {}",
+            index,
+        )))
+    }
+}
+
+/// Calculates a [`MerkleTree`] of bytes written to it.
+pub struct MerkleHasher {
+    // Chunk size in bytes.
+    chunk_size: usize,
+    // Number of bytes written so far.
+    file_size: u64,
+    // Hash of the current chunk.
+    context: Context,
+    // Number of bytes in the current chunk.
+    chunk_len: usize,
+    // Hashes of the finished chunks.
+    leaves: Vec<Digest>,
+}
+
+impl MerkleHasher {
+    /// Creates a hasher of chunks of a given size.
+    pub fn new(chunk_size: NonZeroUsize) -> Self {
+        Self {
+            chunk_size: chunk_size.get(),
+            file_size: 0,
+            context: leaf_context(),
+            chunk_len: 0,
+            leaves: Vec::new(),
+        }
+    }
+
+    /// Hashes given bytes.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.file_size += data.len() as u64;
+        while !data.is_empty() {
+            let n = (self.chunk_size - self.chunk_len).min(data.len());
+            self.context.update(&data[..n]);
+            self.chunk_len += n;
+            data = &data[n..];
+            if self.chunk_len == self.chunk_size {
+                let context = std::mem::replace(
+                    &mut self.context,
+                    leaf_context(),
+                );
+                self.leaves.push(to_digest(context));
+                self.chunk_len = 0;
+            }
+        }
+    }
+
+    /// Finishes the tree.
+    pub fn finish(mut self) -> MerkleTree {
+        if self.chunk_len > 0 || self.leaves.is_empty() {
+            self.leaves.push(to_digest(self.context));
+        }
+        MerkleTree {
+            chunk_size: self.chunk_size,
+            file_size: self.file_size,
+            leaves: self.leaves,
+        }
+    }
+}
+
+impl Write for MerkleHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashed file that also calculates a [`MerkleTree`] of its contents.
+pub struct MerkleHashedFileOut<W> {
+    file: W,
+    hasher: MerkleHasher,
+}
+
+impl<W> MerkleHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    /// Wraps a given hashed file.
+    pub fn new(file: W, chunk_size: NonZeroUsize) -> Self {
+        Self {
+            file,
+            hasher: MerkleHasher::new(chunk_size),
+        }
+    }
+
+    /// Persists the file and returns the hash and the Merkle tree.
+    ///
+    /// You should flush the stream before calling this function.
+    pub fn persist_with_tree(
+        self,
+        extension: impl AsRef<str>,
+    ) -> Result<(String, MerkleTree), Error> {
+        let hash = self.file.persist(extension)?;
+        Ok((hash, self.hasher.finish()))
+    }
+}
+
+impl<W> Write for MerkleHashedFileOut<W>
+where
+    W: HashedFileOut,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Hashed file verified chunk by chunk with a [`MerkleTree`].
+///
+/// Bytes of a chunk are not returned until the whole chunk has been read and
+/// verified against its leaf hash, so a corrupted file fails at the first
+/// corrupted chunk rather than after it has been entirely read.
+///
+/// The tree is supposed to come from a trusted source; e.g., a verified
+/// database header. [`HashedFileIn::verify`] checks that the file has no
+/// bytes beyond the tree.
+pub struct ChunkVerifiedFileIn<R> {
+    file: R,
+    tree: MerkleTree,
+    // Current chunk.
+    chunk: Vec<u8>,
+    // Position of the next byte to return in the current chunk.
+    pos: usize,
+    // Index of the next chunk to read.
+    next_index: usize,
+    // Number of bytes read from the file.
+    read_size: u64,
+}
+
+impl<R> ChunkVerifiedFileIn<R>
+where
+    R: Read,
+{
+    /// Wraps a given file with the Merkle tree of the contents.
+    ///
+    /// `file` does not have to hash the contents.
+    pub fn new(file: R, tree: MerkleTree) -> Self {
+        Self {
+            file,
+            tree,
+            chunk: Vec::new(),
+            pos: 0,
+            next_index: 0,
+            read_size: 0,
+        }
+    }
+
+    // Reads and verifies the next chunk.
+    //
+    // The current chunk becomes empty at the end of the tree.
+    fn read_next_chunk(&mut self) -> Result<(), Error> {
+        let remaining = self.tree.file_size - self.read_size;
+        let len = remaining.min(self.tree.chunk_size as u64) as usize;
+        self.chunk.resize(len, 0u8);
+        self.pos = 0;
+        if len == 0 {
+            return Ok(());
+        }
+        let result = self.file
+            .read_exact(&mut self.chunk)
+            .map_err(Error::from)
+            .and_then(|_| self.tree.verify_chunk(self.next_index, &self.chunk));
+        if let Err(e) = result {
+            // never returns unverified bytes
+            self.chunk.clear();
+            return Err(e);
+        }
+        self.read_size += len as u64;
+        self.next_index += 1;
+        Ok(())
+    }
+}
+
+impl<R> Read for ChunkVerifiedFileIn<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            self.read_next_chunk().map_err(std::io::Error::other)?;
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R> HashedFileIn for ChunkVerifiedFileIn<R>
+where
+    R: Read,
+{
+    /// Fails with `Error::VerificationFailure` if the file has not been
+    /// entirely read, or has extra bytes.
+    fn verify(mut self) -> Result<(), Error> {
+        if self.tree.file_size == 0 {
+            self.tree.verify_chunk(0, &[])?;
+        }
+        if self.read_size != self.tree.file_size {
+            return Err(Error::VerificationFailure(format!(
+                "read only {} of {} bytes",
+                self.read_size,
+                self.tree.file_size,
+            )));
+        }
+        if self.file.read(&mut [0u8; 1])? != 0 {
+            return Err(Error::VerificationFailure(format!(
+                "file is larger than {} bytes",
+                self.tree.file_size,
+            )));
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.tree.file_size)
+    }
+}
+
+impl Serialize<ProtosMerkleTree> for MerkleTree {
+    fn serialize(&self) -> Result<ProtosMerkleTree, Error> {
+        let mut tree = ProtosMerkleTree::new();
+        tree.chunk_size = self.chunk_size as u32;
+        tree.file_size = self.file_size;
+        tree.leaves = self.leaves.iter().map(|leaf| leaf.to_vec()).collect();
+        Ok(tree)
+    }
+}
+
+impl Deserialize<MerkleTree> for ProtosMerkleTree {
+    fn deserialize(self) -> Result<MerkleTree, Error> {
+        if self.chunk_size == 0 {
+            return Err(Error::InvalidData(
+                "chunk size must not be zero".to_string(),
+            ));
+        }
+        let chunk_size = self.chunk_size as usize;
+        let num_chunks = self.file_size.div_ceil(chunk_size as u64).max(1);
+        if self.leaves.len() as u64 != num_chunks {
+            return Err(Error::InvalidData(format!(
+                "number of leaves {} does not match number of chunks {}",
+                self.leaves.len(),
+                num_chunks,
+            )));
+        }
+        let leaves = self.leaves
+            .into_iter()
+            .map(|leaf| leaf.try_into().or(Err(Error::InvalidData(
+                "leaf hash must be 32 bytes".to_string(),
+            ))))
+            .collect::<Result<_, _>>()?;
+        Ok(MerkleTree {
+            chunk_size,
+            file_size: self.file_size,
+            leaves,
+        })
+    }
+}
+
+// Starts hashing a leaf.
+fn leaf_context() -> Context {
+    let mut context = Context::new(&SHA256);
+    context.update(&[LEAF_PREFIX]);
+    context
+}
+
+fn to_digest(context: Context) -> Digest {
+    context.finish().as_ref().try_into().unwrap()
+}
+
+fn hash_leaf(chunk: &[u8]) -> Digest {
+    let mut context = leaf_context();
+    context.update(chunk);
+    to_digest(context)
+}
+
+// Hashes pairs of nodes at a level of a tree.
+fn next_level(level: &[Digest]) -> Vec<Digest> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn hash_node(left: &Digest, right: &Digest) -> Digest {
+    let mut context = Context::new(&SHA256);
+    context.update(&[NODE_PREFIX]);
+    context.update(left);
+    context.update(right);
+    to_digest(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::proto::SerializeOptions;
+    use crate::db::fixtures::{build_database, store_database};
+    use crate::db::stored::{Database, LoadDatabase};
+    use crate::io::{LocalFileSystem, ReadFileSystem};
+    use crate::protos::read_message;
+    use crate::protos::database::Database as ProtosDatabase;
+
+    fn data() -> Vec<u8> {
+        (0..100u8).collect()
+    }
+
+    #[test]
+    fn merkle_tree_should_verify_every_chunk() {
+        let data = data();
+        let tree = MerkleTree::from_reader(
+            &data[..],
+            16.try_into().unwrap(),
+        ).unwrap();
+        assert_eq!(tree.num_chunks(), 7);
+        assert_eq!(tree.chunk_range(6), Some(96..100));
+        assert_eq!(tree.chunk_range(7), None);
+        let root = tree.root();
+        for i in 0..tree.num_chunks() {
+            let range = tree.chunk_range(i).unwrap();
+            let chunk = &data[range.start as usize..range.end as usize];
+            tree.verify_chunk(i, chunk).unwrap();
+            let proof = tree.proof(i).unwrap();
+            verify_chunk_with_proof(&root, 7, i, chunk, &proof).unwrap();
+        }
+    }
+
+    #[test]
+    fn merkle_tree_should_reject_tampered_chunk() {
+        let data = data();
+        let tree = MerkleTree::from_reader(
+            &data[..],
+            16.try_into().unwrap(),
+        ).unwrap();
+        let mut chunk = data[16..32].to_vec();
+        chunk[0] ^= 1;
+        assert!(matches!(
+            tree.verify_chunk(1, &chunk),
+            Err(Error::VerificationFailure(_)),
+        ));
+        let proof = tree.proof(1).unwrap();
+        assert!(matches!(
+            verify_chunk_with_proof(&tree.root(), 7, 1, &chunk, &proof),
+            Err(Error::VerificationFailure(_)),
+        ));
+        // proof of another chunk
+        let proof = tree.proof(2).unwrap();
+        assert!(verify_chunk_with_proof(
+            &tree.root(),
+            7,
+            1,
+            &data[16..32],
+            &proof,
+        ).is_err());
+    }
+
+    #[test]
+    fn merkle_tree_of_empty_file_should_have_single_chunk() {
+        let tree = MerkleTree::from_reader(
+            &[][..],
+            16.try_into().unwrap(),
+        ).unwrap();
+        assert_eq!(tree.num_chunks(), 1);
+        assert_eq!(tree.chunk_range(0), Some(0..0));
+        tree.verify_chunk(0, &[]).unwrap();
+    }
+
+    #[test]
+    fn merkle_hashed_file_out_should_calculate_tree_of_written_bytes() {
+        use crate::io::{LocalFileSystem, WriteFileSystem};
+
+        let dir = tempfile::tempdir().unwrap();
+        let fs = LocalFileSystem::new(dir.path());
+        let mut f = MerkleHashedFileOut::new(
+            fs.create_hashed_file().unwrap(),
+            16.try_into().unwrap(),
+        );
+        f.write_all(&data()).unwrap();
+        f.flush().unwrap();
+        let (hash, tree) = f.persist_with_tree("bin").unwrap();
+        assert!(dir.path().join(hash).with_extension("bin").exists());
+        let expected = MerkleTree::from_reader(
+            &data()[..],
+            16.try_into().unwrap(),
+        ).unwrap();
+        assert_eq!(tree, expected);
+    }
+
+    #[test]
+    fn chunk_verified_file_in_should_stop_at_corrupted_chunk() {
+        let data = data();
+        let tree = MerkleTree::from_reader(
+            &data[..],
+            16.try_into().unwrap(),
+        ).unwrap();
+        let mut f = ChunkVerifiedFileIn::new(&data[..], tree.clone());
+        let mut contents = Vec::new();
+        f.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+        f.verify().unwrap();
+        let mut corrupted = data.clone();
+        corrupted[40] ^= 1;
+        let mut f = ChunkVerifiedFileIn::new(&corrupted[..], tree.clone());
+        let mut contents = Vec::new();
+        assert!(f.read_to_end(&mut contents).is_err());
+        assert_eq!(contents, &data[..32]);
+        let mut extended = data.clone();
+        extended.push(0u8);
+        let mut f = ChunkVerifiedFileIn::new(&extended[..], tree);
+        f.read_to_end(&mut Vec::new()).unwrap();
+        assert!(matches!(f.verify(), Err(Error::VerificationFailure(_))));
+    }
+
+    #[test]
+    fn merkle_tree_can_be_serialized_and_deserialized() {
+        let tree = MerkleTree::from_reader(
+            &data()[..],
+            16.try_into().unwrap(),
+        ).unwrap();
+        let output = tree.serialize().unwrap().deserialize().unwrap();
+        assert_eq!(output, tree);
+        output.verify_root_hash(&tree.root_hash()).unwrap();
+    }
+
+    #[test]
+    fn database_should_verify_files_chunk_by_chunk() {
+        let db = build_database(100, 4);
+        let options = SerializeOptions::new()
+            .with_chunk_size(Some(64.try_into().unwrap()));
+        let (dir, header) = store_database(&db, &options);
+        let message: ProtosDatabase = read_message(
+            &mut LocalFileSystem::new(dir.path())
+                .open_compressed_hashed_file(&header)
+                .unwrap(),
+        ).unwrap();
+        // partitions, partition centroids, codebooks, and attributes logs
+        assert_eq!(message.chunk_trees.len(), 2 + 1 + 2 + 2);
+        for id in message.codebook_ids.iter() {
+            let tree = &message.chunk_trees[&format!("codebooks/{}.binpb", id)];
+            assert_eq!(tree.chunk_size, 64);
+        }
+        let query = [0.5f32, 0.0, -0.5, 1.0];
+        let stored = Database::<f32, _>::load_database(
+            LocalFileSystem::new(dir.path()),
+            &header,
+        ).unwrap();
+        stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap();
+        // corrupts the last chunk of every codebook
+        for entry in std::fs::read_dir(dir.path().join("codebooks")).unwrap() {
+            let codebook_path = entry.unwrap().path();
+            let mut contents = std::fs::read(&codebook_path).unwrap();
+            *contents.last_mut().unwrap() ^= 1;
+            std::fs::write(&codebook_path, contents).unwrap();
+        }
+        let stored = Database::<f32, _>::load_database(
+            LocalFileSystem::new(dir.path()),
+            &header,
+        ).unwrap();
+        assert!(stored
+            .query(&query[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Memory-mapped local file system.
+//!
+//! [`MmapFileSystem`] maps hashed files into memory instead of reading them
+//! into buffers, so that messages are parsed in place and the OS page cache
+//! manages the memory of files that are loaded repeatedly.
+
+use memmap2::Mmap;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+
+use super::hash::HashAlgorithm;
+use super::{
+    HashedFileIn,
+    LocalFileSystem,
+    LocalHashedFileOut,
+    ReadFileSystem,
+    WriteFileSystem,
+};
+
+/// File system that maps files in the local file system into memory.
+///
+/// Files are written in the same way as [`LocalFileSystem`].
+///
+/// Files must not be modified while they are mapped; hashed files are never
+/// modified once they are persisted.
+pub struct MmapFileSystem {
+    // Writes files.
+    local: LocalFileSystem,
+    base_path: PathBuf,
+}
+
+impl MmapFileSystem {
+    /// Creates a memory-mapped file system working under a given base path.
+    pub fn new(base_path: impl AsRef<Path>) -> Self {
+        Self {
+            local: LocalFileSystem::new(base_path.as_ref()),
+            base_path: base_path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl ReadFileSystem for MmapFileSystem {
+    type HashedFileIn = MmapHashedFileIn;
+
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        self.open_hashed_file_with(path, HashAlgorithm::Sha256)
+    }
+
+    fn open_hashed_file_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileIn, Error> {
+        algorithm.check_available()?;
+        MmapHashedFileIn::open(self.base_path.join(path.as_ref()), algorithm)
+    }
+
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        self.local.list_files(prefix)
+    }
+
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        self.local.read_pointer(path)
+    }
+}
+
+impl WriteFileSystem for MmapFileSystem {
+    type HashedFileOut = LocalHashedFileOut;
+
+    fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.local.create_hashed_file()
+    }
+
+    fn create_hashed_file_in(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.local.create_hashed_file_in(path)
+    }
+
+    fn create_hashed_file_in_with(
+        &self,
+        path: impl AsRef<str>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.local.create_hashed_file_in_with(path, algorithm)
+    }
+
+    fn delete(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        self.local.delete(path)
+    }
+
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        self.local.write_pointer(path, target)
+    }
+}
+
+/// Memory-mapped file in the local file system.
+///
+/// The contents are hashed all at once when verified.
+pub struct MmapHashedFileIn {
+    map: Mmap,
+    // Position of the next read.
+    pos: usize,
+    path: PathBuf,
+    algorithm: HashAlgorithm,
+}
+
+impl MmapHashedFileIn {
+    // Maps a file whose name is the hash of its contents calculated with a
+    // given algorithm.
+    fn open(path: PathBuf, algorithm: HashAlgorithm) -> Result<Self, Error> {
+        let file = std::fs::File::open(&path)?;
+        // SAFETY: hashed files are not modified once persisted, and a
+        // modified file fails verification.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(MmapHashedFileIn { map, pos: 0, path, algorithm })
+    }
+}
+
+impl Read for MmapHashedFileIn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = (&self.map[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl HashedFileIn for MmapHashedFileIn {
+    fn verify(self) -> Result<(), Error> {
+        let hash = self.algorithm.hash(&self.map)?;
+        if hash.as_str() == self.path.file_stem().unwrap_or(OsStr::new("")) {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "Expected hash {:?}, but got {}",
+                self.path.file_stem(),
+                hash,
+            )))
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.map.len() as u64)
+    }
+
+    fn read_in_place(&mut self) -> Option<&[u8]> {
+        let pos = self.pos;
+        self.pos = self.map.len();
+        Some(&self.map[pos..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use uuid::Uuid;
+
+    use crate::db::build::proto::serialize_database;
+    use crate::db::fixtures::{build_database, header_path};
+    use crate::db::stored::{Database, LoadDatabase};
+    use crate::io::{HashedFileOut, LocalFileSystem};
+
+    #[test]
+    fn mmap_hashed_file_should_be_read_in_place_and_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = MmapFileSystem::new(dir.path());
+        let mut f = fs.create_hashed_file_in("data").unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let hash = f.persist("bin").unwrap();
+        let path = format!("data/{}.bin", hash);
+
+        let mut f = fs.open_hashed_file(&path).unwrap();
+        assert_eq!(f.size(), Some(10));
+        let mut buf = [0u8; 4];
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"0123");
+        assert_eq!(f.read_in_place().unwrap(), b"456789");
+        assert_eq!(f.read(&mut buf).unwrap(), 0);
+        f.verify().unwrap();
+
+        let other = dir.path().join("data").join("AAAA.bin");
+        std::fs::write(&other, b"0123456789").unwrap();
+        let f = fs.open_hashed_file("data/AAAA.bin").unwrap();
+        assert!(matches!(f.verify(), Err(Error::VerificationFailure(_))));
+    }
+
+    #[test]
+    fn stored_database_should_be_loaded_from_mapped_files() {
+        let db = build_database(100, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = MmapFileSystem::new(dir.path());
+        serialize_database(&db, &mut fs).unwrap();
+        let path = header_path(dir.path());
+        let mapped = Database::<f32, _>::load_database(fs, &path)
+            .unwrap();
+        let local = Database::<f32, _>::load_database(
+            LocalFileSystem::new(dir.path()),
+            &path,
+        ).unwrap();
+        let v = [1.0f32, -1.0, 0.5, 0.0];
+        let expected: Vec<(Uuid, f32)> = local
+            .query(&v[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap()
+            .iter()
+            .map(|r| (r.vector_id, r.squared_distance))
+            .collect();
+        let results: Vec<(Uuid, f32)> = mapped
+            .query(&v[..], 5.try_into().unwrap(), 2.try_into().unwrap())
+            .unwrap()
+            .iter()
+            .map(|r| (r.vector_id, r.squared_distance))
+            .collect();
+        assert_eq!(results, expected);
+    }
+}
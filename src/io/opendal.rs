@@ -0,0 +1,326 @@
+//! File system on storage services supported by OpenDAL.
+//!
+//! [`OpendalFileSystem`] stores hashed files through an
+//! [`Operator`](https://docs.rs/opendal/latest/opendal/struct.Operator.html)
+//! of [OpenDAL](https://opendal.apache.org), so that a database can be
+//! written to and loaded from any service OpenDAL supports. Services are
+//! enabled by features of the `opendal` crate in your own dependencies.
+//!
+//! [`asyncdb::io::opendal`](crate::asyncdb::io::opendal) provides the
+//! asynchronous counterpart.
+
+use base64::{
+    Engine,
+    engine::general_purpose::{URL_SAFE_NO_PAD as base64_engine},
+};
+use ring::digest::{Context, SHA256};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::Error;
+
+use super::{
+    HashedFileIn,
+    HashedFileOut,
+    ReadFileSystem,
+    WriteFileSystem,
+    parse_pointer,
+};
+
+// Size of a chunk uploaded at once.
+pub(crate) const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// File system on an OpenDAL operator.
+///
+/// Requests block the calling thread, and must not be made in an
+/// asynchronous context.
+#[derive(Clone)]
+pub struct OpendalFileSystem {
+    operator: ::opendal::blocking::Operator,
+    // Runs the operator unless it was given in a runtime.
+    _runtime: Option<Arc<tokio::runtime::Runtime>>,
+}
+
+impl OpendalFileSystem {
+    /// Creates a file system on a given operator.
+    ///
+    /// Starts a dedicated runtime that runs the operator. Use
+    /// [`OpendalFileSystem::from_blocking`] to run the operator in an
+    /// existing runtime instead.
+    pub fn new(operator: ::opendal::Operator) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let operator = {
+            let _guard = runtime.enter();
+            ::opendal::blocking::Operator::new(operator)?
+        };
+        Ok(Self {
+            operator,
+            _runtime: Some(Arc::new(runtime)),
+        })
+    }
+
+    /// Creates a file system on a given blocking operator.
+    pub fn from_blocking(operator: ::opendal::blocking::Operator) -> Self {
+        Self {
+            operator,
+            _runtime: None,
+        }
+    }
+}
+
+impl ReadFileSystem for OpendalFileSystem {
+    type HashedFileIn = OpendalHashedFileIn;
+
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        OpendalHashedFileIn::open(&self.operator, path.as_ref())
+    }
+
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        let entries = self.operator.list_options(
+            &dir_path(prefix.as_ref()),
+            ::opendal::options::ListOptions {
+                recursive: true,
+                ..Default::default()
+            },
+        )?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.metadata().is_file())
+            .map(|entry| entry.path().to_string())
+            .collect())
+    }
+
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        match self.operator.read(path.as_ref()) {
+            Ok(contents) => parse_pointer(contents.to_vec()).map(Some),
+            Err(e) if e.kind() == ::opendal::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl WriteFileSystem for OpendalFileSystem {
+    type HashedFileOut = OpendalHashedFileOut;
+
+    fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        OpendalHashedFileOut::create(self.operator.clone(), "")
+    }
+
+    fn create_hashed_file_in(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileOut, Error> {
+        OpendalHashedFileOut::create(self.operator.clone(), path.as_ref())
+    }
+
+    fn delete(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        self.operator.delete(path.as_ref())?;
+        Ok(())
+    }
+
+    /// Whether the pointer is replaced atomically depends on the service;
+    /// object stores replace an object at once.
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        self.operator
+            .write(path.as_ref(), target.as_ref().as_bytes().to_vec())?;
+        Ok(())
+    }
+}
+
+// Returns the OpenDAL path of a directory, which ends with a slash.
+pub(crate) fn dir_path(prefix: &str) -> String {
+    match prefix.trim_matches('/') {
+        "" => "/".to_string(),
+        prefix => format!("{}/", prefix),
+    }
+}
+
+/// Writable file on an OpenDAL operator.
+///
+/// Contents are buffered in a temporary file, and written to the operator
+/// under the hash of the contents when persisted.
+pub struct OpendalHashedFileOut {
+    // Temporary file.
+    tempfile: std::fs::File,
+    operator: ::opendal::blocking::Operator,
+    // Directory to put the file in.
+    dir: String,
+    // Context to calculate an SHA-256 digest.
+    context: Context,
+}
+
+impl OpendalHashedFileOut {
+    // Creates a temporary file to be written under a given directory.
+    fn create(
+        operator: ::opendal::blocking::Operator,
+        dir: &str,
+    ) -> Result<Self, Error> {
+        Ok(OpendalHashedFileOut {
+            tempfile: tempfile::tempfile()?,
+            operator,
+            dir: dir.trim_matches('/').to_string(),
+            context: Context::new(&SHA256),
+        })
+    }
+}
+
+impl Write for OpendalHashedFileOut {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.tempfile.write(buf)?;
+        self.context.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tempfile.flush()
+    }
+}
+
+impl HashedFileOut for OpendalHashedFileOut {
+    fn persist(mut self, extension: impl AsRef<str>) -> Result<String, Error> {
+        self.flush()?;
+        let hash = base64_engine.encode(self.context.finish());
+        let name = format!("{}.{}", hash, extension.as_ref());
+        let path = if self.dir.is_empty() {
+            name
+        } else {
+            format!("{}/{}", self.dir, name)
+        };
+        self.tempfile.seek(SeekFrom::Start(0))?;
+        let mut writer = self.operator.writer(&path)?;
+        let mut chunk = vec![0u8; UPLOAD_CHUNK_SIZE];
+        loop {
+            let n = self.tempfile.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            writer.write(chunk[..n].to_vec())?;
+        }
+        writer.close()?;
+        Ok(hash)
+    }
+}
+
+/// Readable file on an OpenDAL operator.
+pub struct OpendalHashedFileIn {
+    reader: ::opendal::blocking::StdReader,
+    path: String,
+    size: u64,
+    // Context to calculate an SHA-256 digest.
+    context: Context,
+}
+
+impl OpendalHashedFileIn {
+    // Opens a file whose name is the hash of its contents.
+    fn open(
+        operator: &::opendal::blocking::Operator,
+        path: &str,
+    ) -> Result<Self, Error> {
+        let size = operator.stat(path)?.content_length();
+        let reader = operator.reader(path)?.into_std_read(0..size)?;
+        Ok(OpendalHashedFileIn {
+            reader,
+            path: path.to_string(),
+            size,
+            context: Context::new(&SHA256),
+        })
+    }
+}
+
+impl Read for OpendalHashedFileIn {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.context.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl HashedFileIn for OpendalHashedFileIn {
+    fn verify(self) -> Result<(), Error> {
+        let hash = base64_engine.encode(self.context.finish());
+        let stem = Path::new(&self.path)
+            .file_stem()
+            .and_then(|stem| stem.to_str());
+        if stem == Some(hash.as_str()) {
+            Ok(())
+        } else {
+            Err(Error::VerificationFailure(format!(
+                "Expected hash {:?}, but got {}",
+                stem,
+                hash,
+            )))
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opendal_file_system_should_round_trip_hashed_files() {
+        let operator = ::opendal::Operator::new(
+            ::opendal::services::Memory::default(),
+        ).unwrap().finish();
+        let fs = OpendalFileSystem::new(operator).unwrap();
+        let mut f = fs.create_hashed_file_in("data").unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let hash = f.persist("bin").unwrap();
+
+        let mut f = fs.open_hashed_file(format!("data/{}.bin", hash))
+            .unwrap();
+        assert_eq!(f.size(), Some(10));
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"0123456789");
+        f.verify().unwrap();
+        assert!(matches!(
+            fs.open_hashed_file(format!("other/{}.bin", hash)),
+            Err(Error::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound,
+        ));
+    }
+
+    #[test]
+    fn opendal_file_system_should_list_and_delete_files() {
+        let operator = ::opendal::Operator::new(
+            ::opendal::services::Memory::default(),
+        ).unwrap().finish();
+        let fs = OpendalFileSystem::new(operator).unwrap();
+        let mut paths: Vec<String> = Vec::new();
+        for dir in ["data", "data/nested", "database"] {
+            let mut f = fs.create_hashed_file_in(dir).unwrap();
+            f.write_all(dir.as_bytes()).unwrap();
+            paths.push(format!("{}/{}.bin", dir, f.persist("bin").unwrap()));
+        }
+        let mut files = fs.list_files("data").unwrap();
+        files.sort();
+        assert_eq!(files, &paths[..2]);
+        assert_eq!(fs.list_files("").unwrap().len(), 3);
+        assert!(fs.list_files("missing").unwrap().is_empty());
+
+        fs.delete(&paths[0]).unwrap();
+        fs.delete(&paths[0]).unwrap();
+        assert_eq!(fs.list_files("data").unwrap(), &paths[1..2]);
+    }
+}
@@ -0,0 +1,507 @@
+//! Retries of failed reads.
+//!
+//! [`RetryingFileSystem`] wraps a file system, typically a remote one, and
+//! retries opening and reading files with exponential backoff and jitter as
+//! [`RetryPolicy`] says. A file that fails in the middle of reading is
+//! reopened, and the bytes already read are skipped.
+
+use rand::Rng;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+
+use super::{HashedFileIn, ReadFileSystem, WriteFileSystem};
+
+/// Class of errors to decide whether to retry an operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// File is not found.
+    NotFound,
+    /// I/O error that is likely to go away; e.g., a timeout, a reset
+    /// connection, or an interruption.
+    Transient,
+    /// Other I/O error; e.g., an error status from a remote service.
+    Io,
+    /// Error other than I/O errors; e.g., a verification failure.
+    Other,
+}
+
+impl ErrorClass {
+    /// Classifies a given error.
+    pub fn of(e: &Error) -> Self {
+        match e {
+            Error::IOError(e) => Self::of_io(e),
+            _ => Self::Other,
+        }
+    }
+
+    /// Classifies a given I/O error.
+    pub fn of_io(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::TimedOut |
+            ErrorKind::Interrupted |
+            ErrorKind::WouldBlock |
+            ErrorKind::ConnectionReset |
+            ErrorKind::ConnectionAborted |
+            ErrorKind::ConnectionRefused |
+            ErrorKind::NotConnected |
+            ErrorKind::BrokenPipe |
+            ErrorKind::UnexpectedEof => Self::Transient,
+            _ => Self::Io,
+        }
+    }
+}
+
+/// Policy of retries.
+///
+/// The `n`-th retry waits for `initial_backoff × multiplier^(n-1)` capped at
+/// `max_backoff`. With jitter, which is the default, the wait is chosen
+/// uniformly at random up to that.
+///
+/// By default, [`ErrorClass::Transient`] and [`ErrorClass::Io`] errors are
+/// retried up to 3 times, and the other errors are not.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Maximum numbers of retries of `NotFound`, `Transient`, `Io`, and
+    // `Other` errors in order.
+    max_retries: [u32; 4],
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a default policy.
+    pub fn new() -> Self {
+        Self {
+            max_retries: [0, 3, 3, 0],
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+
+    /// Sets the maximum number of retries of a given class of errors.
+    pub fn with_max_retries(mut self, class: ErrorClass, n: u32) -> Self {
+        self.max_retries[class as usize] = n;
+        self
+    }
+
+    /// Sets the wait before the first retry.
+    pub fn with_initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Sets the maximum wait before a retry.
+    pub fn with_max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Sets the factor by which the wait grows every retry.
+    ///
+    /// Fails if `multiplier` is less than 1 or not finite.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Result<Self, Error> {
+        if !multiplier.is_finite() || multiplier < 1.0 {
+            return Err(Error::InvalidArgs(format!(
+                "multiplier must be finite and at least 1 but got {}",
+                multiplier,
+            )));
+        }
+        self.multiplier = multiplier;
+        Ok(self)
+    }
+
+    /// Enables or disables jitter.
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Returns the maximum number of retries of a given class of errors.
+    pub fn max_retries(&self, class: ErrorClass) -> u32 {
+        self.max_retries[class as usize]
+    }
+
+    /// Returns the wait before the `n`-th retry, counting from 1, of a given
+    /// error.
+    ///
+    /// `None` if the error should not be retried any more.
+    pub fn backoff(&self, e: &Error, n: u32) -> Option<Duration> {
+        if n == 0 || n > self.max_retries(ErrorClass::of(e)) {
+            return None;
+        }
+        let factor = self.multiplier.powi((n - 1).min(i32::MAX as u32) as i32);
+        let backoff = (self.initial_backoff.as_secs_f64() * factor)
+            .min(self.max_backoff.as_secs_f64());
+        let backoff = if self.jitter && backoff > 0.0 {
+            rand::thread_rng().gen_range(0.0..=backoff)
+        } else {
+            backoff
+        };
+        Some(Duration::from_secs_f64(backoff))
+    }
+}
+
+/// File system that retries failed reads of another file system.
+///
+/// Files are written to the wrapped file system as they are without
+/// retries. Listings and deletions, which are idempotent, are retried.
+pub struct RetryingFileSystem<FS> {
+    inner: Arc<FS>,
+    policy: Arc<RetryPolicy>,
+}
+
+impl<FS> RetryingFileSystem<FS> {
+    /// Wraps a given file system retrying failed reads with the default
+    /// policy.
+    pub fn new(inner: FS) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            policy: Arc::new(RetryPolicy::new()),
+        }
+    }
+
+    /// Sets the policy of retries.
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Returns the wrapped file system.
+    pub fn inner(&self) -> &FS {
+        &self.inner
+    }
+}
+
+impl<FS> ReadFileSystem for RetryingFileSystem<FS>
+where
+    FS: ReadFileSystem,
+{
+    type HashedFileIn = RetryingHashedFileIn<FS>;
+
+    fn open_hashed_file(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileIn, Error> {
+        let path = path.as_ref().to_string();
+        let file = retry(&self.policy, || self.inner.open_hashed_file(&path))?;
+        Ok(RetryingHashedFileIn {
+            inner: self.inner.clone(),
+            policy: self.policy.clone(),
+            path,
+            file,
+            pos: 0,
+        })
+    }
+
+    fn list_files(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> Result<Vec<String>, Error> {
+        retry(&self.policy, || self.inner.list_files(prefix.as_ref()))
+    }
+
+    fn read_pointer(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Option<String>, Error> {
+        retry(&self.policy, || self.inner.read_pointer(path.as_ref()))
+    }
+}
+
+impl<FS> WriteFileSystem for RetryingFileSystem<FS>
+where
+    FS: WriteFileSystem,
+{
+    type HashedFileOut = FS::HashedFileOut;
+
+    fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file()
+    }
+
+    fn create_hashed_file_in(
+        &self,
+        path: impl AsRef<str>,
+    ) -> Result<Self::HashedFileOut, Error> {
+        self.inner.create_hashed_file_in(path)
+    }
+
+    fn delete(&self, path: impl AsRef<str>) -> Result<(), Error> {
+        retry(&self.policy, || self.inner.delete(path.as_ref()))
+    }
+
+    /// Retrying is safe because writing the same target again is harmless.
+    fn write_pointer(
+        &self,
+        path: impl AsRef<str>,
+        target: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        retry(&self.policy, || {
+            self.inner.write_pointer(path.as_ref(), target.as_ref())
+        })
+    }
+}
+
+/// File that is reopened when reading it fails.
+pub struct RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem,
+{
+    inner: Arc<FS>,
+    policy: Arc<RetryPolicy>,
+    path: String,
+    file: FS::HashedFileIn,
+    // Number of bytes read so far.
+    pos: u64,
+}
+
+impl<FS> RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem,
+{
+    // Reopens the file and skips the bytes already read.
+    //
+    // Skipped bytes go through the new file so that it can verify the
+    // entire contents.
+    fn reopen(&mut self) -> Result<(), Error> {
+        let mut file = self.inner.open_hashed_file(&self.path)?;
+        let skipped = std::io::copy(
+            &mut (&mut file).take(self.pos),
+            &mut std::io::sink(),
+        )?;
+        if skipped != self.pos {
+            return Err(Error::IOError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{} ended before {} bytes", self.path, self.pos),
+            )));
+        }
+        self.file = file;
+        Ok(())
+    }
+}
+
+impl<FS> Read for RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut n = 0;
+        loop {
+            let mut e = match self.file.read(buf) {
+                Ok(len) => {
+                    self.pos += len as u64;
+                    return Ok(len);
+                },
+                Err(e) => Error::IOError(e),
+            };
+            // reopens the file until it succeeds or the policy gives up
+            loop {
+                n += 1;
+                let Some(backoff) = self.policy.backoff(&e, n) else {
+                    return Err(match e {
+                        Error::IOError(e) => e,
+                        e => std::io::Error::other(e),
+                    });
+                };
+                std::thread::sleep(backoff);
+                match self.reopen() {
+                    Ok(()) => break,
+                    Err(reopen_error) => e = reopen_error,
+                }
+            }
+        }
+    }
+}
+
+impl<FS> HashedFileIn for RetryingHashedFileIn<FS>
+where
+    FS: ReadFileSystem,
+{
+    fn verify(self) -> Result<(), Error> {
+        self.file.verify()
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.file.size()
+    }
+}
+
+// Runs an operation retrying it as a given policy says.
+fn retry<T>(
+    policy: &RetryPolicy,
+    mut op: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut n = 0;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                n += 1;
+                match policy.backoff(&e, n) {
+                    Some(backoff) => std::thread::sleep(backoff),
+                    None => return Err(e),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::io::Write;
+
+    use crate::io::{HashedFileOut, LocalFileSystem, LocalHashedFileIn};
+
+    // File system that fails to open files and read them as scheduled.
+    struct FlakyFileSystem {
+        local: LocalFileSystem,
+        // Number of opens to fail.
+        failing_opens: Cell<usize>,
+        // Number of bytes a file can read before failing once.
+        bytes_before_failure: Cell<Option<usize>>,
+        num_opens: Cell<usize>,
+    }
+
+    struct FlakyHashedFileIn {
+        file: LocalHashedFileIn,
+        bytes_before_failure: Option<usize>,
+    }
+
+    impl Read for FlakyHashedFileIn {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.bytes_before_failure.as_mut() {
+                Some(0) => Err(std::io::ErrorKind::ConnectionReset.into()),
+                Some(remaining) => {
+                    let len = buf.len().min(*remaining);
+                    let n = self.file.read(&mut buf[..len])?;
+                    *remaining -= n;
+                    Ok(n)
+                },
+                None => self.file.read(buf),
+            }
+        }
+    }
+
+    impl HashedFileIn for FlakyHashedFileIn {
+        fn verify(self) -> Result<(), Error> {
+            self.file.verify()
+        }
+    }
+
+    impl ReadFileSystem for FlakyFileSystem {
+        type HashedFileIn = FlakyHashedFileIn;
+
+        fn open_hashed_file(
+            &self,
+            path: impl AsRef<str>,
+        ) -> Result<Self::HashedFileIn, Error> {
+            self.num_opens.set(self.num_opens.get() + 1);
+            if self.failing_opens.get() > 0 {
+                self.failing_opens.set(self.failing_opens.get() - 1);
+                return Err(Error::IOError(
+                    std::io::ErrorKind::TimedOut.into(),
+                ));
+            }
+            Ok(FlakyHashedFileIn {
+                file: self.local.open_hashed_file(path)?,
+                bytes_before_failure: self.bytes_before_failure.take(),
+            })
+        }
+    }
+
+    impl WriteFileSystem for FlakyFileSystem {
+        type HashedFileOut =
+            <LocalFileSystem as WriteFileSystem>::HashedFileOut;
+
+        fn create_hashed_file(&self) -> Result<Self::HashedFileOut, Error> {
+            self.local.create_hashed_file()
+        }
+
+        fn create_hashed_file_in(
+            &self,
+            path: impl AsRef<str>,
+        ) -> Result<Self::HashedFileOut, Error> {
+            self.local.create_hashed_file_in(path)
+        }
+    }
+
+    #[test]
+    fn retrying_file_system_should_retry_transient_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = RetryingFileSystem::new(FlakyFileSystem {
+            local: LocalFileSystem::new(dir.path()),
+            failing_opens: Cell::new(0),
+            bytes_before_failure: Cell::new(None),
+            num_opens: Cell::new(0),
+        })
+            .with_policy(RetryPolicy::new()
+                .with_initial_backoff(Duration::ZERO));
+        let mut f = fs.create_hashed_file().unwrap();
+        f.write_all(b"0123456789").unwrap();
+        let path = format!("{}.bin", f.persist("bin").unwrap());
+
+        // fails twice to open, and once to read after 4 bytes
+        fs.inner().failing_opens.set(2);
+        fs.inner().bytes_before_failure.set(Some(4));
+        let mut f = fs.open_hashed_file(&path).unwrap();
+        let mut buf: Vec<u8> = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"0123456789");
+        f.verify().unwrap();
+        assert_eq!(fs.inner().num_opens.get(), 4);
+
+        // gives up after 3 retries
+        fs.inner().num_opens.set(0);
+        fs.inner().failing_opens.set(4);
+        assert!(fs.open_hashed_file(&path).is_err());
+        assert_eq!(fs.inner().num_opens.get(), 4);
+
+        // does not retry missing files
+        fs.inner().num_opens.set(0);
+        fs.inner().failing_opens.set(0);
+        assert!(fs.open_hashed_file("missing.bin").is_err());
+        assert_eq!(fs.inner().num_opens.get(), 1);
+    }
+
+    #[test]
+    fn retry_policy_should_back_off_exponentially() {
+        let policy = RetryPolicy::new()
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_max_backoff(Duration::from_millis(300))
+            .with_jitter(false)
+            .with_max_retries(ErrorClass::Transient, 4);
+        let e = Error::IOError(std::io::ErrorKind::TimedOut.into());
+        let backoffs: Vec<_> = (1..=5).map(|n| policy.backoff(&e, n))
+            .collect();
+        assert_eq!(backoffs, vec![
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(200)),
+            Some(Duration::from_millis(300)),
+            Some(Duration::from_millis(300)),
+            None,
+        ]);
+        let e = Error::VerificationFailure("hash mismatch".to_string());
+        assert_eq!(policy.backoff(&e, 1), None);
+        let policy = policy.with_jitter(true);
+        let e = Error::IOError(std::io::Error::other("service unavailable"));
+        assert!(policy.backoff(&e, 3).unwrap() <= Duration::from_millis(300));
+        assert!(RetryPolicy::new().with_multiplier(0.5).is_err());
+    }
+}
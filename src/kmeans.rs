@@ -2,20 +2,25 @@
 
 use core::ops::{AddAssign, Div, Mul, MulAssign, Sub, SubAssign};
 use core::num::NonZeroUsize;
+use core::ops::ControlFlow;
 use rand::Rng;
 use rand::distributions::Distribution;
 use rand::distributions::uniform::SampleUniform;
 
+use crate::distance::{CentroidKind, Distance, SquaredL2};
 use crate::distribution::WeightedIndex;
 use crate::error::Error;
-use crate::linalg::{add_in, dot, norm2, scale_in, subtract, subtract_in};
-use crate::numbers::{Abs, FromAs, Infinity, One, Sqrt, Zero};
+use crate::linalg::{norm2, scale_in, solve_in, subtract_in};
+use crate::linalg::simd::Kernels;
+use crate::numbers::{Abs, Finite, FromAs, Infinity, One, Sqrt, Zero};
+use crate::numbers::fixed::Fixed;
 use crate::slice::AsSlice;
 use crate::vector::{BlockVectorSet, VectorSet};
 
 /// Default epsilon value.
 ///
-/// Specialized for floating point types; i.e., [`f32`], and [`f64`].
+/// Specialized for floating point types; i.e., [`f32`], and [`f64`], and
+/// [`Fixed`].
 pub trait DefaultEpsilon {
     /// Returns the default espsilon value.
     fn default_epsilon() -> Self;
@@ -33,13 +38,21 @@ impl DefaultEpsilon for f64 {
     }
 }
 
+impl DefaultEpsilon for Fixed {
+    fn default_epsilon() -> Self {
+        // 1/4096
+        Fixed::from_bits(16)
+    }
+}
+
 /// Requirements for a vector element as a scalar value.
 ///
-/// [`f32`] and [`f64`] satisfy all of the curated traits.
+/// [`f32`], [`f64`], and [`Fixed`] satisfy all of the curated traits.
 pub trait Scalar:
     SampleUniform
     + DefaultEpsilon
     + Abs
+    + Finite
     + Infinity
     + One
     + Sqrt
@@ -53,10 +66,14 @@ pub trait Scalar:
     + FromAs<usize>
     + PartialOrd
     + Copy
+    + Send
+    + Sync
+    + Kernels
     + core::fmt::Debug {}
 
 impl Scalar for f32 {}
 impl Scalar for f64 {}
+impl Scalar for Fixed {}
 
 /// Codebook.
 pub struct Codebook<T> {
@@ -67,6 +84,75 @@ pub struct Codebook<T> {
     pub indices: Vec<usize>,
 }
 
+/// Value returned from an event handler.
+///
+/// An event handler may cancel the ongoing operation by returning
+/// [`ControlFlow::Break`].
+/// Event handlers that return `()` never cancel the operation.
+pub trait EventControl {
+    /// Returns if the ongoing operation should be cancelled.
+    fn is_cancelled(&self) -> bool;
+}
+
+impl EventControl for () {
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+impl EventControl for ControlFlow<()> {
+    fn is_cancelled(&self) -> bool {
+        self.is_break()
+    }
+}
+
+// Event that names the operation it is notified from.
+pub(crate) trait OperationEvent {
+    // Name of the operation used in the cancellation message.
+    const OPERATION: &'static str;
+}
+
+// Notifies a given event handler of an event.
+//
+// Fails with `Error::Cancelled` if the event handler cancels the operation.
+pub(crate) fn notify_event<E, C>(
+    event_handler: &mut impl FnMut(E) -> C,
+    event: E,
+) -> Result<(), Error>
+where
+    E: OperationEvent,
+    C: EventControl,
+{
+    if event_handler(event).is_cancelled() {
+        Err(Error::Cancelled(format!("{} has been cancelled", E::OPERATION)))
+    } else {
+        Ok(())
+    }
+}
+
+// Notifies an event handler of an event and returns from the enclosing
+// function if the event handler cancels the operation.
+macro_rules! event {
+    ($event_handler:expr, $event:expr $(,)?) => {
+        $crate::kmeans::notify_event(&mut $event_handler, $event)?
+    };
+}
+
+pub(crate) use event;
+
+/// Convergence metrics of clustering.
+#[derive(Clone, Debug)]
+pub struct ClusterMetrics<T> {
+    /// Inertia; i.e., sum of distances between vectors and their nearest
+    /// centroids.
+    ///
+    /// Distances are squared under the default squared Euclidean distance.
+    pub inertia: T,
+
+    /// Number of vectors assigned to each cluster.
+    pub occupancy: Vec<usize>,
+}
+
 /// Event notified while clustering.
 #[derive(Debug)]
 pub enum ClusterEvent<'a, T> {
@@ -84,7 +170,14 @@ pub enum ClusterEvent<'a, T> {
     /// Starting n-th centroid reassignment.
     StartingCentroidReassignment(usize),
     /// Finished n-th centroid reassignment.
-    FinishedCentroidReassignment(usize),
+    ///
+    /// The second argument is the convergence metrics after the
+    /// reassignment.
+    FinishedCentroidReassignment(usize, &'a ClusterMetrics<T>),
+}
+
+impl<T> OperationEvent for ClusterEvent<'_, T> {
+    const OPERATION: &'static str = "clustering";
 }
 
 /// Performs k-means clustering.
@@ -100,16 +193,54 @@ where
 
 /// Performs k-means clustering.
 ///
+/// Fails if `vs` has fewer vectors than `k`, or if `event_handler` cancels
+/// the clustering.
+pub fn cluster_with_events<T, VS, EV, C>(
+    vs: &VS,
+    k: NonZeroUsize,
+    event_handler: EV,
+) -> Result<Codebook<T>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    EV: FnMut(ClusterEvent<'_, T>) -> C,
+    C: EventControl,
+{
+    cluster_with_distance_and_events(vs, k, &SquaredL2, event_handler)
+}
+
+/// Performs k-means clustering under a given distance.
+///
 /// Fails if `vs` has fewer vectors than `k`.
-pub fn cluster_with_events<T, VS, EV>(
+pub fn cluster_with_distance<T, VS, D>(
+    vs: &VS,
+    k: NonZeroUsize,
+    distance: &D,
+) -> Result<Codebook<T>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    D: Distance<T> + ?Sized,
+{
+    cluster_with_distance_and_events(vs, k, distance, |_| {})
+}
+
+/// Performs k-means clustering under a given distance.
+///
+/// Fails if `vs` has fewer vectors than `k`, or if `event_handler` cancels
+/// the clustering.
+pub fn cluster_with_distance_and_events<T, VS, D, EV, C>(
     vs: &VS,
     k: NonZeroUsize,
+    distance: &D,
     mut event_handler: EV,
 ) -> Result<Codebook<T>, Error>
 where
     T: Scalar,
     VS: VectorSet<T>,
-    EV: FnMut(ClusterEvent<'_, T>) -> (),
+    D: Distance<T> + ?Sized,
+    EV: FnMut(ClusterEvent<'_, T>) -> C,
+    C: EventControl,
 {
     const R: usize = 100;
     let k = k.get();
@@ -119,30 +250,447 @@ where
         ));
     }
     // initializes centroids with k-means++
-    event_handler(ClusterEvent::StartingCentroidInitialization);
-    let mut codebook = initialize_centroids(vs, k);
-    event_handler(ClusterEvent::FinishedCentroidInitialization);
+    event!(event_handler, ClusterEvent::StartingCentroidInitialization);
+    let mut codebook = initialize_centroids(vs, k, distance);
+    event!(event_handler, ClusterEvent::FinishedCentroidInitialization);
     for r in 0..R {
         // updates centroids
-        event_handler(ClusterEvent::StartingCentroidUpdate(r));
-        let gradient = update_centroids(vs, &mut codebook);
-        event_handler(ClusterEvent::FinishedCentroidUpdate(r, &gradient));
+        event!(event_handler, ClusterEvent::StartingCentroidUpdate(r));
+        let gradient = update_centroids(vs, &mut codebook, distance);
+        event!(
+            event_handler,
+            ClusterEvent::FinishedCentroidUpdate(r, &gradient),
+        );
         if gradient < T::default_epsilon() {
             break;
         }
         // re-assigns centroids
-        event_handler(ClusterEvent::StartingCentroidReassignment(r));
-        reassign_centroids(vs, &mut codebook);
-        event_handler(ClusterEvent::FinishedCentroidReassignment(r));
+        event!(event_handler, ClusterEvent::StartingCentroidReassignment(r));
+        let metrics = reassign_centroids(vs, &mut codebook, distance);
+        event!(
+            event_handler,
+            ClusterEvent::FinishedCentroidReassignment(r, &metrics),
+        );
+    }
+    Ok(codebook)
+}
+
+/// Performs k-means clustering in sequential passes over the vectors.
+///
+/// Suitable for a vector set that does not fit in memory; e.g., a
+/// [`SpillVectorSet`](crate::vector::spill::SpillVectorSet) that has
+/// spilled. Initial centroids are chosen with k-means++ from a random sample
+/// of `sample_size` vectors copied into memory. Every iteration then reads
+/// the vectors once from the first to the last, assigning each to the
+/// nearest centroid and accumulating the per-cluster sums from which new
+/// centroids are calculated. Besides the sample, only the centroids, the
+/// sums, and the assigned indices are kept in memory.
+///
+/// A centroid that loses all its members stays where it was.
+///
+/// Fails if `vs` has fewer vectors than `k`, or if `event_handler` cancels
+/// the clustering. Also fails with `Error::InvalidArgs` if `distance` needs
+/// [`CentroidKind::Median`], which cannot be calculated from the sums.
+pub fn cluster_sequential_with_distance_and_events<T, VS, D, EV, C>(
+    vs: &VS,
+    k: NonZeroUsize,
+    sample_size: NonZeroUsize,
+    distance: &D,
+    mut event_handler: EV,
+) -> Result<Codebook<T>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    D: Distance<T> + ?Sized,
+    EV: FnMut(ClusterEvent<'_, T>) -> C,
+    C: EventControl,
+{
+    const R: usize = 100;
+    let k = k.get();
+    let n = vs.len();
+    let m = vs.vector_size();
+    if n < k {
+        return Err(Error::InvalidArgs(
+            format!("vs has fewer vectors than k: {} < {}", n, k),
+        ));
+    }
+    if distance.centroid_kind() != CentroidKind::Mean {
+        return Err(Error::InvalidArgs(
+            "sequential clustering supports only mean centroids".to_string(),
+        ));
+    }
+    // initializes centroids with k-means++ over a sample
+    event!(event_handler, ClusterEvent::StartingCentroidInitialization);
+    let sample_size = sample_size.get().max(k).min(n);
+    let mut sample_indices =
+        rand::seq::index::sample(&mut rand::thread_rng(), n, sample_size)
+            .into_vec();
+    sample_indices.sort_unstable();
+    let mut sample: Vec<T> = Vec::with_capacity(sample_size * m);
+    for i in sample_indices {
+        sample.extend_from_slice(vs.get(i).as_slice());
+    }
+    let sample = BlockVectorSet::chunk(sample, m.try_into().unwrap())?;
+    let mut codebook = Codebook {
+        centroids: initialize_centroids(&sample, k, distance).centroids,
+        indices: vec![0; n],
+    };
+    drop(sample);
+    event!(event_handler, ClusterEvent::FinishedCentroidInitialization);
+    let mut sums: Vec<T> = vec![T::zero(); k * m];
+    for r in 0..R {
+        // assigns vectors and accumulates the sums in a single pass
+        event!(event_handler, ClusterEvent::StartingCentroidReassignment(r));
+        sums.fill(T::zero());
+        let mut inertia = T::zero();
+        let mut occupancy: Vec<usize> = vec![0; k];
+        for (i, v) in vs.iter() {
+            let v = v.as_slice();
+            let mut min_distance = T::infinity();
+            let mut min_index: Option<usize> = None;
+            for (j, centroid) in codebook.centroids.iter() {
+                let d = distance.distance(v, centroid);
+                if d < min_distance {
+                    min_distance = d;
+                    min_index = Some(j);
+                }
+            }
+            let ci = min_index.unwrap();
+            codebook.indices[i] = ci;
+            occupancy[ci] += 1;
+            inertia += min_distance;
+            T::add_in(&mut sums[ci * m..(ci + 1) * m], v);
+        }
+        let metrics = ClusterMetrics { inertia, occupancy };
+        event!(
+            event_handler,
+            ClusterEvent::FinishedCentroidReassignment(r, &metrics),
+        );
+        // updates centroids from the sums
+        event!(event_handler, ClusterEvent::StartingCentroidUpdate(r));
+        let mut max_distance = T::zero();
+        let mut max_norm2 = T::zero();
+        for (ci, (sum, &count)) in sums
+            .chunks_mut(m)
+            .zip(metrics.occupancy.iter())
+            .enumerate()
+        {
+            let centroid = codebook.centroids.get_mut(ci);
+            if count > 0 {
+                scale_in(sum, T::one() / T::from_as(count));
+                distance.normalize_centroid(sum);
+                // leaves the difference from the old centroid in `sum`
+                centroid.iter_mut().zip(sum.iter_mut()).for_each(|(c, s)| {
+                    core::mem::swap(c, s);
+                });
+                subtract_in(sum, centroid);
+                let distance = norm2(sum);
+                if max_distance < distance {
+                    max_distance = distance;
+                }
+            }
+            let centroid_norm2 = norm2(centroid);
+            if max_norm2 < centroid_norm2 {
+                max_norm2 = centroid_norm2;
+            }
+        }
+        let gradient = if max_norm2 != T::zero() {
+            max_distance / max_norm2
+        } else {
+            T::zero()
+        };
+        event!(
+            event_handler,
+            ClusterEvent::FinishedCentroidUpdate(r, &gradient),
+        );
+        if gradient < T::default_epsilon() {
+            break;
+        }
     }
     Ok(codebook)
 }
 
+/// Performs k-means clustering under the score-aware anisotropic loss.
+///
+/// The loss of quantizing a vector `x` to a centroid `c` is
+/// `‖r‖² + (eta - 1) (r · u)²`, where `r = x - c`, and `u` is the direction
+/// of `x` in `directions` (ScaNN; <https://arxiv.org/abs/1908.10396>).
+/// `eta` greater than one penalizes errors parallel to `u` more than
+/// orthogonal errors, which preserves inner products with `x`. `u` is
+/// supposed to be a unit vector, or a subvector of a unit vector for
+/// product quantization. `eta` of one reduces to the squared Euclidean
+/// distance.
+///
+/// Centroids are initialized with k-means++ under the squared Euclidean
+/// distance, and updated to the minimizers of the loss of their members.
+///
+/// Fails if `vs` has fewer vectors than `k`, if `directions` does not match
+/// `vs` in shape, if `eta` is not a positive finite number, or if
+/// `event_handler` cancels the clustering.
+pub fn cluster_anisotropic_with_events<T, VS, DS, EV, C>(
+    vs: &VS,
+    directions: &DS,
+    k: NonZeroUsize,
+    eta: T,
+    mut event_handler: EV,
+) -> Result<Codebook<T>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    DS: VectorSet<T>,
+    EV: FnMut(ClusterEvent<'_, T>) -> C,
+    C: EventControl,
+{
+    const R: usize = 100;
+    let k = k.get();
+    if vs.len() < k {
+        return Err(Error::InvalidArgs(
+            format!("vs has fewer vectors than k: {} < {}", vs.len(), k),
+        ));
+    }
+    if directions.len() != vs.len()
+        || directions.vector_size() != vs.vector_size()
+    {
+        return Err(Error::InvalidArgs(format!(
+            "directions must have {} vectors of size {}",
+            vs.len(),
+            vs.vector_size(),
+        )));
+    }
+    if !eta.is_finite() || eta <= T::zero() {
+        return Err(Error::InvalidArgs(
+            format!("eta must be positive but got {:?}", eta),
+        ));
+    }
+    let loss = AnisotropicLoss { eta };
+    event!(event_handler, ClusterEvent::StartingCentroidInitialization);
+    let mut codebook = initialize_centroids(vs, k, &SquaredL2);
+    event!(event_handler, ClusterEvent::FinishedCentroidInitialization);
+    for r in 0..R {
+        // updates centroids
+        event!(event_handler, ClusterEvent::StartingCentroidUpdate(r));
+        let gradient =
+            update_anisotropic_centroids(vs, directions, &mut codebook, eta);
+        event!(
+            event_handler,
+            ClusterEvent::FinishedCentroidUpdate(r, &gradient),
+        );
+        if gradient < T::default_epsilon() {
+            break;
+        }
+        // re-assigns centroids
+        event!(event_handler, ClusterEvent::StartingCentroidReassignment(r));
+        let mut inertia = T::zero();
+        let mut occupancy: Vec<usize> = vec![0; k];
+        for (i, v) in vs.iter() {
+            let u = directions.get(i).as_slice();
+            let (ci, l) = loss.nearest(v.as_slice(), u, &codebook);
+            codebook.indices[i] = ci;
+            occupancy[ci] += 1;
+            inertia += l;
+        }
+        let metrics = ClusterMetrics { inertia, occupancy };
+        event!(
+            event_handler,
+            ClusterEvent::FinishedCentroidReassignment(r, &metrics),
+        );
+    }
+    Ok(codebook)
+}
+
+/// Quantizes scalars into evenly spaced levels.
+///
+/// Spaces `num_levels` centroids evenly between the minimum and maximum
+/// elements of `vs`, and assigns each vector the nearest centroid. Unlike
+/// k-means clustering, `vs` may have fewer vectors than `num_levels`. If
+/// all the elements are the same, so are all the centroids. A single level
+/// is placed at the midpoint between the minimum and maximum elements.
+///
+/// Fails if `vs` is empty, or its vector size is not one.
+pub fn quantize_scalars<T, VS>(
+    vs: &VS,
+    num_levels: NonZeroUsize,
+) -> Result<Codebook<T>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    if vs.vector_size() != 1 {
+        return Err(Error::InvalidArgs(format!(
+            "scalars must be vectors of size 1 but got {}",
+            vs.vector_size(),
+        )));
+    }
+    let mut range: Option<(T, T)> = None;
+    for (_, v) in vs.iter() {
+        let x = v.as_slice()[0];
+        range = Some(match range {
+            Some((min, max)) => (
+                if x < min { x } else { min },
+                if x > max { x } else { max },
+            ),
+            None => (x, x),
+        });
+    }
+    let (min, max) = range.ok_or(Error::InvalidArgs(
+        "no scalar to quantize".to_string(),
+    ))?;
+    let num_levels = num_levels.get();
+    let levels: Vec<T> = if num_levels > 1 {
+        let step = (max - min) / T::from_as(num_levels - 1);
+        (0..num_levels)
+            .map(|i| {
+                let mut level = min;
+                level += step * T::from_as(i);
+                level
+            })
+            .collect()
+    } else {
+        // the midpoint minimizes the maximum error
+        let mut level = min;
+        level += (max - min) / T::from_as(2);
+        vec![level]
+    };
+    // levels are sorted, so the nearest one is next to the insertion point
+    let indices = vs
+        .iter()
+        .map(|(_, v)| {
+            let x = v.as_slice()[0];
+            let i = levels.partition_point(|&level| level < x);
+            if i == 0 {
+                0
+            } else if i == num_levels
+                || x - levels[i - 1] <= levels[i] - x
+            {
+                i - 1
+            } else {
+                i
+            }
+        })
+        .collect();
+    Ok(Codebook {
+        centroids: BlockVectorSet::chunk(levels, 1.try_into().unwrap())?,
+        indices,
+    })
+}
+
+// Score-aware anisotropic loss.
+struct AnisotropicLoss<T> {
+    // Weight of the error parallel to the direction relative to the
+    // orthogonal error.
+    eta: T,
+}
+
+impl<T> AnisotropicLoss<T>
+where
+    T: Scalar,
+{
+    // Returns the index of and the loss to the centroid with the least loss.
+    fn nearest(&self, v: &[T], u: &[T], codebook: &Codebook<T>) -> (usize, T) {
+        let mut min_loss = T::infinity();
+        let mut min_index = 0;
+        for (j, centroid) in codebook.centroids.iter() {
+            let l = self.loss(v, u, centroid);
+            if l < min_loss {
+                min_loss = l;
+                min_index = j;
+            }
+        }
+        (min_index, min_loss)
+    }
+
+    fn loss(&self, v: &[T], u: &[T], centroid: &[T]) -> T {
+        let mut norm2 = T::zero();
+        let mut parallel = T::zero();
+        for ((&x, &c), &u) in v.iter().zip(centroid).zip(u) {
+            let r = x - c;
+            norm2 += r * r;
+            parallel += r * u;
+        }
+        let mut loss = norm2;
+        loss += (self.eta - T::one()) * parallel * parallel;
+        loss
+    }
+}
+
+// Updates centroids to the minimizers of the anisotropic loss.
+//
+// The minimizer of `Σ ‖x - c‖² + (eta - 1) ((x - c) · u)²` over members
+// solves `(Σ A) c = Σ A x` where `A = I + (eta - 1) u uᵀ`. A centroid whose
+// equations are singular, or that has no member, stays where it was.
+//
+// Returns the normalized magnitude of the change in centroids.
+fn update_anisotropic_centroids<T, VS, DS>(
+    vs: &VS,
+    directions: &DS,
+    codebook: &mut Codebook<T>,
+    eta: T,
+) -> T
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+    DS: VectorSet<T>,
+{
+    let m = vs.vector_size();
+    let k = codebook.centroids.len();
+    let w = eta - T::one();
+    let mut lhs: Vec<T> = vec![T::zero(); k * m * m];
+    let mut rhs: Vec<T> = vec![T::zero(); k * m];
+    let mut counts: Vec<usize> = vec![0; k];
+    for (i, v) in vs.iter() {
+        let v = v.as_slice();
+        let u = directions.get(i).as_slice();
+        let ci = codebook.indices[i];
+        let a = &mut lhs[ci * m * m..(ci + 1) * m * m];
+        let b = &mut rhs[ci * m..(ci + 1) * m];
+        let ux = w * T::dot(u, v);
+        for p in 0..m {
+            a[p * m + p] += T::one();
+            for q in 0..m {
+                a[p * m + q] += w * u[p] * u[q];
+            }
+            b[p] += v[p];
+            b[p] += ux * u[p];
+        }
+        counts[ci] += 1;
+    }
+    let mut max_distance = T::zero();
+    let mut max_norm2 = T::zero();
+    for ci in 0..k {
+        let b = &mut rhs[ci * m..(ci + 1) * m];
+        let centroid = codebook.centroids.get_mut(ci);
+        let a = &mut lhs[ci * m * m..(ci + 1) * m * m];
+        if counts[ci] > 0 && solve_in(a, b) {
+            // leaves the difference from the old centroid in `b`
+            centroid.swap_with_slice(b);
+            subtract_in(b, centroid);
+            let distance = norm2(b);
+            if max_distance < distance {
+                max_distance = distance;
+            }
+        }
+        let centroid_norm2 = norm2(centroid);
+        if max_norm2 < centroid_norm2 {
+            max_norm2 = centroid_norm2;
+        }
+    }
+    if max_norm2 != T::zero() {
+        max_distance / max_norm2
+    } else {
+        T::zero()
+    }
+}
+
 // Initializes centroids and indices with k-means++.
-fn initialize_centroids<T, VS>(vs: &VS, k: usize) -> Codebook<T>
+fn initialize_centroids<T, VS, D>(
+    vs: &VS,
+    k: usize,
+    distance: &D,
+) -> Codebook<T>
 where
     T: Scalar,
     VS: VectorSet<T>,
+    D: Distance<T> + ?Sized,
 {
     assert!(vs.len() >= k);
     let mut rng = rand::thread_rng();
@@ -151,14 +699,10 @@ where
     let mut chosen: Vec<bool> = vec![false; n];
     let mut centroids: Vec<T> = Vec::with_capacity(k * m);
     let mut indices: Vec<usize> = vec![0; n];
-    let mut vector_buf: Vec<T> = Vec::with_capacity(m);
-    unsafe {
-        vector_buf.set_len(m);
-    }
     if k == n {
         // no need for clustering
-        for i in 0..n {
-            centroids.extend_from_slice(vs.get(i).as_slice());
+        for (_, v) in vs.iter() {
+            centroids.extend_from_slice(v.as_slice());
         }
         return Codebook {
             centroids: BlockVectorSet::chunk(
@@ -185,14 +729,11 @@ where
     // calculates the initial distribution
     let mut weights: Vec<T> = Vec::with_capacity(n);
     assert!(n >= 2);
-    for i in 0..n {
+    for (i, v) in vs.iter() {
         if chosen[i] {
             weights.push(T::zero());
         } else {
-            let v = vs.get(i).as_slice();
-            let d: &mut [T] = &mut vector_buf;
-            subtract(v, new_centroid, d);
-            let weight = dot(d, d);
+            let weight = distance.distance(v.as_slice(), new_centroid);
             weights.push(weight);
         }
     }
@@ -205,12 +746,9 @@ where
         let new_centroid = vs.get(ci).as_slice();
         centroids.extend_from_slice(new_centroid);
         weighted_index.update(&[(ci, &T::zero())]).unwrap();
-        for j in 0..n {
+        for (j, v) in vs.iter() {
             if !chosen[j] {
-                let v = vs.get(j).as_slice();
-                let d: &mut [T] = &mut vector_buf[..];
-                subtract(v, new_centroid, d);
-                let new_weight = dot(d, d);
+                let new_weight = distance.distance(v.as_slice(), new_centroid);
                 // updates the weight if it is smaller than the current one
                 if new_weight < weighted_index.get_weight(j) {
                     weighted_index.update(&[(j, &new_weight)]).unwrap();
@@ -229,35 +767,59 @@ where
 }
 
 // Updates centroids.
-fn update_centroids<T, VS>(vs: &VS, codebook: &mut Codebook<T>) -> T
+fn update_centroids<T, VS, D>(
+    vs: &VS,
+    codebook: &mut Codebook<T>,
+    distance: &D,
+) -> T
 where
     T: Scalar,
     VS: VectorSet<T>,
+    D: Distance<T> + ?Sized,
 {
     let m = vs.vector_size();
     let k = codebook.centroids.len();
-    let mut vector_buf: Vec<T> = Vec::with_capacity(m);
-    unsafe {
-        vector_buf.set_len(m);
-    }
+    let mut vector_buf: Vec<T> = vec![T::zero(); m];
+    let mut member_buf: Vec<T> = Vec::new();
     let mut max_distance = T::zero();
     let mut max_norm2 = T::zero();
     for i in 0..k {
         let old_centroid = &mut vector_buf[..];
         old_centroid.copy_from_slice(codebook.centroids.get(i));
         let new_centroid = codebook.centroids.get_mut(i);
-        new_centroid.fill(T::zero());
-        let mut count: usize = 0;
-        for (j, _) in codebook.indices
+        let members = codebook.indices
             .iter()
             .enumerate()
             .filter(|(_, &ci)| ci == i)
-        {
-            add_in(new_centroid, vs.get(j).as_slice());
-            count += 1;
+            .map(|(j, _)| j);
+        match distance.centroid_kind() {
+            CentroidKind::Mean => {
+                new_centroid.fill(T::zero());
+                let mut count: usize = 0;
+                for j in members {
+                    T::add_in(new_centroid, vs.get(j).as_slice());
+                    count += 1;
+                }
+                assert_ne!(count, 0);
+                scale_in(new_centroid, T::one() / T::from_as(count));
+            },
+            CentroidKind::Median => {
+                // copies the members to take the median of every element
+                member_buf.clear();
+                for j in members {
+                    member_buf.extend_from_slice(vs.get(j).as_slice());
+                }
+                let count = member_buf.len() / m;
+                assert_ne!(count, 0);
+                let mut column: Vec<T> = Vec::with_capacity(count);
+                for (d, x) in new_centroid.iter_mut().enumerate() {
+                    column.clear();
+                    column.extend(member_buf.iter().skip(d).step_by(m));
+                    *x = median_in(&mut column);
+                }
+            },
         }
-        assert_ne!(count, 0);
-        scale_in(new_centroid, T::one() / T::from_as(count));
+        distance.normalize_centroid(new_centroid);
         let centroid_norm2 = norm2(new_centroid);
         if max_norm2 < centroid_norm2 {
             max_norm2 = centroid_norm2
@@ -275,32 +837,338 @@ where
     }
 }
 
+// Calculates the median of given values, which are reordered.
+//
+// The mean of the middle two values for an even number of values.
+//
+// Panics if `xs` is empty or has NaN.
+fn median_in<T>(xs: &mut [T]) -> T
+where
+    T: Scalar,
+{
+    let cmp = |a: &T, b: &T| a.partial_cmp(b).unwrap();
+    let n = xs.len();
+    let (lower, &mut upper, _) = xs.select_nth_unstable_by(n / 2, cmp);
+    if n % 2 == 1 {
+        return upper;
+    }
+    let lower = *lower
+        .iter()
+        .max_by(|a, b| cmp(a, b))
+        .unwrap();
+    let mut sum = lower;
+    sum += upper;
+    sum / T::from_as(2)
+}
+
 // Re-assigns centroids.
-fn reassign_centroids<T, VS>(vs: &VS, codebook: &mut Codebook<T>)
+//
+// Returns the convergence metrics after the reassignment.
+fn reassign_centroids<T, VS, D>(
+    vs: &VS,
+    codebook: &mut Codebook<T>,
+    distance: &D,
+) -> ClusterMetrics<T>
 where
     T: Scalar,
     VS: VectorSet<T>,
+    D: Distance<T> + ?Sized,
 {
-    let n = vs.len();
-    let m = vs.vector_size();
     let k = codebook.centroids.len();
-    let mut vector_buf: Vec<T> = Vec::with_capacity(m);
-    unsafe {
-        vector_buf.set_len(m);
-    }
-    for i in 0..n {
-        let v = vs.get(i).as_slice();
-        let d = &mut vector_buf[..];
+    let mut inertia = T::zero();
+    let mut occupancy: Vec<usize> = vec![0; k];
+    for (i, v) in vs.iter() {
+        let v = v.as_slice();
         let mut min_distance = T::infinity();
         let mut min_index: Option<usize> = None;
-        for j in 0..k {
-            subtract(v, codebook.centroids.get(j).as_slice(), d);
-            let distance = dot(d, d);
-            if distance < min_distance {
-                min_distance = distance;
+        for (j, centroid) in codebook.centroids.iter() {
+            let d = distance.distance(v, centroid);
+            if d < min_distance {
+                min_distance = d;
                 min_index = Some(j);
             }
         }
-        codebook.indices[i] = min_index.unwrap();
+        let min_index = min_index.unwrap();
+        codebook.indices[i] = min_index;
+        occupancy[min_index] += 1;
+        inertia += min_distance;
+    }
+    ClusterMetrics { inertia, occupancy }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_vector_set(data: Vec<f32>) -> BlockVectorSet<f32> {
+        BlockVectorSet::chunk(data, 2.try_into().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reassign_centroids_should_report_inertia_and_occupancy() {
+        let vs = make_vector_set(vec![
+            0.0, 0.0,
+            0.0, 2.0,
+            0.0, 4.0,
+            10.0, 0.0,
+            10.0, 2.0,
+        ]);
+        let mut codebook = Codebook {
+            centroids: make_vector_set(vec![0.0, 2.0, 10.0, 1.0]),
+            indices: vec![0; 5],
+        };
+        let metrics = reassign_centroids(&vs, &mut codebook, &SquaredL2);
+        // 4 + 0 + 4 for the first cluster, and 1 + 1 for the second
+        assert_eq!(metrics.inertia, 10.0);
+        assert_eq!(metrics.occupancy, vec![3, 2]);
+        assert_eq!(codebook.indices, vec![0, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn cluster_with_events_should_report_metrics_of_converged_clusters() {
+        let vs = make_vector_set(vec![
+            0.0, 0.0,
+            2.0, 0.0,
+            4.0, 6.0,
+        ]);
+        let mut last_metrics: Option<ClusterMetrics<f32>> = None;
+        let codebook = cluster_with_events(
+            &vs,
+            1.try_into().unwrap(),
+            |event| {
+                if let ClusterEvent::FinishedCentroidReassignment(_, m) =
+                    event
+                {
+                    last_metrics = Some(m.clone());
+                }
+            },
+        ).unwrap();
+        assert_eq!(codebook.centroids.get(0), &[2.0, 2.0]);
+        // squared distances from (2, 2): 8, 4, and 20
+        let metrics = last_metrics.unwrap();
+        assert_eq!(metrics.inertia, 32.0);
+        assert_eq!(metrics.occupancy, vec![3]);
+    }
+
+    #[test]
+    fn cluster_with_events_should_stop_at_event_that_breaks() {
+        let vs = make_vector_set(vec![
+            0.0, 0.0,
+            2.0, 0.0,
+            4.0, 6.0,
+            10.0, 8.0,
+        ]);
+        let mut events: Vec<String> = Vec::new();
+        let result = cluster_with_events(
+            &vs,
+            2.try_into().unwrap(),
+            |event| {
+                events.push(format!("{:?}", event));
+                match event {
+                    ClusterEvent::FinishedCentroidUpdate(0, _) =>
+                        ControlFlow::Break(()),
+                    _ => ControlFlow::Continue(()),
+                }
+            },
+        );
+        match result {
+            Err(Error::Cancelled(message)) => {
+                assert_eq!(message, "clustering has been cancelled");
+            },
+            _ => panic!("clustering must have been cancelled"),
+        }
+        // no events after the one that breaks
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[2], "StartingCentroidUpdate(0)");
+        assert!(events[3].starts_with("FinishedCentroidUpdate(0, "));
+    }
+
+    #[test]
+    fn cluster_with_cosine_distance_should_group_vectors_by_direction() {
+        use crate::distance::Cosine;
+
+        // k-means++ fails if all the remaining weights are zero
+        let vs = make_vector_set(vec![
+            1.0, 0.0,
+            5.0, 0.0,
+            10.0, 0.0,
+            0.0, 1.0,
+            0.01, 4.0,
+            -0.01, 8.0,
+        ]);
+        let codebook =
+            cluster_with_distance(&vs, 2.try_into().unwrap(), &Cosine)
+                .unwrap();
+        let first = codebook.indices[0];
+        assert_eq!(codebook.indices, vec![
+            first, first, first, 1 - first, 1 - first, 1 - first,
+        ]);
+        // centroids are unit vectors
+        assert_eq!(codebook.centroids.get(first), &[1.0, 0.0]);
+        let centroid = codebook.centroids.get(1 - first);
+        assert!(centroid[0].abs() < 1e-3);
+        assert!((centroid[1] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cluster_with_l1_distance_should_place_centroids_at_medians() {
+        use crate::distance::L1;
+
+        let vs = make_vector_set(vec![
+            0.0, 0.0,
+            1.0, 10.0,
+            2.0, 1.0,
+            100.0, 3.0,
+        ]);
+        let codebook =
+            cluster_with_distance(&vs, 1.try_into().unwrap(), &L1).unwrap();
+        // the mean would be (25.75, 3.5)
+        assert_eq!(codebook.centroids.get(0), &[1.5, 2.0]);
+
+        assert!(matches!(
+            cluster_sequential_with_distance_and_events(
+                &vs,
+                1.try_into().unwrap(),
+                4.try_into().unwrap(),
+                &L1,
+                |_| {},
+            ),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn cluster_sequential_should_converge_to_clusters_of_batch_clustering() {
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::StdRng;
+
+        // three distant blobs of 50 vectors each
+        let mut rng = StdRng::seed_from_u64(477);
+        let centers = [(0.0f32, 0.0f32), (100.0, 0.0), (0.0, 100.0)];
+        let mut data: Vec<f32> = Vec::with_capacity(150 * 2);
+        for i in 0..150 {
+            let (x, y) = centers[i % 3];
+            data.push(x + rng.gen_range(-1.0..1.0));
+            data.push(y + rng.gen_range(-1.0..1.0));
+        }
+        let vs = make_vector_set(data);
+        let batch =
+            cluster_with_distance(&vs, 3.try_into().unwrap(), &SquaredL2)
+                .unwrap();
+        // a sample of 30 vectors misses a blob with negligible probability
+        for sample_size in [30, 150] {
+            let mut last_update: Option<(usize, f32)> = None;
+            let sequential = cluster_sequential_with_distance_and_events(
+                &vs,
+                3.try_into().unwrap(),
+                sample_size.try_into().unwrap(),
+                &SquaredL2,
+                |event| {
+                    if let ClusterEvent::FinishedCentroidUpdate(r, g) = event {
+                        last_update = Some((r, *g));
+                    }
+                },
+            ).unwrap();
+            // stops before the iteration limit as centroids stop moving
+            let (r, gradient) = last_update.unwrap();
+            assert!(r < 99);
+            assert!(gradient < f32::default_epsilon());
+            // same clusters as the batch clustering up to their order
+            let mut mapping: [Option<usize>; 3] = [None; 3];
+            for (&bi, &si) in
+                batch.indices.iter().zip(sequential.indices.iter())
+            {
+                assert_eq!(*mapping[bi].get_or_insert(si), si);
+            }
+            for (bi, si) in mapping.iter().enumerate() {
+                let si = si.unwrap();
+                let expected = batch.centroids.get(bi);
+                let actual = sequential.centroids.get(si);
+                for (x, y) in expected.iter().zip(actual.iter()) {
+                    assert!((x - y).abs() < 1e-3);
+                }
+            }
+            // every blob is a cluster of its own
+            for i in 0..3 {
+                assert_ne!(
+                    sequential.indices[i],
+                    sequential.indices[(i + 1) % 3],
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn anisotropic_loss_should_weight_parallel_errors_by_eta() {
+        let loss = AnisotropicLoss { eta: 4.0f32 };
+        let v = [2.0, 0.0];
+        let u = [1.0, 0.0];
+        // parallel error of 1: 1 + (4 - 1) * 1
+        assert_eq!(loss.loss(&v, &u, &[1.0, 0.0]), 4.0);
+        // orthogonal error of 1.5: 2.25 + (4 - 1) * 0
+        assert_eq!(loss.loss(&v, &u, &[2.0, 1.5]), 2.25);
+        // the squared Euclidean distance would prefer the first centroid
+        let codebook = Codebook {
+            centroids: make_vector_set(vec![1.0, 0.0, 2.0, 1.5]),
+            indices: vec![0],
+        };
+        assert_eq!(loss.nearest(&v, &u, &codebook), (1, 2.25));
+    }
+
+    #[test]
+    fn cluster_anisotropic_should_minimize_weighted_loss_of_members() {
+        let vs = make_vector_set(vec![1.0, 0.0, 0.0, 1.0]);
+        let directions = vs.clone();
+        // A = diag(3, 1) and diag(1, 3): (Σ A)⁻¹ Σ A x = (0.75, 0.75)
+        let codebook = cluster_anisotropic_with_events(
+            &vs,
+            &directions,
+            1.try_into().unwrap(),
+            3.0,
+            |_| {},
+        ).unwrap();
+        assert_eq!(codebook.centroids.get(0), &[0.75, 0.75]);
+        // eta of one reduces to the mean
+        let codebook = cluster_anisotropic_with_events(
+            &vs,
+            &directions,
+            1.try_into().unwrap(),
+            1.0,
+            |_| {},
+        ).unwrap();
+        assert_eq!(codebook.centroids.get(0), &[0.5, 0.5]);
+    }
+
+    #[test]
+    fn quantize_scalars_should_collapse_levels_of_constant_scalars() {
+        let vs = BlockVectorSet::chunk(
+            vec![3.0f32, 3.0, 3.0],
+            1.try_into().unwrap(),
+        ).unwrap();
+        let codebook = quantize_scalars(&vs, 4.try_into().unwrap()).unwrap();
+        assert_eq!(codebook.centroids.len(), 4);
+        for (_, level) in codebook.centroids.iter() {
+            assert_eq!(level, &[3.0]);
+        }
+        assert_eq!(codebook.indices, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn quantize_scalars_should_place_single_level_at_midpoint() {
+        let vs = BlockVectorSet::chunk(
+            vec![1.0f32, 9.0, 2.0],
+            1.try_into().unwrap(),
+        ).unwrap();
+        let codebook = quantize_scalars(&vs, 1.try_into().unwrap()).unwrap();
+        assert_eq!(codebook.centroids.len(), 1);
+        assert_eq!(codebook.centroids.get(0), &[5.0]);
+        assert_eq!(codebook.indices, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn median_should_take_middle_values() {
+        assert_eq!(median_in(&mut [3.0f32, 1.0, 2.0]), 2.0);
+        assert_eq!(median_in(&mut [4.0f32, 1.0, 3.0, 2.0]), 2.5);
+        assert_eq!(median_in(&mut [7.0f32]), 7.0);
     }
 }
@@ -2,8 +2,10 @@
 
 #![warn(missing_docs)]
 
+#[cfg(feature = "async")]
 pub mod asyncdb;
 pub mod db;
+pub mod distance;
 pub mod distribution;
 pub mod error;
 pub mod io;
@@ -12,6 +14,9 @@ pub mod linalg;
 pub mod nbest;
 pub mod numbers;
 pub mod partitions;
+pub mod projection;
 pub mod protos;
 pub mod slice;
+pub mod tensor;
+pub mod testing;
 pub mod vector;
@@ -4,6 +4,9 @@ use core::ops::{AddAssign, Div, Mul, MulAssign, Sub, SubAssign};
 
 use crate::numbers::{Abs, One, Sqrt, Zero};
 
+pub mod parallel;
+pub mod simd;
+
 const UNROLL: usize = 16;
 
 /// Calculates the dot (inner) product of given two vectors.
@@ -52,6 +55,36 @@ where
     ans
 }
 
+/// Calculates the squared Euclidean distance between given two vectors.
+///
+/// Unrolls loops to facilitate vectorization.
+pub fn squared_distance<T>(xs: &[T], ys: &[T]) -> T
+where
+    T: Zero + AddAssign + Mul<Output = T> + Sub<Output = T> + Copy,
+{
+    assert_eq!(xs.len(), ys.len());
+    const C: usize = UNROLL;
+    let mut acc = [T::zero(); C];
+    let r = xs.len() % C;
+    for i in 0..r {
+        let d = xs[i] - ys[i];
+        acc[i] = d * d;
+    }
+    let xs = &xs[r..];
+    let ys = &ys[r..];
+    let mut i = 0;
+    while i + C <= xs.len() {
+        let xs = &xs[i..i+C];
+        let ys = &ys[i..i+C];
+        for j in 0..C {
+            let d = xs[j] - ys[j];
+            acc[j] += d * d;
+        }
+        i += C;
+    }
+    sum_naive(&acc[..])
+}
+
 /// Calculates the Euclidean norm of a given vector.
 ///
 /// This function is safe if `xs` contains an extermely large or small value
@@ -78,10 +111,23 @@ where
 fn norm2_scaled<T>(xs: &[T], a: T) -> T
 where
     T: Sqrt + Zero + AddAssign + Mul<Output = T> + Copy,
+{
+    squared_norm_scaled(xs, a).sqrt()
+}
+
+// Calculates the squared Euclidean norm of a scaled vector.
+fn squared_norm_scaled<T>(xs: &[T], a: T) -> T
+where
+    T: Zero + AddAssign + Mul<Output = T> + Copy,
 {
     const C: usize = UNROLL;
     if xs.len() < C {
-        return norm2_scaled_naive(xs, a);
+        let mut acc = T::zero();
+        for x in xs {
+            let scaled = *x * a;
+            acc += scaled * scaled;
+        }
+        return acc;
     }
     let mut acc = [T::zero(); C];
     let r = xs.len() % C;
@@ -101,7 +147,7 @@ where
         }
         i += C;
     }
-    sum_naive(&acc[..]).sqrt()
+    sum_naive(&acc[..])
 }
 
 /// Calculates the Euclidean norm of a scaled vector.
@@ -202,6 +248,65 @@ where
     }
 }
 
+/// Solves a system of linear equations `a x = b` in place.
+///
+/// `a` is a square matrix in row-major order. Performs Gaussian elimination
+/// with partial pivoting, which destroys `a` and leaves `x` in `b`.
+///
+/// Returns `false` if `a` is singular.
+///
+/// Panics if the sizes of `a` and `b` do not match.
+pub fn solve_in<T>(a: &mut [T], b: &mut [T]) -> bool
+where
+    T: Zero
+        + Abs
+        + Div<Output = T>
+        + Mul<Output = T>
+        + SubAssign
+        + PartialOrd
+        + Copy,
+{
+    let n = b.len();
+    assert_eq!(a.len(), n * n);
+    for col in 0..n {
+        // chooses the row with the largest pivot
+        let mut pivot = col;
+        for row in col + 1..n {
+            if a[row * n + col].abs() > a[pivot * n + col].abs() {
+                pivot = row;
+            }
+        }
+        if a[pivot * n + col] == T::zero() {
+            return false;
+        }
+        if pivot != col {
+            for j in 0..n {
+                a.swap(col * n + j, pivot * n + j);
+            }
+            b.swap(col, pivot);
+        }
+        // eliminates the column from the rows below
+        for row in col + 1..n {
+            let factor = a[row * n + col] / a[col * n + col];
+            for j in col..n {
+                let x = factor * a[col * n + j];
+                a[row * n + j] -= x;
+            }
+            let x = factor * b[col];
+            b[row] -= x;
+        }
+    }
+    // substitutes backward
+    for row in (0..n).rev() {
+        for j in row + 1..n {
+            let x = a[row * n + j] * b[j];
+            b[row] -= x;
+        }
+        b[row] = b[row] / a[row * n + row];
+    }
+    true
+}
+
 /// Sums all the elements in a give vector.
 ///
 /// Unrolls loops to facilitate vectorization.
@@ -287,11 +392,11 @@ pub fn min_naive<T>(xs: &[T]) -> Option<T>
 where
     T: PartialOrd + Copy,
 {
-    if xs.len() > 0 {
+    if !xs.is_empty() {
         let mut mn = xs[0];
-        for i in 1..xs.len() {
-            if xs[i] < mn {
-                mn = xs[i];
+        for &x in &xs[1..] {
+            if x < mn {
+                mn = x;
             }
         }
         Some(mn)
@@ -312,8 +417,8 @@ where
         return max_abs_naive(xs);
     }
     let mut acc: Vec<T> = Vec::with_capacity(C);
-    for i in 0..C {
-        acc.push(xs[i].abs());
+    for x in &xs[..C] {
+        acc.push(x.abs());
     }
     let xs = &xs[C..];
     let r = xs.len() % C;
@@ -336,9 +441,9 @@ where
         i += C;
     }
     let mut mx = acc[0];
-    for i in 1..C {
-        if mx < acc[i] {
-            mx = acc[i];
+    for &a in &acc[1..] {
+        if mx < a {
+            mx = a;
         }
     }
     Some(mx)
@@ -349,11 +454,11 @@ pub fn max_abs_naive<T>(xs: &[T]) -> Option<T>
 where
     T: Abs + PartialOrd + Copy,
 {
-    if xs.len() > 0 {
+    if !xs.is_empty() {
         let mut mx = xs[0].abs();
-        for i in 1..xs.len() {
-            if xs[i].abs() > mx {
-                mx = xs[i].abs();
+        for x in &xs[1..] {
+            if x.abs() > mx {
+                mx = x.abs();
             }
         }
         Some(mx)
@@ -362,6 +467,31 @@ where
     }
 }
 
+/// Calculates the Hamming distance between given two packed bit vectors;
+/// i.e., the number of bits that differ.
+///
+/// Accumulates population counts of four words in parallel.
+pub fn hamming_distance(xs: &[u64], ys: &[u64]) -> u32 {
+    assert_eq!(xs.len(), ys.len());
+    const C: usize = 4;
+    let mut acc = [0u32; C];
+    let mut xs_chunks = xs.chunks_exact(C);
+    let mut ys_chunks = ys.chunks_exact(C);
+    for (xs, ys) in (&mut xs_chunks).zip(&mut ys_chunks) {
+        for j in 0..C {
+            acc[j] += (xs[j] ^ ys[j]).count_ones();
+        }
+    }
+    acc.iter().sum::<u32>()
+        + hamming_distance_naive(xs_chunks.remainder(), ys_chunks.remainder())
+}
+
+/// Calculates the Hamming distance between given two packed bit vectors.
+pub fn hamming_distance_naive(xs: &[u64], ys: &[u64]) -> u32 {
+    assert_eq!(xs.len(), ys.len());
+    xs.iter().zip(ys.iter()).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -382,6 +512,19 @@ mod tests {
         };
     }
 
+    #[test]
+    fn solve_in_should_solve_linear_equations() {
+        // requires pivoting because the first diagonal element is zero
+        let mut a = [0.0f32, 2.0, 1.0, 1.0];
+        let mut b = [4.0f32, 3.0];
+        assert!(solve_in(&mut a, &mut b));
+        assert_eq_f!(b[0], 1.0, 1e-6);
+        assert_eq_f!(b[1], 2.0, 1e-6);
+        let mut a = [1.0f32, 2.0, 2.0, 4.0];
+        let mut b = [1.0f32, 2.0];
+        assert!(!solve_in(&mut a, &mut b));
+    }
+
     #[test]
     fn dot_should_calculate_inner_product_of_one_element_vectors() {
         let xs: &[f32] = &[2.0];
@@ -598,7 +741,7 @@ mod tests {
         let xs: &mut [f32] = &mut [];
         let ys: &[f32] = &[];
         add_in(xs, ys);
-        assert_eq!(xs, &[]);
+        assert_eq!(xs, &[0.0f32; 0]);
     }
 
     #[test]
@@ -625,7 +768,7 @@ mod tests {
         let ys: &[f32] = &[];
         let mut out = [0.0f32; 0];
         subtract(xs, ys, &mut out);
-        assert_eq!(&out, &[]);
+        assert_eq!(&out, &[0.0f32; 0]);
     }
 
     #[test]
@@ -649,7 +792,7 @@ mod tests {
         let xs: &mut [f32] = &mut [];
         let ys: &[f32] = &[];
         subtract_in(xs, ys);
-        assert_eq!(xs, &[]);
+        assert_eq!(xs, &[0.0f32; 0]);
     }
 
     #[test]
@@ -670,13 +813,13 @@ mod tests {
     fn scale_in_should_work_with_empty_vector() {
         let xs: &mut [f32] = &mut [];
         scale_in(xs, 2.0);
-        assert_eq!(xs, &[]);
+        assert_eq!(xs, &[0.0f32; 0]);
     }
 
     #[test]
     fn sum_should_calculate_total_of_one_element() {
         let v: &[f32] = &[3.0];
-        assert_eq!(sum(&v), 3.0);
+        assert_eq!(sum(v), 3.0);
     }
 
     #[test]
@@ -687,7 +830,7 @@ mod tests {
             5.0, 10.0, 15.0, 20.0,
             -1.0, -2.0, -3.0, -4.0,
         ];
-        assert_eq!(sum(&v), 70.0);
+        assert_eq!(sum(v), 70.0);
     }
 
     #[test]
@@ -702,7 +845,7 @@ mod tests {
             8.0, 9.0, 10.0, 11.0,
             12.0, 13.0, 14.0, 15.0,
         ];
-        assert_eq!(sum(&v), 190.0);
+        assert_eq!(sum(v), 190.0);
     }
 
     #[test]
@@ -718,19 +861,19 @@ mod tests {
             12.0, 13.0, 14.0, 15.0,
             -1.0,
         ];
-        assert_eq!(sum(&v), 189.0);
+        assert_eq!(sum(v), 189.0);
     }
 
     #[test]
     fn sum_should_return_zero_for_empty_slice() {
         let v: &[f32] = &[];
-        assert_eq!(sum(&v), 0.0);
+        assert_eq!(sum(v), 0.0);
     }
 
     #[test]
     fn min_should_return_value_in_one_element_vector() {
         let v: &[f32] = &[1.0];
-        assert_eq!(min(&v), Some(1.0));
+        assert_eq!(min(v), Some(1.0));
     }
 
     #[test]
@@ -741,7 +884,7 @@ mod tests {
             0.0, 0.0, -4.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
         ];
-        assert_eq!(min(&v), Some(-4.0));
+        assert_eq!(min(v), Some(-4.0));
     }
 
     #[test]
@@ -756,7 +899,7 @@ mod tests {
             0.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
         ];
-        assert_eq!(min(&v), Some(-5.0));
+        assert_eq!(min(v), Some(-5.0));
     }
 
     #[test]
@@ -772,21 +915,21 @@ mod tests {
             0.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 0.0,
         ];
-        assert_eq!(min(&v), Some(-2.0));
+        assert_eq!(min(v), Some(-2.0));
     }
 
     #[test]
     fn min_should_return_none_for_empty_vector() {
         let v: &[f32] = &[];
-        assert_eq!(min(&v), None);
+        assert_eq!(min(v), None);
     }
 
     #[test]
     fn max_abs_should_return_absolute_value_in_one_element_vector() {
         let v: &[f32] = &[1.0];
-        assert_eq!(max_abs(&v), Some(1.0));
+        assert_eq!(max_abs(v), Some(1.0));
         let v: &[f32] = &[-1.0];
-        assert_eq!(max_abs(&v), Some(1.0));
+        assert_eq!(max_abs(v), Some(1.0));
     }
 
     #[test]
@@ -797,14 +940,14 @@ mod tests {
             0.0, 3.0, 0.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
         ];
-        assert_eq!(max_abs(&v), Some(3.0));
+        assert_eq!(max_abs(v), Some(3.0));
         let v: &[f32] = &[
             0.0, 0.0, 0.0, 0.0,
             0.0, 0.0, -2.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
         ];
-        assert_eq!(max_abs(&v), Some(2.0));
+        assert_eq!(max_abs(v), Some(2.0));
     }
 
     #[test]
@@ -819,7 +962,7 @@ mod tests {
             0.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 4.0, 0.0,
         ];
-        assert_eq!(max_abs(&v), Some(4.0));
+        assert_eq!(max_abs(v), Some(4.0));
         let v: &[f32] = &[
             0.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
@@ -830,7 +973,7 @@ mod tests {
             -7.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
         ];
-        assert_eq!(max_abs(&v), Some(7.0));
+        assert_eq!(max_abs(v), Some(7.0));
     }
 
     #[test]
@@ -846,7 +989,7 @@ mod tests {
             0.0, 0.0, 0.0, 0.0,
             1.0, 0.0, 0.0,
         ];
-        assert_eq!(max_abs(&v), Some(6.0));
+        assert_eq!(max_abs(v), Some(6.0));
         let v: &[f32] = &[
             0.0, 0.0, -9.0, 0.0,
             0.0, 0.0, 0.0, 0.0,
@@ -858,12 +1001,26 @@ mod tests {
             0.0, 0.0, 0.0, 0.0,
             0.0, 0.0, 0.0,
         ];
-        assert_eq!(max_abs(&v), Some(9.0));
+        assert_eq!(max_abs(v), Some(9.0));
     }
 
     #[test]
     fn max_abs_should_return_none_for_empty_vector() {
         let v: &[f32] = &[];
-        assert_eq!(max_abs(&v), None);
+        assert_eq!(max_abs(v), None);
+    }
+
+    #[test]
+    fn hamming_distance_should_count_different_bits() {
+        let xs: Vec<u64> = (0..11).map(|i| i * 0x0101_0101).collect();
+        let ys: Vec<u64> = (0..11).map(|i| !(i * 0x0101_0101)).collect();
+        assert_eq!(hamming_distance(&xs, &ys), 11 * 64);
+        assert_eq!(hamming_distance(&xs, &xs), 0);
+        let mut zs = xs.clone();
+        zs[0] ^= 0b1011;
+        zs[10] ^= 1 << 63;
+        assert_eq!(hamming_distance(&xs, &zs), 4);
+        assert_eq!(hamming_distance_naive(&xs, &zs), 4);
+        assert_eq!(hamming_distance(&[], &[]), 0);
     }
 }
@@ -0,0 +1,222 @@
+//! Multi-threaded reductions for very long vectors.
+//!
+//! Vectors are split into chunks of [`CHUNK_SIZE`] elements regardless of the
+//! number of threads, and partial results are combined in the chunk order.
+//! So results do not depend on the number of threads.
+//!
+//! Vectors shorter than [`MIN_PARALLEL_LEN`] are reduced on the calling
+//! thread without splitting, because spawning threads costs more than
+//! reducing them.
+
+use core::num::NonZeroUsize;
+use core::ops::{AddAssign, Div, Mul, Range};
+
+use crate::linalg::{self, max_abs, sum_naive};
+use crate::linalg::simd::Kernels;
+use crate::numbers::{Abs, One, Sqrt, Zero};
+
+/// Number of elements reduced by a single task.
+pub const CHUNK_SIZE: usize = 1024;
+
+/// Minimum number of elements of a vector split among threads.
+pub const MIN_PARALLEL_LEN: usize = 2 * CHUNK_SIZE;
+
+/// Calculates the dot (inner) product of given two vectors in parallel.
+///
+/// Each chunk is reduced with the kernel selected for the running CPU.
+///
+/// Panics if `xs` and `ys` have different lengths.
+pub fn dot<T>(xs: &[T], ys: &[T], num_threads: NonZeroUsize) -> T
+where
+    T: Kernels + Zero + AddAssign + Copy + Send + Sync,
+{
+    assert_eq!(xs.len(), ys.len());
+    if xs.len() < MIN_PARALLEL_LEN {
+        return T::dot(xs, ys);
+    }
+    let partials = reduce_chunks(xs.len(), num_threads, |r| {
+        T::dot(&xs[r.clone()], &ys[r])
+    });
+    sum_naive(&partials[..])
+}
+
+/// Calculates the squared Euclidean distance between given two vectors in
+/// parallel.
+///
+/// Each chunk is reduced with the kernel selected for the running CPU.
+///
+/// Panics if `xs` and `ys` have different lengths.
+pub fn squared_distance<T>(xs: &[T], ys: &[T], num_threads: NonZeroUsize) -> T
+where
+    T: Kernels + Zero + AddAssign + Copy + Send + Sync,
+{
+    assert_eq!(xs.len(), ys.len());
+    if xs.len() < MIN_PARALLEL_LEN {
+        return T::squared_distance(xs, ys);
+    }
+    let partials = reduce_chunks(xs.len(), num_threads, |r| {
+        T::squared_distance(&xs[r.clone()], &ys[r])
+    });
+    sum_naive(&partials[..])
+}
+
+/// Calculates the Euclidean norm of a given vector in parallel.
+///
+/// Safe for extremely large or small values as [`linalg::norm2`] is.
+///
+/// Returns zero if the vector is empty.
+pub fn norm2<T>(xs: &[T], num_threads: NonZeroUsize) -> T
+where
+    T: Abs + One + Sqrt + Zero + AddAssign + Div<Output = T> + Mul<Output = T> + PartialOrd + Copy + Send + Sync,
+{
+    if xs.len() < MIN_PARALLEL_LEN {
+        return linalg::norm2(xs);
+    }
+    let maxima = reduce_chunks(xs.len(), num_threads, |r| {
+        max_abs(&xs[r]).unwrap_or(T::zero())
+    });
+    let mut mx = T::zero();
+    for m in maxima {
+        if m > mx {
+            mx = m;
+        }
+    }
+    if mx == T::zero() {
+        return T::zero();
+    }
+    let mx_sqrt = mx.sqrt();
+    let a = T::one() / mx_sqrt;
+    let partials = reduce_chunks(xs.len(), num_threads, |r| {
+        linalg::squared_norm_scaled(&xs[r], a)
+    });
+    sum_naive(&partials[..]).sqrt() * mx_sqrt
+}
+
+// Applies a given reduction to every chunk and returns the partial results
+// in the chunk order.
+fn reduce_chunks<T, F>(len: usize, num_threads: NonZeroUsize, f: F) -> Vec<T>
+where
+    T: Zero + Copy + Send,
+    F: Fn(Range<usize>) -> T + Sync,
+{
+    let num_chunks = len.div_ceil(CHUNK_SIZE);
+    let chunk_range = |ci: usize| {
+        let from = ci * CHUNK_SIZE;
+        from..(from + CHUNK_SIZE).min(len)
+    };
+    let num_threads = num_threads.get().min(num_chunks);
+    if num_threads <= 1 {
+        return (0..num_chunks).map(|ci| f(chunk_range(ci))).collect();
+    }
+    let mut partials = vec![T::zero(); num_chunks];
+    let chunks_per_thread = num_chunks.div_ceil(num_threads);
+    std::thread::scope(|scope| {
+        for (ti, out) in partials.chunks_mut(chunks_per_thread).enumerate() {
+            let f = &f;
+            scope.spawn(move || {
+                let first = ti * chunks_per_thread;
+                for (i, partial) in out.iter_mut().enumerate() {
+                    *partial = f(chunk_range(first + i));
+                }
+            });
+        }
+    });
+    partials
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_vector(n: usize, seed: f32) -> Vec<f32> {
+        (0..n).map(|i| ((i as f32) * 0.37 + seed).sin()).collect()
+    }
+
+    fn threads(n: usize) -> NonZeroUsize {
+        NonZeroUsize::new(n).unwrap()
+    }
+
+    #[test]
+    fn dot_should_not_depend_on_number_of_threads() {
+        let xs = make_vector(5 * CHUNK_SIZE + 123, 0.5);
+        let ys = make_vector(5 * CHUNK_SIZE + 123, 1.5);
+        let expected = dot(&xs, &ys, threads(1));
+        for n in [2, 3, 4, 8, 16] {
+            assert_eq!(dot(&xs, &ys, threads(n)), expected);
+        }
+    }
+
+    #[test]
+    fn dot_should_be_close_to_naive_dot() {
+        let xs = make_vector(3 * CHUNK_SIZE + 7, 0.5);
+        let ys = make_vector(3 * CHUNK_SIZE + 7, 1.5);
+        let expected = linalg::dot_naive(&xs, &ys);
+        let actual = dot(&xs, &ys, threads(4));
+        assert!((actual - expected).abs() < 1e-2 * expected.abs().max(1.0));
+    }
+
+    #[test]
+    fn dot_of_empty_vectors_should_be_zero() {
+        let xs: &[f32] = &[];
+        assert_eq!(dot(xs, xs, threads(4)), 0.0);
+    }
+
+    #[test]
+    fn short_vectors_should_be_reduced_by_kernels() {
+        let xs = make_vector(MIN_PARALLEL_LEN - 1, 0.5);
+        let ys = make_vector(MIN_PARALLEL_LEN - 1, 1.5);
+        assert_eq!(dot(&xs, &ys, threads(4)), f32::dot(&xs, &ys));
+        assert_eq!(
+            squared_distance(&xs, &ys, threads(4)),
+            f32::squared_distance(&xs, &ys),
+        );
+        assert_eq!(norm2(&xs, threads(4)), linalg::norm2(&xs));
+    }
+
+    #[test]
+    fn squared_distance_should_not_depend_on_number_of_threads() {
+        let xs = make_vector(5 * CHUNK_SIZE + 123, 0.5);
+        let ys = make_vector(5 * CHUNK_SIZE + 123, 1.5);
+        let expected = squared_distance(&xs, &ys, threads(1));
+        for n in [2, 3, 4, 8, 16] {
+            assert_eq!(squared_distance(&xs, &ys, threads(n)), expected);
+        }
+    }
+
+    #[test]
+    fn squared_distance_should_be_close_to_naive_squared_distance() {
+        let xs = make_vector(3 * CHUNK_SIZE + 7, 0.5);
+        let ys = make_vector(3 * CHUNK_SIZE + 7, 1.5);
+        let expected: f32 = xs
+            .iter()
+            .zip(ys.iter())
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum();
+        let actual = squared_distance(&xs, &ys, threads(4));
+        assert!((actual - expected).abs() < 1e-3 * expected);
+    }
+
+    #[test]
+    fn norm2_should_not_depend_on_number_of_threads() {
+        let xs = make_vector(4 * CHUNK_SIZE + 1, 0.25);
+        let expected = norm2(&xs, threads(1));
+        for n in [2, 3, 5, 8] {
+            assert_eq!(norm2(&xs, threads(n)), expected);
+        }
+    }
+
+    #[test]
+    fn norm2_should_be_safe_for_extremely_large_values() {
+        let mut xs = vec![0.0f32; 2 * CHUNK_SIZE];
+        xs[0] = 3.0e30;
+        xs[CHUNK_SIZE + 1] = 4.0e30;
+        let actual = norm2(&xs, threads(2));
+        assert!((actual - 5.0e30).abs() < 1.0e25);
+    }
+
+    #[test]
+    fn norm2_of_zero_vector_should_be_zero() {
+        let xs = vec![0.0f32; 2 * CHUNK_SIZE];
+        assert_eq!(norm2(&xs, threads(2)), 0.0);
+    }
+}
@@ -0,0 +1,667 @@
+//! Runtime dispatch of linear algebra kernels.
+//!
+//! Detects CPU features (AVX2 and FMA on x86_64, NEON on aarch64) when a
+//! kernel is called for the first time, and dispatches to the best
+//! implementation available on the running CPU.
+//! Falls back to the portable implementations in [`crate::linalg`]
+//! otherwise, so binaries need not be built with `target-cpu=native`.
+//!
+//! [`accumulate_fast_scan`] evaluates the fast-scan layout of
+//! [`crate::vector::fastscan`] with byte shuffles on the same CPUs.
+
+use std::sync::OnceLock;
+
+use crate::linalg;
+use crate::numbers::fixed::Fixed;
+use crate::vector::fastscan::{BLOCK_SIZE, DIVISION_BYTES};
+
+/// Kernel set selected for the running CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KernelKind {
+    /// AVX2 and FMA on x86_64.
+    Avx2Fma,
+    /// NEON on aarch64.
+    Neon,
+    /// Portable implementations.
+    Portable,
+}
+
+/// Vector operations specialized for a scalar type.
+///
+/// [`f32`] dispatches to the kernels selected for the running CPU.
+/// [`f64`] and [`Fixed`] use the portable implementations.
+pub trait Kernels: Sized {
+    /// Calculates the dot (inner) product of given two vectors.
+    fn dot(xs: &[Self], ys: &[Self]) -> Self;
+
+    /// Calculates the squared Euclidean distance between given two vectors.
+    fn squared_distance(xs: &[Self], ys: &[Self]) -> Self;
+
+    /// Adds a vector to another vector in place.
+    fn add_in(ls: &mut [Self], rs: &[Self]);
+}
+
+impl Kernels for f32 {
+    fn dot(xs: &[f32], ys: &[f32]) -> f32 {
+        dot(xs, ys)
+    }
+
+    fn squared_distance(xs: &[f32], ys: &[f32]) -> f32 {
+        squared_distance(xs, ys)
+    }
+
+    fn add_in(ls: &mut [f32], rs: &[f32]) {
+        add_in(ls, rs)
+    }
+}
+
+impl Kernels for f64 {
+    fn dot(xs: &[f64], ys: &[f64]) -> f64 {
+        linalg::dot(xs, ys)
+    }
+
+    fn squared_distance(xs: &[f64], ys: &[f64]) -> f64 {
+        linalg::squared_distance(xs, ys)
+    }
+
+    fn add_in(ls: &mut [f64], rs: &[f64]) {
+        linalg::add_in(ls, rs)
+    }
+}
+
+impl Kernels for Fixed {
+    fn dot(xs: &[Fixed], ys: &[Fixed]) -> Fixed {
+        linalg::dot(xs, ys)
+    }
+
+    fn squared_distance(xs: &[Fixed], ys: &[Fixed]) -> Fixed {
+        linalg::squared_distance(xs, ys)
+    }
+
+    fn add_in(ls: &mut [Fixed], rs: &[Fixed]) {
+        linalg::add_in(ls, rs)
+    }
+}
+
+// Function table of the selected kernels.
+//
+// Every kernel assumes its arguments have the same length.
+struct KernelTable {
+    kind: KernelKind,
+    dot: fn(&[f32], &[f32]) -> f32,
+    squared_distance: fn(&[f32], &[f32]) -> f32,
+    add_in: fn(&mut [f32], &[f32]),
+    // Assumes lengths are multiples of `DIVISION_BYTES` as well.
+    fast_scan: fn(&[u8], &[u8], &mut [u16; BLOCK_SIZE]),
+}
+
+static KERNELS: OnceLock<KernelTable> = OnceLock::new();
+
+fn kernels() -> &'static KernelTable {
+    KERNELS.get_or_init(detect)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detect() -> KernelTable {
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        KernelTable {
+            kind: KernelKind::Avx2Fma,
+            dot: |xs, ys| unsafe { avx2::dot(xs, ys) },
+            squared_distance: |xs, ys| unsafe {
+                avx2::squared_distance(xs, ys)
+            },
+            add_in: |ls, rs| unsafe { avx2::add_in(ls, rs) },
+            fast_scan: |codes, lut, sums| unsafe {
+                avx2::fast_scan(codes, lut, sums)
+            },
+        }
+    } else {
+        portable()
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn detect() -> KernelTable {
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        KernelTable {
+            kind: KernelKind::Neon,
+            dot: |xs, ys| unsafe { neon::dot(xs, ys) },
+            squared_distance: |xs, ys| unsafe {
+                neon::squared_distance(xs, ys)
+            },
+            add_in: |ls, rs| unsafe { neon::add_in(ls, rs) },
+            fast_scan: |codes, lut, sums| unsafe {
+                neon::fast_scan(codes, lut, sums)
+            },
+        }
+    } else {
+        portable()
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn detect() -> KernelTable {
+    portable()
+}
+
+fn portable() -> KernelTable {
+    KernelTable {
+        kind: KernelKind::Portable,
+        dot: linalg::dot,
+        squared_distance: linalg::squared_distance,
+        add_in: linalg::add_in,
+        fast_scan: fast_scan_portable,
+    }
+}
+
+// Portable implementation of `accumulate_fast_scan`.
+fn fast_scan_portable(codes: &[u8], lut: &[u8], sums: &mut [u16; BLOCK_SIZE]) {
+    let codes = codes.chunks_exact(DIVISION_BYTES);
+    for (cs, row) in codes.zip(lut.chunks_exact(DIVISION_BYTES)) {
+        for (i, &c) in cs.iter().enumerate() {
+            let lo = row[(c & 0xF) as usize] as u16;
+            let hi = row[(c >> 4) as usize] as u16;
+            sums[i] = sums[i].wrapping_add(lo);
+            sums[i + DIVISION_BYTES] = sums[i + DIVISION_BYTES]
+                .wrapping_add(hi);
+        }
+    }
+}
+
+/// Returns the kernel set selected for the running CPU.
+pub fn selected_kernel() -> KernelKind {
+    kernels().kind
+}
+
+/// Calculates the dot (inner) product of given two vectors.
+///
+/// Panics if `xs` and `ys` have different lengths.
+pub fn dot(xs: &[f32], ys: &[f32]) -> f32 {
+    assert_eq!(xs.len(), ys.len());
+    (kernels().dot)(xs, ys)
+}
+
+/// Calculates the squared Euclidean distance between given two vectors.
+///
+/// Panics if `xs` and `ys` have different lengths.
+pub fn squared_distance(xs: &[f32], ys: &[f32]) -> f32 {
+    assert_eq!(xs.len(), ys.len());
+    (kernels().squared_distance)(xs, ys)
+}
+
+/// Adds a vector to another vector in place.
+///
+/// Panics if `ls` and `rs` have different lengths.
+pub fn add_in(ls: &mut [f32], rs: &[f32]) {
+    assert_eq!(ls.len(), rs.len());
+    (kernels().add_in)(ls, rs)
+}
+
+/// Accumulates the lookups of 4-bit codes of a block of vectors.
+///
+/// `codes` is a block of [`FastScanCodes`], and `lut` has 16 entries per
+/// division. Adds the sum of the entries looked up by the codes of vector
+/// `i` to `sums[i]`. Sums wrap around on overflow, so callers have to keep
+/// the entries small enough.
+///
+/// Panics if `codes` and `lut` have different lengths, or the length is not
+/// a multiple of [`DIVISION_BYTES`].
+///
+/// [`FastScanCodes`]: crate::vector::fastscan::FastScanCodes
+pub fn accumulate_fast_scan(
+    codes: &[u8],
+    lut: &[u8],
+    sums: &mut [u16; BLOCK_SIZE],
+) {
+    assert_eq!(codes.len(), lut.len());
+    assert_eq!(codes.len() % DIVISION_BYTES, 0);
+    (kernels().fast_scan)(codes, lut, sums)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use core::arch::x86_64::*;
+
+    // Sums up all the lanes.
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn horizontal_sum(v: __m256) -> f32 {
+        let lo = _mm256_castps256_ps128(v);
+        let hi = _mm256_extractf128_ps(v, 1);
+        let s = _mm_add_ps(lo, hi);
+        let s = _mm_add_ps(s, _mm_movehl_ps(s, s));
+        let s = _mm_add_ss(s, _mm_shuffle_ps(s, s, 0b01));
+        _mm_cvtss_f32(s)
+    }
+
+    // Caller must ensure `xs.len() == ys.len()`.
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn dot(xs: &[f32], ys: &[f32]) -> f32 {
+        let n = xs.len();
+        let xp = xs.as_ptr();
+        let yp = ys.as_ptr();
+        let mut acc0 = _mm256_setzero_ps();
+        let mut acc1 = _mm256_setzero_ps();
+        let mut acc2 = _mm256_setzero_ps();
+        let mut acc3 = _mm256_setzero_ps();
+        let mut i = 0;
+        while i + 32 <= n {
+            acc0 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(xp.add(i)),
+                _mm256_loadu_ps(yp.add(i)),
+                acc0,
+            );
+            acc1 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(xp.add(i + 8)),
+                _mm256_loadu_ps(yp.add(i + 8)),
+                acc1,
+            );
+            acc2 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(xp.add(i + 16)),
+                _mm256_loadu_ps(yp.add(i + 16)),
+                acc2,
+            );
+            acc3 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(xp.add(i + 24)),
+                _mm256_loadu_ps(yp.add(i + 24)),
+                acc3,
+            );
+            i += 32;
+        }
+        while i + 8 <= n {
+            acc0 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(xp.add(i)),
+                _mm256_loadu_ps(yp.add(i)),
+                acc0,
+            );
+            i += 8;
+        }
+        let acc = _mm256_add_ps(
+            _mm256_add_ps(acc0, acc1),
+            _mm256_add_ps(acc2, acc3),
+        );
+        let mut ans = horizontal_sum(acc);
+        while i < n {
+            ans = xs[i].mul_add(ys[i], ans);
+            i += 1;
+        }
+        ans
+    }
+
+    // Caller must ensure `xs.len() == ys.len()`.
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn squared_distance(xs: &[f32], ys: &[f32]) -> f32 {
+        let n = xs.len();
+        let xp = xs.as_ptr();
+        let yp = ys.as_ptr();
+        let mut acc0 = _mm256_setzero_ps();
+        let mut acc1 = _mm256_setzero_ps();
+        let mut i = 0;
+        while i + 16 <= n {
+            let d0 = _mm256_sub_ps(
+                _mm256_loadu_ps(xp.add(i)),
+                _mm256_loadu_ps(yp.add(i)),
+            );
+            let d1 = _mm256_sub_ps(
+                _mm256_loadu_ps(xp.add(i + 8)),
+                _mm256_loadu_ps(yp.add(i + 8)),
+            );
+            acc0 = _mm256_fmadd_ps(d0, d0, acc0);
+            acc1 = _mm256_fmadd_ps(d1, d1, acc1);
+            i += 16;
+        }
+        while i + 8 <= n {
+            let d = _mm256_sub_ps(
+                _mm256_loadu_ps(xp.add(i)),
+                _mm256_loadu_ps(yp.add(i)),
+            );
+            acc0 = _mm256_fmadd_ps(d, d, acc0);
+            i += 8;
+        }
+        let mut ans = horizontal_sum(_mm256_add_ps(acc0, acc1));
+        while i < n {
+            let d = xs[i] - ys[i];
+            ans = d.mul_add(d, ans);
+            i += 1;
+        }
+        ans
+    }
+
+    // Caller must ensure `ls.len() == rs.len()`.
+    #[target_feature(enable = "avx2,fma")]
+    pub(super) unsafe fn add_in(ls: &mut [f32], rs: &[f32]) {
+        let n = ls.len();
+        let lp = ls.as_mut_ptr();
+        let rp = rs.as_ptr();
+        let mut i = 0;
+        while i + 8 <= n {
+            let sum = _mm256_add_ps(
+                _mm256_loadu_ps(lp.add(i)),
+                _mm256_loadu_ps(rp.add(i)),
+            );
+            _mm256_storeu_ps(lp.add(i), sum);
+            i += 8;
+        }
+        while i < n {
+            ls[i] += rs[i];
+            i += 1;
+        }
+    }
+
+    // Caller must ensure `codes.len() == lut.len()` and the length is a
+    // multiple of 16.
+    //
+    // Looks up two divisions at a time; each 128-bit lane of a shuffle
+    // covers a division.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn fast_scan(
+        codes: &[u8],
+        lut: &[u8],
+        sums: &mut [u16; 32],
+    ) {
+        let n = codes.len();
+        let cp = codes.as_ptr();
+        let lp = lut.as_ptr();
+        let sp = sums.as_mut_ptr() as *mut __m256i;
+        let mask = _mm256_set1_epi8(0x0F);
+        // sums of vectors 0-15 and 16-31
+        let mut acc_lo = _mm256_loadu_si256(sp);
+        let mut acc_hi = _mm256_loadu_si256(sp.add(1));
+        let mut i = 0;
+        while i + 32 <= n {
+            let c = _mm256_loadu_si256(cp.add(i) as *const __m256i);
+            let t = _mm256_loadu_si256(lp.add(i) as *const __m256i);
+            let lo = _mm256_shuffle_epi8(t, _mm256_and_si256(c, mask));
+            let hi = _mm256_shuffle_epi8(
+                t,
+                _mm256_and_si256(_mm256_srli_epi16(c, 4), mask),
+            );
+            acc_lo = _mm256_add_epi16(
+                acc_lo,
+                _mm256_cvtepu8_epi16(_mm256_castsi256_si128(lo)),
+            );
+            acc_lo = _mm256_add_epi16(
+                acc_lo,
+                _mm256_cvtepu8_epi16(_mm256_extracti128_si256(lo, 1)),
+            );
+            acc_hi = _mm256_add_epi16(
+                acc_hi,
+                _mm256_cvtepu8_epi16(_mm256_castsi256_si128(hi)),
+            );
+            acc_hi = _mm256_add_epi16(
+                acc_hi,
+                _mm256_cvtepu8_epi16(_mm256_extracti128_si256(hi, 1)),
+            );
+            i += 32;
+        }
+        if i < n {
+            let mask = _mm_set1_epi8(0x0F);
+            let c = _mm_loadu_si128(cp.add(i) as *const __m128i);
+            let t = _mm_loadu_si128(lp.add(i) as *const __m128i);
+            let lo = _mm_shuffle_epi8(t, _mm_and_si128(c, mask));
+            let hi = _mm_shuffle_epi8(
+                t,
+                _mm_and_si128(_mm_srli_epi16(c, 4), mask),
+            );
+            acc_lo = _mm256_add_epi16(acc_lo, _mm256_cvtepu8_epi16(lo));
+            acc_hi = _mm256_add_epi16(acc_hi, _mm256_cvtepu8_epi16(hi));
+        }
+        _mm256_storeu_si256(sp, acc_lo);
+        _mm256_storeu_si256(sp.add(1), acc_hi);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use core::arch::aarch64::*;
+
+    // Caller must ensure `xs.len() == ys.len()`.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn dot(xs: &[f32], ys: &[f32]) -> f32 {
+        let n = xs.len();
+        let xp = xs.as_ptr();
+        let yp = ys.as_ptr();
+        let mut acc0 = vdupq_n_f32(0.0);
+        let mut acc1 = vdupq_n_f32(0.0);
+        let mut acc2 = vdupq_n_f32(0.0);
+        let mut acc3 = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i + 16 <= n {
+            acc0 = vfmaq_f32(acc0, vld1q_f32(xp.add(i)), vld1q_f32(yp.add(i)));
+            acc1 = vfmaq_f32(
+                acc1,
+                vld1q_f32(xp.add(i + 4)),
+                vld1q_f32(yp.add(i + 4)),
+            );
+            acc2 = vfmaq_f32(
+                acc2,
+                vld1q_f32(xp.add(i + 8)),
+                vld1q_f32(yp.add(i + 8)),
+            );
+            acc3 = vfmaq_f32(
+                acc3,
+                vld1q_f32(xp.add(i + 12)),
+                vld1q_f32(yp.add(i + 12)),
+            );
+            i += 16;
+        }
+        while i + 4 <= n {
+            acc0 = vfmaq_f32(acc0, vld1q_f32(xp.add(i)), vld1q_f32(yp.add(i)));
+            i += 4;
+        }
+        let acc = vaddq_f32(vaddq_f32(acc0, acc1), vaddq_f32(acc2, acc3));
+        let mut ans = vaddvq_f32(acc);
+        while i < n {
+            ans = xs[i].mul_add(ys[i], ans);
+            i += 1;
+        }
+        ans
+    }
+
+    // Caller must ensure `xs.len() == ys.len()`.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn squared_distance(xs: &[f32], ys: &[f32]) -> f32 {
+        let n = xs.len();
+        let xp = xs.as_ptr();
+        let yp = ys.as_ptr();
+        let mut acc0 = vdupq_n_f32(0.0);
+        let mut acc1 = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i + 8 <= n {
+            let d0 = vsubq_f32(vld1q_f32(xp.add(i)), vld1q_f32(yp.add(i)));
+            let d1 = vsubq_f32(
+                vld1q_f32(xp.add(i + 4)),
+                vld1q_f32(yp.add(i + 4)),
+            );
+            acc0 = vfmaq_f32(acc0, d0, d0);
+            acc1 = vfmaq_f32(acc1, d1, d1);
+            i += 8;
+        }
+        while i + 4 <= n {
+            let d = vsubq_f32(vld1q_f32(xp.add(i)), vld1q_f32(yp.add(i)));
+            acc0 = vfmaq_f32(acc0, d, d);
+            i += 4;
+        }
+        let mut ans = vaddvq_f32(vaddq_f32(acc0, acc1));
+        while i < n {
+            let d = xs[i] - ys[i];
+            ans = d.mul_add(d, ans);
+            i += 1;
+        }
+        ans
+    }
+
+    // Caller must ensure `ls.len() == rs.len()`.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn add_in(ls: &mut [f32], rs: &[f32]) {
+        let n = ls.len();
+        let lp = ls.as_mut_ptr();
+        let rp = rs.as_ptr();
+        let mut i = 0;
+        while i + 4 <= n {
+            let sum = vaddq_f32(vld1q_f32(lp.add(i)), vld1q_f32(rp.add(i)));
+            vst1q_f32(lp.add(i), sum);
+            i += 4;
+        }
+        while i < n {
+            ls[i] += rs[i];
+            i += 1;
+        }
+    }
+
+    // Caller must ensure `codes.len() == lut.len()` and the length is a
+    // multiple of 16.
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn fast_scan(
+        codes: &[u8],
+        lut: &[u8],
+        sums: &mut [u16; 32],
+    ) {
+        let n = codes.len();
+        let cp = codes.as_ptr();
+        let lp = lut.as_ptr();
+        let sp = sums.as_mut_ptr();
+        let mask = vdupq_n_u8(0x0F);
+        let mut acc0 = vld1q_u16(sp);
+        let mut acc1 = vld1q_u16(sp.add(8));
+        let mut acc2 = vld1q_u16(sp.add(16));
+        let mut acc3 = vld1q_u16(sp.add(24));
+        let mut i = 0;
+        while i + 16 <= n {
+            let c = vld1q_u8(cp.add(i));
+            let t = vld1q_u8(lp.add(i));
+            let lo = vqtbl1q_u8(t, vandq_u8(c, mask));
+            let hi = vqtbl1q_u8(t, vshrq_n_u8::<4>(c));
+            acc0 = vaddw_u8(acc0, vget_low_u8(lo));
+            acc1 = vaddw_u8(acc1, vget_high_u8(lo));
+            acc2 = vaddw_u8(acc2, vget_low_u8(hi));
+            acc3 = vaddw_u8(acc3, vget_high_u8(hi));
+            i += 16;
+        }
+        vst1q_u16(sp, acc0);
+        vst1q_u16(sp.add(8), acc1);
+        vst1q_u16(sp.add(16), acc2);
+        vst1q_u16(sp.add(24), acc3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LENGTHS: [usize; 11] = [0, 1, 3, 4, 7, 8, 15, 16, 33, 100, 1027];
+
+    fn make_vector(n: usize, seed: f32) -> Vec<f32> {
+        (0..n).map(|i| ((i as f32) * 0.37 + seed).sin()).collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        let tolerance = 1e-4 * expected.abs().max(1.0);
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "expected {} but got {}",
+            expected,
+            actual,
+        );
+    }
+
+    #[test]
+    fn dot_should_match_naive_dot_for_various_lengths() {
+        for n in LENGTHS {
+            let xs = make_vector(n, 0.5);
+            let ys = make_vector(n, 1.5);
+            assert_close(dot(&xs, &ys), linalg::dot_naive(&xs, &ys));
+        }
+    }
+
+    #[test]
+    fn squared_distance_should_match_naive_calculation_for_various_lengths() {
+        for n in LENGTHS {
+            let xs = make_vector(n, 0.5);
+            let ys = make_vector(n, 1.5);
+            let mut d = vec![0.0f32; n];
+            linalg::subtract(&xs, &ys, &mut d);
+            assert_close(
+                squared_distance(&xs, &ys),
+                linalg::dot_naive(&d, &d),
+            );
+        }
+    }
+
+    #[test]
+    fn add_in_should_match_naive_addition_for_various_lengths() {
+        for n in LENGTHS {
+            let mut ls = make_vector(n, 0.5);
+            let rs = make_vector(n, 1.5);
+            let expected: Vec<f32> =
+                ls.iter().zip(&rs).map(|(l, r)| l + r).collect();
+            add_in(&mut ls, &rs);
+            assert_eq!(ls, expected);
+        }
+    }
+
+    #[test]
+    fn accumulate_fast_scan_should_match_portable_lookups() {
+        for num_divisions in [1, 2, 3, 8] {
+            let n = num_divisions * DIVISION_BYTES;
+            let codes: Vec<u8> =
+                (0..n).map(|i| ((i * 37 + 11) % 256) as u8).collect();
+            let lut: Vec<u8> =
+                (0..n).map(|i| ((i * 53 + 7) % 256) as u8).collect();
+            let mut sums = [0u16; BLOCK_SIZE];
+            sums[5] = 1000;
+            let mut expected = sums;
+            fast_scan_portable(&codes, &lut, &mut expected);
+            accumulate_fast_scan(&codes, &lut, &mut sums);
+            assert_eq!(sums, expected);
+        }
+        // vector 16 shares the first byte with vector 0
+        let mut sums = [0u16; BLOCK_SIZE];
+        let mut codes = [0u8; DIVISION_BYTES];
+        codes[0] = 0x31;
+        let lut: Vec<u8> = (0..DIVISION_BYTES as u8).map(|i| i * 2).collect();
+        accumulate_fast_scan(&codes, &lut, &mut sums);
+        assert_eq!(sums[0], 2);
+        assert_eq!(sums[16], 6);
+        assert_eq!(sums[1], 0);
+    }
+
+    // Lengths that are not multiples of the lane widths (8 on AVX2 and 4 on
+    // NEON), so that both the vector loops and the remainders run.
+    const ODD_LENGTHS: [usize; 9] = [1, 3, 5, 7, 9, 13, 31, 33, 1027];
+
+    // Kernel tables available on the running CPU.
+    fn available_kernels() -> Vec<KernelTable> {
+        let mut tables = vec![portable()];
+        let detected = detect();
+        if detected.kind != KernelKind::Portable {
+            tables.push(detected);
+        }
+        tables
+    }
+
+    #[test]
+    fn every_available_kernel_should_match_scalar_kernel_for_odd_lengths() {
+        for table in available_kernels() {
+            for n in ODD_LENGTHS {
+                let xs = make_vector(n, 0.5);
+                let ys = make_vector(n, 1.5);
+                let mut d = vec![0.0f32; n];
+                linalg::subtract(&xs, &ys, &mut d);
+                assert_close(
+                    (table.dot)(&xs, &ys),
+                    linalg::dot_naive(&xs, &ys),
+                );
+                assert_close(
+                    (table.squared_distance)(&xs, &ys),
+                    linalg::dot_naive(&d, &d),
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn dot_should_panic_if_lengths_differ() {
+        dot(&[1.0, 2.0], &[1.0]);
+    }
+}
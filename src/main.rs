@@ -57,6 +57,8 @@ fn generate() -> Result<(), Error> {
         .build_with_events(move |event| {
             match event {
                 BuildEvent::StartingIdAssignment |
+                BuildEvent::StartingDuplicateDetection |
+                BuildEvent::StartingProjection |
                 BuildEvent::StartingPartitioning |
                 BuildEvent::StartingSubvectorDivision |
                 BuildEvent::StartingQuantization(_) => {
@@ -68,6 +70,18 @@ fn generate() -> Result<(), Error> {
                         event_time.elapsed().as_micros(),
                     );
                 },
+                BuildEvent::FinishedDuplicateDetection => {
+                    println!(
+                        "detected duplicates in {} μs",
+                        event_time.elapsed().as_micros(),
+                    );
+                },
+                BuildEvent::FinishedProjection => {
+                    println!(
+                        "projected data in {} μs",
+                        event_time.elapsed().as_micros(),
+                    );
+                },
                 BuildEvent::FinishedPartitioning => {
                     println!(
                         "partitioned data in {} μs",
@@ -104,6 +118,7 @@ fn generate() -> Result<(), Error> {
     // queries k-NN
     let time = std::time::Instant::now();
     let mut event_time = std::time::Instant::now();
+    let mut table_time = std::time::Instant::now();
     let results = db.query_with_events(
         &qv,
         K.try_into().unwrap(),
@@ -128,6 +143,19 @@ fn generate() -> Result<(), Error> {
                         event_time.elapsed().as_micros(),
                     );
                 },
+                QueryEvent::StartingDistanceTableCalculation(_) => {
+                    table_time = std::time::Instant::now();
+                },
+                QueryEvent::FinishedDistanceTableCalculation(i) => {
+                    println!(
+                        "calculated distance table for partition {} in {} μs",
+                        i,
+                        table_time.elapsed().as_micros(),
+                    );
+                },
+                QueryEvent::ScannedCandidates(i, n) => {
+                    println!("scanned {} candidates in partition {}", n, i);
+                },
                 QueryEvent::FinishedResultSelection => {
                     println!(
                         "selected results in {} μs",
@@ -1,9 +1,16 @@
 //! N-best selection.
 
+use core::cmp::Ordering;
 use core::iter::Iterator;
 use core::ops::{Deref, DerefMut};
+use std::collections::BinaryHeap;
 
 /// N-best elements.
+///
+/// Candidates are kept in ascending order of their keys.
+/// Candidates with equal keys are ordered by when they were pushed; i.e.,
+/// an earlier candidate precedes and survives a later one.
+/// Mutating candidates through [`DerefMut`] may break this order.
 pub struct NBestByKey<T, K, F>
 where
     F: FnMut(&T) -> K,
@@ -30,6 +37,14 @@ where
     pub fn into_vec(self) -> Vec<T> {
         self.candidates
     }
+
+    /// Consumes the [`NBestByKey`] and returns the candidates in ascending
+    /// order of their keys.
+    ///
+    /// Candidates with equal keys are in the order they were pushed.
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        self.candidates
+    }
 }
 
 impl<T, K, F> NBestByKey<T, K, F>
@@ -39,28 +54,25 @@ where
 {
     /// Pushes a new candidate to the n-best.
     ///
-    /// If there are less than `n` candidates, the candidate is pushed to the
-    /// current candidates.
-    ///
-    /// Replaces an item in the current n-best with `candidate`, such that
-    /// `candidate` is less than that item (in terms of `F(&T) -> K`).
-    /// That replaced item further replaces another item in the current n-best,
-    /// such that the replaced item is less than that item.
-    /// Repeats the above process until no replacement occurs.
+    /// Inserts `candidate` after all the current candidates whose keys are
+    /// less than or equal to that of `candidate`.
+    /// If there are more than `n` candidates after the insertion, the last
+    /// candidate is pushed out.
     ///
     /// Returns the item pushed out unless there are less than `n` candidates.
-    pub fn push(&mut self, mut candidate: T) -> Option<T> {
-        if self.candidates.len() < self.n {
-            self.candidates.push(candidate);
-            return None;
+    pub fn push(&mut self, candidate: T) -> Option<T> {
+        let key = (self.f)(&candidate);
+        let f = &mut self.f;
+        let pos = self.candidates.partition_point(|item| !key.lt(&f(item)));
+        if pos >= self.n {
+            return Some(candidate);
         }
-        while let Some(to_replace) = self.candidates
-            .iter_mut()
-            .find(|item| (self.f)(&candidate).lt(&(self.f)(item)))
-        {
-            std::mem::swap(&mut candidate, to_replace);
+        self.candidates.insert(pos, candidate);
+        if self.candidates.len() > self.n {
+            self.candidates.pop()
+        } else {
+            None
         }
-        Some(candidate)
     }
 }
 
@@ -84,12 +96,12 @@ where
     }
 }
 
-impl<T, K, F> Into<Vec<T>> for NBestByKey<T, K, F>
+impl<T, K, F> From<NBestByKey<T, K, F>> for Vec<T>
 where
     F: FnMut(&T) -> K,
 {
-    fn into(self) -> Vec<T> {
-        self.into_vec()
+    fn from(val: NBestByKey<T, K, F>) -> Self {
+        val.into_vec()
     }
 }
 
@@ -135,3 +147,182 @@ where
         n_best
     }
 }
+
+/// Iterator that lazily merges sorted sequences.
+///
+/// Yields items in ascending order of their keys, provided every sequence is
+/// sorted in ascending order of the keys.
+/// Items with equal keys are yielded in the order of the sequences.
+///
+/// Created by [`merge_sorted_by_key`].
+pub struct MergeSortedByKey<I, K, F>
+where
+    I: Iterator,
+    F: FnMut(&I::Item) -> K,
+{
+    sources: Vec<I>,
+    f: F,
+    heap: BinaryHeap<MergeEntry<I::Item, K>>,
+}
+
+/// Lazily merges sequences sorted in ascending order of their keys.
+///
+/// Holds at most one item from each sequence at a time.
+pub fn merge_sorted_by_key<L, I, K, F>(
+    lists: L,
+    mut f: F,
+) -> MergeSortedByKey<I::IntoIter, K, F>
+where
+    L: IntoIterator<Item = I>,
+    I: IntoIterator,
+    F: FnMut(&I::Item) -> K,
+    K: PartialOrd,
+{
+    let mut sources: Vec<I::IntoIter> = lists
+        .into_iter()
+        .map(|l| l.into_iter())
+        .collect();
+    let mut heap = BinaryHeap::with_capacity(sources.len());
+    for (source, it) in sources.iter_mut().enumerate() {
+        if let Some(item) = it.next() {
+            let key = f(&item);
+            heap.push(MergeEntry { key, source, item });
+        }
+    }
+    MergeSortedByKey { sources, f, heap }
+}
+
+impl<I, K, F> Iterator for MergeSortedByKey<I, K, F>
+where
+    I: Iterator,
+    F: FnMut(&I::Item) -> K,
+    K: PartialOrd,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let MergeEntry { source, item, .. } = self.heap.pop()?;
+        if let Some(next) = self.sources[source].next() {
+            let key = (self.f)(&next);
+            self.heap.push(MergeEntry { key, source, item: next });
+        }
+        Some(item)
+    }
+}
+
+// Entry in the heap of `MergeSortedByKey`.
+//
+// Ordered in reverse so that `BinaryHeap` pops the smallest key first.
+// Incomparable keys are regarded as equal.
+struct MergeEntry<T, K> {
+    key: K,
+    source: usize,
+    item: T,
+}
+
+impl<T, K> Ord for MergeEntry<T, K>
+where
+    K: PartialOrd,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key
+            .partial_cmp(&self.key)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl<T, K> PartialOrd for MergeEntry<T, K>
+where
+    K: PartialOrd,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, K> PartialEq for MergeEntry<T, K>
+where
+    K: PartialOrd,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T, K> Eq for MergeEntry<T, K> where K: PartialOrd {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn n_best_by_key_should_keep_n_smallest_in_ascending_order() {
+        let n_best = [5, 3, 8, 1, 9, 2]
+            .into_iter()
+            .n_best_by_key(3, |x| *x);
+        assert_eq!(n_best.into_sorted_vec(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn n_best_by_key_should_prefer_earlier_candidates_on_ties() {
+        let n_best = [(1, 'a'), (0, 'b'), (1, 'c'), (0, 'd'), (1, 'e')]
+            .into_iter()
+            .n_best_by_key(3, |(k, _)| *k);
+        assert_eq!(
+            n_best.into_sorted_vec(),
+            vec![(0, 'b'), (0, 'd'), (1, 'a')],
+        );
+    }
+
+    #[test]
+    fn push_should_return_pushed_out_candidate() {
+        let mut n_best = NBestByKey::new(2, |x: &i32| *x);
+        assert_eq!(n_best.push(3), None);
+        assert_eq!(n_best.push(1), None);
+        assert_eq!(n_best.push(2), Some(3));
+        assert_eq!(n_best.push(2), Some(2));
+        assert_eq!(n_best.into_sorted_vec(), vec![1, 2]);
+    }
+
+    #[test]
+    fn n_best_by_key_with_zero_n_should_be_empty() {
+        let n_best = [1, 2].into_iter().n_best_by_key(0, |x| *x);
+        assert!(n_best.into_sorted_vec().is_empty());
+    }
+
+    #[test]
+    fn merge_sorted_by_key_should_merge_sorted_lists() {
+        let merged: Vec<i32> = merge_sorted_by_key(
+            vec![vec![1, 4, 7], vec![], vec![2, 3, 9], vec![5]],
+            |x| *x,
+        ).collect();
+        assert_eq!(merged, vec![1, 2, 3, 4, 5, 7, 9]);
+    }
+
+    #[test]
+    fn merge_sorted_by_key_should_yield_ties_in_list_order() {
+        let merged: Vec<(i32, char)> = merge_sorted_by_key(
+            vec![vec![(1, 'a'), (2, 'b')], vec![(1, 'c'), (2, 'd')]],
+            |(k, _)| *k,
+        ).collect();
+        assert_eq!(merged, vec![(1, 'a'), (1, 'c'), (2, 'b'), (2, 'd')]);
+    }
+
+    #[test]
+    fn merge_sorted_by_key_should_pull_items_lazily() {
+        let pulled = core::cell::Cell::new(0);
+        let lists = (0..3).map(|i| {
+            let pulled = &pulled;
+            (0..100).map(move |j| {
+                pulled.set(pulled.get() + 1);
+                i * 100 + j
+            })
+        });
+        let first: Vec<i32> = merge_sorted_by_key(lists, |x| *x)
+            .take(2)
+            .collect();
+        assert_eq!(first, vec![0, 1]);
+        assert_eq!(pulled.get(), 5);
+    }
+}
@@ -1,6 +1,9 @@
 //! Provides traits for numbers.
 //!
-//! Focuses on floating point numbers so far.
+//! Focuses on floating point numbers, and [`fixed::Fixed`] for targets
+//! without a fast FPU.
+
+pub mod fixed;
 
 /// Represents a number that has zero.
 pub trait Zero {
@@ -109,3 +112,21 @@ impl Sqrt for f64 {
         self.sqrt()
     }
 }
+
+/// Represents a number that may be infinite or NaN.
+pub trait Finite {
+    /// Returns if the number is neither infinite nor NaN.
+    fn is_finite(&self) -> bool;
+}
+
+impl Finite for f32 {
+    fn is_finite(&self) -> bool {
+        f32::is_finite(*self)
+    }
+}
+
+impl Finite for f64 {
+    fn is_finite(&self) -> bool {
+        f64::is_finite(*self)
+    }
+}
@@ -0,0 +1,319 @@
+//! Fixed-point numbers.
+//!
+//! Lets targets without a fast FPU run clustering and queries with integer
+//! arithmetic.
+
+use core::ops::{
+    Add,
+    AddAssign,
+    Div,
+    DivAssign,
+    Mul,
+    MulAssign,
+    Neg,
+    Sub,
+    SubAssign,
+};
+use rand::Rng;
+use rand::distributions::uniform::{
+    SampleBorrow,
+    SampleUniform,
+    UniformInt,
+    UniformSampler,
+};
+
+use super::{Abs, Finite, FromAs, Infinity, One, Sqrt, Zero};
+
+/// Signed fixed-point number with 16 fractional bits (Q15.16).
+///
+/// Backed by an [`i32`] and uses [`i64`] for intermediate results.
+/// Arithmetic saturates at [`Fixed::MIN`] and [`Fixed::MAX`] instead of
+/// overflowing, and [`Fixed::MAX`] also stands for positive infinity.
+/// Division by zero saturates in the direction of the dividend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    /// Number of fractional bits.
+    pub const FRAC_BITS: u32 = 16;
+
+    /// Largest value.
+    pub const MAX: Fixed = Fixed(i32::MAX);
+
+    /// Smallest value.
+    pub const MIN: Fixed = Fixed(i32::MIN);
+
+    /// Smallest positive value.
+    pub const DELTA: Fixed = Fixed(1);
+
+    const ONE_BITS: i32 = 1 << Self::FRAC_BITS;
+
+    /// Creates a number from its raw representation.
+    pub const fn from_bits(bits: i32) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw representation.
+    pub const fn to_bits(self) -> i32 {
+        self.0
+    }
+
+    /// Converts an [`f32`] with saturation.
+    ///
+    /// NaN becomes zero.
+    pub fn from_f32(x: f32) -> Self {
+        Self((x * Self::ONE_BITS as f32) as i32)
+    }
+
+    /// Converts into an [`f32`].
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / Self::ONE_BITS as f32
+    }
+
+    fn saturate(x: i64) -> Self {
+        Self(x.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+}
+
+impl From<i16> for Fixed {
+    fn from(x: i16) -> Self {
+        Self((x as i32) << Self::FRAC_BITS)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Self::saturate((self.0 as i64 * rhs.0 as i64) >> Self::FRAC_BITS)
+    }
+}
+
+impl MulAssign for Fixed {
+    fn mul_assign(&mut self, rhs: Self) {
+        *self = *self * rhs;
+    }
+}
+
+impl Div for Fixed {
+    type Output = Self;
+
+    fn div(self, rhs: Self) -> Self {
+        if rhs.0 == 0 {
+            return if self.0 < 0 { Self::MIN } else { Self::MAX };
+        }
+        Self::saturate(((self.0 as i64) << Self::FRAC_BITS) / rhs.0 as i64)
+    }
+}
+
+impl DivAssign for Fixed {
+    fn div_assign(&mut self, rhs: Self) {
+        *self = *self / rhs;
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self(self.0.saturating_neg())
+    }
+}
+
+impl Zero for Fixed {
+    fn zero() -> Fixed {
+        Fixed(0)
+    }
+}
+
+impl One for Fixed {
+    fn one() -> Fixed {
+        Fixed(Fixed::ONE_BITS)
+    }
+}
+
+impl Infinity for Fixed {
+    fn infinity() -> Fixed {
+        Fixed::MAX
+    }
+}
+
+impl Finite for Fixed {
+    /// Always true; saturated values are still comparable.
+    fn is_finite(&self) -> bool {
+        true
+    }
+}
+
+impl FromAs<usize> for Fixed {
+    fn from_as(t: usize) -> Fixed {
+        Fixed::saturate((t.min(i32::MAX as usize) as i64) << Fixed::FRAC_BITS)
+    }
+}
+
+impl Abs for Fixed {
+    fn abs(self) -> Fixed {
+        Fixed(self.0.saturating_abs())
+    }
+}
+
+impl Sqrt for Fixed {
+    /// Returns zero for a negative number.
+    fn sqrt(self) -> Fixed {
+        if self.0 <= 0 {
+            return Fixed(0);
+        }
+        // sqrt(x / 2^16) * 2^16 = sqrt(x * 2^16)
+        Fixed(isqrt((self.0 as u64) << Fixed::FRAC_BITS) as i32)
+    }
+}
+
+// Calculates the integer square root (floor).
+fn isqrt(n: u64) -> u64 {
+    let mut x = n;
+    let mut result = 0u64;
+    let mut bit = 1u64 << 62;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if x >= result + bit {
+            x -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+/// Uniform sampler of [`Fixed`].
+#[derive(Clone, Copy, Debug)]
+pub struct UniformFixed(UniformInt<i32>);
+
+impl UniformSampler for UniformFixed {
+    type X = Fixed;
+
+    fn new<B1, B2>(low: B1, high: B2) -> Self
+    where
+        B1: SampleBorrow<Self::X> + Sized,
+        B2: SampleBorrow<Self::X> + Sized,
+    {
+        Self(UniformInt::<i32>::new(low.borrow().0, high.borrow().0))
+    }
+
+    fn new_inclusive<B1, B2>(low: B1, high: B2) -> Self
+    where
+        B1: SampleBorrow<Self::X> + Sized,
+        B2: SampleBorrow<Self::X> + Sized,
+    {
+        Self(UniformInt::<i32>::new_inclusive(
+            low.borrow().0,
+            high.borrow().0,
+        ))
+    }
+
+    fn sample<R>(&self, rng: &mut R) -> Self::X
+    where
+        R: Rng + ?Sized,
+    {
+        Fixed(self.0.sample(rng))
+    }
+}
+
+impl SampleUniform for Fixed {
+    type Sampler = UniformFixed;
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroUsize;
+
+    use super::*;
+    use crate::kmeans::cluster;
+    use crate::linalg::dot;
+    use crate::vector::BlockVectorSet;
+
+    fn fixed(x: f32) -> Fixed {
+        Fixed::from_f32(x)
+    }
+
+    #[test]
+    fn fixed_should_multiply_and_divide() {
+        assert_eq!(fixed(1.5) * fixed(-2.0), fixed(-3.0));
+        assert_eq!(fixed(3.0) / fixed(0.5), fixed(6.0));
+    }
+
+    #[test]
+    fn fixed_should_saturate_on_overflow() {
+        assert_eq!(fixed(30000.0) * fixed(30000.0), Fixed::MAX);
+        assert_eq!(fixed(-30000.0) * fixed(30000.0), Fixed::MIN);
+        assert_eq!(Fixed::MAX + Fixed::DELTA, Fixed::MAX);
+        assert_eq!(fixed(1.0) / Fixed::zero(), Fixed::infinity());
+    }
+
+    #[test]
+    fn fixed_should_calculate_square_root() {
+        assert_eq!(fixed(4.0).sqrt(), fixed(2.0));
+        assert_eq!(fixed(0.25).sqrt(), fixed(0.5));
+        assert_eq!(fixed(-1.0).sqrt(), Fixed::zero());
+    }
+
+    #[test]
+    fn fixed_should_convert_from_usize() {
+        assert_eq!(Fixed::from_as(3), fixed(3.0));
+        assert_eq!(Fixed::from_as(usize::MAX), Fixed::MAX);
+    }
+
+    #[test]
+    fn fixed_can_calculate_dot_product() {
+        let xs: Vec<Fixed> = [1.0, 2.0, 0.5].into_iter().map(fixed).collect();
+        let ys: Vec<Fixed> = [2.0, -1.0, 4.0].into_iter().map(fixed).collect();
+        assert_eq!(dot(&xs, &ys), fixed(2.0));
+    }
+
+    #[test]
+    fn fixed_vectors_can_be_clustered() {
+        let data: Vec<Fixed> = [
+            0.0, 0.0,
+            0.01, 0.0,
+            5.0, 5.0,
+            5.0, 5.01,
+        ].into_iter().map(fixed).collect();
+        let vs = BlockVectorSet::chunk(data, NonZeroUsize::new(2).unwrap())
+            .unwrap();
+        let codebook = cluster(&vs, NonZeroUsize::new(2).unwrap()).unwrap();
+        assert_eq!(codebook.indices[0], codebook.indices[1]);
+        assert_eq!(codebook.indices[2], codebook.indices[3]);
+        assert_ne!(codebook.indices[0], codebook.indices[2]);
+    }
+}
@@ -5,15 +5,24 @@
 
 use core::num::NonZeroUsize;
 
+use crate::distance::{Distance, SquaredL2};
 use crate::error::Error;
-use crate::kmeans::{ClusterEvent, Codebook, Scalar, cluster_with_events};
-use crate::linalg::{add_in, subtract_in};
-use crate::slice::AsSlice;
-use crate::vector::{BlockVectorSet, VectorSet};
+use crate::kmeans::{
+    ClusterEvent,
+    Codebook,
+    EventControl,
+    Scalar,
+    cluster_sequential_with_distance_and_events,
+    cluster_with_distance_and_events,
+};
+use crate::linalg::{add_in, subtract, subtract_in};
+use crate::slice::{AsMutSlice, AsSlice};
+use crate::vector::{BlockVectorSet, VectorSet, VectorSetMut};
 
 /// Partitions.
 ///
-/// Moves the input vector set to save memory.
+/// [`Partitioning`] moves the input vector set to save memory, whereas
+/// [`PartitioningCopy`] leaves it intact.
 pub struct Partitions<T, VS> {
     /// Codebook of the partition.
     pub codebook: Codebook<T>,
@@ -82,7 +91,7 @@ where
             );
             let codebook = &self.partitions.codebook;
             let ci = codebook.indices[self.next_index];
-            let centroid = codebook.centroids.get(ci).as_slice();
+            let centroid = codebook.centroids.get(ci);
             add_in(&mut v[..], centroid);
             self.next_index += 1;
             Some(v)
@@ -98,47 +107,298 @@ where
     Self: Sized,
 {
     /// Partitions the vector set in place.
-    fn partition(self, p: NonZeroUsize) -> Result<Partitions<T, VS>, Error> {
+    fn partition(self, p: NonZeroUsize) -> Result<Partitions<T, VS>, Error>
+    where
+        SquaredL2: Distance<T>,
+    {
         self.partition_with_events(p, |_| ())
     }
 
     /// Partitions the vector set in place.
-    fn partition_with_events<EV>(
+    ///
+    /// `event_handler` may cancel the partitioning.
+    fn partition_with_events<EV, C>(
+        self,
+        p: NonZeroUsize,
+        event_handler: EV,
+    ) -> Result<Partitions<T, VS>, Error>
+    where
+        SquaredL2: Distance<T>,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl,
+    {
+        self.partition_with_distance_and_events(p, &SquaredL2, event_handler)
+    }
+
+    /// Partitions the vector set in place under a given distance.
+    ///
+    /// `event_handler` may cancel the partitioning.
+    fn partition_with_distance_and_events<D, EV, C>(
         self,
         p: NonZeroUsize,
+        distance: &D,
         event_handler: EV,
     ) -> Result<Partitions<T, VS>, Error>
     where
-        EV: FnMut(ClusterEvent<'_, T>) -> ();
+        D: Distance<T> + ?Sized,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl;
+
+    /// Partitions the vector set in place with k-means in sequential passes.
+    ///
+    /// See [`cluster_sequential_with_distance_and_events`] for
+    /// `sample_size`.
+    ///
+    /// `event_handler` may cancel the partitioning.
+    fn partition_sequential_with_events<EV, C>(
+        self,
+        p: NonZeroUsize,
+        sample_size: NonZeroUsize,
+        event_handler: EV,
+    ) -> Result<Partitions<T, VS>, Error>
+    where
+        SquaredL2: Distance<T>,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl,
+    {
+        self.partition_sequential_with_distance_and_events(
+            p,
+            sample_size,
+            &SquaredL2,
+            event_handler,
+        )
+    }
+
+    /// Partitions the vector set in place with k-means in sequential passes
+    /// under a given distance.
+    ///
+    /// See [`cluster_sequential_with_distance_and_events`] for
+    /// `sample_size` and supported distances.
+    ///
+    /// `event_handler` may cancel the partitioning.
+    fn partition_sequential_with_distance_and_events<D, EV, C>(
+        self,
+        p: NonZeroUsize,
+        sample_size: NonZeroUsize,
+        distance: &D,
+        event_handler: EV,
+    ) -> Result<Partitions<T, VS>, Error>
+    where
+        D: Distance<T> + ?Sized,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl;
 }
 
-impl<T> Partitioning<T, Self> for BlockVectorSet<T>
+impl<T, VS> Partitioning<T, VS> for VS
 where
     T: Scalar,
+    VS: VectorSetMut<T>,
+    VS::Vector: AsMutSlice<T>,
 {
-    fn partition_with_events<EV>(
+    fn partition_with_distance_and_events<D, EV, C>(
         mut self,
         p: NonZeroUsize,
+        distance: &D,
         event_handler: EV,
-    ) -> Result<Partitions<T, Self>, Error>
+    ) -> Result<Partitions<T, VS>, Error>
     where
-        EV: FnMut(ClusterEvent<'_, T>) -> (),
+        D: Distance<T> + ?Sized,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl,
     {
-        let codebook = cluster_with_events(&self, p, event_handler)?;
-        for i in 0..p.get() {
-            let centroid = codebook.centroids.get(i);
-            for (j, _) in codebook.indices
-                .iter()
-                .enumerate()
-                .filter(|(_, &ci)| ci == i)
-            {
-                let v = self.get_mut(j);
-                subtract_in(v, centroid);
-            }
-        }
+        let codebook = cluster_with_distance_and_events(
+            &self,
+            p,
+            distance,
+            event_handler,
+        )?;
+        subtract_centroids(&mut self, &codebook);
+        Ok(Partitions {
+            codebook,
+            residues: self,
+        })
+    }
+
+    fn partition_sequential_with_distance_and_events<D, EV, C>(
+        mut self,
+        p: NonZeroUsize,
+        sample_size: NonZeroUsize,
+        distance: &D,
+        event_handler: EV,
+    ) -> Result<Partitions<T, VS>, Error>
+    where
+        D: Distance<T> + ?Sized,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl,
+    {
+        let codebook = cluster_sequential_with_distance_and_events(
+            &self,
+            p,
+            sample_size,
+            distance,
+            event_handler,
+        )?;
+        subtract_centroids(&mut self, &codebook);
         Ok(Partitions {
             codebook,
             residues: self,
         })
     }
 }
+
+// Subtracts the assigned centroids from vectors in a single sequential pass.
+fn subtract_centroids<T, VS>(vs: &mut VS, codebook: &Codebook<T>)
+where
+    T: Scalar,
+    VS: VectorSetMut<T>,
+    VS::Vector: AsMutSlice<T>,
+{
+    for (j, &ci) in codebook.indices.iter().enumerate() {
+        let v = vs.get_mut(j).as_mut_slice();
+        subtract_in(v, codebook.centroids.get(ci));
+    }
+}
+
+/// Implementation of partitioning that keeps the input vector set intact.
+///
+/// Residues are written into a new [`BlockVectorSet`], which doubles the
+/// memory footprint but allows the original vectors to be reused; e.g., for
+/// exact re-ranking.
+pub trait PartitioningCopy<T> {
+    /// Partitions the vector set into a copy.
+    fn partition_copy(
+        &self,
+        p: NonZeroUsize,
+    ) -> Result<Partitions<T, BlockVectorSet<T>>, Error>
+    where
+        SquaredL2: Distance<T>,
+    {
+        self.partition_copy_with_events(p, |_| ())
+    }
+
+    /// Partitions the vector set into a copy.
+    ///
+    /// `event_handler` may cancel the partitioning.
+    fn partition_copy_with_events<EV, C>(
+        &self,
+        p: NonZeroUsize,
+        event_handler: EV,
+    ) -> Result<Partitions<T, BlockVectorSet<T>>, Error>
+    where
+        SquaredL2: Distance<T>,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl,
+    {
+        self.partition_copy_with_distance_and_events(
+            p,
+            &SquaredL2,
+            event_handler,
+        )
+    }
+
+    /// Partitions the vector set into a copy under a given distance.
+    ///
+    /// `event_handler` may cancel the partitioning.
+    fn partition_copy_with_distance_and_events<D, EV, C>(
+        &self,
+        p: NonZeroUsize,
+        distance: &D,
+        event_handler: EV,
+    ) -> Result<Partitions<T, BlockVectorSet<T>>, Error>
+    where
+        D: Distance<T> + ?Sized,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl;
+}
+
+impl<T, VS> PartitioningCopy<T> for VS
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    fn partition_copy_with_distance_and_events<D, EV, C>(
+        &self,
+        p: NonZeroUsize,
+        distance: &D,
+        event_handler: EV,
+    ) -> Result<Partitions<T, BlockVectorSet<T>>, Error>
+    where
+        D: Distance<T> + ?Sized,
+        EV: FnMut(ClusterEvent<'_, T>) -> C,
+        C: EventControl,
+    {
+        let codebook = cluster_with_distance_and_events(
+            self,
+            p,
+            distance,
+            event_handler,
+        )?;
+        let m = self.vector_size();
+        let mut residues: Vec<T> = vec![T::zero(); self.len() * m];
+        for ((i, v), residue) in self.iter().zip(residues.chunks_mut(m)) {
+            let centroid = codebook.centroids.get(codebook.indices[i]);
+            subtract(v.as_slice(), centroid, residue);
+        }
+        let residues = BlockVectorSet::chunk(
+            residues,
+            m.try_into().unwrap(),
+        )?;
+        Ok(Partitions { codebook, residues })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partition_copy_should_keep_input_vectors_intact() {
+        let data: Vec<f32> = vec![
+            0.0, 0.0,
+            1.0, 0.0,
+            10.0, 10.0,
+            11.0, 10.0,
+        ];
+        let vs = BlockVectorSet::chunk(data.clone(), 2.try_into().unwrap())
+            .unwrap();
+        let partitions = vs.partition_copy(2.try_into().unwrap()).unwrap();
+        for i in 0..vs.len() {
+            assert_eq!(vs.get(i), &data[i * 2..i * 2 + 2]);
+        }
+        let reconstructed: Vec<f32> =
+            partitions.all_vectors().flatten().collect();
+        assert_eq!(reconstructed, data);
+    }
+
+    #[test]
+    fn partition_sequential_should_separate_distant_clusters() {
+        let data: Vec<f32> = vec![
+            0.0, 0.0,
+            1.0, 0.0,
+            0.0, 1.0,
+            10.0, 10.0,
+            11.0, 10.0,
+            10.0, 11.0,
+        ];
+        let vs = BlockVectorSet::chunk(data.clone(), 2.try_into().unwrap())
+            .unwrap();
+        let partitions = vs
+            .partition_sequential_with_events(
+                2.try_into().unwrap(),
+                6.try_into().unwrap(),
+                |_| (),
+            )
+            .unwrap();
+        let indices = &partitions.codebook.indices;
+        assert_eq!(indices[0], indices[1]);
+        assert_eq!(indices[0], indices[2]);
+        assert_eq!(indices[3], indices[4]);
+        assert_eq!(indices[3], indices[5]);
+        assert_ne!(indices[0], indices[3]);
+        let reconstructed: Vec<f32> =
+            partitions.all_vectors().flatten().collect();
+        for (x, y) in reconstructed.iter().zip(data.iter()) {
+            assert!((x - y).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,595 @@
+//! Linear projections of vectors.
+//!
+//! A [`Projection`] maps vectors to another space before they are indexed;
+//! e.g., onto their principal components with [`train_pca`] to search
+//! high-dimensional embeddings in fewer dimensions, or a
+//! [`random_rotation`] to spread variances evenly across subvectors. A
+//! database built with a projection applies it to query vectors too.
+//! [`random_hyperplanes`] hash vectors by the signs of their projections
+//! instead.
+
+use core::num::NonZeroUsize;
+use rand::SeedableRng;
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
+
+use crate::error::Error;
+use crate::kmeans::Scalar;
+use crate::linalg::{dot, norm2, scale_in};
+use crate::slice::AsSlice;
+use crate::vector::{BlockVectorSet, VectorSet};
+
+pub mod proto;
+
+// Number of subspace iterations to find principal components.
+const NUM_PCA_ITERATIONS: usize = 20;
+// Maximum number of Jacobi sweeps to diagonalize a symmetric matrix.
+const MAX_JACOBI_SWEEPS: usize = 50;
+// Seed of the random initial subspace, which keeps training deterministic.
+const PCA_SEED: u64 = 0;
+// Number of uniform samples summed up to approximate a normal sample.
+const NUM_NORMAL_TERMS: usize = 12;
+
+/// Linear projection of vectors.
+///
+/// Multiplies a vector by a matrix whose rows are the axes of the output
+/// space.
+#[derive(Clone, Debug)]
+pub struct Projection<T> {
+    // Each row is an axis of the output space.
+    matrix: BlockVectorSet<T>,
+}
+
+impl<T> Projection<T> {
+    /// Creates a projection with a given matrix.
+    ///
+    /// Each vector in `matrix` is an axis of the output space, so
+    /// `matrix.len()` is the output size, and `matrix.vector_size()` is the
+    /// input size.
+    ///
+    /// Fails if `matrix` is empty.
+    pub fn new(matrix: BlockVectorSet<T>) -> Result<Self, Error> {
+        if matrix.is_empty() {
+            return Err(Error::InvalidArgs(
+                "projection matrix must not be empty".to_string(),
+            ));
+        }
+        Ok(Self { matrix })
+    }
+
+    /// Returns the size of an input vector.
+    pub const fn input_size(&self) -> usize {
+        self.matrix.vector_size()
+    }
+
+    /// Returns the size of a projected vector.
+    pub fn output_size(&self) -> usize {
+        self.matrix.len()
+    }
+
+    /// Returns the matrix.
+    pub fn matrix(&self) -> &BlockVectorSet<T> {
+        &self.matrix
+    }
+}
+
+impl<T> Projection<T>
+where
+    T: Scalar,
+{
+    /// Projects a given vector.
+    ///
+    /// Fails if the size of `v` does not match the input size.
+    pub fn project<V>(&self, v: &V) -> Result<Vec<T>, Error>
+    where
+        V: AsSlice<T> + ?Sized,
+    {
+        let v = v.as_slice();
+        if v.len() != self.input_size() {
+            return Err(Error::InvalidArgs(format!(
+                "vector size {} does not match projection input size {}",
+                v.len(),
+                self.input_size(),
+            )));
+        }
+        Ok((0..self.output_size())
+            .map(|i| dot(self.matrix.get(i), v))
+            .collect())
+    }
+
+    /// Projects all the vectors in a given vector set.
+    ///
+    /// Fails if the vector size does not match the input size.
+    pub fn project_vector_set<VS>(
+        &self,
+        vs: &VS,
+    ) -> Result<BlockVectorSet<T>, Error>
+    where
+        VS: VectorSet<T>,
+    {
+        let mut data: Vec<T> =
+            Vec::with_capacity(vs.len() * self.output_size());
+        for (_, v) in vs.iter() {
+            data.extend(self.project(v)?);
+        }
+        BlockVectorSet::chunk(data, self.output_size().try_into().unwrap())
+    }
+
+    /// Composes this projection followed by another one.
+    ///
+    /// The composed projection maps a vector `v` to
+    /// `next.project(self.project(v))` at the cost of a single projection.
+    ///
+    /// Fails if the input size of `next` does not match the output size of
+    /// this projection.
+    pub fn then(&self, next: &Projection<T>) -> Result<Projection<T>, Error> {
+        if next.input_size() != self.output_size() {
+            return Err(Error::InvalidArgs(format!(
+                "projection input size {} does not match output size {}",
+                next.input_size(),
+                self.output_size(),
+            )));
+        }
+        let m = self.input_size();
+        let mut data: Vec<T> = Vec::with_capacity(next.output_size() * m);
+        for i in 0..next.output_size() {
+            let mut axis = vec![T::zero(); m];
+            for (r, &weight) in next.matrix.get(i).iter().enumerate() {
+                for (a, &b) in axis.iter_mut().zip(self.matrix.get(r)) {
+                    *a += weight * b;
+                }
+            }
+            data.extend(axis);
+        }
+        Projection::new(BlockVectorSet::chunk(data, m.try_into().unwrap())?)
+    }
+}
+
+/// Generates a random rotation of vectors of a given size.
+///
+/// The rotation is an orthonormal matrix drawn from the seeded random
+/// generator, so the same `seed` always gives the same rotation. It
+/// preserves distances and inner products, and spreads the variances of
+/// vectors across elements; e.g., subvectors of embeddings whose leading
+/// elements dominate get comparable variances, which helps product
+/// quantization as a cheap alternative to optimized product quantization
+/// (OPQ).
+pub fn random_rotation<T>(size: NonZeroUsize, seed: u64) -> Projection<T>
+where
+    T: Scalar,
+{
+    let m = size.get();
+    // normal samples make the rotation nearly uniform
+    let mut matrix: Vec<T> = sample_normals(m * m, seed);
+    orthonormalize_rows(&mut matrix, m);
+    Projection::new(BlockVectorSet::chunk(matrix, size).unwrap()).unwrap()
+}
+
+/// Generates random hyperplanes through the origin for vectors of a given
+/// size.
+///
+/// Each row of the projection is the normal of a hyperplane drawn from
+/// the seeded random generator, so the signs of a projected vector tell
+/// which side of each hyperplane the vector is on. Vectors at a small
+/// angle are likely on the same sides, which makes the signs a
+/// locality-sensitive hash of the vectors (SimHash). The normals are not
+/// orthonormalized, so there may be more hyperplanes than elements.
+pub fn random_hyperplanes<T>(
+    input_size: NonZeroUsize,
+    num_hyperplanes: NonZeroUsize,
+    seed: u64,
+) -> Projection<T>
+where
+    T: Scalar,
+{
+    let matrix = sample_normals(input_size.get() * num_hyperplanes.get(), seed);
+    Projection::new(BlockVectorSet::chunk(matrix, input_size).unwrap())
+        .unwrap()
+}
+
+// Samples approximately standard normal numbers from the seeded random
+// generator.
+fn sample_normals<T>(n: usize, seed: u64) -> Vec<T>
+where
+    T: Scalar,
+{
+    let mut rng = StdRng::seed_from_u64(seed);
+    let uniform = Uniform::new(T::zero(), T::one());
+    let offset = T::from_as(NUM_NORMAL_TERMS) / T::from_as(2);
+    (0..n)
+        .map(|_| {
+            let mut x = T::zero() - offset;
+            for _ in 0..NUM_NORMAL_TERMS {
+                x += uniform.sample(&mut rng);
+            }
+            x
+        })
+        .collect()
+}
+
+/// Trains a projection onto the principal components of given vectors.
+///
+/// The projection maps a vector onto the `output_size` principal components
+/// with the largest variances, in descending order of the variances. The
+/// mean of the vectors is not subtracted, so that the projection preserves
+/// inner products as well as distances within the principal subspace.
+///
+/// The principal components are found by subspace iteration over the
+/// covariance matrix, which takes `O(n·m²)` time to compute for `n` vectors
+/// of `m` elements. Training is deterministic.
+///
+/// Fails if `vs` is empty, or `output_size` exceeds the vector size.
+pub fn train_pca<T, VS>(
+    vs: &VS,
+    output_size: NonZeroUsize,
+) -> Result<Projection<T>, Error>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    let m = vs.vector_size();
+    let k = output_size.get();
+    if vs.is_empty() {
+        return Err(Error::InvalidArgs(
+            "no vector to train PCA".to_string(),
+        ));
+    }
+    if k > m {
+        return Err(Error::InvalidArgs(format!(
+            "PCA output size {} exceeds vector size {}",
+            k,
+            m,
+        )));
+    }
+    let covariance = covariance_matrix(vs);
+    // subspace iteration over the covariance matrix
+    let mut rng = StdRng::seed_from_u64(PCA_SEED);
+    let uniform = Uniform::new_inclusive(T::zero() - T::one(), T::one());
+    let mut basis: Vec<T> = (0..k * m)
+        .map(|_| uniform.sample(&mut rng))
+        .collect();
+    orthonormalize_rows(&mut basis, m);
+    let mut next: Vec<T> = vec![T::zero(); k * m];
+    for _ in 0..NUM_PCA_ITERATIONS {
+        multiply_rows(&basis, &covariance, m, &mut next);
+        core::mem::swap(&mut basis, &mut next);
+        orthonormalize_rows(&mut basis, m);
+    }
+    // Rayleigh-Ritz: diagonalizes the covariance in the subspace
+    multiply_rows(&basis, &covariance, m, &mut next);
+    let mut reduced: Vec<T> = vec![T::zero(); k * k];
+    for i in 0..k {
+        for j in 0..k {
+            reduced[i * k + j] =
+                dot(&basis[i * m..(i + 1) * m], &next[j * m..(j + 1) * m]);
+        }
+    }
+    let (eigenvalues, eigenvectors) = diagonalize_symmetric(reduced, k);
+    let mut order: Vec<usize> = (0..k).collect();
+    order.sort_by(|&i, &j| {
+        eigenvalues[j].partial_cmp(&eigenvalues[i]).unwrap()
+    });
+    let mut matrix: Vec<T> = Vec::with_capacity(k * m);
+    for &c in order.iter() {
+        let mut axis = vec![T::zero(); m];
+        for r in 0..k {
+            let weight = eigenvectors[r * k + c];
+            for (a, &b) in axis.iter_mut().zip(&basis[r * m..(r + 1) * m]) {
+                *a += weight * b;
+            }
+        }
+        matrix.extend(axis);
+    }
+    let matrix = BlockVectorSet::chunk(matrix, m.try_into().unwrap())?;
+    Projection::new(matrix)
+}
+
+// Calculates the covariance matrix of vectors in row-major order.
+fn covariance_matrix<T, VS>(vs: &VS) -> Vec<T>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    let m = vs.vector_size();
+    let n = T::from_as(vs.len());
+    let mut mean = vec![T::zero(); m];
+    for (_, v) in vs.iter() {
+        for (a, &b) in mean.iter_mut().zip(v.as_slice()) {
+            *a += b;
+        }
+    }
+    for a in mean.iter_mut() {
+        *a = *a / n;
+    }
+    let mut covariance = vec![T::zero(); m * m];
+    let mut d = vec![T::zero(); m];
+    for (_, v) in vs.iter() {
+        for ((d, &x), &mu) in d.iter_mut().zip(v.as_slice()).zip(&mean) {
+            *d = x - mu;
+        }
+        // accumulates the upper triangle
+        for i in 0..m {
+            let di = d[i];
+            for j in i..m {
+                covariance[i * m + j] += di * d[j];
+            }
+        }
+    }
+    for i in 0..m {
+        for j in i..m {
+            let c = covariance[i * m + j] / n;
+            covariance[i * m + j] = c;
+            covariance[j * m + i] = c;
+        }
+    }
+    covariance
+}
+
+// Multiplies each row by a symmetric `m`×`m` matrix.
+fn multiply_rows<T>(rows: &[T], matrix: &[T], m: usize, out: &mut [T])
+where
+    T: Scalar,
+{
+    for (row, out) in rows.chunks_exact(m).zip(out.chunks_exact_mut(m)) {
+        for (j, o) in out.iter_mut().enumerate() {
+            *o = dot(row, &matrix[j * m..(j + 1) * m]);
+        }
+    }
+}
+
+// Makes rows of `m` elements orthonormal by the Gram-Schmidt process.
+//
+// A row that depends on the preceding ones is replaced with a standard
+// basis vector independent of them.
+fn orthonormalize_rows<T>(rows: &mut [T], m: usize)
+where
+    T: Scalar,
+{
+    let k = rows.len() / m;
+    let mut next_basis = 0;
+    for i in 0..k {
+        loop {
+            let (done, rest) = rows.split_at_mut(i * m);
+            let row = &mut rest[..m];
+            for prev in done.chunks_exact(m) {
+                let d = dot(prev, row);
+                for (r, &p) in row.iter_mut().zip(prev) {
+                    *r -= d * p;
+                }
+            }
+            let norm = norm2(row);
+            if norm > T::default_epsilon() {
+                scale_in(row, T::one() / norm);
+                break;
+            }
+            // there are at most `m` dependent rows before reaching a
+            // complete basis
+            row.fill(T::zero());
+            row[next_basis % m] = T::one();
+            next_basis += 1;
+        }
+    }
+}
+
+// Diagonalizes a symmetric `k`×`k` matrix by the cyclic Jacobi method.
+//
+// Returns the eigenvalues and a matrix whose columns are the corresponding
+// eigenvectors.
+fn diagonalize_symmetric<T>(mut a: Vec<T>, k: usize) -> (Vec<T>, Vec<T>)
+where
+    T: Scalar,
+{
+    let mut v = vec![T::zero(); k * k];
+    for i in 0..k {
+        v[i * k + i] = T::one();
+    }
+    let mut two = T::one();
+    two += T::one();
+    for _ in 0..MAX_JACOBI_SWEEPS {
+        let mut off = T::zero();
+        let mut diagonal = T::zero();
+        for p in 0..k {
+            diagonal += a[p * k + p] * a[p * k + p];
+            for q in (p + 1)..k {
+                off += a[p * k + q] * a[p * k + q];
+            }
+        }
+        if off <= diagonal * T::default_epsilon() * T::default_epsilon() {
+            break;
+        }
+        for p in 0..k {
+            for q in (p + 1)..k {
+                let apq = a[p * k + q];
+                if apq == T::zero() {
+                    continue;
+                }
+                let theta = (a[q * k + q] - a[p * k + p]) / (two * apq);
+                let mut hypot2 = theta * theta;
+                hypot2 += T::one();
+                let mut denominator = theta.abs();
+                denominator += hypot2.sqrt();
+                let t = T::one() / denominator;
+                let t = if theta < T::zero() { T::zero() - t } else { t };
+                let mut secant2 = t * t;
+                secant2 += T::one();
+                let c = T::one() / secant2.sqrt();
+                let s = t * c;
+                rotate_columns(&mut a, k, p, q, c, s);
+                rotate_rows(&mut a, k, p, q, c, s);
+                rotate_columns(&mut v, k, p, q, c, s);
+            }
+        }
+    }
+    let eigenvalues = (0..k).map(|i| a[i * k + i]).collect();
+    (eigenvalues, v)
+}
+
+// Rotates columns `p` and `q` of a `k`×`k` matrix.
+fn rotate_columns<T>(a: &mut [T], k: usize, p: usize, q: usize, c: T, s: T)
+where
+    T: Scalar,
+{
+    for r in 0..k {
+        let arp = a[r * k + p];
+        let arq = a[r * k + q];
+        a[r * k + p] = c * arp - s * arq;
+        let mut arq2 = s * arp;
+        arq2 += c * arq;
+        a[r * k + q] = arq2;
+    }
+}
+
+// Rotates rows `p` and `q` of a `k`×`k` matrix.
+fn rotate_rows<T>(a: &mut [T], k: usize, p: usize, q: usize, c: T, s: T)
+where
+    T: Scalar,
+{
+    for r in 0..k {
+        let apr = a[p * k + r];
+        let aqr = a[q * k + r];
+        a[p * k + r] = c * apr - s * aqr;
+        let mut aqr2 = s * apr;
+        aqr2 += c * aqr;
+        a[q * k + r] = aqr2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::Rng;
+
+    // Vectors spread mostly along (1, 1, 0, 0) and then (0, 0, 1, -1).
+    fn anisotropic_vectors() -> BlockVectorSet<f32> {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut data: Vec<f32> = Vec::new();
+        for _ in 0..500 {
+            let a: f32 = rng.gen_range(-10.0..10.0);
+            let b: f32 = rng.gen_range(-2.0..2.0);
+            let noise: f32 = rng.gen_range(-0.1..0.1);
+            data.extend([a + noise, a - noise, b, -b]);
+        }
+        BlockVectorSet::chunk(data, 4.try_into().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn train_pca_should_find_axes_of_largest_variances() {
+        let vs = anisotropic_vectors();
+        let projection = train_pca(&vs, 2.try_into().unwrap()).unwrap();
+        assert_eq!(projection.input_size(), 4);
+        assert_eq!(projection.output_size(), 2);
+        let h = 0.5f32.sqrt();
+        let expected = [[h, h, 0.0, 0.0], [0.0, 0.0, h, -h]];
+        for (i, expected) in expected.iter().enumerate() {
+            let axis = projection.matrix().get(i);
+            // the sign of an axis is arbitrary
+            assert!((dot(axis, expected).abs() - 1.0).abs() < 1e-3);
+        }
+        let projected = projection.project_vector_set(&vs).unwrap();
+        assert_eq!(projected.len(), vs.len());
+        assert_eq!(projected.vector_size(), 2);
+        assert_eq!(
+            projected.get(3),
+            projection.project(vs.get(3)).unwrap(),
+        );
+    }
+
+    #[test]
+    fn train_pca_should_complete_basis_of_degenerate_vectors() {
+        let vs = BlockVectorSet::chunk(
+            vec![1.0f32, 0.0, 0.0, -1.0, 0.0, 0.0],
+            3.try_into().unwrap(),
+        ).unwrap();
+        let projection = train_pca(&vs, 3.try_into().unwrap()).unwrap();
+        let matrix = projection.matrix();
+        for i in 0..3 {
+            for j in 0..3 {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                let product = dot(matrix.get(i), matrix.get(j));
+                assert!((product - expected).abs() < 1e-5);
+            }
+        }
+        assert!((matrix.get(0)[0].abs() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn random_rotation_should_preserve_distances() {
+        let rotation: Projection<f32> =
+            random_rotation(8.try_into().unwrap(), 3);
+        assert_eq!(rotation.input_size(), 8);
+        assert_eq!(rotation.output_size(), 8);
+        let matrix = rotation.matrix();
+        for i in 0..8 {
+            for j in 0..8 {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                let product = dot(matrix.get(i), matrix.get(j));
+                assert!((product - expected).abs() < 1e-5);
+            }
+        }
+        let v: Vec<f32> = (0..8).map(|i| i as f32).collect();
+        let rotated = rotation.project(&v).unwrap();
+        assert!((norm2(&rotated) - norm2(&v)).abs() < 1e-4);
+        let same: Projection<f32> = random_rotation(8.try_into().unwrap(), 3);
+        let other: Projection<f32> =
+            random_rotation(8.try_into().unwrap(), 4);
+        assert!((0..8).all(|i| same.matrix().get(i) == matrix.get(i)));
+        assert!((0..8).any(|i| other.matrix().get(i) != matrix.get(i)));
+    }
+
+    #[test]
+    fn random_hyperplanes_should_separate_opposite_vectors() {
+        let hyperplanes: Projection<f32> = random_hyperplanes(
+            4.try_into().unwrap(),
+            16.try_into().unwrap(),
+            3,
+        );
+        assert_eq!(hyperplanes.input_size(), 4);
+        assert_eq!(hyperplanes.output_size(), 16);
+        let v = [1.0f32, -2.0, 0.5, 3.0];
+        let w: Vec<f32> = v.iter().map(|x| -x).collect();
+        let projected = hyperplanes.project(&v[..]).unwrap();
+        let opposite = hyperplanes.project(&w).unwrap();
+        assert!(
+            projected.iter().zip(opposite.iter()).all(|(x, y)| x * y < 0.0),
+        );
+        let same: Projection<f32> = random_hyperplanes(
+            4.try_into().unwrap(),
+            16.try_into().unwrap(),
+            3,
+        );
+        assert!(
+            (0..16).all(|i| {
+                same.matrix().get(i) == hyperplanes.matrix().get(i)
+            }),
+        );
+    }
+
+    #[test]
+    fn composed_projection_should_project_in_sequence() {
+        let vs = anisotropic_vectors();
+        let pca = train_pca(&vs, 2.try_into().unwrap()).unwrap();
+        let rotation = random_rotation(2.try_into().unwrap(), 1);
+        let composed = pca.then(&rotation).unwrap();
+        assert_eq!(composed.input_size(), 4);
+        assert_eq!(composed.output_size(), 2);
+        let v = vs.get(7);
+        let expected = rotation.project(&pca.project(v).unwrap()).unwrap();
+        for (a, b) in composed.project(v).unwrap().iter().zip(&expected) {
+            assert!((a - b).abs() < 1e-3);
+        }
+        assert!(matches!(
+            rotation.then(&pca),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn train_pca_should_fail_if_output_size_exceeds_vector_size() {
+        let vs = anisotropic_vectors();
+        assert!(matches!(
+            train_pca(&vs, 5.try_into().unwrap()),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+}
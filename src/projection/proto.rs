@@ -0,0 +1,67 @@
+//! Protocol Buffers utilities for [`projection`][`crate::projection`].
+
+use crate::error::Error;
+use crate::protos::{Deserialize, Serialize};
+use crate::protos::database::Projection as ProtosProjection;
+use crate::vector::BlockVectorSet;
+
+use super::Projection;
+
+impl Serialize<ProtosProjection> for Projection<f32> {
+    fn serialize(&self) -> Result<ProtosProjection, Error> {
+        let mut projection = ProtosProjection::new();
+        projection.input_size = self.input_size() as u32;
+        projection.matrix.reserve(self.input_size() * self.output_size());
+        for i in 0..self.output_size() {
+            projection.matrix.extend_from_slice(self.matrix.get(i));
+        }
+        Ok(projection)
+    }
+}
+
+impl Deserialize<Projection<f32>> for ProtosProjection {
+    fn deserialize(self) -> Result<Projection<f32>, Error> {
+        let input_size = (self.input_size as usize)
+            .try_into()
+            .or(Err(Error::InvalidData(
+                "projection input size must not be zero".to_string(),
+            )))?;
+        let matrix = BlockVectorSet::chunk(self.matrix, input_size)
+            .map_err(|e| Error::InvalidData(format!(
+                "invalid projection matrix: {}",
+                e,
+            )))?;
+        Projection::new(matrix).map_err(|e| Error::InvalidData(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn projection_can_be_serialized_and_deserialized() {
+        let matrix = BlockVectorSet::chunk(
+            vec![1.0f32, 0.0, 0.0, 0.0, 0.0, 1.0],
+            3.try_into().unwrap(),
+        ).unwrap();
+        let input = Projection::new(matrix).unwrap();
+        let serialized = input.serialize().unwrap();
+        assert_eq!(serialized.input_size, 3);
+        let output: Projection<f32> = serialized.deserialize().unwrap();
+        assert_eq!(output.input_size(), 3);
+        assert_eq!(output.output_size(), 2);
+        assert_eq!(
+            output.project(&[1.0, 2.0, 3.0][..]).unwrap(),
+            vec![1.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn projection_cannot_be_deserialized_from_empty_matrix() {
+        let mut input = ProtosProjection::new();
+        input.input_size = 3;
+        let output: Result<Projection<f32>, Error> = input.deserialize();
+        assert!(matches!(output, Err(Error::InvalidData(_))));
+    }
+}
@@ -54,6 +54,9 @@ where
 }
 
 /// Reads a message from a given input stream.
+///
+/// The message is parsed while the stream is being read, so the entire
+/// encoded message is never held in memory.
 pub fn read_message<M, R>(read: &mut R) -> Result<M, Error>
 where
     M: Message,
@@ -64,6 +67,57 @@ where
     Ok(message)
 }
 
+/// Reads a message no larger than a given number of bytes from a given input
+/// stream.
+///
+/// Fails with `Error::InvalidData` as soon as more than `max_size` bytes are
+/// read, so that a corrupted or malicious stream cannot make the parser
+/// allocate more than about `max_size` bytes.
+pub fn read_message_with_max_size<M, R>(
+    read: &mut R,
+    max_size: usize,
+) -> Result<M, Error>
+where
+    M: Message,
+    R: Read,
+{
+    // reads one more byte to tell if the message exceeds the maximum
+    let limit = (max_size as u64).saturating_add(1);
+    let mut limited = read.take(limit);
+    let result = read_message(&mut limited);
+    check_message_size(limit - limited.limit(), max_size)?;
+    result
+}
+
+// Reads a message no larger than `max_size` bytes unless `None`.
+pub(crate) fn read_message_up_to<M, R>(
+    read: &mut R,
+    max_size: Option<usize>,
+) -> Result<M, Error>
+where
+    M: Message,
+    R: Read,
+{
+    match max_size {
+        Some(max_size) => read_message_with_max_size(read, max_size),
+        None => read_message(read),
+    }
+}
+
+// Fails if the size of a message exceeds a given maximum.
+pub(crate) fn check_message_size(
+    size: u64,
+    max_size: usize,
+) -> Result<(), Error> {
+    if size > max_size as u64 {
+        return Err(Error::InvalidData(format!(
+            "message exceeds the maximum size of {} bytes",
+            max_size,
+        )));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -111,4 +165,24 @@ mod tests {
         let uuid = serialized.deserialize().unwrap();
         assert_eq!(uuid, Uuid::from_u64_pair(upper, lower));
     }
+
+    #[test]
+    fn read_message_with_max_size_should_reject_larger_message() {
+        let mut vector_set = database::VectorSet::new();
+        vector_set.vector_size = 2;
+        vector_set.data = vec![1.0, 2.0, 3.0, 4.0];
+        let mut encoded: Vec<u8> = Vec::new();
+        write_message(&vector_set, &mut encoded).unwrap();
+        let size = encoded.len();
+        let parsed: database::VectorSet =
+            read_message_with_max_size(&mut &encoded[..], size).unwrap();
+        assert_eq!(parsed, vector_set);
+        assert!(matches!(
+            read_message_with_max_size::<database::VectorSet, _>(
+                &mut &encoded[..],
+                size - 1,
+            ),
+            Err(Error::InvalidData(_)),
+        ));
+    }
 }
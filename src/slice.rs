@@ -14,7 +14,7 @@ impl<T> AsSlice<T> for [T] {
 
 impl<T> AsSlice<T> for &[T] {
     fn as_slice(&self) -> &[T] {
-        *self
+        self
     }
 }
 
@@ -23,3 +23,27 @@ impl<T> AsSlice<T> for Vec<T> {
         self.as_slice()
     }
 }
+
+/// Represents a type that can be referenced as a mutable slice.
+pub trait AsMutSlice<T> {
+    /// Returns a mutable reference to the underlying slice.
+    fn as_mut_slice(&mut self) -> &mut [T];
+}
+
+impl<T> AsMutSlice<T> for [T] {
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+}
+
+impl<T> AsMutSlice<T> for &mut [T] {
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+}
+
+impl<T> AsMutSlice<T> for Vec<T> {
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
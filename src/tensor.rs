@@ -0,0 +1,15 @@
+//! Interoperability with tensor libraries.
+//!
+//! Embeddings produced by an in-process model can be passed to queries
+//! without copying them into a `Vec` first; e.g., `candle::CandleVector`
+//! borrows the elements of a candle tensor and implements
+//! [`AsSlice`](crate::slice::AsSlice).
+//!
+//! Each library is behind a feature:
+//! - `candle`: `candle` module
+//! - `tch`: `torch` module
+
+#[cfg(feature = "candle")]
+pub mod candle;
+#[cfg(feature = "tch")]
+pub mod torch;
@@ -0,0 +1,109 @@
+//! Vectors from [candle](https://github.com/huggingface/candle) tensors.
+
+use candle_core::{Storage, Tensor, WithDType};
+use core::marker::PhantomData;
+use core::ops::Range;
+use std::sync::RwLockReadGuard;
+
+use crate::error::Error;
+use crate::slice::AsSlice;
+
+/// Elements of a candle tensor borrowed as a vector.
+///
+/// The tensor is flattened; e.g., a tensor of shape `[1, 384]` makes a
+/// vector of size 384.
+///
+/// Holds the read lock of the storage of the tensor while it is alive.
+pub struct CandleVector<'a, T> {
+    storage: RwLockReadGuard<'a, Storage>,
+    // Range of the elements in the storage.
+    range: Range<usize>,
+    _element: PhantomData<T>,
+}
+
+impl<'a, T> CandleVector<'a, T>
+where
+    T: WithDType,
+{
+    /// Borrows the elements of a given tensor.
+    ///
+    /// Fails if the tensor is not on the CPU, its element type is not `T`,
+    /// or its elements are not contiguous. Use [`to_vector`] to copy such a
+    /// tensor instead.
+    pub fn new(tensor: &'a Tensor) -> Result<Self, Error> {
+        let (storage, layout) = tensor.storage_and_layout();
+        let (start, end) = layout.contiguous_offsets().ok_or(
+            Error::InvalidArgs("tensor is not contiguous".to_string()),
+        )?;
+        match &*storage {
+            Storage::Cpu(cpu) => {
+                cpu.as_slice::<T>().map_err(|e| Error::InvalidArgs(
+                    format!("unexpected tensor element type: {}", e),
+                ))?;
+            },
+            _ => return Err(Error::InvalidArgs(format!(
+                "tensor must be on the CPU but on {:?}",
+                tensor.device().location(),
+            ))),
+        };
+        Ok(Self {
+            storage,
+            range: start..end,
+            _element: PhantomData,
+        })
+    }
+}
+
+impl<T> AsSlice<T> for CandleVector<'_, T>
+where
+    T: WithDType,
+{
+    fn as_slice(&self) -> &[T] {
+        match &*self.storage {
+            Storage::Cpu(cpu) => &cpu
+                .as_slice::<T>()
+                .expect("element type must have been checked")
+                [self.range.clone()],
+            _ => unreachable!("storage must be on the CPU"),
+        }
+    }
+}
+
+/// Copies the elements of a tensor into a new vector.
+///
+/// Flattens the tensor, converts the elements into `T`, and moves them to
+/// the CPU if necessary.
+pub fn to_vector<T>(tensor: &Tensor) -> Result<Vec<T>, Error>
+where
+    T: WithDType,
+{
+    tensor
+        .flatten_all()
+        .and_then(|t| t.to_dtype(T::DTYPE))
+        .and_then(|t| t.to_vec1::<T>())
+        .map_err(|e| Error::InvalidArgs(
+            format!("failed to convert tensor: {}", e),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use candle_core::Device;
+
+    #[test]
+    fn candle_vector_should_borrow_elements_of_tensor() {
+        let tensor = Tensor::arange(0.0f32, 8.0, &Device::Cpu)
+            .unwrap()
+            .reshape((2, 4))
+            .unwrap();
+        let row = tensor.get(1).unwrap();
+        let v = CandleVector::<f32>::new(&row).unwrap();
+        assert_eq!(v.as_slice(), &[4.0, 5.0, 6.0, 7.0]);
+        assert!(CandleVector::<f64>::new(&row).is_err());
+        let column = tensor.narrow(1, 1, 1).unwrap();
+        assert!(CandleVector::<f32>::new(&column).is_err());
+        assert_eq!(to_vector::<f32>(&column).unwrap(), vec![1.0, 5.0]);
+    }
+}
@@ -0,0 +1,81 @@
+//! Vectors from [tch](https://github.com/LaurentMazare/tch-rs) (libtorch)
+//! tensors.
+
+use tch::{Device, Kind, Tensor};
+
+use crate::error::Error;
+use crate::slice::AsSlice;
+
+/// Elements of a tch tensor borrowed as a vector.
+///
+/// The tensor is flattened; e.g., a tensor of shape `[1, 384]` makes a
+/// vector of size 384.
+pub struct TchVector<'a> {
+    tensor: &'a Tensor,
+    len: usize,
+}
+
+impl<'a> TchVector<'a> {
+    /// Borrows the elements of a given tensor.
+    ///
+    /// Fails if the tensor is not on the CPU, its element type is not
+    /// `f32`, or its elements are not contiguous. Use [`to_vector`] to copy
+    /// such a tensor instead.
+    ///
+    /// # Safety
+    ///
+    /// The elements must not be modified through another tensor sharing the
+    /// storage; e.g., a shallow clone, while the vector is alive.
+    pub unsafe fn new(tensor: &'a Tensor) -> Result<Self, Error> {
+        if tensor.device() != Device::Cpu {
+            return Err(Error::InvalidArgs(format!(
+                "tensor must be on the CPU but on {:?}",
+                tensor.device(),
+            )));
+        }
+        if tensor.kind() != Kind::Float {
+            return Err(Error::InvalidArgs(format!(
+                "tensor elements must be f32 but {:?}",
+                tensor.kind(),
+            )));
+        }
+        if !tensor.is_contiguous() {
+            return Err(Error::InvalidArgs(
+                "tensor is not contiguous".to_string(),
+            ));
+        }
+        Ok(Self {
+            tensor,
+            len: tensor.numel(),
+        })
+    }
+}
+
+impl AsSlice<f32> for TchVector<'_> {
+    fn as_slice(&self) -> &[f32] {
+        if self.len == 0 {
+            return &[];
+        }
+        // safety: the tensor holds `len` contiguous f32 elements on the CPU,
+        // and `new` requires they are not modified meanwhile.
+        unsafe {
+            core::slice::from_raw_parts(
+                self.tensor.data_ptr() as *const f32,
+                self.len,
+            )
+        }
+    }
+}
+
+/// Copies the elements of a tensor into a new vector.
+///
+/// Flattens the tensor, converts the elements into `f32`, and moves them
+/// to the CPU if necessary.
+pub fn to_vector(tensor: &Tensor) -> Result<Vec<f32>, Error> {
+    tensor
+        .f_flatten(0, -1)
+        .and_then(|t| Vec::<f32>::try_from(&t))
+        .map_err(|e| Error::InvalidArgs(
+            format!("failed to convert tensor: {}", e),
+        ))
+}
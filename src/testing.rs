@@ -0,0 +1,288 @@
+//! Synthetic datasets for testing and examples.
+//!
+//! Vectors are drawn from Gaussian clusters around random centers, so
+//! partitioning and quantization behave as on real embeddings rather than
+//! on uniform noise. Exact nearest neighbors can be computed as ground truth
+//! to measure the recall of approximate queries.
+
+use core::num::NonZeroUsize;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::db::{AttributeValue, Attributes};
+use crate::error::Error;
+use crate::linalg::squared_distance;
+use crate::nbest::NBestByKey;
+use crate::vector::{BlockVectorSet, VectorSet};
+
+#[cfg(feature = "hdf5")]
+pub mod hdf5;
+pub mod vecs;
+
+/// Builder of a [`SyntheticDataset`].
+pub struct SyntheticDatasetBuilder {
+    // Number of vectors.
+    num_vectors: usize,
+    // Vector size.
+    vector_size: usize,
+    // Number of clusters.
+    num_clusters: usize,
+    // Standard deviation of each element around a cluster center.
+    spread: f32,
+    // Seed of the random number generator.
+    seed: u64,
+}
+
+impl SyntheticDatasetBuilder {
+    /// Starts building a dataset of given numbers of vectors and elements.
+    pub fn new(num_vectors: NonZeroUsize, vector_size: NonZeroUsize) -> Self {
+        Self {
+            num_vectors: num_vectors.get(),
+            vector_size: vector_size.get(),
+            num_clusters: 10,
+            spread: 0.1,
+            seed: 0,
+        }
+    }
+
+    /// Sets the number of clusters.
+    ///
+    /// 10 by default.
+    pub fn with_clusters(mut self, num_clusters: NonZeroUsize) -> Self {
+        self.num_clusters = num_clusters.get();
+        self
+    }
+
+    /// Sets the standard deviation of elements around a cluster center.
+    ///
+    /// Cluster centers are drawn from [-1, 1) in every dimension.
+    /// 0.1 by default.
+    pub fn with_spread(mut self, spread: f32) -> Self {
+        self.spread = spread;
+        self
+    }
+
+    /// Sets the seed of the random number generator.
+    ///
+    /// The same seed reproduces the same dataset. 0 by default.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Builds a dataset.
+    ///
+    /// Fails if `spread` is negative or not finite.
+    pub fn build(self) -> Result<SyntheticDataset, Error> {
+        if !self.spread.is_finite() || self.spread < 0.0 {
+            return Err(Error::InvalidArgs(format!(
+                "spread must be a non-negative finite number but got {}",
+                self.spread,
+            )));
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let m = self.vector_size;
+        let centers: Vec<f32> = (0..self.num_clusters * m)
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect();
+        let mut data: Vec<f32> = Vec::with_capacity(self.num_vectors * m);
+        let mut labels: Vec<usize> = Vec::with_capacity(self.num_vectors);
+        for _ in 0..self.num_vectors {
+            let ci = rng.gen_range(0..self.num_clusters);
+            let center = &centers[ci * m..(ci + 1) * m];
+            data.extend(
+                center.iter().map(|c| c + self.spread * gaussian(&mut rng)),
+            );
+            labels.push(ci);
+        }
+        let vector_size = m.try_into().unwrap();
+        Ok(SyntheticDataset {
+            vectors: BlockVectorSet::chunk(data, vector_size)?,
+            centers: BlockVectorSet::chunk(centers, vector_size)?,
+            labels,
+            rng,
+            spread: self.spread,
+        })
+    }
+}
+
+/// Synthetic dataset of clustered vectors.
+pub struct SyntheticDataset {
+    /// Vectors.
+    pub vectors: BlockVectorSet<f32>,
+    /// Cluster centers.
+    pub centers: BlockVectorSet<f32>,
+    /// Cluster index of every vector.
+    pub labels: Vec<usize>,
+    // Continues the random sequence that generated the vectors.
+    rng: StdRng,
+    // Standard deviation around a cluster center.
+    spread: f32,
+}
+
+impl SyntheticDataset {
+    /// Returns the attributes of the i-th vector.
+    ///
+    /// Attributes follow fixed patterns so that filters have predictable
+    /// selectivity:
+    /// - `"datum_id"`: `i` as a string
+    /// - `"cluster"`: the cluster index
+    /// - `"parity"`: `"even"` or `"odd"` depending on `i`
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn attributes(&self, i: usize) -> Attributes {
+        let parity = if i.is_multiple_of(2) { "even" } else { "odd" };
+        Attributes::from([
+            ("datum_id".to_string(), AttributeValue::from(i.to_string())),
+            (
+                "cluster".to_string(),
+                AttributeValue::from(self.labels[i] as u64),
+            ),
+            ("parity".to_string(), AttributeValue::from(parity)),
+        ])
+    }
+
+    /// Generates query vectors around the cluster centers.
+    ///
+    /// Queries follow the same distribution as the dataset but are not
+    /// members of it.
+    pub fn generate_queries(&mut self, num_queries: usize) -> Vec<Vec<f32>> {
+        let num_clusters = self.centers.len();
+        (0..num_queries)
+            .map(|_| {
+                let ci = self.rng.gen_range(0..num_clusters);
+                let spread = self.spread;
+                let rng = &mut self.rng;
+                self.centers
+                    .get(ci)
+                    .iter()
+                    .map(|c| c + spread * gaussian(rng))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Calculates the exact k-nearest neighbors of a given query vector.
+    ///
+    /// Returns pairs of a vector index and a squared distance in ascending
+    /// order of distances.
+    ///
+    /// Panics if the size of `query` does not match.
+    pub fn ground_truth(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        exact_knn(&self.vectors, query, k)
+    }
+}
+
+/// Calculates the exact k-nearest neighbors of a given query vector by
+/// brute force.
+///
+/// Returns pairs of a vector index and a squared distance in ascending order
+/// of distances.
+///
+/// Panics if the size of `query` does not match.
+pub fn exact_knn<VS>(vs: &VS, query: &[f32], k: usize) -> Vec<(usize, f32)>
+where
+    VS: VectorSet<f32>,
+    VS::Vector: AsRef<[f32]>,
+{
+    let mut nbest = NBestByKey::new(k, |&(_, d): &(usize, f32)| d);
+    for (i, v) in vs.iter() {
+        nbest.push((i, squared_distance(query, v.as_ref())));
+    }
+    nbest.into_sorted_vec()
+}
+
+/// Calculates the recall of approximate results against ground truth.
+///
+/// Returns the fraction of `truth` found in `results`; one if `truth` is
+/// empty.
+pub fn recall<I>(results: &[I], truth: &[I]) -> f32
+where
+    I: PartialEq,
+{
+    if truth.is_empty() {
+        return 1.0;
+    }
+    let hits = truth.iter().filter(|t| results.contains(t)).count();
+    hits as f32 / truth.len() as f32
+}
+
+// Draws a sample from the standard normal distribution (Box-Muller).
+fn gaussian<R>(rng: &mut R) -> f32
+where
+    R: Rng + ?Sized,
+{
+    let u1: f32 = 1.0 - rng.gen::<f32>(); // (0, 1]
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * core::f32::consts::PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nz(n: usize) -> NonZeroUsize {
+        NonZeroUsize::new(n).unwrap()
+    }
+
+    #[test]
+    fn synthetic_dataset_should_be_reproducible_with_seed() {
+        let a = SyntheticDatasetBuilder::new(nz(50), nz(4))
+            .with_seed(42)
+            .build()
+            .unwrap();
+        let b = SyntheticDatasetBuilder::new(nz(50), nz(4))
+            .with_seed(42)
+            .build()
+            .unwrap();
+        assert_eq!(a.labels, b.labels);
+        for i in 0..50 {
+            assert_eq!(a.vectors.get(i), b.vectors.get(i));
+        }
+    }
+
+    #[test]
+    fn synthetic_vectors_should_be_close_to_their_centers() {
+        let dataset = SyntheticDatasetBuilder::new(nz(200), nz(8))
+            .with_clusters(nz(4))
+            .with_spread(0.01)
+            .build()
+            .unwrap();
+        for (i, v) in dataset.vectors.iter() {
+            let center = dataset.centers.get(dataset.labels[i]);
+            assert!(squared_distance(v, center) < 0.1);
+        }
+    }
+
+    #[test]
+    fn ground_truth_should_include_exact_match_first() {
+        let dataset = SyntheticDatasetBuilder::new(nz(100), nz(4))
+            .build()
+            .unwrap();
+        let query = dataset.vectors.get(7).to_vec();
+        let truth = dataset.ground_truth(&query, 5);
+        assert_eq!(truth.len(), 5);
+        assert_eq!(truth[0], (7, 0.0));
+        assert!(truth.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+
+    #[test]
+    fn recall_should_count_found_neighbors() {
+        assert_eq!(recall(&[1, 2, 3, 4], &[2, 4, 6, 8]), 0.5);
+        assert_eq!(recall::<usize>(&[], &[]), 1.0);
+    }
+
+    #[test]
+    fn attributes_should_follow_patterns() {
+        let dataset = SyntheticDatasetBuilder::new(nz(10), nz(2))
+            .build()
+            .unwrap();
+        let attributes = dataset.attributes(3);
+        assert_eq!(attributes["datum_id"], AttributeValue::from("3"));
+        assert_eq!(attributes["parity"], AttributeValue::from("odd"));
+        assert_eq!(
+            attributes["cluster"],
+            AttributeValue::from(dataset.labels[3] as u64),
+        );
+    }
+}
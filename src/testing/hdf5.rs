@@ -0,0 +1,1036 @@
+//! Reader of datasets in the HDF5 layout of ann-benchmarks.
+//!
+//! ann-benchmarks (<https://github.com/erikbern/ann-benchmarks>) distributes
+//! every dataset as an HDF5 file with four two-dimensional datasets at the
+//! root group:
+//! - `train`: vectors to index
+//! - `test`: query vectors
+//! - `neighbors`: indices of the nearest `train` vectors of every `test`
+//!   vector
+//! - `distances`: distances to the `neighbors`
+//!
+//! Parses HDF5 by itself rather than binding the native HDF5 library, so it
+//! covers the subset of the format h5py writes by default: superblock
+//! version 0 or 1, version 1 object headers, symbol table groups, and
+//! contiguous or compact storage of fixed-size numbers. Chunked, and hence
+//! compressed, datasets are rejected.
+//!
+//! Unit tests read files laid out as h5py writes them. An ignored test reads
+//! a real ann-benchmarks file at the path in the environment variable
+//! `FLECHASDB_ANN_BENCHMARKS_HDF5`, which has to be a Euclidean dataset;
+//! e.g., `fashion-mnist-784-euclidean.hdf5`:
+//!
+//! ```sh
+//! FLECHASDB_ANN_BENCHMARKS_HDF5=fashion-mnist-784-euclidean.hdf5 \
+//!     cargo test --features hdf5 -- --ignored read_real_ann_benchmarks
+//! ```
+
+use core::num::NonZeroUsize;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::error::Error;
+use crate::vector::BlockVectorSet;
+
+// Format signature at the beginning of the superblock.
+const SIGNATURE: [u8; 8] =
+    [0x89, b'H', b'D', b'F', b'\r', b'\n', 0x1A, b'\n'];
+
+// Address of an undefined object. Addresses are normalized to 64 bits.
+const UNDEFINED_ADDRESS: u64 = u64::MAX;
+
+// Types of object header messages.
+const NIL_MESSAGE: u16 = 0x0000;
+const DATASPACE_MESSAGE: u16 = 0x0001;
+const DATATYPE_MESSAGE: u16 = 0x0003;
+const LAYOUT_MESSAGE: u16 = 0x0008;
+const FILTER_PIPELINE_MESSAGE: u16 = 0x000B;
+const CONTINUATION_MESSAGE: u16 = 0x0010;
+const SYMBOL_TABLE_MESSAGE: u16 = 0x0011;
+
+/// Dataset in the HDF5 layout of ann-benchmarks.
+pub struct AnnBenchmarksDataset {
+    /// Vectors to index.
+    pub train: BlockVectorSet<f32>,
+    /// Query vectors.
+    pub test: BlockVectorSet<f32>,
+    /// Indices of the nearest neighbors in `train` of every query vector in
+    /// ascending order of distances.
+    pub neighbors: Vec<Vec<usize>>,
+    /// Distances to `neighbors` as ann-benchmarks records them; e.g.,
+    /// Euclidean distances rather than squared ones.
+    pub distances: Vec<Vec<f32>>,
+}
+
+/// Reads a dataset in the HDF5 layout of ann-benchmarks.
+///
+/// `train` and `test` are read as [`read_fvecs`](super::vecs::read_fvecs)
+/// reads vectors, and `neighbors` as
+/// [`read_ivecs`](super::vecs::read_ivecs) reads neighbor lists.
+///
+/// Fails with `Error::InvalidData` if the input is not an HDF5 file, uses
+/// features of HDF5 outside the supported subset, lacks any of the
+/// datasets, or has datasets of inconsistent shapes.
+pub fn read_ann_benchmarks<R>(reader: R) -> Result<AnnBenchmarksDataset, Error>
+where
+    R: Read + Seek,
+{
+    let mut file = Hdf5File::open(reader)?;
+    let train = file.read_matrix("train")?;
+    let test = file.read_matrix("test")?;
+    let neighbors = file.read_matrix("neighbors")?;
+    let distances = file.read_matrix("distances")?;
+    if train.cols != test.cols {
+        return Err(Error::InvalidData(format!(
+            "train and test vector sizes differ: {} and {}",
+            train.cols,
+            test.cols,
+        )));
+    }
+    for matrix in [&neighbors, &distances] {
+        if matrix.rows != test.rows {
+            return Err(Error::InvalidData(format!(
+                "{} has {} rows but test has {}",
+                matrix.name,
+                matrix.rows,
+                test.rows,
+            )));
+        }
+    }
+    if neighbors.cols != distances.cols {
+        return Err(Error::InvalidData(format!(
+            "neighbors and distances columns differ: {} and {}",
+            neighbors.cols,
+            distances.cols,
+        )));
+    }
+    let neighbors_cols = neighbors.cols;
+    let distances_cols = distances.cols;
+    Ok(AnnBenchmarksDataset {
+        train: BlockVectorSet::chunk(train.to_floats()?, train.vector_size()?)?,
+        test: BlockVectorSet::chunk(test.to_floats()?, test.vector_size()?)?,
+        neighbors: neighbors
+            .to_indices()?
+            .chunks(neighbors_cols)
+            .map(|row| row.to_vec())
+            .collect(),
+        distances: distances
+            .to_floats()?
+            .chunks(distances_cols)
+            .map(|row| row.to_vec())
+            .collect(),
+    })
+}
+
+// HDF5 file being read.
+struct Hdf5File<R> {
+    reader: R,
+    // Absolute address of the superblock, which other addresses are
+    // relative to.
+    base: u64,
+    // Size of addresses in bytes.
+    offset_size: usize,
+    // Size of lengths in bytes.
+    length_size: usize,
+    // Address of the object header of the root group.
+    root: u64,
+}
+
+impl<R> Hdf5File<R>
+where
+    R: Read + Seek,
+{
+    // Locates and reads the superblock.
+    //
+    // The superblock is at 0, 512, 1024, 2048, and so on.
+    fn open(mut reader: R) -> Result<Self, Error> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let mut base = 0u64;
+        loop {
+            if base + SIGNATURE.len() as u64 > len {
+                return Err(Error::InvalidData(
+                    "no HDF5 signature".to_string(),
+                ));
+            }
+            reader.seek(SeekFrom::Start(base))?;
+            let mut signature = [0u8; 8];
+            reader.read_exact(&mut signature)?;
+            if signature == SIGNATURE {
+                break;
+            }
+            base = if base == 0 { 512 } else { base * 2 };
+        }
+        let mut file = Hdf5File {
+            reader,
+            base,
+            offset_size: 8,
+            length_size: 8,
+            root: UNDEFINED_ADDRESS,
+        };
+        // signature, versions, sizes, K values, and flags
+        let head = file.read_block(0, 24)?;
+        let mut fields = Fields::new(&head);
+        fields.skip(8)?;
+        let version = fields.u8()?;
+        if version > 1 {
+            return Err(Error::InvalidData(format!(
+                "unsupported superblock version: {}",
+                version,
+            )));
+        }
+        fields.skip(4)?;
+        file.offset_size = check_size(fields.u8()?, "offsets")?;
+        file.length_size = check_size(fields.u8()?, "lengths")?;
+        let size = 24
+            + if version == 1 { 4 } else { 0 }
+            + 4 * file.offset_size
+            + symbol_table_entry_size(file.offset_size);
+        let superblock = file.read_block(0, size)?;
+        let mut fields = Fields::new(&superblock);
+        fields.skip(size - symbol_table_entry_size(file.offset_size))?;
+        // root group symbol table entry
+        fields.skip(file.offset_size)?;
+        file.root = fields.address(file.offset_size)?;
+        Ok(file)
+    }
+
+    // Reads a two-dimensional dataset in the root group.
+    fn read_matrix(&mut self, name: &str) -> Result<Matrix, Error> {
+        let address = self.find_object(name)?;
+        let mut dims: Option<Vec<u64>> = None;
+        let mut datatype: Option<Datatype> = None;
+        let mut layout: Option<Layout> = None;
+        for (message_type, data) in self.read_messages(address)? {
+            let mut fields = Fields::new(&data);
+            match message_type {
+                DATASPACE_MESSAGE => {
+                    dims = Some(self.parse_dataspace(&mut fields)?);
+                },
+                DATATYPE_MESSAGE => {
+                    datatype = Some(parse_datatype(&mut fields)?);
+                },
+                LAYOUT_MESSAGE => {
+                    layout = Some(self.parse_layout(&mut fields)?);
+                },
+                FILTER_PIPELINE_MESSAGE => {
+                    return Err(Error::InvalidData(format!(
+                        "filtered dataset is not supported: {}",
+                        name,
+                    )));
+                },
+                _ => {},
+            }
+        }
+        let missing = |what: &str| Error::InvalidData(format!(
+            "{} has no {}",
+            name,
+            what,
+        ));
+        let dims = dims.ok_or_else(|| missing("dataspace"))?;
+        let datatype = datatype.ok_or_else(|| missing("datatype"))?;
+        let layout = layout.ok_or_else(|| missing("layout"))?;
+        let [rows, cols] = dims[..] else {
+            return Err(Error::InvalidData(format!(
+                "{} is not two-dimensional: {:?}",
+                name,
+                dims,
+            )));
+        };
+        if rows == 0 || cols == 0 {
+            return Err(Error::InvalidData(format!("{} is empty", name)));
+        }
+        let size = rows
+            .checked_mul(cols)
+            .and_then(|n| n.checked_mul(datatype.size() as u64))
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or_else(|| Error::InvalidData(format!(
+                "{} is too large: {:?}",
+                name,
+                dims,
+            )))?;
+        let bytes = match layout {
+            Layout::Contiguous { address, size: stored } => {
+                if stored < size as u64 {
+                    return Err(Error::InvalidData(format!(
+                        "{} stores {} bytes but needs {}",
+                        name,
+                        stored,
+                        size,
+                    )));
+                }
+                self.read_block(address, size)?
+            },
+            Layout::Compact(bytes) => {
+                if bytes.len() < size {
+                    return Err(Error::InvalidData(format!(
+                        "{} stores {} bytes but needs {}",
+                        name,
+                        bytes.len(),
+                        size,
+                    )));
+                }
+                bytes[..size].to_vec()
+            },
+        };
+        Ok(Matrix {
+            name: name.to_string(),
+            rows: rows as usize,
+            cols: cols as usize,
+            datatype,
+            bytes,
+        })
+    }
+
+    // Returns the address of the object header of a given name in the root
+    // group.
+    fn find_object(&mut self, name: &str) -> Result<u64, Error> {
+        let mut symbol_table: Option<(u64, u64)> = None;
+        for (message_type, data) in self.read_messages(self.root)? {
+            if message_type == SYMBOL_TABLE_MESSAGE {
+                let mut fields = Fields::new(&data);
+                let btree = fields.address(self.offset_size)?;
+                let heap = fields.address(self.offset_size)?;
+                symbol_table = Some((btree, heap));
+            }
+        }
+        let (btree, heap) = symbol_table.ok_or(Error::InvalidData(
+            "root group has no symbol table".to_string(),
+        ))?;
+        let names = self.read_local_heap(heap)?;
+        let entry_size = symbol_table_entry_size(self.offset_size);
+        let mut nodes = vec![btree];
+        while let Some(address) = nodes.pop() {
+            let (level, children) = self.read_group_node(address)?;
+            if level > 0 {
+                nodes.extend(children);
+                continue;
+            }
+            for child in children {
+                let head = self.read_block(child, 8)?;
+                let mut fields = Fields::new(&head);
+                if fields.bytes(4)? != b"SNOD" {
+                    return Err(Error::InvalidData(
+                        "broken symbol table node".to_string(),
+                    ));
+                }
+                fields.skip(2)?;
+                let count = fields.u16()? as usize;
+                let entries =
+                    self.read_block(child + 8, count * entry_size)?;
+                let mut fields = Fields::new(&entries);
+                for _ in 0..count {
+                    let name_offset = fields.uint(self.offset_size)?;
+                    let object = fields.address(self.offset_size)?;
+                    fields.skip(24)?;
+                    if heap_string(&names, name_offset)? == name.as_bytes() {
+                        return Ok(object);
+                    }
+                }
+            }
+        }
+        Err(Error::InvalidData(format!("no dataset: {}", name)))
+    }
+
+    // Reads the level and children of a node of a group B-tree.
+    fn read_group_node(
+        &mut self,
+        address: u64,
+    ) -> Result<(u8, Vec<u64>), Error> {
+        let head_size = 8 + 2 * self.offset_size;
+        let head = self.read_block(address, head_size)?;
+        let mut fields = Fields::new(&head);
+        if fields.bytes(4)? != b"TREE" || fields.u8()? != 0 {
+            return Err(Error::InvalidData(
+                "broken group B-tree node".to_string(),
+            ));
+        }
+        let level = fields.u8()?;
+        let used = fields.u16()? as usize;
+        // keys and children interleaved with one more key than children
+        let entries = self.read_block(
+            address + head_size as u64,
+            (used + 1) * self.length_size + used * self.offset_size,
+        )?;
+        let mut fields = Fields::new(&entries);
+        let mut children = Vec::with_capacity(used);
+        for _ in 0..used {
+            fields.skip(self.length_size)?;
+            children.push(fields.address(self.offset_size)?);
+        }
+        Ok((level, children))
+    }
+
+    // Reads the data segment of a local heap.
+    fn read_local_heap(&mut self, address: u64) -> Result<Vec<u8>, Error> {
+        let head = self.read_block(
+            address,
+            8 + 2 * self.length_size + self.offset_size,
+        )?;
+        let mut fields = Fields::new(&head);
+        if fields.bytes(4)? != b"HEAP" {
+            return Err(Error::InvalidData("broken local heap".to_string()));
+        }
+        fields.skip(4)?;
+        let size = fields.uint(self.length_size)?;
+        fields.skip(self.length_size)?;
+        let data = fields.address(self.offset_size)?;
+        self.read_block(data, to_usize(size)?)
+    }
+
+    // Reads the messages in a version 1 object header.
+    //
+    // Follows continuation messages and leaves out NIL messages.
+    fn read_messages(
+        &mut self,
+        address: u64,
+    ) -> Result<Vec<(u16, Vec<u8>)>, Error> {
+        let prefix = self.read_block(address, 16)?;
+        let mut fields = Fields::new(&prefix);
+        let version = fields.u8()?;
+        if version != 1 {
+            return Err(Error::InvalidData(format!(
+                "unsupported object header version: {}",
+                version,
+            )));
+        }
+        fields.skip(1)?;
+        let num_messages = fields.u16()? as usize;
+        fields.skip(4)?;
+        let size = fields.u32()? as usize;
+        let mut blocks = vec![(address + 16, size)];
+        let mut messages = Vec::new();
+        let mut count = 0;
+        while let Some((address, size)) = blocks.pop() {
+            let block = self.read_block(address, size)?;
+            let mut fields = Fields::new(&block);
+            while fields.remaining() >= 8 && count < num_messages {
+                let message_type = fields.u16()?;
+                let size = fields.u16()? as usize;
+                fields.skip(4)?;
+                let data = fields.bytes(size)?;
+                count += 1;
+                match message_type {
+                    NIL_MESSAGE => {},
+                    CONTINUATION_MESSAGE => {
+                        let mut fields = Fields::new(data);
+                        let address = fields.address(self.offset_size)?;
+                        let size = fields.uint(self.length_size)?;
+                        blocks.push((address, to_usize(size)?));
+                    },
+                    _ => messages.push((message_type, data.to_vec())),
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    // Parses a dataspace message and returns the dimensions.
+    fn parse_dataspace(
+        &self,
+        fields: &mut Fields<'_>,
+    ) -> Result<Vec<u64>, Error> {
+        let version = fields.u8()?;
+        let rank = fields.u8()? as usize;
+        match version {
+            1 => fields.skip(6)?,
+            2 => fields.skip(2)?,
+            _ => {
+                return Err(Error::InvalidData(format!(
+                    "unsupported dataspace version: {}",
+                    version,
+                )));
+            },
+        }
+        (0..rank).map(|_| fields.uint(self.length_size)).collect()
+    }
+
+    // Parses a data layout message.
+    fn parse_layout(&self, fields: &mut Fields<'_>) -> Result<Layout, Error> {
+        let version = fields.u8()?;
+        let class = match version {
+            1 | 2 => {
+                let rank = fields.u8()? as usize;
+                let class = fields.u8()?;
+                fields.skip(5)?;
+                if class == 1 {
+                    let address = fields.address(self.offset_size)?;
+                    // the size is implied by the dataspace and datatype
+                    fields.skip(4 * rank)?;
+                    return Ok(Layout::Contiguous { address, size: u64::MAX });
+                }
+                if class == 0 {
+                    fields.skip(4 * rank)?;
+                    let size = fields.u32()? as usize;
+                    return Ok(Layout::Compact(fields.bytes(size)?.to_vec()));
+                }
+                class
+            },
+            3 => {
+                let class = fields.u8()?;
+                if class == 1 {
+                    let address = fields.address(self.offset_size)?;
+                    let size = fields.uint(self.length_size)?;
+                    return Ok(Layout::Contiguous { address, size });
+                }
+                if class == 0 {
+                    let size = fields.u16()? as usize;
+                    return Ok(Layout::Compact(fields.bytes(size)?.to_vec()));
+                }
+                class
+            },
+            _ => {
+                return Err(Error::InvalidData(format!(
+                    "unsupported layout version: {}",
+                    version,
+                )));
+            },
+        };
+        Err(Error::InvalidData(format!("unsupported layout class: {}", class)))
+    }
+
+    // Reads a block of a given size at a given address.
+    fn read_block(
+        &mut self,
+        address: u64,
+        size: usize,
+    ) -> Result<Vec<u8>, Error> {
+        if address == UNDEFINED_ADDRESS {
+            return Err(Error::InvalidData("undefined address".to_string()));
+        }
+        self.reader.seek(SeekFrom::Start(self.base + address))?;
+        let mut block = vec![0u8; size];
+        self.reader.read_exact(&mut block).map_err(|e| {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                Error::InvalidData("truncated file".to_string())
+            } else {
+                e.into()
+            }
+        })?;
+        Ok(block)
+    }
+}
+
+// Two-dimensional dataset.
+struct Matrix {
+    name: String,
+    rows: usize,
+    cols: usize,
+    datatype: Datatype,
+    // Elements in row-major order.
+    bytes: Vec<u8>,
+}
+
+impl Matrix {
+    // Returns the number of columns as the size of vectors in rows.
+    //
+    // Fails with `Error::InvalidData` if there is no column.
+    fn vector_size(&self) -> Result<NonZeroUsize, Error> {
+        self.cols.try_into().or(Err(Error::InvalidData(format!(
+            "{} has no columns",
+            self.name,
+        ))))
+    }
+
+    // Converts the elements into floats.
+    fn to_floats(&self) -> Result<Vec<f32>, Error> {
+        let size = self.datatype.size();
+        Ok(self.bytes
+            .chunks(size)
+            .map(|b| match self.datatype {
+                Datatype::Float { big_endian, .. } => {
+                    if size == 4 {
+                        f32::from_bits(decode_uint(b, big_endian) as u32)
+                    } else {
+                        f64::from_bits(decode_uint(b, big_endian)) as f32
+                    }
+                },
+                Datatype::Integer { big_endian, signed, .. } => {
+                    decode_int(b, big_endian, signed) as f32
+                },
+            })
+            .collect())
+    }
+
+    // Converts the elements into indices.
+    //
+    // Fails if the elements are not integers or any of them is negative.
+    fn to_indices(&self) -> Result<Vec<usize>, Error> {
+        let Datatype::Integer { big_endian, signed, size } = self.datatype
+        else {
+            return Err(Error::InvalidData(format!(
+                "{} is not of integers",
+                self.name,
+            )));
+        };
+        self.bytes
+            .chunks(size)
+            .map(|b| {
+                let i = decode_int(b, big_endian, signed);
+                usize::try_from(i).map_err(|_| Error::InvalidData(format!(
+                    "negative neighbor index: {}",
+                    i,
+                )))
+            })
+            .collect()
+    }
+}
+
+// Datatype of elements.
+#[derive(Clone, Copy)]
+enum Datatype {
+    // IEEE floating point number of 4 or 8 bytes.
+    Float { size: usize, big_endian: bool },
+    // Integer of 1, 2, 4, or 8 bytes.
+    Integer { size: usize, big_endian: bool, signed: bool },
+}
+
+impl Datatype {
+    // Returns the size of an element in bytes.
+    fn size(&self) -> usize {
+        match self {
+            Datatype::Float { size, .. } => *size,
+            Datatype::Integer { size, .. } => *size,
+        }
+    }
+}
+
+// Storage of elements.
+enum Layout {
+    // Elements at an address in the file.
+    Contiguous { address: u64, size: u64 },
+    // Elements in the layout message.
+    Compact(Vec<u8>),
+}
+
+// Parses a datatype message.
+fn parse_datatype(fields: &mut Fields<'_>) -> Result<Datatype, Error> {
+    let class = fields.u8()? & 0x0F;
+    let flags = fields.u8()?;
+    fields.skip(2)?;
+    let size = fields.u32()? as usize;
+    let big_endian = flags & 0x01 != 0;
+    match class {
+        0 if [1, 2, 4, 8].contains(&size) => Ok(Datatype::Integer {
+            size,
+            big_endian,
+            signed: flags & 0x08 != 0,
+        }),
+        // bit 6 indicates the VAX byte order
+        1 if [4, 8].contains(&size) && flags & 0x40 == 0 => {
+            Ok(Datatype::Float { size, big_endian })
+        },
+        _ => Err(Error::InvalidData(format!(
+            "unsupported datatype: class {} of {} bytes",
+            class,
+            size,
+        ))),
+    }
+}
+
+// Cursor over the fields of a structure in an HDF5 file.
+struct Fields<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    // Returns the number of bytes left.
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > self.remaining() {
+            return Err(Error::InvalidData("truncated structure".to_string()));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Error> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    // Reads a little-endian unsigned integer of `n` bytes.
+    fn uint(&mut self, n: usize) -> Result<u64, Error> {
+        Ok(decode_uint(self.bytes(n)?, false))
+    }
+
+    // Reads an address of `n` bytes.
+    //
+    // Normalizes the undefined address to `UNDEFINED_ADDRESS`.
+    fn address(&mut self, n: usize) -> Result<u64, Error> {
+        let address = self.uint(n)?;
+        if n < 8 && address == (1 << (8 * n)) - 1 {
+            Ok(UNDEFINED_ADDRESS)
+        } else {
+            Ok(address)
+        }
+    }
+}
+
+// Decodes an unsigned integer of up to 8 bytes.
+fn decode_uint(bytes: &[u8], big_endian: bool) -> u64 {
+    let fold = |n: u64, &b: &u8| (n << 8) | b as u64;
+    if big_endian {
+        bytes.iter().fold(0, fold)
+    } else {
+        bytes.iter().rev().fold(0, fold)
+    }
+}
+
+// Decodes an integer of up to 8 bytes.
+fn decode_int(bytes: &[u8], big_endian: bool, signed: bool) -> i128 {
+    let n = decode_uint(bytes, big_endian);
+    let bits = 8 * bytes.len() as u32;
+    if signed && n >> (bits - 1) & 1 == 1 {
+        n as i128 - (1i128 << bits)
+    } else {
+        n as i128
+    }
+}
+
+// Returns the null-terminated string at an offset in a local heap.
+fn heap_string(heap: &[u8], offset: u64) -> Result<&[u8], Error> {
+    let name = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| heap.get(offset..))
+        .ok_or(Error::InvalidData("name out of local heap".to_string()))?;
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Ok(&name[..end])
+}
+
+// Returns the size of a symbol table entry.
+fn symbol_table_entry_size(offset_size: usize) -> usize {
+    2 * offset_size + 24
+}
+
+// Checks the size of offsets or lengths.
+fn check_size(size: u8, what: &str) -> Result<usize, Error> {
+    if [2, 4, 8].contains(&size) {
+        Ok(size as usize)
+    } else {
+        Err(Error::InvalidData(format!(
+            "unsupported size of {}: {}",
+            what,
+            size,
+        )))
+    }
+}
+
+fn to_usize(n: u64) -> Result<usize, Error> {
+    usize::try_from(n)
+        .map_err(|_| Error::InvalidData(format!("too large size: {}", n)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::linalg::simd::Kernels;
+
+    // Datatype message of little-endian 32-bit floats.
+    const F32_DATATYPE: [u8; 20] = [
+        0x11, 0x20, 0x1F, 0x00, 4, 0, 0, 0,
+        0, 0, 32, 0, 23, 8, 0, 23, 127, 0, 0, 0,
+    ];
+
+    // Datatype message of little-endian signed 32-bit integers.
+    const I32_DATATYPE: [u8; 12] = [
+        0x10, 0x08, 0x00, 0x00, 4, 0, 0, 0,
+        0, 0, 32, 0,
+    ];
+
+    // Name, datatype message, dimensions, and elements of a dataset.
+    type DatasetSpec<'a> = (&'a str, &'a [u8], [u64; 2], Vec<u8>);
+
+    // Writer of HDF5 files laid out as h5py writes them by default.
+    struct Fixture {
+        bytes: Vec<u8>,
+    }
+
+    impl Fixture {
+        // Size of the version 0 superblock with 8-byte offsets and lengths.
+        const SUPERBLOCK_SIZE: usize = 96;
+
+        // Writes the superblock, root group, and given datasets.
+        //
+        // Puts the layout message of every other dataset in a continuation
+        // block.
+        fn write(datasets: &[DatasetSpec<'_>]) -> Vec<u8> {
+            let mut fixture = Fixture {
+                bytes: vec![0u8; Self::SUPERBLOCK_SIZE],
+            };
+            let mut heap = vec![0u8; 8];
+            let mut entries: Vec<(u64, u64)> = Vec::new();
+            for (i, (name, datatype, dims, elements)) in
+                datasets.iter().enumerate()
+            {
+                let data = fixture.alloc(elements);
+                let mut dataspace = vec![1, 2, 0, 0, 0, 0, 0, 0];
+                dataspace.extend(dims[0].to_le_bytes());
+                dataspace.extend(dims[1].to_le_bytes());
+                let mut layout = vec![3, 1];
+                layout.extend(data.to_le_bytes());
+                layout.extend((elements.len() as u64).to_le_bytes());
+                let mut messages = vec![
+                    (DATASPACE_MESSAGE, dataspace),
+                    (DATATYPE_MESSAGE, datatype.to_vec()),
+                    // fill value, which the reader skips
+                    (0x0005, vec![2, 2, 2, 0]),
+                ];
+                if i % 2 == 0 {
+                    messages.push((LAYOUT_MESSAGE, layout));
+                } else {
+                    let block = message_block(&[
+                        (LAYOUT_MESSAGE, layout),
+                        (NIL_MESSAGE, vec![0u8; 8]),
+                    ]);
+                    let mut continuation =
+                        fixture.alloc(&block).to_le_bytes().to_vec();
+                    continuation.extend((block.len() as u64).to_le_bytes());
+                    messages.push((CONTINUATION_MESSAGE, continuation));
+                }
+                let header = object_header(&messages, 2);
+                entries.push((heap.len() as u64, fixture.alloc(&header)));
+                heap.extend(name.as_bytes());
+                heap.push(0);
+            }
+            // symbol table node
+            let mut node = b"SNOD".to_vec();
+            node.extend([1, 0]);
+            node.extend((entries.len() as u16).to_le_bytes());
+            for (name, object) in &entries {
+                node.extend(name.to_le_bytes());
+                node.extend(object.to_le_bytes());
+                node.extend([0u8; 24]);
+            }
+            let node = fixture.alloc(&node);
+            // group B-tree of a single leaf
+            let mut btree = b"TREE".to_vec();
+            btree.extend([0, 0, 1, 0]);
+            btree.extend(u64::MAX.to_le_bytes());
+            btree.extend(u64::MAX.to_le_bytes());
+            btree.extend(0u64.to_le_bytes());
+            btree.extend(node.to_le_bytes());
+            btree.extend(entries.last().unwrap().0.to_le_bytes());
+            let btree = fixture.alloc(&btree);
+            // local heap
+            let data = fixture.alloc(&heap);
+            let mut header = b"HEAP".to_vec();
+            header.extend([0u8; 4]);
+            header.extend((heap.len() as u64).to_le_bytes());
+            header.extend(u64::MAX.to_le_bytes());
+            header.extend(data.to_le_bytes());
+            let heap = fixture.alloc(&header);
+            // root group
+            let mut symbol_table = btree.to_le_bytes().to_vec();
+            symbol_table.extend(heap.to_le_bytes());
+            let root = fixture.alloc(&object_header(
+                &[(SYMBOL_TABLE_MESSAGE, symbol_table.clone())],
+                0,
+            ));
+            // superblock
+            let mut superblock = SIGNATURE.to_vec();
+            superblock.extend([0, 0, 0, 0, 0, 8, 8, 0]);
+            superblock.extend([4, 0, 16, 0, 0, 0, 0, 0]);
+            superblock.extend(0u64.to_le_bytes());
+            superblock.extend(u64::MAX.to_le_bytes());
+            superblock.extend((fixture.bytes.len() as u64).to_le_bytes());
+            superblock.extend(u64::MAX.to_le_bytes());
+            superblock.extend(0u64.to_le_bytes());
+            superblock.extend(root.to_le_bytes());
+            superblock.extend(1u32.to_le_bytes());
+            superblock.extend([0u8; 4]);
+            superblock.extend(symbol_table);
+            assert_eq!(superblock.len(), Self::SUPERBLOCK_SIZE);
+            fixture.bytes[..Self::SUPERBLOCK_SIZE].copy_from_slice(&superblock);
+            fixture.bytes
+        }
+
+        // Appends a block aligned to 8 bytes and returns its address.
+        fn alloc(&mut self, block: &[u8]) -> u64 {
+            self.bytes.resize(self.bytes.len().next_multiple_of(8), 0);
+            let address = self.bytes.len() as u64;
+            self.bytes.extend(block);
+            address
+        }
+    }
+
+    // Builds version 1 object header messages padded to 8 bytes.
+    fn message_block(messages: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (message_type, data) in messages {
+            let size = data.len().next_multiple_of(8);
+            block.extend(message_type.to_le_bytes());
+            block.extend((size as u16).to_le_bytes());
+            block.extend([0u8; 4]);
+            block.extend(data);
+            block.resize(block.len() + size - data.len(), 0);
+        }
+        block
+    }
+
+    // Builds a version 1 object header with given messages and a given
+    // number of messages in continuation blocks.
+    fn object_header(
+        messages: &[(u16, Vec<u8>)],
+        num_continued: usize,
+    ) -> Vec<u8> {
+        let block = message_block(messages);
+        let mut header = vec![1, 0];
+        header.extend(((messages.len() + num_continued) as u16).to_le_bytes());
+        header.extend(1u32.to_le_bytes());
+        header.extend((block.len() as u32).to_le_bytes());
+        header.extend([0u8; 4]);
+        header.extend(block);
+        header
+    }
+
+    fn f32_elements(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn i32_elements(values: &[i32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn write_dataset(neighbors: &[i32]) -> Vec<u8> {
+        Fixture::write(&[
+            (
+                "distances",
+                &F32_DATATYPE,
+                [2, 2],
+                f32_elements(&[0.0, 1.5, 0.0, 2.5]),
+            ),
+            (
+                "neighbors",
+                &I32_DATATYPE,
+                [2, 2],
+                i32_elements(neighbors),
+            ),
+            (
+                "test",
+                &F32_DATATYPE,
+                [2, 3],
+                f32_elements(&[1.0, 2.0, 3.0, 7.0, 8.0, 9.0]),
+            ),
+            (
+                "train",
+                &F32_DATATYPE,
+                [3, 3],
+                f32_elements(&[
+                    1.0, 2.0, 3.0,
+                    4.0, 5.0, 6.0,
+                    7.0, 8.0, 9.0,
+                ]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn read_ann_benchmarks_should_read_all_datasets() {
+        let input = write_dataset(&[0, 1, 2, 1]);
+        let dataset = read_ann_benchmarks(Cursor::new(input)).unwrap();
+        assert_eq!(dataset.train.len(), 3);
+        assert_eq!(dataset.train.vector_size(), 3);
+        assert_eq!(dataset.train.get(1), &[4.0, 5.0, 6.0]);
+        assert_eq!(dataset.test.len(), 2);
+        assert_eq!(dataset.test.get(1), &[7.0, 8.0, 9.0]);
+        assert_eq!(dataset.neighbors, vec![vec![0, 1], vec![2, 1]]);
+        assert_eq!(dataset.distances, vec![vec![0.0, 1.5], vec![0.0, 2.5]]);
+    }
+
+    #[test]
+    fn matrix_without_columns_should_have_no_vector_size() {
+        let matrix = Matrix {
+            name: "train".to_string(),
+            rows: 1,
+            cols: 0,
+            datatype: parse_datatype(&mut Fields::new(&F32_DATATYPE)).unwrap(),
+            bytes: Vec::new(),
+        };
+        assert!(matches!(matrix.vector_size(), Err(Error::InvalidData(_))));
+    }
+
+    #[test]
+    fn read_ann_benchmarks_should_reject_negative_index() {
+        let input = write_dataset(&[0, 1, -1, 1]);
+        assert!(matches!(
+            read_ann_benchmarks(Cursor::new(input)),
+            Err(Error::InvalidData(_)),
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs a real ann-benchmarks file of a Euclidean dataset"]
+    fn read_real_ann_benchmarks_file() {
+        let path = std::env::var("FLECHASDB_ANN_BENCHMARKS_HDF5")
+            .expect("FLECHASDB_ANN_BENCHMARKS_HDF5 must name a file");
+        let f = std::fs::File::open(path).unwrap();
+        let dataset =
+            read_ann_benchmarks(std::io::BufReader::new(f)).unwrap();
+        assert_eq!(dataset.train.vector_size(), dataset.test.vector_size());
+        assert_eq!(dataset.neighbors.len(), dataset.test.len());
+        // distances are Euclidean distances to the neighbors in order
+        for (qi, (neighbors, distances)) in dataset.neighbors
+            .iter()
+            .zip(dataset.distances.iter())
+            .enumerate()
+            .take(10)
+        {
+            let query = dataset.test.get(qi);
+            for (&ni, &distance) in neighbors.iter().zip(distances.iter()) {
+                let exact = f32::squared_distance(query, dataset.train.get(ni))
+                    .sqrt();
+                assert!((exact - distance).abs() <= 1e-3 * distance.max(1.0));
+            }
+            assert!(distances.windows(2).all(|d| d[0] <= d[1]));
+        }
+    }
+
+    #[test]
+    fn read_ann_benchmarks_should_reject_malformed_input() {
+        // not HDF5
+        assert!(matches!(
+            read_ann_benchmarks(Cursor::new(vec![0u8; 1024])),
+            Err(Error::InvalidData(_)),
+        ));
+        // truncated
+        let mut input = write_dataset(&[0, 1, 2, 1]);
+        input.truncate(input.len() - 1);
+        assert!(matches!(
+            read_ann_benchmarks(Cursor::new(input)),
+            Err(Error::InvalidData(_)),
+        ));
+        // missing dataset
+        let input = Fixture::write(&[(
+            "train",
+            &F32_DATATYPE,
+            [1, 1],
+            f32_elements(&[1.0]),
+        )]);
+        assert!(matches!(
+            read_ann_benchmarks(Cursor::new(input)),
+            Err(Error::InvalidData(_)),
+        ));
+    }
+}
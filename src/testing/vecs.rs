@@ -0,0 +1,195 @@
+//! Readers of the fvecs, bvecs, and ivecs formats.
+//!
+//! These are the formats of the SIFT and GIST benchmark datasets
+//! (<http://corpus-texmex.irisa.fr/>). Every record consists of a
+//! little-endian 32-bit dimension followed by that many elements; 32-bit
+//! floats for fvecs, bytes for bvecs, and 32-bit integers for ivecs.
+//!
+//! Readers consume the whole input. To read only a prefix of a large file,
+//! limit the reader with [`Read::take`].
+//!
+//! Datasets of ann-benchmarks in HDF5 are read by [`super::hdf5`] with the
+//! `hdf5` feature.
+
+use std::io::{BufReader, ErrorKind, Read};
+
+use crate::error::Error;
+use crate::vector::BlockVectorSet;
+
+/// Reads vectors in the fvecs format.
+///
+/// Fails if the input is empty, truncated, or has records of different
+/// dimensions.
+pub fn read_fvecs<R>(reader: R) -> Result<BlockVectorSet<f32>, Error>
+where
+    R: Read,
+{
+    let (data, dim) = read_vecs(reader, f32::from_le_bytes)?;
+    BlockVectorSet::chunk(data, dim.try_into().unwrap())
+}
+
+/// Reads vectors in the bvecs format.
+///
+/// Elements are converted into [`f32`].
+///
+/// Fails if the input is empty, truncated, or has records of different
+/// dimensions.
+pub fn read_bvecs<R>(reader: R) -> Result<BlockVectorSet<f32>, Error>
+where
+    R: Read,
+{
+    let (data, dim) = read_vecs(reader, |[b]: [u8; 1]| b as f32)?;
+    BlockVectorSet::chunk(data, dim.try_into().unwrap())
+}
+
+/// Reads ground-truth neighbor lists in the ivecs format.
+///
+/// Each list holds the indices of the nearest neighbors of a query vector
+/// in ascending order of distances.
+///
+/// Fails if the input is empty, truncated, has records of different
+/// dimensions, or has a negative index.
+pub fn read_ivecs<R>(reader: R) -> Result<Vec<Vec<usize>>, Error>
+where
+    R: Read,
+{
+    let (data, dim) = read_vecs(reader, i32::from_le_bytes)?;
+    data.chunks(dim)
+        .map(|row| {
+            row.iter()
+                .map(|&i| usize::try_from(i).map_err(|_| Error::InvalidData(
+                    format!("negative neighbor index: {}", i),
+                )))
+                .collect()
+        })
+        .collect()
+}
+
+// Reads all the records and returns the concatenated elements and the
+// dimension.
+fn read_vecs<R, T, F, const W: usize>(
+    reader: R,
+    decode: F,
+) -> Result<(Vec<T>, usize), Error>
+where
+    R: Read,
+    F: Fn([u8; W]) -> T,
+{
+    let mut reader = BufReader::new(reader);
+    let mut data: Vec<T> = Vec::new();
+    let mut dim: Option<usize> = None;
+    let mut buf = [0u8; W];
+    while let Some(d) = read_dimension(&mut reader)? {
+        match dim {
+            None => dim = Some(d),
+            Some(dim) if dim != d => {
+                return Err(Error::InvalidData(format!(
+                    "inconsistent dimension: expected {} but got {}",
+                    dim,
+                    d,
+                )));
+            },
+            _ => {},
+        }
+        data.reserve(d);
+        for _ in 0..d {
+            reader.read_exact(&mut buf).map_err(truncated)?;
+            data.push(decode(buf));
+        }
+    }
+    let dim = dim.ok_or(Error::InvalidData("no vectors".to_string()))?;
+    Ok((data, dim))
+}
+
+// Reads the dimension of the next record.
+//
+// Returns `None` at the end of the input.
+fn read_dimension<R>(reader: &mut R) -> Result<Option<usize>, Error>
+where
+    R: Read,
+{
+    let mut buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(truncated(ErrorKind::UnexpectedEof.into())),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let d = i32::from_le_bytes(buf);
+    if d <= 0 {
+        return Err(Error::InvalidData(format!("invalid dimension: {}", d)));
+    }
+    Ok(Some(d as usize))
+}
+
+// Turns an unexpected EOF into an `InvalidData` error.
+fn truncated(e: std::io::Error) -> Error {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        Error::InvalidData("truncated record".to_string())
+    } else {
+        e.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record<const W: usize>(elements: &[[u8; W]]) -> Vec<u8> {
+        let mut bytes = (elements.len() as i32).to_le_bytes().to_vec();
+        for e in elements {
+            bytes.extend_from_slice(e);
+        }
+        bytes
+    }
+
+    #[test]
+    fn read_fvecs_should_read_all_vectors() {
+        let mut input = record(&[1.0f32.to_le_bytes(), 2.0f32.to_le_bytes()]);
+        input.extend(record(&[3.0f32.to_le_bytes(), 4.0f32.to_le_bytes()]));
+        let vs = read_fvecs(&input[..]).unwrap();
+        assert_eq!(vs.len(), 2);
+        assert_eq!(vs.vector_size(), 2);
+        assert_eq!(vs.get(0), &[1.0, 2.0]);
+        assert_eq!(vs.get(1), &[3.0, 4.0]);
+    }
+
+    #[test]
+    fn read_bvecs_should_convert_bytes_into_floats() {
+        let input = record(&[[0u8], [128u8], [255u8]]);
+        let vs = read_bvecs(&input[..]).unwrap();
+        assert_eq!(vs.get(0), &[0.0, 128.0, 255.0]);
+    }
+
+    #[test]
+    fn read_ivecs_should_read_neighbor_lists() {
+        let mut input = record(&[5i32.to_le_bytes(), 3i32.to_le_bytes()]);
+        input.extend(record(&[0i32.to_le_bytes(), 1i32.to_le_bytes()]));
+        let lists = read_ivecs(&input[..]).unwrap();
+        assert_eq!(lists, vec![vec![5, 3], vec![0, 1]]);
+    }
+
+    #[test]
+    fn read_ivecs_should_reject_negative_index() {
+        let input = record(&[(-1i32).to_le_bytes()]);
+        assert!(matches!(read_ivecs(&input[..]), Err(Error::InvalidData(_))));
+    }
+
+    #[test]
+    fn read_fvecs_should_reject_malformed_input() {
+        // empty
+        assert!(matches!(read_fvecs(&[][..]), Err(Error::InvalidData(_))));
+        // truncated
+        let mut input = record(&[1.0f32.to_le_bytes(), 2.0f32.to_le_bytes()]);
+        input.truncate(input.len() - 1);
+        assert!(matches!(read_fvecs(&input[..]), Err(Error::InvalidData(_))));
+        // inconsistent dimensions
+        let mut input = record(&[1.0f32.to_le_bytes(), 2.0f32.to_le_bytes()]);
+        input.extend(record(&[3.0f32.to_le_bytes()]));
+        assert!(matches!(read_fvecs(&input[..]), Err(Error::InvalidData(_))));
+    }
+}
@@ -1,11 +1,18 @@
 //! Vectors.
 
 use std::num::NonZeroUsize;
+use std::ops::Range;
 
 use crate::error::Error;
-use crate::slice::AsSlice;
+use crate::kmeans::Scalar;
+use crate::linalg::{norm2, scale_in};
+use crate::slice::{AsMutSlice, AsSlice};
 
+pub mod bits;
+pub mod fastscan;
 pub mod proto;
+#[cfg(feature = "mmap")]
+pub mod spill;
 
 /// Set of vectors of the same size.
 pub trait VectorSet<T> {
@@ -17,11 +24,120 @@ pub trait VectorSet<T> {
     /// Returns the number of vectors in the set.
     fn len(&self) -> usize;
 
+    /// Returns if the set has no vector.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Returns the size of each vector.
     fn vector_size(&self) -> usize;
 
     /// Returns the i-th vector.
     fn get(&self, i: usize) -> &Self::Vector;
+
+    /// Returns an iterator over pairs of an index and a vector.
+    fn iter(&self) -> VectorSetIter<'_, T, Self>
+    where
+        Self: Sized,
+    {
+        VectorSetIter::new(self)
+    }
+}
+
+/// Iterator over vectors in a [`VectorSet`].
+///
+/// Yields pairs of an index and a vector.
+pub struct VectorSetIter<'a, T, VS>
+where
+    VS: VectorSet<T>,
+{
+    vs: &'a VS,
+    // Index of the next vector from the front.
+    front: usize,
+    // Index next to the next vector from the back.
+    back: usize,
+    t: std::marker::PhantomData<T>,
+}
+
+impl<'a, T, VS> VectorSetIter<'a, T, VS>
+where
+    VS: VectorSet<T>,
+{
+    /// Creates an iterator over all the vectors in a given vector set.
+    pub fn new(vs: &'a VS) -> Self {
+        Self {
+            vs,
+            front: 0,
+            back: vs.len(),
+            t: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<'a, T, VS> Iterator for VectorSetIter<'a, T, VS>
+where
+    VS: VectorSet<T>,
+    VS::Vector: 'a,
+{
+    type Item = (usize, &'a VS::Vector);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front < self.back {
+            let i = self.front;
+            self.front += 1;
+            Some((i, self.vs.get(i)))
+        } else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.back - self.front;
+        (n, Some(n))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.front = self.front.saturating_add(n).min(self.back);
+        self.next()
+    }
+}
+
+impl<'a, T, VS> DoubleEndedIterator for VectorSetIter<'a, T, VS>
+where
+    VS: VectorSet<T>,
+    VS::Vector: 'a,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front < self.back {
+            self.back -= 1;
+            Some((self.back, self.vs.get(self.back)))
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T, VS> ExactSizeIterator for VectorSetIter<'a, T, VS>
+where
+    VS: VectorSet<T>,
+    VS::Vector: 'a,
+{}
+
+/// Set of vectors that can be updated in place.
+pub trait VectorSetMut<T>: VectorSet<T> {
+    /// Returns the mutable i-th vector.
+    fn get_mut(&mut self, i: usize) -> &mut Self::Vector;
+}
+
+/// Set of vectors from which vectors can be removed.
+pub trait VectorSetRemove<T>: VectorSet<T> {
+    /// Retains only the vectors for whose indices `keep` returns `true`.
+    ///
+    /// `keep` is called with every index in ascending order, and remaining
+    /// vectors preserve their order.
+    fn retain<F>(&mut self, keep: F)
+    where
+        F: FnMut(usize) -> bool;
 }
 
 /// Vectors in a contiguous array.
@@ -42,7 +158,7 @@ impl<T> BlockVectorSet<T> {
         vector_size: NonZeroUsize,
     ) -> Result<Self, Error> {
         let m = vector_size.get();
-        if data.is_empty() || data.len() % m == 0 {
+        if data.is_empty() || data.len().is_multiple_of(m) {
             Ok(Self {
                 data,
                 vector_size: m,
@@ -61,6 +177,11 @@ impl<T> BlockVectorSet<T> {
         self.data.len() / self.vector_size
     }
 
+    /// Returns if the vector set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
     /// Returns the size of each vector in the vector set.
     pub const fn vector_size(&self) -> usize {
         self.vector_size
@@ -81,6 +202,29 @@ impl<T> BlockVectorSet<T> {
         let to = from + self.vector_size;
         &mut self.data[from..to]
     }
+
+    /// Appends a vector.
+    ///
+    /// Fails if the size of `v` does not match the vector size.
+    pub fn push(&mut self, v: &[T]) -> Result<(), Error>
+    where
+        T: Clone,
+    {
+        if v.len() != self.vector_size {
+            return Err(Error::InvalidArgs(format!(
+                "vector size ({}) does not match {}",
+                v.len(),
+                self.vector_size,
+            )));
+        }
+        self.data.extend_from_slice(v);
+        Ok(())
+    }
+
+    /// Returns the number of bytes allocated for the elements.
+    pub fn memory_bytes(&self) -> usize {
+        self.data.capacity() * core::mem::size_of::<T>()
+    }
 }
 
 impl<T> VectorSet<T> for BlockVectorSet<T> {
@@ -99,6 +243,148 @@ impl<T> VectorSet<T> for BlockVectorSet<T> {
     }
 }
 
+impl<T> VectorSetMut<T> for BlockVectorSet<T> {
+    fn get_mut(&mut self, i: usize) -> &mut Self::Vector {
+        self.get_mut(i)
+    }
+}
+
+impl<T> VectorSetRemove<T> for BlockVectorSet<T> {
+    fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let m = self.vector_size;
+        let n = self.len();
+        let mut num_kept = 0;
+        for i in 0..n {
+            if keep(i) {
+                if num_kept != i {
+                    for j in 0..m {
+                        self.data.swap(num_kept * m + j, i * m + j);
+                    }
+                }
+                num_kept += 1;
+            }
+        }
+        self.data.truncate(num_kept * m);
+    }
+}
+
+/// Code in an encoded vector; i.e., an index in a codebook.
+pub trait Code: Copy {
+    /// Returns the code as an index.
+    fn index(self) -> usize;
+}
+
+impl Code for u8 {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl Code for u32 {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Encoded vectors whose codes are stored in the narrowest type.
+#[derive(Clone, Debug)]
+pub enum EncodedVectorSet {
+    /// Codes stored a byte each; i.e., there are at most 256 codes.
+    U8(BlockVectorSet<u8>),
+    /// Codes stored four bytes each.
+    U32(BlockVectorSet<u32>),
+}
+
+impl EncodedVectorSet {
+    /// Stores codes in the narrowest type that can hold `num_codes` codes.
+    ///
+    /// Fails if a code is not less than `num_codes`.
+    pub fn with_num_codes(
+        vs: BlockVectorSet<u32>,
+        num_codes: usize,
+    ) -> Result<Self, Error> {
+        let out_of_bounds = vs.data.iter().find(|&&c| c as usize >= num_codes);
+        if let Some(&code) = out_of_bounds {
+            return Err(Error::InvalidArgs(format!(
+                "code {} exceeds the number of codes {}",
+                code,
+                num_codes,
+            )));
+        }
+        if num_codes > 256 {
+            return Ok(Self::U32(vs));
+        }
+        let data = vs.data.into_iter().map(|c| c as u8).collect();
+        Ok(Self::U8(BlockVectorSet {
+            data,
+            vector_size: vs.vector_size,
+        }))
+    }
+
+    /// Returns the number of vectors.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::U8(vs) => vs.len(),
+            Self::U32(vs) => vs.len(),
+        }
+    }
+
+    /// Returns if there is no vector.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of codes in each vector.
+    pub const fn vector_size(&self) -> usize {
+        match self {
+            Self::U8(vs) => vs.vector_size(),
+            Self::U32(vs) => vs.vector_size(),
+        }
+    }
+
+    /// Returns the `di`-th code of the `i`-th vector.
+    ///
+    /// Panics if `i` or `di` is out of bounds.
+    pub fn code(&self, i: usize, di: usize) -> usize {
+        match self {
+            Self::U8(vs) => vs.get(i)[di].index(),
+            Self::U32(vs) => vs.get(i)[di].index(),
+        }
+    }
+
+    /// Returns the codes of the `i`-th vector as `u32`.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn get(&self, i: usize) -> Vec<u32> {
+        match self {
+            Self::U8(vs) => vs.get(i).iter().map(|&c| c as u32).collect(),
+            Self::U32(vs) => vs.get(i).to_vec(),
+        }
+    }
+
+    /// Returns the codes as `u32`.
+    pub fn into_u32(self) -> BlockVectorSet<u32> {
+        match self {
+            Self::U8(vs) => BlockVectorSet {
+                data: vs.data.into_iter().map(|c| c as u32).collect(),
+                vector_size: vs.vector_size,
+            },
+            Self::U32(vs) => vs,
+        }
+    }
+
+    /// Returns the number of bytes allocated for the codes.
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            Self::U8(vs) => vs.memory_bytes(),
+            Self::U32(vs) => vs.memory_bytes(),
+        }
+    }
+}
+
 /// Subvectors of another vector set.
 pub struct SubVectorSet<'a, T, VS>
 where
@@ -148,9 +434,34 @@ where
     }
 }
 
+/// Returns the range of elements in a subvector division.
+///
+/// A vector of `vector_size` elements is divided into `num_divisions`
+/// subvectors as evenly as possible; the first
+/// `vector_size % num_divisions` divisions have one more element than the
+/// others. Ranges of all the divisions are contiguous and cover the whole
+/// vector.
+///
+/// `division` must be less than `num_divisions`.
+pub fn division_range(
+    vector_size: usize,
+    num_divisions: usize,
+    division: usize,
+) -> Range<usize> {
+    let md = vector_size / num_divisions;
+    let remainder = vector_size % num_divisions;
+    let from = division * md + division.min(remainder);
+    let to = from + md + usize::from(division < remainder);
+    from..to
+}
+
 /// Divides a given vector set into subvector sets.
 ///
-/// Fails if `vs.vector_size()` is not multiple of `d`.
+/// Subvectors are as long as the ranges given by [`division_range`], so
+/// `vs.vector_size()` does not have to be a multiple of `d`.
+///
+/// Fails if `d` exceeds `vs.vector_size()`; i.e., a division would be
+/// empty.
 pub fn divide_vector_set<'a, T, VS>(
     vs: &'a VS,
     d: NonZeroUsize,
@@ -159,20 +470,95 @@ where
     VS: VectorSet<T>,
 {
     let d = d.get();
-    if vs.vector_size() % d != 0 {
+    let m = vs.vector_size();
+    if d > m {
         return Err(Error::InvalidArgs(format!(
-            "vector size ({}) is not divisible by {}",
-            vs.vector_size(),
+            "vector size ({}) is less than the number of divisions ({})",
+            m,
             d,
         )));
     }
-    let m = vs.vector_size() / d;
     let divided = (0..d)
-        .map(|i| SubVectorSet::new(vs, m, i * m))
+        .map(|i| {
+            let range = division_range(m, d, i);
+            SubVectorSet::new(vs, range.len(), range.start)
+        })
         .collect();
     Ok(divided)
 }
 
+/// Normalizes every vector in a given vector set to unit length in place.
+///
+/// Leaves zero vectors unchanged.
+pub fn normalize_vectors<T, VS>(vs: &mut VS)
+where
+    T: Scalar,
+    VS: VectorSetMut<T>,
+    VS::Vector: AsMutSlice<T>,
+{
+    for i in 0..vs.len() {
+        let v = vs.get_mut(i).as_mut_slice();
+        let norm = norm2(v);
+        if norm != T::zero() {
+            scale_in(v, T::one() / norm);
+        }
+    }
+}
+
+/// Finds groups of duplicate vectors in a given vector set.
+///
+/// Two vectors are duplicates if the Euclidean distance between them is at
+/// most `tolerance`; zero `tolerance` finds exact duplicates.
+/// Every vector belongs to at most one group, and a group gathers vectors
+/// within `tolerance` of its first vector. Indices in a group are in
+/// ascending order, and groups are ordered by their first indices.
+/// Vectors without duplicates are not included.
+pub fn find_duplicates<T, VS>(vs: &VS, tolerance: T) -> Vec<Vec<usize>>
+where
+    T: Scalar,
+    VS: VectorSet<T>,
+{
+    if vs.vector_size() == 0 {
+        return Vec::new();
+    }
+    // duplicates are close in every element, so only vectors close in the
+    // first element need to be compared
+    let key = |i: usize| vs.get(i).as_slice()[0];
+    let mut order: Vec<usize> = (0..vs.len()).collect();
+    order.sort_by(|&l, &r| {
+        key(l).partial_cmp(&key(r)).unwrap_or(core::cmp::Ordering::Equal)
+    });
+    let squared_tolerance = tolerance * tolerance;
+    let mut grouped = vec![false; vs.len()];
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (a, &i) in order.iter().enumerate() {
+        if grouped[i] {
+            continue;
+        }
+        let v = vs.get(i).as_slice();
+        let mut group = vec![i];
+        for &j in &order[a + 1..] {
+            if key(j) - key(i) > tolerance {
+                break;
+            }
+            if !grouped[j]
+                && T::squared_distance(v, vs.get(j).as_slice())
+                    <= squared_tolerance
+            {
+                grouped[j] = true;
+                group.push(j);
+            }
+        }
+        if group.len() > 1 {
+            grouped[i] = true;
+            group.sort_unstable();
+            groups.push(group);
+        }
+    }
+    groups.sort_unstable_by_key(|g| g[0]);
+    groups
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -253,7 +639,7 @@ mod tests {
     }
 
     #[test]
-    fn divide_vector_set_cannot_divide_5_vectors_of_4_elements_by_3() {
+    fn divide_vector_set_can_divide_5_vectors_of_4_elements_by_3() {
         let v: Vec<f32> = vec![
             1.0, 2.0, 3.0, 4.0,
             5.0, 6.0, 7.0, 8.0,
@@ -262,6 +648,140 @@ mod tests {
             17.0, 18.0, 19.0, 20.0,
         ];
         let vs = BlockVectorSet::chunk(v, 4.try_into().unwrap()).unwrap();
-        assert!(divide_vector_set(&vs, 3.try_into().unwrap()).is_err());
+        let divided = divide_vector_set(&vs, 3.try_into().unwrap()).unwrap();
+        assert_eq!(divided.len(), 3);
+        assert_eq!(divided[0].vector_size(), 2);
+        assert_eq!(divided[0].get(0), &[1.0, 2.0]);
+        assert_eq!(divided[0].get(4), &[17.0, 18.0]);
+        assert_eq!(divided[1].vector_size(), 1);
+        assert_eq!(divided[1].get(0), &[3.0]);
+        assert_eq!(divided[1].get(4), &[19.0]);
+        assert_eq!(divided[2].vector_size(), 1);
+        assert_eq!(divided[2].get(0), &[4.0]);
+        assert_eq!(divided[2].get(4), &[20.0]);
+    }
+
+    #[test]
+    fn divide_vector_set_cannot_divide_vectors_of_4_elements_by_5() {
+        let v: Vec<f32> = vec![1.0, 2.0, 3.0, 4.0];
+        let vs = BlockVectorSet::chunk(v, 4.try_into().unwrap()).unwrap();
+        assert!(divide_vector_set(&vs, 5.try_into().unwrap()).is_err());
+    }
+
+    #[test]
+    fn division_ranges_should_cover_vector_contiguously() {
+        for (m, d) in [(6, 2), (10, 4), (1536, 7), (5, 5)] {
+            let mut from = 0;
+            for i in 0..d {
+                let range = division_range(m, d, i);
+                assert_eq!(range.start, from);
+                assert!(range.len() == m / d || range.len() == m / d + 1);
+                from = range.end;
+            }
+            assert_eq!(from, m);
+        }
+        assert_eq!(division_range(10, 4, 1), 3..6);
+        assert_eq!(division_range(10, 4, 3), 8..10);
+    }
+
+    #[test]
+    fn vector_set_iter_should_yield_indices_and_vectors() {
+        let v: Vec<f32> = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let vs = BlockVectorSet::chunk(v, 2.try_into().unwrap()).unwrap();
+        let mut iter = VectorSet::iter(&vs);
+        assert_eq!(iter.len(), 3);
+        assert_eq!(iter.next(), Some((0, &[1.0, 2.0][..])));
+        assert_eq!(iter.next_back(), Some((2, &[5.0, 6.0][..])));
+        assert_eq!(iter.next(), Some((1, &[3.0, 4.0][..])));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn vector_set_iter_should_iterate_subvectors() {
+        let v: Vec<f32> = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+        let vs = BlockVectorSet::chunk(v, 4.try_into().unwrap()).unwrap();
+        let sub = SubVectorSet::new(&vs, 2, 2);
+        let collected: Vec<(usize, &[f32])> = sub.iter().collect();
+        assert_eq!(collected, vec![(0, &[3.0, 4.0][..]), (1, &[7.0, 8.0][..])]);
+    }
+
+    #[test]
+    fn block_vector_set_should_retain_specified_vectors() {
+        let v: Vec<f32> = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+        let mut vs = BlockVectorSet::chunk(v, 2.try_into().unwrap()).unwrap();
+        VectorSetRemove::retain(&mut vs, |i| i != 0 && i != 2);
+        assert_eq!(vs.len(), 2);
+        assert_eq!(vs.get(0), &[3.0, 4.0]);
+        assert_eq!(vs.get(1), &[7.0, 8.0]);
+    }
+
+    #[test]
+    fn block_vector_set_can_be_updated_through_vector_set_mut() {
+        fn fill_first<VS>(vs: &mut VS)
+        where
+            VS: VectorSetMut<f32>,
+            VS::Vector: AsMutSlice<f32>,
+        {
+            vs.get_mut(0).as_mut_slice().fill(0.0);
+        }
+        let v: Vec<f32> = vec![1.0, 2.0, 3.0, 4.0];
+        let mut vs = BlockVectorSet::chunk(v, 2.try_into().unwrap()).unwrap();
+        fill_first(&mut vs);
+        assert_eq!(vs.get(0), &[0.0, 0.0]);
+        assert_eq!(vs.get(1), &[3.0, 4.0]);
+    }
+
+    #[test]
+    fn normalize_vectors_should_normalize_non_zero_vectors() {
+        let v: Vec<f32> = vec![3.0, 4.0, 0.0, 0.0, 0.0, -2.0];
+        let mut vs = BlockVectorSet::chunk(v, 2.try_into().unwrap()).unwrap();
+        normalize_vectors(&mut vs);
+        assert!((vs.get(0)[0] - 0.6).abs() < 1e-6);
+        assert!((vs.get(0)[1] - 0.8).abs() < 1e-6);
+        assert_eq!(vs.get(1), &[0.0, 0.0]);
+        assert_eq!(vs.get(2)[0], 0.0);
+        assert!((vs.get(2)[1] + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn find_duplicates_should_group_close_vectors() {
+        let vs = BlockVectorSet::chunk(
+            vec![
+                1.0f32, 2.0,
+                5.0, 5.0,
+                1.0, 2.0,
+                5.0, 5.001,
+                9.0, 9.0,
+                1.0, 2.0,
+            ],
+            2.try_into().unwrap(),
+        ).unwrap();
+        assert_eq!(find_duplicates(&vs, 0.0), vec![vec![0, 2, 5]]);
+        assert_eq!(
+            find_duplicates(&vs, 0.01),
+            vec![vec![0, 2, 5], vec![1, 3]],
+        );
+    }
+
+    #[test]
+    fn encoded_vector_set_should_store_codes_in_narrowest_type() {
+        let vs = BlockVectorSet::chunk(
+            vec![0u32, 255, 3, 4],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let encoded =
+            EncodedVectorSet::with_num_codes(vs.clone(), 256).unwrap();
+        assert!(matches!(encoded, EncodedVectorSet::U8(_)));
+        assert_eq!(encoded.len(), 2);
+        assert_eq!(encoded.memory_bytes(), 4);
+        assert_eq!(encoded.code(0, 1), 255);
+        assert_eq!(encoded.get(1), vec![3, 4]);
+        assert_eq!(encoded.into_u32().get(0), vec![0, 255]);
+        let encoded =
+            EncodedVectorSet::with_num_codes(vs.clone(), 257).unwrap();
+        assert!(matches!(encoded, EncodedVectorSet::U32(_)));
+        assert_eq!(encoded.code(0, 1), 255);
+        assert!(EncodedVectorSet::with_num_codes(vs, 255).is_err());
     }
 }
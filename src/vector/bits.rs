@@ -0,0 +1,269 @@
+//! Binary vectors packed into bits.
+//!
+//! Binary embeddings; e.g., sign-quantized embeddings, take a bit per
+//! dimension. [`BitVectorSet`] packs them into 64-bit words, so that the
+//! Hamming distance between two vectors is calculated with population
+//! counts of a few words. See [`hamming_distance`].
+//!
+//! [`hamming_distance`]: crate::linalg::hamming_distance
+
+use std::num::NonZeroUsize;
+
+use crate::error::Error;
+use crate::kmeans::Scalar;
+use crate::numbers::Zero;
+use crate::slice::AsSlice;
+
+use super::VectorSet;
+
+/// Number of bits in a word.
+pub const WORD_BITS: usize = u64::BITS as usize;
+
+/// Binary vectors of the same number of bits packed into 64-bit words.
+///
+/// Bit `j` of a vector is bit `j % 64` of the `j / 64`-th word, and unused
+/// bits of the last word are always zero, so that they never contribute to
+/// Hamming distances. As a [`VectorSet<u64>`], vectors are slices of words.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitVectorSet {
+    words: Vec<u64>,
+    // Number of bits in a vector.
+    num_bits: usize,
+    // Number of words in a vector.
+    num_words: usize,
+}
+
+impl BitVectorSet {
+    /// Creates an empty vector set of vectors of `num_bits` bits.
+    pub fn new(num_bits: NonZeroUsize) -> Self {
+        let num_bits = num_bits.get();
+        Self {
+            words: Vec::new(),
+            num_bits,
+            num_words: num_bits.div_ceil(WORD_BITS),
+        }
+    }
+
+    /// Tries to chunk given packed words into vectors of `num_bits` bits.
+    ///
+    /// Fails if `words.len()` is not a multiple of the number of words in a
+    /// vector, or an unused bit is set.
+    pub fn from_words(
+        words: Vec<u64>,
+        num_bits: NonZeroUsize,
+    ) -> Result<Self, Error> {
+        let mut vs = Self::new(num_bits);
+        if !words.len().is_multiple_of(vs.num_words) {
+            return Err(Error::InvalidArgs(format!(
+                "number of words ({}) is not a multiple of {}",
+                words.len(),
+                vs.num_words,
+            )));
+        }
+        vs.words = words;
+        if let Some(i) = (0..vs.len()).find(|&i| !vs.has_clear_padding(i)) {
+            return Err(Error::InvalidArgs(format!(
+                "vector {} has a bit beyond {} bits",
+                i,
+                vs.num_bits,
+            )));
+        }
+        Ok(vs)
+    }
+
+    /// Packs the signs of vectors in a given vector set.
+    ///
+    /// A positive element becomes a set bit, and the other elements become
+    /// clear bits.
+    ///
+    /// Fails if `vs` has zero-sized vectors.
+    pub fn from_signs<T, VS>(vs: &VS) -> Result<Self, Error>
+    where
+        T: Scalar,
+        VS: VectorSet<T>,
+    {
+        let num_bits = vs.vector_size().try_into().or(Err(
+            Error::InvalidArgs("vector size must not be zero".to_string()),
+        ))?;
+        let mut bits = Self::new(num_bits);
+        bits.words.reserve(vs.len() * bits.num_words);
+        for (_, v) in vs.iter() {
+            bits.words.extend(pack_signs(v.as_slice()));
+        }
+        Ok(bits)
+    }
+
+    /// Returns the number of vectors.
+    pub fn len(&self) -> usize {
+        self.words.len() / self.num_words
+    }
+
+    /// Returns if there is no vector.
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Returns the number of bits in a vector.
+    pub const fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    /// Returns the number of words in a vector.
+    pub const fn num_words(&self) -> usize {
+        self.num_words
+    }
+
+    /// Returns the words of the i-th vector.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn get(&self, i: usize) -> &[u64] {
+        let from = i * self.num_words;
+        &self.words[from..from + self.num_words]
+    }
+
+    /// Returns the j-th bit of the i-th vector.
+    ///
+    /// Panics if `i` or `j` is out of bounds.
+    pub fn get_bit(&self, i: usize, j: usize) -> bool {
+        assert!(j < self.num_bits);
+        (self.get(i)[j / WORD_BITS] >> (j % WORD_BITS)) & 1 == 1
+    }
+
+    /// Appends a vector of packed words.
+    ///
+    /// Fails if `v` does not have as many words as a vector, or an unused
+    /// bit is set.
+    pub fn push(&mut self, v: &[u64]) -> Result<(), Error> {
+        self.check_vector(v)?;
+        self.words.extend_from_slice(v);
+        Ok(())
+    }
+
+    /// Checks if a given vector of packed words fits in this vector set.
+    ///
+    /// Fails if `v` does not have as many words as a vector, or an unused
+    /// bit is set.
+    pub fn check_vector(&self, v: &[u64]) -> Result<(), Error> {
+        if v.len() != self.num_words {
+            return Err(Error::InvalidArgs(format!(
+                "expected {} words but got {}",
+                self.num_words,
+                v.len(),
+            )));
+        }
+        if v[self.num_words - 1] & !self.last_word_mask() != 0 {
+            return Err(Error::InvalidArgs(format!(
+                "vector has a bit beyond {} bits",
+                self.num_bits,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes allocated for the words.
+    pub fn memory_bytes(&self) -> usize {
+        self.words.capacity() * core::mem::size_of::<u64>()
+    }
+
+    // Mask of the used bits in the last word of a vector.
+    fn last_word_mask(&self) -> u64 {
+        match self.num_bits % WORD_BITS {
+            0 => !0,
+            r => (1 << r) - 1,
+        }
+    }
+
+    // Returns if the unused bits of the i-th vector are clear.
+    fn has_clear_padding(&self, i: usize) -> bool {
+        self.get(i)[self.num_words - 1] & !self.last_word_mask() == 0
+    }
+}
+
+impl VectorSet<u64> for BitVectorSet {
+    type Vector = [u64];
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn vector_size(&self) -> usize {
+        self.num_words
+    }
+
+    fn get(&self, i: usize) -> &Self::Vector {
+        self.get(i)
+    }
+}
+
+/// Packs given bits into 64-bit words.
+pub fn pack_bits(bits: &[bool]) -> Vec<u64> {
+    let mut words = vec![0u64; bits.len().div_ceil(WORD_BITS)];
+    for (j, _) in bits.iter().enumerate().filter(|(_, &b)| b) {
+        words[j / WORD_BITS] |= 1 << (j % WORD_BITS);
+    }
+    words
+}
+
+/// Packs the signs of a given vector into 64-bit words.
+///
+/// A positive element becomes a set bit.
+pub fn pack_signs<T>(v: &[T]) -> Vec<u64>
+where
+    T: Zero + PartialOrd,
+{
+    let mut words = vec![0u64; v.len().div_ceil(WORD_BITS)];
+    for (j, _) in v.iter().enumerate().filter(|(_, x)| **x > T::zero()) {
+        words[j / WORD_BITS] |= 1 << (j % WORD_BITS);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::vector::BlockVectorSet;
+
+    #[test]
+    fn bit_vector_set_should_pack_bits_into_words() {
+        let mut vs = BitVectorSet::new(70.try_into().unwrap());
+        assert_eq!(vs.num_words(), 2);
+        let mut bits = vec![false; 70];
+        bits[0] = true;
+        bits[64] = true;
+        bits[69] = true;
+        vs.push(&pack_bits(&bits)).unwrap();
+        vs.push(&[u64::MAX, 0b11_1111]).unwrap();
+        assert_eq!(vs.len(), 2);
+        assert_eq!(vs.get(0), &[1, 0b10_0001]);
+        assert!(vs.get_bit(0, 69));
+        assert!(!vs.get_bit(0, 68));
+        assert!(vs.get_bit(1, 68));
+        assert!(matches!(
+            vs.push(&[0, 0b100_0000]),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(vs.push(&[0]), Err(Error::InvalidArgs(_))));
+        assert!(matches!(
+            BitVectorSet::from_words(vec![0, 1 << 6], 70.try_into().unwrap()),
+            Err(Error::InvalidArgs(_)),
+        ));
+        assert!(matches!(
+            BitVectorSet::from_words(vec![0; 3], 70.try_into().unwrap()),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+
+    #[test]
+    fn bit_vector_set_should_pack_signs_of_vectors() {
+        let vs = BlockVectorSet::chunk(
+            vec![1.0f32, -1.0, 0.0, 2.0, -0.5, 0.5, 3.0, -3.0],
+            4.try_into().unwrap(),
+        ).unwrap();
+        let bits = BitVectorSet::from_signs(&vs).unwrap();
+        assert_eq!(bits.len(), 2);
+        assert_eq!(bits.num_bits(), 4);
+        assert_eq!(bits.get(0), &[0b1001]);
+        assert_eq!(bits.get(1), &[0b0110]);
+    }
+}
@@ -0,0 +1,193 @@
+//! Encoded vectors laid out for fast scan.
+//!
+//! Fast scan evaluates 4-bit codes of [`BLOCK_SIZE`] vectors at once by
+//! looking up a lookup table of 16 entries per division with a single vector
+//! shuffle instruction. [`FastScanCodes`] interleaves the codes of a block
+//! so that the codes of a division are contiguous in 16 bytes.
+
+use std::num::NonZeroUsize;
+
+use crate::error::Error;
+
+use super::{BlockVectorSet, Code};
+
+/// Number of vectors in a block.
+pub const BLOCK_SIZE: usize = 32;
+
+/// Number of codes that fit in 4 bits.
+pub const NUM_CODES: usize = 16;
+
+/// Number of bytes taken by a division of a block.
+pub const DIVISION_BYTES: usize = BLOCK_SIZE / 2;
+
+/// Encoded vectors of 4-bit codes interleaved in blocks of [`BLOCK_SIZE`]
+/// vectors.
+///
+/// A block consists of [`DIVISION_BYTES`] bytes per division. Byte `i` of
+/// division `j` holds the code of vector `i` in the lower 4 bits and that
+/// of vector `i + 16` in the upper 4 bits. The last block is padded with
+/// zero codes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FastScanCodes {
+    data: Vec<u8>,
+    // Number of vectors excluding padding.
+    len: usize,
+    num_divisions: usize,
+}
+
+impl FastScanCodes {
+    /// Lays out given encoded vectors for fast scan.
+    ///
+    /// Fails if a code does not fit in 4 bits.
+    pub fn from_codes<C>(vs: &BlockVectorSet<C>) -> Result<Self, Error>
+    where
+        C: Code,
+    {
+        if let Some(code) = (0..vs.len())
+            .flat_map(|i| vs.get(i).iter().map(|c| c.index()))
+            .find(|&c| c >= NUM_CODES)
+        {
+            return Err(Error::InvalidArgs(format!(
+                "code does not fit in 4 bits: {}",
+                code,
+            )));
+        }
+        Ok(Self::build(
+            vs.len(),
+            vs.vector_size(),
+            |vi, di| vs.get(vi)[di].index() as u8,
+        ))
+    }
+
+    // Lays out encoded vectors packed 4 bits each in the order of vectors.
+    //
+    // See `serialize_packed` for the packed format.
+    //
+    // Panics if `packed_data` is shorter than `len` packed vectors.
+    pub(crate) fn from_packed(
+        packed_data: &[u8],
+        len: usize,
+        num_divisions: NonZeroUsize,
+    ) -> Self {
+        let m = num_divisions.get();
+        let packed_size = m.div_ceil(2);
+        Self::build(len, m, |vi, di| {
+            let b = packed_data[vi * packed_size + di / 2];
+            if di % 2 == 0 { b & 0xF } else { b >> 4 }
+        })
+    }
+
+    // Interleaves the codes given by `code(vector, division)`.
+    fn build<F>(len: usize, num_divisions: usize, code: F) -> Self
+    where
+        F: Fn(usize, usize) -> u8,
+    {
+        let num_blocks = len.div_ceil(BLOCK_SIZE);
+        let mut data = vec![0u8; num_blocks * num_divisions * DIVISION_BYTES];
+        for vi in 0..len {
+            let block = vi / BLOCK_SIZE;
+            let lane = vi % DIVISION_BYTES;
+            let shift = if vi % BLOCK_SIZE < DIVISION_BYTES { 0 } else { 4 };
+            for di in 0..num_divisions {
+                let i = (block * num_divisions + di) * DIVISION_BYTES + lane;
+                data[i] |= code(vi, di) << shift;
+            }
+        }
+        Self {
+            data,
+            len,
+            num_divisions,
+        }
+    }
+
+    /// Returns the number of vectors.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns if there is no vector.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of divisions.
+    pub const fn num_divisions(&self) -> usize {
+        self.num_divisions
+    }
+
+    /// Returns the number of blocks including the padded last block.
+    pub const fn num_blocks(&self) -> usize {
+        self.len.div_ceil(BLOCK_SIZE)
+    }
+
+    /// Returns the interleaved codes of the i-th block.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn block(&self, i: usize) -> &[u8] {
+        let size = self.num_divisions * DIVISION_BYTES;
+        &self.data[i * size..(i + 1) * size]
+    }
+
+    /// Returns the code of the i-th vector in the j-th division.
+    ///
+    /// Panics if `i` or `j` is out of bounds.
+    pub fn code(&self, i: usize, j: usize) -> u8 {
+        assert!(i < self.len);
+        let b = self.block(i / BLOCK_SIZE)[j * DIVISION_BYTES
+            + i % DIVISION_BYTES];
+        if i % BLOCK_SIZE < DIVISION_BYTES { b & 0xF } else { b >> 4 }
+    }
+
+    /// Returns the number of bytes allocated for the codes.
+    pub fn memory_bytes(&self) -> usize {
+        self.data.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::vector::proto::serialize_packed;
+
+    #[test]
+    fn fast_scan_codes_should_interleave_codes_in_blocks() {
+        let vs = BlockVectorSet::chunk(
+            (0..40 * 3).map(|i| (i % 16) as u8).collect(),
+            3.try_into().unwrap(),
+        ).unwrap();
+        let codes = FastScanCodes::from_codes(&vs).unwrap();
+        assert_eq!(codes.len(), 40);
+        assert_eq!(codes.num_blocks(), 2);
+        assert_eq!(codes.block(0).len(), 3 * DIVISION_BYTES);
+        // vectors 0 and 16 share the first byte of a division
+        assert_eq!(codes.block(0)[0], vs.get(0)[0] | (vs.get(16)[0] << 4));
+        // vectors 40 and beyond are padded with zero codes
+        assert_eq!(codes.block(1)[7], vs.get(39)[0]);
+        assert_eq!(codes.block(1)[8], 0);
+        for vi in 0..vs.len() {
+            for di in 0..3 {
+                assert_eq!(codes.code(vi, di), vs.get(vi)[di]);
+            }
+        }
+        let packed = serialize_packed(&vs).unwrap();
+        let unpacked = FastScanCodes::from_packed(
+            &packed.packed_data,
+            vs.len(),
+            3.try_into().unwrap(),
+        );
+        assert_eq!(unpacked, codes);
+    }
+
+    #[test]
+    fn fast_scan_codes_cannot_be_made_of_codes_exceeding_4_bits() {
+        let vs: BlockVectorSet<u32> = BlockVectorSet::chunk(
+            vec![1, 16],
+            2.try_into().unwrap(),
+        ).unwrap();
+        assert!(matches!(
+            FastScanCodes::from_codes(&vs),
+            Err(Error::InvalidArgs(_)),
+        ));
+    }
+}
@@ -1,5 +1,8 @@
 //! Protocol Buffers utilities for [`vector`][`crate::vector`].
 
+use std::num::NonZeroUsize;
+use std::sync::OnceLock;
+
 use crate::error::Error;
 use crate::protos::{Deserialize, Serialize};
 use crate::protos::database::{
@@ -7,7 +10,8 @@ use crate::protos::database::{
     VectorSet as ProtosVectorSet,
 };
 
-use super::BlockVectorSet;
+use super::{BlockVectorSet, Code, EncodedVectorSet};
+use super::fastscan::FastScanCodes;
 
 impl Serialize<ProtosVectorSet> for BlockVectorSet<f32> {
     fn serialize(&self) -> Result<ProtosVectorSet, Error> {
@@ -40,19 +44,228 @@ impl Serialize<ProtosEncodedVectorSet> for BlockVectorSet<u32> {
     }
 }
 
+impl Serialize<ProtosEncodedVectorSet> for BlockVectorSet<u8> {
+    fn serialize(&self) -> Result<ProtosEncodedVectorSet, Error> {
+        let mut vs = ProtosEncodedVectorSet::new();
+        vs.vector_size = self.vector_size() as u32;
+        vs.byte_data = self.data.clone();
+        Ok(vs)
+    }
+}
+
 impl Deserialize<BlockVectorSet<u32>> for ProtosEncodedVectorSet {
     fn deserialize(self) -> Result<BlockVectorSet<u32>, Error> {
-        BlockVectorSet::chunk(
-            self.data,
-            (self.vector_size as usize)
-                .try_into()
-                .or(Err(Error::InvalidData(
-                    "vector size must not be zero".to_string(),
-                )))?,
+        Ok(deserialize_lazy(self)?.into_inner().into_u32())
+    }
+}
+
+/// Deserializes encoded vectors without copying their elements.
+///
+/// Elements packed 4 bits each are kept packed until they are accessed.
+/// Elements stored a byte each, or packed, are materialized as
+/// [`EncodedVectorSet::U8`].
+///
+/// Fails if the message is invalid.
+pub fn deserialize_lazy(
+    vs: ProtosEncodedVectorSet,
+) -> Result<LazyEncodedVectorSet, Error> {
+    let vector_size: NonZeroUsize = (vs.vector_size as usize)
+        .try_into()
+        .or(Err(Error::InvalidData(
+            "vector size must not be zero".to_string(),
+        )))?;
+    let num_representations = [
+        vs.data.is_empty(),
+        vs.packed_data.is_empty(),
+        vs.byte_data.is_empty(),
+    ].iter().filter(|&&is_empty| !is_empty).count();
+    if num_representations > 1 {
+        return Err(Error::InvalidData(
+            "encoded vector set has more than one kind of data".to_string(),
+        ));
+    }
+    if vs.packed_data.is_empty() {
+        let vectors = if vs.byte_data.is_empty() {
+            EncodedVectorSet::U32(BlockVectorSet::chunk(vs.data, vector_size)?)
+        } else {
+            EncodedVectorSet::U8(
+                BlockVectorSet::chunk(vs.byte_data, vector_size)?,
+            )
+        };
+        return Ok(LazyEncodedVectorSet {
+            vector_size,
+            len: vectors.len(),
+            packed_data: Vec::new(),
+            vectors: OnceLock::from(vectors),
+            fast_scan: OnceLock::new(),
+        });
+    }
+    let packed_size = vector_size.get().div_ceil(2);
+    if !vs.packed_data.len().is_multiple_of(packed_size) {
+        return Err(Error::InvalidData(format!(
+            "packed data size ({}) is not a multiple of {}",
+            vs.packed_data.len(),
+            packed_size,
+        )));
+    }
+    Ok(LazyEncodedVectorSet {
+        vector_size,
+        len: vs.packed_data.len() / packed_size,
+        packed_data: vs.packed_data,
+        vectors: OnceLock::new(),
+        fast_scan: OnceLock::new(),
+    })
+}
+
+/// Encoded vectors deserialized without copying their elements.
+///
+/// Made by [`deserialize_lazy`], which takes over the elements of a message
+/// instead of copying them. Elements packed 4 bits each are unpacked on the
+/// first call to [`LazyEncodedVectorSet::get`]; e.g., a partition loaded
+/// only for its vector IDs never unpacks its encoded vectors.
+///
+/// [`EncodedVectorSet`] converts into one that is already materialized.
+#[derive(Clone, Debug)]
+pub struct LazyEncodedVectorSet {
+    vector_size: NonZeroUsize,
+    len: usize,
+    // Elements packed 4 bits each. Empty unless they are packed.
+    packed_data: Vec<u8>,
+    vectors: OnceLock<EncodedVectorSet>,
+    // `None` if a code does not fit in 4 bits.
+    fast_scan: OnceLock<Option<FastScanCodes>>,
+}
+
+impl LazyEncodedVectorSet {
+    /// Returns the number of vectors.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns if there is no vector.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the size of each vector.
+    pub const fn vector_size(&self) -> usize {
+        self.vector_size.get()
+    }
+
+    /// Returns if the vectors have been materialized.
+    pub fn is_materialized(&self) -> bool {
+        self.vectors.get().is_some()
+    }
+
+    /// Returns the vectors materializing them if necessary.
+    pub fn get(&self) -> &EncodedVectorSet {
+        self.vectors.get_or_init(|| self.unpack())
+    }
+
+    /// Returns the vectors materializing them if necessary.
+    pub fn into_inner(mut self) -> EncodedVectorSet {
+        match self.vectors.take() {
+            Some(vs) => vs,
+            None => self.unpack(),
+        }
+    }
+
+    /// Returns the vectors laid out for fast scan making them if necessary.
+    ///
+    /// Packed elements are laid out without being unpacked. `None` if an
+    /// element does not fit in 4 bits.
+    pub fn fast_scan_codes(&self) -> Option<&FastScanCodes> {
+        self.fast_scan.get_or_init(|| {
+            if self.packed_data.is_empty() {
+                match self.get() {
+                    EncodedVectorSet::U8(vs) => FastScanCodes::from_codes(vs),
+                    EncodedVectorSet::U32(vs) => FastScanCodes::from_codes(vs),
+                }.ok()
+            } else {
+                Some(FastScanCodes::from_packed(
+                    &self.packed_data,
+                    self.len,
+                    self.vector_size,
+                ))
+            }
+        }).as_ref()
+    }
+
+    /// Returns the number of bytes allocated for the elements.
+    pub fn memory_bytes(&self) -> usize {
+        self.packed_data.capacity()
+            + self.vectors.get().map_or(0, |vs| vs.memory_bytes())
+            + self.fast_scan
+                .get()
+                .and_then(Option::as_ref)
+                .map_or(0, |codes| codes.memory_bytes())
+    }
+
+    // Unpacks the packed elements.
+    //
+    // The size of the packed elements has been validated.
+    fn unpack(&self) -> EncodedVectorSet {
+        let m = self.vector_size.get();
+        let mut data: Vec<u8> = Vec::with_capacity(self.len * m);
+        for packed in self.packed_data.chunks_exact(m.div_ceil(2)) {
+            for i in 0..m {
+                let b = packed[i / 2];
+                data.push(if i % 2 == 0 { b & 0xF } else { b >> 4 });
+            }
+        }
+        EncodedVectorSet::U8(
+            BlockVectorSet::chunk(data, self.vector_size)
+                .expect("packed data size must have been validated"),
         )
     }
 }
 
+impl From<EncodedVectorSet> for LazyEncodedVectorSet {
+    fn from(vectors: EncodedVectorSet) -> Self {
+        Self {
+            vector_size: vectors.vector_size().try_into()
+                .expect("vector size must not be zero"),
+            len: vectors.len(),
+            packed_data: Vec::new(),
+            vectors: OnceLock::from(vectors),
+            fast_scan: OnceLock::new(),
+        }
+    }
+}
+
+/// Serializes encoded vectors with elements packed 4 bits each.
+///
+/// Halves the size of encoded vectors whose codebooks have at most 16 codes.
+///
+/// Fails if any element is greater than 15.
+pub fn serialize_packed<C>(
+    vs: &BlockVectorSet<C>,
+) -> Result<ProtosEncodedVectorSet, Error>
+where
+    C: Code,
+{
+    let m = vs.vector_size();
+    let mut packed_data: Vec<u8> =
+        Vec::with_capacity(vs.len() * m.div_ceil(2));
+    for i in 0..vs.len() {
+        for pair in vs.get(i).chunks(2) {
+            let lower = pair[0].index();
+            let upper = pair.get(1).map_or(0, |c| c.index());
+            if lower > 0xF || upper > 0xF {
+                return Err(Error::InvalidArgs(format!(
+                    "code does not fit in 4 bits: {:?}",
+                    [lower, upper],
+                )));
+            }
+            packed_data.push((lower | (upper << 4)) as u8);
+        }
+    }
+    let mut packed = ProtosEncodedVectorSet::new();
+    packed.vector_size = m as u32;
+    packed.packed_data = packed_data;
+    Ok(packed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,10 +326,139 @@ mod tests {
         assert_eq!(output.get(1), vec![4, 5, 6]);
     }
 
+    #[test]
+    fn block_vector_set_u32_can_be_serialized_and_deserialized_packed() {
+        let data: Vec<u32> = vec![1, 2, 15, 0, 4, 9];
+        let input: BlockVectorSet<u32> = BlockVectorSet::chunk(
+            data.clone(),
+            3.try_into().unwrap(),
+        ).unwrap();
+        let packed = serialize_packed(&input).unwrap();
+        assert_eq!(packed.vector_size, 3);
+        assert!(packed.data.is_empty());
+        assert_eq!(packed.packed_data, vec![0x21, 0x0F, 0x40, 0x09]);
+        let output = packed.deserialize().unwrap();
+        assert_eq!(output.len(), 2);
+        assert_eq!(output.get(0), vec![1, 2, 15]);
+        assert_eq!(output.get(1), vec![0, 4, 9]);
+    }
+
+    #[test]
+    fn block_vector_set_u32_cannot_be_packed_if_code_exceeds_4_bits() {
+        let input: BlockVectorSet<u32> = BlockVectorSet::chunk(
+            vec![1, 16],
+            2.try_into().unwrap(),
+        ).unwrap();
+        assert!(serialize_packed(&input).is_err());
+    }
+
     #[test]
     fn block_vector_set_u32_cannot_be_deserialized_if_vector_size_is_zero() {
         let mut input = ProtosEncodedVectorSet::new();
         input.vector_size = 0;
         assert!(input.deserialize().is_err());
     }
+
+    #[test]
+    fn block_vector_set_should_take_over_elements_of_messages() {
+        let mut input = ProtosVectorSet::new();
+        input.vector_size = 2;
+        input.data = vec![0.0, 1.0, 2.0, 3.0];
+        let ptr = input.data.as_ptr();
+        let output: BlockVectorSet<f32> = input.deserialize().unwrap();
+        assert_eq!(output.get(0).as_ptr(), ptr);
+
+        let mut input = ProtosEncodedVectorSet::new();
+        input.vector_size = 2;
+        input.data = vec![1, 2, 3, 4];
+        let ptr = input.data.as_ptr();
+        let output = deserialize_lazy(input).unwrap();
+        assert!(output.is_materialized());
+        match output.get() {
+            EncodedVectorSet::U32(vs) => assert_eq!(vs.get(0).as_ptr(), ptr),
+            EncodedVectorSet::U8(_) => panic!("codes must be u32"),
+        }
+    }
+
+    #[test]
+    fn lazy_encoded_vector_set_should_unpack_on_first_access() {
+        let input: BlockVectorSet<u32> = BlockVectorSet::chunk(
+            vec![1, 2, 15, 0, 4, 9],
+            3.try_into().unwrap(),
+        ).unwrap();
+        let output =
+            deserialize_lazy(serialize_packed(&input).unwrap()).unwrap();
+        assert!(!output.is_materialized());
+        assert_eq!(output.len(), 2);
+        assert_eq!(output.vector_size(), 3);
+        assert_eq!(output.memory_bytes(), 4);
+        assert_eq!(output.get().get(1), vec![0, 4, 9]);
+        assert!(output.is_materialized());
+        assert!(matches!(output.get(), EncodedVectorSet::U8(_)));
+        assert_eq!(output.clone().into_inner().get(0), vec![1, 2, 15]);
+    }
+
+    #[test]
+    fn lazy_encoded_vector_set_should_lay_out_codes_for_fast_scan() {
+        let input: BlockVectorSet<u32> = BlockVectorSet::chunk(
+            vec![1, 2, 15, 0, 4, 9],
+            3.try_into().unwrap(),
+        ).unwrap();
+        let output =
+            deserialize_lazy(serialize_packed(&input).unwrap()).unwrap();
+        let codes = output.fast_scan_codes().unwrap();
+        assert_eq!(codes.code(1, 2), 9);
+        assert!(!output.is_materialized());
+        let output = deserialize_lazy(
+            BlockVectorSet::chunk(vec![1u32, 16], 2.try_into().unwrap())
+                .unwrap()
+                .serialize()
+                .unwrap(),
+        ).unwrap();
+        assert!(output.fast_scan_codes().is_none());
+    }
+
+    #[test]
+    fn deserialize_lazy_should_fail_if_packed_data_is_broken() {
+        let mut input = ProtosEncodedVectorSet::new();
+        input.vector_size = 3;
+        input.packed_data = vec![0x21, 0x0F, 0x40];
+        assert!(matches!(
+            deserialize_lazy(input),
+            Err(Error::InvalidData(_)),
+        ));
+    }
+
+    #[test]
+    fn block_vector_set_u8_can_be_serialized_and_deserialized_as_bytes() {
+        let input: BlockVectorSet<u8> = BlockVectorSet::chunk(
+            vec![1, 200, 255, 0],
+            2.try_into().unwrap(),
+        ).unwrap();
+        let output = input.serialize().unwrap();
+        assert!(output.data.is_empty());
+        assert_eq!(output.byte_data, vec![1, 200, 255, 0]);
+        let widened: BlockVectorSet<u32> =
+            output.clone().deserialize().unwrap();
+        assert_eq!(widened.get(1), vec![255, 0]);
+        let ptr = output.byte_data.as_ptr();
+        let lazy = deserialize_lazy(output).unwrap();
+        assert!(lazy.is_materialized());
+        match lazy.get() {
+            EncodedVectorSet::U8(vs) => assert_eq!(vs.get(0).as_ptr(), ptr),
+            EncodedVectorSet::U32(_) => panic!("codes must be u8"),
+        }
+    }
+
+    #[test]
+    fn deserialize_lazy_should_reject_mixed_kinds_of_data() {
+        let mut input = ProtosEncodedVectorSet::new();
+        input.vector_size = 2;
+        input.data = vec![1, 2];
+        input.byte_data = vec![1, 2];
+        assert!(matches!(
+            deserialize_lazy(input),
+            Err(Error::InvalidData(_)),
+        ));
+    }
 }
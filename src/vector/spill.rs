@@ -0,0 +1,313 @@
+//! Vector sets that spill to disk.
+//!
+//! [`SpillVectorSet`] keeps vectors in memory until they exceed a memory
+//! budget, then moves them into a memory-mapped temporary file. Since
+//! [`DatabaseBuilder`](crate::db::build::DatabaseBuilder) partitions the
+//! input vector set in place and keeps residual vectors in it, a database
+//! built from a spilled vector set holds its residues and subvector
+//! divisions in the file as well; the operating system pages them in and
+//! out while they are encoded and serialized.
+
+use memmap2::MmapMut;
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use crate::error::Error;
+
+use super::{VectorSet, VectorSetMut, VectorSetRemove};
+
+/// Element that can be stored in a spill file as raw bytes.
+///
+/// # Safety
+///
+/// Every bit pattern of the size of an implementing type must be a valid
+/// value, the type must have no padding, and its alignment must not exceed
+/// the page size.
+pub unsafe trait SpillElement: Copy {}
+
+unsafe impl SpillElement for f32 {}
+
+unsafe impl SpillElement for f64 {}
+
+/// Vector set that moves its vectors into a temporary file when they exceed
+/// a memory budget.
+///
+/// The temporary file is deleted when the vector set is dropped.
+pub struct SpillVectorSet<T> {
+    // Vector size.
+    vector_size: usize,
+    // Number of vectors.
+    len: usize,
+    // Maximum number of bytes of elements kept in memory.
+    memory_budget: usize,
+    // Directory to create the temporary file in. The system default if
+    // `None`.
+    spill_dir: Option<PathBuf>,
+    storage: Storage<T>,
+}
+
+// Storage of elements.
+enum Storage<T> {
+    // Elements in memory.
+    Memory(Vec<T>),
+    // Elements in a memory-mapped file.
+    Spilled {
+        file: File,
+        map: MmapMut,
+        // Number of vectors the file can hold.
+        capacity: usize,
+    },
+}
+
+impl<T> SpillVectorSet<T>
+where
+    T: SpillElement,
+{
+    /// Creates an empty vector set.
+    ///
+    /// `memory_budget` is the maximum number of bytes of elements kept in
+    /// memory.
+    pub fn new(vector_size: NonZeroUsize, memory_budget: usize) -> Self {
+        Self {
+            vector_size: vector_size.get(),
+            len: 0,
+            memory_budget,
+            spill_dir: None,
+            storage: Storage::Memory(Vec::new()),
+        }
+    }
+
+    /// Sets the directory to create the temporary file in.
+    ///
+    /// The system temporary directory by default.
+    pub fn with_spill_dir<P>(mut self, dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+
+    /// Returns if the vectors have been moved into a temporary file.
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.storage, Storage::Spilled { .. })
+    }
+
+    /// Appends a vector.
+    ///
+    /// Spills the vectors to a temporary file if they exceed the memory
+    /// budget.
+    ///
+    /// Fails if the size of `v` does not match the vector size, or if the
+    /// temporary file cannot be created or extended.
+    pub fn push(&mut self, v: &[T]) -> Result<(), Error> {
+        if v.len() != self.vector_size {
+            return Err(Error::InvalidArgs(format!(
+                "vector size mismatch: expected {} but got {}",
+                self.vector_size,
+                v.len(),
+            )));
+        }
+        let m = self.vector_size;
+        if !self.is_spilled()
+            && (self.len + 1) * m * core::mem::size_of::<T>()
+                > self.memory_budget
+        {
+            self.spill()?;
+        }
+        match &mut self.storage {
+            Storage::Memory(data) => data.extend_from_slice(v),
+            Storage::Spilled { file, map, capacity } => {
+                if self.len == *capacity {
+                    *capacity *= 2;
+                    file.set_len(file_size::<T>(*capacity, m) as u64)?;
+                    *map = unsafe { MmapMut::map_mut(&*file)? };
+                }
+            },
+        }
+        let from = self.len * m;
+        self.len += 1;
+        if self.is_spilled() {
+            self.elements_mut()[from..].copy_from_slice(v);
+        }
+        Ok(())
+    }
+
+    // Moves the elements into a new temporary file.
+    fn spill(&mut self) -> Result<(), Error> {
+        let file = match &self.spill_dir {
+            Some(dir) => tempfile::tempfile_in(dir)?,
+            None => tempfile::tempfile()?,
+        };
+        let m = self.vector_size;
+        let capacity = (self.len * 2).max(1);
+        file.set_len(file_size::<T>(capacity, m) as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        if let Storage::Memory(data) = &self.storage {
+            map[..data.len() * core::mem::size_of::<T>()].copy_from_slice(
+                as_bytes(data),
+            );
+        }
+        self.storage = Storage::Spilled { file, map, capacity };
+        Ok(())
+    }
+
+    // Returns all the elements.
+    fn elements(&self) -> &[T] {
+        let n = self.len * self.vector_size;
+        match &self.storage {
+            Storage::Memory(data) => &data[..n],
+            // safety: `T: SpillElement` and the map is page-aligned
+            Storage::Spilled { map, .. } => unsafe {
+                core::slice::from_raw_parts(map.as_ptr() as *const T, n)
+            },
+        }
+    }
+
+    // Returns all the mutable elements.
+    fn elements_mut(&mut self) -> &mut [T] {
+        let n = self.len * self.vector_size;
+        match &mut self.storage {
+            Storage::Memory(data) => &mut data[..n],
+            // safety: `T: SpillElement` and the map is page-aligned
+            Storage::Spilled { map, .. } => unsafe {
+                core::slice::from_raw_parts_mut(map.as_mut_ptr() as *mut T, n)
+            },
+        }
+    }
+}
+
+impl<T> VectorSet<T> for SpillVectorSet<T>
+where
+    T: SpillElement,
+{
+    type Vector = [T];
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn vector_size(&self) -> usize {
+        self.vector_size
+    }
+
+    fn get(&self, i: usize) -> &Self::Vector {
+        let from = i * self.vector_size;
+        &self.elements()[from..from + self.vector_size]
+    }
+}
+
+impl<T> VectorSetMut<T> for SpillVectorSet<T>
+where
+    T: SpillElement,
+{
+    fn get_mut(&mut self, i: usize) -> &mut Self::Vector {
+        let m = self.vector_size;
+        let from = i * m;
+        &mut self.elements_mut()[from..from + m]
+    }
+}
+
+impl<T> VectorSetRemove<T> for SpillVectorSet<T>
+where
+    T: SpillElement,
+{
+    fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let m = self.vector_size;
+        let n = self.len;
+        let mut num_kept = 0;
+        let elements = self.elements_mut();
+        for i in 0..n {
+            if keep(i) {
+                if num_kept != i {
+                    elements.copy_within(i * m..(i + 1) * m, num_kept * m);
+                }
+                num_kept += 1;
+            }
+        }
+        self.len = num_kept;
+        if let Storage::Memory(data) = &mut self.storage {
+            data.truncate(num_kept * m);
+        }
+    }
+}
+
+// Returns the number of bytes of a file that holds `capacity` vectors.
+fn file_size<T>(capacity: usize, vector_size: usize) -> usize {
+    capacity * vector_size * core::mem::size_of::<T>()
+}
+
+// Reinterprets elements as bytes.
+fn as_bytes<T>(data: &[T]) -> &[u8]
+where
+    T: SpillElement,
+{
+    // safety: `T: SpillElement` has no padding
+    unsafe {
+        core::slice::from_raw_parts(
+            data.as_ptr() as *const u8,
+            core::mem::size_of_val(data),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::build::DatabaseBuilder;
+
+    #[test]
+    fn spill_vector_set_should_keep_vectors_within_budget_in_memory() {
+        let mut vs = SpillVectorSet::<f32>::new(2.try_into().unwrap(), 16);
+        vs.push(&[1.0, 2.0]).unwrap();
+        vs.push(&[3.0, 4.0]).unwrap();
+        assert!(!vs.is_spilled());
+        assert_eq!(vs.get(1), &[3.0, 4.0]);
+    }
+
+    #[test]
+    fn spill_vector_set_should_preserve_vectors_after_spilling() {
+        let mut vs = SpillVectorSet::<f32>::new(2.try_into().unwrap(), 16);
+        for i in 0..10 {
+            vs.push(&[i as f32, -(i as f32)]).unwrap();
+        }
+        assert!(vs.is_spilled());
+        assert_eq!(vs.len(), 10);
+        for (i, v) in vs.iter() {
+            assert_eq!(v, &[i as f32, -(i as f32)]);
+        }
+        vs.get_mut(3)[0] = 100.0;
+        vs.retain(|i| i % 3 == 0);
+        assert_eq!(vs.len(), 4);
+        assert_eq!(vs.get(1), &[100.0, -3.0]);
+        assert_eq!(vs.get(3), &[9.0, -9.0]);
+    }
+
+    #[test]
+    fn spill_vector_set_should_reject_vector_of_wrong_size() {
+        let mut vs = SpillVectorSet::<f32>::new(2.try_into().unwrap(), 16);
+        assert!(matches!(vs.push(&[1.0]), Err(Error::InvalidArgs(_))));
+    }
+
+    #[test]
+    fn database_can_be_built_from_spilled_vector_set() {
+        let mut vs = SpillVectorSet::<f32>::new(4.try_into().unwrap(), 0);
+        for i in 0..100 {
+            let x = (i % 10) as f32;
+            vs.push(&[x, x + 1.0, -x, (i / 10) as f32]).unwrap();
+        }
+        assert!(vs.is_spilled());
+        let db = DatabaseBuilder::new(vs)
+            .with_partitions(2.try_into().unwrap())
+            .with_divisions(2.try_into().unwrap())
+            .with_clusters(4.try_into().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(db.num_vectors(), 100);
+    }
+}